@@ -1,6 +1,7 @@
+/* Generated by `xtasks linker-scripts` from common/src/layout.rs, edit that instead */
 ENTRY(start)
 SECTIONS {
-  . = 0x10000;                /* where your loader copies it */
+  . = 0x60000;                /* where stage1 copies it, see memory_map.rs */
 
   /DISCARD/ : {
     *(.eh_frame*) *(.gcc_except_table*)
@@ -17,4 +18,6 @@ SECTIONS {
   .rodata : ALIGN(16) { *(.rodata .rodata.*) }  /* include constants! */
   .data   : ALIGN(16) { *(.data .data.*) }
   .bss (NOLOAD) : ALIGN(16) { *(.bss .bss.*) *(COMMON) }
+
+  ASSERT(. <= 0x80000, "stage2 doesn't fit under its stack")
 }
@@ -0,0 +1,158 @@
+// Where stage2 was when an exception hit: the chain of saved EBPs on its stack, each followed by
+// the return address of its frame, as stage2 is built with frame pointers. The addresses are
+// symbolized with stage2's symbols once they're loaded, i.e. only for faults past the payload load
+use core::ops::Range;
+
+use common::{
+    layout,
+    payload::PayloadKind,
+    serial,
+    symbols::{Location, SymbolTable},
+    vga,
+};
+
+const MAX_FRAMES: usize = 32;
+// The frame pointer and the return address
+const FRAME_SIZE: u32 = 8;
+
+/// The return addresses up a stack, from the frame `frame_pointer` points to. Stops at the first
+/// frame pointer out of `stack` or not above the previous one, so a smashed chain ends it early
+pub struct Frames<F> {
+    frame_pointer: u32,
+    stack: Range<u32>,
+    read: F,
+    depth: usize,
+}
+
+impl<F: Fn(u32) -> u32> Frames<F> {
+    /// `read` reads the u32 at an address in `stack`
+    pub fn new(frame_pointer: u32, stack: Range<u32>, read: F) -> Self {
+        Self {
+            frame_pointer,
+            stack,
+            read,
+            depth: 0,
+        }
+    }
+}
+
+impl<F: Fn(u32) -> u32> Iterator for Frames<F> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame_pointer = self.frame_pointer;
+        if self.depth == MAX_FRAMES
+            || !frame_pointer.is_multiple_of(4)
+            || frame_pointer < self.stack.start
+            || frame_pointer.checked_add(FRAME_SIZE)? > self.stack.end
+        {
+            return None;
+        }
+        let return_address = (self.read)(frame_pointer + 4);
+        if return_address == 0 {
+            return None;
+        }
+        let caller_frame_pointer = (self.read)(frame_pointer);
+        // Stacks grow down, so callers' frames are above, anything else is a broken chain
+        self.frame_pointer = if caller_frame_pointer > frame_pointer {
+            caller_frame_pointer
+        } else {
+            0
+        };
+        self.depth += 1;
+        Some(return_address)
+    }
+}
+
+fn symbols() -> Option<SymbolTable<'static>> {
+    let boot_info_ptr = &raw const crate::BOOT_INFO;
+    // SAFETY: no threads, and exception handlers only read the boot info
+    let payload = unsafe { &*boot_info_ptr }.payload(PayloadKind::Stage2Symbols)?;
+    // SAFETY: The payload was loaded in identity mapped memory below 4GB, which paging being off
+    // in stage2 lets us read, and nothing writes over it
+    let bytes = unsafe {
+        core::slice::from_raw_parts(payload.address as usize as *const u8, payload.size as usize)
+    };
+    SymbolTable::try_from(bytes).ok()
+}
+
+fn print_address_no_sync(address: u32, location: Option<Location>) {
+    match location {
+        Some(location) => {
+            vga::writeln_no_sync!("  {address:08X} {location}");
+            serial::writeln_no_sync!("  {address:08X} {location}");
+        }
+        None => {
+            vga::writeln_no_sync!("  {address:08X}");
+            serial::writeln_no_sync!("  {address:08X}");
+        }
+    }
+}
+
+/// Prints the faulting EIP, then the return addresses up stage2's stack from the frame EBP points
+/// to
+pub fn print_no_sync(eip: u32, ebp: u32) {
+    let symbols = symbols();
+    let lookup = |address: u32| symbols.and_then(|symbols| symbols.lookup(address.into()));
+    vga::writeln_no_sync!("Backtrace:");
+    serial::writeln_no_sync!("Backtrace:");
+    print_address_no_sync(eip, lookup(eip));
+    let stack = layout::STAGE2_STACK.start as u32..layout::STAGE2_STACK.end as u32;
+    let read = |address: u32| {
+        // SAFETY: Frames only reads aligned addresses within stage2's stack, which is mapped
+        unsafe { *(address as usize as *const u32) }
+    };
+    for return_address in Frames::new(ebp, stack, read) {
+        print_address_no_sync(return_address, lookup(return_address));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backtrace::{Frames, MAX_FRAMES};
+
+    const STACK_START: u32 = 0x8_0000;
+
+    fn frames(stack: &[u32], frame_pointer: u32) -> Frames<impl Fn(u32) -> u32> {
+        let end = STACK_START + 4 * stack.len() as u32;
+        Frames::new(frame_pointer, STACK_START..end, move |address| {
+            stack[((address - STACK_START) / 4) as usize]
+        })
+    }
+
+    #[test]
+    fn walk() {
+        // Three frames at 0x80000, 0x80010 and 0x80020, the outermost with a null frame pointer
+        let stack = [
+            0x8_0010, 0x61_000, 0, 0, //
+            0x8_0020, 0x62_000, 0, 0, //
+            0, 0x63_000,
+        ];
+        assert!(frames(&stack, STACK_START).eq([0x61_000, 0x62_000, 0x63_000]));
+        assert!(frames(&stack, STACK_START + 0x10).eq([0x62_000, 0x63_000]));
+        // Misaligned, and out of the stack
+        assert_eq!(0, frames(&stack, STACK_START + 2).count());
+        assert_eq!(0, frames(&stack, STACK_START + 0x24).count());
+        assert_eq!(0, frames(&stack, 0).count());
+    }
+
+    #[test]
+    fn broken_chains() {
+        // A frame pointing back at itself
+        let stack = [0x8_0000, 0x61_000];
+        assert!(frames(&stack, STACK_START).eq([0x61_000]));
+        // A null return address
+        let stack = [0x8_0008, 0, 0, 0x62_000];
+        assert_eq!(0, frames(&stack, STACK_START).count());
+        // A chain going around in circles
+        let stack = [0x8_0008, 0x61_000, 0x8_0000, 0x62_000];
+        assert!(frames(&stack, STACK_START).eq([0x61_000, 0x62_000]));
+        // A chain deeper than what's printed
+        let mut stack = [0; 2 * (MAX_FRAMES + 8)];
+        for (i, frame) in stack.chunks_mut(2).enumerate() {
+            frame[0] = STACK_START + 8 * (i as u32 + 1);
+            frame[1] = 0x61_000 + i as u32;
+        }
+        assert_eq!(MAX_FRAMES, frames(&stack, STACK_START).count());
+    }
+}
@@ -0,0 +1,94 @@
+// The stages stage2 goes through between stage1 handing over and the jump to the kernel. Entering
+// one logs it to serial with the time since stage2 started, and checks it in with the watchdog,
+// so that a failure or a hang can be blamed on the stage it happened in
+use core::fmt;
+
+use common::{
+    error::{Context, Error, Facility, Fault},
+    serial, tunables,
+};
+
+use crate::watchdog;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootStage {
+    Stage1Handoff,
+    DriveProbe,
+    KernelRead,
+    SegmentLoad,
+    Relocation,
+    PayloadLoad,
+    DriveCatalog,
+    PciReport,
+    Paging,
+    Gdt,
+    ControlRegs,
+    Jump,
+}
+
+impl BootStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BootStage::Stage1Handoff => "taking over from stage1",
+            BootStage::DriveProbe => "probing the boot drive",
+            BootStage::KernelRead => "reading the kernel from disk",
+            BootStage::SegmentLoad => "loading the kernel segments",
+            BootStage::Relocation => "relocating the kernel",
+            BootStage::PayloadLoad => "loading the other payloads",
+            BootStage::DriveCatalog => "scanning the ATA channels",
+            BootStage::PciReport => "listing the PCI devices",
+            BootStage::Paging => "setting up the page tables",
+            BootStage::Gdt => "setting up the GDT",
+            BootStage::ControlRegs => "setting up the control registers",
+            BootStage::Jump => "preparing to jump to the kernel",
+        }
+    }
+
+    /// How long the stage can take before the watchdog gives up on booting
+    fn deadline_ms(&self) -> u64 {
+        let tunables = tunables::get();
+        let deadline_ns = match self {
+            BootStage::KernelRead | BootStage::PayloadLoad => tunables.payload_read_deadline_ns,
+            _ => tunables.boot_stage_deadline_ns,
+        };
+        deadline_ns / 1_000_000
+    }
+}
+
+impl fmt::Display for BootStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+static mut CURRENT_STAGE: BootStage = BootStage::Stage1Handoff;
+
+/// Moves on to `stage`, logging the transition. The watchdog must be armed
+pub fn enter(stage: BootStage) {
+    let uptime_ns = watchdog::uptime_ns();
+    serial::writeln_no_sync!(
+        "[{:>5}.{:03} ms] boot stage: {}",
+        uptime_ns / 1_000_000,
+        uptime_ns / 1_000 % 1_000,
+        stage
+    );
+    let current_stage_ptr = &raw mut CURRENT_STAGE;
+    // SAFETY: This is safe because we are in the bootloader and no other threads are running.
+    unsafe { *current_stage_ptr = stage };
+    watchdog::check_in(stage.name(), stage.deadline_ms());
+}
+
+pub fn current() -> BootStage {
+    let current_stage_ptr = &raw const CURRENT_STAGE;
+    // SAFETY: This is safe because we are in the bootloader and no other threads are running.
+    unsafe { *current_stage_ptr }
+}
+
+/// The error to top a failure's error chain with, naming the stage it happened in
+pub fn failed() -> Error {
+    Error::new(
+        Fault::KernelInitialization,
+        Context::BootStep(current().name()),
+        Facility::Bootloader,
+    )
+}
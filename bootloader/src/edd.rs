@@ -3,6 +3,7 @@
 // http://www.o3one.org/hwdocs/bios_doc/bios_specs_edd30.pdf
 use core::fmt::Display;
 
+use common::boot_info::{BiosDrive, MAX_BIOS_DRIVES};
 use common::error::{Error, Facility, Fault};
 use common::make_bitmap;
 
@@ -12,6 +13,8 @@ use zerocopy::{LE, TryFromBytes, TryReadError, U16, U32, U64};
 
 pub const DRIVE_PARAMETERS_BUFFER_SIZE: usize =
     size_of::<DriveParametersRaw>() + size_of::<DevicePathInformationRaw>();
+/// What the BIOS calls the first hard drive in DL
+pub const FIRST_BIOS_DRIVE: u8 = 0x80;
 
 #[derive(TryFromBytes)]
 #[repr(C)]
@@ -315,46 +318,86 @@ impl Display for InfoFlagType {
 
 make_bitmap!(new_type: InfoFlags, underlying_flag_type: InfoFlagType, repr: u16, bit_skipper: |i| i > 6);
 
+/// Which EDD version's structure the BIOS filled in, told apart by the size it reports. Each one
+/// adds fields after the ones of the previous
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    /// Up to the bytes per sector: 26 bytes
+    #[default]
+    V1_1,
+    /// Then the pointer to the fixed disk parameter table: 30 bytes
+    V2_0,
+    /// Then the device path information: 66 bytes, or more from BIOSes following later drafts
+    V3_0,
+}
+
+impl Version {
+    fn from_buffer_size(buffer_size: u16) -> Option<Self> {
+        match buffer_size {
+            0..26 => None,
+            26..30 => Some(Version::V1_1),
+            30..66 => Some(Version::V2_0),
+            _ => Some(Version::V3_0),
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Version::V1_1 => write!(f, "1.1"),
+            Version::V2_0 => write!(f, "2.0"),
+            Version::V3_0 => write!(f, "3.0"),
+        }
+    }
+}
+
 #[cfg_attr(test, derive(PartialEq, Eq))]
 #[derive(Debug, Default)]
 pub struct DriveParameters {
     buffer_size: u16,
+    version: Version,
     information_flags: InfoFlags,
     cylinders: u32,
     heads: u32,
     sectors_per_track: u32,
     sectors: u64,
     bytes_per_sector: u16,
+    // Where the BIOS left the fixed disk parameter table, until resolve_fdbt reads it
+    fixed_disk_parameter_table_address: Option<u32>,
     fixed_disk_parameter_table: Option<FixedDiskParameterTable>,
     device_path_information: Option<DevicePathInformation>,
 }
 
 impl DriveParameters {
+    /// The drive for the kernel's BIOS drive inventory, the BIOS calling it `number`
+    pub fn bios_drive(&self, number: u8) -> BiosDrive {
+        BiosDrive::new(
+            number,
+            self.sectors,
+            self.bytes_per_sector,
+            self.information_flags.into(),
+        )
+    }
+
     fn try_read_error<U: TryFromBytes>(err: TryReadError<&[u8], U>) -> Error {
         try_read_error(Facility::EDDDriveParameters, err)
     }
 
-    pub fn resolve_fdbt(&mut self, mut fdbt_address: u32) -> Result<(), Error> {
-        if fdbt_address == u32::MAX {
-            // Nothing to do, the fdbt address is invalid
+    /// Reads the fixed disk parameter table the BIOS pointed to, if it did. Parsing leaves it out,
+    /// as the pointer is an address in the memory the BIOS keeps the table in, which only stage2
+    /// can read
+    pub fn resolve_fdbt(&mut self) -> Result<(), Error> {
+        let Some(fdbt_address) = self.fixed_disk_parameter_table_address else {
             return Ok(());
-        }
-
-        if self.buffer_size as usize != size_of::<DriveParametersRaw>() {
-            return Err(Error::parsing_error(
-                Fault::NotEnoughBytesFor("fixed disk parameter table"),
-                Facility::EDDFixedDiskParameterTable,
-            ));
-        }
-        // Address is in seg:offset format, with offset coming first
-        fdbt_address = ((fdbt_address >> 16) * 16) + (fdbt_address & 0xffff);
+        };
 
         self.fixed_disk_parameter_table = Some(FixedDiskParameterTable::try_from(
-            //SAFETY: If we got to this point, the fdbt address is valid and points to a
-            //FixedDiskParameterTableRaw sized byte array
+            //SAFETY: The BIOS reported the table at this address, in memory nothing wrote over
+            //since, and the table is a FixedDiskParameterTableRaw sized byte array
             unsafe {
                 core::slice::from_raw_parts(
-                    fdbt_address as *const u8,
+                    fdbt_address as usize as *const u8,
                     size_of::<FixedDiskParameterTableRaw>(),
                 )
             },
@@ -366,7 +409,11 @@ impl DriveParameters {
 impl Display for DriveParameters {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         writeln!(f, "Drive Parameters:")?;
-        writeln!(f, "  Buffer Size: {}", self.buffer_size)?;
+        writeln!(
+            f,
+            "  Buffer Size: {} (EDD {})",
+            self.buffer_size, self.version
+        )?;
         writeln!(f, "  Information Flags: {}", self.information_flags)?;
         writeln!(f, "  Cylinders: {}", self.cylinders)?;
         writeln!(f, "  Heads: {}", self.heads)?;
@@ -394,12 +441,12 @@ impl TryFrom<&DriveParametersRaw> for DriveParameters {
     type Error = Error;
 
     fn try_from(value: &DriveParametersRaw) -> Result<Self, Self::Error> {
-        if value.buffer_size.get() != 26 && value.buffer_size.get() != 30 {
+        let Some(version) = Version::from_buffer_size(value.buffer_size.get()) else {
             return Err(Error::parsing_error(
                 Fault::InvalidValueForField("buffer size"),
-                Facility::EDDDevicePathInformation,
+                Facility::EDDDriveParameters,
             ));
-        }
+        };
 
         let information_flags: InfoFlags = InfoFlags {
             bits: value.information_flags.get(),
@@ -458,12 +505,14 @@ impl TryFrom<&DriveParametersRaw> for DriveParameters {
 
         Ok(Self {
             buffer_size: value.buffer_size.get(),
+            version,
             information_flags,
             cylinders: value.cylinders.get(),
             heads: value.heads.get(),
             sectors_per_track: value.sectors_per_track.get(),
             sectors: value.sectors.get(),
             bytes_per_sector: value.bytes_per_sector.get(),
+            fixed_disk_parameter_table_address: None,
             fixed_disk_parameter_table: None,
             device_path_information: None,
         })
@@ -483,21 +532,25 @@ impl TryFrom<DriveParameters> for common::ata::Device {
         let Some(device_path_information) = &value.device_path_information else {
             return Err(value);
         };
-        let is_slave = match device_path_information.interface {
-            Interface::Ata { is_slave } | Interface::Atapi { is_slave, .. } => is_slave,
-            _ => {
-                return Err(value);
-            }
-        };
         let sectors = value.sectors;
         let sector_size_bytes = value.bytes_per_sector;
-        Ok(common::ata::Device::new(
-            io_port_base_address,
-            control_port_base_address,
-            is_slave,
-            sectors,
-            sector_size_bytes,
-        ))
+        match device_path_information.interface {
+            Interface::Ata { is_slave } => Ok(common::ata::Device::new(
+                io_port_base_address,
+                control_port_base_address,
+                is_slave,
+                sectors,
+                sector_size_bytes,
+            )),
+            Interface::Atapi { is_slave, .. } => Ok(common::ata::Device::new_atapi(
+                io_port_base_address,
+                control_port_base_address,
+                is_slave,
+                sectors,
+                sector_size_bytes,
+            )),
+            _ => Err(value),
+        }
     }
 }
 
@@ -509,20 +562,45 @@ impl TryFrom<&[u8]> for DriveParameters {
             DriveParametersRaw::try_read_from_prefix(bytes).map_err(Self::try_read_error)?;
 
         let mut result = Self::try_from(&drive_parameters_raw)?;
-        if drive_parameters_raw.configuration_parameters.get() != u32::MAX
-            && drive_parameters_raw.buffer_size.get() as usize == size_of::<DriveParametersRaw>()
-        {
-            result.resolve_fdbt(drive_parameters_raw.configuration_parameters.get())?;
+        // 1.1 structures end before the pointer, and FFFF:FFFF means there's no table
+        let fdbt_address = drive_parameters_raw.configuration_parameters.get();
+        if result.version >= Version::V2_0 && fdbt_address != u32::MAX {
+            // In seg:offset format, with the offset coming first
+            result.fixed_disk_parameter_table_address =
+                Some((fdbt_address >> 16) * 16 + (fdbt_address & 0xffff));
         }
 
-        if u16::from_le_bytes([bytes[30], bytes[31]]) == 0xbedd {
-            result.device_path_information = Some(DevicePathInformation::try_from(&bytes[30..])?)
+        // Some BIOSes (QEMU's and Bochs' among them) fill in the device path information while
+        // reporting the 2.0 size, so the key rather than the version says whether it's there
+        let device_path_information = &bytes[size_of::<DriveParametersRaw>()..];
+        if device_path_information.starts_with(&0xbeddu16.to_le_bytes()) {
+            result.device_path_information =
+                Some(DevicePathInformation::try_from(device_path_information)?)
         }
 
         Ok(result)
     }
 }
 
+/// The number of the drive the BIOS booted from, as stage1 left it at the start of the BIOS drive
+/// table (see memory_map::BIOS_DRIVES)
+pub fn boot_drive_number(table: &[u8]) -> Option<u8> {
+    table.first().copied()
+}
+
+/// The numbers of the drives the BIOS described in the BIOS drive table, with their parameters.
+/// stage1 zeroes the size of the slots of the drives the BIOS had nothing to say about
+pub fn bios_drives(table: &[u8]) -> impl Iterator<Item = (u8, Result<DriveParameters, Error>)> {
+    table
+        .get(2..)
+        .unwrap_or_default()
+        .chunks_exact(DRIVE_PARAMETERS_BUFFER_SIZE)
+        .take(MAX_BIOS_DRIVES)
+        .zip(FIRST_BIOS_DRIVE..)
+        .filter(|(slot, _)| slot[..2] != [0, 0])
+        .map(|(slot, number)| (number, DriveParameters::try_from(slot)))
+}
+
 #[derive(TryFromPrimitive, Clone, Copy)]
 #[repr(u8)]
 pub enum HeadRegisterFlagType {
@@ -768,12 +846,14 @@ mod tests {
         assert_eq!(
             edd::DriveParameters {
                 buffer_size: 30,
+                version: edd::Version::V2_0,
                 information_flags: edd::InfoFlags { bits: 2 },
                 cylinders: 2,
                 heads: 16,
                 sectors_per_track: 63,
                 sectors: 145,
                 bytes_per_sector: 512,
+                fixed_disk_parameter_table_address: None,
                 fixed_disk_parameter_table: None,
                 device_path_information: Some(DevicePathInformation {
                     host_bus: edd::HostBus::Pci {
@@ -792,12 +872,14 @@ mod tests {
         assert_eq!(
             edd::DriveParameters {
                 buffer_size: 30,
+                version: edd::Version::V2_0,
                 information_flags: edd::InfoFlags { bits: 2 },
                 cylinders: 1,
                 heads: 1,
                 sectors_per_track: 18,
                 sectors: 145,
                 bytes_per_sector: 512,
+                fixed_disk_parameter_table_address: None,
                 fixed_disk_parameter_table: None,
                 device_path_information: Some(DevicePathInformation {
                     host_bus: edd::HostBus::Isa {
@@ -810,6 +892,80 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_versions() {
+        let with_size = |buffer_size: u16| {
+            let mut bytes = QEMU_DRIVE_PARAMETERS_BYTES;
+            bytes[..2].copy_from_slice(&buffer_size.to_le_bytes());
+            bytes
+        };
+        // The full 3.0 structure, and a longer one
+        for buffer_size in [66, 74] {
+            let drive_parameters = edd::DriveParameters::try_from(&with_size(buffer_size)[..]);
+            let drive_parameters = drive_parameters.unwrap();
+            assert_eq!(edd::Version::V3_0, drive_parameters.version);
+            assert!(drive_parameters.device_path_information.is_some());
+        }
+        // A 1.1 structure has no fixed disk parameter table pointer to follow, whatever comes
+        // after the bytes per sector
+        let mut bytes = with_size(26);
+        bytes[26..30].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        let drive_parameters = edd::DriveParameters::try_from(&bytes[..]).unwrap();
+        assert_eq!(edd::Version::V1_1, drive_parameters.version);
+        assert!(
+            drive_parameters
+                .fixed_disk_parameter_table_address
+                .is_none()
+        );
+        // From 2.0 on it's there, and kept for resolve_fdbt rather than read while parsing
+        let mut bytes = with_size(30);
+        bytes[26..30].copy_from_slice(&0x9fc0_0022u32.to_le_bytes());
+        let drive_parameters = edd::DriveParameters::try_from(&bytes[..]).unwrap();
+        assert_eq!(
+            Some(0x9fc0 * 16 + 0x22),
+            drive_parameters.fixed_disk_parameter_table_address
+        );
+        assert!(drive_parameters.fixed_disk_parameter_table.is_none());
+        // Too short for any version
+        assert!(edd::DriveParameters::try_from(&with_size(20)[..]).is_err());
+    }
+
+    #[test]
+    fn test_bios_drives() {
+        // Booted from 0x81, with 0x80 and 0x82 there too, 0x83 missing and 0x84 garbled
+        let mut table = [0u8; 2 + 5 * edd::DRIVE_PARAMETERS_BUFFER_SIZE];
+        table[0] = 0x81;
+        for (i, slot) in table[2..]
+            .chunks_exact_mut(edd::DRIVE_PARAMETERS_BUFFER_SIZE)
+            .enumerate()
+        {
+            match i {
+                0 | 2 => slot.copy_from_slice(&QEMU_DRIVE_PARAMETERS_BYTES),
+                1 => slot.copy_from_slice(&BOCHS_DRIVE_PARAMETERS_BYTES),
+                4 => slot[..2].copy_from_slice(&[0x4, 0x0]),
+                _ => {}
+            }
+        }
+        assert_eq!(Some(0x81), edd::boot_drive_number(&table));
+        let drives: Vec<_> = edd::bios_drives(&table).collect();
+        assert_eq!(
+            vec![0x80, 0x81, 0x82, 0x84],
+            drives.iter().map(|(number, _)| *number).collect::<Vec<_>>()
+        );
+        let boot_drive = drives[1].1.as_ref().unwrap().bios_drive(0x81);
+        assert_eq!(
+            (0x81, 145, 512, 2),
+            (
+                boot_drive.number,
+                boot_drive.sectors,
+                boot_drive.bytes_per_sector,
+                boot_drive.information_flags
+            )
+        );
+        assert!(drives[3].1.is_err());
+        assert_eq!(0, edd::bios_drives(&[]).count());
+    }
+
     #[test]
     fn test_parse_fdpt() {
         let qemu_fdpt = edd::FixedDiskParameterTable::try_from(&QEMU_FDPT_BYTES[..]).unwrap();
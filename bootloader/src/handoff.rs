@@ -0,0 +1,316 @@
+// The jump from stage2 to the kernel. stage2 runs in 32-bit protected mode, and either stays there
+// for a 32-bit kernel, or turns long mode on for a 64-bit one: PAE in CR4, the page tables in CR3,
+// LME in EFER and finally paging in CR0, after which the far return lands in the kernel's 64-bit
+// code segment. Either way the kernel gets the boot info's address in EDI, and 32-bit kernels also
+// get it on the stack, as the cdecl argument of their entrypoint
+#[cfg(target_os = "none")]
+use core::arch::asm;
+
+#[cfg(target_os = "none")]
+use common::msr::{Msr, wrmsr};
+use common::{
+    control_registers::{
+        ControlRegister0, ControlRegister0Bit, ControlRegister3, ControlRegister4,
+        ControlRegister4Bit, ExtendedFeatureEnableRegister, ExtendedFeatureEnableRegisterBit,
+    },
+    error::{Context, Error, Facility, Fault},
+};
+
+// The kernel's stack has to be aligned for the System V and cdecl ABIs
+const STACK_ALIGNMENT: u32 = 16;
+
+/// The mode the kernel runs in, with what it takes to switch to it
+#[derive(Debug, Clone, Copy)]
+pub enum Mode {
+    Protected,
+    Long {
+        cr0: ControlRegister0,
+        cr3: ControlRegister3,
+        cr4: ControlRegister4,
+        efer: ExtendedFeatureEnableRegister,
+    },
+}
+
+/// Where the kernel starts, and what it starts with
+#[derive(Debug, Clone, Copy)]
+pub struct HandoffParameters {
+    pub mode: Mode,
+    pub entrypoint: u32,
+    pub stack_pointer: u32,
+    /// Selectors of the GDT stage2 loaded, of segments for `mode`
+    pub code_selector: u16,
+    pub data_selector: u16,
+    pub boot_info: u32,
+}
+
+/// Handoff parameters that passed the checks of `Handoff::new`, ready to jump with
+#[derive(Debug)]
+pub struct Handoff(HandoffParameters);
+
+fn error(reason: &'static str) -> Error {
+    Error::new(
+        Fault::InvalidHandoff(reason),
+        Context::PreparingForJumpToKernel,
+        Facility::Bootloader,
+    )
+}
+
+/// A selector of a ring 0 segment of the GDT, past the null descriptor
+fn is_kernel_gdt_selector(selector: u16) -> bool {
+    // The requested privilege level is in bits 0-1, and bit 2 selects the LDT
+    selector >= 8 && selector & 0b111 == 0
+}
+
+impl Handoff {
+    /// Checks what the jump relies on, as once it's taken a mistake is a triple fault rather than
+    /// an error
+    pub fn new(parameters: HandoffParameters) -> Result<Self, Error> {
+        if parameters.entrypoint == 0 {
+            return Err(error("null entrypoint"));
+        }
+        if parameters.stack_pointer == 0
+            || !parameters.stack_pointer.is_multiple_of(STACK_ALIGNMENT)
+        {
+            return Err(error("misaligned stack"));
+        }
+        if !is_kernel_gdt_selector(parameters.code_selector)
+            || !is_kernel_gdt_selector(parameters.data_selector)
+        {
+            return Err(error("not a ring 0 GDT selector"));
+        }
+        if parameters.boot_info == 0 {
+            return Err(error("null boot info"));
+        }
+        if let Mode::Long {
+            cr0,
+            cr3,
+            cr4,
+            efer,
+        } = parameters.mode
+        {
+            if !cr0.is_set(ControlRegister0Bit::ProtectedMode)
+                || !cr0.is_set(ControlRegister0Bit::Paging)
+            {
+                return Err(error("long mode without paging"));
+            }
+            if !cr4.is_set(ControlRegister4Bit::PhysicalAddressExtensions) {
+                return Err(error("long mode without PAE"));
+            }
+            if !efer.is_set(ExtendedFeatureEnableRegisterBit::IA32eEnabled) {
+                return Err(error("long mode not enabled in EFER"));
+            }
+            if cr3.pml4_physical_address() == 0 {
+                return Err(error("no PML4"));
+            }
+        }
+        Ok(Self(parameters))
+    }
+
+    /// Jumps to the kernel, for good
+    #[cfg(target_os = "none")]
+    pub fn jump(self) -> ! {
+        let parameters = self.0;
+        // SAFETY: Handoff::new checked the selector to be a ring 0 GDT one, which stage2 set up
+        // as a flat data segment, so the stack and the data stay where they are
+        unsafe {
+            asm!(
+              "mov ds, {data_selector:x}",
+              "mov es, {data_selector:x}",
+              "mov fs, {data_selector:x}",
+              "mov gs, {data_selector:x}",
+              "mov ss, {data_selector:x}",
+              data_selector = in(reg) parameters.data_selector,
+            )
+        }
+        match parameters.mode {
+            // SAFETY: Handoff::new checked the code selector to be a ring 0 GDT one, which stage2
+            // set up for 32-bit code, and the entrypoint and the stack, which the kernel was
+            // loaded for. Paging stays off, as the kernel was loaded at its addresses below 4GB
+            Mode::Protected => unsafe {
+                asm!(
+                  "mov esp, {stack_pointer:e}",
+                  // cdecl: the argument, then the return address, which there's none of
+                  "push edi",
+                  "push 0",
+                  "push {code_selector:e}",
+                  "push {entrypoint:e}",
+                  "retf",
+                  stack_pointer = in(reg) parameters.stack_pointer,
+                  code_selector = in(reg) u32::from(parameters.code_selector),
+                  entrypoint = in(reg) parameters.entrypoint,
+                  in("edi") parameters.boot_info,
+                  options(noreturn),
+                )
+            },
+            Mode::Long {
+                cr0,
+                cr3,
+                cr4,
+                efer,
+            } => {
+                // SAFETY: Handoff::new checked that CR4 enables PAE and that CR3 points to a PML4,
+                // which stage2 set up. Neither takes effect before paging is turned on below
+                unsafe {
+                    asm!(
+                      "mov cr4, {cr4:e}",
+                      "mov cr3, {cr3:e}",
+                      cr4 = in(reg) u32::from(cr4),
+                      cr3 = in(reg) u64::from(cr3) as u32,
+                    )
+                }
+                wrmsr(&Msr::Efer(efer));
+                // SAFETY: Handoff::new checked that CR0 turns paging on, which with the above
+                // enables long mode, the page tables identity mapping this code, and the code
+                // selector to be a ring 0 GDT one, which stage2 set up for 64-bit code. The
+                // entrypoint and the stack are the ones the kernel was loaded for
+                unsafe {
+                    asm!(
+                      "mov cr0, {cr0:e}",
+                      "mov esp, {stack_pointer:e}",
+                      "push {code_selector:e}",
+                      "push {entrypoint:e}",
+                      "retf",
+                      cr0 = in(reg) u32::from(cr0),
+                      stack_pointer = in(reg) parameters.stack_pointer,
+                      code_selector = in(reg) u32::from(parameters.code_selector),
+                      entrypoint = in(reg) parameters.entrypoint,
+                      in("edi") parameters.boot_info,
+                      options(noreturn),
+                    )
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::control_registers::{
+        ControlRegister0, ControlRegister0Bit::*, ControlRegister3, ControlRegister4,
+        ControlRegister4Bit::*, ExtendedFeatureEnableRegister, ExtendedFeatureEnableRegisterBit::*,
+    };
+    use common::error::Fault;
+
+    use crate::handoff::{Handoff, HandoffParameters, Mode};
+
+    fn long_mode() -> Mode {
+        Mode::Long {
+            cr0: ProtectedMode | Paging,
+            cr3: ControlRegister3::from(0x1000u64),
+            cr4: PhysicalAddressExtensions | PhysicalSizeExtensions,
+            efer: IA32eEnabled.into(),
+        }
+    }
+
+    fn parameters(mode: Mode) -> HandoffParameters {
+        HandoffParameters {
+            mode,
+            entrypoint: 0x20_0000,
+            stack_pointer: 0x30_0000,
+            code_selector: 0x18,
+            data_selector: 0x20,
+            boot_info: 0x9000,
+        }
+    }
+
+    fn reason(parameters: HandoffParameters) -> &'static str {
+        match Handoff::new(parameters).map_err(|err| err.fault()) {
+            Err(Fault::InvalidHandoff(reason)) => reason,
+            _ => panic!("the handoff was accepted"),
+        }
+    }
+
+    #[test]
+    fn accepts_both_modes() {
+        assert!(Handoff::new(parameters(long_mode())).is_ok());
+        assert!(Handoff::new(parameters(Mode::Protected)).is_ok());
+    }
+
+    #[test]
+    fn sanity_checks() {
+        let valid = parameters(long_mode());
+        assert_eq!(
+            "null entrypoint",
+            reason(HandoffParameters {
+                entrypoint: 0,
+                ..valid
+            })
+        );
+        assert_eq!(
+            "misaligned stack",
+            reason(HandoffParameters {
+                stack_pointer: 0x30_0008,
+                ..valid
+            })
+        );
+        // The null selector, an LDT one and a ring 3 one
+        for code_selector in [0, 0x1c, 0x1b] {
+            assert_eq!(
+                "not a ring 0 GDT selector",
+                reason(HandoffParameters {
+                    code_selector,
+                    ..valid
+                })
+            );
+        }
+        assert_eq!(
+            "null boot info",
+            reason(HandoffParameters {
+                boot_info: 0,
+                ..valid
+            })
+        );
+    }
+
+    #[test]
+    fn long_mode_checks() {
+        let with_long_mode = |cr0: ControlRegister0,
+                              cr3: ControlRegister3,
+                              cr4: ControlRegister4,
+                              efer: ExtendedFeatureEnableRegister| {
+            reason(parameters(Mode::Long {
+                cr0,
+                cr3,
+                cr4,
+                efer,
+            }))
+        };
+        let cr3 = ControlRegister3::from(0x1000u64);
+        assert_eq!(
+            "long mode without paging",
+            with_long_mode(
+                ProtectedMode.into(),
+                cr3,
+                PhysicalAddressExtensions.into(),
+                IA32eEnabled.into()
+            )
+        );
+        assert_eq!(
+            "long mode without PAE",
+            with_long_mode(
+                ProtectedMode | Paging,
+                cr3,
+                PhysicalSizeExtensions.into(),
+                IA32eEnabled.into()
+            )
+        );
+        assert_eq!(
+            "long mode not enabled in EFER",
+            with_long_mode(
+                ProtectedMode | Paging,
+                cr3,
+                PhysicalAddressExtensions.into(),
+                ExtendedFeatureEnableRegister::empty()
+            )
+        );
+        assert_eq!(
+            "no PML4",
+            with_long_mode(
+                ProtectedMode | Paging,
+                ControlRegister3::empty(),
+                PhysicalAddressExtensions.into(),
+                IA32eEnabled.into()
+            )
+        );
+    }
+}
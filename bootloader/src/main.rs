@@ -7,34 +7,80 @@
 #![forbid(clippy::undocumented_unsafe_blocks)]
 
 use common::elf::program_header::ProgramHeaderEntryType;
-use core::arch::{asm, naked_asm};
+use core::{
+    arch::{asm, naked_asm},
+    ops::Range,
+};
 
+mod backtrace;
+mod boot_stage;
 mod edd;
+mod handoff;
+mod memory_map;
+mod watchdog;
 
+#[cfg(target_os = "none")]
+use common::{bios, command_line::CommandLine, panicking, tunables};
 #[cfg(target_os = "none")]
 use core::panic::PanicInfo;
 
 use common::{
     ata,
+    block_device::BlockDevice,
+    boot_info::{BootInfo, DriveInfo, LoadedPayload},
     control_registers::{
-        self, ControlRegister0, ControlRegister3, ControlRegister4, ExtendedFeatureEnableRegister,
+        self, ControlRegister0, ControlRegister3, ControlRegister4, Cr2, Cr3,
+        ExtendedFeatureEnableRegister,
+    },
+    e820,
+    elf::{
+        self,
+        header::{Class, Machine, ObjectType},
+        program_header,
+        relocation::{RelocationEntry, RelocationType},
     },
-    elf::{self},
     error::{self, Context, Error, Facility, Fault},
     gdt::{self, SegmentDescriptor},
-    idt,
+    hexdump::HexDump,
+    idt, layout, log,
+    metrics::{self, Counter},
     paging::{self},
-    pci, serial, tss, vga,
+    payload::{PayloadEntry, PayloadKind, PayloadTable},
+    pic, protection, random, serial, tss, vga,
 };
 
-use crate::edd::DRIVE_PARAMETERS_BUFFER_SIZE;
+#[cfg(all(target_os = "none", any(feature = "usb-probe", feature = "pci-report")))]
+use common::pci_function::Function;
+
+use crate::{
+    boot_stage::BootStage,
+    edd::DRIVE_PARAMETERS_BUFFER_SIZE,
+    handoff::{Handoff, HandoffParameters, Mode},
+};
+
+/// Logs how a step went, unless stage2 is built without the `verbose-console` feature. The
+/// arguments are still type checked, but the lines and their formatting are left out of stage2
+#[cfg(target_os = "none")]
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "verbose-console") {
+            log::info_no_sync!($($arg)*);
+        }
+    };
+}
 
 /// This function is called on panic.
 #[cfg(target_os = "none")]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    if !panicking::enter_no_sync() {
+        panicking::write_raw("stage2 panicked while handling a panic\n");
+        panicking::halt_no_sync();
+    }
+    vga::writeln_no_sync!("Panicked during boot stage '{}'", boot_stage::current());
     vga::writeln_no_sync!("{info:#?}");
-    loop {}
+    serial::writeln_no_sync!("stage2 {info}");
+    panicking::halt_no_sync()
 }
 
 #[unsafe(no_mangle)]
@@ -46,135 +92,162 @@ fn panic(info: &PanicInfo) -> ! {
 pub extern "cdecl" fn start(
     drive_parameters_pointer: *const u8,
     stage2_sectors: u32,
-    kernel_sectors: u32,
     stack_start: u32,
-    _edd_version: u32,
-    _extensions_bitmap: u32,
+    edd_version: u32,
+    extensions_bitmap: u32,
 ) -> ! {
-    use common::control_registers::{Msr, wrmsr};
-
     vga::writeln_no_sync!("Hello from stage2!");
+    // Both are ready to use as the BIOS left them
+    log::start_sinks_no_sync();
+    progress!(
+        "int 13h extensions: {}",
+        bios::Extensions::from_handoff(edd_version, extensions_bitmap)
+    );
 
-    let initialization_parameters = init(
-        drive_parameters_pointer,
-        stage2_sectors,
-        kernel_sectors,
-        stack_start,
-    )
-    .inspect_err(|err| {
-        error::push_to_global_error_chain_no_sync(*err);
-        error::push_to_global_error_chain_no_sync(Error::new(
-            Fault::KernelInitialization,
-            Context::PreparingForJumpToKernel,
-            Facility::Bootloader,
-        ));
-        vga::writeln_no_sync!("{:}", error::get_global_error_chain_no_sync());
-        serial::writeln_no_sync!("{:#}", error::get_global_error_chain_no_sync());
-    })
-    .expect("failed initializing the kernel");
-
-    // SAFETY: A valid page table was set up in setup_page_tables, and cr3 was loaded with its
-    // address in setup_control_regsiters.
-    // cr4 was set up in setup_control_regsiters with the PAE and PSE flags enabled The following
-    // assembly is necessary to set the values of the control registers, and because of the reason
-    // above is safe
-    unsafe {
-        asm!(
-          "mov cr4, {cr4:e}",
-          "mov cr3, {cr3:e}",
-          cr4 = in(reg) u32::from(initialization_parameters.cr4),
-          cr3 = in(reg) u64::from(initialization_parameters.cr3) as u32,
-        );
-    }
-
-    wrmsr(&Msr::Efer(initialization_parameters.efer));
+    setup_debug_interrupt_descriptor_table();
+    watchdog::arm();
+    boot_stage::enter(BootStage::Stage1Handoff);
 
-    // SAFETY: Cr0 was set to enable paging and protected mode
-    // The GDT was set up by setup_global_descriptor_table
-    // A stack pointer of ~1MB was set up above
-    // We need some assembly to set CR0, set the stack, and far jump to the kernel entrypoint, and
-    // because of the reasons above, this is safe
-    unsafe {
-        asm!(
-          "mov cr0, {cr0:e}",
-          "mov esp, {stack_pointer:e}",
-          // Code selector
-          "push {code_selector}",
-          "push {kernel_entrypoint}",
-          "retf",
-          cr0 = in(reg) u32::from(initialization_parameters.cr0),
-          out("ax") _,
-          kernel_entrypoint = in(reg) initialization_parameters.kernel_entrypoint as u32,
-          stack_pointer = in(reg) initialization_parameters.stack_pointer,
-          code_selector = in(reg) initialization_parameters.code_selector,
-        )
-    }
+    let handoff = init(drive_parameters_pointer, stage2_sectors, stack_start);
+    watchdog::disarm();
 
-    panic!("We didn't load the kernel?");
-}
-
-struct InitializationParameters {
-    kernel_entrypoint: u32,
-    cr0: ControlRegister0,
-    cr3: ControlRegister3,
-    cr4: ControlRegister4,
-    efer: ExtendedFeatureEnableRegister,
-    stack_pointer: u32,
-    code_selector: usize,
+    handoff
+        .inspect_err(|err| {
+            error::push_to_global_error_chain_no_sync(*err);
+            error::push_to_global_error_chain_no_sync(boot_stage::failed());
+            vga::writeln_no_sync!("{:}", error::get_global_error_chain_no_sync());
+            serial::writeln_no_sync!("{:#}", error::get_global_error_chain_no_sync());
+        })
+        .expect("failed initializing the kernel")
+        .jump()
 }
 
 #[cfg(target_os = "none")]
 fn init(
     drive_parameters_pointer: *const u8,
     stage2_sectors: u32,
-    kernel_sectors: u32,
     stack_start: u32,
-) -> Result<InitializationParameters, Error> {
-    let kernel = load_kernel_from_boot_disk(
-        drive_parameters_pointer,
-        stage2_sectors,
-        kernel_sectors,
-        stack_start,
-    )?;
+) -> Result<Handoff, Error> {
+    boot_stage::enter(BootStage::DriveProbe);
+    let (kernel, mut boot_device, payloads) =
+        load_kernel_from_boot_disk(drive_parameters_pointer, stage2_sectors, stack_start)?;
 
-    vga::writeln_no_sync!("Read kernel from disk!");
+    progress!("Read kernel from disk!");
 
-    let Ok(kernel_entrypoint) = u32::try_from(kernel.header().entrypoint()) else {
-        return Err(Error::new(
-            Fault::KernelEntrypointAbove4G,
-            Context::PreparingForJumpToKernel,
-            Facility::Bootloader,
-        ));
-    };
+    let kernel_slide = choose_kernel_slide(&kernel);
+    let (kernel_entrypoint, stack_pointer) =
+        kernel_entrypoint_and_stack_pointer(&kernel, kernel_slide)?;
 
-    // FIXME: what if the size of all statics in the kernel gets larger that 1MB? One should
-    // probably find the highest address mapped for the kernel, and add 1MB to that
-    // 1MB of stack + heap should be enough for the first stage of the kernel, right?
-    let Some(stack_pointer) = kernel_entrypoint.checked_next_multiple_of(0x100000) else {
-        return Err(Error::new(
-            Fault::KernelEntrypointTooHigh,
-            Context::PreparingForJumpToKernel,
-            Facility::Bootloader,
-        ));
-    };
+    boot_stage::enter(BootStage::SegmentLoad);
+    let kernel_range = metrics::measure(Counter::SegmentCopy, || {
+        load_segments_into_memory(&kernel, kernel_slide)
+    })?;
+    progress!("Loaded kernel segments into memory!");
 
-    load_segments_into_memory(&kernel)?;
-    vga::writeln_no_sync!("Loaded kernel segments into memory!");
+    boot_stage::enter(BootStage::Relocation);
+    metrics::measure(Counter::Relocation, || {
+        relocate_kernel(&kernel, kernel_slide, &kernel_range)
+    })?;
+    if kernel_slide != 0 {
+        progress!("Relocated kernel by {:#x}", kernel_slide);
+    }
 
-    setup_page_tables()?;
+    let boot_info_ptr = &raw mut BOOT_INFO;
+    // SAFETY: This is safe because we are in the bootloader and no other threads are running.
+    let boot_info = unsafe { &mut *boot_info_ptr };
+    // The catalogs are private, so no struct update syntax from here
+    *boot_info = BootInfo::empty();
+    boot_info.kernel_slide = kernel_slide;
+    boot_info.kernel_start = kernel_range.start;
+    boot_info.kernel_end = kernel_range.end;
+    copy_memory_map(boot_info);
+    progress!("BIOS memory map: {} entries", boot_info.memory_map().len());
+
+    boot_stage::enter(BootStage::PayloadLoad);
+    load_other_payloads(
+        &mut boot_device,
+        &payloads,
+        kernel_range.end.max(stack_pointer.into()),
+        boot_info,
+    )?;
+    for payload in boot_info.payloads() {
+        progress!("Loaded {}", payload);
+    }
+    // `panic=exit` and the tunables are for stage2 as much as for the kernel
+    if let Some(payload) = boot_info.payload(PayloadKind::CommandLine) {
+        // SAFETY: The payload was just loaded in identity mapped memory below 4GB, see
+        // load_other_payloads
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                payload.address as usize as *const u8,
+                payload.size as usize,
+            )
+        };
+        let command_line = CommandLine::from_payload(bytes);
+        if command_line.get("panic") == Some("exit") {
+            panicking::set_exit_qemu_no_sync(true);
+        }
+        let mut tunables = tunables::get();
+        tunables.apply_command_line(&command_line, |name, value| {
+            log::warn_no_sync!(
+                "{}={} isn't a valid value, keeping the default",
+                name,
+                value
+            )
+        });
+        tunables::set_no_sync(tunables);
+    }
+
+    boot_stage::enter(BootStage::DriveCatalog);
+    catalog_drives(boot_info, &boot_device);
+    catalog_bios_drives(boot_info);
+    #[cfg(feature = "pci-report")]
+    {
+        boot_stage::enter(BootStage::PciReport);
+        report_pci_devices();
+    }
 
+    boot_stage::enter(BootStage::Paging);
+    metrics::measure(Counter::PageTableSetup, setup_page_tables)?;
+    // Nothing is measured past this point
+    boot_info.metrics = metrics::boot_metrics();
+
+    boot_stage::enter(BootStage::Gdt);
     setup_global_descriptor_table()?;
 
-    let (cr0, cr3, cr4, efer) = setup_control_registers()?;
+    boot_stage::enter(BootStage::ControlRegs);
+    let (mode, code_segment, data_segment) = match kernel.header().class() {
+        Class::Elf64 => {
+            let (cr0, cr3, cr4, efer) = setup_control_registers()?;
+            (
+                Mode::Long {
+                    cr0,
+                    cr3,
+                    cr4,
+                    efer,
+                },
+                GDTI_64_BIT_CODE_SEGMENT,
+                GDTI_64_BIT_DATA_SEGMENT,
+            )
+        }
+        Class::Elf32 => (
+            Mode::Protected,
+            GDTI_32_BIT_CODE_SEGMENT,
+            GDTI_32_BIT_DATA_SEGMENT,
+        ),
+    };
 
-    Ok(InitializationParameters {
-        kernel_entrypoint,
-        cr0,
-        cr3,
-        cr4,
-        efer,
+    boot_stage::enter(BootStage::Jump);
+    // Whatever went wrong on the way that didn't stop the boot, for the kernel to log
+    boot_info.set_boot_errors(error::get_boot_warnings_no_sync());
+    let selector = |index: usize| (index * size_of::<gdt::SegmentDescriptor>()) as u16;
+    Handoff::new(HandoffParameters {
+        mode,
+        entrypoint: kernel_entrypoint,
         stack_pointer,
-        code_selector: GDTI_64_BIT_CODE_SEGMENT * size_of::<gdt::SegmentDescriptor>(),
+        code_selector: selector(code_segment),
+        data_selector: selector(data_segment),
+        boot_info: &raw const BOOT_INFO as u32,
     })
 }
 
@@ -223,6 +296,7 @@ const GDTI_TSS: usize = 5;
 /// exceed u16 (likely programming errors)
 fn setup_global_descriptor_table() -> Result<(), Error> {
     use gdt::SegmentKind::*;
+    use protection::PrivilegeLevel::*;
     macro_rules! update_gdt {
         ($gdt:ident[$gdt_index:expr] => $segment_decriptor:expr) => {
             // SAFETY: This is safe because we are in the bootloader and no other threads are
@@ -241,19 +315,19 @@ fn setup_global_descriptor_table() -> Result<(), Error> {
 
     update_gdt!(
         GLOBAL_DESCRIPTOR_TABLE[GDTI_32_BIT_CODE_SEGMENT] =>
-            SegmentDescriptor::new_flat(Code, false)
+            SegmentDescriptor::new_flat(Code, false, Ring0)
     );
     update_gdt!(
         GLOBAL_DESCRIPTOR_TABLE[GDTI_32_BIT_DATA_SEGMENT] =>
-            SegmentDescriptor::new_flat(Data, false)
+            SegmentDescriptor::new_flat(Data, false, Ring0)
     );
     update_gdt!(
         GLOBAL_DESCRIPTOR_TABLE[GDTI_64_BIT_CODE_SEGMENT] =>
-            SegmentDescriptor::new_flat(Code, true)
+            SegmentDescriptor::new_flat(Code, true, Ring0)
     );
     update_gdt!(
         GLOBAL_DESCRIPTOR_TABLE[GDTI_64_BIT_DATA_SEGMENT] =>
-            SegmentDescriptor::new_flat(Data, true)
+            SegmentDescriptor::new_flat(Data, true, Ring0)
     );
 
     update_static_mut!(TASK_STATE_SEGMENT =>
@@ -299,7 +373,14 @@ fn setup_global_descriptor_table() -> Result<(), Error> {
 static mut INTERRUPT_DESCRIPTOR_TABLE: idt::IDT<{ idt::STANDARD_VECTOR_TABLE_SIZE }> =
     [idt::GateDescriptor::blank(); _];
 
+const GP_STACK_DUMP_SIZE: usize = 64;
+const GP_CODE_DUMP_SIZE: usize = 32;
+// What general_protection_stub pushes
+const GP_PUSHED_REGISTERS: usize = 7;
+
+/// `frame_pointer` is where the stub left ESP after pushing the registers
 extern "cdecl" fn general_protection_handler(
+    frame_pointer: u32,
     ebp: u32,
     edi: u32,
     esi: u32,
@@ -312,13 +393,8 @@ extern "cdecl" fn general_protection_handler(
     cs: u32,
     eflags: u32,
 ) {
-    let cr2: u32;
-    let cr3: u32;
-
-    // SAFETY: This is safe because we are only reading the registers to print them out.
-    unsafe {
-        asm!("mov {cr2}, cr2", "mov {cr3}, cr3", cr2 = out(reg) cr2, cr3 = out(reg) cr3);
-    }
+    let cr2 = u64::from(Cr2::read());
+    let cr3 = u64::from(Cr3::read());
 
     vga::writeln_no_sync!("General Protection Fault!");
     vga::writeln_no_sync!(
@@ -337,6 +413,29 @@ extern "cdecl" fn general_protection_handler(
         error_code
     );
     vga::writeln_no_sync!("CR2={:08X} CR3={:08X}", cr2, cr3);
+
+    // No privilege change happens in the bootloader, so the CPU pushed EFLAGS, CS, EIP and the
+    // error code on the interrupted stack, right above the registers the stub pushed
+    let interrupted_stack_pointer =
+        frame_pointer as usize + (GP_PUSHED_REGISTERS + 4) * size_of::<u32>();
+    // SAFETY: The interrupted stack is mapped and at least as large as a stack dump, as we are
+    // running on it
+    let stack = unsafe {
+        core::slice::from_raw_parts(interrupted_stack_pointer as *const u8, GP_STACK_DUMP_SIZE)
+    };
+    let code_start = (eip as usize).saturating_sub(GP_CODE_DUMP_SIZE / 2);
+    // SAFETY: Paging is disabled in the bootloader, so all memory below 4GB can be read, and the
+    // code around EIP is part of the bootloader image
+    let code = unsafe { core::slice::from_raw_parts(code_start as *const u8, GP_CODE_DUMP_SIZE) };
+
+    vga::writeln_no_sync!("Stack:");
+    vga::writeln_no_sync!("{}", HexDump::new(interrupted_stack_pointer as u64, stack));
+    vga::writeln_no_sync!("Code around EIP:");
+    vga::writeln_no_sync!("{}", HexDump::new(code_start as u64, code));
+    serial::writeln_no_sync!("General Protection Fault at EIP={:08X}", eip);
+    serial::writeln_no_sync!("{}", HexDump::new(interrupted_stack_pointer as u64, stack));
+    serial::writeln_no_sync!("{}", HexDump::new(code_start as u64, code));
+    backtrace::print_no_sync(eip, ebp);
     loop {}
 }
 
@@ -344,30 +443,189 @@ extern "cdecl" fn general_protection_handler(
 extern "C" fn general_protection_stub() {
     naked_asm!(
         "push eax", "push ebx", "push ecx", "push edx", "push esi", "push edi", "push ebp",
+        "push esp",                 // the frame pointer, ESP before this push
         "call {handler}",
+        "add esp, 4",
         "pop ebp", "pop edi", "pop esi", "pop edx", "pop ecx", "pop ebx", "pop eax",
         "add esp, 8",                // discard error_code (we handled it)
         "hlt", handler = sym general_protection_handler,
     );
 }
 
-fn setup_debug_interrupt_descriptor_table() {
+extern "cdecl" fn page_fault_handler(
+    ebp: u32,
+    edi: u32,
+    esi: u32,
+    edx: u32,
+    ecx: u32,
+    ebx: u32,
+    eax: u32,
+    error_code: u32,
+    eip: u32,
+    cs: u32,
+    eflags: u32,
+) {
+    let cr2 = u64::from(Cr2::read());
+    let cr3 = u64::from(Cr3::read());
+
+    let error_code = paging::PageFaultErrorCode::from(error_code);
+    // The bootloader's page tables identity map the first GB, and live in it
+    let page_walk = paging::PageWalk::new(cr3, cr2, 0);
+
+    vga::writeln_no_sync!("Page Fault!");
+    vga::writeln_no_sync!("{} at {:#x}", error_code.describe(), cr2);
+    vga::writeln_no_sync!(
+        "EAX={:08X} EBX={:08X} ECX={:08X} EDX={:08X}",
+        eax,
+        ebx,
+        ecx,
+        edx
+    );
+    vga::writeln_no_sync!("ESI={:08X} EDI={:08X} EBP={:08X}", esi, edi, ebp);
+    vga::writeln_no_sync!(
+        "EIP={:08X} CS={:08X} EFLAGS={:08X} ERROR_CODE={:08X} ({})",
+        eip,
+        cs,
+        eflags,
+        u32::from(error_code),
+        error_code
+    );
+    vga::writeln_no_sync!("CR2={:08X} CR3={:08X}", cr2, cr3);
+    vga::writeln_no_sync!("{}", page_walk);
+    serial::writeln_no_sync!(
+        "Page Fault: {} at {:#x}, EIP={:#x}, error code {:#x} ({})",
+        error_code.describe(),
+        cr2,
+        eip,
+        u32::from(error_code),
+        error_code
+    );
+    serial::writeln_no_sync!("{}", page_walk);
+    loop {}
+}
+
+#[unsafe(naked)]
+extern "C" fn page_fault_stub() {
+    naked_asm!(
+        "push eax", "push ebx", "push ecx", "push edx", "push esi", "push edi", "push ebp",
+        "call {handler}",
+        "pop ebp", "pop edi", "pop esi", "pop edx", "pop ecx", "pop ebx", "pop eax",
+        "add esp, 8",                // discard error_code (we handled it)
+        "hlt", handler = sym page_fault_handler,
+    );
+}
+
+extern "cdecl" fn exception_handler(
+    ebp: u32,
+    edi: u32,
+    esi: u32,
+    edx: u32,
+    ecx: u32,
+    ebx: u32,
+    eax: u32,
+    vector: u32,
+    error_code: u32,
+    eip: u32,
+    cs: u32,
+    eflags: u32,
+) -> ! {
+    let cr2 = u64::from(Cr2::read());
+    let cr3 = u64::from(Cr3::read());
+    let mnemonic = idt::exception_mnemonic(vector as u8).unwrap_or("Reserved exception");
+
+    vga::writeln_no_sync!("{} (vector {})!", mnemonic, vector);
+    vga::writeln_no_sync!(
+        "EAX={:08X} EBX={:08X} ECX={:08X} EDX={:08X}",
+        eax,
+        ebx,
+        ecx,
+        edx
+    );
+    vga::writeln_no_sync!("ESI={:08X} EDI={:08X} EBP={:08X}", esi, edi, ebp);
+    vga::writeln_no_sync!(
+        "EIP={:08X} CS={:08X} EFLAGS={:08X} ERROR_CODE={:08X}",
+        eip,
+        cs,
+        eflags,
+        error_code
+    );
+    vga::writeln_no_sync!("CR2={:08X} CR3={:08X}", cr2, cr3);
+    serial::writeln_no_sync!(
+        "{} (vector {}) at EIP={:08X}, error code {:#x}",
+        mnemonic,
+        vector,
+        eip,
+        error_code
+    );
+    backtrace::print_no_sync(eip, ebp);
+    loop {}
+}
+
+/// One stub per exception vector, all sharing `exception_handler`. The stubs of the exceptions
+/// the CPU pushes no error code for push a 0 in its place, so that the stack looks the same
+macro_rules! exception_stubs {
+    ($($vector:literal $($error_code:ident)?),* $(,)?) => {
+        [$({
+            #[unsafe(naked)]
+            extern "C" fn stub() {
+                naked_asm!(
+                    exception_stubs!(@error_code $($error_code)?),
+                    "push {vector}",
+                    "push eax", "push ebx", "push ecx", "push edx", "push esi", "push edi", "push ebp",
+                    // Never returns, there's nothing to go back to this early
+                    "call {handler}",
+                    vector = const $vector,
+                    handler = sym exception_handler,
+                );
+            }
+            stub as extern "C" fn()
+        }),*]
+    };
+    (@error_code error_code) => { "" };
+    (@error_code) => { "push 0" };
+}
+
+const EXCEPTION_VECTORS: usize = 32;
+
+const EXCEPTION_STUBS: [extern "C" fn(); EXCEPTION_VECTORS] = exception_stubs![
+    0, 1, 2, 3, 4, 5, 6, 7, 8 error_code, 9, 10 error_code, 11 error_code, 12 error_code,
+    13 error_code, 14 error_code, 15, 16, 17 error_code, 18, 19, 20, 21 error_code, 22, 23, 24,
+    25, 26, 27, 28, 29 error_code, 30 error_code, 31,
+];
+
+fn set_interrupt_gate(vector: usize, stub: extern "C" fn()) {
     let idt_ptr = &raw mut INTERRUPT_DESCRIPTOR_TABLE;
     // SAFETY: This is safe because we are in the bootloader and no other threads are running.
-    let gp_descriptor = unsafe { &mut (*idt_ptr)[idt::Interrupt::GeneralProtectionFault as usize] };
+    let descriptor = unsafe { &mut (*idt_ptr)[vector] };
 
-    *gp_descriptor = idt::InterruptGateDescriptor::with_address_and_segment_selector(
-        general_protection_stub as *const fn() -> () as u32,
+    *descriptor = idt::InterruptGateDescriptor::with_address_and_segment_selector(
+        stub as *const fn() -> () as u32,
         GDTI_32_BIT_CODE_SEGMENT as u16 * size_of::<gdt::SegmentDescriptor>() as u16,
     )
     .into();
+}
 
+/// Every exception gets a handler dumping the registers, so that early faults are reported
+/// rather than triple faulting, GP and PF with a closer look at what went wrong
+fn setup_debug_interrupt_descriptor_table() {
+    for (vector, stub) in EXCEPTION_STUBS.into_iter().enumerate() {
+        set_interrupt_gate(vector, stub);
+    }
+    set_interrupt_gate(
+        idt::Interrupt::GeneralProtectionFault as usize,
+        general_protection_stub,
+    );
+    set_interrupt_gate(idt::Interrupt::PageFault as usize, page_fault_stub);
+    set_interrupt_gate(pic::Irq::Timer.vector() as usize, watchdog::timer_stub);
+
+    let idt_ptr = &raw mut INTERRUPT_DESCRIPTOR_TABLE;
     let idt_descriptor = idt::IDTDescriptor::new(
         size_of::<u64>() as u16 * idt::STANDARD_VECTOR_TABLE_SIZE as u16,
         idt_ptr as *const _ as u32,
     );
 
-    // SAFETY: A handler for GP was set up in the global IDT variable
+    // SAFETY: Handlers for the exceptions and the watchdog's timer were set up in the global IDT
+    // variable
     // A descriptor pointing to the global IDT was correctly created and stored in the
     // idt_descriptor variable
     // The following assembly is necessary to load the IDT, and because of the reasons above is
@@ -382,6 +640,12 @@ fn setup_debug_interrupt_descriptor_table() {
 static mut PML4: paging::PML4 = paging::PML4::new();
 static mut PAGE_DIRECTORY_POINTER_TABLE: paging::PageDirectoryPointerTable =
     paging::PageDirectoryPointerTable::new();
+static mut PHYSICAL_MEMORY_PAGE_DIRECTORY_POINTER_TABLE: paging::PageDirectoryPointerTable =
+    paging::PageDirectoryPointerTable::new();
+
+const GIGABYTE: u64 = 1 << 30;
+// The PML4 entry covering the 512GB from PHYSICAL_MEMORY_OFFSET
+const PHYSICAL_MEMORY_PML4_INDEX: usize = (layout::PHYSICAL_MEMORY_OFFSET >> 39) as usize % 512;
 
 fn setup_page_tables() -> Result<(), Error> {
     let pdpt_ptr = &raw mut PAGE_DIRECTORY_POINTER_TABLE;
@@ -403,11 +667,161 @@ fn setup_page_tables() -> Result<(), Error> {
     pml4.entries[0].set_page_directory_pointer_table(unsafe { &*pdpt_ptr });
     pml4.entries[0].set_flag(paging::PageTableEntryFlag::Write);
 
+    // The memory under the MMIO window again at PHYSICAL_MEMORY_OFFSET, with 1GB pages, for the
+    // kernel to edit page tables through
+    let physical_memory_pdpt_ptr = &raw mut PHYSICAL_MEMORY_PAGE_DIRECTORY_POINTER_TABLE;
+    // SAFETY: This is safe because we are in the bootloader and no other threads are running.
+    let physical_memory_pdpt = unsafe { &mut *physical_memory_pdpt_ptr };
+    let gigabytes = (layout::MMIO_WINDOW.start / GIGABYTE) as usize;
+    for (index, entry) in physical_memory_pdpt.entries[..gigabytes]
+        .iter_mut()
+        .enumerate()
+    {
+        let page_address = (index as u64 * GIGABYTE) as usize as *const u8;
+        entry.set_physical_address(page_address.try_into().map_err(|reason| {
+            Error::new(reason, Context::SettingUpPageTable, Facility::Bootloader)
+        })?);
+        entry.set_flag(paging::PageTableEntryFlag::Write);
+    }
+    let physical_memory_pml4_entry = &mut pml4.entries[PHYSICAL_MEMORY_PML4_INDEX];
+    // SAFETY: This is safe because we are in the bootloader and no other threads are running.
+    physical_memory_pml4_entry
+        .set_page_directory_pointer_table(unsafe { &*physical_memory_pdpt_ptr });
+    physical_memory_pml4_entry.set_flag(paging::PageTableEntryFlag::Write);
+
+    // Paging isn't on yet, so there are no cached translations to flush. Without a PAT the
+    // power-on memory types stay, which only lack write-combining, and nothing maps with that yet
+    if let Err(reason) = paging::setup_page_attribute_table() {
+        log::warn_no_sync!("{}, keeping the default memory types", reason);
+    }
     Ok(())
 }
 
+// Slides are multiples of 2MB, so that the kernel keeps the alignment large pages need, and stay
+// below 32MB, so that the kernel fits in the RAM of a default QEMU machine
+const KERNEL_SLIDE_ALIGNMENT: u64 = 0x200000;
+const KERNEL_SLIDE_SLOTS: u64 = 16;
+
+static mut BOOT_INFO: BootInfo = BootInfo::empty();
+// Payloads are loaded on page boundaries, so that the kernel can map them as they are
+const PAYLOAD_ALIGNMENT: u64 = 0x1000;
+
+/// The kernels stage2 hands off to: x86_64 executables, linked at a fixed address or position
+/// independent, which it switches to long mode for, and i386 ones, which stay in protected mode
+const KERNEL_TARGETS: [elf::Target; 2] = [
+    elf::Target {
+        machine: Machine::X86_64,
+        class: Class::Elf64,
+        object_types: &[ObjectType::Executable, ObjectType::Dynamic],
+    },
+    elf::Target {
+        machine: Machine::I386,
+        class: Class::Elf32,
+        object_types: &[ObjectType::Executable],
+    },
+];
+
+/// Checks that stage2 can hand off to `kernel`, with the error of the x86_64 target if it can't
+fn check_kernel_target(kernel: &elf::File) -> Result<(), Error> {
+    let [x86_64, i386] = &KERNEL_TARGETS;
+    kernel
+        .check_target(x86_64)
+        .or_else(|err| kernel.check_target(i386).map_err(|_| err))
+}
+
+/// A random offset to load the kernel at, from its link address. Only position independent
+/// kernels can be moved, others get a slide of 0
+fn choose_kernel_slide(kernel: &elf::File<'static>) -> u64 {
+    if !matches!(kernel.header().r#type(), ObjectType::Dynamic) {
+        return 0;
+    }
+    random::entropy() % KERNEL_SLIDE_SLOTS * KERNEL_SLIDE_ALIGNMENT
+}
+
+/// Where to jump to `kernel` once it's moved by `slide`, and where its stack starts
+fn kernel_entrypoint_and_stack_pointer(
+    kernel: &elf::File,
+    slide: u64,
+) -> Result<(u32, u32), Error> {
+    let Ok(kernel_entrypoint) = u32::try_from(kernel.header().entrypoint() + slide) else {
+        return Err(Error::new(
+            Fault::KernelEntrypointAbove4G,
+            Context::PreparingForJumpToKernel,
+            Facility::Bootloader,
+        ));
+    };
+
+    // FIXME: what if the size of all statics in the kernel gets larger than the stack size? One
+    // should probably find the highest address mapped for the kernel, and add the stack to that
+    let Some(stack_pointer) =
+        kernel_entrypoint.checked_next_multiple_of(layout::KERNEL_STACK_SIZE as u32)
+    else {
+        return Err(Error::new(
+            Fault::KernelEntrypointTooHigh,
+            Context::PreparingForJumpToKernel,
+            Facility::Bootloader,
+        ));
+    };
+    Ok((kernel_entrypoint, stack_pointer))
+}
+
+fn relocation_error(fault: Fault) -> Error {
+    Error::new(fault, Context::RelocatingKernel, Facility::Bootloader)
+}
+
+fn apply_relocation(
+    relocation: &RelocationEntry,
+    slide: u64,
+    kernel_range: &Range<u64>,
+) -> Result<(), Error> {
+    match relocation.r#type().map_err(relocation_error)? {
+        RelocationType::None => Ok(()),
+        RelocationType::Relative => {
+            let target = relocation.offset() + slide;
+            if target < kernel_range.start || target + size_of::<u64>() as u64 > kernel_range.end {
+                return Err(relocation_error(Fault::RelocationOutOfBounds(target)));
+            }
+            let value = (relocation.addend() as u64).wrapping_add(slide);
+            // SAFETY: The target was checked above to be within the loaded kernel image, which
+            // is below 4GB
+            unsafe { (target as usize as *mut u64).write_unaligned(value) };
+            Ok(())
+        }
+        // The kernel doesn't import symbols, so it has no symbol to resolve these against
+        r#type => Err(relocation_error(Fault::UnsupportedRelocation(
+            r#type as u32,
+        ))),
+    }
+}
+
+/// Patches the loaded kernel for the addresses it was moved to. Position independent kernels need
+/// this even when `slide` is 0, as the linker leaves the patched locations blank
+fn relocate_kernel(
+    kernel: &elf::File<'static>,
+    slide: u64,
+    kernel_range: &Range<u64>,
+) -> Result<(), Error> {
+    for relocation_table in kernel.relocation_tables() {
+        let relocation_table = relocation_table.map_err(|err| {
+            error::push_to_global_error_chain_no_sync(err);
+            relocation_error(Fault::InvalidElf)
+        })?;
+        for relocation in relocation_table {
+            let relocation = relocation.map_err(|err| {
+                error::push_to_global_error_chain_no_sync(err);
+                relocation_error(Fault::InvalidElf)
+            })?;
+            apply_relocation(&relocation, slide, kernel_range)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the loadable segments of the kernel at their virtual address plus `slide`, and returns
+/// the address range they span
 #[cfg(target_os = "none")]
-fn load_segments_into_memory(kernel: &elf::File<'static>) -> Result<(), Error> {
+fn load_segments_into_memory(kernel: &elf::File<'static>, slide: u64) -> Result<Range<u64>, Error> {
+    let mut kernel_range = u64::MAX..0;
     for loadable_program_header in kernel.program_headers().filter_map(|program_header| {
         program_header.ok().and_then(|program_header| {
             if matches!(program_header.r#type(), ProgramHeaderEntryType::Load) {
@@ -417,28 +831,14 @@ fn load_segments_into_memory(kernel: &elf::File<'static>) -> Result<(), Error> {
             }
         })
     }) {
-        let loading_address = loadable_program_header.virtual_address();
+        let segment_range = segment_destination(&loadable_program_header, slide)?;
+        let loading_address = segment_range.start;
         let size = loadable_program_header.segment_size_on_file();
-        if loading_address <= start as *const () as u64 || loading_address + size >= u32::MAX as u64
-        {
-            return Err(Error::new(
-                Fault::InvalidSegmentParameters {
-                    virtual_address: loading_address,
-                    size,
-                },
-                Context::LoadingSegment,
-                Facility::Bootloader,
-            ));
-        }
 
         // SAFETY: Virtual address and size have been verified above to be at a address range
-        // accessible from 32-bit
-        let loading_area = unsafe {
-            core::slice::from_raw_parts_mut(
-                loadable_program_header.virtual_address() as *mut u8,
-                loadable_program_header.segment_size_on_file() as usize,
-            )
-        };
+        // accessible from 32-bit, and not used by the bootloader or the BIOS
+        let loading_area =
+            unsafe { core::slice::from_raw_parts_mut(loading_address as *mut u8, size as usize) };
         loading_area.copy_from_slice(kernel.get_segment(&loadable_program_header).ok_or(
             Error::new(
                 Fault::InvalidSegmentParameters {
@@ -449,168 +849,537 @@ fn load_segments_into_memory(kernel: &elf::File<'static>) -> Result<(), Error> {
                 Facility::Bootloader,
             ),
         )?);
+        kernel_range.start = kernel_range.start.min(segment_range.start);
+        kernel_range.end = kernel_range.end.max(segment_range.end);
+    }
+    Ok(kernel_range)
+}
+
+/// Where the loadable segment of `program_header` goes once moved by `slide`: anywhere below 4GB
+/// outside of the reserved regions
+fn segment_destination(
+    program_header: &program_header::HeaderEntry,
+    slide: u64,
+) -> Result<Range<u64>, Error> {
+    let loading_address = program_header.virtual_address() + slide;
+    let segment_range = loading_address..loading_address + program_header.segment_size_in_memory();
+    let overlapped_region = memory_map::overlapped_reserved_region(&segment_range);
+    if overlapped_region.is_some() || segment_range.end >= u32::MAX as u64 {
+        if let Some(region) = overlapped_region {
+            error::push_to_global_error_chain_no_sync(Error::new(
+                Fault::OverlapsReservedMemory(region),
+                Context::LoadingSegment,
+                Facility::Bootloader,
+            ));
+        }
+        return Err(Error::new(
+            Fault::InvalidSegmentParameters {
+                virtual_address: loading_address,
+                size: program_header.segment_size_on_file(),
+            },
+            Context::LoadingSegment,
+            Facility::Bootloader,
+        ));
+    }
+    Ok(segment_range)
+}
+
+/// Passes the E820 memory map stage1 left at memory_map::E820_MAP on to the kernel
+fn copy_memory_map(boot_info: &mut BootInfo) {
+    let count_pointer = memory_map::E820_MAP.start as usize as *const u32;
+    // SAFETY: stage1 writes the count there before jumping to stage2, and the region is reserved
+    let count = unsafe { count_pointer.read_volatile() } as usize;
+    let entries_pointer = (memory_map::E820_MAP.start + 8) as usize as *const e820::Entry;
+    for index in 0..count.min(e820::MAX_ENTRIES) {
+        // SAFETY: stage1 writes at most MAX_ENTRIES entries right after the count
+        let entry = unsafe { entries_pointer.wrapping_add(index).read_volatile() };
+        boot_info.add_memory_map_entry(entry);
+    }
+}
+
+/// Lists the drives on the legacy ATA channels for the kernel, marking the one it was read from
+fn catalog_drives(boot_info: &mut BootInfo, boot_device: &ata::Device) {
+    let mut found_boot_device = false;
+    for device in ata::scan().iter().flatten() {
+        let is_boot_device = device.is_same_drive(boot_device);
+        found_boot_device |= is_boot_device;
+        boot_info.add_drive(DriveInfo::from(device), is_boot_device);
+    }
+    // The BIOS could read from it, so it's worth passing on even if it didn't answer IDENTIFY
+    if !found_boot_device {
+        boot_info.add_drive(DriveInfo::from(boot_device), true);
+    }
+}
+
+/// Lists the hard drives the BIOS described to stage1 for the kernel, marking the one it booted from
+#[cfg(target_os = "none")]
+fn catalog_bios_drives(boot_info: &mut BootInfo) {
+    // SAFETY: stage1 fills the region before jumping to stage2, and it's reserved
+    let table = unsafe {
+        core::slice::from_raw_parts(
+            memory_map::BIOS_DRIVES.start as usize as *const u8,
+            (memory_map::BIOS_DRIVES.end - memory_map::BIOS_DRIVES.start) as usize,
+        )
+    };
+    let boot_drive = edd::boot_drive_number(table);
+    for (number, drive_parameters) in edd::bios_drives(table) {
+        match drive_parameters {
+            Ok(drive_parameters) => {
+                let drive = drive_parameters.bios_drive(number);
+                progress!("{}", drive);
+                boot_info.add_bios_drive(drive, Some(number) == boot_drive);
+            }
+            Err(err) => {
+                log::warn_no_sync!("BIOS drive {:#x}: {}", number, err);
+                // Not worth stopping the boot for, but worth the kernel knowing about
+                error::push_boot_warning_no_sync(err);
+            }
+        }
+    }
+}
+
+/// Reads `payload` from `device` into `buffer`, which has to be exactly its size, a few sectors at a
+/// time as PIO commands take at most 255 of them
+fn read_payload(
+    device: &mut impl BlockDevice,
+    payload: &PayloadEntry,
+    buffer: &mut [u8],
+) -> Result<(), Error> {
+    let sector_size = device.sector_size_bytes() as usize;
+    let mut lba = payload.lba;
+    for chunk in buffer.chunks_mut(u8::MAX as usize * sector_size) {
+        device.read_sectors(lba, chunk)?;
+        lba += chunk.len().div_ceil(sector_size) as u64;
     }
     Ok(())
 }
 
+fn kernel_read_error(fault: Fault) -> Error {
+    Error::new(fault, Context::ReadingKernelFromDisk, Facility::Bootloader)
+}
+
+/// Reads the payload table, from the sector right after stage2
+fn read_payload_table(
+    device: &mut impl BlockDevice,
+    stage2_sectors: u32,
+) -> Result<PayloadTable, Error> {
+    // Big enough for a sector of any drive, ATAPI ones included
+    let mut table_sector = [0u8; 2048];
+    let table_sector_size = (device.sector_size_bytes() as usize).min(table_sector.len());
+    device
+        .read_sectors(
+            u64::from(stage2_sectors) + 1,
+            &mut table_sector[..table_sector_size],
+        )
+        .map_err(|err| {
+            error::push_to_global_error_chain_no_sync(err);
+            kernel_read_error(Fault::IOError)
+        })?;
+    PayloadTable::try_from(&table_sector[..]).map_err(|err| {
+        error::push_to_global_error_chain_no_sync(err);
+        kernel_read_error(Fault::InvalidValueForField("payload table"))
+    })
+}
+
+/// Reads the kernel payload of `payloads` into the start of `kernel_file`, and parses it
+fn read_kernel<'a>(
+    device: &mut impl BlockDevice,
+    payloads: &PayloadTable,
+    kernel_file: &'a mut [u8],
+) -> Result<elf::File<'a>, Error> {
+    let kernel_payload = *payloads
+        .find(PayloadKind::Kernel)
+        .ok_or(kernel_read_error(Fault::NoKernelPayload))?;
+    let kernel_size_bytes = kernel_payload.sectors as u64 * device.sector_size_bytes() as u64;
+    if kernel_size_bytes > kernel_file.len() as u64 {
+        return Err(kernel_read_error(Fault::TooManySectors(
+            kernel_payload.sectors,
+        )));
+    }
+    let kernel_bytes = &mut kernel_file[..kernel_size_bytes as usize];
+    metrics::measure(Counter::DiskRead, || {
+        read_payload(device, &kernel_payload, kernel_bytes)
+    })
+    .map_err(|err| {
+        error::push_to_global_error_chain_no_sync(err);
+        kernel_read_error(Fault::IOError)
+    })?;
+
+    let kernel_bytes: &'a [u8] = kernel_bytes;
+    metrics::measure(Counter::ElfParse, || {
+        elf::File::try_from(kernel_bytes)
+            .and_then(|kernel| check_kernel_target(&kernel).map(|()| kernel))
+    })
+    .map_err(|err| {
+        error::push_to_global_error_chain_no_sync(err);
+        kernel_read_error(Fault::InvalidElf)
+    })
+}
+
+/// Reads the payload table and the kernel from the drive the BIOS booted from, which is returned
+/// along with them. The table is in the sector right after stage2
 fn load_kernel_from_boot_disk(
     drive_parameters_pointer: *const u8,
     stage2_sectors: u32,
-    kernel_sectors: u32,
     stack_start: u32,
-) -> Result<elf::File<'static>, Error> {
-    fn error(fault: Fault) -> Error {
-        Error::new(fault, Context::ReadingKernelFromDisk, Facility::Bootloader)
-    }
-
+) -> Result<(elf::File<'static>, ata::Device, PayloadTable), Error> {
     // SAFETY: The call to BIOS interrupt 13h with AH=48h returned without error in stage1 if we
     // got to stage2, and the drive_parameters_pointer, passed during stage1 to start, points to a
     // buffer of 30 bytes containing the result
     let drive_parameters_bytes = unsafe {
         core::ptr::slice_from_raw_parts(drive_parameters_pointer, DRIVE_PARAMETERS_BUFFER_SIZE)
             .as_ref()
-            .ok_or(error(Fault::InvalidDriveParametersPointer(
+            .ok_or(kernel_read_error(Fault::InvalidDriveParametersPointer(
                 drive_parameters_pointer,
             )))?
     };
 
     // SAFETY: For the reasons above, it's just as safe to unwrap here
-    let drive_parameters =
+    let mut drive_parameters =
         edd::DriveParameters::try_from(drive_parameters_bytes).map_err(|err| {
             error::push_to_global_error_chain_no_sync(err);
-            error(Fault::FailedBootDeviceIdentification)
+            kernel_read_error(Fault::FailedBootDeviceIdentification)
         })?;
+    // The I/O ports of the boot drive's controller are in it
+    drive_parameters.resolve_fdbt().map_err(|err| {
+        error::push_to_global_error_chain_no_sync(err);
+        kernel_read_error(Fault::FailedBootDeviceIdentification)
+    })?;
 
     match ata::Device::try_from(drive_parameters) {
-        Ok(ata_device) => {
-            let kernel_size_bytes =
-                (kernel_sectors * ata_device.sector_size_bytes() as u32) as usize;
-            // SAFETY: The start of the stack for stage 2 and the number of sectors in the kernel were
-            // correctly determined at compile time and passed by the stage1
-            let kernel_bytes = unsafe {
-                core::ptr::slice_from_raw_parts_mut(
-                    // Align to a 8 byte boundary (for reading a ELF header)
-                    ((stack_start + 7) & !0x7) as *mut u8,
-                    kernel_size_bytes,
+        Ok(mut ata_device) => {
+            let payloads = read_payload_table(&mut ata_device, stage2_sectors)?;
+
+            boot_stage::enter(BootStage::KernelRead);
+            // stage1 and stage2 have to agree on the memory map for the kernel file to be out of
+            // the way of the stack
+            if u64::from(stack_start) != memory_map::STAGE2_STACK.end {
+                return Err(kernel_read_error(Fault::InvalidStackStart(stack_start)));
+            }
+            // SAFETY: The kernel file region is reserved for it in the memory map, and nothing
+            // else uses it. Its start is page aligned, which is enough for reading an ELF header
+            let kernel_file = unsafe {
+                core::slice::from_raw_parts_mut(
+                    memory_map::KERNEL_FILE.start as *mut u8,
+                    (memory_map::KERNEL_FILE.end - memory_map::KERNEL_FILE.start) as usize,
                 )
-                .as_mut()
-                .ok_or(error(Fault::InvalidStackStart(stack_start)))?
             };
-
-            // FIXME: if the kernel gets large enough, we might want to read it in multiple
-            // operations, or use lba48
-            if kernel_sectors > 256 {
-                return Err(error(Fault::TooManySectors(kernel_sectors)));
-            }
-            ata_device
-                .read_sectors_lba28_pio(kernel_sectors as u8, stage2_sectors + 1, kernel_bytes)
-                .map_err(|err| {
-                    error::push_to_global_error_chain_no_sync(err);
-                    error(Fault::IOError)
-                })?;
-
-            elf::File::try_from(&kernel_bytes[..kernel_size_bytes]).map_err(|err| {
-                error::push_to_global_error_chain_no_sync(err);
-                error(Fault::InvalidElf)
-            })
+            read_kernel(&mut ata_device, &payloads, kernel_file)
+                .map(|kernel| (kernel, ata_device, payloads))
         }
         Err(_drive_parametrs) => {
             error::clear_global_error_chain_no_sync();
             // TODO: try USB
+            #[cfg(all(target_os = "none", feature = "usb-probe"))]
             look_for_usb_root_hubs();
 
-            Err(error(Fault::UnsupportedBootMedium))
+            Err(kernel_read_error(Fault::UnsupportedBootMedium))
         }
     }
 }
 
-#[allow(clippy::unwrap_used)]
-#[allow(clippy::missing_panics_doc)]
-fn look_for_usb_root_hubs() {
-    let mut config_addr = pci::ConfigAddressRegister::default();
-    // Brute-force enumeration
-    for bus_number in 0..=pci::MAX_BUS_NUMBER as u8 {
-        config_addr.set_bus_number(bus_number);
-        config_addr.set_flag(pci::ConfigAddressRegisterFlag::Enable);
-        for device_number in 0..=pci::MAX_DEVICE_NUMBER as u8 {
-            config_addr.set_device_number(device_number);
-            if let Some(config_header) = config_addr.dump_configuration_space_header() {
-                if config_header.as_ref().unwrap().is_usb() {
-                    vga::writeln_no_sync!("{}", &config_header.as_ref().unwrap());
-                    serial::writeln_no_sync!("{}", &config_header.as_ref().unwrap());
-                }
-                if config_header.unwrap().is_multi_function_device() {
-                    for function in 1..=pci::MAX_FUNCTION_NUMBER as u8 {
-                        config_addr.set_function_number(function);
-                        if let Some(config_header) = config_addr.dump_configuration_space_header()
-                            && config_header.as_ref().unwrap().is_usb()
-                        {
-                            vga::writeln_no_sync!("{}", &config_header.as_ref().unwrap());
-                            serial::writeln_no_sync!("{}", &config_header.as_ref().unwrap());
-                        }
-                    }
-                    config_addr.set_function_number(0);
-                }
-            }
+/// Loads the payloads other than the kernel one after the other from `start`, which has to be past
+/// the kernel and its stack, recording where they went in `boot_info`
+fn load_other_payloads(
+    device: &mut impl BlockDevice,
+    payloads: &PayloadTable,
+    start: u64,
+    boot_info: &mut BootInfo,
+) -> Result<(), Error> {
+    fn error(fault: Fault) -> Error {
+        Error::new(fault, Context::LoadingPayload, Facility::Bootloader)
+    }
+
+    let mut address = start.next_multiple_of(PAYLOAD_ALIGNMENT);
+    for payload in payloads
+        .entries()
+        .filter(|payload| payload.kind != PayloadKind::Kernel)
+    {
+        let size = payload.sectors as u64 * device.sector_size_bytes() as u64;
+        let range = address..address + size;
+        if let Some(region) = memory_map::overlapped_reserved_region(&range) {
+            return Err(error(Fault::OverlapsReservedMemory(region)));
+        }
+        if range.end > memory_map::IDENTITY_MAPPED.end {
+            return Err(error(Fault::InvalidSegmentParameters {
+                virtual_address: address,
+                size,
+            }));
+        }
+        if !boot_info.add_payload(LoadedPayload::new(payload.kind, address, size)) {
+            return Err(error(Fault::TooManyPayloads(
+                payloads.entries().count() - 1,
+            )));
         }
+        // SAFETY: The range was checked above to be identity mapped memory below 4GB that neither
+        // the BIOS nor the bootloader use, and it starts past the kernel and its stack
+        let buffer = unsafe { core::slice::from_raw_parts_mut(address as *mut u8, size as usize) };
+        metrics::measure(Counter::DiskRead, || read_payload(device, payload, buffer)).map_err(
+            |err| {
+                error::push_to_global_error_chain_no_sync(err);
+                error(Fault::IOError)
+            },
+        )?;
+        address = range.end.next_multiple_of(PAYLOAD_ALIGNMENT);
     }
+    Ok(())
+}
+
+/// Logs the USB host controllers on the PCI bus, without waiting on any of them
+#[cfg(all(target_os = "none", feature = "usb-probe"))]
+fn look_for_usb_root_hubs() {
+    Function::find(|function| {
+        if function.pci_class().is_usb() {
+            log::debug_no_sync!("USB host controller: {}", function);
+        }
+        false
+    });
 }
 
+/// Logs every PCI function, for a record of the machine's devices next to the drives'
+#[cfg(all(target_os = "none", feature = "pci-report"))]
+fn report_pci_devices() {
+    Function::find(|function| {
+        log::info_no_sync!("{}", function);
+        false
+    });
+}
+
+// stage2 is only built for the host to run its tests. ELF files are read there by `xtasks elf-info`
 #[cfg(not(target_os = "none"))]
 fn main() {
-    use std::fmt::Write as _;
-    let mut args = std::env::args();
-    args.next().unwrap();
-    let bytes = std::fs::read(args.next().unwrap()).unwrap();
-    let elf_file = match elf::File::try_from(bytes.as_slice()) {
-        Ok(elf_file) => elf_file,
-        Err(err) => {
-            println!("{err}");
-            return;
-        }
+    eprintln!("stage2 only runs on bare metal, use `xtasks elf-info` to look into ELF files");
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{
+        block_device::BlockDevice,
+        elf,
+        error::{Error, Fault},
+        payload::TABLE_MAGIC,
+        ram_disk::RamDisk,
     };
-    let mut s = String::new();
-    writeln!(&mut s, "{}", elf_file.header()).unwrap();
-    print!("{s}");
-
-    let string_table = elf_file
-        .get_section_by_index(elf_file.header().string_table_index().into())
-        .unwrap()
-        .unwrap()
-        .downcast_to_string_table()
-        .unwrap();
-
-    println!("--------");
-    println!("SECTIONS");
-    println!("--------");
-    for section in elf_file.sections() {
-        use core::fmt::Write as _;
-
-        let section = section.unwrap();
-
-        let mut s = String::new();
-        let section_name = string_table
-            .get_string(section.name_index() as usize)
-            .unwrap()
-            .unwrap();
-        s.write_fmt(format_args!("Section name: {section_name}\n"))
-            .unwrap();
-        section.write_to(&mut s).unwrap();
-        println!("--------");
-        print!("{s}");
-        println!("--------");
-    }
-
-    println!("--------");
-    println!("SEGMENTS");
-    println!("--------");
-    for header in elf_file.program_headers() {
-        let header = header.unwrap();
-
-        let mut s = String::new();
-        write!(&mut s, "{header}").unwrap();
-        println!("--------");
-        print!("{s}");
-        println!("--------");
+
+    use crate::{
+        kernel_entrypoint_and_stack_pointer, memory_map, read_kernel, read_payload_table,
+        segment_destination,
+    };
+
+    const STAGE2_SECTORS: u32 = 3;
+    const TABLE_LBA: usize = 4;
+    const KERNEL_LBA: usize = 5;
+    // More than a PIO command takes
+    const KERNEL_SECTORS: usize = 300;
+    const SEGMENT_OFFSET: usize = 0x1000;
+
+    /// A RAM disk keeping track of the reads, as their LBA and length
+    struct RecordingDisk<'a> {
+        disk: RamDisk<'a>,
+        reads: Vec<(u64, usize)>,
+    }
+
+    impl BlockDevice for RecordingDisk<'_> {
+        fn sector_size_bytes(&self) -> u16 {
+            self.disk.sector_size_bytes()
+        }
+
+        fn sectors(&self) -> u64 {
+            self.disk.sectors()
+        }
+
+        fn read_sectors(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Error> {
+            self.reads.push((lba, buffer.len()));
+            self.disk.read_sectors(lba, buffer)
+        }
+
+        fn write_sectors(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Error> {
+            self.disk.write_sectors(lba, buffer)
+        }
+    }
+
+    /// An x86_64 executable with a single loadable segment at `segment_address`
+    fn kernel(machine: u16, entrypoint: u64, segment_address: u64) -> Vec<u8> {
+        let mut bytes = vec![0u8; KERNEL_SECTORS * 512];
+        bytes[..8].copy_from_slice(b"\x7fELF\x02\x01\x01\x00");
+        bytes[16..18].copy_from_slice(&2u16.to_le_bytes());
+        bytes[18..20].copy_from_slice(&machine.to_le_bytes());
+        bytes[20..24].copy_from_slice(&1u32.to_le_bytes());
+        bytes[24..32].copy_from_slice(&entrypoint.to_le_bytes());
+        bytes[32..40].copy_from_slice(&64u64.to_le_bytes());
+        bytes[52..54].copy_from_slice(&64u16.to_le_bytes());
+        bytes[54..56].copy_from_slice(&56u16.to_le_bytes());
+        bytes[56..58].copy_from_slice(&1u16.to_le_bytes());
+        bytes[58..60].copy_from_slice(&64u16.to_le_bytes());
+
+        // Loadable, readable and executable, with a page of zeros past what's in the file
+        let segment = &mut bytes[64..64 + 56];
+        segment[..4].copy_from_slice(&1u32.to_le_bytes());
+        segment[4..8].copy_from_slice(&5u32.to_le_bytes());
+        segment[8..16].copy_from_slice(&(SEGMENT_OFFSET as u64).to_le_bytes());
+        segment[16..24].copy_from_slice(&segment_address.to_le_bytes());
+        segment[24..32].copy_from_slice(&segment_address.to_le_bytes());
+        segment[32..40].copy_from_slice(&0x1000u64.to_le_bytes());
+        segment[40..48].copy_from_slice(&0x2000u64.to_le_bytes());
+        segment[48..56].copy_from_slice(&0x1000u64.to_le_bytes());
+        bytes[SEGMENT_OFFSET..SEGMENT_OFFSET + 0x1000].fill(0xc3);
+        bytes
+    }
+
+    /// stage1 and stage2, the payload table with `entries` as kind, sectors and LBA, then `kernel`
+    fn disk_image(entries: &[(u32, u32, u64)], kernel: &[u8]) -> Vec<u8> {
+        let mut image = vec![0xaa; (KERNEL_LBA + KERNEL_SECTORS) * 512];
+        let table = &mut image[TABLE_LBA * 512..][..512];
+        table.fill(0);
+        table[..8].copy_from_slice(&TABLE_MAGIC);
+        table[8..12].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+        for (i, (kind, sectors, lba)) in entries.iter().enumerate() {
+            let entry = &mut table[16 + i * 16..32 + i * 16];
+            entry[..4].copy_from_slice(&kind.to_le_bytes());
+            entry[4..8].copy_from_slice(&sectors.to_le_bytes());
+            entry[8..].copy_from_slice(&lba.to_le_bytes());
+        }
+        image[KERNEL_LBA * 512..][..kernel.len()].copy_from_slice(kernel);
+        image
+    }
+
+    fn kernel_file() -> Vec<u8> {
+        vec![0; (memory_map::KERNEL_FILE.end - memory_map::KERNEL_FILE.start) as usize]
+    }
+
+    /// What reading the kernel off `image` fails with
+    fn read_fault(image: &mut [u8], kernel_file: &mut [u8]) -> Fault {
+        let mut disk = RamDisk::new(image);
+        match read_payload_table(&mut disk, STAGE2_SECTORS)
+            .and_then(|payloads| read_kernel(&mut disk, &payloads, kernel_file))
+        {
+            Err(err) => err.fault(),
+            Ok(_) => panic!("the kernel was read"),
+        }
+    }
+
+    #[test]
+    fn reads_and_places_the_kernel() {
+        let kernel = kernel(0x3e, 0x20_0010, 0x20_0000);
+        let initrd = (3, 1, 1);
+        let mut image = disk_image(
+            &[initrd, (1, KERNEL_SECTORS as u32, KERNEL_LBA as u64)],
+            &kernel,
+        );
+        let mut disk = RecordingDisk {
+            disk: RamDisk::new(&mut image),
+            reads: Vec::new(),
+        };
+        let mut kernel_file = kernel_file();
+
+        let payloads = read_payload_table(&mut disk, STAGE2_SECTORS).unwrap();
+        let kernel_read = read_kernel(&mut disk, &payloads, &mut kernel_file).unwrap();
+        // The table, then the kernel in chunks of at most 255 sectors
+        assert_eq!(vec![(4, 512), (5, 255 * 512), (260, 45 * 512)], disk.reads);
+
+        // The stack goes up to the next MiB
+        assert_eq!(
+            (0x20_0010, 0x30_0000),
+            kernel_entrypoint_and_stack_pointer(&kernel_read, 0).unwrap()
+        );
+        assert_eq!(
+            (0x30_0010, 0x40_0000),
+            kernel_entrypoint_and_stack_pointer(&kernel_read, 0x10_0000).unwrap()
+        );
+        let segment = kernel_read.program_headers().next().unwrap().unwrap();
+        assert_eq!(
+            0x20_0000..0x20_2000,
+            segment_destination(&segment, 0).unwrap()
+        );
+        assert_eq!(
+            0x30_0000..0x30_2000,
+            segment_destination(&segment, 0x10_0000).unwrap()
+        );
+        assert_eq!(kernel[..], kernel_file[..kernel.len()]);
+    }
+
+    // The failures push to the global error chain, so they're all checked from a single thread
+    #[test]
+    fn load_errors() {
+        let kernel_entry = (1, KERNEL_SECTORS as u32, KERNEL_LBA as u64);
+        let valid_kernel = kernel(0x3e, 0x20_0010, 0x20_0000);
+
+        let mut image = disk_image(&[kernel_entry], &valid_kernel);
+        image[TABLE_LBA * 512] = b'X';
+        assert!(matches!(
+            read_fault(&mut image, &mut kernel_file()),
+            Fault::InvalidValueForField("payload table")
+        ));
+        let mut image = disk_image(&[(3, 1, 1)], &valid_kernel);
+        assert!(matches!(
+            read_fault(&mut image, &mut kernel_file()),
+            Fault::NoKernelPayload
+        ));
+        let mut image = disk_image(&[kernel_entry], &valid_kernel);
+        assert!(matches!(
+            read_fault(&mut image, &mut vec![0; 512]),
+            Fault::TooManySectors(300)
+        ));
+        // Past the end of the disk
+        let mut image = disk_image(&[(1, 2, 10_000)], &valid_kernel);
+        assert!(matches!(
+            read_fault(&mut image, &mut kernel_file()),
+            Fault::IOError
+        ));
+        let mut image = disk_image(&[kernel_entry], &[0xaa; 512]);
+        assert!(matches!(
+            read_fault(&mut image, &mut kernel_file()),
+            Fault::InvalidElf
+        ));
+        // For ARM
+        let mut image = disk_image(&[kernel_entry], &kernel(0x28, 0x20_0010, 0x20_0000));
+        assert!(matches!(
+            read_fault(&mut image, &mut kernel_file()),
+            Fault::InvalidElf
+        ));
+
+        let entrypoint_fault = |entrypoint: u64, slide: u64| {
+            let kernel = kernel(0x3e, entrypoint, 0x20_0000);
+            let kernel = elf::File::try_from(&kernel[..]).unwrap();
+            kernel_entrypoint_and_stack_pointer(&kernel, slide)
+                .unwrap_err()
+                .fault()
+        };
+        assert!(matches!(
+            entrypoint_fault(0x1_0000_0000, 0),
+            Fault::KernelEntrypointAbove4G
+        ));
+        assert!(matches!(
+            entrypoint_fault(0xFFFF_F000, 0x1000),
+            Fault::KernelEntrypointAbove4G
+        ));
+        // No room for the stack
+        assert!(matches!(
+            entrypoint_fault(0xFFFF_FFF0, 0),
+            Fault::KernelEntrypointTooHigh
+        ));
+
+        let segment_fault = |segment_address: u64, slide: u64| {
+            let kernel = kernel(0x3e, 0x20_0010, segment_address);
+            let kernel = elf::File::try_from(&kernel[..]).unwrap();
+            let segment = kernel.program_headers().next().unwrap().unwrap();
+            segment_destination(&segment, slide).unwrap_err().fault()
+        };
+        // Over stage2, and past 4GB once moved
+        assert!(matches!(
+            segment_fault(0x6_0000, 0),
+            Fault::InvalidSegmentParameters {
+                virtual_address: 0x6_0000,
+                size: 0x1000
+            }
+        ));
+        assert!(matches!(
+            segment_fault(0xFFFF_0000, 0x1_0000),
+            Fault::InvalidSegmentParameters {
+                virtual_address: 0x1_0000_0000,
+                ..
+            }
+        ));
     }
 }
@@ -0,0 +1,110 @@
+// Where things live in the first MiB while the bootloader runs. stage2's addresses come from
+// common::layout, which link.x is generated from, and stage1 (boot.asm, or the stage1 crate)
+// hard-codes them too, keep it in sync:
+//   0x00000..0x00500  real mode IVT and BIOS data area
+//   0x00500..0x01000  the E820 memory map stage1 collects, see common/src/e820.rs
+//   0x01000..0x01500  the boot drive's number and the drive parameters of every BIOS hard drive
+//   0x01500..0x07C00  stage1's real mode stack
+//   0x07C00..0x07E00  stage1
+//   0x10000..0x60000  the kernel file, as read from disk, before its segments are loaded
+//   0x60000..0x80000  stage2, with its statics (page tables, GDT, boot info) the kernel keeps using
+//   0x80000..0x90000  stage2's stack, growing down from 0x90000
+//   0x9FC00..0xA0000  extended BIOS data area, on most machines
+//   0xA0000..0x100000 VGA memory and ROMs
+// The kernel's segments may go anywhere outside of the reserved regions below, which include the
+// kernel heap
+use core::ops::Range;
+
+use common::layout;
+
+pub const BIOS_DATA: Range<u64> = 0x0..0x500;
+/// A u32 count, then the entries from E820_MAP.start + 8
+pub const E820_MAP: Range<u64> = 0x500..0x1000;
+/// The number of the boot drive as a word, then MAX_BIOS_DRIVES slots of
+/// edd::DRIVE_PARAMETERS_BUFFER_SIZE bytes, one per BIOS hard drive from 0x80
+pub const BIOS_DRIVES: Range<u64> = 0x1000..0x1500;
+pub const STAGE1: Range<u64> = 0x7C00..0x7E00;
+pub const KERNEL_FILE: Range<u64> = 0x10000..0x60000;
+pub const STAGE2: Range<u64> = layout::STAGE2_BASE..layout::STAGE2_STACK.start;
+pub const STAGE2_STACK: Range<u64> = layout::STAGE2_STACK;
+pub const EXTENDED_BIOS_DATA_AND_ROMS: Range<u64> = 0x9FC00..0x100000;
+/// What the page tables stage2 sets up map, with a single 1GB page
+pub const IDENTITY_MAPPED: Range<u64> = 0x0..0x4000_0000;
+
+const KERNEL_HEAP: Range<u64> = layout::HEAP_START..layout::HEAP_START + layout::HEAP_SIZE;
+
+const RESERVED_REGIONS: [(&str, Range<u64>); 10] = [
+    ("the BIOS data area", BIOS_DATA),
+    ("the E820 memory map", E820_MAP),
+    ("the BIOS drive parameters", BIOS_DRIVES),
+    ("stage1", STAGE1),
+    ("the kernel file", KERNEL_FILE),
+    ("stage2", STAGE2),
+    ("stage2's stack", STAGE2_STACK),
+    (
+        "the extended BIOS data area and ROMs",
+        EXTENDED_BIOS_DATA_AND_ROMS,
+    ),
+    ("the kernel heap", KERNEL_HEAP),
+    ("the frame pool", layout::FRAME_POOL),
+];
+
+/// The name of the first reserved region `range` overlaps, if any
+pub fn overlapped_reserved_region(range: &Range<u64>) -> Option<&'static str> {
+    if range.is_empty() {
+        return None;
+    }
+    RESERVED_REGIONS
+        .iter()
+        .find(|(_, region)| range.start < region.end && region.start < range.end)
+        .map(|(name, _)| *name)
+}
+
+#[cfg(test)]
+mod tests {
+    use common::boot_info::MAX_BIOS_DRIVES;
+
+    use crate::{
+        edd::DRIVE_PARAMETERS_BUFFER_SIZE,
+        memory_map::{
+            BIOS_DRIVES, KERNEL_FILE, RESERVED_REGIONS, STAGE2, STAGE2_STACK,
+            overlapped_reserved_region,
+        },
+    };
+
+    #[test]
+    fn reserved_regions() {
+        for (i, (_, region)) in RESERVED_REGIONS.iter().enumerate() {
+            for (_, other) in &RESERVED_REGIONS[i + 1..] {
+                assert!(region.end <= other.start);
+            }
+        }
+        // xtasks lets the kernel take up to 640 sectors, as many as fit in here
+        assert_eq!(640 * 512, KERNEL_FILE.end - KERNEL_FILE.start);
+        assert_eq!(STAGE2.end, STAGE2_STACK.start);
+        assert!(
+            2 + (MAX_BIOS_DRIVES * DRIVE_PARAMETERS_BUFFER_SIZE) as u64
+                <= BIOS_DRIVES.end - BIOS_DRIVES.start
+        );
+
+        assert_eq!(None, overlapped_reserved_region(&(0x100000..0x200000)));
+        assert_eq!(None, overlapped_reserved_region(&(0x90000..0x9FC00)));
+        assert_eq!(
+            Some("stage2"),
+            overlapped_reserved_region(&(0x70000..0x70010))
+        );
+        assert_eq!(
+            Some("the kernel file"),
+            overlapped_reserved_region(&(0x8000..0x20000))
+        );
+        assert_eq!(None, overlapped_reserved_region(&(0x70000..0x70000)));
+        assert_eq!(
+            Some("the kernel heap"),
+            overlapped_reserved_region(&(0x0FFF_F000..0x1000_1000))
+        );
+        assert_eq!(
+            Some("the frame pool"),
+            overlapped_reserved_region(&(0x11FF_F000..0x1200_1000))
+        );
+    }
+}
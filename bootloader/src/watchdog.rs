@@ -0,0 +1,107 @@
+// Catches boot steps that hang, e.g. waiting on a disk that never answers, and reports them
+// instead of leaving a blank screen. The PIT raises IRQ0 every ~55ms, and each tick counts
+// against the deadline of the step that last checked in
+use core::arch::naked_asm;
+
+use common::{
+    error::{self, Context, Error, Facility, Fault},
+    interrupts, log,
+    pic::{self, Irq},
+    serial,
+    timer::{self, TIMER_0_PERIOD_NS},
+    vga,
+};
+
+struct Step {
+    name: &'static str,
+    deadline_ns: u64,
+    elapsed_ns: u64,
+}
+
+static mut CURRENT_STEP: Option<Step> = None;
+static mut TICKS: u64 = 0;
+
+extern "cdecl" fn timer_handler() {
+    pic::end_of_interrupt(Irq::Timer);
+
+    let ticks_ptr = &raw mut TICKS;
+    // SAFETY: This is safe because we are in the bootloader and no other threads are running.
+    // Interrupts are disabled while the main code reads the ticks
+    unsafe { *ticks_ptr += 1 };
+
+    let current_step_ptr = &raw mut CURRENT_STEP;
+    // SAFETY: This is safe because we are in the bootloader and no other threads are running.
+    // Interrupts are disabled while the main code changes the current step
+    let Some(step) = (unsafe { &mut *current_step_ptr }).as_mut() else {
+        return;
+    };
+    step.elapsed_ns += TIMER_0_PERIOD_NS;
+    if step.elapsed_ns < step.deadline_ns {
+        return;
+    }
+
+    error::push_to_global_error_chain_no_sync(Error::new(
+        Fault::Timeout(step.deadline_ns),
+        Context::BootStep(step.name),
+        Facility::Bootloader,
+    ));
+    log::error_no_sync!("Boot step '{}' is taking too long, giving up", step.name);
+    vga::writeln_no_sync!("{:}", error::get_global_error_chain_no_sync());
+    serial::writeln_no_sync!("{:#}", error::get_global_error_chain_no_sync());
+    loop {}
+}
+
+#[unsafe(naked)]
+pub extern "C" fn timer_stub() {
+    naked_asm!(
+        "pushad",
+        "cld",
+        "call {handler}",
+        "popad",
+        "iretd",
+        handler = sym timer_handler,
+    );
+}
+
+/// Starts the timer ticks. The IDT must have `timer_stub` as the handler for the timer IRQ
+pub fn arm() {
+    pic::remap(pic::PIC_1_OFFSET, pic::PIC_2_OFFSET);
+    timer::start_timer_0_rate_generator();
+    pic::unmask(Irq::Timer);
+    interrupts::enable();
+}
+
+/// Starts timing `step`, which has `deadline_ms` to complete before the boot is given up on
+pub fn check_in(step: &'static str, deadline_ms: u64) {
+    let current_step_ptr = &raw mut CURRENT_STEP;
+    interrupts::without_interrupts(|| {
+        // SAFETY: This is safe because we are in the bootloader and no other threads are
+        // running, and the timer handler can't run in the meantime
+        *(unsafe { &mut *current_step_ptr }) = Some(Step {
+            name: step,
+            deadline_ns: deadline_ms * 1_000_000,
+            elapsed_ns: 0,
+        });
+    });
+}
+
+/// Time since `arm`, good to the PIT's resolution until `disarm`
+pub fn uptime_ns() -> u64 {
+    let ticks_ptr = &raw const TICKS;
+    interrupts::without_interrupts(|| {
+        // SAFETY: This is safe because we are in the bootloader and no other threads are
+        // running, and the timer handler can't run in the meantime. A wrap around whose IRQ is
+        // still pending is only counted once it's handled, so this can be a period behind
+        let ticks = unsafe { *ticks_ptr };
+        ticks * TIMER_0_PERIOD_NS + timer::timer_0_period_elapsed_ns()
+    })
+}
+
+/// Stops the timer ticks, before leaving protected mode behind along with the IDT
+pub fn disarm() {
+    interrupts::disable();
+    pic::mask(Irq::Timer);
+    let current_step_ptr = &raw mut CURRENT_STEP;
+    // SAFETY: This is safe because we are in the bootloader and no other threads are running.
+    *(unsafe { &mut *current_step_ptr }) = None;
+}
@@ -1,8 +1,21 @@
 ; boot.asm
 bits 16
 org 0x7C00
+; See bootloader/src/memory_map.rs for the rest of the memory map
 STAGE2_STACK_START equ 0x90000
-STAGE2_ENTRYPOINT equ 0x0010000
+STAGE2_ENTRYPOINT equ 0x0060000
+; The E820 memory map: a dword count, then the entries from 0x508, see common/src/e820.rs
+MEMORY_MAP_COUNT equ 0x500
+MEMORY_MAP_ENTRIES equ 0x508
+MEMORY_MAP_ENTRY_SIZE equ 24
+MEMORY_MAP_MAX_ENTRIES equ 64
+SMAP equ 0x534D4150
+; The boot drive's number as a word, then the drive parameters of the BIOS hard drives 0x80 to
+; 0x8F, see bootloader/src/edd.rs
+BOOT_DRIVE equ 0x1000
+BIOS_DRIVES equ 0x1002
+BIOS_DRIVE_COUNT equ 16
+DRIVE_PARAMETERS_SIZE equ 66
 
 jmp _start
 ; precondition: ah contains the desired interrupt code
@@ -34,24 +47,46 @@ mov sp, 0x7C00
 
 mov ax, cs
 mov ds, ax
+mov es, ax
 
 ; save boot drive from BIOS (already in DL)
-mov [BootDrive], dl
+mov [BOOT_DRIVE], dl
 
 ; ---- get extensions via EDD (AH=41h)
 mov ah, 0x41
 mov bx, 0x55aa
-mov dl, [BootDrive]
+mov dl, [BOOT_DRIVE]
 call interrupt_with_retry
 mov [ExtensionsBitmap], cx
 mov [EDDVersion], ah
 
 ; ---- get drive parameters via EDD (AH=48h)
 mov ah, 0x48
-mov dl, [BootDrive]
+mov dl, [BOOT_DRIVE]
 mov si, DriveParameters ; ---- addres for the result
 call interrupt_with_retry
 
+; ---- the same for every BIOS hard drive, without retrying as most of them aren't there. The
+; slots of the ones that fail get a size of 0
+  mov dl, 0x80
+  mov si, BIOS_DRIVES
+bios_drives:
+.loop:
+  mov word [si], DRIVE_PARAMETERS_SIZE
+  mov ah, 0x48
+  push si
+  push dx
+  int 0x13
+  pop dx
+  pop si
+  jnc .next
+  mov word [si], 0
+.next:
+  add si, DRIVE_PARAMETERS_SIZE
+  inc dl
+  cmp dl, 0x80 + BIOS_DRIVE_COUNT
+  jb .loop
+
 ; ---- read stage2 via EDD (AH=42h) ----
 mov cx, STAGE2_SECTORS      ; CX keeps track of sectors left to read
 read_sectors:
@@ -66,7 +101,7 @@ read_sectors:
   ; 2. Perform the read
   mov ah, 0x42
   mov si, dap
-  mov dl, [BootDrive]
+  mov dl, [BOOT_DRIVE]
   push cx
   call interrupt_with_retry
   pop cx
@@ -91,6 +126,28 @@ read_sectors:
   jmp .loop
 .done:
 
+; ---- get the memory map via E820 (EAX=E820h), into ES:DI
+get_memory_map:
+  mov dword [MEMORY_MAP_COUNT], 0
+  mov di, MEMORY_MAP_ENTRIES
+  xor ebx, ebx                ; continuation, 0 to start from the first entry
+.loop:
+  mov eax, 0xE820
+  mov edx, SMAP
+  mov ecx, MEMORY_MAP_ENTRY_SIZE
+  mov dword [di + 20], 1      ; ACPI 3.0 attributes: valid, for BIOSes that only write 20 bytes
+  int 0x15
+  jc .done                    ; no E820 at all, or past the last entry
+  cmp eax, SMAP
+  jne .done
+  inc dword [MEMORY_MAP_COUNT]
+  add di, MEMORY_MAP_ENTRY_SIZE
+  cmp di, MEMORY_MAP_ENTRIES + MEMORY_MAP_ENTRY_SIZE * MEMORY_MAP_MAX_ENTRIES
+  jae .done
+  test ebx, ebx               ; 0 after the last entry
+  jnz .loop
+.done:
+
 ; enter protected mode
 cli
 lgdt [gdt.desc]
@@ -114,7 +171,6 @@ mov esp, STAGE2_STACK_START
 push dword [ExtensionsBitmap]
 push dword [EDDVersion]
 push dword STAGE2_STACK_START
-push dword KERNEL_SECTORS
 push dword STAGE2_SECTORS
 push dword DriveParameters
 call STAGE2_ENTRYPOINT
@@ -137,16 +193,14 @@ dap:
 db 0x10
 db 0x00
 dw 0
-; 0x1000*16 + 0x0000 = 0x0010000, the handover address
+; 0x6000*16 + 0x0000 = 0x0060000, the handover address
 dw 0x0000
-dw 0x1000
+dw 0x6000
 dq 1
 
-BootDrive db 0
 align 4
 EDDVersion dd 0
 ExtensionsBitmap dd 0
-dw KERNEL_SECTORS
 align 2
 DriveParameters:
     dw 66       ; The size of this buffer structure. The BIOS reads this.
@@ -0,0 +1,11 @@
+/* stage1 is the boot sector the BIOS loads at 0x7C00, all of it in the .boot section of main.rs */
+ENTRY(start)
+SECTIONS {
+  . = 0x7C00;
+
+  .boot : { KEEP(*(.boot)) }
+
+  /DISCARD/ : { *(*) }
+
+  ASSERT(SIZEOF(.boot) == 512, "stage1 isn't exactly a sector")
+}
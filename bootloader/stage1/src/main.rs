@@ -0,0 +1,267 @@
+// stage1 as a cargo crate, for building the whole boot chain without nasm: the same boot sector
+// as boot.asm, written with global_asm!, which link.x places at 0x7C00 and `xtasks build-image
+// --stage1 cargo` extracts with objcopy. Like boot.asm, it reads stage2 with the EDD extended
+// reads, collects the E820 memory map, switches to protected mode and calls stage2 with
+//   start(DriveParameters, STAGE2_SECTORS, stage2's stack, EDD version, extensions bitmap)
+// xtasks passes STAGE2_SECTORS in the environment, as it passes it to nasm with -D
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+
+// stage2's addresses, the same ones its link.x is generated from
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+#[path = "../../../common/src/layout.rs"]
+mod layout;
+
+/// The E820 memory map: a dword count, then the entries, see common/src/e820.rs
+#[cfg(target_os = "none")]
+const MEMORY_MAP_COUNT: u32 = 0x500;
+#[cfg(target_os = "none")]
+const MEMORY_MAP_ENTRIES: u32 = 0x508;
+#[cfg(target_os = "none")]
+const MEMORY_MAP_ENTRY_SIZE: u32 = 24;
+#[cfg(target_os = "none")]
+const MEMORY_MAP_MAX_ENTRIES: u32 = 64;
+#[cfg(target_os = "none")]
+const SMAP: u32 = 0x534D4150;
+/// The boot drive's number as a word, then the drive parameters of the BIOS hard drives 0x80 to
+/// 0x8F, see bootloader/src/edd.rs
+#[cfg(target_os = "none")]
+const BOOT_DRIVE: u32 = 0x1000;
+#[cfg(target_os = "none")]
+const BIOS_DRIVES: u32 = 0x1002;
+#[cfg(target_os = "none")]
+const BIOS_DRIVE_COUNT: u32 = 16;
+#[cfg(target_os = "none")]
+const DRIVE_PARAMETERS_SIZE: u32 = 66;
+
+#[cfg(target_os = "none")]
+const STAGE2_SECTORS: u32 = match option_env!("STAGE2_SECTORS") {
+    Some(sectors) => parse_decimal(sectors),
+    None => panic!("STAGE2_SECTORS isn't set, stage1 is built by xtasks"),
+};
+
+#[cfg(target_os = "none")]
+const fn parse_decimal(digits: &str) -> u32 {
+    let digits = digits.as_bytes();
+    assert!(!digits.is_empty(), "STAGE2_SECTORS is empty");
+    let mut value = 0u32;
+    let mut i = 0;
+    while i < digits.len() {
+        assert!(
+            digits[i].is_ascii_digit(),
+            "STAGE2_SECTORS isn't a decimal number"
+        );
+        value = value * 10 + (digits[i] - b'0') as u32;
+        i += 1;
+    }
+    value
+}
+
+#[cfg(target_os = "none")]
+core::arch::global_asm!(
+    ".section .boot, \"awx\"",
+    ".code16",
+    ".global start",
+    "    jmp start",
+
+    // precondition: ah holds the function, and the other arguments (e.g. DS:SI) are set
+    "interrupt_with_retry:",
+    "    int 0x13",
+    "    jc .Lsoft_fail",
+    "    ret",
+    ".Lsoft_fail:",
+    "    push ax",
+    "    xor ax, ax",              // AH=0: reset disk
+    "    int 0x13",
+    "    jc .Lhard_fail",
+    "    pop ax",
+    "    jmp interrupt_with_retry",
+    ".Lhard_fail:",
+    "    hlt",
+    "    ret",
+
+    // real mode stack before any interrupt pushing flags/CS:IP to it
+    "start:",
+    "    xor ax, ax",
+    "    mov ss, ax",
+    "    mov sp, 0x7C00",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    mov es, ax",
+    // the BIOS passes the boot drive in DL
+    "    mov byte ptr [{boot_drive}], dl",
+
+    // ---- get extensions via EDD (AH=41h)
+    "    mov ah, 0x41",
+    "    mov bx, 0x55aa",
+    "    mov dl, byte ptr [{boot_drive}]",
+    "    call interrupt_with_retry",
+    "    mov word ptr [extensions_bitmap], cx",
+    "    mov byte ptr [edd_version], ah",
+
+    // ---- get drive parameters via EDD (AH=48h)
+    "    mov ah, 0x48",
+    "    mov dl, byte ptr [{boot_drive}]",
+    "    mov si, offset drive_parameters",
+    "    call interrupt_with_retry",
+
+    // ---- the same for every BIOS hard drive, without retrying as most of them aren't there. The
+    // slots of the ones that fail get a size of 0
+    "    mov dl, 0x80",
+    "    mov si, {bios_drives}",
+    ".Lbios_drive:",
+    "    mov word ptr [si], {drive_parameters_size}",
+    "    mov ah, 0x48",
+    "    push si",
+    "    push dx",
+    "    int 0x13",
+    "    pop dx",
+    "    pop si",
+    "    jnc .Lnext_bios_drive",
+    "    mov word ptr [si], 0",
+    ".Lnext_bios_drive:",
+    "    add si, {drive_parameters_size}",
+    "    inc dl",
+    "    cmp dl, {bios_drives_end}",
+    "    jb .Lbios_drive",
+
+    // ---- read stage2 via EDD (AH=42h), at most 127 sectors at a time. CX counts the sectors
+    // left
+    "    mov cx, {stage2_sectors}",
+    ".Lread_sectors:",
+    "    mov ax, cx",
+    "    cmp ax, 127",
+    "    jbe .Lset_dap_count",
+    "    mov ax, 127",
+    ".Lset_dap_count:",
+    "    mov word ptr [dap + 2], ax",
+    "    mov ah, 0x42",
+    "    mov si, offset dap",
+    "    mov dl, byte ptr [{boot_drive}]",
+    "    push cx",
+    "    call interrupt_with_retry",
+    "    pop cx",
+    "    mov ax, word ptr [dap + 2]",
+    "    sub cx, ax",
+    "    jz .Lread_done",
+    // a full chunk was read: advance the LBA, and the buffer by 127 * 512 bytes, moving to the
+    // next 64KB segment when the offset wraps around
+    "    add word ptr [dap + 8], 127",
+    "    adc word ptr [dap + 10], 0",
+    "    add word ptr [dap + 4], 0xFE00",
+    "    jnc .Lread_sectors",
+    "    add word ptr [dap + 6], 0x1000",
+    "    jmp .Lread_sectors",
+    ".Lread_done:",
+
+    // ---- get the memory map via E820 (EAX=E820h), into ES:DI
+    "    mov dword ptr [{memory_map_count}], 0",
+    "    mov di, {memory_map_entries}",
+    "    xor ebx, ebx",            // continuation, 0 to start from the first entry
+    ".Lmemory_map_entry:",
+    "    mov eax, 0xE820",
+    "    mov edx, {smap}",
+    "    mov ecx, {memory_map_entry_size}",
+    // ACPI 3.0 attributes: valid, for BIOSes that only write 20 bytes
+    "    mov dword ptr [di + 20], 1",
+    "    int 0x15",
+    "    jc .Lmemory_map_done",    // no E820 at all, or past the last entry
+    "    cmp eax, {smap}",
+    "    jne .Lmemory_map_done",
+    "    inc dword ptr [{memory_map_count}]",
+    "    add di, {memory_map_entry_size}",
+    "    cmp di, {memory_map_end}",
+    "    jae .Lmemory_map_done",
+    "    test ebx, ebx",           // 0 after the last entry
+    "    jnz .Lmemory_map_entry",
+    ".Lmemory_map_done:",
+
+    // ---- enter protected mode, reloading CS with the code descriptor
+    "    cli",
+    "    lgdt [gdt_descriptor]",
+    "    mov eax, cr0",
+    "    or eax, 1",
+    "    mov cr0, eax",
+    "    ljmp 0x08, offset protected_mode_entry",
+
+    ".code32",
+    "protected_mode_entry:",
+    "    mov ax, 0x10",            // data selector
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    mov fs, ax",
+    "    mov gs, ax",
+    "    mov esp, {stage2_stack_start}",
+    // cdecl arguments of stage2's start, the last one first
+    "    push dword ptr [extensions_bitmap]",
+    "    push dword ptr [edd_version]",
+    // Encoded by hand, so that the pushes keep their 32-bit immediates and the call its relative
+    // displacement, which is how xtasks' image check finds STAGE2_SECTORS
+    "    .byte 0x68",
+    "    .long {stage2_stack_start}",
+    "    .byte 0x68",
+    "    .long {stage2_sectors}",
+    "    .byte 0x68",
+    "    .long drive_parameters",
+    "    .byte 0xE8",
+    "    .long {stage2_entrypoint} - (. + 4)",
+
+    // flat 0..4GiB code/data
+    "    .balign 8",
+    "gdt:",
+    "    .quad 0",                    // null
+    "    .quad 0x00CF9A000000FFFF",   // code: base=0, limit=4GB, P=1, DPL=0, Code, R, 32-bit
+    "    .quad 0x00CF92000000FFFF",   // data: base=0, limit=4GB, P=1, DPL=0, Data, W, 32-bit
+    "gdt_end:",
+    "gdt_descriptor:",
+    "    .word gdt_end - gdt - 1",
+    "    .long gdt",
+
+    // Disk Address Packet (EDD): size, reserved, sectors, buffer offset:segment, LBA
+    "dap:",
+    "    .byte 0x10",
+    "    .byte 0",
+    "    .word 0",
+    "    .word 0",
+    "    .word {stage2_segment}",
+    "    .quad 1",
+
+    "    .balign 4",
+    "edd_version:",
+    "    .long 0",
+    "extensions_bitmap:",
+    "    .long 0",
+    "    .balign 2",
+    "drive_parameters:",
+    "    .word 66",                // the size of the buffer, which the BIOS reads
+    "    .space 64",               // for the BIOS to fill
+
+    "    .org 510",
+    "    .word 0xAA55",
+
+    stage2_sectors = const STAGE2_SECTORS,
+    stage2_stack_start = const layout::STAGE2_STACK.end,
+    stage2_entrypoint = const layout::STAGE2_BASE,
+    stage2_segment = const layout::STAGE2_BASE / 16,
+    memory_map_count = const MEMORY_MAP_COUNT,
+    memory_map_entries = const MEMORY_MAP_ENTRIES,
+    memory_map_entry_size = const MEMORY_MAP_ENTRY_SIZE,
+    memory_map_end = const MEMORY_MAP_ENTRIES + MEMORY_MAP_ENTRY_SIZE * MEMORY_MAP_MAX_ENTRIES,
+    smap = const SMAP,
+    boot_drive = const BOOT_DRIVE,
+    bios_drives = const BIOS_DRIVES,
+    drive_parameters_size = const DRIVE_PARAMETERS_SIZE,
+    bios_drives_end = const 0x80 + BIOS_DRIVE_COUNT,
+);
+
+#[cfg(target_os = "none")]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// Only built for the boot sector, this keeps host builds of the workspace linking
+#[cfg(not(target_os = "none"))]
+fn main() {}
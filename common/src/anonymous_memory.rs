@@ -0,0 +1,227 @@
+// Anonymous memory, mmap style: each task gets one of these along with its page tables, and asks
+// it for zeroed memory that isn't backed by anything. Nothing is mapped when a region is handed
+// out: the first access to each page faults, and the page fault handler gets it a zeroed frame of
+// the pool then, so that only the pages that are actually touched take up memory
+use core::ops::Range;
+
+use crate::{
+    control_registers::{Efer, ExtendedFeatureEnableRegisterBit},
+    error::Fault,
+    frame::{FRAME_SIZE, FrameAllocator},
+    msr::MemoryType,
+    paging::{Mapper, PageFaultErrorCode, PageTableEntry, PageTableEntryFlag},
+    vma::{AddressSpace, RegionFlag, RegionFlags},
+};
+
+/// Whether a page fault with `error_code` in a region with `flags` is one that mapping the page
+/// resolves: the page isn't there yet, and the access is one the region allows
+fn is_demand_fault(flags: RegionFlags, error_code: PageFaultErrorCode) -> bool {
+    !error_code.is_protection_violation()
+        && !error_code.is_reserved_bit_violation()
+        && (!error_code.is_write() || flags.is_set(RegionFlag::Write))
+        && (!error_code.is_instruction_fetch() || flags.is_set(RegionFlag::Execute))
+        && (!error_code.is_user_mode() || flags.is_set(RegionFlag::User))
+}
+
+/// The flags of the pages of a region with `flags`. ExecuteDisable is a reserved bit unless EFER
+/// enables it, in which case pages are only executable if the region is
+fn page_flags(flags: RegionFlags, execute_disable_enabled: bool) -> PageTableEntry {
+    let mut entry = PageTableEntry::empty();
+    if flags.is_set(RegionFlag::Write) {
+        entry.set_flag(PageTableEntryFlag::Write);
+    }
+    if flags.is_set(RegionFlag::User) {
+        entry.set_flag(PageTableEntryFlag::AllowUserModeAccess);
+    }
+    if execute_disable_enabled && !flags.is_set(RegionFlag::Execute) {
+        entry.set_flag(PageTableEntryFlag::ExecuteDisable);
+    }
+    entry
+}
+
+fn memory_type(flags: RegionFlags) -> MemoryType {
+    if flags.is_set(RegionFlag::Uncacheable) {
+        MemoryType::Uncacheable
+    } else {
+        MemoryType::WriteBack
+    }
+}
+
+fn execute_disable_enabled() -> bool {
+    Efer::read().is_set(ExtendedFeatureEnableRegisterBit::ExecuteDisableBitEnabled)
+}
+
+/// The anonymous memory of an address space, handed out of a window of it, e.g.
+/// `layout::USER_REGIONS`
+pub struct AnonymousMemory<const N: usize> {
+    regions: AddressSpace<N>,
+}
+
+impl<const N: usize> AnonymousMemory<N> {
+    pub const fn new(window: Range<u64>) -> Self {
+        Self {
+            regions: AddressSpace::new(window),
+        }
+    }
+
+    pub fn regions(&self) -> &AddressSpace<N> {
+        &self.regions
+    }
+
+    /// Hands out `size` bytes, rounded up to whole pages, which are mapped as they're first
+    /// accessed
+    pub fn mmap(&mut self, size: u64, flags: RegionFlags) -> Result<Range<u64>, Fault> {
+        self.regions.alloc_region(size, FRAME_SIZE, flags)
+    }
+
+    /// Records a range whose place is fixed, e.g. a segment of a program, which is mapped the same
+    /// way as what `mmap` hands out
+    pub fn reserve(&mut self, range: Range<u64>, flags: RegionFlags) -> Result<(), Fault> {
+        self.regions.reserve(range, flags)
+    }
+
+    /// Gives back the region starting at `start`, unmapping the pages of it that were accessed
+    /// from the page tables of `mapper` and releasing their frames to `frames`
+    pub fn munmap<const F: usize>(
+        &mut self,
+        start: u64,
+        mapper: &Mapper,
+        frames: &mut FrameAllocator<F>,
+    ) -> Result<(), Fault> {
+        let region = self.regions.free_region(start)?;
+        for page in region.range.step_by(FRAME_SIZE as usize) {
+            if mapper.page_entry(page).is_some() {
+                let (frame, _) = mapper.unmap(page)?;
+                frames.release(frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of the region starting at `start`, and of the pages of it that were
+    /// accessed already. Their memory type stays what it was, and pages shared copy-on-write only
+    /// become writable through the copy they get on the first write
+    pub fn mprotect(
+        &mut self,
+        start: u64,
+        flags: RegionFlags,
+        mapper: &Mapper,
+    ) -> Result<(), Fault> {
+        let range = self.regions.set_flags(start, flags)?;
+        let execute_disable_enabled = execute_disable_enabled();
+        let all_flags = page_flags(
+            RegionFlag::Write | RegionFlag::User,
+            execute_disable_enabled,
+        );
+        for page in range.step_by(FRAME_SIZE as usize) {
+            let Some((entry, _)) = mapper.page_entry(page) else {
+                continue;
+            };
+            let mut set = page_flags(flags, execute_disable_enabled);
+            if entry.is_set(PageTableEntryFlag::CopyOnWrite) {
+                set.clear_flag(PageTableEntryFlag::Write);
+            }
+            let clear = PageTableEntry::from(u64::from(all_flags) & !u64::from(set));
+            mapper.set_page_flags(page..page + FRAME_SIZE, set, clear, || None)?;
+        }
+        Ok(())
+    }
+
+    /// Resolves a page fault at `fault_address` if it's the first access to a page of a region
+    /// that allows it, by mapping a zeroed frame of `frames` there. True if the fault was resolved
+    /// and the faulting instruction can be retried
+    pub fn handle_page_fault<const F: usize>(
+        &self,
+        fault_address: u64,
+        error_code: PageFaultErrorCode,
+        mapper: &Mapper,
+        frames: &mut FrameAllocator<F>,
+    ) -> Result<bool, Fault> {
+        let Some(region) = self
+            .regions
+            .overlapping(&(fault_address..fault_address + 1))
+        else {
+            return Ok(false);
+        };
+        if !is_demand_fault(region.flags, error_code) {
+            return Ok(false);
+        }
+
+        self.map_zeroed_page(fault_address & !(FRAME_SIZE - 1), mapper, frames)?;
+        Ok(true)
+    }
+
+    /// Maps a zeroed frame of `frames` at `page`, of one of the regions, the way the region asks
+    /// for, returning the frame's physical address
+    pub fn map_zeroed_page<const F: usize>(
+        &self,
+        page: u64,
+        mapper: &Mapper,
+        frames: &mut FrameAllocator<F>,
+    ) -> Result<u64, Fault> {
+        let Some(region) = self.regions.overlapping(&(page..page + 1)) else {
+            return Err(Fault::NoRegionAt(page));
+        };
+        let frame = frames.allocate().ok_or(Fault::OutOfFrames)?;
+        let frame_ptr = mapper.physical_memory_offset().wrapping_add(frame) as usize as *mut u8;
+        // SAFETY: the frame was just allocated, so nothing else refers to it, and the pool is
+        // mapped at the physical memory offset
+        unsafe { core::ptr::write_bytes(frame_ptr, 0, FRAME_SIZE as usize) };
+        let result = mapper.map_4k(
+            page,
+            frame,
+            page_flags(region.flags, execute_disable_enabled()),
+            memory_type(region.flags),
+            || frames.allocate(),
+        );
+        if let Err(fault) = result {
+            frames.release(frame)?;
+            return Err(fault);
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        anonymous_memory::{is_demand_fault, page_flags},
+        paging::{PageFaultErrorCode, PageTableEntryFlag},
+        vma::{RegionFlag::*, RegionFlags},
+    };
+
+    // Bits of the error code
+    const PRESENT: u32 = 1 << 0;
+    const WRITE: u32 = 1 << 1;
+    const USER: u32 = 1 << 2;
+    const FETCH: u32 = 1 << 4;
+
+    #[test]
+    fn demand_faults() {
+        let fault = |flags: RegionFlags, error_code: u32| {
+            is_demand_fault(flags, PageFaultErrorCode::from(error_code))
+        };
+        let read_only = RegionFlags::empty();
+        assert!(fault(read_only, 0));
+        assert!(!fault(read_only, WRITE));
+        assert!(fault(Write.into(), WRITE));
+        // Already mapped, so not a first access
+        assert!(!fault(Write.into(), PRESENT | WRITE));
+        assert!(!fault(Write.into(), USER));
+        assert!(fault(Write | User, USER | WRITE));
+        assert!(!fault(Write | User, USER | FETCH));
+        assert!(fault(Execute | User, USER | FETCH));
+    }
+
+    #[test]
+    fn flags_of_pages() {
+        let flags = page_flags(Write | User, true);
+        assert!(flags.is_set(PageTableEntryFlag::Write));
+        assert!(flags.is_set(PageTableEntryFlag::AllowUserModeAccess));
+        assert!(flags.is_set(PageTableEntryFlag::ExecuteDisable));
+        assert!(!page_flags(Write | User, false).is_set(PageTableEntryFlag::ExecuteDisable));
+        let flags = page_flags(Execute.into(), true);
+        assert!(!flags.is_set(PageTableEntryFlag::Write));
+        assert!(!flags.is_set(PageTableEntryFlag::ExecuteDisable));
+    }
+}
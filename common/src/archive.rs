@@ -0,0 +1,521 @@
+// Archives the initrd can come as: POSIX ustar, and the "new" ASCII cpio format Linux uses for its
+// initramfs. Both are a header for each entry followed by its data, tar in 512-byte blocks with
+// octal numbers, cpio aligned to 4 bytes with hexadecimal ones. Only files and directories are
+// unpacked, as the tmpfs has nothing else: links and device nodes are skipped
+// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/pax.html#tag_20_92_13_06
+// https://www.kernel.org/doc/html/latest/driver-api/early-userspace/buffer-format.html
+use zerocopy::TryFromBytes;
+
+use crate::{
+    error::{Context, Error, Facility, Fault, try_read_error},
+    tmpfs::{NodeKind, Tmpfs},
+};
+
+pub const MAX_PATH_LENGTH: usize = 256;
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC: &[u8; 5] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_CHECKSUM_OFFSET: usize = 148;
+const TAR_CHECKSUM_SIZE: usize = 8;
+const TAR_REGULAR_FILE: u8 = b'0';
+// What tar used for regular files before there was a type flag
+const TAR_OLD_REGULAR_FILE: u8 = 0;
+const TAR_DIRECTORY: u8 = b'5';
+
+const CPIO_MAGIC: &[u8; 6] = b"070701";
+const CPIO_ALIGNMENT: usize = 4;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const FILE_TYPE_MASK: u32 = 0o170000;
+const REGULAR_FILE: u32 = 0o100000;
+const DIRECTORY: u32 = 0o040000;
+
+mod inner {
+    use zerocopy::{FromBytes, Immutable, KnownLayout, Unaligned};
+
+    #[derive(FromBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct TarHeader {
+        pub(super) name: [u8; 100],
+        pub(super) mode: [u8; 8],
+        pub(super) uid: [u8; 8],
+        pub(super) gid: [u8; 8],
+        pub(super) size: [u8; 12],
+        pub(super) mtime: [u8; 12],
+        pub(super) checksum: [u8; 8],
+        pub(super) type_flag: u8,
+        pub(super) link_name: [u8; 100],
+        pub(super) magic: [u8; 6],
+        pub(super) version: [u8; 2],
+        pub(super) user_name: [u8; 32],
+        pub(super) group_name: [u8; 32],
+        pub(super) device_major: [u8; 8],
+        pub(super) device_minor: [u8; 8],
+        pub(super) prefix: [u8; 155],
+        pub(super) padding: [u8; 12],
+    }
+
+    #[derive(FromBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct CpioHeader {
+        pub(super) magic: [u8; 6],
+        pub(super) inode: [u8; 8],
+        pub(super) mode: [u8; 8],
+        pub(super) uid: [u8; 8],
+        pub(super) gid: [u8; 8],
+        pub(super) links: [u8; 8],
+        pub(super) mtime: [u8; 8],
+        pub(super) file_size: [u8; 8],
+        pub(super) device_major: [u8; 8],
+        pub(super) device_minor: [u8; 8],
+        pub(super) rdevice_major: [u8; 8],
+        pub(super) rdevice_minor: [u8; 8],
+        pub(super) name_size: [u8; 8],
+        pub(super) check: [u8; 8],
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    Cpio,
+}
+
+impl Format {
+    /// Which format `bytes` are an archive of, if any
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(CPIO_MAGIC) {
+            Some(Self::Cpio)
+        } else if bytes
+            .get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len())
+            .is_some_and(|magic| magic == TAR_MAGIC)
+        {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+
+    fn facility(self) -> Facility {
+        match self {
+            Self::Tar => Facility::TarArchive,
+            Self::Cpio => Facility::CpioArchive,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// Links, device nodes and anything else there's no node for
+    Other,
+}
+
+/// A file or directory in an archive
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    path: [u8; MAX_PATH_LENGTH],
+    path_length: usize,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    /// Where the entry goes, relative to where the archive is unpacked to
+    pub fn path(&self) -> &str {
+        core::str::from_utf8(&self.path[..self.path_length]).unwrap_or_default()
+    }
+}
+
+/// A number in a header field, in ASCII with the given radix and padded with spaces or NULs
+fn parse_number(field: &[u8], radix: u32, name: &'static str) -> Result<u64, Fault> {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    let digits = core::str::from_utf8(&field[..end])
+        .map_err(|_| Fault::InvalidValueForField(name))?
+        .trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, radix).map_err(|_| Fault::InvalidValueForField(name))
+}
+
+/// The name in a NUL-terminated field, or that fills it
+fn field_str(field: &[u8]) -> &[u8] {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    &field[..end]
+}
+
+/// `parts` joined into a path, without "." components, or slashes at either end. ".." components
+/// are refused, as they'd have entries land outside the directory they're unpacked into
+fn join_path(parts: &[&[u8]]) -> Result<([u8; MAX_PATH_LENGTH], usize), Fault> {
+    let mut path = [0u8; MAX_PATH_LENGTH];
+    let mut length = 0;
+    for part in parts {
+        let part = core::str::from_utf8(part).map_err(|_| Fault::InvalidFileName)?;
+        for component in part
+            .split('/')
+            .filter(|component| !matches!(*component, "" | "."))
+        {
+            if component == ".." {
+                return Err(Fault::InvalidFileName);
+            }
+            let separator = usize::from(length != 0);
+            if length + separator + component.len() > MAX_PATH_LENGTH {
+                return Err(Fault::InvalidFileName);
+            }
+            if separator != 0 {
+                path[length] = b'/';
+                length += separator;
+            }
+            path[length..length + component.len()].copy_from_slice(component.as_bytes());
+            length += component.len();
+        }
+    }
+    Ok((path, length))
+}
+
+/// The entries of an archive, in the order they're in
+pub struct Entries<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+/// The entries of `bytes`, which must be an archive in one of the formats
+pub fn entries(bytes: &[u8]) -> Result<Entries<'_>, Error> {
+    let Some(format) = Format::detect(bytes) else {
+        return Err(Error::parsing_error(
+            Fault::InvalidValueForField("magic"),
+            Facility::TarArchive,
+        ));
+    };
+    Ok(Entries {
+        format,
+        bytes,
+        offset: 0,
+        done: false,
+    })
+}
+
+impl<'a> Entries<'a> {
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    fn data(&self, start: usize, size: u64, name: &'static str) -> Result<&'a [u8], Fault> {
+        usize::try_from(size)
+            .ok()
+            .and_then(|size| start.checked_add(size))
+            .and_then(|end| self.bytes.get(start..end))
+            .ok_or(Fault::NotEnoughBytesFor(name))
+    }
+
+    fn next_tar(&mut self) -> Result<Option<Entry<'a>>, Error> {
+        let facility = Facility::TarArchive;
+        let parsing_error = |fault| Error::parsing_error(fault, facility);
+        let Some(block) = self.bytes.get(self.offset..self.offset + TAR_BLOCK_SIZE) else {
+            // Archives are supposed to end with two zeroed blocks, but the end is the end
+            return Ok(None);
+        };
+        if block.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+        let (header, _) = inner::TarHeader::try_read_from_prefix(block)
+            .map_err(|err| try_read_error(facility, err))?;
+        if !header.magic.starts_with(TAR_MAGIC) {
+            return Err(parsing_error(Fault::InvalidValueForField("magic")));
+        }
+        // The sum of the header's bytes, with the checksum field taken as spaces
+        let checksum = parse_number(&header.checksum, 8, "checksum").map_err(parsing_error)?;
+        let sum: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(index, &byte)| {
+                let in_checksum =
+                    (TAR_CHECKSUM_OFFSET..TAR_CHECKSUM_OFFSET + TAR_CHECKSUM_SIZE).contains(&index);
+                (if in_checksum { b' ' } else { byte }) as u64
+            })
+            .sum();
+        if sum != checksum {
+            return Err(parsing_error(Fault::InvalidChecksum(sum as u16)));
+        }
+
+        let size = parse_number(&header.size, 8, "size").map_err(parsing_error)?;
+        let mode = parse_number(&header.mode, 8, "mode").map_err(parsing_error)? as u32;
+        let start = self.offset + TAR_BLOCK_SIZE;
+        let data = self.data(start, size, "data").map_err(parsing_error)?;
+        let (path, path_length) = join_path(&[field_str(&header.prefix), field_str(&header.name)])
+            .map_err(parsing_error)?;
+        self.offset = start + data.len().next_multiple_of(TAR_BLOCK_SIZE);
+        Ok(Some(Entry {
+            path,
+            path_length,
+            kind: match header.type_flag {
+                TAR_REGULAR_FILE | TAR_OLD_REGULAR_FILE => EntryKind::File,
+                TAR_DIRECTORY => EntryKind::Directory,
+                _ => EntryKind::Other,
+            },
+            mode: mode & !FILE_TYPE_MASK,
+            data,
+        }))
+    }
+
+    fn next_cpio(&mut self) -> Result<Option<Entry<'a>>, Error> {
+        let facility = Facility::CpioArchive;
+        let parsing_error = |fault| Error::parsing_error(fault, facility);
+        let bytes = self.bytes.get(self.offset..).unwrap_or_default();
+        let (header, _) = inner::CpioHeader::try_read_from_prefix(bytes)
+            .map_err(|err| try_read_error(facility, err))?;
+        if &header.magic != CPIO_MAGIC {
+            return Err(parsing_error(Fault::InvalidValueForField("magic")));
+        }
+        let mode = parse_number(&header.mode, 16, "mode").map_err(parsing_error)? as u32;
+        let size = parse_number(&header.file_size, 16, "file_size").map_err(parsing_error)?;
+        let name_size = parse_number(&header.name_size, 16, "name_size").map_err(parsing_error)?;
+
+        let name_start = self.offset + size_of::<inner::CpioHeader>();
+        // The size counts the NUL at the end of the name
+        let name = self
+            .data(name_start, name_size, "name")
+            .map_err(parsing_error)?;
+        let name = field_str(name);
+        if name == CPIO_TRAILER.as_bytes() {
+            return Ok(None);
+        }
+        let start = (name_start + name_size as usize).next_multiple_of(CPIO_ALIGNMENT);
+        let data = self.data(start, size, "data").map_err(parsing_error)?;
+        let (path, path_length) = join_path(&[name]).map_err(parsing_error)?;
+        self.offset = (start + data.len()).next_multiple_of(CPIO_ALIGNMENT);
+        Ok(Some(Entry {
+            path,
+            path_length,
+            kind: match mode & FILE_TYPE_MASK {
+                REGULAR_FILE => EntryKind::File,
+                DIRECTORY => EntryKind::Directory,
+                _ => EntryKind::Other,
+            },
+            mode: mode & !FILE_TYPE_MASK,
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = match self.format {
+            Format::Tar => self.next_tar(),
+            Format::Cpio => self.next_cpio(),
+        };
+        // Nothing after an error or the end can be trusted to be an entry
+        if !matches!(entry, Ok(Some(_))) {
+            self.done = true;
+        }
+        entry.transpose()
+    }
+}
+
+/// Unpacks the files and directories of the archive in `bytes` into `fs`, under the directory
+/// `destination`, creating the directories on the way to them if the archive doesn't have them.
+/// Files that exist already are overwritten. Returns how many entries were unpacked
+pub fn unpack<const NODES: usize, const BLOCKS: usize>(
+    bytes: &[u8],
+    fs: &mut Tmpfs<NODES, BLOCKS>,
+    destination: &str,
+) -> Result<usize, Error> {
+    let entries = entries(bytes)?;
+    let facility = entries.format().facility();
+    let io_error = |fault| Error::new(fault, Context::Io, facility);
+    let destination = destination.trim_end_matches('/');
+    let mut unpacked = 0;
+    for entry in entries {
+        let entry = entry?;
+        if entry.kind == EntryKind::Other || entry.path().is_empty() {
+            continue;
+        }
+        let (full_path, length) =
+            join_path(&[destination.as_bytes(), entry.path().as_bytes()]).map_err(io_error)?;
+        let full_path = core::str::from_utf8(&full_path[..length]).unwrap_or_default();
+
+        // Every directory on the way, then the entry itself
+        let separators = full_path.match_indices('/').map(|(index, _)| index);
+        for end in separators.chain([full_path.len()]) {
+            let kind = if end == full_path.len() && entry.kind == EntryKind::File {
+                NodeKind::File
+            } else {
+                NodeKind::Directory
+            };
+            let node = match fs.lookup(&full_path[..end]) {
+                Ok(node) => node,
+                Err(Fault::FileNotFound) => fs.create(&full_path[..end], kind).map_err(io_error)?,
+                Err(fault) => return Err(io_error(fault)),
+            };
+            if fs.kind(node).map_err(io_error)? != kind {
+                return Err(io_error(match kind {
+                    NodeKind::File => Fault::NotAFile,
+                    NodeKind::Directory => Fault::NotADirectory,
+                }));
+            }
+            if kind == NodeKind::File {
+                fs.truncate(node, 0).map_err(io_error)?;
+                fs.write(node, 0, entry.data).map_err(io_error)?;
+            }
+        }
+        unpacked += 1;
+    }
+    Ok(unpacked)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        archive::{self, EntryKind, Format},
+        error::Fault,
+        tmpfs::{NodeKind, Tmpfs},
+    };
+
+    /// A ustar header for `name`, with its checksum
+    fn tar_header(name: &str, type_flag: u8, size: usize) -> [u8; 512] {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        let mut size_field = [b'0'; 11];
+        let mut remaining = size;
+        for digit in size_field.iter_mut().rev() {
+            *digit = b'0' + (remaining % 8) as u8;
+            remaining /= 8;
+        }
+        header[124..135].copy_from_slice(&size_field);
+        header[156] = type_flag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[148..156].fill(b' ');
+        let sum: u32 = header.iter().map(|&byte| byte as u32).sum();
+        let mut checksum = [b'0'; 6];
+        let mut remaining = sum;
+        for digit in checksum.iter_mut().rev() {
+            *digit = b'0' + (remaining % 8) as u8;
+            remaining /= 8;
+        }
+        header[148..154].copy_from_slice(&checksum);
+        header[154] = 0;
+        header
+    }
+
+    #[test]
+    fn tar() {
+        let mut bytes = [0u8; 512 * 6];
+        bytes[..512].copy_from_slice(&tar_header("./etc/", b'5', 0));
+        bytes[512..1024].copy_from_slice(&tar_header("./etc/motd", b'0', 5));
+        bytes[1024..1029].copy_from_slice(b"hello");
+        bytes[1536..2048].copy_from_slice(&tar_header("./etc/link", b'2', 0));
+        assert_eq!(Some(Format::Tar), Format::detect(&bytes));
+
+        let mut entries = archive::entries(&bytes).unwrap();
+        let directory = entries.next().unwrap().unwrap();
+        assert_eq!(
+            ("etc", EntryKind::Directory),
+            (directory.path(), directory.kind)
+        );
+        let file = entries.next().unwrap().unwrap();
+        assert_eq!(("etc/motd", EntryKind::File), (file.path(), file.kind));
+        assert_eq!((b"hello".as_slice(), 0o644), (file.data, file.mode));
+        assert_eq!(EntryKind::Other, entries.next().unwrap().unwrap().kind);
+        assert!(entries.next().is_none());
+
+        let mut fs = Tmpfs::<4, 2>::new();
+        assert_eq!(Some(2), archive::unpack(&bytes, &mut fs, "/").ok());
+        let motd = fs.lookup("/etc/motd").unwrap();
+        let mut buffer = [0u8; 8];
+        assert_eq!(Some(5), fs.read(motd, 0, &mut buffer).ok());
+
+        // A corrupted header doesn't pass the checksum
+        bytes[520] ^= 1;
+        let mut entries = archive::entries(&bytes).unwrap();
+        assert!(entries.next().unwrap().is_ok());
+        assert!(entries.next().unwrap().is_err());
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn cpio() {
+        // As `find bin | cpio -o -H newc` writes them, without the parent directory
+        let mut bytes = [0u8; 512];
+        let mut length = 0;
+        for (name, mode, data) in [
+            ("bin/init", 0o100755, b"\x7fELF".as_slice()),
+            ("TRAILER!!!", 0, b"".as_slice()),
+        ] {
+            let header = [
+                "070701", "00000002", "", "00000000", "00000000", "00000001", "00000000", "",
+                "00000000", "00000000", "00000000", "00000000", "", "00000000",
+            ];
+            for (index, field) in header.iter().enumerate() {
+                let mut hex = [0u8; 8];
+                let field = match index {
+                    2 => format_hex(&mut hex, mode),
+                    7 => format_hex(&mut hex, data.len() as u32),
+                    12 => format_hex(&mut hex, name.len() as u32 + 1),
+                    _ => field.as_bytes(),
+                };
+                bytes[length..length + field.len()].copy_from_slice(field);
+                length += field.len();
+            }
+            bytes[length..length + name.len()].copy_from_slice(name.as_bytes());
+            length = (length + name.len() + 1).next_multiple_of(4);
+            bytes[length..length + data.len()].copy_from_slice(data);
+            length = (length + data.len()).next_multiple_of(4);
+        }
+        assert_eq!(Some(Format::Cpio), Format::detect(&bytes));
+
+        let mut fs = Tmpfs::<4, 2>::new();
+        assert_eq!(
+            Some(1),
+            archive::unpack(&bytes[..length], &mut fs, "/initrd").ok()
+        );
+        let init = fs.lookup("/initrd/bin/init").unwrap();
+        assert_eq!(Some(4), fs.size(init).ok());
+        // Cut short in the middle of the data
+        assert!(archive::unpack(&bytes[..130], &mut fs, "/").is_err());
+    }
+
+    #[test]
+    fn entries_outside_the_destination() {
+        let mut bytes = [0u8; 512 * 4];
+        bytes[..512].copy_from_slice(&tar_header("../x", b'0', 5));
+        bytes[512..517].copy_from_slice(b"hello");
+        let mut entries = archive::entries(&bytes).unwrap();
+        assert!(matches!(
+            entries.next(),
+            Some(Err(error)) if matches!(error.fault(), Fault::InvalidFileName)
+        ));
+
+        let mut fs = Tmpfs::<4, 2>::new();
+        let initrd = fs.create("/initrd", NodeKind::Directory).unwrap();
+        assert!(archive::unpack(&bytes, &mut fs, "/initrd").is_err());
+        assert!(matches!(fs.lookup("/x"), Err(Fault::FileNotFound)));
+        assert_eq!(1, fs.children(fs.root()).unwrap().count());
+        assert_eq!(0, fs.children(initrd).unwrap().count());
+    }
+
+    fn format_hex(buffer: &mut [u8; 8], value: u32) -> &[u8] {
+        for (index, digit) in buffer.iter_mut().enumerate() {
+            let nibble = (value >> (28 - 4 * index)) & 0xF;
+            *digit = b"0123456789ABCDEF"[nibble as usize];
+        }
+        buffer
+    }
+}
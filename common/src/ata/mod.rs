@@ -0,0 +1,1050 @@
+pub mod smart;
+
+use core::arch::asm;
+
+use crate::{
+    block_device::BlockDevice,
+    error::{Context, Error, Facility, Fault},
+    ioport::{IoPorts, Port, PortIo, PortOf},
+    make_bitmap,
+    pic::Irq,
+    timer, tunables,
+};
+
+// https://wiki.osdev.org/ATA_PIO_Mode#Resetting_a_drive_.2F_bus
+const SOFT_RESET_PULSE_NS: u64 = 10_000;
+const SOFT_RESET_SETTLE_NS: u64 = 2_000_000;
+const DEFAULT_READ_RETRIES: u8 = 3;
+
+pub const PRIMARY_BUS_IO_PORT_BASE_ADDRESS: u16 = 0x1F0;
+pub const PRIMARY_BUS_CONTROL_PORT_BASE_ADDRESS: u16 = 0x3F6;
+pub const SECONDARY_BUS_IO_PORT_BASE_ADDRESS: u16 = 0x170;
+pub const SECONDARY_BUS_CONTROL_PORT_BASE_ADDRESS: u16 = 0x376;
+/// The legacy channels, as their I/O and control port base addresses
+pub const CHANNELS: [(u16, u16); 2] = [
+    (
+        PRIMARY_BUS_IO_PORT_BASE_ADDRESS,
+        PRIMARY_BUS_CONTROL_PORT_BASE_ADDRESS,
+    ),
+    (
+        SECONDARY_BUS_IO_PORT_BASE_ADDRESS,
+        SECONDARY_BUS_CONTROL_PORT_BASE_ADDRESS,
+    ),
+];
+/// A master and a slave on each channel
+pub const MAX_DEVICES: usize = CHANNELS.len() * 2;
+const DEFAULT_SECTOR_SIZE_BYTES: u16 = 512;
+// Words 60 and 61 of the IDENTIFY data hold the number of LBA28 addressable sectors
+const IDENTIFY_LBA28_SECTORS_OFFSET: usize = 60 * size_of::<u16>();
+// What the LBA mid and high registers hold after a reset, or after an aborted IDENTIFY
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xEB);
+
+// https://wiki.osdev.org/ATAPI
+const PACKET_SIZE: usize = 12;
+// The upper nibble of the error register holds the SCSI sense key after a failed PACKET command
+const SENSE_KEY_SHIFT: u8 = 4;
+
+#[repr(u8)]
+enum ScsiCommand {
+    ReadCapacity10 = 0x25,
+    Read12 = 0xA8,
+}
+
+/// How a device waits for the data it was asked for
+#[derive(Debug, Clone, Copy)]
+pub enum WaitMode {
+    /// Spinning on the status register, which works without interrupts, e.g. in the bootloader
+    Polling,
+    /// Sleeping in the given function until the channel's IRQ. It's passed the I/O port base
+    /// address of the channel and a timeout, and returns false if the IRQ didn't come in time
+    Interrupts(fn(u16, u64) -> bool),
+}
+
+/// The command set a device on the bus speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Hard disks, read with ATA commands
+    Ata,
+    /// Optical drives, read with SCSI commands sent through the PACKET command
+    Atapi,
+}
+
+/// A device on an ATA channel, driven through the ports of `P`
+#[derive(Debug, Clone, Copy)]
+pub struct Device<P: PortIo = IoPorts> {
+    ports: P,
+    io_port_base_address: u16,
+    control_port_base_address: u16,
+    is_slave: bool,
+    protocol: Protocol,
+    wait_mode: WaitMode,
+    sectors: u64,
+    sector_size_bytes: u16,
+    read_retries: u8,
+}
+
+#[repr(u8)]
+enum Command {
+    ReadSectors = 0x20,
+    Packet = 0xA0,
+    IdentifyPacketDevice = 0xA1,
+    Smart = 0xB0,
+    IdentifyDevice = 0xEC,
+}
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum DriveHeadRegisterFlag {
+    Lba24Chs0 = 0x1,
+    Lba25Chs1 = 0x2,
+    Lba26Chs2 = 0x4,
+    Lba27Chs3 = 0x8,
+    IsSlave = 0x10,
+    AlwaysSet1 = 0x20,
+    Lba = 0x40,
+    AlwaysSet2 = 0x80,
+}
+
+make_bitmap!(new_type: DriveHeadRegisterFlags, underlying_flag_type: DriveHeadRegisterFlag, repr: u8, nodisplay);
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum StatusRegisterFlag {
+    Error = 0x1,
+    Index = 0x2,
+    CorrectedData = 0x4,
+    ReadyForSendReceive = 0x8, // DRQ
+    OverlappedModeServiceRequest = 0x10,
+    DriveFaultError = 0x20,
+    Spinning = 0x40,                   // RDY
+    BusyPreparingToSendReceive = 0x80, // BSY
+}
+
+make_bitmap!(new_type: StatusRegisterFlags, underlying_flag_type: StatusRegisterFlag, repr: u8, names: [
+    BusyPreparingToSendReceive => "BSY",
+    Spinning => "RDY",
+    DriveFaultError => "DF",
+    OverlappedModeServiceRequest => "SRV",
+    ReadyForSendReceive => "DRQ",
+    CorrectedData => "CORR",
+    Index => "IDX",
+    Error => "ERR",
+]);
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum ErrorRegisterFlag {
+    AddressMarkNotFound = 0x1, // AMNF
+    TrackZeroNotFound = 0x2,   // TKZNF
+    Aborted = 0x4,             // ABRT
+    MediaChangeRequest = 0x8,  // MCR
+    IdNotFound = 0x10,         // IDNF
+    MediaChanged = 0x20,       // MC
+    UncorrectableData = 0x40,  // UNC
+    BadBlock = 0x80,           // BBK
+}
+
+make_bitmap!(new_type: ErrorRegisterFlags, underlying_flag_type: ErrorRegisterFlag, repr: u8, names: [
+    BadBlock => "BBK",
+    UncorrectableData => "UNC",
+    MediaChanged => "MC",
+    IdNotFound => "IDNF",
+    MediaChangeRequest => "MCR",
+    Aborted => "ABRT",
+    TrackZeroNotFound => "TKZNF",
+    AddressMarkNotFound => "AMNF",
+]);
+
+impl ErrorRegisterFlags {
+    /// The most specific fault the error register reports. Aborts come last, as devices abort
+    /// the command whatever else went wrong
+    pub fn fault(&self) -> Fault {
+        use ErrorRegisterFlag::*;
+        if self.is_set(BadBlock) {
+            Fault::AtaBadBlock
+        } else if self.is_set(UncorrectableData) {
+            Fault::AtaUncorrectableData
+        } else if self.is_set(IdNotFound) {
+            Fault::AtaIdNotFound
+        } else if self.is_set(AddressMarkNotFound) {
+            Fault::AtaAddressMarkNotFound
+        } else if self.is_set(TrackZeroNotFound) {
+            Fault::AtaTrackZeroNotFound
+        } else if self.is_set(MediaChanged) || self.is_set(MediaChangeRequest) {
+            Fault::AtaMediaChanged
+        } else if self.is_set(Aborted) {
+            Fault::AtaCommandAborted
+        } else {
+            Fault::IOError
+        }
+    }
+}
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum DeviceControlRegisterFlag {
+    InterruptsDisabled = 0x2, // nIEN
+    SoftwareReset = 0x4,      // SRST
+    HighOrderByte = 0x80,     // HOB
+}
+
+make_bitmap!(new_type: DeviceControlRegisterFlags, underlying_flag_type: DeviceControlRegisterFlag, repr: u8, nodisplay);
+
+impl DriveHeadRegisterFlags {
+    pub fn new() -> Self {
+        use DriveHeadRegisterFlag::*;
+        AlwaysSet1 | AlwaysSet2
+    }
+
+    pub fn lba(mut self, address: u32) -> Self {
+        let flags = DriveHeadRegisterFlags {
+            bits: (address >> 24) as u8,
+        };
+        use DriveHeadRegisterFlag::*;
+        if flags.is_set(Lba24Chs0) {
+            self.set_flag(Lba24Chs0);
+        }
+        if flags.is_set(Lba25Chs1) {
+            self.set_flag(Lba25Chs1);
+        }
+        if flags.is_set(Lba26Chs2) {
+            self.set_flag(Lba26Chs2);
+        }
+        if flags.is_set(Lba27Chs3) {
+            self.set_flag(Lba27Chs3);
+        }
+        self.set_flag(DriveHeadRegisterFlag::Lba);
+        self
+    }
+}
+
+impl Device {
+    pub fn new(
+        io_port_base_address: u16,
+        control_port_base_address: u16,
+        is_slave: bool,
+        sectors: u64,
+        sector_size_bytes: u16,
+    ) -> Self {
+        Self::with_ports(
+            IoPorts,
+            io_port_base_address,
+            control_port_base_address,
+            is_slave,
+            sectors,
+            sector_size_bytes,
+        )
+    }
+
+    /// A device speaking ATAPI, e.g. a CD drive, whose sectors are read with `read_sectors_atapi`
+    pub fn new_atapi(
+        io_port_base_address: u16,
+        control_port_base_address: u16,
+        is_slave: bool,
+        sectors: u64,
+        sector_size_bytes: u16,
+    ) -> Self {
+        Self {
+            protocol: Protocol::Atapi,
+            ..Self::new(
+                io_port_base_address,
+                control_port_base_address,
+                is_slave,
+                sectors,
+                sector_size_bytes,
+            )
+        }
+    }
+
+    /// Probes the given bus for a device with the IDENTIFY command, for when there are no drive
+    /// parameters from the BIOS to build the device from. ATAPI devices are told apart by their
+    /// signature, and identified with IDENTIFY PACKET DEVICE instead
+    pub fn identify(
+        io_port_base_address: u16,
+        control_port_base_address: u16,
+        is_slave: bool,
+    ) -> Result<Self, Error> {
+        Self::identify_with_ports(
+            IoPorts,
+            io_port_base_address,
+            control_port_base_address,
+            is_slave,
+        )
+    }
+}
+
+#[allow(unused)]
+impl<P: PortIo> Device<P> {
+    /// Like `new`, through `ports`
+    pub fn with_ports(
+        ports: P,
+        io_port_base_address: u16,
+        control_port_base_address: u16,
+        is_slave: bool,
+        sectors: u64,
+        sector_size_bytes: u16,
+    ) -> Self {
+        Self {
+            ports,
+            io_port_base_address,
+            control_port_base_address,
+            is_slave,
+            protocol: Protocol::Ata,
+            wait_mode: WaitMode::Polling,
+            sectors,
+            sector_size_bytes,
+            read_retries: DEFAULT_READ_RETRIES,
+        }
+    }
+
+    /// Switches between polling and interrupts, enabling or disabling the channel's IRQ to match.
+    /// The IRQ is shared by both devices on the channel, so they should use the same mode
+    pub fn set_wait_mode(&mut self, wait_mode: WaitMode) {
+        self.wait_mode = wait_mode;
+        self.device_control_register()
+            .writeb(self.device_control().into());
+    }
+
+    /// The legacy IRQ of the channel, for devices on one of the legacy channels
+    pub fn irq(&self) -> Option<Irq> {
+        match self.io_port_base_address {
+            PRIMARY_BUS_IO_PORT_BASE_ADDRESS => Some(Irq::PrimaryAta),
+            SECONDARY_BUS_IO_PORT_BASE_ADDRESS => Some(Irq::SecondaryAta),
+            _ => None,
+        }
+    }
+
+    /// How many times a failed read is retried, after resetting the bus
+    pub fn set_read_retries(&mut self, read_retries: u8) {
+        self.read_retries = read_retries;
+    }
+
+    /// Like `identify`, through `ports`
+    // https://wiki.osdev.org/ATA_PIO_Mode#IDENTIFY_command
+    pub fn identify_with_ports(
+        ports: P,
+        io_port_base_address: u16,
+        control_port_base_address: u16,
+        is_slave: bool,
+    ) -> Result<Self, Error> {
+        let mut device = Self::with_ports(
+            ports,
+            io_port_base_address,
+            control_port_base_address,
+            is_slave,
+            0,
+            DEFAULT_SECTOR_SIZE_BYTES,
+        );
+
+        let mut drive_head_register_flags = DriveHeadRegisterFlags::new();
+        if is_slave {
+            drive_head_register_flags.set_flag(DriveHeadRegisterFlag::IsSlave);
+        }
+        device
+            .drive_head_register()
+            .writeb(drive_head_register_flags.into());
+        Self::courtesy_delay();
+        device.sector_count_register().writeb(0);
+        device.lba_low_register().writeb(0);
+        device.lba_mid_register().writeb(0);
+        device.lba_high_register().writeb(0);
+        device
+            .command_register()
+            .writeb(Command::IdentifyDevice as u8);
+
+        // A floating bus reads as all ones, a missing drive on a present bus as zero
+        if matches!(device.status_register().readb(), 0 | u8::MAX) {
+            return Err(device.io_error(Fault::NoAtaDevice));
+        }
+
+        let timeout_ns = tunables::get().ata_command_timeout_ns;
+        let mut timeout_timer = timer::LowPrecisionTimer::new(timeout_ns);
+        while device
+            .get_status()
+            .is_set(StatusRegisterFlag::BusyPreparingToSendReceive)
+            && !timeout_timer.timeout()
+        {
+            timeout_timer.update();
+        }
+
+        // ATAPI and SATA devices report their signature here instead of answering IDENTIFY
+        match device.signature() {
+            (0, 0) => {}
+            ATAPI_SIGNATURE => return Self::identify_packet_device(device),
+            _ => return Err(device.io_error(Fault::NoAtaDevice)),
+        }
+
+        device.poll_for_reads(timeout_ns)?;
+
+        let mut identify_data = [0u8; DEFAULT_SECTOR_SIZE_BYTES as usize];
+        device.read_data(&mut identify_data)?;
+
+        let mut sectors = [0u8; size_of::<u32>()];
+        sectors.copy_from_slice(
+            &identify_data
+                [IDENTIFY_LBA28_SECTORS_OFFSET..IDENTIFY_LBA28_SECTORS_OFFSET + size_of::<u32>()],
+        );
+        device.sectors = u32::from_le_bytes(sectors).into();
+
+        Ok(device)
+    }
+
+    /// Finishes identifying an ATAPI device, then asks it for the size of its medium
+    fn identify_packet_device(mut device: Self) -> Result<Self, Error> {
+        device.protocol = Protocol::Atapi;
+        device
+            .command_register()
+            .writeb(Command::IdentifyPacketDevice as u8);
+        device.poll_for_reads(tunables::get().ata_command_timeout_ns)?;
+        // Nothing in there is needed, but it has to be read for the command to complete
+        let mut identify_data = [0u8; DEFAULT_SECTOR_SIZE_BYTES as usize];
+        device.read_data(&mut identify_data)?;
+
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[0] = ScsiCommand::ReadCapacity10 as u8;
+        let mut capacity = [0u8; 2 * size_of::<u32>()];
+        device.send_packet(&packet, capacity.len() as u16)?;
+        device.read_packet_data(&mut capacity)?;
+        let last_lba = u32::from_be_bytes([capacity[0], capacity[1], capacity[2], capacity[3]]);
+        let sector_size_bytes =
+            u32::from_be_bytes([capacity[4], capacity[5], capacity[6], capacity[7]]);
+        device.sectors = u64::from(last_lba) + 1;
+        device.sector_size_bytes = u16::try_from(sector_size_bytes)
+            .map_err(|_| device.io_error(Fault::InvalidValueForField("ATAPI block length")))?;
+        Ok(device)
+    }
+
+    fn data_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address)
+    }
+
+    fn error_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 1)
+    }
+
+    fn features_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 1)
+    }
+
+    fn sector_count_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 2)
+    }
+
+    fn sector_number_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 3)
+    }
+
+    fn lba_low_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 3)
+    }
+
+    fn cylinder_low_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 4)
+    }
+
+    fn lba_mid_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 4)
+    }
+
+    fn cylinder_high_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 5)
+    }
+
+    fn lba_high_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 5)
+    }
+
+    fn drive_head_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 6)
+    }
+
+    fn status_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 7)
+    }
+
+    fn command_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.io_port_base_address + 7)
+    }
+
+    fn alternate_status_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.control_port_base_address)
+    }
+
+    fn device_control_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.control_port_base_address)
+    }
+
+    fn drive_address_register(&self) -> PortOf<'_, P> {
+        self.ports.port(self.control_port_base_address + 1)
+    }
+
+    fn io_error(&self, fault: Fault) -> Error {
+        Error::new(
+            fault,
+            Context::Io,
+            Facility::AtaDevice(self.io_port_base_address),
+        )
+    }
+
+    fn delay(delay_ns: u64) {
+        let mut delay = timer::LowPrecisionTimer::new(delay_ns);
+        while !delay.timeout() {
+            delay.update();
+        }
+    }
+
+    fn courtesy_delay() {
+        Self::delay(tunables::get().ata_courtesy_delay_ns);
+    }
+
+    fn get_error(&self) -> ErrorRegisterFlags {
+        ErrorRegisterFlags::from(self.error_register().readb())
+    }
+
+    /// Why the last command failed. ATAPI devices report a sense key on top of the ATA error bits
+    fn get_fault(&self) -> Fault {
+        let error = self.get_error();
+        let sense_key = u8::from(error) >> SENSE_KEY_SHIFT;
+        if self.protocol == Protocol::Atapi && sense_key != 0 {
+            Fault::AtapiSenseKey(sense_key)
+        } else {
+            error.fault()
+        }
+    }
+
+    fn signature(&self) -> (u8, u8) {
+        (
+            self.lba_mid_register().readb(),
+            self.lba_high_register().readb(),
+        )
+    }
+
+    fn read_data(&self, output_buffer: &mut [u8]) -> Result<(), Error> {
+        let n_words = output_buffer.len() / size_of::<u16>();
+        self.data_register()
+            .rep_insw(output_buffer, n_words as u16)
+            .map_err(|n_words| {
+                self.io_error(Fault::CantReadIntoBuffer(
+                    (n_words as usize * size_of::<u16>()) as u64,
+                    output_buffer.len() as u64,
+                ))
+            })
+    }
+
+    fn get_status(&self) -> StatusRegisterFlags {
+        StatusRegisterFlags::from(self.status_register().readb())
+    }
+
+    fn ready_for_command(&self) -> bool {
+        let status = self.get_status();
+        use StatusRegisterFlag::*;
+        status.is_set(Spinning) && !status.is_set(BusyPreparingToSendReceive)
+    }
+
+    fn is_busy(&self) -> bool {
+        self.get_status()
+            .is_set(StatusRegisterFlag::BusyPreparingToSendReceive)
+    }
+
+    fn wait_for_readiness(&self, timeout_ns: u64) -> Result<(), Error> {
+        Self::courtesy_delay();
+        let mut timeout_timer = timer::LowPrecisionTimer::new(timeout_ns);
+        while !self.ready_for_command() && !timeout_timer.timeout() {
+            timeout_timer.update();
+        }
+        if timeout_timer.timeout() && !self.ready_for_command() {
+            return Err(self.io_error(Fault::Timeout(timeout_ns)));
+        }
+        Ok(())
+    }
+
+    /// Sleeps until the channel's IRQ when the device uses interrupts, rather than spinning on the
+    /// status register while the device works
+    fn wait_for_interrupt(&self, timeout_ns: u64) -> Result<(), Error> {
+        match self.wait_mode {
+            WaitMode::Polling => Ok(()),
+            WaitMode::Interrupts(wait) if wait(self.io_port_base_address, timeout_ns) => Ok(()),
+            WaitMode::Interrupts(_) => Err(self.io_error(Fault::Timeout(timeout_ns))),
+        }
+    }
+
+    /// Waits for a command that doesn't transfer data to complete, reporting why it failed if it
+    /// did
+    fn wait_for_completion(&self, timeout_ns: u64) -> Result<(), Error> {
+        Self::courtesy_delay();
+        let mut timeout_timer = timer::LowPrecisionTimer::new(timeout_ns);
+        while self.is_busy() && !timeout_timer.timeout() {
+            timeout_timer.update();
+        }
+        let status = self.get_status();
+        if status.is_set(StatusRegisterFlag::BusyPreparingToSendReceive) {
+            return Err(self.io_error(Fault::Timeout(timeout_ns)));
+        }
+        if status.is_set(StatusRegisterFlag::DriveFaultError) {
+            return Err(self.io_error(Fault::AtaDriveFault));
+        }
+        if status.is_set(StatusRegisterFlag::Error) {
+            return Err(self.io_error(self.get_fault()));
+        }
+        Ok(())
+    }
+
+    /// Waits for the device to have data to send, or to report why it won't
+    fn poll_for_reads(&self, timeout_ns: u64) -> Result<(), Error> {
+        Self::courtesy_delay();
+        let mut timeout_timer = timer::LowPrecisionTimer::new(timeout_ns);
+        loop {
+            let status = self.get_status();
+            if !status.is_set(StatusRegisterFlag::BusyPreparingToSendReceive) {
+                if status.is_set(StatusRegisterFlag::DriveFaultError) {
+                    return Err(self.io_error(Fault::AtaDriveFault));
+                }
+                if status.is_set(StatusRegisterFlag::Error) {
+                    return Err(self.io_error(self.get_fault()));
+                }
+                if status.is_set(StatusRegisterFlag::ReadyForSendReceive) {
+                    return Ok(());
+                }
+            }
+            if timeout_timer.timeout() {
+                return Err(self.io_error(Fault::Timeout(timeout_ns)));
+            }
+            timeout_timer.update();
+        }
+    }
+
+    /// What the device control register holds outside of resets: interrupts are only enabled for
+    /// devices waiting for them
+    fn device_control(&self) -> DeviceControlRegisterFlags {
+        let mut device_control = DeviceControlRegisterFlags::empty();
+        if matches!(self.wait_mode, WaitMode::Polling) {
+            device_control.set_flag(DeviceControlRegisterFlag::InterruptsDisabled);
+        }
+        device_control
+    }
+
+    /// Resets both devices on the bus, getting them out of whatever state a failed command left
+    /// them in
+    // https://wiki.osdev.org/ATA_PIO_Mode#Resetting_a_drive_.2F_bus
+    pub fn soft_reset(&self) -> Result<(), Error> {
+        let mut device_control = self.device_control();
+        device_control.set_flag(DeviceControlRegisterFlag::SoftwareReset);
+        self.device_control_register().writeb(device_control.into());
+        Self::delay(SOFT_RESET_PULSE_NS);
+        self.device_control_register()
+            .writeb(self.device_control().into());
+        Self::delay(SOFT_RESET_SETTLE_NS);
+
+        let mut timeout_timer = timer::LowPrecisionTimer::new(tunables::get().ata_reset_timeout_ns);
+        while self.is_busy() && !timeout_timer.timeout() {
+            timeout_timer.update();
+        }
+        if self.is_busy() {
+            return Err(self.io_error(Fault::HangingAtaDevice));
+        }
+        Ok(())
+    }
+
+    /// Runs `read`, resetting the bus and retrying with an increasing backoff on failures a retry
+    /// could fix
+    fn with_retries(&self, mut read: impl FnMut() -> Result<(), Error>) -> Result<(), Error> {
+        let mut backoff_ns = tunables::get().ata_retry_backoff_ns;
+        let mut attempts_left = self.read_retries;
+        loop {
+            let err = match read() {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            // Neither bad arguments nor commands the device doesn't support go away by retrying
+            if attempts_left == 0
+                || matches!(
+                    err.fault(),
+                    Fault::InvalidLBAAddress(..)
+                        | Fault::CantReadIntoBuffer(..)
+                        | Fault::AtaCommandAborted
+                )
+            {
+                return Err(err);
+            }
+            attempts_left -= 1;
+            self.soft_reset()?;
+            Self::delay(backoff_ns);
+            backoff_ns = backoff_ns.saturating_mul(2);
+        }
+    }
+
+    /// Reads `sector_count` sectors starting at `lba_address` with the command set the device
+    /// speaks
+    pub fn read_sectors(
+        &self,
+        sector_count: u8,
+        lba_address: u32,
+        output_buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        match self.protocol {
+            Protocol::Ata => self.read_sectors_lba28_pio(sector_count, lba_address, output_buffer),
+            Protocol::Atapi => self.read_sectors_atapi(sector_count, lba_address, output_buffer),
+        }
+    }
+
+    /// Reads `sector_count` sectors starting at `lba_address` from an ATA device, retrying on
+    /// failures
+    pub fn read_sectors_lba28_pio(
+        &self,
+        sector_count: u8,
+        lba_address: u32,
+        output_buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        self.with_retries(|| {
+            self.try_read_sectors_lba28_pio(sector_count, lba_address, output_buffer)
+        })
+    }
+
+    /// Reads `sector_count` sectors starting at `lba_address` from an ATAPI device with READ(12),
+    /// retrying on failures
+    pub fn read_sectors_atapi(
+        &self,
+        sector_count: u8,
+        lba_address: u32,
+        output_buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        self.with_retries(|| self.try_read_sectors_atapi(sector_count, lba_address, output_buffer))
+    }
+
+    fn check_read_arguments(
+        &self,
+        sector_count: u8,
+        lba_address: u32,
+        output_buffer: &[u8],
+    ) -> Result<(), Error> {
+        if lba_address as u64 >= self.sectors {
+            return Err(self.io_error(Fault::InvalidLBAAddress(lba_address.into(), self.sectors)));
+        }
+
+        if (output_buffer.len() as u64) < (sector_count as u64 * self.sector_size_bytes as u64) {
+            return Err(self.io_error(Fault::CantReadIntoBuffer(
+                output_buffer.len() as u64,
+                sector_count as u64 * self.sector_size_bytes as u64,
+            )));
+        }
+        Ok(())
+    }
+
+    /// Sends a SCSI command to an ATAPI device, which answers with at most
+    /// `byte_count_limit` bytes per data transfer
+    fn send_packet(&self, packet: &[u8; PACKET_SIZE], byte_count_limit: u16) -> Result<(), Error> {
+        let mut drive_head_register_flags = DriveHeadRegisterFlags::new();
+        if self.is_slave {
+            drive_head_register_flags.set_flag(DriveHeadRegisterFlag::IsSlave);
+        }
+        self.drive_head_register()
+            .writeb(drive_head_register_flags.into());
+        Self::courtesy_delay();
+        // PIO, no DMA
+        self.features_register().writeb(0);
+        self.lba_mid_register().writeb(byte_count_limit as u8);
+        self.lba_high_register()
+            .writeb((byte_count_limit >> 8) as u8);
+        self.command_register().writeb(Command::Packet as u8);
+
+        self.poll_for_reads(tunables::get().ata_command_timeout_ns)?;
+        for word in packet.chunks_exact(size_of::<u16>()) {
+            self.data_register()
+                .writew(u16::from_le_bytes([word[0], word[1]]));
+        }
+        Ok(())
+    }
+
+    /// Reads the next data transfer of a PACKET command, which should fill `output_buffer`
+    fn read_packet_data(&self, output_buffer: &mut [u8]) -> Result<(), Error> {
+        self.poll_for_reads(tunables::get().ata_command_timeout_ns)?;
+        let (byte_count_low, byte_count_high) = self.signature();
+        let byte_count = u16::from_le_bytes([byte_count_low, byte_count_high]) as usize;
+        if byte_count != output_buffer.len() {
+            return Err(self.io_error(Fault::CantReadIntoBuffer(
+                output_buffer.len() as u64,
+                byte_count as u64,
+            )));
+        }
+        self.read_data(output_buffer)
+    }
+
+    fn try_read_sectors_atapi(
+        &self,
+        sector_count: u8,
+        lba_address: u32,
+        output_buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        self.check_read_arguments(sector_count, lba_address, output_buffer)?;
+
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[0] = ScsiCommand::Read12 as u8;
+        packet[2..6].copy_from_slice(&lba_address.to_be_bytes());
+        packet[6..10].copy_from_slice(&u32::from(sector_count).to_be_bytes());
+        // One sector per data transfer
+        self.send_packet(&packet, self.sector_size_bytes)?;
+
+        for sector in output_buffer
+            .chunks_exact_mut(self.sector_size_bytes as usize)
+            .take(sector_count as usize)
+        {
+            self.wait_for_interrupt(tunables::get().ata_command_timeout_ns)?;
+            self.read_packet_data(sector)?;
+        }
+        Ok(())
+    }
+
+    fn try_read_sectors_lba28_pio(
+        &self,
+        sector_count: u8,
+        lba_address: u32,
+        output_buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        self.check_read_arguments(sector_count, lba_address, output_buffer)?;
+        crate::trace_event!(
+            Ata,
+            "{:#x}: read {} at {}",
+            self.io_port_base_address,
+            sector_count,
+            lba_address
+        );
+
+        use DriveHeadRegisterFlag::*;
+        let mut drive_head_register_flags = DriveHeadRegisterFlags::new().lba(lba_address);
+        if self.is_slave {
+            drive_head_register_flags.set_flag(IsSlave);
+        }
+
+        self.drive_head_register()
+            .writeb(drive_head_register_flags.into());
+        self.sector_count_register().writeb(sector_count);
+        self.lba_low_register().writeb(lba_address as u8);
+        self.lba_mid_register().writeb((lba_address >> 8) as u8);
+        self.lba_high_register().writeb((lba_address >> 16) as u8);
+
+        self.wait_for_readiness(tunables::get().ata_command_timeout_ns)?;
+        self.command_register().writeb(Command::ReadSectors as u8);
+
+        for i in 0..sector_count {
+            self.wait_for_interrupt(tunables::get().ata_command_timeout_ns)?;
+            self.poll_for_reads(tunables::get().ata_command_timeout_ns)?;
+
+            let start = i as usize * self.sector_size_bytes as usize;
+            let end = start + (self.sector_size_bytes as usize);
+            let n_words = self.sector_size_bytes as usize / size_of::<u16>();
+
+            self.data_register()
+                .rep_insw(&mut output_buffer[start..end], n_words as u16)
+                .map_err(|n_words| {
+                    self.io_error(Fault::CantReadIntoBuffer(
+                        (n_words as usize * size_of::<u16>()) as u64,
+                        self.sector_size_bytes as u64,
+                    ))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn sector_size_bytes(&self) -> u16 {
+        self.sector_size_bytes
+    }
+
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn io_port_base_address(&self) -> u16 {
+        self.io_port_base_address
+    }
+
+    pub fn control_port_base_address(&self) -> u16 {
+        self.control_port_base_address
+    }
+
+    pub fn is_slave(&self) -> bool {
+        self.is_slave
+    }
+
+    /// Whether both refer to the same position on the same channel
+    pub fn is_same_drive(&self, other: &Self) -> bool {
+        self.io_port_base_address == other.io_port_base_address && self.is_slave == other.is_slave
+    }
+}
+
+/// Reads go through `read_sectors`, at most 255 sectors at a time. There are no writes to ATA
+/// devices yet
+impl<P: PortIo> BlockDevice for Device<P> {
+    fn sector_size_bytes(&self) -> u16 {
+        self.sector_size_bytes
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let sectors = buffer.len() / self.sector_size_bytes as usize;
+        let (Ok(lba), Ok(sectors)) = (u32::try_from(lba), u8::try_from(sectors)) else {
+            return Err(self.io_error(Fault::InvalidLBAAddress(lba, self.sectors)));
+        };
+        Device::read_sectors(self, sectors, lba, buffer)
+    }
+
+    fn write_sectors(&mut self, _lba: u64, _buffer: &[u8]) -> Result<(), Error> {
+        Err(self.io_error(Fault::ReadOnly))
+    }
+}
+
+/// Reads the status register of the channel at `io_port_base_address`, which is how its devices
+/// learn that their IRQ was handled
+pub fn acknowledge_interrupt(io_port_base_address: u16) {
+    Port::new(io_port_base_address + 7).readb();
+}
+
+/// Probes master and slave on both legacy channels, in that order. Positions without a device
+/// answering IDENTIFY are None
+pub fn scan() -> [Option<Device>; MAX_DEVICES] {
+    let mut devices = [None; MAX_DEVICES];
+    for ((io_port_base_address, control_port_base_address), devices) in
+        CHANNELS.into_iter().zip(devices.chunks_exact_mut(2))
+    {
+        for (is_slave, device) in [false, true].into_iter().zip(devices) {
+            *device =
+                Device::identify(io_port_base_address, control_port_base_address, is_slave).ok();
+        }
+    }
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        ata::{Device, ErrorRegisterFlags, StatusRegisterFlags},
+        error::Fault,
+        ioport::mock::MockPorts,
+        test_support::TestWriter,
+    };
+
+    // Ready, with data to send
+    const STATUS_DATA_READY: u16 = 0x58;
+
+    #[test]
+    fn registers_by_flag_name() {
+        let mut writer = TestWriter::<64>::new();
+        write!(
+            writer,
+            "{}",
+            StatusRegisterFlags::from(STATUS_DATA_READY as u8)
+        )
+        .unwrap();
+        assert_eq!("RDY|SRV|DRQ", writer.as_str());
+
+        let mut writer = TestWriter::<64>::new();
+        write!(
+            writer,
+            "{} {}",
+            ErrorRegisterFlags::from(0x44),
+            StatusRegisterFlags::empty()
+        )
+        .unwrap();
+        assert_eq!("UNC|ABRT 0x0", writer.as_str());
+    }
+
+    #[test]
+    fn error_register_faults() {
+        assert!(matches!(
+            ErrorRegisterFlags::from(0x04).fault(),
+            Fault::AtaCommandAborted
+        ));
+        // Aborts accompany the actual error
+        assert!(matches!(
+            ErrorRegisterFlags::from(0x44).fault(),
+            Fault::AtaUncorrectableData
+        ));
+        assert!(matches!(
+            ErrorRegisterFlags::from(0x14).fault(),
+            Fault::AtaIdNotFound
+        ));
+        assert!(matches!(
+            ErrorRegisterFlags::from(0x00).fault(),
+            Fault::IOError
+        ));
+    }
+
+    #[test]
+    fn identify_then_read() {
+        let mut identify_data = [0u8; 512];
+        identify_data[120..124].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+        let sectors: [u8; 1024] = core::array::from_fn(|i| i as u8);
+        let ports = MockPorts::new();
+        ports.stub(0x1F7, STATUS_DATA_READY);
+        ports.feed(0x1F0, &identify_data);
+
+        let device = Device::identify_with_ports(&ports, 0x1F0, 0x3F6, false).unwrap();
+        assert_eq!(0x0FFF_FFFF, device.sectors());
+        assert_eq!(
+            [
+                (0x1F6, 0xA0),
+                (0x1F2, 0),
+                (0x1F3, 0),
+                (0x1F4, 0),
+                (0x1F5, 0),
+                (0x1F7, 0xEC)
+            ],
+            *ports.writes()
+        );
+
+        ports.clear_writes();
+        ports.feed(0x1F0, &sectors);
+        let mut buffer = [0u8; 1024];
+        device.read_sectors(2, 0x0123_4567, &mut buffer).unwrap();
+        assert_eq!(sectors, buffer);
+        assert_eq!(
+            [
+                (0x1F6, 0xE1),
+                (0x1F2, 2),
+                (0x1F3, 0x67),
+                (0x1F4, 0x45),
+                (0x1F5, 0x23),
+                (0x1F7, 0x20)
+            ],
+            *ports.writes()
+        );
+    }
+
+    #[test]
+    fn failed_reads() {
+        let ports = MockPorts::new();
+        // Ready, with an error: the command was aborted
+        ports.stub(0x177, 0x41);
+        ports.stub(0x171, 0x04);
+        let device = Device::with_ports(&ports, 0x170, 0x376, true, 100, 512);
+        let mut buffer = [0u8; 512];
+
+        let err = device.read_sectors(1, 100, &mut buffer).unwrap_err();
+        assert!(matches!(err.fault(), Fault::InvalidLBAAddress(100, 100)));
+        assert!(ports.writes().is_empty());
+
+        // Not retried, so there's no reset either
+        let err = device.read_sectors(1, 5, &mut buffer).unwrap_err();
+        assert!(matches!(err.fault(), Fault::AtaCommandAborted));
+        assert_eq!(
+            [
+                (0x176, 0xF0),
+                (0x172, 1),
+                (0x173, 5),
+                (0x174, 0),
+                (0x175, 0),
+                (0x177, 0x20)
+            ],
+            *ports.writes()
+        );
+    }
+}
@@ -0,0 +1,332 @@
+// https://en.wikipedia.org/wiki/Self-Monitoring,_Analysis_and_Reporting_Technology
+// ATA/ATAPI-7 volume 1, section 6.54 for the commands, and the SFF-8035i draft for the layout of
+// the data read
+use core::fmt::Display;
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    ata::{Command, Device, DriveHeadRegisterFlag, DriveHeadRegisterFlags},
+    error::{Error, Facility, Fault},
+    ioport::PortIo,
+    tunables,
+};
+
+/// Slots in the attribute table, not all of which are used
+pub const MAX_ATTRIBUTES: usize = 30;
+const DATA_SIZE_BYTES: usize = 512;
+// Written to LBA mid and high to unlock the SMART command, and what RETURN STATUS leaves there
+// when no attribute is past its threshold
+const SMART_KEY: (u8, u8) = (0x4F, 0xC2);
+const THRESHOLD_EXCEEDED: (u8, u8) = (0xF4, 0x2C);
+const PREFAILURE_FLAG: u16 = 0x1;
+
+mod inner {
+    use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, LE, U16, Unaligned};
+
+    use super::MAX_ATTRIBUTES;
+
+    #[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Attribute {
+        pub(super) id: u8,
+        pub(super) flags: U16<LE>,
+        pub(super) current: u8,
+        pub(super) worst: u8,
+        pub(super) raw: [u8; 6],
+        pub(super) reserved: u8,
+    }
+
+    #[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Data {
+        pub(super) revision: U16<LE>,
+        pub(super) attributes: [Attribute; MAX_ATTRIBUTES],
+        pub(super) rest: [u8; 149],
+        pub(super) checksum: u8,
+    }
+
+    #[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Threshold {
+        pub(super) id: u8,
+        pub(super) threshold: u8,
+        pub(super) reserved: [u8; 10],
+    }
+
+    #[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Thresholds {
+        pub(super) revision: U16<LE>,
+        pub(super) thresholds: [Threshold; MAX_ATTRIBUTES],
+        pub(super) rest: [u8; 149],
+        pub(super) checksum: u8,
+    }
+}
+
+#[repr(u8)]
+enum Subcommand {
+    ReadData = 0xD0,
+    ReadThresholds = 0xD1,
+    Enable = 0xD8,
+    ReturnStatus = 0xDA,
+}
+
+/// The attributes most drives agree on the meaning of
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AttributeId {
+    RawReadErrorRate = 1,
+    ThroughputPerformance = 2,
+    SpinUpTime = 3,
+    StartStopCount = 4,
+    ReallocatedSectorsCount = 5,
+    SeekErrorRate = 7,
+    PowerOnHours = 9,
+    SpinRetryCount = 10,
+    PowerCycleCount = 12,
+    PowerOffRetractCount = 192,
+    LoadCycleCount = 193,
+    Temperature = 194,
+    ReallocationEventCount = 196,
+    CurrentPendingSectorCount = 197,
+    OfflineUncorrectable = 198,
+    UltraDmaCrcErrorCount = 199,
+}
+
+/// What RETURN STATUS reports about the drive as a whole
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Healthy,
+    /// Some attribute went past its threshold, the drive is expected to fail soon
+    ThresholdExceeded,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attribute {
+    pub id: u8,
+    pub flags: u16,
+    /// Normalized values, the higher the better. Their range is up to the vendor
+    pub current: u8,
+    pub worst: u8,
+    /// 0 for attributes that are only informational
+    pub threshold: u8,
+    /// Vendor specific, though often a plain count
+    pub raw: u64,
+}
+
+impl AttributeId {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AttributeId::RawReadErrorRate => "Raw read error rate",
+            AttributeId::ThroughputPerformance => "Throughput performance",
+            AttributeId::SpinUpTime => "Spin-up time",
+            AttributeId::StartStopCount => "Start/stop count",
+            AttributeId::ReallocatedSectorsCount => "Reallocated sectors",
+            AttributeId::SeekErrorRate => "Seek error rate",
+            AttributeId::PowerOnHours => "Power-on hours",
+            AttributeId::SpinRetryCount => "Spin retry count",
+            AttributeId::PowerCycleCount => "Power cycle count",
+            AttributeId::PowerOffRetractCount => "Power-off retract count",
+            AttributeId::LoadCycleCount => "Load cycle count",
+            AttributeId::Temperature => "Temperature",
+            AttributeId::ReallocationEventCount => "Reallocation events",
+            AttributeId::CurrentPendingSectorCount => "Pending sectors",
+            AttributeId::OfflineUncorrectable => "Offline uncorrectable",
+            AttributeId::UltraDmaCrcErrorCount => "UltraDMA CRC errors",
+        }
+    }
+}
+
+impl Attribute {
+    pub fn kind(&self) -> Option<AttributeId> {
+        AttributeId::try_from(self.id).ok()
+    }
+
+    /// Pre-failure attributes going past their threshold predict an imminent failure, the others
+    /// just old age
+    pub fn is_prefailure(&self) -> bool {
+        self.flags & PREFAILURE_FLAG != 0
+    }
+
+    pub fn is_past_threshold(&self) -> bool {
+        self.threshold != 0 && self.current <= self.threshold
+    }
+}
+
+impl Display for Attribute {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:3} {:<24} value {:3} worst {:3} threshold {:3} raw {}{}",
+            self.id,
+            self.kind().map_or("Unknown", |kind| kind.name()),
+            self.current,
+            self.worst,
+            self.threshold,
+            self.raw,
+            if self.is_past_threshold() {
+                " FAILING"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+/// The attribute table of a drive, with the threshold of each attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    attributes: [Option<Attribute>; MAX_ATTRIBUTES],
+}
+
+fn checksum_error(sum: u8) -> Error {
+    Error::parsing_error(Fault::InvalidChecksum(sum.into()), Facility::SmartData)
+}
+
+impl Attributes {
+    /// Builds the table from what READ DATA and READ THRESHOLDS return
+    pub fn parse(
+        data: [u8; DATA_SIZE_BYTES],
+        thresholds: [u8; DATA_SIZE_BYTES],
+    ) -> Result<Self, Error> {
+        // The last byte makes each structure sum to 0
+        for bytes in [&data, &thresholds] {
+            let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            if sum != 0 {
+                return Err(checksum_error(sum));
+            }
+        }
+        let data: inner::Data = zerocopy::transmute!(data);
+        let thresholds: inner::Thresholds = zerocopy::transmute!(thresholds);
+
+        let mut attributes = [None; MAX_ATTRIBUTES];
+        for (attribute, raw_attribute) in attributes.iter_mut().zip(&data.attributes) {
+            // Unused slots have ID 0
+            if raw_attribute.id == 0 {
+                continue;
+            }
+            let mut raw = [0u8; size_of::<u64>()];
+            raw[..raw_attribute.raw.len()].copy_from_slice(&raw_attribute.raw);
+            *attribute = Some(Attribute {
+                id: raw_attribute.id,
+                flags: raw_attribute.flags.get(),
+                current: raw_attribute.current,
+                worst: raw_attribute.worst,
+                // Thresholds aren't necessarily in the same slots as the attributes
+                threshold: thresholds
+                    .thresholds
+                    .iter()
+                    .find(|threshold| threshold.id == raw_attribute.id)
+                    .map_or(0, |threshold| threshold.threshold),
+                raw: u64::from_le_bytes(raw),
+            });
+        }
+        Ok(Self { attributes })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Attribute> {
+        self.attributes.iter().flatten()
+    }
+
+    pub fn get(&self, id: AttributeId) -> Option<&Attribute> {
+        self.iter().find(|attribute| attribute.id == id as u8)
+    }
+}
+
+impl<P: PortIo> Device<P> {
+    fn send_smart_command(&self, subcommand: Subcommand) -> Result<(), Error> {
+        let mut drive_head_register_flags = DriveHeadRegisterFlags::new();
+        if self.is_slave {
+            drive_head_register_flags.set_flag(DriveHeadRegisterFlag::IsSlave);
+        }
+        self.drive_head_register()
+            .writeb(drive_head_register_flags.into());
+        self.wait_for_readiness(tunables::get().ata_command_timeout_ns)?;
+        self.features_register().writeb(subcommand as u8);
+        self.sector_count_register().writeb(0);
+        self.lba_low_register().writeb(0);
+        self.lba_mid_register().writeb(SMART_KEY.0);
+        self.lba_high_register().writeb(SMART_KEY.1);
+        self.command_register().writeb(Command::Smart as u8);
+        Ok(())
+    }
+
+    fn read_smart_data(&self, subcommand: Subcommand) -> Result<[u8; DATA_SIZE_BYTES], Error> {
+        self.send_smart_command(subcommand)?;
+        self.poll_for_reads(tunables::get().ata_command_timeout_ns)?;
+        let mut data = [0u8; DATA_SIZE_BYTES];
+        self.read_data(&mut data)?;
+        Ok(data)
+    }
+
+    /// Turns SMART on, which the other SMART commands need. Devices without SMART abort this
+    pub fn smart_enable(&self) -> Result<(), Error> {
+        self.send_smart_command(Subcommand::Enable)?;
+        self.wait_for_completion(tunables::get().ata_command_timeout_ns)
+    }
+
+    pub fn smart_status(&self) -> Result<Status, Error> {
+        self.send_smart_command(Subcommand::ReturnStatus)?;
+        self.wait_for_completion(tunables::get().ata_command_timeout_ns)?;
+        match (
+            self.lba_mid_register().readb(),
+            self.lba_high_register().readb(),
+        ) {
+            SMART_KEY => Ok(Status::Healthy),
+            THRESHOLD_EXCEEDED => Ok(Status::ThresholdExceeded),
+            status => Err(self.io_error(Fault::InvalidSmartStatus(status))),
+        }
+    }
+
+    pub fn smart_attributes(&self) -> Result<Attributes, Error> {
+        let data = self.read_smart_data(Subcommand::ReadData)?;
+        let thresholds = self.read_smart_data(Subcommand::ReadThresholds)?;
+        Attributes::parse(data, thresholds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ata::smart::{AttributeId, Attributes, DATA_SIZE_BYTES};
+
+    fn seal(mut bytes: [u8; DATA_SIZE_BYTES]) -> [u8; DATA_SIZE_BYTES] {
+        let sum = bytes[..DATA_SIZE_BYTES - 1]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        bytes[DATA_SIZE_BYTES - 1] = sum.wrapping_neg();
+        bytes
+    }
+
+    #[test]
+    fn attributes() {
+        let mut data = [0u8; DATA_SIZE_BYTES];
+        data[0] = 0x10;
+        // Reallocated sectors, pre-failure, value 100 (worst 99), raw 3
+        data[2..14].copy_from_slice(&[5, 0x33, 0x00, 100, 99, 3, 0, 0, 0, 0, 0, 0]);
+        // Temperature, value 64, raw 36
+        data[14..26].copy_from_slice(&[194, 0x22, 0x00, 64, 40, 36, 0, 0, 0, 0, 0, 0]);
+        let mut thresholds = [0u8; DATA_SIZE_BYTES];
+        thresholds[0] = 0x10;
+        // In the opposite order of the attributes
+        thresholds[2..4].copy_from_slice(&[194, 0]);
+        thresholds[14..16].copy_from_slice(&[5, 100]);
+
+        assert!(Attributes::parse(data, seal(thresholds)).is_err());
+        let attributes = Attributes::parse(seal(data), seal(thresholds)).unwrap();
+        assert_eq!(2, attributes.iter().count());
+
+        let reallocated_sectors = attributes
+            .get(AttributeId::ReallocatedSectorsCount)
+            .unwrap();
+        assert!(reallocated_sectors.is_prefailure());
+        assert_eq!(100, reallocated_sectors.threshold);
+        assert!(reallocated_sectors.is_past_threshold());
+        assert_eq!(3, reallocated_sectors.raw);
+
+        let temperature = attributes.get(AttributeId::Temperature).unwrap();
+        assert!(!temperature.is_prefailure());
+        assert!(!temperature.is_past_threshold());
+        assert_eq!(36, temperature.raw);
+    }
+}
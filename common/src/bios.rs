@@ -0,0 +1,685 @@
+// The BIOS services the boot chain uses, as typed calls: each one knows which registers to load
+// before its software interrupt and how to read the outcome back out of them, so the register
+// conventions live here rather than in every piece of real mode code that needs them. Running the
+// interrupt is up to a `Bios`, i.e. whatever can get to real mode and back (a thunk down from
+// protected mode, or a fake one in tests)
+// https://www.ctyme.com/intr/int.htm
+// http://www.o3one.org/hwdocs/bios_doc/bios_specs_edd30.pdf
+// https://www.phatcode.net/res/221/files/vbe20.pdf
+use core::fmt::Display;
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    e820,
+    error::{Context, Error, Facility, Fault},
+    make_bitmap,
+};
+
+pub const VIDEO: u8 = 0x10;
+pub const DISK: u8 = 0x13;
+pub const SYSTEM: u8 = 0x15;
+
+const CARRY_FLAG: u32 = 1 << 0;
+// "SMAP", which E820 wants in EDX and answers with in EAX
+const SMAP: u32 = 0x534D_4150;
+// AL=4Fh: the function is supported, AH=0: it succeeded
+const VBE_SUCCESS: u16 = 0x004F;
+const VBE_LINEAR_FRAMEBUFFER: u16 = 1 << 14;
+
+/// The registers around a real mode software interrupt: loaded before it, and holding what the
+/// BIOS returned after it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Registers {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub ds: u16,
+    pub es: u16,
+    pub flags: u32,
+}
+
+impl Registers {
+    pub fn ah(&self) -> u8 {
+        (self.eax >> 8) as u8
+    }
+
+    pub fn ax(&self) -> u16 {
+        self.eax as u16
+    }
+
+    pub fn bx(&self) -> u16 {
+        self.ebx as u16
+    }
+
+    pub fn cx(&self) -> u16 {
+        self.ecx as u16
+    }
+
+    pub fn carry(&self) -> bool {
+        self.flags & CARRY_FLAG != 0
+    }
+
+    /// AH=`function`, AL=`argument`
+    fn with_ax(mut self, function: u8, argument: u8) -> Self {
+        self.eax = u32::from(function) << 8 | u32::from(argument);
+        self
+    }
+
+    /// DS:SI, where the disk services take their buffers
+    fn with_ds_si(mut self, pointer: FarPointer) -> Self {
+        self.ds = pointer.segment;
+        self.esi = pointer.offset.into();
+        self
+    }
+
+    /// ES:DI, where the system and video services take theirs
+    fn with_es_di(mut self, pointer: FarPointer) -> Self {
+        self.es = pointer.segment;
+        self.edi = pointer.offset.into();
+        self
+    }
+}
+
+/// A real mode segment:offset address
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FarPointer {
+    pub segment: u16,
+    pub offset: u16,
+}
+
+impl FarPointer {
+    /// The pointer with the smallest offset to a linear address, if real mode can reach it
+    pub fn from_linear(address: u32) -> Option<Self> {
+        if address >= 1 << 20 {
+            return None;
+        }
+        Some(Self {
+            segment: (address >> 4) as u16,
+            offset: (address & 0xF) as u16,
+        })
+    }
+
+    pub fn linear(&self) -> u32 {
+        (u32::from(self.segment) << 4) + u32::from(self.offset)
+    }
+}
+
+/// Whatever can run a software interrupt in real mode
+pub trait Bios {
+    /// Runs `int vector` with `registers` loaded, then stores the registers and flags it returned
+    /// with into `registers`
+    fn interrupt(&mut self, vector: u8, registers: &mut Registers);
+}
+
+impl<F: FnMut(u8, &mut Registers) + ?Sized> Bios for F {
+    fn interrupt(&mut self, vector: u8, registers: &mut Registers) {
+        self(vector, registers)
+    }
+}
+
+/// A BIOS service and its arguments
+pub trait Call {
+    const VECTOR: u8;
+    type Output;
+
+    fn registers(&self) -> Registers;
+    fn output(&self, registers: &Registers) -> Result<Self::Output, Error>;
+}
+
+/// The disk services, which a disk reset may get going again after a failure
+pub trait DiskCall: Call {
+    fn drive(&self) -> u8;
+}
+
+fn failed(vector: u8, status: u8) -> Error {
+    Error::new(
+        Fault::BiosCallFailed { vector, status },
+        Context::Io,
+        Facility::Bios,
+    )
+}
+
+/// Failed if the BIOS set the carry flag, with the status it left in AH
+fn carry_status(vector: u8, registers: &Registers) -> Result<(), Error> {
+    if registers.carry() {
+        return Err(failed(vector, registers.ah()));
+    }
+    Ok(())
+}
+
+fn vbe_status(registers: &Registers) -> Result<(), Error> {
+    if registers.ax() != VBE_SUCCESS {
+        return Err(failed(VIDEO, registers.ah()));
+    }
+    Ok(())
+}
+
+pub fn call<B: Bios + ?Sized, C: Call>(bios: &mut B, call: &C) -> Result<C::Output, Error> {
+    let mut registers = call.registers();
+    bios.interrupt(C::VECTOR, &mut registers);
+    call.output(&registers)
+}
+
+/// Runs a disk service, resetting the drive and trying again after each failure, up to `attempts`
+/// times in total. Gives up early if the reset itself fails
+pub fn call_disk<B: Bios + ?Sized, C: DiskCall>(
+    bios: &mut B,
+    disk_call: &C,
+    attempts: usize,
+) -> Result<C::Output, Error> {
+    let mut result = call(bios, disk_call);
+    for _ in 1..attempts {
+        if result.is_ok() {
+            break;
+        }
+        call(
+            bios,
+            &ResetDisk {
+                drive: disk_call.drive(),
+            },
+        )?;
+        result = call(bios, disk_call);
+    }
+    result
+}
+
+/// INT 13h, AH=00h
+pub struct ResetDisk {
+    pub drive: u8,
+}
+
+impl Call for ResetDisk {
+    const VECTOR: u8 = DISK;
+    type Output = ();
+
+    fn registers(&self) -> Registers {
+        Registers {
+            edx: self.drive.into(),
+            ..Default::default()
+        }
+        .with_ax(0x00, 0)
+    }
+
+    fn output(&self, registers: &Registers) -> Result<(), Error> {
+        carry_status(Self::VECTOR, registers)
+    }
+}
+
+#[derive(TryFromPrimitive, Clone, Copy)]
+#[repr(u16)]
+pub enum ExtensionType {
+    FixedDiskAccess = 0x1,
+    DriveLocking = 0x2,
+    EnhancedDiskDrive = 0x4,
+    SixtyFourBitAddresses = 0x8,
+}
+
+impl Display for ExtensionType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ExtensionType::FixedDiskAccess => write!(f, "FIXED_DISK_ACCESS"),
+            ExtensionType::DriveLocking => write!(f, "DRIVE_LOCKING"),
+            ExtensionType::EnhancedDiskDrive => write!(f, "ENHANCED_DISK_DRIVE"),
+            ExtensionType::SixtyFourBitAddresses => write!(f, "64_BIT_ADDRESSES"),
+        }
+    }
+}
+
+make_bitmap!(new_type: ExtensionTypes, underlying_flag_type: ExtensionType, repr: u16, bit_skipper: |i| i > 3);
+
+/// What the BIOS supports of the int 13h extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions {
+    /// The major version in BCD, e.g. 0x30 for EDD 3.0
+    pub version: u8,
+    pub supported: ExtensionTypes,
+}
+
+impl Extensions {
+    /// From the version and the bitmap stage1 got in AH and CX, and hands over as dwords
+    pub fn from_handoff(version: u32, bitmap: u32) -> Self {
+        Self {
+            version: version as u8,
+            supported: (bitmap as u16).into(),
+        }
+    }
+}
+
+impl Display for Extensions {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "version {:x}.{:x} ({})",
+            self.version >> 4,
+            self.version & 0xF,
+            self.supported
+        )
+    }
+}
+
+/// INT 13h, AH=41h: checks the extensions are there
+pub struct CheckExtensions {
+    pub drive: u8,
+}
+
+impl Call for CheckExtensions {
+    const VECTOR: u8 = DISK;
+    type Output = Extensions;
+
+    fn registers(&self) -> Registers {
+        Registers {
+            ebx: 0x55AA,
+            edx: self.drive.into(),
+            ..Default::default()
+        }
+        .with_ax(0x41, 0)
+    }
+
+    fn output(&self, registers: &Registers) -> Result<Extensions, Error> {
+        carry_status(Self::VECTOR, registers)?;
+        // BIOSes that don't know the function may leave the carry clear, but don't swap BX
+        if registers.bx() != 0xAA55 {
+            return Err(failed(Self::VECTOR, registers.ah()));
+        }
+        Ok(Extensions {
+            version: registers.ah(),
+            supported: registers.cx().into(),
+        })
+    }
+}
+
+impl DiskCall for CheckExtensions {
+    fn drive(&self) -> u8 {
+        self.drive
+    }
+}
+
+/// The packet INT 13h, AH=42h reads with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct DiskAddressPacket {
+    size: u8,
+    reserved: u8,
+    pub sectors: u16,
+    pub buffer_offset: u16,
+    pub buffer_segment: u16,
+    pub lba: u64,
+}
+
+impl DiskAddressPacket {
+    pub fn new(lba: u64, sectors: u16, buffer: FarPointer) -> Self {
+        Self {
+            size: size_of::<Self>() as u8,
+            reserved: 0,
+            sectors,
+            buffer_offset: buffer.offset,
+            buffer_segment: buffer.segment,
+            lba,
+        }
+    }
+}
+
+/// INT 13h, AH=42h: reads what the disk address packet at `packet` asks for
+pub struct ExtendedRead {
+    pub drive: u8,
+    pub packet: FarPointer,
+}
+
+impl Call for ExtendedRead {
+    const VECTOR: u8 = DISK;
+    type Output = ();
+
+    fn registers(&self) -> Registers {
+        Registers {
+            edx: self.drive.into(),
+            ..Default::default()
+        }
+        .with_ax(0x42, 0)
+        .with_ds_si(self.packet)
+    }
+
+    fn output(&self, registers: &Registers) -> Result<(), Error> {
+        carry_status(Self::VECTOR, registers)
+    }
+}
+
+impl DiskCall for ExtendedRead {
+    fn drive(&self) -> u8 {
+        self.drive
+    }
+}
+
+/// INT 13h, AH=48h: fills the buffer at `buffer`, which must start with its own size as a word,
+/// with the drive parameters bootloader/src/edd.rs parses
+pub struct GetDriveParameters {
+    pub drive: u8,
+    pub buffer: FarPointer,
+}
+
+impl Call for GetDriveParameters {
+    const VECTOR: u8 = DISK;
+    type Output = ();
+
+    fn registers(&self) -> Registers {
+        Registers {
+            edx: self.drive.into(),
+            ..Default::default()
+        }
+        .with_ax(0x48, 0)
+        .with_ds_si(self.buffer)
+    }
+
+    fn output(&self, registers: &Registers) -> Result<(), Error> {
+        carry_status(Self::VECTOR, registers)
+    }
+}
+
+impl DiskCall for GetDriveParameters {
+    fn drive(&self) -> u8 {
+        self.drive
+    }
+}
+
+/// What a `MemoryMapEntry` call did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMapStep {
+    /// Wrote an entry, and the continuation for the next one unless it was the last
+    Entry { next: Option<u32> },
+    /// Wrote nothing, the previous entry was the last
+    End,
+}
+
+/// INT 15h, EAX=E820h: writes the memory map entry `continuation` stands for to `buffer`. The
+/// first call takes 0, the following ones the continuation the previous one returned
+pub struct MemoryMapEntry {
+    pub continuation: u32,
+    pub buffer: FarPointer,
+}
+
+impl Call for MemoryMapEntry {
+    const VECTOR: u8 = SYSTEM;
+    type Output = MemoryMapStep;
+
+    fn registers(&self) -> Registers {
+        Registers {
+            eax: 0xE820,
+            ebx: self.continuation,
+            ecx: size_of::<e820::Entry>() as u32,
+            edx: SMAP,
+            ..Default::default()
+        }
+        .with_es_di(self.buffer)
+    }
+
+    fn output(&self, registers: &Registers) -> Result<MemoryMapStep, Error> {
+        // Past the last entry, some BIOSes set the carry rather than returning a 0 continuation
+        if registers.carry() && self.continuation != 0 {
+            return Ok(MemoryMapStep::End);
+        }
+        carry_status(Self::VECTOR, registers)?;
+        if registers.eax != SMAP {
+            return Err(failed(Self::VECTOR, registers.ah()));
+        }
+        Ok(MemoryMapStep::Entry {
+            next: Some(registers.ebx).filter(|&continuation| continuation != 0),
+        })
+    }
+}
+
+/// INT 15h, AX=2401h: has the BIOS enable the A20 gate
+pub struct EnableA20;
+
+impl Call for EnableA20 {
+    const VECTOR: u8 = SYSTEM;
+    type Output = ();
+
+    fn registers(&self) -> Registers {
+        Registers::default().with_ax(0x24, 0x01)
+    }
+
+    fn output(&self, registers: &Registers) -> Result<(), Error> {
+        carry_status(Self::VECTOR, registers)
+    }
+}
+
+/// INT 10h, AH=0Eh: prints a character at the cursor of `page`, moving the cursor past it
+pub struct Teletype {
+    pub character: u8,
+    pub page: u8,
+}
+
+impl Call for Teletype {
+    const VECTOR: u8 = VIDEO;
+    type Output = ();
+
+    fn registers(&self) -> Registers {
+        Registers {
+            ebx: u32::from(self.page) << 8,
+            ..Default::default()
+        }
+        .with_ax(0x0E, self.character)
+    }
+
+    fn output(&self, _registers: &Registers) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// INT 10h, AX=4F00h: fills the 512 bytes at `buffer` with the VBE controller information
+pub struct VbeControllerInfo {
+    pub buffer: FarPointer,
+}
+
+impl Call for VbeControllerInfo {
+    const VECTOR: u8 = VIDEO;
+    type Output = ();
+
+    fn registers(&self) -> Registers {
+        Registers::default()
+            .with_ax(0x4F, 0x00)
+            .with_es_di(self.buffer)
+    }
+
+    fn output(&self, registers: &Registers) -> Result<(), Error> {
+        vbe_status(registers)
+    }
+}
+
+/// INT 10h, AX=4F01h: fills the 256 bytes at `buffer` with what `mode` is
+pub struct VbeModeInfo {
+    pub mode: u16,
+    pub buffer: FarPointer,
+}
+
+impl Call for VbeModeInfo {
+    const VECTOR: u8 = VIDEO;
+    type Output = ();
+
+    fn registers(&self) -> Registers {
+        Registers {
+            ecx: self.mode.into(),
+            ..Default::default()
+        }
+        .with_ax(0x4F, 0x01)
+        .with_es_di(self.buffer)
+    }
+
+    fn output(&self, registers: &Registers) -> Result<(), Error> {
+        vbe_status(registers)
+    }
+}
+
+/// INT 10h, AX=4F02h: switches to `mode`, with its framebuffer mapped linearly if asked to
+pub struct SetVbeMode {
+    pub mode: u16,
+    pub linear_framebuffer: bool,
+}
+
+impl Call for SetVbeMode {
+    const VECTOR: u8 = VIDEO;
+    type Output = ();
+
+    fn registers(&self) -> Registers {
+        let mode = if self.linear_framebuffer {
+            self.mode | VBE_LINEAR_FRAMEBUFFER
+        } else {
+            self.mode
+        };
+        Registers {
+            ebx: mode.into(),
+            ..Default::default()
+        }
+        .with_ax(0x4F, 0x02)
+    }
+
+    fn output(&self, registers: &Registers) -> Result<(), Error> {
+        vbe_status(registers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bios::{
+        CARRY_FLAG, CheckExtensions, DISK, ExtendedRead, ExtensionType, FarPointer, MemoryMapEntry,
+        MemoryMapStep, Registers, SMAP, SYSTEM, SetVbeMode, VIDEO, call, call_disk,
+    };
+    use crate::error::Fault;
+
+    const DRIVE: u8 = 0x80;
+
+    #[test]
+    fn edd() {
+        let mut bios = |vector: u8, registers: &mut Registers| {
+            assert_eq!(DISK, vector);
+            assert_eq!(0x41, registers.ah());
+            assert_eq!(0x55AA, registers.bx());
+            assert_eq!(u32::from(DRIVE), registers.edx);
+            registers.eax = 0x3000;
+            registers.ebx = 0xAA55;
+            registers.ecx = 0b1101;
+        };
+        let extensions = call(&mut bios, &CheckExtensions { drive: DRIVE }).unwrap();
+        assert_eq!(0x30, extensions.version);
+        assert!(extensions.supported.is_set(ExtensionType::FixedDiskAccess));
+        assert!(!extensions.supported.is_set(ExtensionType::DriveLocking));
+        assert!(
+            extensions
+                .supported
+                .is_set(ExtensionType::SixtyFourBitAddresses)
+        );
+
+        // No extensions at all, and a BIOS that doesn't know the function
+        let mut bios = |_: u8, registers: &mut Registers| {
+            registers.eax = 0x0100;
+            registers.flags |= CARRY_FLAG;
+        };
+        let err = call(&mut bios, &CheckExtensions { drive: DRIVE }).unwrap_err();
+        assert!(matches!(
+            err.fault(),
+            Fault::BiosCallFailed {
+                vector: DISK,
+                status: 0x01
+            }
+        ));
+        let mut bios = |_: u8, _: &mut Registers| {};
+        assert!(call(&mut bios, &CheckExtensions { drive: DRIVE }).is_err());
+    }
+
+    #[test]
+    fn disk_retries() {
+        let packet = FarPointer::from_linear(0x7D60).unwrap();
+        assert_eq!(FarPointer::from_linear(0x10_0000), None);
+        let mut reads = 0;
+        let mut resets = 0;
+        let mut bios = |_: u8, registers: &mut Registers| match registers.ah() {
+            0x42 => {
+                assert_eq!((0x7D6, 0), (registers.ds, registers.esi));
+                assert_eq!(0x7D60, packet.linear());
+                reads += 1;
+                if reads < 3 {
+                    registers.eax = 0x8000;
+                    registers.flags |= CARRY_FLAG;
+                }
+            }
+            0x00 => resets += 1,
+            function => panic!("unexpected function {function:#x}"),
+        };
+        let read = ExtendedRead {
+            drive: DRIVE,
+            packet,
+        };
+        call_disk(&mut bios, &read, 3).unwrap();
+        assert_eq!((3, 2), (reads, resets));
+
+        // Giving up
+        let mut bios = |_: u8, registers: &mut Registers| {
+            if registers.ah() == 0x42 {
+                registers.eax = 0x8000;
+                registers.flags |= CARRY_FLAG;
+            }
+        };
+        let err = call_disk(&mut bios, &read, 3).unwrap_err();
+        assert!(matches!(
+            err.fault(),
+            Fault::BiosCallFailed {
+                vector: DISK,
+                status: 0x80
+            }
+        ));
+    }
+
+    #[test]
+    fn memory_map() {
+        let buffer = FarPointer::from_linear(0x508).unwrap();
+        let step = |bios: &mut dyn FnMut(u8, &mut Registers), continuation| {
+            call(
+                bios,
+                &MemoryMapEntry {
+                    continuation,
+                    buffer,
+                },
+            )
+        };
+        // Two entries, the continuation after the second one being 0
+        let mut bios = |vector: u8, registers: &mut Registers| {
+            assert_eq!(SYSTEM, vector);
+            assert_eq!(
+                (0xE820, SMAP, 24),
+                (registers.eax, registers.edx, registers.ecx)
+            );
+            assert_eq!((0x50, 8), (registers.es, registers.edi));
+            registers.ebx = if registers.ebx == 0 { 7 } else { 0 };
+            registers.eax = SMAP;
+        };
+        let first = step(&mut bios, 0).unwrap();
+        assert_eq!(MemoryMapStep::Entry { next: Some(7) }, first);
+        let second = step(&mut bios, 7).unwrap();
+        assert_eq!(MemoryMapStep::Entry { next: None }, second);
+        // Ending with the carry instead, and no E820 at all
+        let mut bios = |_: u8, registers: &mut Registers| registers.flags |= CARRY_FLAG;
+        assert_eq!(MemoryMapStep::End, step(&mut bios, 7).unwrap());
+        assert!(step(&mut bios, 0).is_err());
+    }
+
+    #[test]
+    fn vbe() {
+        let mut bios = |vector: u8, registers: &mut Registers| {
+            assert_eq!(VIDEO, vector);
+            assert_eq!((0x4F02, 0x4118), (registers.ax(), registers.bx()));
+            registers.eax = 0x004F;
+        };
+        let set_mode = SetVbeMode {
+            mode: 0x118,
+            linear_framebuffer: true,
+        };
+        call(&mut bios, &set_mode).unwrap();
+        // Supported, but failed
+        let mut bios = |_: u8, registers: &mut Registers| registers.eax = 0x014F;
+        assert!(call(&mut bios, &set_mode).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+use crate::error::Error;
+
+/// Storage addressed in fixed size sectors, for code like filesystems that shouldn't care where
+/// the sectors live
+pub trait BlockDevice {
+    fn sector_size_bytes(&self) -> u16;
+
+    fn sectors(&self) -> u64;
+
+    /// Reads as many sectors as fit in `buffer`, whose length must be a multiple of the sector
+    /// size, starting from sector `lba`
+    fn read_sectors(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Error>;
+
+    /// Writes `buffer`, whose length must be a multiple of the sector size, to the sectors
+    /// starting from `lba`
+    fn write_sectors(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Error>;
+}
@@ -0,0 +1,530 @@
+use core::fmt::{self, Display, Write};
+
+use crate::{
+    ata::{self, Protocol},
+    e820,
+    error::Error,
+    metrics::BootMetrics,
+    payload::PayloadKind,
+    ring_buffer::RingBuffer,
+};
+
+/// How many drives fit in the catalog, all there can be on the two legacy ATA channels
+pub const MAX_DRIVES: usize = ata::MAX_DEVICES;
+/// How many BIOS hard drives stage1 asks about, 0x80 to 0x8F
+pub const MAX_BIOS_DRIVES: usize = 16;
+/// How many payloads besides the kernel the bootloader can pass on
+pub const MAX_LOADED_PAYLOADS: usize = 4;
+/// How much of the errors the bootloader booted past the kernel gets, as text
+pub const BOOT_ERRORS_SIZE: usize = 1024;
+const NO_BOOT_DRIVE: u32 = u32::MAX;
+
+/// An ATA or ATAPI drive found by the bootloader. Laid out with no padding, so that it's the
+/// same for the 32-bit bootloader and the 64-bit kernel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct DriveInfo {
+    pub sectors: u64,
+    pub io_port_base_address: u16,
+    pub control_port_base_address: u16,
+    pub sector_size_bytes: u16,
+    pub is_slave: bool,
+    pub is_atapi: bool,
+}
+
+impl From<&ata::Device> for DriveInfo {
+    fn from(device: &ata::Device) -> Self {
+        Self {
+            sectors: device.sectors(),
+            io_port_base_address: device.io_port_base_address(),
+            control_port_base_address: device.control_port_base_address(),
+            sector_size_bytes: device.sector_size_bytes(),
+            is_slave: device.is_slave(),
+            is_atapi: device.protocol() == Protocol::Atapi,
+        }
+    }
+}
+
+impl From<&DriveInfo> for ata::Device {
+    fn from(drive: &DriveInfo) -> Self {
+        let new = if drive.is_atapi {
+            ata::Device::new_atapi
+        } else {
+            ata::Device::new
+        };
+        new(
+            drive.io_port_base_address,
+            drive.control_port_base_address,
+            drive.is_slave,
+            drive.sectors,
+            drive.sector_size_bytes,
+        )
+    }
+}
+
+impl Display for DriveInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} {} on {:#x}: {} sectors of {} bytes",
+            if self.is_atapi { "ATAPI" } else { "ATA" },
+            if self.is_slave { "slave" } else { "master" },
+            self.io_port_base_address,
+            self.sectors,
+            self.sector_size_bytes
+        )
+    }
+}
+
+/// A hard drive as the BIOS described it to stage1 (int 13h, AH=48h), whatever the controller it's
+/// behind. Laid out with no padding, like DriveInfo
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct BiosDrive {
+    pub sectors: u64,
+    pub bytes_per_sector: u16,
+    /// The EDD information flags, see bootloader/src/edd.rs
+    pub information_flags: u16,
+    /// What the BIOS calls it in DL, from 0x80
+    pub number: u8,
+    reserved: [u8; 3],
+}
+
+impl BiosDrive {
+    pub const fn new(
+        number: u8,
+        sectors: u64,
+        bytes_per_sector: u16,
+        information_flags: u16,
+    ) -> Self {
+        Self {
+            sectors,
+            bytes_per_sector,
+            information_flags,
+            number,
+            reserved: [0; 3],
+        }
+    }
+}
+
+impl Display for BiosDrive {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "BIOS drive {:#x}: {} sectors of {} bytes",
+            self.number, self.sectors, self.bytes_per_sector
+        )
+    }
+}
+
+/// A payload the bootloader loaded from the boot disk for the kernel, left as it was on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct LoadedPayload {
+    pub address: u64,
+    pub size: u64,
+    kind: u32,
+    reserved: u32,
+}
+
+impl LoadedPayload {
+    pub const fn new(kind: PayloadKind, address: u64, size: u64) -> Self {
+        Self {
+            address,
+            size,
+            kind: kind as u32,
+            reserved: 0,
+        }
+    }
+
+    pub fn kind(&self) -> Option<PayloadKind> {
+        PayloadKind::try_from(self.kind).ok()
+    }
+}
+
+impl Display for LoadedPayload {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.kind() {
+            Some(kind) => write!(f, "{kind}")?,
+            None => write!(f, "unknown payload {}", self.kind)?,
+        }
+        write!(f, ": {} bytes at {:#x}", self.size, self.address)
+    }
+}
+
+/// Formats into a buffer, dropping what doesn't fit on a character boundary
+struct TruncatingWriter<'a> {
+    bytes: &'a mut [u8],
+    length: usize,
+    truncated: bool,
+}
+
+impl Write for TruncatingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for character in s.chars() {
+            let start = self.length;
+            let Some(destination) = self.bytes.get_mut(start..start + character.len_utf8()) else {
+                // The rest is dropped, there's no telling the caller to stop formatting early
+                self.truncated = true;
+                return Ok(());
+            };
+            character.encode_utf8(destination);
+            self.length += character.len_utf8();
+        }
+        Ok(())
+    }
+}
+
+/// What the bootloader passes to the kernel entrypoint, by address in EDI. The layout is shared
+/// between the 32-bit bootloader and the 64-bit kernel, so it only uses fixed size fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct BootInfo {
+    /// How far the kernel was loaded from the address it was linked at
+    pub kernel_slide: u64,
+    /// Loaded (slid) address range of the kernel image
+    pub kernel_start: u64,
+    pub kernel_end: u64,
+    drives: [DriveInfo; MAX_DRIVES],
+    drive_count: u32,
+    boot_drive: u32,
+    bios_drives: [BiosDrive; MAX_BIOS_DRIVES],
+    bios_drive_count: u32,
+    boot_bios_drive: u32,
+    payloads: [LoadedPayload; MAX_LOADED_PAYLOADS],
+    payload_count: u32,
+    memory_map_entry_count: u32,
+    memory_map: [e820::Entry; e820::MAX_ENTRIES],
+    /// How long the bootloader took for each step, if built with the `metrics` feature
+    pub metrics: BootMetrics,
+    // Errors hold pointers, which are 4 bytes in the bootloader and 8 in the kernel, so they're
+    // passed on already formatted
+    boot_errors: [u8; BOOT_ERRORS_SIZE],
+    boot_errors_length: u32,
+    boot_errors_truncated: u32,
+}
+
+impl Default for BootInfo {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl BootInfo {
+    pub const fn empty() -> Self {
+        Self {
+            kernel_slide: 0,
+            kernel_start: 0,
+            kernel_end: 0,
+            drives: [DriveInfo {
+                sectors: 0,
+                io_port_base_address: 0,
+                control_port_base_address: 0,
+                sector_size_bytes: 0,
+                is_slave: false,
+                is_atapi: false,
+            }; MAX_DRIVES],
+            drive_count: 0,
+            boot_drive: NO_BOOT_DRIVE,
+            bios_drives: [BiosDrive::new(0, 0, 0, 0); MAX_BIOS_DRIVES],
+            bios_drive_count: 0,
+            boot_bios_drive: NO_BOOT_DRIVE,
+            payloads: [LoadedPayload {
+                address: 0,
+                size: 0,
+                kind: 0,
+                reserved: 0,
+            }; MAX_LOADED_PAYLOADS],
+            payload_count: 0,
+            memory_map_entry_count: 0,
+            memory_map: [e820::Entry::empty(); e820::MAX_ENTRIES],
+            metrics: BootMetrics::new(),
+            boot_errors: [0; BOOT_ERRORS_SIZE],
+            boot_errors_length: 0,
+            boot_errors_truncated: 0,
+        }
+    }
+
+    /// The link-time address of a loaded kernel address, the one to look up in the kernel ELF
+    /// symbols
+    pub fn deslide(&self, address: u64) -> u64 {
+        address.wrapping_sub(self.kernel_slide)
+    }
+
+    /// Adds a drive to the catalog, unless it's full. False if it was
+    pub fn add_drive(&mut self, drive: DriveInfo, is_boot_drive: bool) -> bool {
+        let Some(slot) = self.drives.get_mut(self.drive_count as usize) else {
+            return false;
+        };
+        *slot = drive;
+        if is_boot_drive {
+            self.boot_drive = self.drive_count;
+        }
+        self.drive_count += 1;
+        true
+    }
+
+    pub fn drives(&self) -> &[DriveInfo] {
+        &self.drives[..(self.drive_count as usize).min(MAX_DRIVES)]
+    }
+
+    /// The drive the kernel was read from, if the bootloader could tell which one it was
+    pub fn boot_drive(&self) -> Option<&DriveInfo> {
+        self.drives().get(self.boot_drive as usize)
+    }
+
+    /// Adds a drive to the BIOS drive inventory, unless it's full. False if it was
+    pub fn add_bios_drive(&mut self, drive: BiosDrive, is_boot_drive: bool) -> bool {
+        let Some(slot) = self.bios_drives.get_mut(self.bios_drive_count as usize) else {
+            return false;
+        };
+        *slot = drive;
+        if is_boot_drive {
+            self.boot_bios_drive = self.bios_drive_count;
+        }
+        self.bios_drive_count += 1;
+        true
+    }
+
+    pub fn bios_drives(&self) -> &[BiosDrive] {
+        &self.bios_drives[..(self.bios_drive_count as usize).min(MAX_BIOS_DRIVES)]
+    }
+
+    /// The BIOS drive stage1 was loaded from, if the BIOS described it
+    pub fn boot_bios_drive(&self) -> Option<&BiosDrive> {
+        self.bios_drives().get(self.boot_bios_drive as usize)
+    }
+
+    /// Records a loaded payload, unless there's no room left for it. False if there wasn't
+    pub fn add_payload(&mut self, payload: LoadedPayload) -> bool {
+        let Some(slot) = self.payloads.get_mut(self.payload_count as usize) else {
+            return false;
+        };
+        *slot = payload;
+        self.payload_count += 1;
+        true
+    }
+
+    pub fn payloads(&self) -> &[LoadedPayload] {
+        &self.payloads[..(self.payload_count as usize).min(MAX_LOADED_PAYLOADS)]
+    }
+
+    /// Records an entry of the BIOS memory map, unless there's no room left for it. False if there
+    /// wasn't
+    pub fn add_memory_map_entry(&mut self, entry: e820::Entry) -> bool {
+        let Some(slot) = self
+            .memory_map
+            .get_mut(self.memory_map_entry_count as usize)
+        else {
+            return false;
+        };
+        *slot = entry;
+        self.memory_map_entry_count += 1;
+        true
+    }
+
+    /// The BIOS memory map, as stage1 got it, empty if the BIOS doesn't support E820
+    pub fn memory_map(&self) -> &[e820::Entry] {
+        &self.memory_map[..(self.memory_map_entry_count as usize).min(e820::MAX_ENTRIES)]
+    }
+
+    /// Records the errors the bootloader ran into without giving up on booting, e.g. a drive it
+    /// couldn't catalog, for the kernel to log once its console is up. They're formatted oldest
+    /// first, and cut short if they don't fit. Nothing is recorded if there were none
+    pub fn set_boot_errors<const N: usize>(&mut self, warnings: &RingBuffer<Error, N>) {
+        self.boot_errors_length = 0;
+        self.boot_errors_truncated = 0;
+        let mut writer = TruncatingWriter {
+            bytes: &mut self.boot_errors,
+            length: 0,
+            truncated: false,
+        };
+        let (front, back) = warnings.as_slices();
+        for warning in front.iter().chain(back) {
+            let _ = writeln!(writer, "Warning:\n{warning}");
+        }
+        let (length, truncated) = (writer.length, writer.truncated);
+        self.boot_errors_length = length as u32;
+        self.boot_errors_truncated = truncated as u32;
+    }
+
+    /// The errors the bootloader booted past, as it formatted them, None if there were none
+    pub fn boot_errors(&self) -> Option<&str> {
+        let length = (self.boot_errors_length as usize).min(BOOT_ERRORS_SIZE);
+        // Only whole characters are written in
+        core::str::from_utf8(&self.boot_errors[..length])
+            .ok()
+            .map(str::trim_end)
+            .filter(|errors| !errors.is_empty())
+    }
+
+    /// Whether the errors were cut short to fit in `BOOT_ERRORS_SIZE` bytes
+    pub fn boot_errors_truncated(&self) -> bool {
+        self.boot_errors_truncated != 0
+    }
+
+    /// The first loaded payload of `kind`
+    pub fn payload(&self, kind: PayloadKind) -> Option<&LoadedPayload> {
+        self.payloads()
+            .iter()
+            .find(|payload| payload.kind() == Some(kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        boot_info::{
+            BOOT_ERRORS_SIZE, BiosDrive, BootInfo, DriveInfo, LoadedPayload, MAX_BIOS_DRIVES,
+            MAX_DRIVES, MAX_LOADED_PAYLOADS,
+        },
+        e820::{self, MemoryKind},
+        error::{Context, Error, Facility, Fault},
+        metrics,
+        payload::PayloadKind,
+        ring_buffer::RingBuffer,
+    };
+
+    #[test]
+    fn layout_and_deslide() {
+        assert_eq!(16, size_of::<DriveInfo>());
+        assert_eq!(16, size_of::<BiosDrive>());
+        assert_eq!(24, size_of::<LoadedPayload>());
+        assert_eq!(
+            24 + 16 * MAX_DRIVES
+                + 8
+                + 16 * MAX_BIOS_DRIVES
+                + 8
+                + 24 * MAX_LOADED_PAYLOADS
+                + 8
+                + 24 * e820::MAX_ENTRIES
+                + 16 * metrics::COUNTERS
+                + BOOT_ERRORS_SIZE
+                + 8,
+            size_of::<BootInfo>()
+        );
+
+        let boot_info = BootInfo {
+            kernel_slide: 0x600000,
+            kernel_start: 0x800000,
+            kernel_end: 0x840000,
+            ..BootInfo::empty()
+        };
+        assert_eq!(0x201234, boot_info.deslide(0x801234));
+    }
+
+    #[test]
+    fn drives() {
+        let mut boot_info = BootInfo::empty();
+        assert!(boot_info.drives().is_empty());
+        assert!(boot_info.boot_drive().is_none());
+
+        let drive = DriveInfo {
+            sectors: 0x800,
+            io_port_base_address: 0x170,
+            control_port_base_address: 0x376,
+            sector_size_bytes: 2048,
+            is_slave: false,
+            is_atapi: true,
+        };
+        assert!(boot_info.add_drive(DriveInfo::default(), false));
+        assert!(boot_info.add_drive(drive, true));
+        assert_eq!(2, boot_info.drives().len());
+        assert_eq!(Some(&drive), boot_info.boot_drive());
+
+        for _ in 2..MAX_DRIVES {
+            assert!(boot_info.add_drive(DriveInfo::default(), false));
+        }
+        assert!(!boot_info.add_drive(DriveInfo::default(), false));
+    }
+
+    #[test]
+    fn bios_drives() {
+        let mut boot_info = BootInfo::empty();
+        assert!(boot_info.boot_bios_drive().is_none());
+
+        let drive = BiosDrive::new(0x81, 0x10000, 512, 0x2);
+        assert!(boot_info.add_bios_drive(BiosDrive::new(0x80, 145, 512, 0x2), false));
+        assert!(boot_info.add_bios_drive(drive, true));
+        assert_eq!(Some(&drive), boot_info.boot_bios_drive());
+
+        for _ in 2..MAX_BIOS_DRIVES {
+            assert!(boot_info.add_bios_drive(drive, false));
+        }
+        assert!(!boot_info.add_bios_drive(drive, false));
+        assert_eq!(MAX_BIOS_DRIVES, boot_info.bios_drives().len());
+    }
+
+    #[test]
+    fn payloads() {
+        let mut boot_info = BootInfo::empty();
+        assert!(boot_info.payload(PayloadKind::Initrd).is_none());
+
+        let initrd = LoadedPayload::new(PayloadKind::Initrd, 0x1000000, 0x4000);
+        assert!(boot_info.add_payload(LoadedPayload::new(PayloadKind::Symbols, 0xFFF000, 0x800)));
+        assert!(boot_info.add_payload(initrd));
+        assert_eq!(Some(&initrd), boot_info.payload(PayloadKind::Initrd));
+
+        for _ in 2..MAX_LOADED_PAYLOADS {
+            assert!(boot_info.add_payload(initrd));
+        }
+        assert!(!boot_info.add_payload(initrd));
+    }
+
+    #[test]
+    fn memory_map() {
+        let mut boot_info = BootInfo::empty();
+        assert!(boot_info.memory_map().is_empty());
+
+        let usable = e820::Entry::new(MemoryKind::Usable, 0x0..0x9_FC00);
+        for _ in 0..e820::MAX_ENTRIES {
+            assert!(boot_info.add_memory_map_entry(usable));
+        }
+        assert!(!boot_info.add_memory_map_entry(usable));
+        assert_eq!(e820::MAX_ENTRIES, boot_info.memory_map().len());
+        assert!(boot_info.memory_map()[0].is_usable());
+    }
+
+    #[test]
+    fn boot_errors() {
+        let mut boot_info = BootInfo::empty();
+        let io_error = Error::new(
+            Fault::IOError,
+            Context::LoadingPayload,
+            Facility::Bootloader,
+        );
+        boot_info.set_boot_errors(&RingBuffer::<_, 8>::new(io_error));
+        assert_eq!(None, boot_info.boot_errors());
+
+        let mut warnings = RingBuffer::<_, 8>::new(io_error);
+        warnings.push_overwriting(io_error);
+        warnings.push_overwriting(Error::new(
+            Fault::InvalidElf,
+            Context::LoadingKernel,
+            Facility::Bootloader,
+        ));
+        boot_info.set_boot_errors(&warnings);
+        let Some(text) = boot_info.boot_errors() else {
+            panic!("no boot errors");
+        };
+        // Oldest first, and neither the cause of the other
+        let first = text.find("I/O error").unwrap();
+        let second = text.find("invalid elf").unwrap();
+        assert!(first < second);
+        assert_eq!(2, text.matches("Warning:").count());
+        assert!(!text.contains("Due to:"));
+        assert!(!text.ends_with('\n'));
+        assert!(!boot_info.boot_errors_truncated());
+
+        let mut warnings = RingBuffer::<_, 32>::new(io_error);
+        for _ in 0..32 {
+            warnings.push_overwriting(io_error);
+        }
+        boot_info.set_boot_errors(&warnings);
+        assert!(boot_info.boot_errors_truncated());
+        assert!(boot_info.boot_errors().unwrap().len() <= BOOT_ERRORS_SIZE);
+        boot_info.set_boot_errors(&RingBuffer::<_, 8>::new(io_error));
+        assert_eq!(None, boot_info.boot_errors());
+        assert!(!boot_info.boot_errors_truncated());
+    }
+}
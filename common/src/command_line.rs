@@ -0,0 +1,80 @@
+// The kernel command line, which xtasks ships as a payload of its own: whitespace separated
+// options, either `key=value` or a bare flag, e.g. "console=com2,115200 quiet"
+use core::fmt::Display;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandLine<'a> {
+    line: &'a str,
+}
+
+impl<'a> CommandLine<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self { line: line.trim() }
+    }
+
+    /// The command line in a payload, which is padded to a whole sector with zeros. Only the part
+    /// that's valid UTF-8 is kept
+    pub fn from_payload(bytes: &'a [u8]) -> Self {
+        let bytes = bytes.split(|byte| *byte == 0).next().unwrap_or_default();
+        let line = match core::str::from_utf8(bytes) {
+            Ok(line) => line,
+            Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or_default(),
+        };
+        Self::new(line)
+    }
+
+    /// Every option, with its value if it has one
+    pub fn options(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> + use<'a> {
+        self.line
+            .split_ascii_whitespace()
+            .map(|option| match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (option, None),
+            })
+    }
+
+    /// The value of the last `key=value` option for `key`, so later options override earlier ones
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.options()
+            .filter(|(option, _)| *option == key)
+            .filter_map(|(_, value)| value)
+            .last()
+    }
+
+    pub fn has_flag(&self, key: &str) -> bool {
+        self.options().any(|option| option == (key, None))
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.line
+    }
+}
+
+impl Display for CommandLine<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command_line::CommandLine;
+
+    #[test]
+    fn options() {
+        let command_line =
+            CommandLine::from_payload(b" console=com2 quiet console=com3,9600\n\0\0\0");
+        assert_eq!(
+            "console=com2 quiet console=com3,9600",
+            command_line.as_str()
+        );
+        assert_eq!(Some("com3,9600"), command_line.get("console"));
+        assert!(command_line.has_flag("quiet"));
+        assert!(!command_line.has_flag("console"));
+        assert_eq!(None, command_line.get("quiet"));
+        assert_eq!(3, command_line.options().count());
+
+        assert_eq!("", CommandLine::from_payload(&[0; 512]).as_str());
+        assert_eq!("quiet", CommandLine::from_payload(b"quiet\xff").as_str());
+    }
+}
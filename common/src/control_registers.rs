@@ -1,12 +1,47 @@
-use core::arch::asm;
+use core::{arch::asm, fmt::Display};
+
+use num_enum::TryFromPrimitive;
 
 // https://cdrdv2-public.intel.com/868137/325462-089-sdm-vol-1-2abcd-3abcd-4.pdf
 use crate::{
     error::{Fault, bounded_context},
-    make_bitmap, paging,
+    make_bitmap, msr, paging,
 };
 
+pub type Cr0 = ControlRegister0;
+pub type Cr2 = ControlRegister2;
+pub type Cr3 = ControlRegister3;
+pub type Cr4 = ControlRegister4;
+pub type Efer = ExtendedFeatureEnableRegister;
+
+// Reading a control register needs a register of the native width, so the value is read as a usize
+// in both protected and long mode
+macro_rules! read_control_register {
+    ($register:literal) => {{
+        let value: usize;
+        // SAFETY: Reading a control register has no side effects
+        unsafe {
+            asm!(concat!("mov {value}, ", $register), value = out(reg) value,
+                 options(nomem, nostack, preserves_flags));
+        }
+        value
+    }};
+}
+
+macro_rules! write_control_register {
+    ($register:literal, $value:expr) => {{
+        let value: usize = $value;
+        // SAFETY: The typed wrappers only hold bits defined for the register, and callers are
+        // responsible for the consequences of the configuration they write
+        unsafe {
+            asm!(concat!("mov ", $register, ", {value}"), value = in(reg) value,
+                 options(nostack, preserves_flags));
+        }
+    }};
+}
+
 #[allow(unused)]
+#[derive(TryFromPrimitive, Clone, Copy)]
 #[repr(u32)]
 pub enum ControlRegister0Bit {
     Paging = 1 << 31,
@@ -17,11 +52,66 @@ pub enum ControlRegister0Bit {
     ReportFPUNumericError = 1 << 5,
     MathCoprocessor = 1 << 4,
     TaskSwitched = 1 << 3,
+    Emulation = 1 << 2,
     MonitorCoprocessor = 1 << 1,
     ProtectedMode = 1 << 0,
 }
 
-make_bitmap!(new_type: ControlRegister0, underlying_flag_type: ControlRegister0Bit, repr: u32, nodisplay);
+impl Display for ControlRegister0Bit {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        use ControlRegister0Bit::*;
+        let mnemonic = match self {
+            Paging => "PG",
+            CacheDisable => "CD",
+            NotWriteThrough => "NW",
+            AutomaticAlignmentChecking => "AM",
+            WriteProtect => "WP",
+            ReportFPUNumericError => "NE",
+            MathCoprocessor => "ET",
+            TaskSwitched => "TS",
+            Emulation => "EM",
+            MonitorCoprocessor => "MP",
+            ProtectedMode => "PE",
+        };
+        write!(f, "{mnemonic}")
+    }
+}
+
+make_bitmap!(new_type: ControlRegister0, underlying_flag_type: ControlRegister0Bit, repr: u32, bit_skipper: |i| !matches!(i, 0..=5 | 16 | 18 | 29..=31));
+
+impl ControlRegister0 {
+    pub fn read() -> Self {
+        Self::from(read_control_register!("cr0") as u32)
+    }
+
+    pub fn write(&self) {
+        write_control_register!("cr0", self.bits as usize);
+    }
+}
+
+/// Holds the linear address that caused the last page fault
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct ControlRegister2 {
+    linear_address: u64,
+}
+
+impl ControlRegister2 {
+    pub fn read() -> Self {
+        Self {
+            linear_address: read_control_register!("cr2") as u64,
+        }
+    }
+
+    pub fn page_fault_linear_address(&self) -> u64 {
+        self.linear_address
+    }
+}
+
+impl From<ControlRegister2> for u64 {
+    fn from(value: ControlRegister2) -> Self {
+        value.linear_address
+    }
+}
 
 #[allow(unused)]
 #[repr(u64)]
@@ -35,6 +125,14 @@ pub enum ControlRegister3Bit {
 make_bitmap!(new_type: ControlRegister3, underlying_flag_type: ControlRegister3Bit, repr: u64, nodisplay);
 
 impl ControlRegister3 {
+    pub fn read() -> Self {
+        Self::from(read_control_register!("cr3") as u64)
+    }
+
+    pub fn pml4_physical_address(&self) -> u64 {
+        self.bits & 0x000f_ffff_ffff_f000
+    }
+
     pub fn set_pml4(&mut self, pml4: &'static paging::PML4) -> Result<(), Fault> {
         let address = pml4 as *const _ as u64;
         if !address.is_multiple_of(0x1000) {
@@ -50,6 +148,7 @@ impl ControlRegister3 {
 }
 
 #[allow(unused)]
+#[derive(TryFromPrimitive, Clone, Copy)]
 #[repr(u32)]
 pub enum ControlRegister4Bit {
     Virtual8086ModeExtensions = 1 << 0,
@@ -81,37 +180,56 @@ pub enum ControlRegister4Bit {
     SupervisorLinearAddressMasking = 1 << 28,
 }
 
-make_bitmap!(new_type: ControlRegister4, underlying_flag_type: ControlRegister4Bit, repr: u32, nodisplay);
-
-#[repr(u32)]
-pub enum Msr {
-    Efer(ExtendedFeatureEnableRegister) = 0xC000_0080,
+impl Display for ControlRegister4Bit {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        use ControlRegister4Bit::*;
+        let mnemonic = match self {
+            Virtual8086ModeExtensions => "VME",
+            ProtectedModeVirtualInterrupts => "PVI",
+            TimestampDisable => "TSD",
+            DebuggingExtensions => "DE",
+            PhysicalSizeExtensions => "PSE",
+            PhysicalAddressExtensions => "PAE",
+            MachineCheckExceptions => "MCE",
+            GlobalPage => "PGE",
+            PerformanceMonitoringCounter => "PCE",
+            OperatingSystemSupportForFXSAVEAndFXRSTOR => "OSFXSR",
+            OperatingSystemSupportForUnmaskedSIMDFloatingPointExceptions => "OSXMMEXCPT",
+            UserModeInstructionPrevention => "UMIP",
+            _5LevelPaging => "LA57",
+            VirtualMachineExtensions => "VMXE",
+            SaferModeExtensions => "SMXE",
+            FSGSBASEEnable => "FSGSBASE",
+            ProcessContextIdentifiers => "PCIDE",
+            XSAVEAndProcessorExtendedStates => "OSXSAVE",
+            KeyLockerEnableBit => "KL",
+            SupervisorModeExecutionPrevention => "SMEP",
+            SupervisorModeAccessPrevention => "SMAP",
+            ProtectionKeysForUserModePages => "PKE",
+            ControlflowEnforcementTechnology => "CET",
+            ProtectionKeysForSupervisorModePages => "PKS",
+            UserInterrupts => "UINTR",
+            LinearAddressSpaceSeparation => "LASS",
+            SupervisorLinearAddressMasking => "LAM_SUP",
+        };
+        write!(f, "{mnemonic}")
+    }
 }
 
-pub fn wrmsr(msr: &Msr) {
-    // SAFETY: Msr has a primitive representation which allows pointer casting to retrieve the
-    // discriminant
-    let register_index = unsafe { *(msr as *const Msr as *const u32) };
+make_bitmap!(new_type: ControlRegister4, underlying_flag_type: ControlRegister4Bit, repr: u32, bit_skipper: |i| matches!(i, 15 | 26 | 29..));
 
-    let (low, high) = match msr {
-        Msr::Efer(extended_feature_enable_register) => {
-            let bits = u64::from(*extended_feature_enable_register);
-            (bits as u32, (bits >> 32) as u32)
-        }
-    };
+impl ControlRegister4 {
+    pub fn read() -> Self {
+        Self::from(read_control_register!("cr4") as u32)
+    }
 
-    // SAFETY: The validity of the value for the given MSR is guaranteed by the type signature
-    unsafe {
-        asm!(
-          "wrmsr",
-          in("eax") low,
-          in("edx") high,
-          in("ecx") register_index,
-        )
+    pub fn write(&self) {
+        write_control_register!("cr4", self.bits as usize);
     }
 }
 
 #[allow(unused)]
+#[derive(TryFromPrimitive, Clone, Copy)]
 #[repr(u64)]
 pub enum ExtendedFeatureEnableRegisterBit {
     SyscallEnable = 1 << 0,
@@ -120,4 +238,47 @@ pub enum ExtendedFeatureEnableRegisterBit {
     ExecuteDisableBitEnabled = 1 << 11,
 }
 
-make_bitmap!(new_type: ExtendedFeatureEnableRegister, underlying_flag_type: ExtendedFeatureEnableRegisterBit, repr: u64, nodisplay);
+impl Display for ExtendedFeatureEnableRegisterBit {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        use ExtendedFeatureEnableRegisterBit::*;
+        let mnemonic = match self {
+            SyscallEnable => "SCE",
+            IA32eEnabled => "LME",
+            IA32eActive => "LMA",
+            ExecuteDisableBitEnabled => "NXE",
+        };
+        write!(f, "{mnemonic}")
+    }
+}
+
+make_bitmap!(new_type: ExtendedFeatureEnableRegister, underlying_flag_type: ExtendedFeatureEnableRegisterBit, repr: u64, bit_skipper: |i| !matches!(i, 0 | 8 | 10 | 11));
+
+impl ExtendedFeatureEnableRegister {
+    pub fn read() -> Self {
+        Self::from(msr::rdmsr(msr::IA32_EFER))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        control_registers::{Cr0, Cr4, Efer},
+        vga::{Buffer, Writer},
+    };
+
+    #[test]
+    fn display_flags() {
+        let mut buffer = Buffer::blank();
+        let mut writer = Writer::with_buffer(&mut buffer);
+        writeln!(writer, "CR0={}", Cr0::from(0x8001_0011)).unwrap();
+        writeln!(writer, "EFER={}", Efer::from(0xd01)).unwrap();
+        // Reserved bits are skipped rather than making the formatting panic
+        writeln!(writer, "CR4={}", Cr4::from(0x8400_0020)).unwrap();
+
+        assert!(buffer.contains("CR0=PE|ET|WP|PG"));
+        assert!(buffer.contains("EFER=SCE|LME|LMA|NXE"));
+        assert!(buffer.contains("CR4=PAE "));
+    }
+}
@@ -0,0 +1,247 @@
+// The device file system: devices the drivers register by name, to be reached at /dev/<name> by
+// reading and writing bytes, the same way whatever the device is. Each device comes with the
+// functions that read and write it, which get an offset that only block devices care about.
+// Character devices are streams, where reads take what's there without waiting and may well come
+// back with nothing. Block devices are read and written at any byte offset, see
+// `read_blocks` and `write_blocks` for doing that on top of a `BlockDevice`
+use crate::{
+    block_device::BlockDevice,
+    error::{Error, Fault},
+};
+
+pub const MOUNT_POINT: &str = "/dev";
+pub const MAX_DEVICE_NAME_LENGTH: usize = 16;
+const MAX_SECTOR_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Character,
+    Block { size: u64 },
+}
+
+/// Reads from the device at an offset into the buffer, returning how many bytes it read
+pub type ReadFn = fn(u64, &mut [u8]) -> Result<usize, Error>;
+/// Writes the buffer to the device at an offset, returning how many bytes it wrote
+pub type WriteFn = fn(u64, &[u8]) -> Result<usize, Error>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    name: [u8; MAX_DEVICE_NAME_LENGTH],
+    name_length: usize,
+    pub kind: DeviceKind,
+    read: ReadFn,
+    write: WriteFn,
+}
+
+impl Device {
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_length]).unwrap_or_default()
+    }
+
+    pub fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        (self.read)(offset, buffer)
+    }
+
+    pub fn write(&self, offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+        (self.write)(offset, bytes)
+    }
+}
+
+/// Up to `N` devices
+pub struct DevFs<const N: usize> {
+    devices: [Option<Device>; N],
+}
+
+impl<const N: usize> DevFs<N> {
+    pub const fn new() -> Self {
+        Self { devices: [None; N] }
+    }
+
+    /// Adds a device, to be found as `name`
+    pub fn register(
+        &mut self,
+        name: &str,
+        kind: DeviceKind,
+        read: ReadFn,
+        write: WriteFn,
+    ) -> Result<(), Fault> {
+        if name.is_empty() || name.len() > MAX_DEVICE_NAME_LENGTH || name.contains('/') {
+            return Err(Fault::InvalidFileName);
+        }
+        if self.get(name).is_some() {
+            return Err(Fault::FileExists);
+        }
+        let Some(slot) = self.devices.iter_mut().find(|slot| slot.is_none()) else {
+            return Err(Fault::TooManyFiles);
+        };
+        let mut device = Device {
+            name: [0; MAX_DEVICE_NAME_LENGTH],
+            name_length: name.len(),
+            kind,
+            read,
+            write,
+        };
+        device.name[..name.len()].copy_from_slice(name.as_bytes());
+        *slot = Some(device);
+        Ok(())
+    }
+
+    /// The device called `name`
+    pub fn get(&self, name: &str) -> Option<&Device> {
+        self.devices().find(|device| device.name() == name)
+    }
+
+    /// The device at `path`, under the mount point, e.g. /dev/null
+    pub fn lookup(&self, path: &str) -> Result<&Device, Fault> {
+        let name = path
+            .strip_prefix(MOUNT_POINT)
+            .and_then(|name| name.strip_prefix('/'))
+            .ok_or(Fault::FileNotFound)?;
+        self.get(name).ok_or(Fault::FileNotFound)
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &Device> {
+        self.devices.iter().flatten()
+    }
+}
+
+impl<const N: usize> Default for DevFs<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// /dev/null: nothing to read, and whatever is written is gone
+pub fn null_read(_offset: u64, _buffer: &mut [u8]) -> Result<usize, Error> {
+    Ok(0)
+}
+
+pub fn null_write(_offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+    Ok(bytes.len())
+}
+
+/// /dev/zero: as many zeroes as are asked for
+pub fn zero_read(_offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+    buffer.fill(0);
+    Ok(buffer.len())
+}
+
+/// Reads from `device` at `offset` into `buffer`, which need not be aligned to sectors, up to the
+/// end of the device. Returns how many bytes were read
+pub fn read_blocks(
+    device: &mut impl BlockDevice,
+    offset: u64,
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    let sector_size = device.sector_size_bytes() as usize;
+    let size = device.sectors() * sector_size as u64;
+    let length = (buffer.len() as u64).min(size.saturating_sub(offset)) as usize;
+    let mut sector = [0u8; MAX_SECTOR_SIZE];
+    let sector = &mut sector[..sector_size.min(MAX_SECTOR_SIZE)];
+    let mut read = 0;
+    while read < length {
+        let position = offset + read as u64;
+        let in_sector = (position % sector_size as u64) as usize;
+        device.read_sectors(position / sector_size as u64, sector)?;
+        let copied = (sector_size - in_sector).min(length - read);
+        buffer[read..read + copied].copy_from_slice(&sector[in_sector..in_sector + copied]);
+        read += copied;
+    }
+    Ok(read)
+}
+
+/// Writes `bytes` to `device` at `offset`, which need not be aligned to sectors, up to the end of
+/// the device. Sectors that are only written in part are read first. Returns how many bytes were
+/// written
+pub fn write_blocks(
+    device: &mut impl BlockDevice,
+    offset: u64,
+    bytes: &[u8],
+) -> Result<usize, Error> {
+    let sector_size = device.sector_size_bytes() as usize;
+    let size = device.sectors() * sector_size as u64;
+    let length = (bytes.len() as u64).min(size.saturating_sub(offset)) as usize;
+    let mut sector = [0u8; MAX_SECTOR_SIZE];
+    let sector = &mut sector[..sector_size.min(MAX_SECTOR_SIZE)];
+    let mut written = 0;
+    while written < length {
+        let position = offset + written as u64;
+        let lba = position / sector_size as u64;
+        let in_sector = (position % sector_size as u64) as usize;
+        let copied = (sector_size - in_sector).min(length - written);
+        if copied != sector_size {
+            device.read_sectors(lba, sector)?;
+        }
+        sector[in_sector..in_sector + copied].copy_from_slice(&bytes[written..written + copied]);
+        device.write_sectors(lba, sector)?;
+        written += copied;
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        devfs::{self, DevFs, DeviceKind},
+        error::Fault,
+        ram_disk::RamDisk,
+    };
+
+    #[test]
+    fn registry() {
+        let mut devices = DevFs::<2>::new();
+        let (null_read, null_write) = (devfs::null_read, devfs::null_write);
+        devices
+            .register("null", DeviceKind::Character, null_read, null_write)
+            .unwrap();
+        devices
+            .register("zero", DeviceKind::Character, devfs::zero_read, null_write)
+            .unwrap();
+        assert!(matches!(
+            devices.register("zero", DeviceKind::Character, null_read, null_write),
+            Err(Fault::FileExists)
+        ));
+        assert!(matches!(
+            devices.register("other", DeviceKind::Character, null_read, null_write),
+            Err(Fault::TooManyFiles)
+        ));
+
+        let mut buffer = [0xFFu8; 4];
+        let zero = devices.lookup("/dev/zero").unwrap();
+        assert_eq!(Some(4), zero.read(0, &mut buffer).ok());
+        assert_eq!([0; 4], buffer);
+        let null = devices.lookup("/dev/null").unwrap();
+        assert_eq!(Some(0), null.read(0, &mut buffer).ok());
+        assert_eq!(Some(3), null.write(0, b"abc").ok());
+        assert!(devices.lookup("/dev").is_err());
+        assert!(devices.lookup("/null").is_err());
+        assert_eq!(2, devices.devices().count());
+    }
+
+    #[test]
+    fn unaligned_block_access() {
+        let mut bytes = [0u8; 3 * 512];
+        let mut disk = RamDisk::new(&mut bytes);
+        let data = [0xAB; 600];
+        assert_eq!(Some(600), devfs::write_blocks(&mut disk, 500, &data).ok());
+        // Only as much as there's room for before the end
+        assert_eq!(Some(36), devfs::write_blocks(&mut disk, 1500, &data).ok());
+        let mut buffer = [0u8; 8];
+        assert_eq!(
+            Some(8),
+            devfs::read_blocks(&mut disk, 496, &mut buffer).ok()
+        );
+        assert_eq!([0, 0, 0, 0, 0xAB, 0xAB, 0xAB, 0xAB], buffer);
+        assert_eq!(
+            Some(4),
+            devfs::read_blocks(&mut disk, 1532, &mut buffer).ok()
+        );
+        assert_eq!(
+            Some(0),
+            devfs::read_blocks(&mut disk, 4096, &mut buffer).ok()
+        );
+        assert_eq!(0, bytes[499]);
+        assert_eq!(0xAB, bytes[1099]);
+        assert_eq!(0, bytes[1100]);
+    }
+}
@@ -0,0 +1,231 @@
+// Buffers devices can DMA to and from. Devices take physical addresses, which the caller's buffer
+// may not have in a usable form: it may span frames that aren't contiguous, or be above what the
+// device can address, e.g. 4GB for busmaster IDE and xHCI controllers without 64-bit support.
+// There's no IOMMU to remap it for the device, so such a buffer is bounced instead: the device
+// gets contiguous frames of the frame pool, which the caller's buffer is copied to before and
+// from after, depending on which way the data goes. Memory a device keeps using for as long as its
+// driver runs, like descriptor rings and the buffers they point to, can't be bounced, as there's
+// no telling when the device is done with it: it's only checked to be within reach
+use core::ops::Range;
+
+use crate::{
+    error::Fault,
+    frame::{self, FRAME_SIZE},
+    paging::Mapper,
+};
+
+/// The limit of devices that can only DMA to 32-bit addresses
+pub const DMA_32_BIT_LIMIT: u64 = 1 << 32;
+/// The limit of devices that can DMA to 64-bit addresses
+pub const DMA_64_BIT_LIMIT: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The device reads the buffer
+    ToDevice,
+    /// The device writes the buffer
+    FromDevice,
+    Bidirectional,
+}
+
+/// The physical address of `range`, as `translate` maps virtual addresses, if it's physically
+/// contiguous and ends below `limit`
+fn contiguous_physical_address(
+    range: Range<u64>,
+    limit: u64,
+    translate: impl Fn(u64) -> Option<u64>,
+) -> Option<u64> {
+    let start = translate(range.start)?;
+    // Each page is contiguous, so checking where each of them starts is enough
+    let mut page = range.start & !(FRAME_SIZE - 1);
+    while page + FRAME_SIZE < range.end {
+        page += FRAME_SIZE;
+        if translate(page)? != start + (page - range.start) {
+            return None;
+        }
+    }
+    (start.checked_add(range.end - range.start)? <= limit).then_some(start)
+}
+
+/// Where a device that can DMA below `limit` finds `memory`, mapped by the active page tables,
+/// which it keeps using for as long as its driver runs. Fails for memory that would have to be
+/// bounced, as it's either not physically contiguous or not below the limit. Kernel statics are
+/// both, as the bootloader loads the kernel in the identity mapped first GB
+pub fn device_address<T: ?Sized>(memory: &T, limit: u64) -> Result<u64, Fault> {
+    let mapper = Mapper::active();
+    let start = memory as *const T as *const u8 as usize as u64;
+    let physical_address = mapper
+        .translate(start)
+        .ok_or(Fault::NoPageTableFor(start))?;
+    contiguous_physical_address(
+        start..start + size_of_val(memory) as u64,
+        limit,
+        |address| mapper.translate(address),
+    )
+    .ok_or(Fault::AboveDmaLimit(physical_address))
+}
+
+/// A buffer of the caller's, as a device can DMA to or from it, bounced if needed. The data the
+/// device wrote to a bounce buffer goes back to the caller's buffer when this is dropped
+pub struct DmaBuffer<'a> {
+    buffer: &'a mut [u8],
+    direction: Direction,
+    physical_address: u64,
+    // The first frame of the bounce buffer and how many there are, if the buffer is bounced
+    bounce_frames: Option<(u64, usize)>,
+    physical_memory_offset: u64,
+}
+
+impl<'a> DmaBuffer<'a> {
+    /// Sets up `buffer`, mapped by the active page tables, for a device that can DMA below
+    /// `limit`. A bounce buffer gets a copy of it right away, unless the device only writes it
+    pub fn new(buffer: &'a mut [u8], direction: Direction, limit: u64) -> Result<Self, Fault> {
+        let mapper = Mapper::active();
+        let start = buffer.as_ptr() as u64;
+        let physical_memory_offset = mapper.physical_memory_offset();
+        if let Some(physical_address) =
+            contiguous_physical_address(start..start + buffer.len() as u64, limit, |address| {
+                mapper.translate(address)
+            })
+        {
+            return Ok(Self {
+                buffer,
+                direction,
+                physical_address,
+                bounce_frames: None,
+                physical_memory_offset,
+            });
+        }
+
+        let count = (buffer.len() as u64).div_ceil(FRAME_SIZE).max(1) as usize;
+        let first_frame = frame::with_frames(|frames| frames.allocate_contiguous(count))
+            .ok_or(Fault::OutOfFrames)?;
+        if first_frame + buffer.len() as u64 > limit {
+            release(first_frame, count);
+            return Err(Fault::AboveDmaLimit(first_frame));
+        }
+        let mut dma_buffer = Self {
+            buffer,
+            direction,
+            physical_address: first_frame,
+            bounce_frames: Some((first_frame, count)),
+            physical_memory_offset,
+        };
+        if direction != Direction::FromDevice {
+            dma_buffer.copy_bounce_buffer(true);
+        }
+        Ok(dma_buffer)
+    }
+
+    /// Copies the caller's buffer to the bounce buffer, or the other way around
+    fn copy_bounce_buffer(&mut self, to_bounce_buffer: bool) {
+        let Some((first_frame, _)) = self.bounce_frames else {
+            return;
+        };
+        let bounce_buffer_ptr = self.physical_memory_offset.wrapping_add(first_frame) as usize;
+        // SAFETY: The frames were allocated for this buffer only, as many as its length takes,
+        // and the frame pool is mapped at the physical memory offset
+        let bounce_buffer = unsafe {
+            core::slice::from_raw_parts_mut(bounce_buffer_ptr as *mut u8, self.buffer.len())
+        };
+        if to_bounce_buffer {
+            bounce_buffer.copy_from_slice(self.buffer);
+        } else {
+            self.buffer.copy_from_slice(bounce_buffer);
+        }
+    }
+
+    /// Where the device is to DMA to or from
+    pub fn physical_address(&self) -> u64 {
+        self.physical_address
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn is_bounced(&self) -> bool {
+        self.bounce_frames.is_some()
+    }
+}
+
+fn release(first_frame: u64, count: usize) {
+    frame::with_frames(|frames| {
+        for index in 0..count as u64 {
+            // The frames were allocated together, so they're all still in the pool
+            let _ = frames.release(first_frame + index * FRAME_SIZE);
+        }
+    });
+}
+
+impl Drop for DmaBuffer<'_> {
+    fn drop(&mut self) {
+        let Some((first_frame, count)) = self.bounce_frames else {
+            return;
+        };
+        if self.direction != Direction::ToDevice {
+            self.copy_bounce_buffer(false);
+        }
+        release(first_frame, count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dma::{DMA_32_BIT_LIMIT, contiguous_physical_address};
+
+    // Pages 0x1000 and 0x2000 map frames in a row, 0x3000 a frame elsewhere, 0x4000 nothing
+    fn translate(address: u64) -> Option<u64> {
+        let offset = address & 0xFFF;
+        match address & !0xFFF {
+            0x1000 => Some(0x8000 + offset),
+            0x2000 => Some(0x9000 + offset),
+            0x3000 => Some(0x1_0000_0000 + offset),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn contiguous_buffers() {
+        assert_eq!(
+            Some(0x8800),
+            contiguous_physical_address(0x1800..0x2800, DMA_32_BIT_LIMIT, translate)
+        );
+        // Ending right at the end of a page doesn't look at the next one
+        assert_eq!(
+            Some(0x8000),
+            contiguous_physical_address(0x1000..0x3000, DMA_32_BIT_LIMIT, translate)
+        );
+        assert_eq!(
+            Some(0x8010),
+            contiguous_physical_address(0x1010..0x1010, DMA_32_BIT_LIMIT, translate)
+        );
+    }
+
+    #[test]
+    fn buffers_to_bounce() {
+        // Across frames that aren't contiguous
+        assert_eq!(
+            None,
+            contiguous_physical_address(0x2800..0x3800, u64::MAX, translate)
+        );
+        // Above the limit
+        assert_eq!(
+            None,
+            contiguous_physical_address(0x3000..0x3100, DMA_32_BIT_LIMIT, translate)
+        );
+        assert_eq!(
+            Some(0x1_0000_0000),
+            contiguous_physical_address(0x3000..0x3100, u64::MAX, translate)
+        );
+        // Partly unmapped
+        assert_eq!(
+            None,
+            contiguous_physical_address(0x3800..0x4800, u64::MAX, translate)
+        );
+    }
+}
@@ -0,0 +1,554 @@
+// Intel 8254x/82574 (e1000/e1000e) network card, QEMU's default NIC
+// https://wiki.osdev.org/Intel_Ethernet_i217
+// https://www.intel.com/content/dam/doc/manual/pci-pci-x-family-gbe-controllers-software-dev-manual.pdf
+use crate::{
+    dma::{self, DMA_64_BIT_LIMIT},
+    error::{Context, Error, Facility, Fault},
+    interrupts, make_bitmap,
+    msr::MemoryType,
+    paging,
+    pci_function::{BaseAddress, Function},
+};
+
+const INTEL_VENDOR_ID: u16 = 0x8086;
+/// 82540EM (QEMU's `e1000`), 82545EM and 82574L (QEMU's `e1000e`)
+const SUPPORTED_DEVICE_IDS: [u16; 3] = [0x100E, 0x100F, 0x10D3];
+
+const CONTROL: u32 = 0x0000;
+const STATUS: u32 = 0x0008;
+const INTERRUPT_CAUSE_READ: u32 = 0x00C0;
+const INTERRUPT_MASK_SET: u32 = 0x00D0;
+const INTERRUPT_MASK_CLEAR: u32 = 0x00D8;
+const RECEIVE_CONTROL: u32 = 0x0100;
+const TRANSMIT_CONTROL: u32 = 0x0400;
+const TRANSMIT_INTER_PACKET_GAP: u32 = 0x0410;
+const RECEIVE_DESCRIPTOR_BASE_LOW: u32 = 0x2800;
+const RECEIVE_DESCRIPTOR_BASE_HIGH: u32 = 0x2804;
+const RECEIVE_DESCRIPTOR_LENGTH: u32 = 0x2808;
+const RECEIVE_DESCRIPTOR_HEAD: u32 = 0x2810;
+const RECEIVE_DESCRIPTOR_TAIL: u32 = 0x2818;
+const TRANSMIT_DESCRIPTOR_BASE_LOW: u32 = 0x3800;
+const TRANSMIT_DESCRIPTOR_BASE_HIGH: u32 = 0x3804;
+const TRANSMIT_DESCRIPTOR_LENGTH: u32 = 0x3808;
+const TRANSMIT_DESCRIPTOR_HEAD: u32 = 0x3810;
+const TRANSMIT_DESCRIPTOR_TAIL: u32 = 0x3818;
+const MULTICAST_TABLE_ARRAY: u32 = 0x5200;
+const MULTICAST_TABLE_ARRAY_ENTRIES: u32 = 128;
+const RECEIVE_ADDRESS_LOW: u32 = 0x5400;
+const RECEIVE_ADDRESS_HIGH: u32 = 0x5404;
+
+// Descriptor rings have to be a multiple of 128 bytes long, i.e. of 8 descriptors
+const RECEIVE_DESCRIPTORS: usize = 32;
+const TRANSMIT_DESCRIPTORS: usize = 8;
+const BUFFER_SIZE: usize = 2048;
+/// Largest frame `send` accepts, without the CRC the card appends
+pub const MAX_FRAME_SIZE: usize = 1514;
+const RESET_POLLS: usize = 1_000_000;
+const TRANSMIT_POLLS: usize = 1_000_000;
+
+// 10/8/6 are the recommended IEEE 802.3 values for IPGT/IPGR1/IPGR2
+const TRANSMIT_INTER_PACKET_GAP_VALUE: u32 = 10 | 8 << 10 | 6 << 20;
+// Recommended collision threshold and full duplex collision distance
+const TRANSMIT_COLLISION_THRESHOLD: u32 = 0x0F << 4;
+const TRANSMIT_COLLISION_DISTANCE: u32 = 0x40 << 12;
+
+#[allow(unused)]
+#[repr(u32)]
+pub enum ControlFlag {
+    FullDuplex = 1 << 0,
+    AutoSpeedDetection = 1 << 5,
+    SetLinkUp = 1 << 6,
+    Reset = 1 << 26,
+}
+
+make_bitmap!(new_type: ControlRegister, underlying_flag_type: ControlFlag, repr: u32, nodisplay);
+
+#[allow(unused)]
+#[repr(u32)]
+pub enum StatusFlag {
+    FullDuplex = 1 << 0,
+    LinkUp = 1 << 1,
+}
+
+make_bitmap!(new_type: StatusRegister, underlying_flag_type: StatusFlag, repr: u32, nodisplay);
+
+#[allow(unused)]
+#[repr(u32)]
+pub enum InterruptCause {
+    TransmitDescriptorWrittenBack = 1 << 0,
+    TransmitQueueEmpty = 1 << 1,
+    LinkStatusChange = 1 << 2,
+    ReceiveDescriptorMinimumThreshold = 1 << 4,
+    ReceiverOverrun = 1 << 6,
+    ReceiverTimer = 1 << 7,
+}
+
+make_bitmap!(new_type: InterruptCauses, underlying_flag_type: InterruptCause, repr: u32, nodisplay);
+
+#[allow(unused)]
+#[repr(u32)]
+pub enum ReceiveControlFlag {
+    Enable = 1 << 1,
+    StoreBadPackets = 1 << 2,
+    UnicastPromiscuous = 1 << 3,
+    MulticastPromiscuous = 1 << 4,
+    LongPacketEnable = 1 << 5,
+    BroadcastAccept = 1 << 15,
+    StripEthernetCrc = 1 << 26,
+}
+
+make_bitmap!(new_type: ReceiveControl, underlying_flag_type: ReceiveControlFlag, repr: u32, nodisplay);
+
+#[allow(unused)]
+#[repr(u32)]
+pub enum TransmitControlFlag {
+    Enable = 1 << 1,
+    PadShortPackets = 1 << 3,
+}
+
+make_bitmap!(new_type: TransmitControl, underlying_flag_type: TransmitControlFlag, repr: u32, nodisplay);
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum DescriptorStatusFlag {
+    DescriptorDone = 1 << 0,
+    EndOfPacket = 1 << 1,
+}
+
+make_bitmap!(new_type: DescriptorStatus, underlying_flag_type: DescriptorStatusFlag, repr: u8, nodisplay);
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum TransmitCommandFlag {
+    EndOfPacket = 1 << 0,
+    InsertFcs = 1 << 1,
+    ReportStatus = 1 << 3,
+}
+
+make_bitmap!(new_type: TransmitCommand, underlying_flag_type: TransmitCommandFlag, repr: u8, nodisplay);
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct ReceiveDescriptor {
+    buffer_address: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct TransmitDescriptor {
+    buffer_address: u64,
+    length: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+impl ReceiveDescriptor {
+    const fn blank() -> Self {
+        Self {
+            buffer_address: 0,
+            length: 0,
+            checksum: 0,
+            status: 0,
+            errors: 0,
+            special: 0,
+        }
+    }
+}
+
+impl TransmitDescriptor {
+    const fn blank() -> Self {
+        Self {
+            buffer_address: 0,
+            length: 0,
+            checksum_offset: 0,
+            command: 0,
+            // Done, so that the first round of `send`s doesn't wait on descriptors never queued
+            status: DescriptorStatusFlag::DescriptorDone as u8,
+            checksum_start: 0,
+            special: 0,
+        }
+    }
+}
+
+#[repr(C, align(128))]
+struct DescriptorRing<T, const N: usize>([T; N]);
+
+#[repr(C, align(16))]
+struct PacketBuffers<const N: usize>([[u8; BUFFER_SIZE]; N]);
+
+/// Driver state: the card DMAs into the rings and buffers, so they live in statics
+struct State {
+    mmio_base: u64,
+    mac_address: [u8; 6],
+    next_receive: usize,
+    next_transmit: usize,
+    received_frames: u64,
+    dropped_frames: u64,
+}
+
+static mut E1000_STATE: Option<State> = None;
+static mut E1000_RECEIVE_RING: DescriptorRing<ReceiveDescriptor, RECEIVE_DESCRIPTORS> =
+    DescriptorRing([ReceiveDescriptor::blank(); _]);
+static mut E1000_TRANSMIT_RING: DescriptorRing<TransmitDescriptor, TRANSMIT_DESCRIPTORS> =
+    DescriptorRing([TransmitDescriptor::blank(); _]);
+static mut E1000_RECEIVE_BUFFERS: PacketBuffers<RECEIVE_DESCRIPTORS> =
+    PacketBuffers([[0; BUFFER_SIZE]; _]);
+static mut E1000_TRANSMIT_BUFFERS: PacketBuffers<TRANSMIT_DESCRIPTORS> =
+    PacketBuffers([[0; BUFFER_SIZE]; _]);
+
+pub struct E1000;
+
+fn error(fault: Fault, context: Context) -> Error {
+    Error::new(fault, context, Facility::E1000)
+}
+
+fn state_no_sync() -> Option<&'static mut State> {
+    let state_ptr = &raw mut E1000_STATE;
+    // SAFETY: no threads, and the state is only modified with interrupts disabled outside of
+    // `initialize`
+    unsafe { (*state_ptr).as_mut() }
+}
+
+impl E1000 {
+    fn read_register(mmio_base: u64, register: u32) -> u32 {
+        let register_ptr = (mmio_base + register as u64) as usize as *const u32;
+        // SAFETY: `mmio_base` is the card's BAR0, identity mapped as uncacheable by `initialize`,
+        // and the register offsets are within its 128KB
+        unsafe { register_ptr.read_volatile() }
+    }
+
+    fn write_register(mmio_base: u64, register: u32, value: u32) {
+        let register_ptr = (mmio_base + register as u64) as usize as *mut u32;
+        // SAFETY: see `read_register`
+        unsafe { register_ptr.write_volatile(value) }
+    }
+
+    /// Looks for a supported card on the PCI bus and brings it up: receive and transmit are
+    /// enabled, with the receive interrupt unmasked. Returns the card's PCI function, whose
+    /// interrupt handler is expected to call `handle_interrupt_no_sync`
+    pub fn initialize() -> Result<Function, Error> {
+        let Some(function) = Function::find(|function| {
+            function.vendor_id() == INTEL_VENDOR_ID
+                && SUPPORTED_DEVICE_IDS.contains(&function.device_id())
+        }) else {
+            return Err(error(Fault::NoNetworkCard, Context::Io));
+        };
+        let Some(BaseAddress::Memory(mmio_base)) = function.base_address(0) else {
+            return Err(error(
+                Fault::InvalidValueForField("BAR0"),
+                Context::SettingUpNetworkCard,
+            ));
+        };
+
+        paging::Mapper::active()
+            .identity_map_gigabyte(mmio_base, MemoryType::Uncacheable)
+            .map_err(|fault| error(fault, Context::SettingUpPageTable))?;
+        function.enable_memory_space_and_bus_mastering();
+
+        Self::reset(mmio_base)?;
+        Self::setup_receive(mmio_base)
+            .and_then(|_| Self::setup_transmit(mmio_base))
+            .map_err(|fault| error(fault, Context::SettingUpNetworkCard))?;
+
+        let receive_address_low = Self::read_register(mmio_base, RECEIVE_ADDRESS_LOW);
+        let receive_address_high = Self::read_register(mmio_base, RECEIVE_ADDRESS_HIGH);
+        let mut mac_address = [0; 6];
+        mac_address[..4].copy_from_slice(&receive_address_low.to_le_bytes());
+        mac_address[4..].copy_from_slice(&receive_address_high.to_le_bytes()[..2]);
+
+        let state_ptr = &raw mut E1000_STATE;
+        // SAFETY: no threads, and the card's interrupts are still masked
+        unsafe {
+            *state_ptr = Some(State {
+                mmio_base,
+                mac_address,
+                next_receive: 0,
+                next_transmit: 0,
+                received_frames: 0,
+                dropped_frames: 0,
+            })
+        };
+
+        use InterruptCause::*;
+        Self::write_register(
+            mmio_base,
+            INTERRUPT_MASK_SET,
+            (ReceiverTimer
+                | ReceiveDescriptorMinimumThreshold
+                | ReceiverOverrun
+                | LinkStatusChange)
+                .into(),
+        );
+        Ok(function)
+    }
+
+    fn reset(mmio_base: u64) -> Result<(), Error> {
+        Self::write_register(mmio_base, INTERRUPT_MASK_CLEAR, u32::MAX);
+        let mut control = ControlRegister::from(Self::read_register(mmio_base, CONTROL));
+        control.set_flag(ControlFlag::Reset);
+        Self::write_register(mmio_base, CONTROL, control.into());
+        if !(0..RESET_POLLS).any(|_| {
+            !ControlRegister::from(Self::read_register(mmio_base, CONTROL))
+                .is_set(ControlFlag::Reset)
+        }) {
+            return Err(error(
+                Fault::HangingNetworkCard,
+                Context::SettingUpNetworkCard,
+            ));
+        }
+        // The reset unmasks nothing, but may have left causes pending
+        Self::write_register(mmio_base, INTERRUPT_MASK_CLEAR, u32::MAX);
+        Self::read_register(mmio_base, INTERRUPT_CAUSE_READ);
+
+        let mut control = ControlRegister::from(Self::read_register(mmio_base, CONTROL));
+        control.set_flag(ControlFlag::SetLinkUp);
+        control.set_flag(ControlFlag::AutoSpeedDetection);
+        Self::write_register(mmio_base, CONTROL, control.into());
+        Ok(())
+    }
+
+    fn setup_receive(mmio_base: u64) -> Result<(), Fault> {
+        for entry in 0..MULTICAST_TABLE_ARRAY_ENTRIES {
+            Self::write_register(mmio_base, MULTICAST_TABLE_ARRAY + entry * 4, 0);
+        }
+
+        let ring_ptr = &raw mut E1000_RECEIVE_RING;
+        let buffers_ptr = &raw const E1000_RECEIVE_BUFFERS;
+        // SAFETY: no threads, and the receiver is still disabled, so the card isn't using the ring
+        let ring = unsafe { &mut (*ring_ptr).0 };
+        // SAFETY: same as above, for the buffers
+        let buffers = unsafe { &(*buffers_ptr).0 };
+        for (descriptor, buffer) in ring.iter_mut().zip(buffers.iter()) {
+            *descriptor = ReceiveDescriptor::blank();
+            descriptor.buffer_address = dma::device_address(buffer, DMA_64_BIT_LIMIT)?;
+        }
+
+        let ring_address = dma::device_address(ring, DMA_64_BIT_LIMIT)?;
+        Self::write_register(mmio_base, RECEIVE_DESCRIPTOR_BASE_LOW, ring_address as u32);
+        Self::write_register(
+            mmio_base,
+            RECEIVE_DESCRIPTOR_BASE_HIGH,
+            (ring_address >> 32) as u32,
+        );
+        Self::write_register(
+            mmio_base,
+            RECEIVE_DESCRIPTOR_LENGTH,
+            size_of_val(ring) as u32,
+        );
+        Self::write_register(mmio_base, RECEIVE_DESCRIPTOR_HEAD, 0);
+        // The tail is the last descriptor handed to the card: all but one are, so that head ==
+        // tail always means the ring is full
+        Self::write_register(
+            mmio_base,
+            RECEIVE_DESCRIPTOR_TAIL,
+            RECEIVE_DESCRIPTORS as u32 - 1,
+        );
+
+        use ReceiveControlFlag::*;
+        // A zero buffer size field means 2048 byte buffers
+        Self::write_register(
+            mmio_base,
+            RECEIVE_CONTROL,
+            (Enable | BroadcastAccept | StripEthernetCrc).into(),
+        );
+        Ok(())
+    }
+
+    fn setup_transmit(mmio_base: u64) -> Result<(), Fault> {
+        let ring_ptr = &raw mut E1000_TRANSMIT_RING;
+        let buffers_ptr = &raw const E1000_TRANSMIT_BUFFERS;
+        // SAFETY: no threads, and the transmitter is still disabled, so the card isn't using the
+        // ring
+        let ring = unsafe { &mut (*ring_ptr).0 };
+        // SAFETY: same as above, for the buffers
+        let buffers = unsafe { &(*buffers_ptr).0 };
+        for (descriptor, buffer) in ring.iter_mut().zip(buffers.iter()) {
+            *descriptor = TransmitDescriptor::blank();
+            descriptor.buffer_address = dma::device_address(buffer, DMA_64_BIT_LIMIT)?;
+        }
+
+        let ring_address = dma::device_address(ring, DMA_64_BIT_LIMIT)?;
+        Self::write_register(mmio_base, TRANSMIT_DESCRIPTOR_BASE_LOW, ring_address as u32);
+        Self::write_register(
+            mmio_base,
+            TRANSMIT_DESCRIPTOR_BASE_HIGH,
+            (ring_address >> 32) as u32,
+        );
+        Self::write_register(
+            mmio_base,
+            TRANSMIT_DESCRIPTOR_LENGTH,
+            size_of_val(ring) as u32,
+        );
+        Self::write_register(mmio_base, TRANSMIT_DESCRIPTOR_HEAD, 0);
+        Self::write_register(mmio_base, TRANSMIT_DESCRIPTOR_TAIL, 0);
+
+        Self::write_register(
+            mmio_base,
+            TRANSMIT_INTER_PACKET_GAP,
+            TRANSMIT_INTER_PACKET_GAP_VALUE,
+        );
+        use TransmitControlFlag::*;
+        Self::write_register(
+            mmio_base,
+            TRANSMIT_CONTROL,
+            u32::from(Enable | PadShortPackets)
+                | TRANSMIT_COLLISION_THRESHOLD
+                | TRANSMIT_COLLISION_DISTANCE,
+        );
+        Ok(())
+    }
+
+    pub fn initialized() -> bool {
+        interrupts::without_interrupts(|| state_no_sync().is_some())
+    }
+
+    pub fn mac_address() -> Option<[u8; 6]> {
+        interrupts::without_interrupts(|| state_no_sync().map(|state| state.mac_address))
+    }
+
+    pub fn link_up() -> bool {
+        interrupts::without_interrupts(|| {
+            state_no_sync().is_some_and(|state| {
+                StatusRegister::from(Self::read_register(state.mmio_base, STATUS))
+                    .is_set(StatusFlag::LinkUp)
+            })
+        })
+    }
+
+    /// Frames received and frames dropped because the receive ring was full
+    pub fn statistics() -> (u64, u64) {
+        interrupts::without_interrupts(|| {
+            state_no_sync().map_or((0, 0), |state| {
+                (state.received_frames, state.dropped_frames)
+            })
+        })
+    }
+
+    /// Acknowledges the card's interrupt. Received frames stay in the receive ring until
+    /// `poll_recv` copies them out, the interrupt only wakes up whoever is waiting for them
+    pub fn handle_interrupt_no_sync() {
+        let Some(state) = state_no_sync() else {
+            return;
+        };
+        // Reading the cause register clears it
+        let causes =
+            InterruptCauses::from(Self::read_register(state.mmio_base, INTERRUPT_CAUSE_READ));
+        if causes.is_set(InterruptCause::ReceiverOverrun) {
+            state.dropped_frames += 1;
+        }
+    }
+
+    /// Queues `frame`, a full Ethernet frame without CRC, for transmission. Waits for the oldest
+    /// queued frame to be sent if all transmit descriptors are in use
+    ///
+    /// Not reentrant: it must not be called from interrupt handlers
+    pub fn send(frame: &[u8]) -> Result<(), Fault> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(Fault::FrameTooLarge(frame.len()));
+        }
+        let (mmio_base, index) = interrupts::without_interrupts(|| {
+            let state = state_no_sync()?;
+            let index = state.next_transmit;
+            state.next_transmit = (index + 1) % TRANSMIT_DESCRIPTORS;
+            Some((state.mmio_base, index))
+        })
+        .ok_or(Fault::NoNetworkCard)?;
+
+        let ring_ptr = &raw mut E1000_TRANSMIT_RING;
+        let buffers_ptr = &raw mut E1000_TRANSMIT_BUFFERS;
+        // SAFETY: only computes the address of a field of the static ring, `index` is in range
+        let status_ptr = unsafe { &raw const (*ring_ptr).0[index].status };
+        if !(0..TRANSMIT_POLLS).any(|_| {
+            // SAFETY: the card writes the status back with DMA, hence the volatile read
+            let status = DescriptorStatus::from(unsafe { status_ptr.read_volatile() });
+            status.is_set(DescriptorStatusFlag::DescriptorDone)
+        }) {
+            return Err(Fault::HangingNetworkCard);
+        }
+
+        // SAFETY: no threads, the descriptor was claimed above and the card is done with it
+        let descriptor = unsafe { &mut (*ring_ptr).0[index] };
+        // SAFETY: same as above, for the descriptor's buffer
+        let buffer = unsafe { &mut (*buffers_ptr).0[index] };
+        buffer[..frame.len()].copy_from_slice(frame);
+        descriptor.length = frame.len() as u16;
+        use TransmitCommandFlag::*;
+        descriptor.command = (EndOfPacket | InsertFcs | ReportStatus).into();
+        descriptor.status = 0;
+
+        // Handing the descriptor over: the card reads it, and the buffer, after seeing the tail
+        // move
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+        Self::write_register(
+            mmio_base,
+            TRANSMIT_DESCRIPTOR_TAIL,
+            ((index + 1) % TRANSMIT_DESCRIPTORS) as u32,
+        );
+        Ok(())
+    }
+
+    /// Copies the next received frame into `buffer` and returns its length, or None if no frame
+    /// was received. Frames longer than `buffer` are truncated
+    pub fn poll_recv(buffer: &mut [u8]) -> Option<usize> {
+        interrupts::without_interrupts(|| {
+            let state = state_no_sync()?;
+            let index = state.next_receive;
+            let ring_ptr = &raw mut E1000_RECEIVE_RING;
+            let buffers_ptr = &raw const E1000_RECEIVE_BUFFERS;
+            // SAFETY: no threads and interrupts are disabled. The card only writes descriptors
+            // it owns, and this one is only touched once the card marked it done
+            let descriptor = unsafe { &mut (*ring_ptr).0[index] };
+            let status_ptr = &raw const descriptor.status;
+            // SAFETY: the card writes the status back with DMA, hence the volatile read
+            let status = DescriptorStatus::from(unsafe { status_ptr.read_volatile() });
+            if !status.is_set(DescriptorStatusFlag::DescriptorDone) {
+                return None;
+            }
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+
+            let length = (descriptor.length as usize).min(buffer.len());
+            // SAFETY: see above
+            let received = unsafe { &(*buffers_ptr).0[index] };
+            buffer[..length].copy_from_slice(&received[..length]);
+            // Frames spanning multiple buffers can't happen with long packets disabled
+            state.received_frames += 1;
+
+            // Giving the descriptor back to the card
+            descriptor.status = 0;
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+            Self::write_register(state.mmio_base, RECEIVE_DESCRIPTOR_TAIL, index as u32);
+            state.next_receive = (index + 1) % RECEIVE_DESCRIPTORS;
+            Some(length)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::e1000::{
+        DescriptorRing, RECEIVE_DESCRIPTORS, ReceiveDescriptor, TRANSMIT_DESCRIPTORS,
+        TransmitDescriptor,
+    };
+
+    #[test]
+    fn descriptor_layout() {
+        assert_eq!(16, size_of::<ReceiveDescriptor>());
+        assert_eq!(16, size_of::<TransmitDescriptor>());
+        assert_eq!(
+            0,
+            size_of::<DescriptorRing<ReceiveDescriptor, RECEIVE_DESCRIPTORS>>() % 128
+        );
+        assert_eq!(
+            0,
+            size_of::<DescriptorRing<TransmitDescriptor, TRANSMIT_DESCRIPTORS>>() % 128
+        );
+    }
+}
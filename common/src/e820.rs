@@ -0,0 +1,145 @@
+// The physical memory map the BIOS reports through INT 15h, EAX=E820h, which stage1 collects in
+// real mode for stage2 to pass on to the kernel in the BootInfo. Entries may be in any order,
+// overlap, and leave holes: anything that isn't covered by a usable entry isn't RAM to hand out
+// https://wiki.osdev.org/Detecting_Memory_(x86)#BIOS_Function:_INT_0x15,_EAX_=_0xE820
+use core::{fmt::Display, ops::Range};
+
+use num_enum::TryFromPrimitive;
+
+/// How many entries stage1 collects, see bootloader/stage1/boot.asm
+pub const MAX_ENTRIES: usize = 64;
+// ACPI 3.0 extended attributes: entries without it set are to be ignored
+const ATTRIBUTE_VALID: u32 = 1 << 0;
+
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MemoryKind {
+    Usable = 1,
+    Reserved = 2,
+    AcpiReclaimable = 3,
+    AcpiNonVolatile = 4,
+    Bad = 5,
+}
+
+impl Display for MemoryKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            MemoryKind::Usable => "usable",
+            MemoryKind::Reserved => "reserved",
+            MemoryKind::AcpiReclaimable => "ACPI reclaimable",
+            MemoryKind::AcpiNonVolatile => "ACPI NVS",
+            MemoryKind::Bad => "bad",
+        })
+    }
+}
+
+/// An entry as the BIOS writes it, which is also how the BootInfo carries it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Entry {
+    pub base: u64,
+    pub length: u64,
+    kind: u32,
+    attributes: u32,
+}
+
+impl Entry {
+    pub const fn empty() -> Self {
+        Self {
+            base: 0,
+            length: 0,
+            kind: 0,
+            attributes: 0,
+        }
+    }
+
+    pub const fn new(kind: MemoryKind, range: Range<u64>) -> Self {
+        Self {
+            base: range.start,
+            length: range.end - range.start,
+            kind: kind as u32,
+            attributes: ATTRIBUTE_VALID,
+        }
+    }
+
+    pub fn range(&self) -> Range<u64> {
+        self.base..self.base.saturating_add(self.length)
+    }
+
+    /// None for the kinds ACPI added later, which are to be treated as reserved
+    pub fn kind(&self) -> Option<MemoryKind> {
+        MemoryKind::try_from(self.kind).ok()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.attributes & ATTRIBUTE_VALID != 0 && self.length != 0
+    }
+
+    pub fn is_usable(&self) -> bool {
+        self.is_valid() && self.kind() == Some(MemoryKind::Usable)
+    }
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let range = self.range();
+        write!(f, "{:#014x}-{:#014x} ", range.start, range.end)?;
+        match self.kind() {
+            Some(kind) => write!(f, "{kind}"),
+            None => write!(f, "type {}", self.kind),
+        }
+    }
+}
+
+/// Whether `range` is all RAM according to `entries`, i.e. covered by usable entries, which may
+/// be adjacent, and by no entry of another kind
+pub fn is_usable(entries: &[Entry], range: &Range<u64>) -> bool {
+    let overlaps = |entry: &Entry| {
+        let entry_range = entry.range();
+        entry_range.start < range.end && range.start < entry_range.end
+    };
+    if entries
+        .iter()
+        .any(|entry| entry.is_valid() && !entry.is_usable() && overlaps(entry))
+    {
+        return false;
+    }
+    let mut covered_up_to = range.start;
+    while covered_up_to < range.end {
+        let Some(entry) = entries
+            .iter()
+            .find(|entry| entry.is_usable() && entry.range().contains(&covered_up_to))
+        else {
+            return false;
+        };
+        covered_up_to = entry.range().end;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::e820::{Entry, MemoryKind, is_usable};
+
+    #[test]
+    fn usable_ranges() {
+        let mut acpi_3_invalid = Entry::new(MemoryKind::Reserved, 0x20_0000..0x30_0000);
+        acpi_3_invalid.attributes = 0;
+        let entries = [
+            Entry::new(MemoryKind::Usable, 0x10_0000..0x20_0000),
+            Entry::new(MemoryKind::Usable, 0x0..0x9_FC00),
+            Entry::new(MemoryKind::Reserved, 0x9_FC00..0x10_0000),
+            Entry::new(MemoryKind::Usable, 0x20_0000..0x800_0000),
+            acpi_3_invalid,
+            Entry::new(MemoryKind::AcpiNonVolatile, 0x7FF_F000..0x800_0000),
+        ];
+        assert!(is_usable(&entries, &(0x1000..0x9_F000)));
+        // Across two adjacent usable entries, and through one that doesn't count
+        assert!(is_usable(&entries, &(0x1F_0000..0x40_0000)));
+        assert!(!is_usable(&entries, &(0x9_F000..0xA_0000)));
+        assert!(!is_usable(&entries, &(0x7FF_E000..0x800_0000)));
+        // Past the end of the map
+        assert!(!is_usable(&entries, &(0x800_0000..0x800_1000)));
+        assert!(is_usable(&entries, &(0x1000..0x1000)));
+    }
+}
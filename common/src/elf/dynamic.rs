@@ -0,0 +1,227 @@
+// https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.dynamic.html#dynamic_section
+// Only the 64-bit format is supported, like for relocations
+
+use zerocopy::TryFromBytes as _;
+
+use crate::{
+    elf::Halfword,
+    error::{Error, Facility, Fault, try_read_error},
+};
+
+mod inner {
+    use zerocopy::{I64, LE, TryFromBytes, U64};
+
+    #[derive(Debug, TryFromBytes)]
+    #[repr(C)]
+    pub(super) struct Elf64DynamicEntry {
+        pub(super) tag: I64<LE>,
+        pub(super) value: U64<LE>,
+    }
+}
+
+pub const ELF64_ENTRY_SIZE: usize = size_of::<inner::Elf64DynamicEntry>();
+
+const DT_NULL: i64 = 0;
+const DT_NEEDED: i64 = 1;
+const DT_PLTRELSZ: i64 = 2;
+const DT_PLTGOT: i64 = 3;
+const DT_HASH: i64 = 4;
+const DT_STRTAB: i64 = 5;
+const DT_SYMTAB: i64 = 6;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+const DT_STRSZ: i64 = 10;
+const DT_SYMENT: i64 = 11;
+const DT_INIT: i64 = 12;
+const DT_FINI: i64 = 13;
+const DT_SONAME: i64 = 14;
+const DT_REL: i64 = 17;
+const DT_RELSZ: i64 = 18;
+const DT_RELENT: i64 = 19;
+const DT_PLTREL: i64 = 20;
+const DT_TEXTREL: i64 = 22;
+const DT_JMPREL: i64 = 23;
+const DT_BIND_NOW: i64 = 24;
+const DT_INIT_ARRAY: i64 = 25;
+const DT_FINI_ARRAY: i64 = 26;
+const DT_INIT_ARRAYSZ: i64 = 27;
+const DT_FINI_ARRAYSZ: i64 = 28;
+const DT_FLAGS: i64 = 30;
+const DT_GNU_HASH: i64 = 0x6fff_fef5;
+const DT_RELACOUNT: i64 = 0x6fff_fff9;
+const DT_FLAGS_1: i64 = 0x6fff_fffb;
+
+/// An entry of the dynamic section. Addresses are virtual addresses as linked, sizes are in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DynamicEntry {
+    /// String table offset of the name of a needed library
+    Needed(u64),
+    PltRelocationsSize(u64),
+    PltGot(u64),
+    Hash(u64),
+    GnuHash(u64),
+    StringTable(u64),
+    StringTableSize(u64),
+    SymbolTable(u64),
+    SymbolEntrySize(u64),
+    Rela(u64),
+    RelaSize(u64),
+    RelaEntrySize(u64),
+    /// How many of the RELA relocations are relative ones, which come first
+    RelaCount(u64),
+    Rel(u64),
+    RelSize(u64),
+    RelEntrySize(u64),
+    /// Type of the PLT relocations, DT_REL or DT_RELA
+    PltRelocationType(u64),
+    JmpRel(u64),
+    Init(u64),
+    Fini(u64),
+    InitArray(u64),
+    InitArraySize(u64),
+    FiniArray(u64),
+    FiniArraySize(u64),
+    /// String table offset of the name of this shared object
+    SoName(u64),
+    TextRelocations,
+    BindNow,
+    Flags(u64),
+    Flags1(u64),
+    /// Anything else, e.g. OS or processor specific tags
+    Other {
+        tag: i64,
+        value: u64,
+    },
+}
+
+impl DynamicEntry {
+    fn new(tag: i64, value: u64) -> Self {
+        match tag {
+            DT_NEEDED => Self::Needed(value),
+            DT_PLTRELSZ => Self::PltRelocationsSize(value),
+            DT_PLTGOT => Self::PltGot(value),
+            DT_HASH => Self::Hash(value),
+            DT_GNU_HASH => Self::GnuHash(value),
+            DT_STRTAB => Self::StringTable(value),
+            DT_STRSZ => Self::StringTableSize(value),
+            DT_SYMTAB => Self::SymbolTable(value),
+            DT_SYMENT => Self::SymbolEntrySize(value),
+            DT_RELA => Self::Rela(value),
+            DT_RELASZ => Self::RelaSize(value),
+            DT_RELAENT => Self::RelaEntrySize(value),
+            DT_RELACOUNT => Self::RelaCount(value),
+            DT_REL => Self::Rel(value),
+            DT_RELSZ => Self::RelSize(value),
+            DT_RELENT => Self::RelEntrySize(value),
+            DT_PLTREL => Self::PltRelocationType(value),
+            DT_JMPREL => Self::JmpRel(value),
+            DT_INIT => Self::Init(value),
+            DT_FINI => Self::Fini(value),
+            DT_INIT_ARRAY => Self::InitArray(value),
+            DT_INIT_ARRAYSZ => Self::InitArraySize(value),
+            DT_FINI_ARRAY => Self::FiniArray(value),
+            DT_FINI_ARRAYSZ => Self::FiniArraySize(value),
+            DT_SONAME => Self::SoName(value),
+            DT_TEXTREL => Self::TextRelocations,
+            DT_BIND_NOW => Self::BindNow,
+            DT_FLAGS => Self::Flags(value),
+            DT_FLAGS_1 => Self::Flags1(value),
+            tag => Self::Other { tag, value },
+        }
+    }
+}
+
+/// The entries of a dynamic section or PT_DYNAMIC segment, up to the terminating DT_NULL
+#[derive(Debug)]
+pub struct DynamicEntries<'a> {
+    bytes: &'a [u8],
+    bytes_read_so_far: usize,
+}
+
+impl<'a> DynamicEntries<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        if !bytes.len().is_multiple_of(ELF64_ENTRY_SIZE) {
+            return Err(Error::parsing_error(
+                Fault::NotEnoughBytesFor("dynamic entries"),
+                Facility::ElfDynamicSection,
+            ));
+        }
+
+        Ok(Self {
+            bytes,
+            bytes_read_so_far: 0,
+        })
+    }
+}
+
+impl Iterator for DynamicEntries<'_> {
+    type Item = Result<DynamicEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes_read_so_far >= self.bytes.len() {
+            return None;
+        }
+
+        let facility =
+            Facility::ElfDynamicEntry((self.bytes_read_so_far / ELF64_ENTRY_SIZE) as Halfword);
+        match inner::Elf64DynamicEntry::try_read_from_prefix(
+            self.bytes.get(self.bytes_read_so_far..)?,
+        ) {
+            Ok((entry, _rest)) if entry.tag.get() == DT_NULL => {
+                // Whatever follows the terminator is padding
+                self.bytes_read_so_far = self.bytes.len();
+                None
+            }
+            Ok((entry, _rest)) => {
+                self.bytes_read_so_far += ELF64_ENTRY_SIZE;
+                Some(Ok(DynamicEntry::new(entry.tag.get(), entry.value.get())))
+            }
+            Err(err) => Some(Err(try_read_error(facility, err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elf::dynamic::{DynamicEntries, DynamicEntry, ELF64_ENTRY_SIZE};
+
+    fn entry(tag: i64, value: u64) -> [u8; ELF64_ENTRY_SIZE] {
+        let mut entry = [0u8; ELF64_ENTRY_SIZE];
+        entry[..8].copy_from_slice(&tag.to_le_bytes());
+        entry[8..].copy_from_slice(&value.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn dynamic_entries() {
+        let entries = [
+            entry(1, 0x21),
+            entry(7, 0x1000),
+            entry(8, 0x180),
+            entry(0x6fff_fff9, 16),
+            entry(25, 0x203000),
+            entry(0x7000_0001, 42),
+            entry(0, 0),
+            entry(1, 0x33),
+        ];
+        let bytes = entries.as_flattened();
+        let mut entries = DynamicEntries::new(bytes).unwrap();
+        for expected in [
+            DynamicEntry::Needed(0x21),
+            DynamicEntry::Rela(0x1000),
+            DynamicEntry::RelaSize(0x180),
+            DynamicEntry::RelaCount(16),
+            DynamicEntry::InitArray(0x203000),
+            DynamicEntry::Other {
+                tag: 0x7000_0001,
+                value: 42,
+            },
+        ] {
+            assert_eq!(expected, entries.next().unwrap().unwrap());
+        }
+        // Nothing after DT_NULL
+        assert!(entries.next().is_none());
+        assert!(DynamicEntries::new(&bytes[1..]).is_err());
+    }
+}
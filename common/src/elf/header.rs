@@ -81,10 +81,10 @@ impl Display for Encoding {
     }
 }
 
-#[cfg_attr(test, derive(Default, PartialEq, Eq))]
-#[derive(Debug, Clone, Copy, TryFromBytes, TryFromPrimitive)]
+#[cfg_attr(test, derive(Default))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromBytes, TryFromPrimitive)]
 #[repr(u8)]
-pub(crate) enum Class {
+pub enum Class {
     #[cfg_attr(test, default)]
     Elf32 = 1,
     Elf64 = 2,
@@ -124,7 +124,7 @@ impl Display for Version {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(unused)]
 pub enum ObjectType {
     None,
@@ -171,10 +171,10 @@ impl Display for ObjectType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
 #[allow(unused)]
-enum Machine {
+pub enum Machine {
     None = 0,
     M32 = 1,
     Sparc = 2,
@@ -284,7 +284,7 @@ impl Header {
         }
     }
 
-    pub(crate) fn class(&self) -> Class {
+    pub fn class(&self) -> Class {
         match &self.0 {
             inner::Header::Elf32(elf32_header) => elf32_header.identifier.class,
             inner::Header::Elf64(elf64_header) => elf64_header.identifier.class,
@@ -354,6 +354,14 @@ impl Header {
         }
     }
 
+    /// The e_machine field, which may be a machine Machine doesn't list
+    pub fn machine(&self) -> Halfword {
+        match &self.0 {
+            inner::Header::Elf32(elf32_header) => elf32_header.machine.get(),
+            inner::Header::Elf64(elf64_header) => elf64_header.machine.get(),
+        }
+    }
+
     pub fn entrypoint(&self) -> u64 {
         match &self.0 {
             inner::Header::Elf32(elf32_header) => elf32_header.entrypoint.get() as u64,
@@ -448,11 +456,13 @@ impl TryFrom<&[u8]> for Header {
             ));
         }
 
-        if elf_header.program_header_entry_size() as usize
-            != (match elf_identifier.class {
-                Class::Elf32 => program_header::ELF32_ENTRY_SIZE,
-                Class::Elf64 => program_header::ELF64_ENTRY_SIZE,
-            })
+        // Relocatable objects have no program header, and leave its entry size at 0
+        if elf_header.program_header_entries() != 0
+            && elf_header.program_header_entry_size() as usize
+                != (match elf_identifier.class {
+                    Class::Elf32 => program_header::ELF32_ENTRY_SIZE,
+                    Class::Elf64 => program_header::ELF64_ENTRY_SIZE,
+                })
         {
             return Err(Error::parsing_error(
                 Fault::InvalidValueForField("phentsize"),
@@ -616,4 +626,3 @@ mod tests {
         );
     }
 }
-
@@ -1,17 +1,33 @@
 // https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html#elfid
 
+pub mod dynamic;
 pub mod header;
 pub mod program_header;
+pub mod relocation;
 pub mod section;
+pub mod symbol;
 
 use crate::error::{Error, Facility, Fault};
 
 type Halfword = u16;
 type Word = u32;
 
+// e_shstrndx of files without a section names string table
+const UNDEFINED_SECTION_INDEX: usize = 0;
+
+/// What a loader can run, see File::check_target
+#[derive(Debug, Clone, Copy)]
+pub struct Target {
+    pub machine: header::Machine,
+    pub class: header::Class,
+    /// Any of these
+    pub object_types: &'static [header::ObjectType],
+}
+
 pub struct File<'a> {
     bytes: &'a [u8],
     header: header::Header,
+    string_table: Option<section::StringTable<'a>>,
 }
 
 impl<'a> File<'a> {
@@ -22,8 +38,8 @@ impl<'a> File<'a> {
         let n_entries = self.header.section_header_entries();
 
         section::SectionHeaderEntries::new(
-            &self.bytes[self.header.section_header_offset() as usize..]
-                [..(self.header.section_header_entry_size() * n_entries) as usize],
+            section_header_bytes(self.bytes, &self.header)
+                .expect("not enough bytes for the section header"),
             self.header.class(),
             n_entries,
         )
@@ -37,8 +53,8 @@ impl<'a> File<'a> {
         let n_entries = self.header.program_header_entries();
 
         program_header::ProgramHeaderEntries::new(
-            &self.bytes[self.header.program_header_offset() as usize..]
-                [..(self.header.program_header_entry_size() * n_entries) as usize],
+            program_header_bytes(self.bytes, &self.header)
+                .expect("not enough bytes for the program header"),
             self.header.class(),
             n_entries,
         )
@@ -56,36 +72,159 @@ impl<'a> File<'a> {
         let error_reporting_facility = Facility::ElfSectionHeaderEntry(index as Halfword);
 
         match section::HeaderEntry::try_from_bytes(
-            self.bytes.get(
-                (self.header.section_header_offset() as usize
-                    + index * self.header.section_header_entry_size() as usize)..,
-            )?,
+            section_header_entry_bytes(self.bytes, &self.header, index)?,
             self.header.class(),
             error_reporting_facility,
         ) {
             Ok(section_entry_header) => {
-                let offset = section_entry_header.offset() as usize;
-                Some(
-                    section_entry_header.try_to_entry(
-                        self.bytes
-                            .get(offset..offset + section_entry_header.size() as usize)?,
-                    ),
-                )
+                Some(section_entry_header.try_to_entry(self.section_bytes(&section_entry_header)?))
             }
             Err(err) => Some(Err(err)),
         }
     }
 
+    /// The program header entry at `index`, without parsing the ones before it. None if there's
+    /// no such entry
+    pub fn get_program_header_by_index(
+        &self,
+        index: usize,
+    ) -> Option<Result<program_header::HeaderEntry, Error>> {
+        self.program_headers().nth(index)
+    }
+
+    /// The string table with the section names, validated when the file was parsed. None if the
+    /// file doesn't have one
+    pub fn string_table(&self) -> Option<section::StringTable<'a>> {
+        self.string_table
+    }
+
+    /// The index and the name of every section, in order. Without a string table for the section
+    /// names, i.e. when the header's index of it is SHN_UNDEF, every name is empty. Section
+    /// headers that don't parse and names out of the string table come out as errors
+    pub fn section_names(&self) -> impl Iterator<Item = Result<(usize, &'a str), Error>> + use<'a> {
+        let string_table = self.string_table;
+        self.sections()
+            .enumerate()
+            .map(move |(index, section_entry_header)| {
+                let name_index = section_entry_header?.name_index() as usize;
+                let name = match string_table {
+                    Some(string_table) => string_table.get_string(name_index)?,
+                    None => "",
+                };
+                Ok((index, name))
+            })
+    }
+
+    /// The header entry of the first section called `name`, e.g. ".text". None if there's no
+    /// such section, or no string table to look up section names in
+    pub fn get_section_by_name(&self, name: &str) -> Option<Result<section::HeaderEntry, Error>> {
+        // Without a string table every section is nameless
+        self.string_table?;
+        self.sections().zip(self.section_names()).find_map(
+            |(section_entry_header, section_name)| match section_name {
+                Ok((_, section_name)) => (section_name == name).then_some(section_entry_header),
+                Err(err) => Some(Err(err)),
+            },
+        )
+    }
+
+    pub fn section_bytes(&self, section_entry_header: &section::HeaderEntry) -> Option<&'a [u8]> {
+        get_bytes(
+            self.bytes,
+            section_entry_header.offset(),
+            section_entry_header.size(),
+            "section",
+        )
+        .ok()
+    }
+
     pub fn get_segment(&self, program_header: &program_header::HeaderEntry) -> Option<&[u8]> {
-        self.bytes.get(
-            (program_header.offset() as usize)
-                ..(program_header.offset() + program_header.segment_size_on_file()) as usize,
+        get_bytes(
+            self.bytes,
+            program_header.offset(),
+            program_header.segment_size_on_file(),
+            "segment",
         )
+        .ok()
+    }
+
+    /// The relocation tables of the file, one per RELA section
+    pub fn relocation_tables(
+        &self,
+    ) -> impl Iterator<Item = Result<relocation::RelocationEntries<'a>, Error>> + use<'a> {
+        let bytes = self.bytes;
+        self.sections().filter_map(move |section_entry_header| {
+            let section_entry_header = match section_entry_header {
+                Ok(section_entry_header) => section_entry_header,
+                Err(err) => return Some(Err(err)),
+            };
+            if !matches!(
+                section_entry_header.r#type(),
+                section::SectionEntryType::Rela
+            ) {
+                return None;
+            }
+            Some(
+                get_bytes(
+                    bytes,
+                    section_entry_header.offset(),
+                    section_entry_header.size(),
+                    "relocations",
+                )
+                .map_err(|fault| Error::parsing_error(fault, Facility::ElfRelocationTable))
+                .and_then(relocation::RelocationEntries::new),
+            )
+        })
+    }
+
+    /// The entries of the PT_DYNAMIC segment, if the file has one
+    pub fn dynamic_entries(&self) -> Option<Result<dynamic::DynamicEntries<'a>, Error>> {
+        let bytes = self.bytes;
+        self.program_headers().find_map(|program_header| {
+            let program_header = match program_header {
+                Ok(program_header) => program_header,
+                Err(err) => return Some(Err(err)),
+            };
+            if !matches!(
+                program_header.r#type(),
+                program_header::ProgramHeaderEntryType::Dynamic
+            ) {
+                return None;
+            }
+            Some(
+                get_bytes(
+                    bytes,
+                    program_header.offset(),
+                    program_header.segment_size_on_file(),
+                    "dynamic entries",
+                )
+                .map_err(|fault| Error::parsing_error(fault, Facility::ElfDynamicSection))
+                .and_then(dynamic::DynamicEntries::new),
+            )
+        })
     }
 
     pub fn header(&self) -> &header::Header {
         &self.header
     }
+
+    /// Checks that the file is for `target` before anything of it gets loaded, as code for another
+    /// machine or mode only fails once it runs
+    pub fn check_target(&self, target: &Target) -> Result<(), Error> {
+        let field = if self.header.machine() != target.machine as Halfword {
+            "machine"
+        } else if self.header.class() != target.class {
+            "class"
+        } else if !target.object_types.contains(&self.header.r#type()) {
+            "object type"
+        } else {
+            return Ok(());
+        };
+        Err(Error::parsing_error(
+            Fault::InvalidValueForField(field),
+            Facility::ElfHeader,
+        ))
+    }
 }
 
 impl<'a> TryFrom<&'a [u8]> for File<'a> {
@@ -95,37 +234,331 @@ impl<'a> TryFrom<&'a [u8]> for File<'a> {
         let result = Self {
             bytes,
             header: bytes.try_into()?,
+            string_table: None,
         };
 
-        if result.bytes.len() < result.header.section_header_offset() as usize
-            || result.bytes.len()
-                < (result.header.section_header_offset()
-                    + (result.header.section_header_entry_size()
-                        * result.header.section_header_entries()) as u64) as usize
-        {
-            return Err(Error::parsing_error(
-                Fault::NotEnoughBytesFor("section header"),
-                Facility::ElfFile,
-            ));
-        }
+        section_header_bytes(bytes, &result.header)
+            .map_err(|fault| Error::parsing_error(fault, Facility::ElfFile))?;
+        program_header_bytes(bytes, &result.header)
+            .map_err(|fault| Error::parsing_error(fault, Facility::ElfFile))?;
 
-        if result.bytes.len() < result.header.program_header_offset() as usize
-            || result.bytes.len()
-                < (result.header.program_header_offset()
-                    + (result.header.program_header_entry_size()
-                        * result.header.program_header_entries()) as u64) as usize
-        {
-            return Err(Error::parsing_error(
-                Fault::NotEnoughBytesFor("program header"),
-                Facility::ElfFile,
-            ));
-        }
+        let string_table = find_string_table(bytes, &result.header)?;
 
         Ok(Self {
             bytes,
             header: bytes.try_into()?,
+            string_table,
         })
     }
 }
 
+/// The `size` bytes at `offset` in `bytes`, `what` being what they are for the fault. Both come
+/// from the file, so they may not fit in a usize (on 32-bit targets) or add up past its end
+fn get_bytes<'a>(
+    bytes: &'a [u8],
+    offset: u64,
+    size: u64,
+    what: &'static str,
+) -> Result<&'a [u8], Fault> {
+    let end = offset
+        .checked_add(size)
+        .ok_or(Fault::OffsetOverflow(what))?;
+    let range = usize::try_from(offset)
+        .and_then(|offset| Ok(offset..usize::try_from(end)?))
+        .map_err(|_| Fault::OffsetOverflow(what))?;
+    bytes.get(range).ok_or(Fault::NotEnoughBytesFor(what))
+}
+
+fn section_header_bytes<'a>(bytes: &'a [u8], header: &header::Header) -> Result<&'a [u8], Fault> {
+    get_bytes(
+        bytes,
+        header.section_header_offset(),
+        u64::from(header.section_header_entry_size()) * u64::from(header.section_header_entries()),
+        "section header",
+    )
+}
+
+fn program_header_bytes<'a>(bytes: &'a [u8], header: &header::Header) -> Result<&'a [u8], Fault> {
+    get_bytes(
+        bytes,
+        header.program_header_offset(),
+        u64::from(header.program_header_entry_size()) * u64::from(header.program_header_entries()),
+        "program header",
+    )
+}
 
+/// The bytes of the section header from the entry at `index` on, which must be below the number
+/// of entries
+fn section_header_entry_bytes<'a>(
+    bytes: &'a [u8],
+    header: &header::Header,
+    index: usize,
+) -> Option<&'a [u8]> {
+    let entry_size = usize::from(header.section_header_entry_size());
+    section_header_bytes(bytes, header)
+        .ok()?
+        .get(index * entry_size..)
+}
+
+/// Checks that the string table index of the header points to a string table within `bytes`,
+/// whose section header was already checked to be in bounds
+fn find_string_table<'a>(
+    bytes: &'a [u8],
+    header: &header::Header,
+) -> Result<Option<section::StringTable<'a>>, Error> {
+    let index = header.string_table_index() as usize;
+    if index == UNDEFINED_SECTION_INDEX {
+        return Ok(None);
+    }
+    let invalid_index = Error::parsing_error(
+        Fault::InvalidValueForField("string table index"),
+        Facility::ElfHeader,
+    );
+    if index >= header.section_header_entries() as usize {
+        return Err(invalid_index);
+    }
+
+    let section_entry_header = section::HeaderEntry::try_from_bytes(
+        section_header_entry_bytes(bytes, header, index).ok_or(invalid_index)?,
+        header.class(),
+        Facility::ElfSectionHeaderEntry(index as Halfword),
+    )?;
+    if !matches!(
+        section_entry_header.r#type(),
+        section::SectionEntryType::Strtab
+    ) {
+        return Err(invalid_index);
+    }
+    get_bytes(
+        bytes,
+        section_entry_header.offset(),
+        section_entry_header.size(),
+        "string table",
+    )
+    .map(|string_table| Some(section::StringTable::new(string_table)))
+    .map_err(|fault| Error::parsing_error(fault, Facility::ElfFile))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        elf::{
+            File, Target,
+            header::{Class, Machine, ObjectType},
+        },
+        error::Fault,
+    };
+
+    const SECTION_HEADER_OFFSET: usize = 64;
+    const STRING_TABLE_OFFSET: usize = SECTION_HEADER_OFFSET + 3 * 64;
+    const STRING_TABLE: &[u8] = b"\0.text\0.shstrtab\0";
+
+    /// A 64-bit executable with a null section, .text and .shstrtab, and no segments
+    fn elf_file(string_table_index: u16) -> [u8; STRING_TABLE_OFFSET + STRING_TABLE.len()] {
+        let mut bytes = [0u8; STRING_TABLE_OFFSET + STRING_TABLE.len()];
+        bytes[..8].copy_from_slice(b"\x7fELF\x02\x01\x01\x00");
+        bytes[16..18].copy_from_slice(&2u16.to_le_bytes());
+        bytes[18..20].copy_from_slice(&0x3eu16.to_le_bytes());
+        bytes[20..24].copy_from_slice(&1u32.to_le_bytes());
+        bytes[40..48].copy_from_slice(&(SECTION_HEADER_OFFSET as u64).to_le_bytes());
+        bytes[52..54].copy_from_slice(&64u16.to_le_bytes());
+        bytes[54..56].copy_from_slice(&56u16.to_le_bytes());
+        bytes[58..60].copy_from_slice(&64u16.to_le_bytes());
+        bytes[60..62].copy_from_slice(&3u16.to_le_bytes());
+        bytes[62..64].copy_from_slice(&string_table_index.to_le_bytes());
+
+        let mut section =
+            |index: usize, name_index: u32, r#type: u32, offset: usize, size: usize| {
+                let entry = &mut bytes[SECTION_HEADER_OFFSET + index * 64..][..64];
+                entry[0..4].copy_from_slice(&name_index.to_le_bytes());
+                entry[4..8].copy_from_slice(&r#type.to_le_bytes());
+                entry[24..32].copy_from_slice(&(offset as u64).to_le_bytes());
+                entry[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+            };
+        section(1, 1, 1, 0, 0);
+        section(2, 7, 3, STRING_TABLE_OFFSET, STRING_TABLE.len());
+        bytes[STRING_TABLE_OFFSET..].copy_from_slice(STRING_TABLE);
+        bytes
+    }
+
+    #[test]
+    fn finds_sections_by_name() {
+        let bytes = elf_file(2);
+        let file = File::try_from(&bytes[..]).unwrap();
+        assert_eq!(
+            ".shstrtab",
+            file.string_table().unwrap().get_string(7).unwrap()
+        );
+        assert!(file.string_table().unwrap().get_string(100).is_err());
+        let text = file.get_section_by_name(".text").unwrap().unwrap();
+        assert_eq!(1, text.name_index());
+        let string_table = file.get_section_by_name(".shstrtab").unwrap().unwrap();
+        assert_eq!(STRING_TABLE_OFFSET as u64, string_table.offset());
+        assert!(file.get_section_by_name(".data").is_none());
+
+        assert_eq!(3, file.sections().len());
+        let string_table = file.sections().nth(2).unwrap().unwrap();
+        assert_eq!(7, string_table.name_index());
+        assert!(file.sections().nth(3).is_none());
+        assert_eq!(0, file.program_headers().len());
+        assert!(file.get_program_header_by_index(0).is_none());
+    }
+
+    #[test]
+    fn names_sections() {
+        let bytes = elf_file(2);
+        let file = File::try_from(&bytes[..]).unwrap();
+        let mut names = file.section_names();
+        assert_eq!((0, ""), names.next().unwrap().unwrap());
+        assert_eq!((1, ".text"), names.next().unwrap().unwrap());
+        assert_eq!((2, ".shstrtab"), names.next().unwrap().unwrap());
+        assert!(names.next().is_none());
+
+        let bytes_without_names = elf_file(0);
+        let file = File::try_from(&bytes_without_names[..]).unwrap();
+        assert!(
+            file.section_names()
+                .enumerate()
+                .all(|(index, name)| name.unwrap() == (index, ""))
+        );
+        assert_eq!(3, file.section_names().count());
+
+        // .text's name past the end of the string table
+        let mut bytes = elf_file(2);
+        bytes[SECTION_HEADER_OFFSET + 64..][..4].copy_from_slice(&100u32.to_le_bytes());
+        let file = File::try_from(&bytes[..]).unwrap();
+        let mut names = file.section_names();
+        assert!(names.next().unwrap().is_ok());
+        assert!(matches!(
+            names.next().unwrap().map_err(|err| err.fault()),
+            Err(Fault::StringIndexOutOfBounds(100))
+        ));
+        assert!(names.next().unwrap().is_ok());
+    }
+
+    #[test]
+    fn validates_the_string_table_index() {
+        let bytes = elf_file(0);
+        let file = File::try_from(&bytes[..]).unwrap();
+        assert!(file.string_table().is_none());
+        assert!(file.get_section_by_name(".text").is_none());
+
+        // Out of bounds, and pointing to a section that isn't a string table
+        assert!(File::try_from(&elf_file(3)[..]).is_err());
+        assert!(File::try_from(&elf_file(1)[..]).is_err());
+    }
+
+    #[test]
+    fn checks_the_target() {
+        let x86_64_executables = Target {
+            machine: Machine::X86_64,
+            class: Class::Elf64,
+            object_types: &[ObjectType::Executable, ObjectType::Dynamic],
+        };
+        let check = |bytes: &[u8], target: &Target| {
+            File::try_from(bytes)
+                .unwrap()
+                .check_target(target)
+                .map_err(|err| err.fault())
+        };
+        let bytes = elf_file(2);
+        assert!(check(&bytes, &x86_64_executables).is_ok());
+        let mut i386 = bytes;
+        i386[18..20].copy_from_slice(&(Machine::I386 as u16).to_le_bytes());
+        assert!(matches!(
+            check(&i386, &x86_64_executables),
+            Err(Fault::InvalidValueForField("machine"))
+        ));
+        let mut object = bytes;
+        object[16..18].copy_from_slice(&1u16.to_le_bytes());
+        assert!(matches!(
+            check(&object, &x86_64_executables),
+            Err(Fault::InvalidValueForField("object type"))
+        ));
+        let elf32 = Target {
+            class: Class::Elf32,
+            ..x86_64_executables
+        };
+        assert!(matches!(
+            check(&bytes, &elf32),
+            Err(Fault::InvalidValueForField("class"))
+        ));
+    }
+
+    fn parsing_fault(bytes: &[u8]) -> Fault {
+        match File::try_from(bytes) {
+            Ok(_) => panic!("the file was parsed"),
+            Err(err) => err.fault(),
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_headers() {
+        // A section header offset wrapping around with its size, and one just past the end
+        let mut bytes = elf_file(2);
+        bytes[40..48].copy_from_slice(&(u64::MAX - 64).to_le_bytes());
+        assert!(matches!(
+            parsing_fault(&bytes),
+            Fault::OffsetOverflow("section header")
+        ));
+        let mut bytes = elf_file(2);
+        let past_the_end = bytes.len() as u64 - 3 * 64 + 1;
+        bytes[40..48].copy_from_slice(&past_the_end.to_le_bytes());
+        assert!(matches!(
+            parsing_fault(&bytes),
+            Fault::NotEnoughBytesFor("section header")
+        ));
+        // More entries than the file has room for, 0xFFFF * 64 overflowing a u16
+        let mut bytes = elf_file(2);
+        bytes[60..62].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(
+            parsing_fault(&bytes),
+            Fault::NotEnoughBytesFor("section header")
+        ));
+        // The same for the program header
+        let mut bytes = elf_file(2);
+        bytes[32..40].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        bytes[56..58].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(
+            parsing_fault(&bytes),
+            Fault::OffsetOverflow("program header")
+        ));
+        let mut bytes = elf_file(2);
+        bytes[32..40].copy_from_slice(&64u64.to_le_bytes());
+        bytes[56..58].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(
+            parsing_fault(&bytes),
+            Fault::NotEnoughBytesFor("program header")
+        ));
+        // A string table wrapping around
+        let mut bytes = elf_file(2);
+        let string_table = SECTION_HEADER_OFFSET + 2 * 64;
+        bytes[string_table + 24..string_table + 32].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            parsing_fault(&bytes),
+            Fault::OffsetOverflow("string table")
+        ));
+
+        // A section whose bytes wrap around
+        let mut bytes = elf_file(0);
+        let text = SECTION_HEADER_OFFSET + 64;
+        bytes[text + 24..text + 32].copy_from_slice(&u64::MAX.to_le_bytes());
+        bytes[text + 32..text + 40].copy_from_slice(&2u64.to_le_bytes());
+        let file = File::try_from(&bytes[..]).unwrap();
+        let text = file.sections().nth(1).unwrap().unwrap();
+        assert!(file.section_bytes(&text).is_none());
+        assert!(file.get_section_by_index(1).is_none());
+    }
+
+    #[test]
+    fn sections_of_unsupported_types() {
+        let bytes = elf_file(2);
+        let file = File::try_from(&bytes[..]).unwrap();
+        let Some(Err(err)) = file.get_section_by_index(1) else {
+            panic!(".text isn't a section type File parses")
+        };
+        assert!(matches!(err.fault(), Fault::UnsupportedSectionType(1)));
+        assert!(file.get_section_by_index(2).unwrap().is_ok());
+        assert!(file.get_section_by_index(3).is_none());
+    }
+}
@@ -140,6 +140,8 @@ impl HeaderEntry {
             5 => ProgramHeaderEntryType::SharedLibrary,
             6 => ProgramHeaderEntryType::ProgramHeader,
             7 => ProgramHeaderEntryType::ThreadLocalStorage,
+            0x6474e551 => ProgramHeaderEntryType::GnuStack,
+            0x6474e552 => ProgramHeaderEntryType::GnuRelro,
             t if (8..=0x5FFFFFFF).contains(&t) => ProgramHeaderEntryType::OsSpecific(t),
             t if (0x60000000..=0xFFFFFFFF).contains(&t) => {
                 ProgramHeaderEntryType::ProcessorSpecific(t)
@@ -248,6 +250,10 @@ pub enum ProgramHeaderEntryType {
     SharedLibrary = 5,
     ProgramHeader = 6,
     ThreadLocalStorage = 7,
+    /// Whether the stack is to be executable, in its permissions
+    GnuStack = 0x6474e551,
+    /// What's to be made read-only once relocations are applied
+    GnuRelro = 0x6474e552,
     OsSpecific(u32),
     ProcessorSpecific(u32),
 }
@@ -265,6 +271,8 @@ impl TryFrom<u32> for ProgramHeaderEntryType {
             5 => Ok(ProgramHeaderEntryType::SharedLibrary),
             6 => Ok(ProgramHeaderEntryType::ProgramHeader),
             7 => Ok(ProgramHeaderEntryType::ThreadLocalStorage),
+            0x6474e551 => Ok(ProgramHeaderEntryType::GnuStack),
+            0x6474e552 => Ok(ProgramHeaderEntryType::GnuRelro),
             t if (8..=0x5FFFFFFF).contains(&t) => Ok(ProgramHeaderEntryType::OsSpecific(t)),
             t if (0x60000000..=0xFFFFFFFF).contains(&t) => {
                 Ok(ProgramHeaderEntryType::ProcessorSpecific(t))
@@ -285,6 +293,8 @@ impl core::fmt::Display for ProgramHeaderEntryType {
             ProgramHeaderEntryType::SharedLibrary => write!(f, "SHLIB"),
             ProgramHeaderEntryType::ProgramHeader => write!(f, "PHDR"),
             ProgramHeaderEntryType::ThreadLocalStorage => write!(f, "TLS"),
+            ProgramHeaderEntryType::GnuStack => write!(f, "GNU_STACK"),
+            ProgramHeaderEntryType::GnuRelro => write!(f, "GNU_RELRO"),
             ProgramHeaderEntryType::OsSpecific(t) => write!(f, "OS-SPECIFIC({t:#x})"),
             ProgramHeaderEntryType::ProcessorSpecific(t) => write!(f, "PROCESSOR-SPECIFIC({t:#x})"),
         }
@@ -303,16 +313,12 @@ impl<'a> ProgramHeaderEntries<'a> {
         class: header::Class,
         n_entries: Halfword,
     ) -> Result<Self, Error> {
-        let entry_size = match class {
-            header::Class::Elf32 => ELF32_ENTRY_SIZE,
-            header::Class::Elf64 => ELF64_ENTRY_SIZE,
-        };
-        if bytes.len() < (n_entries as u32 * entry_size as u32) as usize {
+        let Some(bytes) = bytes.get(..usize::from(n_entries) * header_entry_size(class)) else {
             return Err(Error::parsing_error(
                 Fault::NotEnoughBytesFor("program headers"),
                 Facility::ElfProgramHeader,
             ));
-        }
+        };
 
         Ok(Self {
             bytes,
@@ -330,21 +336,40 @@ impl<'a> Iterator for ProgramHeaderEntries<'a> {
             return None;
         }
 
-        let entry_size = match self.class {
-            header::Class::Elf32 => ELF32_ENTRY_SIZE,
-            header::Class::Elf64 => ELF64_ENTRY_SIZE,
-        };
+        let entry_size = header_entry_size(self.class);
 
-        Some(
-            HeaderEntry::try_from_bytes(
-                self.bytes.get(self.bytes_read_so_far..)?,
-                self.class,
-                Facility::ElfProgramHeaderEntry(entry_size as Halfword),
-            )
-            .inspect(|_| {
-                self.bytes_read_so_far += entry_size;
-            }),
-        )
+        let entry = HeaderEntry::try_from_bytes(
+            self.bytes.get(self.bytes_read_so_far..)?,
+            self.class,
+            Facility::ElfProgramHeaderEntry((self.bytes_read_so_far / entry_size) as Halfword),
+        );
+        // Past a broken entry too, so that iterating to the end always ends
+        self.bytes_read_so_far += entry_size;
+        Some(entry)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len =
+            self.bytes.len().saturating_sub(self.bytes_read_so_far) / header_entry_size(self.class);
+        (len, Some(len))
+    }
+
+    /// Skips to the entry without parsing the ones before it
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.bytes_read_so_far = n
+            .checked_mul(header_entry_size(self.class))
+            .and_then(|skipped| skipped.checked_add(self.bytes_read_so_far))
+            .unwrap_or(usize::MAX);
+        self.next()
+    }
+}
+
+impl ExactSizeIterator for ProgramHeaderEntries<'_> {}
+
+fn header_entry_size(class: header::Class) -> usize {
+    match class {
+        header::Class::Elf32 => ELF32_ENTRY_SIZE,
+        header::Class::Elf64 => ELF64_ENTRY_SIZE,
     }
 }
 
@@ -353,8 +378,10 @@ mod tests {
     use crate::{
         elf::{
             self,
+            header::Class,
             program_header::{
-                HeaderEntry, PermissionFlag, Permissions, ProgramHeaderEntryType,
+                HeaderEntry, PermissionFlag, Permissions, ProgramHeaderEntries,
+                ProgramHeaderEntryType,
                 inner::{Elf32HeaderEntry, Elf64HeaderEntry},
             },
         },
@@ -396,7 +423,7 @@ mod tests {
         0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     ];
 
-    const PROCESSOR_SPECIFIC_HEADER_64_BIT: [u8; size_of::<Elf64HeaderEntry>()] = [
+    const GNU_RELRO_HEADER_64_BIT: [u8; size_of::<Elf64HeaderEntry>()] = [
         0x52, 0xe5, 0x74, 0x64, 0x04, 0x00, 0x00, 0x00, 0x40, 0x09, 0x08, 0x00, 0x00, 0x00, 0x00,
         0x00, 0x40, 0x29, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x29, 0x08, 0x00, 0x00, 0x00,
         0x00, 0x00, 0x10, 0x4c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc0, 0x56, 0x00, 0x00, 0x00,
@@ -410,6 +437,35 @@ mod tests {
         0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     ];
 
+    #[test]
+    fn index_based_access() {
+        let mut bytes = [0u8; 3 * size_of::<Elf64HeaderEntry>()];
+        for (entry, header) in bytes.chunks_exact_mut(size_of::<Elf64HeaderEntry>()).zip([
+            PHDR_HEADER_64_BIT,
+            INTERPRETER_HEADER_64_BIT,
+            PT_LOAD_HEADER_64_BIT,
+        ]) {
+            entry.copy_from_slice(&header);
+        }
+
+        let mut entries = ProgramHeaderEntries::new(&bytes, Class::Elf64, 3).unwrap();
+        assert_eq!(3, entries.len());
+        let interpreter = entries.nth(1).unwrap().unwrap();
+        assert_eq!(ProgramHeaderEntryType::Interpreter, interpreter.r#type());
+        assert_eq!(1, entries.len());
+        let load = entries.next().unwrap().unwrap();
+        assert_eq!(ProgramHeaderEntryType::Load, load.r#type());
+        assert_eq!(0, entries.len());
+        assert!(entries.next().is_none());
+
+        // Only the entries of the header, whatever follows them
+        let mut entries = ProgramHeaderEntries::new(&bytes, Class::Elf64, 2).unwrap();
+        assert_eq!(2, entries.len());
+        assert!(entries.nth(2).is_none());
+        assert!(entries.nth(usize::MAX).is_none());
+        assert!(ProgramHeaderEntries::new(&bytes, Class::Elf64, 4).is_err());
+    }
+
     #[test]
     fn test_headers_64bit() {
         let mut header = HeaderEntry::try_from_bytes(
@@ -503,15 +559,12 @@ mod tests {
         );
 
         header = HeaderEntry::try_from_bytes(
-            &PROCESSOR_SPECIFIC_HEADER_64_BIT[..],
+            &GNU_RELRO_HEADER_64_BIT[..],
             crate::elf::header::Class::Elf64,
             Facility::ElfProgramHeader,
         )
         .unwrap();
-        assert_eq!(
-            ProgramHeaderEntryType::ProcessorSpecific(0x6474e552),
-            header.r#type()
-        );
+        assert_eq!(ProgramHeaderEntryType::GnuRelro, header.r#type());
         assert_eq!(0x80940, header.offset());
         assert_eq!(0x82940, header.virtual_address());
         assert_eq!(0x82940, header.physical_address());
@@ -548,7 +601,7 @@ mod tests {
         0x00, 0x00,
     ];
 
-    const PROCESSOR_SPECIFIC_HEADER_32_BIT: [u8; size_of::<Elf32HeaderEntry>()] = [
+    const GNU_STACK_HEADER_32_BIT: [u8; size_of::<Elf32HeaderEntry>()] = [
         0x51, 0xe5, 0x74, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00,
         0x00, 0x00,
@@ -575,15 +628,12 @@ mod tests {
         );
 
         header = HeaderEntry::try_from_bytes(
-            &PROCESSOR_SPECIFIC_HEADER_32_BIT[..],
+            &GNU_STACK_HEADER_32_BIT[..],
             crate::elf::header::Class::Elf32,
             Facility::ElfProgramHeader,
         )
         .unwrap();
-        assert_eq!(
-            ProgramHeaderEntryType::ProcessorSpecific(0x6474e551),
-            header.r#type()
-        );
+        assert_eq!(ProgramHeaderEntryType::GnuStack, header.r#type());
         assert_eq!(0x0, header.offset());
         assert_eq!(0x0, header.virtual_address());
         assert_eq!(0x0, header.physical_address());
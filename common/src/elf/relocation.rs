@@ -0,0 +1,149 @@
+// https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.reloc.html
+// Only the 64-bit RELA format is supported, as that's what the kernel is linked with
+
+use num_enum::TryFromPrimitive;
+use zerocopy::TryFromBytes as _;
+
+use crate::{
+    elf::Halfword,
+    error::{Error, Facility, Fault, try_read_error},
+};
+
+mod inner {
+    use zerocopy::{I64, LE, TryFromBytes, U64};
+
+    #[derive(Debug, TryFromBytes)]
+    #[repr(C)]
+    pub(super) struct Elf64RelocationEntry {
+        pub(super) offset: U64<LE>,
+        pub(super) info: U64<LE>,
+        pub(super) addend: I64<LE>,
+    }
+}
+
+pub const ELF64_ENTRY_SIZE: usize = size_of::<inner::Elf64RelocationEntry>();
+
+/// The x86_64 relocation types a position independent kernel and kernel modules need
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum RelocationType {
+    None = 0,
+    /// Symbol value plus addend
+    Absolute64 = 1,
+    /// Symbol value plus addend, minus the address of the patched location, in 32 bits
+    Pc32 = 2,
+    /// Like Pc32, for calls that could go through the PLT. There's no PLT for modules
+    Plt32 = 4,
+    /// Load address plus addend
+    Relative = 8,
+    /// Symbol value plus addend, zero extended from 32 bits
+    Absolute32 = 10,
+    /// Symbol value plus addend, sign extended from 32 bits
+    Absolute32Signed = 11,
+}
+
+#[derive(Debug)]
+pub struct RelocationEntry(inner::Elf64RelocationEntry);
+
+impl RelocationEntry {
+    /// Virtual address, as linked, of the location to patch
+    pub fn offset(&self) -> u64 {
+        self.0.offset.get()
+    }
+
+    pub fn r#type(&self) -> Result<RelocationType, Fault> {
+        let r#type = self.0.info.get() as u32;
+        RelocationType::try_from(r#type).map_err(|_| Fault::UnsupportedRelocation(r#type))
+    }
+
+    pub fn symbol_index(&self) -> u32 {
+        (self.0.info.get() >> 32) as u32
+    }
+
+    pub fn addend(&self) -> i64 {
+        self.0.addend.get()
+    }
+}
+
+pub struct RelocationEntries<'a> {
+    bytes: &'a [u8],
+    bytes_read_so_far: usize,
+}
+
+impl<'a> RelocationEntries<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        if !bytes.len().is_multiple_of(ELF64_ENTRY_SIZE) {
+            return Err(Error::parsing_error(
+                Fault::NotEnoughBytesFor("relocations"),
+                Facility::ElfRelocationTable,
+            ));
+        }
+
+        Ok(Self {
+            bytes,
+            bytes_read_so_far: 0,
+        })
+    }
+}
+
+impl Iterator for RelocationEntries<'_> {
+    type Item = Result<RelocationEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes_read_so_far >= self.bytes.len() {
+            return None;
+        }
+
+        let facility =
+            Facility::ElfRelocationEntry((self.bytes_read_so_far / ELF64_ENTRY_SIZE) as Halfword);
+        Some(
+            inner::Elf64RelocationEntry::try_read_from_prefix(
+                self.bytes.get(self.bytes_read_so_far..)?,
+            )
+            .map_err(|err| try_read_error(facility, err))
+            .map(|(entry, _rest)| RelocationEntry(entry))
+            .inspect(|_| {
+                self.bytes_read_so_far += ELF64_ENTRY_SIZE;
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        elf::relocation::{ELF64_ENTRY_SIZE, RelocationEntries, RelocationType},
+        error::Fault,
+    };
+
+    const RELATIVE_ENTRY: [u8; ELF64_ENTRY_SIZE] = [
+        0x08, 0x30, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x40, 0x12, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    const GOT_ENTRY: [u8; ELF64_ENTRY_SIZE] = [
+        0x10, 0x30, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn relocation_entries() {
+        let mut table = [0u8; 2 * ELF64_ENTRY_SIZE];
+        table[..ELF64_ENTRY_SIZE].copy_from_slice(&RELATIVE_ENTRY);
+        table[ELF64_ENTRY_SIZE..].copy_from_slice(&GOT_ENTRY);
+        let mut entries = RelocationEntries::new(&table).unwrap();
+
+        let relative = entries.next().unwrap().unwrap();
+        assert_eq!(0x203008, relative.offset());
+        assert_eq!(RelocationType::Relative, relative.r#type().unwrap());
+        assert_eq!(0x201240, relative.addend());
+        assert_eq!(0, relative.symbol_index());
+
+        let got = entries.next().unwrap().unwrap();
+        assert_eq!(3, got.symbol_index());
+        assert!(matches!(got.r#type(), Err(Fault::UnsupportedRelocation(6))));
+
+        assert!(entries.next().is_none());
+        assert!(RelocationEntries::new(&table[1..]).is_err());
+    }
+}
@@ -1,10 +1,10 @@
-use core::{fmt::Display, str::Utf8Error};
+use core::fmt::Display;
 
 use num_enum::TryFromPrimitive;
 use zerocopy::TryFromBytes;
 
 use crate::{
-    elf::{Halfword, Word, header},
+    elf::{Halfword, Word, dynamic, header},
     error::{Error, Facility, Fault, try_read_error},
     make_bitmap,
 };
@@ -57,7 +57,7 @@ pub const ELF64_ENTRY_SIZE: usize = size_of::<inner::Elf64HeaderEntry>();
 #[cfg_attr(test, derive(PartialEq, Eq))]
 #[derive(Debug)]
 #[repr(u32)]
-pub(crate) enum SectionEntryType {
+pub enum SectionEntryType {
     Null = 0,
     Progbits = 1,
     Symtab = 2,
@@ -180,12 +180,14 @@ make_bitmap!(new_type: Flags, underlying_flag_type: FlagType, repr: u64, bit_ski
 #[derive(Debug)]
 pub enum Section<'a> {
     StringTable(&'a [u8]),
+    Dynamic(dynamic::DynamicEntries<'a>),
 }
 
 impl<'a> Section<'a> {
     pub fn downcast_to_string_table(&self) -> Result<StringTable<'a>, Facility> {
         match self {
-            Section::StringTable(items) => Ok(StringTable(items)),
+            Section::StringTable(items) => Ok(StringTable::new(items)),
+            Section::Dynamic(_) => Err(Facility::ElfDynamicSection),
         }
     }
 }
@@ -244,7 +246,7 @@ impl HeaderEntry {
 
     /// # Panics
     /// Panics if the type field doesn't contain a valid section type value
-    pub(crate) fn r#type(&self) -> SectionEntryType {
+    pub fn r#type(&self) -> SectionEntryType {
         let error_msg = "type field did not contain a valid ELF object type";
         match &self.0 {
             inner::HeaderEntry::Elf32(entry) => entry.r#type.get().try_into().expect(error_msg),
@@ -306,26 +308,15 @@ impl HeaderEntry {
         'b: 'a,
     {
         match self.r#type() {
-            SectionEntryType::Null => todo!(),
-            SectionEntryType::Progbits => todo!(),
-            SectionEntryType::Symtab => todo!(),
             SectionEntryType::Strtab => Ok(Section::StringTable(bytes)),
-            SectionEntryType::Rela => todo!(),
-            SectionEntryType::Hash => todo!(),
-            SectionEntryType::Dynamic => todo!(),
-            SectionEntryType::Note => todo!(),
-            SectionEntryType::NoBits => todo!(),
-            SectionEntryType::Rel => todo!(),
-            SectionEntryType::Shlib => todo!(),
-            SectionEntryType::DynSym => todo!(),
-            SectionEntryType::InitArray => todo!(),
-            SectionEntryType::FiniArray => todo!(),
-            SectionEntryType::PreinitArray => todo!(),
-            SectionEntryType::Group => todo!(),
-            SectionEntryType::SymtabIndex => todo!(),
-            SectionEntryType::OsSpecific(_) => todo!(),
-            SectionEntryType::ProcessorSpecific(_) => todo!(),
-            SectionEntryType::UserSpecific(_) => todo!(),
+            SectionEntryType::Dynamic => dynamic::DynamicEntries::new(bytes).map(Section::Dynamic),
+            _ => Err(Error::parsing_error(
+                Fault::UnsupportedSectionType(match &self.0 {
+                    inner::HeaderEntry::Elf32(entry) => entry.r#type.get(),
+                    inner::HeaderEntry::Elf64(entry) => entry.r#type.get(),
+                }),
+                Facility::ElfSectionHeader,
+            )),
         }
     }
 
@@ -366,16 +357,12 @@ impl<'a> SectionHeaderEntries<'a> {
         class: header::Class,
         n_entries: Halfword,
     ) -> Result<Self, Error> {
-        let entry_size = match class {
-            header::Class::Elf32 => ELF32_ENTRY_SIZE,
-            header::Class::Elf64 => ELF64_ENTRY_SIZE,
-        };
-        if bytes.len() < (n_entries as u32 * entry_size as u32) as usize {
+        let Some(bytes) = bytes.get(..usize::from(n_entries) * header_entry_size(class)) else {
             return Err(Error::parsing_error(
                 Fault::NotEnoughBytesFor("sections"),
                 Facility::ElfSectionHeader,
             ));
-        }
+        };
 
         Ok(Self {
             bytes,
@@ -393,35 +380,72 @@ impl<'a> Iterator for SectionHeaderEntries<'a> {
             return None;
         }
 
-        let entry_size = match self.class {
-            header::Class::Elf32 => ELF32_ENTRY_SIZE,
-            header::Class::Elf64 => ELF64_ENTRY_SIZE,
-        };
+        let entry_size = header_entry_size(self.class);
 
-        Some(
-            HeaderEntry::try_from_bytes(
-                self.bytes.get(self.bytes_read_so_far..)?,
-                self.class,
-                Facility::ElfSectionHeaderEntry(entry_size as Halfword),
-            )
-            .inspect(|_| {
-                self.bytes_read_so_far += entry_size;
-            }),
-        )
+        let entry = HeaderEntry::try_from_bytes(
+            self.bytes.get(self.bytes_read_so_far..)?,
+            self.class,
+            Facility::ElfSectionHeaderEntry((self.bytes_read_so_far / entry_size) as Halfword),
+        );
+        // Past a broken entry too, so that iterating to the end always ends
+        self.bytes_read_so_far += entry_size;
+        Some(entry)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len =
+            self.bytes.len().saturating_sub(self.bytes_read_so_far) / header_entry_size(self.class);
+        (len, Some(len))
+    }
+
+    /// Skips to the entry without parsing the ones before it
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.bytes_read_so_far = n
+            .checked_mul(header_entry_size(self.class))
+            .and_then(|skipped| skipped.checked_add(self.bytes_read_so_far))
+            .unwrap_or(usize::MAX);
+        self.next()
     }
 }
 
+impl ExactSizeIterator for SectionHeaderEntries<'_> {}
+
+fn header_entry_size(class: header::Class) -> usize {
+    match class {
+        header::Class::Elf32 => ELF32_ENTRY_SIZE,
+        header::Class::Elf64 => ELF64_ENTRY_SIZE,
+    }
+}
+
+/// A table of NUL terminated strings, referenced by the offset they start at
+#[derive(Debug, Clone, Copy)]
 pub struct StringTable<'a>(&'a [u8]);
 
 impl<'a> StringTable<'a> {
-    pub fn get_string(&self, index: usize) -> Option<core::result::Result<&str, Utf8Error>> {
-        if index >= self.0.len() {
-            return None;
-        }
-
-        let endpoint = self.0[index..].iter().position(|&c| c == 0x0)?;
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
 
-        Some(str::from_utf8(&self.0[index..][..endpoint]))
+    /// The string starting at `index`, which has to end within the table
+    pub fn get_string(&self, index: usize) -> Result<&'a str, Error> {
+        let bytes = self.0.get(index..).ok_or(Error::parsing_error(
+            Fault::StringIndexOutOfBounds(index),
+            Facility::ElfStringTable,
+        ))?;
+        let length = bytes
+            .iter()
+            .position(|&c| c == 0x0)
+            .ok_or(Error::parsing_error(
+                Fault::NotEnoughBytesFor("string terminator"),
+                Facility::ElfStringTable,
+            ))?;
+
+        str::from_utf8(&bytes[..length]).map_err(|_| {
+            Error::parsing_error(
+                Fault::InvalidValueForField("UTF-8 string"),
+                Facility::ElfStringTable,
+            )
+        })
     }
 }
 
@@ -916,4 +940,3 @@ mod tests {
         assert_eq!(0, header.entry_size());
     }
 }
-
@@ -0,0 +1,129 @@
+// https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.symtab.html
+// Only the 64-bit format is supported, like for relocations
+
+use num_enum::TryFromPrimitive;
+use zerocopy::TryFromBytes as _;
+
+use crate::error::{Error, Facility, Fault, try_read_error};
+
+mod inner {
+    use zerocopy::{LE, TryFromBytes, U16, U32, U64};
+
+    #[derive(Debug, TryFromBytes)]
+    #[repr(C)]
+    pub(super) struct Elf64Symbol {
+        pub(super) name_index: U32<LE>,
+        pub(super) info: u8,
+        pub(super) other: u8,
+        pub(super) section_index: U16<LE>,
+        pub(super) value: U64<LE>,
+        pub(super) size: U64<LE>,
+    }
+}
+
+pub const ELF64_ENTRY_SIZE: usize = size_of::<inner::Elf64Symbol>();
+
+/// Section index of symbols defined elsewhere
+pub const UNDEFINED_SECTION_INDEX: u16 = 0;
+/// Section index of symbols whose value is not an address, and so isn't relocated
+pub const ABSOLUTE_SECTION_INDEX: u16 = 0xfff1;
+
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Binding {
+    Local = 0,
+    Global = 1,
+    Weak = 2,
+}
+
+#[derive(Debug)]
+pub struct Symbol(inner::Elf64Symbol);
+
+impl Symbol {
+    /// Offset of the name in the string table the symbol table section links to
+    pub fn name_index(&self) -> u32 {
+        self.0.name_index.get()
+    }
+
+    /// None for OS and processor specific bindings
+    pub fn binding(&self) -> Option<Binding> {
+        Binding::try_from(self.0.info >> 4).ok()
+    }
+
+    pub fn section_index(&self) -> u16 {
+        self.0.section_index.get()
+    }
+
+    /// In relocatable files, the offset of the symbol in its section
+    pub fn value(&self) -> u64 {
+        self.0.value.get()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.0.size.get()
+    }
+}
+
+/// The contents of a SYMTAB section, whose first entry is the null symbol
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a>(&'a [u8]);
+
+impl<'a> SymbolTable<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        if !bytes.len().is_multiple_of(ELF64_ENTRY_SIZE) {
+            return Err(Error::parsing_error(
+                Fault::NotEnoughBytesFor("symbols"),
+                Facility::ElfSymbolTable,
+            ));
+        }
+        Ok(Self(bytes))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len() / ELF64_ENTRY_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Result<Symbol, Error>> {
+        let bytes = self.0.get(index.checked_mul(ELF64_ENTRY_SIZE)?..)?;
+        if bytes.is_empty() {
+            return None;
+        }
+        Some(
+            inner::Elf64Symbol::try_read_from_prefix(bytes)
+                .map_err(|err| try_read_error(Facility::ElfSymbol(index as u32), err))
+                .map(|(symbol, _rest)| Symbol(symbol)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elf::symbol::{Binding, ELF64_ENTRY_SIZE, SymbolTable};
+
+    const MODULE_INIT_SYMBOL: [u8; ELF64_ENTRY_SIZE] = [
+        0x01, 0x00, 0x00, 0x00, 0x12, 0x00, 0x02, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn symbol_table() {
+        let mut table = [0u8; 2 * ELF64_ENTRY_SIZE];
+        table[ELF64_ENTRY_SIZE..].copy_from_slice(&MODULE_INIT_SYMBOL);
+        let symbols = SymbolTable::new(&table).unwrap();
+        assert_eq!(2, symbols.len());
+
+        let symbol = symbols.get(1).unwrap().unwrap();
+        assert_eq!(1, symbol.name_index());
+        assert_eq!(Some(Binding::Global), symbol.binding());
+        assert_eq!(2, symbol.section_index());
+        assert_eq!(0x10, symbol.value());
+        assert_eq!(0x20, symbol.size());
+
+        assert!(symbols.get(2).is_none());
+        assert!(SymbolTable::new(&table[1..]).is_err());
+    }
+}
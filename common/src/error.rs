@@ -5,6 +5,8 @@ use core::cmp::min;
 use thiserror::Error;
 use zerocopy::{TryFromBytes, TryReadError};
 
+use crate::{msr::MemoryType, ring_buffer::RingBuffer};
+
 pub const CONTEXT_LENGTH: usize = 16;
 
 #[derive(Clone, Copy, Error, Debug)]
@@ -29,6 +31,18 @@ pub enum Context {
     SettingUpPageTable,
     #[error("setting up processor data structures")]
     SettingUpProcessor,
+    #[error("relocating the kernel")]
+    RelocatingKernel,
+    #[error("loading a payload")]
+    LoadingPayload,
+    #[error("setting up the network card")]
+    SettingUpNetworkCard,
+    #[error("loading a kernel module")]
+    LoadingModule,
+    #[error("loading a user program")]
+    LoadingUserProgram,
+    #[error("boot step '{0}'")]
+    BootStep(&'static str),
 }
 
 impl Error {
@@ -48,6 +62,10 @@ impl Error {
         }
     }
 
+    pub fn fault(&self) -> Fault {
+        self.fault
+    }
+
     pub const fn blank() -> Self {
         Self {
             fault: Fault::None,
@@ -110,6 +128,8 @@ pub enum Fault {
     },
     #[error("not enough bytes for '{0}'")]
     NotEnoughBytesFor(&'static str),
+    #[error("the offset of '{0}' overflows")]
+    OffsetOverflow(&'static str),
     #[error("Invalid LBA address '{0}' (max allowed: {1})")]
     InvalidLBAAddress(u64, u64),
     #[error("Can't read into the given buffer: needed '{1}' bytes, only have {0}")]
@@ -126,30 +146,180 @@ pub enum Fault {
     UnsupportedBootMedium,
     #[error("unsupported CPU feature: {0}")]
     UnsupportedFeature(Feature),
+    #[error("no PAT entry selects memory type {0}")]
+    UnsupportedMemoryType(MemoryType),
+    #[error("unsupported relocation type {0}")]
+    UnsupportedRelocation(u32),
+    #[error("unsupported section type {0:#x}")]
+    UnsupportedSectionType(u32),
+    #[error("no page table to map {0:#x} in")]
+    NoPageTableFor(u64),
+    #[error("{0:#x} is mapped already")]
+    AlreadyMapped(u64),
+    #[error("{0:#x} isn't mapped")]
+    NotMapped(u64),
+    #[error("{0:#x} isn't aligned to the page size")]
+    MisalignedAddress(u64),
+    #[error("{0:#x} is mapped by a large page")]
+    LargePage(u64),
+    #[error("frame {0:#x} isn't in the frame pool")]
+    FrameNotInPool(u64),
+    #[error("frame {0:#x} isn't allocated")]
+    FrameNotAllocated(u64),
+    #[error("frame {0:#x} is shared too many times")]
+    TooManyReferences(u64),
+    #[error("out of frames")]
+    OutOfFrames,
+    #[error("{0:#x} is out of the device's DMA reach")]
+    AboveDmaLimit(u64),
+    #[error("too many reserved memory regions")]
+    TooManyReservedRegions,
+    #[error("too many virtual memory regions")]
+    TooManyVirtualRegions,
+    #[error("{0:#x} is in a virtual memory region already")]
+    RegionTaken(u64),
+    #[error("no room for a virtual memory region of {0:#x} bytes")]
+    OutOfAddressSpace(u64),
+    #[error("no virtual memory region starts at {0:#x}")]
+    NoRegionAt(u64),
+    #[error("interrupt vector {0:#x} is taken")]
+    VectorTaken(u8),
+    #[error("interrupt vector {0:#x} isn't taken")]
+    VectorNotTaken(u8),
+    #[error("out of interrupt vectors")]
+    OutOfVectors,
+    #[error("port {0:#x} is past the I/O permission bitmap")]
+    PortPastIoPermissionBitmap(u16),
+    #[error("there's no interrupt stack {0}, only 1 to 7")]
+    NoSuchInterruptStack(u8),
+    #[error("undefined symbol {name:?}", name = core::str::from_utf8(.0))]
+    UndefinedSymbol([u8; CONTEXT_LENGTH]),
+    #[error("the other end of the pipe is closed")]
+    BrokenPipe,
+    #[error("message of {0} bytes is too large for the queue")]
+    MessageTooLarge(usize),
+    #[error("the queue is full")]
+    QueueFull,
+    #[error("arguments and environment take {0} bytes, more than the stack has room for")]
+    ArgumentsTooLarge(usize),
+    #[error("the program asks for an executable stack")]
+    ExecutableStack,
+    #[error("no more software timers available")]
+    TooManyTimers,
+    #[error("too many partitions: {0}")]
+    TooManyPartitions(usize),
+    #[error("file not found")]
+    FileNotFound,
+    #[error("file exists already")]
+    FileExists,
+    #[error("not a file")]
+    NotAFile,
+    #[error("not a directory")]
+    NotADirectory,
+    #[error("invalid file name")]
+    InvalidFileName,
+    #[error("no free clusters left")]
+    NoFreeClusters,
+    #[error("too many files")]
+    TooManyFiles,
+    #[error("no space left")]
+    NoSpaceLeft,
+    #[error("directory not empty")]
+    DirectoryNotEmpty,
+    #[error("can't move a directory into itself")]
+    MoveIntoItself,
+    #[error("read-only device")]
+    ReadOnly,
+    #[error("module needs {0} bytes, more than there is room for")]
+    ModuleTooLarge(usize),
+    #[error("too many sections: {0}")]
+    TooManySections(usize),
+    #[error("relocated value {0:#x} doesn't fit the relocation")]
+    RelocationOverflow(u64),
+    #[error("string index {0} outside of the string table")]
+    StringIndexOutOfBounds(usize),
+    #[error("relocation target {0:#x} outside of the kernel image")]
+    RelocationOutOfBounds(u64),
     #[error("too many sectors: {0}")]
     TooManySectors(u32),
     #[error("hanging ATA device")]
     HangingAtaDevice,
     #[error("ATA device not ready for commands")]
     AtaDeviceNotReady,
+    #[error("no ATA device attached")]
+    NoAtaDevice,
+    #[error("ATA device fault")]
+    AtaDriveFault,
+    #[error("ATA command aborted")]
+    AtaCommandAborted,
+    #[error("bad block")]
+    AtaBadBlock,
+    #[error("uncorrectable data error")]
+    AtaUncorrectableData,
+    #[error("sector ID not found")]
+    AtaIdNotFound,
+    #[error("address mark not found")]
+    AtaAddressMarkNotFound,
+    #[error("track zero not found")]
+    AtaTrackZeroNotFound,
+    #[error("media changed")]
+    AtaMediaChanged,
+    #[error("ATAPI command failed with sense key {0:#x}")]
+    AtapiSenseKey(u8),
+    #[error("unexpected SMART status {0:#x?}")]
+    InvalidSmartStatus((u8, u8)),
+    #[error("unexpected response {0:#x} from a PS/2 device")]
+    UnexpectedPs2Response(u8),
+    #[error("no supported network card found")]
+    NoNetworkCard,
+    #[error("network card not responding")]
+    HangingNetworkCard,
+    #[error("frame too large: {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("virtio device rejected the driver's features")]
+    VirtioFeaturesRejected,
+    #[error("unsupported virtqueue size {0}")]
+    UnsupportedQueueSize(u16),
+    #[error("invalid checksum (sums to {0:#x})")]
+    InvalidChecksum(u16),
+    #[error("no route to {0:?}")]
+    NoRouteTo([u8; 4]),
+    #[error("UDP port {0} already bound")]
+    PortInUse(u16),
+    #[error("no free UDP socket")]
+    NoFreeSocket,
     #[error("kernel entrypoint above addressable memory for 32-bit")]
     KernelEntrypointAbove4G,
     #[error("kernel entrypoint too high for a 1MB stack")]
     KernelEntrypointTooHigh,
+    #[error("invalid handoff to the kernel: {0}")]
+    InvalidHandoff(&'static str),
     #[error("kernel initialization fault")]
     KernelInitialization,
     #[error("invalid drive parameters pointer: {0:#p}")]
     InvalidDriveParametersPointer(*const u8),
     #[error("invalid stack start: {0:#x}")]
     InvalidStackStart(u32),
+    #[error("overlaps {0}")]
+    OverlapsReservedMemory(&'static str),
+    #[error("no kernel in the payload table")]
+    NoKernelPayload,
+    #[error("too many payloads: {0}")]
+    TooManyPayloads(usize),
     #[error("couldn't identify boot device")]
     FailedBootDeviceIdentification,
+    #[error("BIOS call int {vector:#x} failed with status {status:#x}")]
+    BiosCallFailed { vector: u8, status: u8 },
 }
 
 #[derive(Debug, Error, Clone, Copy)]
 pub enum Feature {
     #[error("1GB pages")]
     _1GBPages,
+    #[error("page attribute table")]
+    PageAttributeTable,
+    #[error("SSE2 with FXSAVE/FXRSTOR")]
+    Sse,
 }
 
 #[derive(Clone, Copy, Debug, Error)]
@@ -178,14 +348,77 @@ pub enum Facility {
     ElfSectionHeaderEntry(u16),
     #[error("ELF program header entry {0}")]
     ElfProgramHeaderEntry(u16),
+    #[error("ELF relocation table")]
+    ElfRelocationTable,
+    #[error("ELF relocation entry {0}")]
+    ElfRelocationEntry(u16),
+    #[error("ELF string table")]
+    ElfStringTable,
+    #[error("ELF dynamic section")]
+    ElfDynamicSection,
+    #[error("ELF dynamic entry {0}")]
+    ElfDynamicEntry(u16),
+    #[error("ELF symbol table")]
+    ElfSymbolTable,
+    #[error("ELF symbol {0}")]
+    ElfSymbol(u32),
 
     // Ata
     #[error("Ata Device (base io port: {0:#x})")]
     AtaDevice(u16),
+    #[error("SMART data")]
+    SmartData,
+    #[error("RAM disk")]
+    RamDisk,
+    #[error("GUID partition table")]
+    Gpt,
+    #[error("FAT32 file system")]
+    Fat32,
+    #[error("tar archive")]
+    TarArchive,
+    #[error("cpio archive")]
+    CpioArchive,
+
+    // PS/2
+    #[error("PS/2 controller")]
+    Ps2Controller,
+    #[error("PS/2 mouse")]
+    Ps2Mouse,
+
+    #[error("payload table")]
+    PayloadTable,
+    #[error("kernel symbol table")]
+    SymbolTable,
+
+    // Modules
+    #[error("kernel module")]
+    KernelModule,
+    #[error("user program")]
+    UserProgram,
+
+    // Network
+    #[error("e1000 network card")]
+    E1000,
+    #[error("virtio network device")]
+    VirtioNet,
+    #[error("Ethernet frame")]
+    EthernetFrame,
+    #[error("ARP packet")]
+    ArpPacket,
+    #[error("IPv4 packet")]
+    Ipv4Packet,
+    #[error("ICMP message")]
+    IcmpMessage,
+    #[error("UDP datagram")]
+    UdpDatagram,
+    #[error("DHCP message")]
+    DhcpMessage,
 
     // Bootloader
     #[error("Bootloader")]
     Bootloader,
+    #[error("BIOS")]
+    Bios,
 }
 
 #[derive(Clone, Copy, Debug, Error)]
@@ -204,7 +437,21 @@ pub struct ErrorChain<const N: usize> {
 }
 
 impl<const N: usize> ErrorChain<N> {
-    fn push(&mut self, error: Error) {
+    pub const fn new() -> Self {
+        Self {
+            errors: [Error::blank(); N],
+            length: 0,
+            theres_more: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Adds `error`, caused by the ones pushed before it, unless the chain is full, in which case
+    /// it only records that there's more
+    pub fn push(&mut self, error: Error) {
         if self.length == N {
             self.theres_more = true;
             return;
@@ -219,6 +466,12 @@ impl<const N: usize> ErrorChain<N> {
     }
 }
 
+impl<const N: usize> Default for ErrorChain<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<const N: usize> core::fmt::Display for ErrorChain<N> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         enum Iter<'a> {
@@ -260,11 +513,7 @@ impl<const N: usize> core::fmt::Display for ErrorChain<N> {
 }
 
 static MAX_ERROR_CHAIN_LENGTH: usize = 5;
-static mut GLOBAL_ERROR_CHAIN: ErrorChain<MAX_ERROR_CHAIN_LENGTH> = ErrorChain {
-    errors: [Error::blank(); MAX_ERROR_CHAIN_LENGTH],
-    length: 0,
-    theres_more: false,
-};
+static mut GLOBAL_ERROR_CHAIN: ErrorChain<MAX_ERROR_CHAIN_LENGTH> = ErrorChain::new();
 
 pub fn get_global_error_chain_no_sync() -> &'static ErrorChain<MAX_ERROR_CHAIN_LENGTH> {
     let error_chain_ptr = &raw const GLOBAL_ERROR_CHAIN;
@@ -287,3 +536,24 @@ pub fn clear_global_error_chain_no_sync() {
 
     error_chain.clear();
 }
+
+static MAX_BOOT_WARNINGS: usize = 8;
+// Errors that didn't stop the boot, kept apart from the chain so they aren't taken for the cause of
+// a later one that does
+static mut BOOT_WARNINGS: RingBuffer<Error, MAX_BOOT_WARNINGS> = RingBuffer::new(Error::blank());
+
+pub fn get_boot_warnings_no_sync() -> &'static RingBuffer<Error, MAX_BOOT_WARNINGS> {
+    let warnings_ptr = &raw const BOOT_WARNINGS;
+    // SAFETY: no threads means no concurrent access
+    unsafe { &*warnings_ptr }
+}
+
+/// Records an error that isn't worth stopping the boot for, dropping the oldest one if there's no
+/// room left
+pub fn push_boot_warning_no_sync(error: Error) {
+    let warnings_ptr = &raw mut BOOT_WARNINGS;
+    // SAFETY: no threads means no concurrent access
+    let warnings = unsafe { &mut *warnings_ptr };
+
+    warnings.push_overwriting(error);
+}
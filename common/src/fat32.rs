@@ -0,0 +1,1164 @@
+// FAT32 on a block device, enough to find, read, create and grow files. It's only the driver for
+// now: the boot image has no FAT32 partition yet, so nothing mounts a volume for the kernel to
+// keep logs or test artifacts on. Files live in chains of clusters the FAT links
+// together, and directories are files of 32-byte entries: a short 8.3 entry for each file, after
+// the long file name entries holding its full name, if it has one. Every copy of the FAT is kept
+// in sync, unless the volume asks for only the active one to be used. There's no clock to take the
+// time from yet, so timestamps are left zeroed. Only the data region decides how big the volume
+// is: smaller volumes than the specification calls FAT32 work too, as long as they say they are
+// https://academy.cba.mit.edu/classes/networking_communications/SD/FAT.pdf
+use zerocopy::TryFromBytes;
+
+use crate::{
+    block_device::BlockDevice,
+    error::{Context, Error, Facility, Fault, try_read_error},
+};
+
+const MAX_SECTOR_SIZE: usize = 4096;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const ENTRY_SIZE: usize = 32;
+// Only the low 28 bits of a FAT entry are the cluster number, the rest is left as it was
+const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+const FREE_CLUSTER: u32 = 0;
+// Any value from this one up ends a chain
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+const FIRST_CLUSTER: u32 = 2;
+// Set in the extended flags when only the active FAT, in the low bits, is to be written
+const NO_MIRRORING: u16 = 1 << 7;
+const ACTIVE_FAT_MASK: u16 = 0xF;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_FREE_COUNT_OFFSET: usize = 488;
+const FS_INFO_NEXT_FREE_OFFSET: usize = 492;
+const UNKNOWN: u32 = 0xFFFF_FFFF;
+
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+const END_OF_DIRECTORY: u8 = 0x00;
+const DELETED_ENTRY: u8 = 0xE5;
+// Set in the order of the long name entry that comes first, which holds the end of the name
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_ENTRY_ORDER_MASK: u8 = 0x1F;
+const LONG_NAME_CHARACTERS: usize = 13;
+// Where the characters of a long name entry are, two bytes each
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARACTERS] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LONG_ENTRY_CHECKSUM_OFFSET: usize = 13;
+pub const MAX_NAME_LENGTH: usize = 255;
+const SHORT_NAME_LENGTH: usize = 11;
+// Characters short names can have besides letters and digits
+const SHORT_NAME_SPECIAL_CHARACTERS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+// Fields of the short entries
+const ATTRIBUTES_OFFSET: usize = 11;
+const FIRST_CLUSTER_HIGH_OFFSET: usize = 20;
+const FIRST_CLUSTER_LOW_OFFSET: usize = 26;
+const FILE_SIZE_OFFSET: usize = 28;
+
+mod inner {
+    use zerocopy::{FromBytes, Immutable, KnownLayout, LE, U16, U32, Unaligned};
+
+    #[derive(FromBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct BootSector {
+        pub(super) jump: [u8; 3],
+        pub(super) oem_name: [u8; 8],
+        pub(super) bytes_per_sector: U16<LE>,
+        pub(super) sectors_per_cluster: u8,
+        pub(super) reserved_sectors: U16<LE>,
+        pub(super) fats: u8,
+        pub(super) root_entries: U16<LE>,
+        pub(super) total_sectors_16: U16<LE>,
+        pub(super) media: u8,
+        pub(super) fat_size_16: U16<LE>,
+        pub(super) sectors_per_track: U16<LE>,
+        pub(super) heads: U16<LE>,
+        pub(super) hidden_sectors: U32<LE>,
+        pub(super) total_sectors_32: U32<LE>,
+        pub(super) fat_size_32: U32<LE>,
+        pub(super) extended_flags: U16<LE>,
+        pub(super) version: U16<LE>,
+        pub(super) root_cluster: U32<LE>,
+        pub(super) fs_info_sector: U16<LE>,
+        pub(super) backup_boot_sector: U16<LE>,
+    }
+}
+
+fn io_error(fault: Fault) -> Error {
+    Error::new(fault, Context::Io, Facility::Fat32)
+}
+
+fn parsing_error(fault: Fault) -> Error {
+    Error::parsing_error(fault, Facility::Fat32)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// The checksum of a short name that its long name entries carry, so that entries left behind by
+/// software that doesn't know about long names can be told apart
+fn short_name_checksum(short_name: &[u8]) -> u8 {
+    short_name.iter().fold(0u8, |checksum, &byte| {
+        checksum.rotate_right(1).wrapping_add(byte)
+    })
+}
+
+fn is_short_name_character(byte: u8) -> bool {
+    byte.is_ascii_uppercase()
+        || byte.is_ascii_digit()
+        || SHORT_NAME_SPECIAL_CHARACTERS.contains(&byte)
+}
+
+/// `name` as a short name, if it's a valid 8.3 name as it is, in upper case
+fn exact_short_name(name: &str) -> Option<[u8; SHORT_NAME_LENGTH]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || extension.len() > 3
+        || !base
+            .bytes()
+            .chain(extension.bytes())
+            .all(is_short_name_character)
+    {
+        return None;
+    }
+    let mut short_name = [b' '; SHORT_NAME_LENGTH];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short_name)
+}
+
+/// `part` of a long name with what can't be in a short name left out or replaced
+fn short_name_characters(part: &str) -> impl Iterator<Item = u8> + '_ {
+    part.bytes()
+        .filter(|&byte| byte != b' ' && byte != b'.')
+        .map(|byte| {
+            let byte = byte.to_ascii_uppercase();
+            if is_short_name_character(byte) {
+                byte
+            } else {
+                b'_'
+            }
+        })
+}
+
+/// The short name Windows would give a file with long name `name`, with a `~tail` numeric tail:
+/// up to six characters of the name, upper case, and three of the extension after the last dot
+fn numbered_short_name(name: &str, tail: u32) -> [u8; SHORT_NAME_LENGTH] {
+    // A leading dot doesn't start an extension
+    let (base, extension) = name
+        .rsplit_once('.')
+        .filter(|(base, _)| !base.is_empty())
+        .unwrap_or((name, ""));
+    let mut short_name = [b' '; SHORT_NAME_LENGTH];
+    let mut digits = [0u8; 10];
+    let mut digits_length = 0;
+    let mut remaining = tail;
+    loop {
+        digits[digits_length] = b'0' + (remaining % 10) as u8;
+        digits_length += 1;
+        remaining /= 10;
+        if remaining == 0 {
+            break;
+        }
+    }
+    let base_length = 8 - 1 - digits_length;
+    let mut length = 0;
+    for byte in short_name_characters(base).take(base_length) {
+        short_name[length] = byte;
+        length += 1;
+    }
+    short_name[length] = b'~';
+    for (slot, digit) in short_name[length + 1..]
+        .iter_mut()
+        .zip(digits[..digits_length].iter().rev())
+    {
+        *slot = *digit;
+    }
+    for (slot, byte) in short_name[8..]
+        .iter_mut()
+        .zip(short_name_characters(extension))
+    {
+        *slot = byte;
+    }
+    short_name
+}
+
+/// Whether the 8.3 name `short_name` is written `name`, ignoring case
+fn short_name_matches(short_name: &[u8], name: &str) -> bool {
+    let base = short_name[..8].trim_ascii_end();
+    let extension = short_name[8..SHORT_NAME_LENGTH].trim_ascii_end();
+    let (name_base, name_extension) = name.split_once('.').unwrap_or((name, ""));
+    base.eq_ignore_ascii_case(name_base.as_bytes())
+        && extension.eq_ignore_ascii_case(name_extension.as_bytes())
+}
+
+/// Whether the UTF-16 `long_name` is `name`, ignoring the case of ASCII letters
+fn long_name_matches(long_name: &[u16], name: &str) -> bool {
+    let mut characters = char::decode_utf16(long_name.iter().copied());
+    for expected in name.chars() {
+        match characters.next() {
+            Some(Ok(character)) if character.eq_ignore_ascii_case(&expected) => {}
+            _ => return false,
+        }
+    }
+    characters.next().is_none()
+}
+
+/// Where a directory entry is: the cluster of the directory it's in, and its index in there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EntryLocation {
+    cluster: u32,
+    index: usize,
+}
+
+/// A file or directory that was looked up or created
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    entry: Option<EntryLocation>,
+    first_cluster: u32,
+    size: u32,
+    directory: bool,
+}
+
+impl File {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.directory
+    }
+}
+
+/// A long name being put together from its entries, which come last part first
+struct LongName {
+    units: [u16; MAX_NAME_LENGTH + LONG_NAME_CHARACTERS],
+    checksum: u8,
+    // The order the next entry must have, 0 once the name is complete
+    next_order: u8,
+    valid: bool,
+}
+
+impl LongName {
+    fn new() -> Self {
+        Self {
+            units: [0; MAX_NAME_LENGTH + LONG_NAME_CHARACTERS],
+            checksum: 0,
+            next_order: 0,
+            valid: false,
+        }
+    }
+
+    fn add_entry(&mut self, entry: &[u8]) {
+        let order = entry[0] & LONG_ENTRY_ORDER_MASK;
+        if entry[0] & LAST_LONG_ENTRY != 0 {
+            self.units.fill(0);
+            self.checksum = entry[LONG_ENTRY_CHECKSUM_OFFSET];
+            self.valid = true;
+        } else if !self.valid
+            || order != self.next_order
+            || entry[LONG_ENTRY_CHECKSUM_OFFSET] != self.checksum
+        {
+            self.valid = false;
+            return;
+        }
+        let Some(start) = (order as usize)
+            .checked_sub(1)
+            .map(|index| index * LONG_NAME_CHARACTERS)
+        else {
+            self.valid = false;
+            return;
+        };
+        let Some(units) = self.units.get_mut(start..start + LONG_NAME_CHARACTERS) else {
+            self.valid = false;
+            return;
+        };
+        for (unit, offset) in units.iter_mut().zip(LONG_NAME_OFFSETS) {
+            *unit = read_u16(entry, offset);
+        }
+        self.next_order = order - 1;
+    }
+
+    /// The name, if the entries before `short_name` make a whole one that belongs to it
+    fn for_short_name(&self, short_name: &[u8]) -> Option<&[u16]> {
+        if !self.valid || self.next_order != 0 || short_name_checksum(short_name) != self.checksum {
+            return None;
+        }
+        let length = self
+            .units
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(self.units.len());
+        Some(&self.units[..length])
+    }
+}
+
+/// A mounted FAT32 volume
+pub struct Fat32<D: BlockDevice> {
+    device: D,
+    bytes_per_sector: usize,
+    sectors_per_cluster: u64,
+    fat_start_lba: u64,
+    fat_sectors: u64,
+    // Which FATs are written, all of them unless mirroring is off
+    written_fats: core::ops::Range<u64>,
+    data_start_lba: u64,
+    clusters: u32,
+    root_cluster: u32,
+    fs_info_lba: Option<u64>,
+    // Where to start looking for a free cluster
+    next_free: u32,
+}
+
+impl<D: BlockDevice> Fat32<D> {
+    /// Mounts the volume that starts at the first sector of `device`
+    pub fn mount(mut device: D) -> Result<Self, Error> {
+        let sector_size = device.sector_size_bytes() as usize;
+        if !(512..=MAX_SECTOR_SIZE).contains(&sector_size) {
+            return Err(parsing_error(Fault::InvalidValueForField("sector size")));
+        }
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        let sector = &mut sector[..sector_size];
+        device.read_sectors(0, sector)?;
+        if sector[BOOT_SIGNATURE_OFFSET..BOOT_SIGNATURE_OFFSET + 2] != BOOT_SIGNATURE {
+            return Err(parsing_error(Fault::InvalidValueForField("signature")));
+        }
+        let (boot_sector, _) = inner::BootSector::try_read_from_prefix(sector)
+            .map_err(|err| try_read_error(Facility::Fat32, err))?;
+
+        if boot_sector.bytes_per_sector.get() as usize != sector_size {
+            return Err(parsing_error(Fault::InvalidValueForField(
+                "bytes_per_sector",
+            )));
+        }
+        let sectors_per_cluster = boot_sector.sectors_per_cluster as u64;
+        if !sectors_per_cluster.is_power_of_two() {
+            return Err(parsing_error(Fault::InvalidValueForField(
+                "sectors_per_cluster",
+            )));
+        }
+        // What FAT12 and FAT16 have and FAT32 doesn't
+        if boot_sector.root_entries.get() != 0 || boot_sector.fat_size_16.get() != 0 {
+            return Err(parsing_error(Fault::InvalidValueForField("root_entries")));
+        }
+        let fats = boot_sector.fats as u64;
+        let fat_sectors = boot_sector.fat_size_32.get() as u64;
+        let fat_start_lba = boot_sector.reserved_sectors.get() as u64;
+        let data_start_lba = fat_start_lba + fats * fat_sectors;
+        let total_sectors = boot_sector.total_sectors_32.get() as u64;
+        if fats == 0 || total_sectors > device.sectors() || data_start_lba >= total_sectors {
+            return Err(parsing_error(Fault::InvalidValueForField(
+                "total_sectors_32",
+            )));
+        }
+        // As many as there's room for both in the data region and in the FAT
+        let fat_entries = fat_sectors * sector_size as u64 / size_of::<u32>() as u64;
+        let clusters = ((total_sectors - data_start_lba) / sectors_per_cluster)
+            .min(fat_entries.saturating_sub(FIRST_CLUSTER as u64)) as u32;
+        let root_cluster = boot_sector.root_cluster.get();
+        if !(FIRST_CLUSTER..FIRST_CLUSTER + clusters).contains(&root_cluster) {
+            return Err(parsing_error(Fault::InvalidValueForField("root_cluster")));
+        }
+        let extended_flags = boot_sector.extended_flags.get();
+        let written_fats = if extended_flags & NO_MIRRORING != 0 {
+            let active = (extended_flags & ACTIVE_FAT_MASK) as u64;
+            if active >= fats {
+                return Err(parsing_error(Fault::InvalidValueForField("extended_flags")));
+            }
+            active..active + 1
+        } else {
+            0..fats
+        };
+        let fs_info_sector = boot_sector.fs_info_sector.get() as u64;
+
+        let mut fat32 = Self {
+            device,
+            bytes_per_sector: sector_size,
+            sectors_per_cluster,
+            fat_start_lba,
+            fat_sectors,
+            written_fats,
+            data_start_lba,
+            clusters,
+            root_cluster,
+            fs_info_lba: None,
+            next_free: FIRST_CLUSTER,
+        };
+        // The FS information sector is only a hint, a volume without a valid one still works
+        if fs_info_sector != 0 && fs_info_sector < fat_start_lba {
+            fat32.device.read_sectors(fs_info_sector, sector)?;
+            if read_u32(sector, 0) == FS_INFO_LEAD_SIGNATURE
+                && read_u32(sector, 484) == FS_INFO_STRUCT_SIGNATURE
+            {
+                fat32.fs_info_lba = Some(fs_info_sector);
+                let next_free = read_u32(sector, FS_INFO_NEXT_FREE_OFFSET);
+                if fat32.is_valid_cluster(next_free) {
+                    fat32.next_free = next_free;
+                }
+            }
+        }
+        Ok(fat32)
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    pub fn cluster_size_bytes(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster as usize
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.clusters).contains(&cluster)
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.data_start_lba + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster
+    }
+
+    /// The sector of the first FAT with the entry of `cluster`, and where the entry is in it
+    fn fat_entry_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = cluster as usize * size_of::<u32>();
+        (
+            self.fat_start_lba + (offset / self.bytes_per_sector) as u64,
+            offset % self.bytes_per_sector,
+        )
+    }
+
+    fn read_sector(&mut self, lba: u64, sector: &mut [u8; MAX_SECTOR_SIZE]) -> Result<(), Error> {
+        self.device
+            .read_sectors(lba, &mut sector[..self.bytes_per_sector])
+    }
+
+    fn write_sector(&mut self, lba: u64, sector: &[u8; MAX_SECTOR_SIZE]) -> Result<(), Error> {
+        self.device
+            .write_sectors(lba, &sector[..self.bytes_per_sector])
+    }
+
+    /// What the FAT has for `cluster`: the next cluster of its chain, or a free or end marker
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, Error> {
+        let (lba, offset) = self.fat_entry_position(cluster);
+        let active = self.written_fats.start;
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        self.read_sector(lba + active * self.fat_sectors, &mut sector)?;
+        Ok(read_u32(&sector, offset) & CLUSTER_MASK)
+    }
+
+    /// Sets the entry of `cluster` in every FAT that's written
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), Error> {
+        let (lba, offset) = self.fat_entry_position(cluster);
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        for fat in self.written_fats.clone() {
+            let lba = lba + fat * self.fat_sectors;
+            self.read_sector(lba, &mut sector)?;
+            let entry = read_u32(&sector, offset) & !CLUSTER_MASK | value & CLUSTER_MASK;
+            sector[offset..offset + size_of::<u32>()].copy_from_slice(&entry.to_le_bytes());
+            self.write_sector(lba, &sector)?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, None at the end of it
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, Error> {
+        let next = self.fat_entry(cluster)?;
+        if next >= END_OF_CHAIN {
+            return Ok(None);
+        }
+        if !self.is_valid_cluster(next) {
+            return Err(io_error(Fault::InvalidValueForField("FAT entry")));
+        }
+        Ok(Some(next))
+    }
+
+    /// Updates the free cluster count and the next free hint of the FS information sector
+    fn update_fs_info(&mut self, allocated: i64) -> Result<(), Error> {
+        let Some(lba) = self.fs_info_lba else {
+            return Ok(());
+        };
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        self.read_sector(lba, &mut sector)?;
+        let free = read_u32(&sector, FS_INFO_FREE_COUNT_OFFSET);
+        if free != UNKNOWN {
+            let free = (free as i64 - allocated).clamp(0, self.clusters as i64) as u32;
+            sector[FS_INFO_FREE_COUNT_OFFSET..FS_INFO_FREE_COUNT_OFFSET + 4]
+                .copy_from_slice(&free.to_le_bytes());
+        }
+        sector[FS_INFO_NEXT_FREE_OFFSET..FS_INFO_NEXT_FREE_OFFSET + 4]
+            .copy_from_slice(&self.next_free.to_le_bytes());
+        self.write_sector(lba, &sector)
+    }
+
+    /// Takes a free cluster, zeroes it and links it after `previous`, if it's given, as the new
+    /// end of its chain
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, Error> {
+        let mut cluster = None;
+        for index in 0..self.clusters {
+            let candidate =
+                FIRST_CLUSTER + (self.next_free - FIRST_CLUSTER + index) % self.clusters;
+            if self.fat_entry(candidate)? == FREE_CLUSTER {
+                cluster = Some(candidate);
+                break;
+            }
+        }
+        let Some(cluster) = cluster else {
+            return Err(io_error(Fault::NoFreeClusters));
+        };
+
+        let sector = [0u8; MAX_SECTOR_SIZE];
+        let lba = self.cluster_lba(cluster);
+        for index in 0..self.sectors_per_cluster {
+            self.write_sector(lba + index, &sector)?;
+        }
+        self.set_fat_entry(cluster, CLUSTER_MASK)?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        self.next_free = FIRST_CLUSTER + (cluster + 1 - FIRST_CLUSTER) % self.clusters;
+        self.update_fs_info(1)?;
+        Ok(cluster)
+    }
+
+    /// Frees the chain starting at `cluster`
+    fn free_chain(&mut self, mut cluster: u32) -> Result<(), Error> {
+        let mut freed = 0;
+        loop {
+            let next = self.next_cluster(cluster)?;
+            self.set_fat_entry(cluster, FREE_CLUSTER)?;
+            freed += 1;
+            match next {
+                Some(next) => cluster = next,
+                None => break,
+            }
+        }
+        self.update_fs_info(-freed)
+    }
+
+    fn entries_per_cluster(&self) -> usize {
+        self.cluster_size_bytes() / ENTRY_SIZE
+    }
+
+    fn read_entry(&mut self, location: EntryLocation) -> Result<[u8; ENTRY_SIZE], Error> {
+        let offset = location.index * ENTRY_SIZE;
+        let lba = self.cluster_lba(location.cluster) + (offset / self.bytes_per_sector) as u64;
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        self.read_sector(lba, &mut sector)?;
+        let offset = offset % self.bytes_per_sector;
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry.copy_from_slice(&sector[offset..offset + ENTRY_SIZE]);
+        Ok(entry)
+    }
+
+    fn write_entry(
+        &mut self,
+        location: EntryLocation,
+        entry: &[u8; ENTRY_SIZE],
+    ) -> Result<(), Error> {
+        let offset = location.index * ENTRY_SIZE;
+        let lba = self.cluster_lba(location.cluster) + (offset / self.bytes_per_sector) as u64;
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        self.read_sector(lba, &mut sector)?;
+        let offset = offset % self.bytes_per_sector;
+        sector[offset..offset + ENTRY_SIZE].copy_from_slice(entry);
+        self.write_sector(lba, &sector)
+    }
+
+    /// The entry after `location`, None past the end of the directory's clusters
+    fn next_location(&mut self, location: EntryLocation) -> Result<Option<EntryLocation>, Error> {
+        if location.index + 1 < self.entries_per_cluster() {
+            return Ok(Some(EntryLocation {
+                index: location.index + 1,
+                ..location
+            }));
+        }
+        Ok(self
+            .next_cluster(location.cluster)?
+            .map(|cluster| EntryLocation { cluster, index: 0 }))
+    }
+
+    fn root(&self) -> File {
+        File {
+            entry: None,
+            first_cluster: self.root_cluster,
+            size: 0,
+            directory: true,
+        }
+    }
+
+    /// Looks `name` up in `directory`, by its long name or its short one
+    fn find_in(&mut self, directory: &File, name: &str) -> Result<Option<File>, Error> {
+        if !directory.directory {
+            return Err(io_error(Fault::NotADirectory));
+        }
+        let mut long_name = LongName::new();
+        let mut location = Some(EntryLocation {
+            cluster: directory.first_cluster,
+            index: 0,
+        });
+        while let Some(current) = location {
+            let entry = self.read_entry(current)?;
+            location = self.next_location(current)?;
+            match entry[0] {
+                END_OF_DIRECTORY => break,
+                DELETED_ENTRY => {
+                    long_name = LongName::new();
+                    continue;
+                }
+                _ => {}
+            }
+            let attributes = entry[ATTRIBUTES_OFFSET];
+            if attributes & ATTRIBUTE_LONG_NAME == ATTRIBUTE_LONG_NAME {
+                long_name.add_entry(&entry);
+                continue;
+            }
+            let short_name = &entry[..SHORT_NAME_LENGTH];
+            let matches = match long_name.for_short_name(short_name) {
+                Some(long_name) => {
+                    long_name_matches(long_name, name) || short_name_matches(short_name, name)
+                }
+                None => short_name_matches(short_name, name),
+            };
+            long_name = LongName::new();
+            if attributes & ATTRIBUTE_VOLUME_ID != 0 || !matches {
+                continue;
+            }
+            let first_cluster = (read_u16(&entry, FIRST_CLUSTER_HIGH_OFFSET) as u32) << 16
+                | read_u16(&entry, FIRST_CLUSTER_LOW_OFFSET) as u32;
+            let directory = attributes & ATTRIBUTE_DIRECTORY != 0;
+            // ".." has 0 for the root directory
+            let first_cluster = if directory && first_cluster == 0 {
+                self.root_cluster
+            } else {
+                first_cluster
+            };
+            let size = read_u32(&entry, FILE_SIZE_OFFSET);
+            // Only empty files have no cluster
+            let valid = if first_cluster == 0 {
+                size == 0
+            } else {
+                self.is_valid_cluster(first_cluster)
+            };
+            if !valid {
+                return Err(io_error(Fault::InvalidValueForField("first_cluster")));
+            }
+            return Ok(Some(File {
+                entry: Some(current),
+                first_cluster,
+                size,
+                directory,
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Looks up the file or directory at `path`, of names separated by slashes from the root
+    pub fn open(&mut self, path: &str) -> Result<File, Error> {
+        let mut file = self.root();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            file = self
+                .find_in(&file, name)?
+                .ok_or(io_error(Fault::FileNotFound))?;
+        }
+        Ok(file)
+    }
+
+    /// Reads what's in `file` from `offset` into `buffer`, as much as fits, returning how much
+    /// that was
+    pub fn read(&mut self, file: &File, offset: u32, buffer: &mut [u8]) -> Result<usize, Error> {
+        if file.directory {
+            return Err(io_error(Fault::NotAFile));
+        }
+        let length = buffer.len().min(file.size.saturating_sub(offset) as usize);
+        // At the end of the file there may be no cluster left to skip to
+        if length == 0 {
+            return Ok(0);
+        }
+        let cluster_size = self.cluster_size_bytes();
+        let mut cluster = file.first_cluster;
+        for _ in 0..offset as usize / cluster_size {
+            cluster = self
+                .next_cluster(cluster)?
+                .ok_or(io_error(Fault::IOError))?;
+        }
+        let mut position = offset as usize % cluster_size;
+        let mut read = 0;
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        while read < length {
+            if position == cluster_size {
+                cluster = self
+                    .next_cluster(cluster)?
+                    .ok_or(io_error(Fault::IOError))?;
+                position = 0;
+            }
+            let lba = self.cluster_lba(cluster) + (position / self.bytes_per_sector) as u64;
+            self.read_sector(lba, &mut sector)?;
+            let in_sector = position % self.bytes_per_sector;
+            let copied = (self.bytes_per_sector - in_sector).min(length - read);
+            buffer[read..read + copied].copy_from_slice(&sector[in_sector..in_sector + copied]);
+            read += copied;
+            position += copied;
+        }
+        Ok(read)
+    }
+
+    /// Creates an empty file called `name` in the directory at `directory_path`, with long name
+    /// entries unless `name` is a short name as it is
+    pub fn create(&mut self, directory_path: &str, name: &str) -> Result<File, Error> {
+        let directory = self.open(directory_path)?;
+        let characters = name.encode_utf16().count();
+        if name.is_empty()
+            || characters > MAX_NAME_LENGTH
+            || name == "."
+            || name == ".."
+            || name
+                .chars()
+                .any(|character| character.is_control() || "\"*/:<>?\\|".contains(character))
+        {
+            return Err(io_error(Fault::InvalidFileName));
+        }
+        if self.find_in(&directory, name)?.is_some() {
+            return Err(io_error(Fault::FileExists));
+        }
+
+        let (short_name, long_entries) = match exact_short_name(name) {
+            Some(short_name) => (short_name, 0),
+            None => {
+                let mut tail = 1;
+                let short_name = loop {
+                    let short_name = numbered_short_name(name, tail);
+                    let mut printed = [0u8; SHORT_NAME_LENGTH + 1];
+                    let base = short_name[..8].trim_ascii_end();
+                    let extension = short_name[8..].trim_ascii_end();
+                    printed[..base.len()].copy_from_slice(base);
+                    let mut length = base.len();
+                    if !extension.is_empty() {
+                        printed[length] = b'.';
+                        printed[length + 1..length + 1 + extension.len()]
+                            .copy_from_slice(extension);
+                        length += 1 + extension.len();
+                    }
+                    let printed = core::str::from_utf8(&printed[..length])
+                        .map_err(|_| io_error(Fault::InvalidFileName))?;
+                    if self.find_in(&directory, printed)?.is_none() {
+                        break short_name;
+                    }
+                    tail += 1;
+                };
+                (short_name, characters.div_ceil(LONG_NAME_CHARACTERS))
+            }
+        };
+
+        let first = self.free_entries(&directory, long_entries + 1)?;
+        let checksum = short_name_checksum(&short_name);
+        let mut units = [0xFFFFu16; MAX_NAME_LENGTH + LONG_NAME_CHARACTERS];
+        let mut length = 0;
+        for unit in name.encode_utf16() {
+            units[length] = unit;
+            length += 1;
+        }
+        // The name ends with a 0 unless it fills its last entry, and the rest is 0xFFFF
+        if length < units.len() {
+            units[length] = 0;
+        }
+        let mut location = first;
+        for order in (1..=long_entries).rev() {
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[0] = order as u8
+                | if order == long_entries {
+                    LAST_LONG_ENTRY
+                } else {
+                    0
+                };
+            entry[ATTRIBUTES_OFFSET] = ATTRIBUTE_LONG_NAME;
+            entry[LONG_ENTRY_CHECKSUM_OFFSET] = checksum;
+            let start = (order - 1) * LONG_NAME_CHARACTERS;
+            for (unit, offset) in units[start..start + LONG_NAME_CHARACTERS]
+                .iter()
+                .zip(LONG_NAME_OFFSETS)
+            {
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            self.write_entry(location, &entry)?;
+            location = self
+                .next_location(location)?
+                .ok_or(io_error(Fault::IOError))?;
+        }
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[..SHORT_NAME_LENGTH].copy_from_slice(&short_name);
+        entry[ATTRIBUTES_OFFSET] = ATTRIBUTE_ARCHIVE;
+        self.write_entry(location, &entry)?;
+        Ok(File {
+            entry: Some(location),
+            first_cluster: 0,
+            size: 0,
+            directory: false,
+        })
+    }
+
+    /// The first of `count` free entries in a row in `directory`, which gets more clusters if
+    /// it's too full
+    fn free_entries(&mut self, directory: &File, count: usize) -> Result<EntryLocation, Error> {
+        let mut location = EntryLocation {
+            cluster: directory.first_cluster,
+            index: 0,
+        };
+        let mut run: Option<(EntryLocation, usize)> = None;
+        loop {
+            let entry = self.read_entry(location)?;
+            if entry[0] == END_OF_DIRECTORY || entry[0] == DELETED_ENTRY {
+                let (start, length) = run.unwrap_or((location, 0));
+                if length + 1 == count {
+                    return Ok(start);
+                }
+                run = Some((start, length + 1));
+            } else {
+                run = None;
+            }
+            location = match self.next_location(location)? {
+                Some(next) => next,
+                None => EntryLocation {
+                    cluster: self.allocate_cluster(Some(location.cluster))?,
+                    index: 0,
+                },
+            };
+        }
+    }
+
+    /// Writes the size and first cluster of `file` to its directory entry
+    fn update_entry(&mut self, file: &File) -> Result<(), Error> {
+        let Some(location) = file.entry else {
+            return Err(io_error(Fault::NotAFile));
+        };
+        let mut entry = self.read_entry(location)?;
+        entry[FIRST_CLUSTER_HIGH_OFFSET..FIRST_CLUSTER_HIGH_OFFSET + 2]
+            .copy_from_slice(&((file.first_cluster >> 16) as u16).to_le_bytes());
+        entry[FIRST_CLUSTER_LOW_OFFSET..FIRST_CLUSTER_LOW_OFFSET + 2]
+            .copy_from_slice(&(file.first_cluster as u16).to_le_bytes());
+        entry[FILE_SIZE_OFFSET..FILE_SIZE_OFFSET + 4].copy_from_slice(&file.size.to_le_bytes());
+        self.write_entry(location, &entry)
+    }
+
+    /// Adds `bytes` to the end of `file`, allocating clusters as it grows
+    pub fn append(&mut self, file: &mut File, bytes: &[u8]) -> Result<(), Error> {
+        if file.directory || file.entry.is_none() {
+            return Err(io_error(Fault::NotAFile));
+        }
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let new_size = u32::try_from(bytes.len())
+            .ok()
+            .and_then(|length| file.size.checked_add(length))
+            .ok_or(io_error(Fault::InvalidValueForField("size")))?;
+        let cluster_size = self.cluster_size_bytes();
+
+        // Where the file ends: the last cluster and how much of it is used
+        let (mut cluster, mut position) = if file.first_cluster == 0 {
+            file.first_cluster = self.allocate_cluster(None)?;
+            (file.first_cluster, 0)
+        } else {
+            let mut cluster = file.first_cluster;
+            while let Some(next) = self.next_cluster(cluster)? {
+                cluster = next;
+            }
+            let used = file.size as usize % cluster_size;
+            let full = file.size != 0 && used == 0;
+            (cluster, if full { cluster_size } else { used })
+        };
+
+        let mut written = 0;
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        while written < bytes.len() {
+            if position == cluster_size {
+                cluster = self.allocate_cluster(Some(cluster))?;
+                position = 0;
+            }
+            let lba = self.cluster_lba(cluster) + (position / self.bytes_per_sector) as u64;
+            let in_sector = position % self.bytes_per_sector;
+            let copied = (self.bytes_per_sector - in_sector).min(bytes.len() - written);
+            if in_sector != 0 || copied != self.bytes_per_sector {
+                self.read_sector(lba, &mut sector)?;
+            }
+            sector[in_sector..in_sector + copied]
+                .copy_from_slice(&bytes[written..written + copied]);
+            self.write_sector(lba, &sector)?;
+            written += copied;
+            position += copied;
+        }
+        file.size = new_size;
+        self.update_entry(file)
+    }
+
+    /// Shortens `file` to `size` bytes, freeing the clusters it doesn't need anymore
+    pub fn truncate(&mut self, file: &mut File, size: u32) -> Result<(), Error> {
+        if file.directory || file.entry.is_none() {
+            return Err(io_error(Fault::NotAFile));
+        }
+        if size > file.size {
+            return Err(io_error(Fault::InvalidValueForField("size")));
+        }
+        let kept_clusters = (size as usize).div_ceil(self.cluster_size_bytes());
+        if kept_clusters == 0 {
+            if file.first_cluster != 0 {
+                self.free_chain(file.first_cluster)?;
+            }
+            file.first_cluster = 0;
+        } else {
+            let mut last = file.first_cluster;
+            for _ in 1..kept_clusters {
+                last = self.next_cluster(last)?.ok_or(io_error(Fault::IOError))?;
+            }
+            if let Some(next) = self.next_cluster(last)? {
+                self.set_fat_entry(last, CLUSTER_MASK)?;
+                self.free_chain(next)?;
+            }
+        }
+        file.size = size;
+        self.update_entry(file)
+    }
+
+    /// How many clusters are free, counted from the FAT
+    pub fn free_clusters(&mut self) -> Result<u32, Error> {
+        let mut free = 0;
+        for cluster in FIRST_CLUSTER..FIRST_CLUSTER + self.clusters {
+            if self.fat_entry(cluster)? == FREE_CLUSTER {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block_device::BlockDevice,
+        error::Fault,
+        fat32::{Fat32, numbered_short_name},
+        ram_disk::RamDisk,
+    };
+
+    const SECTOR: usize = 512;
+    const RESERVED_SECTORS: usize = 32;
+    const FAT_SECTORS: usize = 2;
+    const DATA_SECTORS: usize = 200;
+    const TOTAL_SECTORS: usize = RESERVED_SECTORS + 2 * FAT_SECTORS + DATA_SECTORS;
+    const FS_INFO_SECTOR: usize = 1;
+
+    /// A volume with two FATs and one sector per cluster, with an empty root directory in
+    /// cluster 2
+    fn format() -> [u8; TOTAL_SECTORS * SECTOR] {
+        let mut disk = [0u8; TOTAL_SECTORS * SECTOR];
+        let boot_sector = &mut disk[..SECTOR];
+        boot_sector[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+        boot_sector[13] = 1;
+        boot_sector[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        boot_sector[16] = 2;
+        boot_sector[32..36].copy_from_slice(&(TOTAL_SECTORS as u32).to_le_bytes());
+        boot_sector[36..40].copy_from_slice(&(FAT_SECTORS as u32).to_le_bytes());
+        boot_sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+        boot_sector[48..50].copy_from_slice(&(FS_INFO_SECTOR as u16).to_le_bytes());
+        boot_sector[510..].copy_from_slice(&[0x55, 0xAA]);
+
+        let fs_info = &mut disk[FS_INFO_SECTOR * SECTOR..][..SECTOR];
+        fs_info[..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        fs_info[488..492].copy_from_slice(&(DATA_SECTORS as u32 - 1).to_le_bytes());
+        fs_info[492..496].copy_from_slice(&3u32.to_le_bytes());
+
+        for fat in 0..2 {
+            let fat = &mut disk[(RESERVED_SECTORS + fat * FAT_SECTORS) * SECTOR..];
+            fat[..4].copy_from_slice(&0x0FFF_FFF8u32.to_le_bytes());
+            fat[4..8].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+            // The root directory
+            fat[8..12].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+        }
+        disk
+    }
+
+    fn fats_match(disk: &mut RamDisk) -> bool {
+        let (mut first, mut second) = ([0u8; SECTOR], [0u8; SECTOR]);
+        (0..FAT_SECTORS as u64).all(|sector| {
+            let lba = RESERVED_SECTORS as u64 + sector;
+            disk.read_sectors(lba, &mut first).is_ok()
+                && disk
+                    .read_sectors(lba + FAT_SECTORS as u64, &mut second)
+                    .is_ok()
+                && first == second
+        })
+    }
+
+    #[test]
+    fn creates_and_appends() {
+        let mut bytes = format();
+        let mut fat32 = Fat32::mount(RamDisk::new(&mut bytes)).unwrap();
+        // The root directory has one of the clusters
+        assert_eq!(Some(199), fat32.free_clusters().ok());
+
+        let mut log = fat32.create("/", "kernel log.txt").unwrap();
+        let line = [b'x'; 300];
+        for _ in 0..4 {
+            fat32.append(&mut log, &line).unwrap();
+        }
+        fat32.append(&mut log, b"end").unwrap();
+        assert_eq!(1203, log.size());
+        assert_eq!(Some(196), fat32.free_clusters().ok());
+        assert!(fats_match(fat32.device()));
+
+        // Found again by either name, ignoring case
+        let log = fat32.open("/KERNEL LOG.TXT").unwrap();
+        assert_eq!(1203, log.size());
+        let short = fat32.open("kernel~1.txt").unwrap();
+        assert_eq!(1203, short.size());
+        let mut buffer = [0u8; 8];
+        assert_eq!(Some(8), fat32.read(&log, 1195, &mut buffer).ok());
+        assert_eq!(b"xxxxxend", &buffer);
+
+        assert!(matches!(
+            fat32
+                .create("/", "Kernel Log.txt")
+                .map_err(|err| err.fault()),
+            Err(Fault::FileExists)
+        ));
+        let other = fat32.create("/", "kernel log.txt.old").unwrap();
+        assert_eq!(0, other.size());
+        // Another extension, so the same tail
+        assert!(fat32.open("/kernel~1.old").is_ok());
+        let short = fat32.create("/", "README.TXT").unwrap();
+        assert_eq!(0, short.size());
+        assert!(fat32.open("readme.txt").is_ok());
+    }
+
+    #[test]
+    fn truncates() {
+        let mut bytes = format();
+        let mut fat32 = Fat32::mount(RamDisk::new(&mut bytes)).unwrap();
+        let mut file = fat32.create("/", "data").unwrap();
+        fat32.append(&mut file, &[1; 1500]).unwrap();
+        assert_eq!(Some(196), fat32.free_clusters().ok());
+        fat32.truncate(&mut file, 600).unwrap();
+        assert_eq!(Some(197), fat32.free_clusters().ok());
+        fat32.append(&mut file, &[2; 10]).unwrap();
+        let mut buffer = [0u8; 4];
+        assert_eq!(Some(4), fat32.read(&file, 598, &mut buffer).ok());
+        assert_eq!([1, 1, 2, 2], buffer);
+        fat32.truncate(&mut file, 0).unwrap();
+        assert_eq!(Some(199), fat32.free_clusters().ok());
+        assert!(fats_match(fat32.device()));
+
+        // The FS information sector keeps count
+        let mut fs_info = [0u8; SECTOR];
+        fat32.device().read_sectors(1, &mut fs_info).unwrap();
+        assert_eq!((DATA_SECTORS as u32 - 1).to_le_bytes(), fs_info[488..492]);
+    }
+
+    #[test]
+    fn directory_grows() {
+        let mut bytes = format();
+        let mut fat32 = Fat32::mount(RamDisk::new(&mut bytes)).unwrap();
+        // Two entries each, so the 16 entries of the root directory's cluster run out
+        for index in 0..12u8 {
+            let name = [b'f', b'i', b'l', b'e', b'-', b'a' + index];
+            let name = core::str::from_utf8(&name).unwrap();
+            fat32.create("/", name).unwrap();
+        }
+        assert!(fat32.open("file-l").is_ok());
+        assert_eq!(Some(198), fat32.free_clusters().ok());
+    }
+
+    #[test]
+    fn reads_at_the_end() {
+        let mut bytes = format();
+        let mut fat32 = Fat32::mount(RamDisk::new(&mut bytes)).unwrap();
+        let mut buffer = [0u8; 4];
+        // Two whole clusters
+        let mut file = fat32.create("/", "data").unwrap();
+        fat32.append(&mut file, &[1; 2 * SECTOR]).unwrap();
+        assert_eq!(Some(0), fat32.read(&file, file.size(), &mut buffer).ok());
+        assert_eq!(
+            Some(0),
+            fat32.read(&file, file.size() + 100, &mut buffer).ok()
+        );
+        // No cluster at all
+        let empty = fat32.create("/", "empty").unwrap();
+        assert_eq!(Some(0), fat32.read(&empty, 0, &mut buffer).ok());
+        assert_eq!(Some(0), fat32.read(&empty, 10, &mut buffer).ok());
+    }
+
+    #[test]
+    fn first_cluster_outside_the_volume() {
+        let mut bytes = format();
+        {
+            let mut fat32 = Fat32::mount(RamDisk::new(&mut bytes)).unwrap();
+            // A short name only, so a single entry
+            let mut file = fat32.create("/", "DATA").unwrap();
+            fat32.append(&mut file, b"hello").unwrap();
+        }
+        let root = (RESERVED_SECTORS + 2 * FAT_SECTORS) * SECTOR;
+        let entry = bytes[root..root + SECTOR]
+            .chunks(32)
+            .position(|entry| entry.starts_with(b"DATA       "))
+            .unwrap();
+        let entry = root + entry * 32;
+        let open = |bytes: &mut [u8; TOTAL_SECTORS * SECTOR]| {
+            let mut fat32 = Fat32::mount(RamDisk::new(bytes)).unwrap();
+            fat32.open("/DATA").map(|_| ()).map_err(|err| err.fault())
+        };
+
+        bytes[entry + 26..entry + 28].copy_from_slice(&1u16.to_le_bytes());
+        assert!(matches!(
+            open(&mut bytes),
+            Err(Fault::InvalidValueForField("first_cluster"))
+        ));
+        bytes[entry + 20..entry + 22].copy_from_slice(&0x1000u16.to_le_bytes());
+        assert!(matches!(
+            open(&mut bytes),
+            Err(Fault::InvalidValueForField("first_cluster"))
+        ));
+        // No cluster is only fine for an empty file
+        bytes[entry + 20..entry + 22].fill(0);
+        bytes[entry + 26..entry + 28].fill(0);
+        assert!(matches!(
+            open(&mut bytes),
+            Err(Fault::InvalidValueForField("first_cluster"))
+        ));
+        bytes[entry + 28..entry + 32].fill(0);
+        assert!(open(&mut bytes).is_ok());
+    }
+
+    #[test]
+    fn active_fat_past_the_fats() {
+        let mut bytes = format();
+        // Not mirrored, with the 16th FAT of two active
+        bytes[40..42].copy_from_slice(&0x008fu16.to_le_bytes());
+        assert!(matches!(
+            Fat32::mount(RamDisk::new(&mut bytes))
+                .map(|_| ())
+                .map_err(|err| err.fault()),
+            Err(Fault::InvalidValueForField("extended_flags"))
+        ));
+        bytes[40..42].copy_from_slice(&0x0081u16.to_le_bytes());
+        assert!(Fat32::mount(RamDisk::new(&mut bytes)).is_ok());
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(*b"KERNEL~1TXT", numbered_short_name("kernel log.txt", 1));
+        assert_eq!(*b"A_B~12  C  ", numbered_short_name("a+b.c", 12));
+        assert_eq!(*b"NOEXT~1    ", numbered_short_name(".noext", 1));
+    }
+}
@@ -0,0 +1,150 @@
+use core::arch::asm;
+#[cfg(target_arch = "x86")]
+use core::arch::x86::__cpuid;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+
+use crate::{
+    control_registers::{Cr0, Cr4},
+    error::{Fault, Feature},
+    make_bitmap,
+};
+
+const FEATURE_INFORMATION: u32 = 0x1;
+
+/// Size of the FXSAVE area
+pub const FPU_STATE_SIZE: usize = 512;
+
+// Power-on values: all exceptions masked, round to nearest, 64-bit precision for the x87
+const DEFAULT_FPU_CONTROL_WORD: u16 = 0x037f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+const FPU_CONTROL_WORD_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+#[allow(unused)]
+#[repr(u32)]
+pub enum FeatureInformationEdxBit {
+    Fpu = 1 << 0,
+    MachineCheckException = 1 << 7,
+    MachineCheckArchitecture = 1 << 14,
+    Fxsr = 1 << 24,
+    Sse = 1 << 25,
+    Sse2 = 1 << 26,
+}
+
+make_bitmap!(new_type: FeatureInformationEdx, underlying_flag_type: FeatureInformationEdxBit, repr: u32, nodisplay);
+
+#[allow(unused)]
+#[repr(u32)]
+pub enum FeatureInformationEcxBit {
+    Xsave = 1 << 26,
+}
+
+make_bitmap!(new_type: FeatureInformationEcx, underlying_flag_type: FeatureInformationEcxBit, repr: u32, nodisplay);
+
+fn supports_sse() -> bool {
+    use FeatureInformationEdxBit::*;
+    // SAFETY: The `__cpuid` instruction is safe to call with the given arguments.
+    let result = FeatureInformationEdx::from(unsafe { __cpuid(FEATURE_INFORMATION).edx });
+
+    [Fpu, Fxsr, Sse, Sse2]
+        .into_iter()
+        .all(|feature| result.is_set(feature))
+}
+
+/// Whether XSAVE/XRSTOR are available. They aren't enabled by `init`, as `FpuState` only needs
+/// FXSAVE/FXRSTOR as long as there's no AVX state to preserve
+pub fn supports_xsave() -> bool {
+    // SAFETY: The `__cpuid` instruction is safe to call with the given arguments.
+    let result = FeatureInformationEcx::from(unsafe { __cpuid(FEATURE_INFORMATION).ecx });
+
+    result.is_set(FeatureInformationEcxBit::Xsave)
+}
+
+/// Enables the x87 FPU and SSE, with exceptions reported through #MF and #XM, and resets their
+/// state to the power-on defaults, rather than running with whatever the BIOS left behind
+pub fn init() -> Result<(), Fault> {
+    use crate::control_registers::ControlRegister0Bit::*;
+    use crate::control_registers::ControlRegister4Bit::*;
+
+    if !supports_sse() {
+        return Err(Fault::UnsupportedFeature(Feature::Sse));
+    }
+
+    let mut cr0 = Cr0::read();
+    cr0.clear_flag(Emulation);
+    cr0.clear_flag(TaskSwitched);
+    cr0.set_flag(MonitorCoprocessor);
+    cr0.set_flag(ReportFPUNumericError);
+    cr0.write();
+
+    let mut cr4 = Cr4::read();
+    cr4.set_flag(OperatingSystemSupportForFXSAVEAndFXRSTOR);
+    cr4.set_flag(OperatingSystemSupportForUnmaskedSIMDFloatingPointExceptions);
+    cr4.write();
+
+    FpuState::new().restore();
+    Ok(())
+}
+
+/// The x87 FPU and SSE registers, in the FXSAVE format. Tasks switching between each other need
+/// one each, saved when switching out and restored when switching in
+#[derive(Clone)]
+#[repr(C, align(16))]
+pub struct FpuState([u8; FPU_STATE_SIZE]);
+
+impl FpuState {
+    /// The state `init` leaves the FPU in: empty x87 stack, all exceptions masked
+    pub const fn new() -> Self {
+        let mut bytes = [0u8; FPU_STATE_SIZE];
+        let control_word = DEFAULT_FPU_CONTROL_WORD.to_le_bytes();
+        let mxcsr = DEFAULT_MXCSR.to_le_bytes();
+        bytes[FPU_CONTROL_WORD_OFFSET] = control_word[0];
+        bytes[FPU_CONTROL_WORD_OFFSET + 1] = control_word[1];
+        let mut i = 0;
+        while i < mxcsr.len() {
+            bytes[MXCSR_OFFSET + i] = mxcsr[i];
+            i += 1;
+        }
+        Self(bytes)
+    }
+
+    /// Saves the current FPU and SSE registers. `init` must have run
+    pub fn save(&mut self) {
+        // SAFETY: The area is 512 bytes and 16-byte aligned, as FXSAVE requires, and CR4.OSFXSR
+        // was set by `init`
+        unsafe {
+            asm!("fxsave [{}]", in(reg) self.0.as_mut_ptr(), options(nostack, preserves_flags));
+        }
+    }
+
+    /// Loads the FPU and SSE registers from this state. `init` must have run
+    pub fn restore(&self) {
+        // SAFETY: The area is 512 bytes and 16-byte aligned, as FXRSTOR requires, and it holds
+        // either the default state or one saved by FXSAVE, so MXCSR has no reserved bits set
+        unsafe {
+            asm!("fxrstor [{}]", in(reg) self.0.as_ptr(), options(nostack, preserves_flags, readonly));
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fpu::{FPU_STATE_SIZE, FpuState};
+
+    #[test]
+    fn default_state() {
+        let state = FpuState::new();
+        assert_eq!(FPU_STATE_SIZE, size_of::<FpuState>());
+        assert_eq!(16, align_of::<FpuState>());
+        assert_eq!([0x7f, 0x03], state.0[0..2]);
+        assert_eq!([0x80, 0x1f, 0, 0], state.0[24..28]);
+        assert!(state.0[28..].iter().all(|&byte| byte == 0));
+    }
+}
@@ -0,0 +1,416 @@
+// Physical frames handed out of a fixed pool with a reference count each, and copy-on-write on top
+// of them: a frame shared between address spaces is mapped read-only everywhere, with the
+// CopyOnWrite bit set where it used to be writable. The first write to it page faults, and the
+// writer gets a copy of its own, or the frame itself back if nobody else holds it anymore
+use crate::{
+    e820,
+    error::Fault,
+    interrupts, layout,
+    msr::MemoryType,
+    paging::{Mapper, PageFaultErrorCode, PageTableEntry, PageTableEntryFlag},
+    reserved_regions::{self, ReservedRegions},
+};
+
+pub const FRAME_SIZE: u64 = 0x1000;
+const POOL_FRAMES: usize =
+    ((layout::FRAME_POOL.end - layout::FRAME_POOL.start) / FRAME_SIZE) as usize;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+// The reference count of frames that are never handed out
+const RESERVED: u16 = u16::MAX;
+
+static mut FRAMES: FrameAllocator<POOL_FRAMES> = FrameAllocator::new(layout::FRAME_POOL.start);
+
+/// Hands out the `FRAMES` frames starting at physical address `base` and counts the references
+/// to each, so that a frame goes back to the pool once the last one is released
+pub struct FrameAllocator<const FRAMES: usize> {
+    base: u64,
+    reference_counts: [u16; FRAMES],
+    // Where to start looking for a free frame, past the last one handed out
+    next: usize,
+}
+
+impl<const FRAMES: usize> FrameAllocator<FRAMES> {
+    /// `base` must be 4KB aligned
+    pub const fn new(base: u64) -> Self {
+        Self {
+            base,
+            reference_counts: [0; FRAMES],
+            next: 0,
+        }
+    }
+
+    fn index(&self, frame: u64) -> Result<usize, Fault> {
+        let offset = frame.wrapping_sub(self.base);
+        if !frame.is_multiple_of(FRAME_SIZE) || offset >= FRAMES as u64 * FRAME_SIZE {
+            return Err(Fault::FrameNotInPool(frame));
+        }
+        Ok((offset / FRAME_SIZE) as usize)
+    }
+
+    /// The physical address of a free frame, now referenced once, or None if there are none left.
+    /// The frame isn't zeroed
+    pub fn allocate(&mut self) -> Option<u64> {
+        let index = (0..FRAMES)
+            .map(|i| (self.next + i) % FRAMES)
+            .find(|&index| self.reference_counts[index] == 0)?;
+        self.reference_counts[index] = 1;
+        self.next = (index + 1) % FRAMES;
+        Some(self.base + index as u64 * FRAME_SIZE)
+    }
+
+    /// The physical address of the first of `count` free frames in a row, now referenced once
+    /// each, or None if there's no such run. The frames aren't zeroed
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<u64> {
+        if count == 0 {
+            return None;
+        }
+        let mut run = 0;
+        for index in 0..FRAMES {
+            run = if self.reference_counts[index] == 0 {
+                run + 1
+            } else {
+                0
+            };
+            if run == count {
+                let first = index + 1 - count;
+                self.reference_counts[first..=index].fill(1);
+                return Some(self.base + first as u64 * FRAME_SIZE);
+            }
+        }
+        None
+    }
+
+    /// Takes the frames that aren't free to hand out according to `regions` and `memory_map` out
+    /// of the pool for good, returning how many of them there were. Meant to be called before
+    /// anything is allocated: frames in use already are left alone
+    pub fn reserve<const N: usize>(
+        &mut self,
+        regions: &ReservedRegions<N>,
+        memory_map: &[e820::Entry],
+    ) -> usize {
+        let mut reserved = 0;
+        for (index, count) in self.reference_counts.iter_mut().enumerate() {
+            let frame = self.base + index as u64 * FRAME_SIZE;
+            if *count == 0
+                && !reserved_regions::is_available(
+                    regions,
+                    memory_map,
+                    &(frame..frame + FRAME_SIZE),
+                )
+            {
+                *count = RESERVED;
+                reserved += 1;
+            }
+        }
+        reserved
+    }
+
+    /// How many references to `frame` there are, 0 if it's free
+    pub fn reference_count(&self, frame: u64) -> Result<u16, Fault> {
+        match self.reference_counts[self.index(frame)?] {
+            RESERVED => Err(Fault::FrameNotAllocated(frame)),
+            count => Ok(count),
+        }
+    }
+
+    /// Adds a reference to an allocated frame, returning how many there are now
+    pub fn share(&mut self, frame: u64) -> Result<u16, Fault> {
+        let index = self.index(frame)?;
+        self.reference_counts[index] = match self.reference_counts[index] {
+            0 | RESERVED => return Err(Fault::FrameNotAllocated(frame)),
+            count if count + 1 == RESERVED => return Err(Fault::TooManyReferences(frame)),
+            count => count + 1,
+        };
+        Ok(self.reference_counts[index])
+    }
+
+    /// Drops a reference to an allocated frame. True if it was the last one, and the frame is
+    /// free again
+    pub fn release(&mut self, frame: u64) -> Result<bool, Fault> {
+        let index = self.index(frame)?;
+        self.reference_counts[index] = match self.reference_counts[index] {
+            0 | RESERVED => return Err(Fault::FrameNotAllocated(frame)),
+            count => count - 1,
+        };
+        Ok(self.reference_counts[index] == 0)
+    }
+
+    /// Maps the 4KB page at `virtual_address` in `source` at the same address in `destination`,
+    /// sharing its frame, which must come from this pool. A writable page becomes read-only and
+    /// copy-on-write in both, a read-only one is simply shared. Tables missing in `destination`
+    /// are created as in `paging::map_4k`
+    pub fn share_copy_on_write(
+        &mut self,
+        source: &Mapper,
+        destination: &Mapper,
+        virtual_address: u64,
+        allocate_table: impl FnMut() -> Option<u64>,
+    ) -> Result<(), Fault> {
+        use PageTableEntryFlag::*;
+        let page = virtual_address & !(FRAME_SIZE - 1);
+        let Some((entry, page_size)) = source.page_entry(page) else {
+            return Err(Fault::NotMapped(virtual_address));
+        };
+        if page_size != FRAME_SIZE {
+            return Err(Fault::LargePage(virtual_address));
+        }
+        let frame = u64::from(entry) & ADDRESS_MASK;
+        self.share(frame)?;
+
+        let mut flags = entry;
+        if entry.is_set(Write) {
+            flags.clear_flag(Write);
+            flags.set_flag(CopyOnWrite);
+            let result = source.set_page_flags(
+                page..page + FRAME_SIZE,
+                CopyOnWrite.into(),
+                Write.into(),
+                || None,
+            );
+            if let Err(fault) = result {
+                self.release(frame)?;
+                return Err(fault);
+            }
+        }
+        // Frames in the pool are plain RAM
+        let result = destination.map_4k(
+            page,
+            frame,
+            PageTableEntry::from(u64::from(flags) & !ADDRESS_MASK),
+            MemoryType::WriteBack,
+            allocate_table,
+        );
+        if let Err(fault) = result {
+            self.release(frame)?;
+            return Err(fault);
+        }
+        Ok(())
+    }
+
+    /// Resolves a page fault at `fault_address` if it's a write to a copy-on-write page: the page
+    /// gets a copy of the frame it maps, unless it's the frame's only reference left, and becomes
+    /// writable again. True if the fault was resolved and the faulting instruction can be retried
+    pub fn handle_copy_on_write(
+        &mut self,
+        mapper: &Mapper,
+        fault_address: u64,
+        error_code: PageFaultErrorCode,
+    ) -> Result<bool, Fault> {
+        use PageTableEntryFlag::*;
+        if !error_code.is_write() || !error_code.is_protection_violation() {
+            return Ok(false);
+        }
+        let page = fault_address & !(FRAME_SIZE - 1);
+        let Some((entry, FRAME_SIZE)) = mapper.page_entry(page) else {
+            return Ok(false);
+        };
+        if !entry.is_set(CopyOnWrite) {
+            return Ok(false);
+        }
+
+        let frame = u64::from(entry) & ADDRESS_MASK;
+        if self.reference_count(frame)? > 1 {
+            let copy = self.allocate().ok_or(Fault::OutOfFrames)?;
+            let offset = mapper.physical_memory_offset();
+            let source = offset.wrapping_add(frame) as usize as *const u8;
+            let destination = offset.wrapping_add(copy) as usize as *mut u8;
+            // SAFETY: both frames are in the pool, mapped at the physical memory offset, and the
+            // copy was just allocated, so nothing else refers to it
+            unsafe { core::ptr::copy_nonoverlapping(source, destination, FRAME_SIZE as usize) };
+            mapper.remap(page, copy)?;
+            self.release(frame)?;
+        }
+        mapper.set_page_flags(
+            page..page + FRAME_SIZE,
+            Write.into(),
+            CopyOnWrite.into(),
+            || None,
+        )?;
+        Ok(true)
+    }
+}
+
+/// Runs `f` on the kernel's frame pool, `layout::FRAME_POOL`, with interrupts disabled
+pub fn with_frames<R>(f: impl FnOnce(&mut FrameAllocator<POOL_FRAMES>) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let frames_ptr = &raw mut FRAMES;
+        // SAFETY: no threads, interrupts are disabled, and the page fault handler only takes the
+        // pool for writes to copy-on-write pages, which `f` has no reason to make
+        f(unsafe { &mut *frames_ptr })
+    })
+}
+
+/// `FrameAllocator::handle_copy_on_write` on the kernel's frame pool and the active page tables,
+/// for the page fault handler, which runs with interrupts disabled
+pub fn handle_page_fault_no_sync(
+    fault_address: u64,
+    error_code: PageFaultErrorCode,
+) -> Result<bool, Fault> {
+    let frames_ptr = &raw mut FRAMES;
+    // SAFETY: no threads, and the frame pool is only accessed with interrupts disabled outside of
+    // the page fault handler
+    let frames = unsafe { &mut *frames_ptr };
+    frames.handle_copy_on_write(&Mapper::active(), fault_address, error_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        e820::{self, MemoryKind},
+        error::Fault,
+        frame::{FRAME_SIZE, FrameAllocator},
+        msr::MemoryType,
+        paging::{Mapper, PageFaultErrorCode, PageFaultErrorCodeFlag, PageTableEntryFlag},
+        reserved_regions::{RegionKind, ReservedRegions},
+    };
+
+    #[repr(align(4096))]
+    struct Frames([[u64; 512]; 4]);
+
+    #[test]
+    fn reference_counts() {
+        let mut frames = FrameAllocator::<3>::new(0x10_0000);
+        assert_eq!(Some(0x10_0000), frames.allocate());
+        assert_eq!(Some(0x10_1000), frames.allocate());
+        assert_eq!(2, frames.share(0x10_0000).unwrap());
+        assert_eq!(Some(0x10_2000), frames.allocate());
+        assert_eq!(None, frames.allocate());
+
+        assert!(!frames.release(0x10_0000).unwrap());
+        assert!(frames.release(0x10_0000).unwrap());
+        assert_eq!(0, frames.reference_count(0x10_0000).unwrap());
+        assert_eq!(Some(0x10_0000), frames.allocate());
+
+        assert!(frames.release(0x10_2000).unwrap());
+        assert!(matches!(
+            frames.release(0x10_2000),
+            Err(Fault::FrameNotAllocated(0x10_2000))
+        ));
+        assert!(matches!(
+            frames.share(0x10_2000),
+            Err(Fault::FrameNotAllocated(0x10_2000))
+        ));
+        for frame in [0xF_F000, 0x10_3000, 0x10_0800] {
+            assert!(matches!(
+                frames.reference_count(frame),
+                Err(Fault::FrameNotInPool(address)) if address == frame
+            ));
+        }
+    }
+
+    #[test]
+    fn contiguous_frames() {
+        let mut frames = FrameAllocator::<4>::new(0x10_0000);
+        assert_eq!(Some(0x10_0000), frames.allocate());
+        assert_eq!(Some(0x10_1000), frames.allocate());
+        assert!(frames.release(0x10_0000).unwrap());
+        // The free frame at the start is too short a run
+        assert_eq!(Some(0x10_2000), frames.allocate_contiguous(2));
+        assert_eq!(1, frames.reference_count(0x10_3000).unwrap());
+        assert_eq!(None, frames.allocate_contiguous(2));
+        assert_eq!(None, frames.allocate_contiguous(0));
+        assert_eq!(Some(0x10_0000), frames.allocate_contiguous(1));
+    }
+
+    #[test]
+    fn reserved_frames() {
+        let mut frames = FrameAllocator::<4>::new(0x10_0000);
+        assert_eq!(Some(0x10_0000), frames.allocate());
+        let mut regions = ReservedRegions::<2>::new();
+        regions
+            .reserve(0x10_0000..0x10_1800, RegionKind::Kernel)
+            .unwrap();
+        let memory_map = [e820::Entry::new(MemoryKind::Usable, 0x0..0x10_3000)];
+        // The frame in use stays as it is, the one past the memory map is reserved too
+        assert_eq!(2, frames.reserve(&regions, &memory_map));
+        assert_eq!(1, frames.reference_count(0x10_0000).unwrap());
+        assert!(matches!(
+            frames.share(0x10_1000),
+            Err(Fault::FrameNotAllocated(0x10_1000))
+        ));
+        assert!(matches!(
+            frames.release(0x10_3000),
+            Err(Fault::FrameNotAllocated(0x10_3000))
+        ));
+        assert_eq!(Some(0x10_2000), frames.allocate());
+        assert_eq!(None, frames.allocate());
+    }
+
+    #[test]
+    fn copy_on_write() {
+        // Physical memory is pretended to be mapped one page above where it is, as in the paging
+        // tests. Frame 0 and 1 are the PML4s, 2 the page shared between them, 3 free for the copy
+        const OFFSET: u64 = 0x1000;
+        let mut pool = Frames([[0; 512]; 4]);
+        let mut tables = [(); 6].map(|_| Table([0; 512]));
+        let base = &raw const pool as u64 - OFFSET;
+        let mut frames = FrameAllocator::<4>::new(base);
+        let [source_pml4, destination_pml4, shared] = [(); 3].map(|_| frames.allocate().unwrap());
+        let source = Mapper::new(source_pml4, OFFSET);
+        let destination = Mapper::new(destination_pml4, OFFSET);
+        let mut free_tables = tables
+            .iter_mut()
+            .map(|table| &raw mut *table as u64 - OFFSET);
+        let writable = PageTableEntryFlag::Write.into();
+
+        source
+            .map_4k(0x40_0000, shared, writable, MemoryType::WriteBack, || {
+                free_tables.next()
+            })
+            .unwrap();
+        pool.0[2][0] = 0x1234;
+        frames
+            .share_copy_on_write(&source, &destination, 0x40_0123, || free_tables.next())
+            .unwrap();
+        assert_eq!(2, frames.reference_count(shared).unwrap());
+        for mapper in [&source, &destination] {
+            let (entry, _) = mapper.page_entry(0x40_0000).unwrap();
+            assert!(!entry.is_set(PageTableEntryFlag::Write));
+            assert!(entry.is_set(PageTableEntryFlag::CopyOnWrite));
+        }
+
+        let write = PageFaultErrorCode::from(
+            PageFaultErrorCodeFlag::Present as u32 | PageFaultErrorCodeFlag::Write as u32,
+        );
+        let read = PageFaultErrorCode::from(PageFaultErrorCodeFlag::Present as u32);
+        assert!(
+            !frames
+                .handle_copy_on_write(&destination, 0x40_0008, read)
+                .unwrap()
+        );
+        assert!(
+            !frames
+                .handle_copy_on_write(&destination, 0x50_0000, write)
+                .unwrap()
+        );
+
+        // The destination gets a copy, the source is left with the only reference
+        assert!(
+            frames
+                .handle_copy_on_write(&destination, 0x40_0008, write)
+                .unwrap()
+        );
+        let copy = base + 3 * FRAME_SIZE;
+        assert_eq!(Some(copy), destination.translate(0x40_0000));
+        assert_eq!(0x1234, pool.0[3][0]);
+        assert_eq!(1, frames.reference_count(shared).unwrap());
+        let (entry, _) = destination.page_entry(0x40_0000).unwrap();
+        assert!(entry.is_set(PageTableEntryFlag::Write));
+        assert!(!entry.is_set(PageTableEntryFlag::CopyOnWrite));
+
+        // Which it gets back without a copy, with the pool exhausted
+        assert_eq!(None, frames.allocate());
+        assert!(
+            frames
+                .handle_copy_on_write(&source, 0x40_0000, write)
+                .unwrap()
+        );
+        assert_eq!(Some(shared), source.translate(0x40_0000));
+        let (entry, _) = source.page_entry(0x40_0000).unwrap();
+        assert!(entry.is_set(PageTableEntryFlag::Write));
+        assert!(!entry.is_set(PageTableEntryFlag::CopyOnWrite));
+    }
+
+    #[repr(align(4096))]
+    struct Table([u64; 512]);
+}
@@ -0,0 +1,514 @@
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+// The target independent part of a GDB stub: packet framing over a polled serial port and the
+// commands GDB needs to read and write registers and memory, set breakpoints, step and continue.
+// Memory is accessed through the callbacks of whoever runs the stub, which know what's mapped
+
+use crate::serial::SerialPort;
+
+pub const MAX_PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 16;
+const INT3: u8 = 0xcc;
+// Stopped by SIGTRAP, which is what breakpoints and single steps amount to
+const STOP_REPLY: &[u8] = b"S05";
+const TRAP_FLAG: u64 = 1 << 8;
+
+const GENERAL_PURPOSE_REGISTERS: usize = 16;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+const SEGMENT_REGISTERS: usize = 6;
+const FIRST_SEGMENT_REGISTER: usize = 18;
+
+/// The registers of the stopped code, in the order of GDB's amd64 register numbers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    /// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, then r8 to r15
+    pub general_purpose: [u64; GENERAL_PURPOSE_REGISTERS],
+    pub rip: u64,
+    pub rflags: u64,
+    /// cs, ss, ds, es, fs, gs
+    pub segments: [u32; SEGMENT_REGISTERS],
+}
+
+impl Registers {
+    /// Writes register `number` as GDB expects it: little endian hex, 32 bits for eflags and
+    /// the segment registers. False if there's no such register
+    fn write_hex(&self, number: usize, reply: &mut Reply) -> bool {
+        match number {
+            0..GENERAL_PURPOSE_REGISTERS => {
+                reply.push_hex(&self.general_purpose[number].to_le_bytes())
+            }
+            RIP => reply.push_hex(&self.rip.to_le_bytes()),
+            EFLAGS => reply.push_hex(&(self.rflags as u32).to_le_bytes()),
+            FIRST_SEGMENT_REGISTER..=23 => {
+                reply.push_hex(&self.segments[number - FIRST_SEGMENT_REGISTER].to_le_bytes())
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Sets register `number` from the start of `hex`, returning how many hex digits it took
+    fn read_hex(&mut self, number: usize, hex: &[u8]) -> Option<usize> {
+        match number {
+            0..GENERAL_PURPOSE_REGISTERS => {
+                self.general_purpose[number] = u64::from_le_bytes(decode_hex(hex)?);
+                Some(16)
+            }
+            RIP => {
+                self.rip = u64::from_le_bytes(decode_hex(hex)?);
+                Some(16)
+            }
+            EFLAGS => {
+                let eflags = u32::from_le_bytes(decode_hex(hex)?) as u64;
+                self.rflags = self.rflags & !0xffff_ffff | eflags;
+                Some(8)
+            }
+            // Segment selectors can't be changed from here, the stopped code would just crash
+            FIRST_SEGMENT_REGISTER..=23 => decode_hex::<4>(hex).map(|_| 8),
+            _ => None,
+        }
+    }
+}
+
+/// How the stopped code should go on once GDB is done with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    /// Run one instruction, then trap back into the stub
+    Step,
+}
+
+/// A reply being built, silently truncated to the buffer
+struct Reply<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+impl Reply<'_> {
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if let Some(slot) = self.buffer.get_mut(self.length) {
+                *slot = byte;
+                self.length += 1;
+            }
+        }
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(&hex_digits(byte));
+        }
+    }
+}
+
+fn hex_digits(byte: u8) -> [u8; 2] {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    [DIGITS[(byte >> 4) as usize], DIGITS[(byte & 0xf) as usize]]
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+/// Decodes the first `2 * N` hex digits of `hex`
+fn decode_hex<const N: usize>(hex: &[u8]) -> Option<[u8; N]> {
+    let mut bytes = [0; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.get(..2 * N)?.chunks_exact(2)) {
+        *byte = hex_value(digits[0])? << 4 | hex_value(digits[1])?;
+    }
+    Some(bytes)
+}
+
+/// A big endian hex number, like addresses and lengths in packets
+fn parse_number(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0u64, |number, &digit| {
+        Some(number << 4 | hex_value(digit)? as u64)
+    })
+}
+
+/// Splits "address,length" in two numbers
+fn parse_range(arguments: &[u8]) -> Option<(u64, u64)> {
+    let comma = arguments.iter().position(|&byte| byte == b',')?;
+    Some((
+        parse_number(&arguments[..comma])?,
+        parse_number(&arguments[comma + 1..])?,
+    ))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// The state kept across stops: the breakpoints GDB inserted and whether it's waiting for a
+/// stop reply
+pub struct Stub {
+    breakpoints: [Option<(u64, u8)>; MAX_BREAKPOINTS],
+    resumed: bool,
+}
+
+impl Stub {
+    pub const fn new() -> Self {
+        Self {
+            breakpoints: [None; MAX_BREAKPOINTS],
+            resumed: false,
+        }
+    }
+
+    /// Talks to GDB on `port` until it resumes the stopped code, whose registers are updated
+    /// with GDB's changes. Single steps are requested by setting the trap flag in them
+    pub fn run(
+        &mut self,
+        port: &SerialPort,
+        registers: &mut Registers,
+        read_memory: impl Fn(u64) -> Option<u8>,
+        mut write_memory: impl FnMut(u64, u8) -> bool,
+    ) {
+        if self.resumed {
+            send_packet(port, STOP_REPLY);
+        }
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        let mut reply = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let packet = receive_packet(port, &mut packet);
+            let (length, resume) = self.handle_packet(
+                packet,
+                registers,
+                &read_memory,
+                &mut write_memory,
+                &mut reply,
+            );
+            if let Some(resume) = resume {
+                // Only detaching has a reply, the others get a stop reply the next time around
+                if length > 0 {
+                    send_packet(port, &reply[..length]);
+                }
+                // After detaching, nobody waits for the next stop
+                self.resumed = !matches!(packet.first(), Some(b'D' | b'k'));
+                match resume {
+                    Resume::Continue => registers.rflags &= !TRAP_FLAG,
+                    Resume::Step => registers.rflags |= TRAP_FLAG,
+                }
+                return;
+            }
+            send_packet(port, &reply[..length]);
+        }
+    }
+
+    /// Handles a packet, without its framing. Returns the length of the reply written into
+    /// `reply`, and how to resume the stopped code if the packet asked to
+    fn handle_packet(
+        &mut self,
+        packet: &[u8],
+        registers: &mut Registers,
+        read_memory: impl Fn(u64) -> Option<u8>,
+        mut write_memory: impl FnMut(u64, u8) -> bool,
+        reply: &mut [u8],
+    ) -> (usize, Option<Resume>) {
+        let mut reply = Reply {
+            buffer: reply,
+            length: 0,
+        };
+        let mut resume = None;
+        let (&command, arguments) = packet.split_first().unwrap_or((&0, &[]));
+        match command {
+            b'?' => reply.push(STOP_REPLY),
+            b'g' => {
+                for number in 0..FIRST_SEGMENT_REGISTER + SEGMENT_REGISTERS {
+                    registers.write_hex(number, &mut reply);
+                }
+            }
+            b'G' => {
+                let mut offset = 0;
+                for number in 0..FIRST_SEGMENT_REGISTER + SEGMENT_REGISTERS {
+                    let Some(length) = registers.read_hex(number, &arguments[offset..]) else {
+                        break;
+                    };
+                    offset += length;
+                }
+                reply.push(b"OK");
+            }
+            b'p' => {
+                let written = parse_number(arguments)
+                    .is_some_and(|number| registers.write_hex(number as usize, &mut reply));
+                if !written {
+                    reply.push(b"E01");
+                }
+            }
+            b'P' => {
+                let set = arguments
+                    .iter()
+                    .position(|&byte| byte == b'=')
+                    .and_then(|equals| {
+                        let number = parse_number(&arguments[..equals])? as usize;
+                        registers.read_hex(number, &arguments[equals + 1..])
+                    });
+                reply.push(if set.is_some() { b"OK" } else { b"E01" });
+            }
+            b'm' => {
+                let read = parse_range(arguments).and_then(|(address, length)| {
+                    (0..length.min((MAX_PACKET_SIZE / 2) as u64)).try_for_each(|offset| {
+                        reply.push_hex(&[read_memory(address.checked_add(offset)?)?]);
+                        Some(())
+                    })
+                });
+                if read.is_none() {
+                    reply.length = 0;
+                    reply.push(b"E01");
+                }
+            }
+            b'M' => {
+                let written = arguments
+                    .iter()
+                    .position(|&byte| byte == b':')
+                    .and_then(|colon| {
+                        let (address, length) = parse_range(&arguments[..colon])?;
+                        let data = &arguments[colon + 1..];
+                        if data.len() as u64 != 2 * length {
+                            return None;
+                        }
+                        data.chunks_exact(2)
+                            .zip(address..)
+                            .try_for_each(|(digits, address)| {
+                                let [byte] = decode_hex(digits)?;
+                                write_memory(address, byte).then_some(())
+                            })
+                    });
+                reply.push(if written.is_some() { b"OK" } else { b"E01" });
+            }
+            b'c' | b's' => {
+                if let Some(address) = parse_number(arguments) {
+                    registers.rip = address;
+                }
+                resume = Some(if command == b'c' {
+                    Resume::Continue
+                } else {
+                    Resume::Step
+                });
+            }
+            b'Z' | b'z' if arguments.starts_with(b"0,") => {
+                let done = parse_range(&arguments[2..]).and_then(|(address, _kind)| {
+                    if command == b'Z' {
+                        self.insert_breakpoint(address, &read_memory, &mut write_memory)
+                    } else {
+                        self.remove_breakpoint(address, &mut write_memory)
+                    }
+                });
+                reply.push(if done.is_some() { b"OK" } else { b"E01" });
+            }
+            b'D' => {
+                reply.push(b"OK");
+                resume = Some(Resume::Continue);
+            }
+            b'k' => resume = Some(Resume::Continue),
+            // There's only one thread, whichever GDB asks for
+            b'H' => reply.push(b"OK"),
+            b'q' if arguments.starts_with(b"Supported") => {
+                reply.push(b"PacketSize=");
+                reply.push_hex(&(MAX_PACKET_SIZE as u16).to_be_bytes());
+            }
+            b'q' if arguments == b"Attached" => reply.push(b"1"),
+            // An empty reply tells GDB the packet isn't supported
+            _ => {}
+        }
+        (reply.length, resume)
+    }
+
+    fn insert_breakpoint(
+        &mut self,
+        address: u64,
+        read_memory: impl Fn(u64) -> Option<u8>,
+        mut write_memory: impl FnMut(u64, u8) -> bool,
+    ) -> Option<()> {
+        if self
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|&(breakpoint, _)| breakpoint == address)
+        {
+            return Some(());
+        }
+        let slot = self.breakpoints.iter_mut().find(|slot| slot.is_none())?;
+        let original = read_memory(address)?;
+        write_memory(address, INT3).then_some(())?;
+        *slot = Some((address, original));
+        Some(())
+    }
+
+    fn remove_breakpoint(
+        &mut self,
+        address: u64,
+        mut write_memory: impl FnMut(u64, u8) -> bool,
+    ) -> Option<()> {
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|(breakpoint, _)| breakpoint == address))?;
+        let (_, original) = slot.take()?;
+        write_memory(address, original).then_some(())
+    }
+}
+
+impl Default for Stub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits "$data#checksum" into the data and whether its checksum is right. None if `bytes`
+/// isn't a whole packet
+fn parse_packet(bytes: &[u8]) -> Option<(&[u8], bool)> {
+    let bytes = bytes.strip_prefix(b"$")?;
+    let hash = bytes.iter().position(|&byte| byte == b'#')?;
+    let [expected] = decode_hex(bytes.get(hash + 1..hash + 3)?)?;
+    Some((&bytes[..hash], checksum(&bytes[..hash]) == expected))
+}
+
+/// Blocks until a packet with a valid checksum arrives, acknowledging it, and returns its data.
+/// Anything outside of packets, like acknowledgements and interrupt requests, is ignored
+fn receive_packet<'a>(port: &SerialPort, buffer: &'a mut [u8]) -> &'a [u8] {
+    loop {
+        while port.read_byte_blocking() != b'$' {}
+        buffer[0] = b'$';
+        let mut length = 1;
+        // The data, the '#' and the two checksum digits
+        let mut remaining = None;
+        while remaining != Some(0) && length < buffer.len() {
+            let byte = port.read_byte_blocking();
+            buffer[length] = byte;
+            length += 1;
+            remaining = match (remaining, byte) {
+                (None, b'#') => Some(2),
+                (Some(remaining), _) => Some(remaining - 1),
+                (None, _) => None,
+            };
+        }
+        match parse_packet(&buffer[..length]) {
+            Some((_, true)) => {
+                port.send_byte(b'+');
+                let hash = length - 3;
+                return &buffer[1..hash];
+            }
+            _ => port.send_byte(b'-'),
+        }
+    }
+}
+
+/// Sends a packet until GDB acknowledges it
+fn send_packet(port: &SerialPort, data: &[u8]) {
+    loop {
+        port.send_byte(b'$');
+        for &byte in data {
+            port.send_byte(byte);
+        }
+        port.send_byte(b'#');
+        for digit in hex_digits(checksum(data)) {
+            port.send_byte(digit);
+        }
+        match port.read_byte_blocking() {
+            b'-' => continue,
+            _ => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gdb::{MAX_PACKET_SIZE, Registers, Resume, Stub, parse_packet};
+
+    fn handle(
+        stub: &mut Stub,
+        packet: &[u8],
+        registers: &mut Registers,
+        memory: &mut [u8; 16],
+    ) -> ([u8; MAX_PACKET_SIZE], usize, Option<Resume>) {
+        let mut reply = [0u8; MAX_PACKET_SIZE];
+        let snapshot = *memory;
+        let (length, resume) = stub.handle_packet(
+            packet,
+            registers,
+            |address| snapshot.get(address.checked_sub(0x1000)? as usize).copied(),
+            |address, byte| match memory.get_mut((address - 0x1000) as usize) {
+                Some(slot) => {
+                    *slot = byte;
+                    true
+                }
+                None => false,
+            },
+            &mut reply,
+        );
+        (reply, length, resume)
+    }
+
+    #[test]
+    fn packet_framing() {
+        assert_eq!(Some((&b"g"[..], true)), parse_packet(b"$g#67"));
+        assert_eq!(Some((&b"g"[..], false)), parse_packet(b"$g#00"));
+        assert_eq!(None, parse_packet(b"$g#6"));
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut stub = Stub::new();
+        let mut registers = Registers {
+            rip: 0x1234,
+            rflags: 0x202,
+            ..Default::default()
+        };
+        registers.general_purpose[0] = 0x1122_3344_5566_7788;
+        let mut memory = [0u8; 16];
+        memory[..4].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+
+        let (reply, length, _) = handle(&mut stub, b"g", &mut registers, &mut memory);
+        assert_eq!(16 * 16 + 16 + 8 + 6 * 8, length);
+        assert_eq!(b"8877665544332211", &reply[..16]);
+        assert_eq!(b"3412000000000000", &reply[256..272]);
+        assert_eq!(b"02020000", &reply[272..280]);
+
+        let (reply, length, _) = handle(
+            &mut stub,
+            b"P10=7856000000000000",
+            &mut registers,
+            &mut memory,
+        );
+        assert_eq!(b"OK", &reply[..length]);
+        assert_eq!(0x5678, registers.rip);
+
+        let (reply, length, _) = handle(&mut stub, b"m1000,4", &mut registers, &mut memory);
+        assert_eq!(b"deadbeef", &reply[..length]);
+        let (reply, length, _) = handle(&mut stub, b"m100e,4", &mut registers, &mut memory);
+        assert_eq!(b"E01", &reply[..length]);
+        let (reply, length, _) = handle(&mut stub, b"M1001,2:cafe", &mut registers, &mut memory);
+        assert_eq!(b"OK", &reply[..length]);
+        assert_eq!([0xde, 0xca, 0xfe, 0xef], memory[..4]);
+    }
+
+    #[test]
+    fn breakpoints_and_resuming() {
+        let mut stub = Stub::new();
+        let mut registers = Registers::default();
+        let mut memory = [0x90u8; 16];
+
+        let (reply, length, _) = handle(&mut stub, b"Z0,1004,1", &mut registers, &mut memory);
+        assert_eq!(b"OK", &reply[..length]);
+        assert_eq!(0xcc, memory[4]);
+        let (reply, length, _) = handle(&mut stub, b"z0,1004,1", &mut registers, &mut memory);
+        assert_eq!(b"OK", &reply[..length]);
+        assert_eq!(0x90, memory[4]);
+        let (reply, length, _) = handle(&mut stub, b"z0,1004,1", &mut registers, &mut memory);
+        assert_eq!(b"E01", &reply[..length]);
+
+        let (_, length, resume) = handle(&mut stub, b"s", &mut registers, &mut memory);
+        assert_eq!((0, Some(Resume::Step)), (length, resume));
+        let (_, _, resume) = handle(&mut stub, b"c1008", &mut registers, &mut memory);
+        assert_eq!(Some(Resume::Continue), resume);
+        assert_eq!(0x1008, registers.rip);
+
+        // Unsupported packets get an empty reply
+        let (_, length, resume) =
+            handle(&mut stub, b"vMustReplyEmpty", &mut registers, &mut memory);
+        assert_eq!((0, None), (length, resume));
+    }
+}
@@ -1,4 +1,4 @@
-use core::mem::size_of;
+use core::{arch::asm, fmt::Display, mem::size_of};
 
 use num_enum::TryFromPrimitive;
 
@@ -13,8 +13,8 @@ macro_rules! impl_descriptor_ops {
             }
 
             pub fn set_privilege_level(&mut self, privilege_level: PrivilegeLevel) {
-                self.bits &= !0x60_00;
-                self.bits |= (privilege_level as u16) << 12;
+                self.bits &= !PRIVILEGE_LEVEL_BITS;
+                self.bits |= (privilege_level as u16) << PRIVILEGE_LEVEL_SHIFT;
             }
         }
     };
@@ -36,21 +36,72 @@ macro_rules! update_flags {
 
 pub type GDT<const N: usize> = [SegmentDescriptor; N];
 
+// Bits of the flags that aren't flags: the limit's high nibble, the DPL, the S bit and the type,
+// of which code and data segments only leave the code bit out of their flags
+const LIMIT_HI_BITS: u16 = 0x0f_00;
+const PRIVILEGE_LEVEL_SHIFT: u16 = 5;
+const PRIVILEGE_LEVEL_BITS: u16 = 0b11 << PRIVILEGE_LEVEL_SHIFT;
+const CODE_OR_DATA_SEGMENT_BIT: u16 = 1 << 4;
+const SYSTEM_TYPE_BITS: u16 = 0x0f;
+const CODE_DATA_TYPE_BITS: u16 = 0b11 << 3;
+const AVAILABLE_TSS: u8 = 0x9;
+const BUSY_TSS: u8 = 0xb;
+
+/// What LGDT loads and SGDT stores: a 32-bit base in protected mode and a 64-bit one in long
+/// mode, as wide as `usize` either way
 #[repr(C, packed)]
 pub struct GDTDescriptor {
     size: u16,
-    address: u32,
+    address: usize,
 }
 
 impl<const N: usize> From<&'static GDT<N>> for GDTDescriptor {
     fn from(value: &'static GDT<N>) -> Self {
         Self {
-            address: value as *const _ as u32,
+            address: value as *const _ as usize,
             size: size_of::<GDT<N>>() as u16 - 1,
         }
     }
 }
 
+impl GDTDescriptor {
+    /// Where the GDT the CPU uses is, and how big
+    pub fn read_current() -> Self {
+        let mut descriptor = Self {
+            size: 0,
+            address: 0,
+        };
+        // SAFETY: SGDT stores as many bytes as a GDTDescriptor has in the mode this runs in, and
+        // nothing else
+        unsafe {
+            asm!("sgdt [{}]", in(reg) &raw mut descriptor, options(nostack, preserves_flags));
+        }
+        descriptor
+    }
+
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// How many descriptors fit in the table. Long mode TSS descriptors take two
+    pub fn entries(&self) -> usize {
+        (self.size as usize + 1) / size_of::<SegmentDescriptor>()
+    }
+
+    /// The descriptors of the table
+    ///
+    /// # Safety
+    /// The table must be mapped at its address for as long as the slice is used, which is the
+    /// case for the one the CPU uses as long as it stays loaded
+    pub unsafe fn descriptors(&self) -> &'static [SegmentDescriptor] {
+        // SAFETY: the caller guarantees the table is mapped, and descriptors have no alignment
+        // requirements
+        unsafe {
+            core::slice::from_raw_parts(self.address as *const SegmentDescriptor, self.entries())
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 struct SegmentDescriptorFlags(u16);
 
@@ -78,6 +129,12 @@ impl SegmentDescriptorFlags {
         flags.set_limit_hi(limit_hi);
         *self = flags.into();
     }
+
+    pub fn set_privilege_level(&mut self, privilege_level: PrivilegeLevel) {
+        let mut flags = SegmentFlags::from(self.0);
+        flags.set_privilege_level(privilege_level);
+        *self = flags.into();
+    }
 }
 
 impl From<SegmentFlags> for SegmentDescriptorFlags {
@@ -110,7 +167,16 @@ pub enum DataSegmentDescriptorBit {
     SegmentLimitHas4KGranularity = 1 << 15,
 }
 
-make_bitmap!(new_type: DataSegmentDescriptorFlags, underlying_flag_type: DataSegmentDescriptorBit, repr: u16, nodisplay);
+make_bitmap!(new_type: DataSegmentDescriptorFlags, underlying_flag_type: DataSegmentDescriptorBit, repr: u16, names: [
+    Accessed => "A",
+    Writable => "W",
+    ExpandsDown => "E",
+    Present => "P",
+    Available => "AVL",
+    LongMode => "L",
+    Big => "B",
+    SegmentLimitHas4KGranularity => "G",
+]);
 impl_descriptor_ops!(DataSegmentDescriptorFlags);
 
 #[allow(unused)]
@@ -127,7 +193,16 @@ pub enum CodeSegmentDescriptorBit {
     SegmentLimitHas4KGranularity = 1 << 15,
 }
 
-make_bitmap!(new_type: CodeSegmentDescriptorFlags, underlying_flag_type: CodeSegmentDescriptorBit, repr: u16, nodisplay);
+make_bitmap!(new_type: CodeSegmentDescriptorFlags, underlying_flag_type: CodeSegmentDescriptorBit, repr: u16, names: [
+    Accessed => "A",
+    Readable => "R",
+    Conforming => "C",
+    Present => "P",
+    Available => "AVL",
+    LongMode => "L",
+    DefaultOperandLengthIs32Bit => "D",
+    SegmentLimitHas4KGranularity => "G",
+]);
 impl_descriptor_ops!(CodeSegmentDescriptorFlags);
 
 #[allow(unused)]
@@ -139,7 +214,11 @@ pub enum TaskSegmentDescriptorBit {
     SegmentLimitHas4KGranularity = 1 << 15,
 }
 
-make_bitmap!(new_type: TaskSegmentDescriptorFlags, underlying_flag_type: TaskSegmentDescriptorBit, repr: u16, nodisplay);
+make_bitmap!(new_type: TaskSegmentDescriptorFlags, underlying_flag_type: TaskSegmentDescriptorBit, repr: u16, names: [
+    Present => "P",
+    LongMode => "L",
+    SegmentLimitHas4KGranularity => "G",
+]);
 impl_descriptor_ops!(TaskSegmentDescriptorFlags);
 
 #[repr(u8)]
@@ -153,7 +232,10 @@ pub enum SegmentKind {
     Data,
 }
 
-enum SegmentFlags {
+/// The flags of a descriptor, by the kind of segment it describes. System descriptors all have
+/// the flags of a TSS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentFlags {
     Code(CodeSegmentDescriptorFlags),
     Data(DataSegmentDescriptorFlags),
     Task(TaskSegmentDescriptorFlags),
@@ -173,6 +255,27 @@ impl From<u16> for SegmentFlags {
     }
 }
 
+impl Display for SegmentFlags {
+    /// Only the flags, without the bits `DecodedSegmentDescriptor` tells apart
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let non_flag_bits = LIMIT_HI_BITS | PRIVILEGE_LEVEL_BITS | CODE_OR_DATA_SEGMENT_BIT;
+        match self {
+            SegmentFlags::Code(flags) => {
+                let bits = u16::from(*flags) & !(non_flag_bits | CODE_DATA_TYPE_BITS);
+                write!(f, "{}", CodeSegmentDescriptorFlags::from(bits))
+            }
+            SegmentFlags::Data(flags) => {
+                let bits = u16::from(*flags) & !(non_flag_bits | CODE_DATA_TYPE_BITS);
+                write!(f, "{}", DataSegmentDescriptorFlags::from(bits))
+            }
+            SegmentFlags::Task(flags) => {
+                let bits = u16::from(*flags) & !(non_flag_bits | SYSTEM_TYPE_BITS);
+                write!(f, "{}", TaskSegmentDescriptorFlags::from(bits))
+            }
+        }
+    }
+}
+
 impl SegmentFlags {
     pub fn set_limit_hi(&mut self, limit_hi: u8) {
         match self {
@@ -188,6 +291,20 @@ impl SegmentFlags {
         }
     }
 
+    pub fn set_privilege_level(&mut self, privilege_level: PrivilegeLevel) {
+        match self {
+            SegmentFlags::Code(code_segment_descriptor_flags) => {
+                code_segment_descriptor_flags.set_privilege_level(privilege_level)
+            }
+            SegmentFlags::Data(data_segment_descriptor_flags) => {
+                data_segment_descriptor_flags.set_privilege_level(privilege_level)
+            }
+            SegmentFlags::Task(task_segment_descriptor_flags) => {
+                task_segment_descriptor_flags.set_privilege_level(privilege_level)
+            }
+        }
+    }
+
     pub fn set_present(&mut self) {
         match self {
             SegmentFlags::Code(code_segment_descriptor_flags) => {
@@ -283,13 +400,16 @@ impl SegmentDescriptor {
         }
     }
 
-    pub fn new_flat(kind: SegmentKind, long: bool) -> Self {
+    /// A segment spanning the whole address space, for code running at `privilege_level`, e.g.
+    /// Ring3 for user programs
+    pub fn new_flat(kind: SegmentKind, long: bool, privilege_level: PrivilegeLevel) -> Self {
         let mut new_segment = Self::blank();
         new_segment.set_base(0);
         new_segment.set_limit(u32::MAX);
         update_flags!(new_segment, |flags: &mut SegmentDescriptorFlags| {
             flags.set_present();
             flags.set_4k_granularity();
+            flags.set_privilege_level(privilege_level);
         });
         match kind {
             SegmentKind::Code => {
@@ -336,6 +456,32 @@ impl SegmentDescriptor {
         new_segment
     }
 
+    /// The descriptor of a TSS with an I/O permission bitmap, whose limit covers the bitmap
+    pub fn new_tss_with_io_permissions<const SIZE: usize>(
+        tss: &tss::TaskStateSegmentWithIoPermissions<SIZE>,
+    ) -> Self {
+        let mut new_segment = Self::new_tss(tss.tss());
+        new_segment.set_limit(size_of::<tss::TaskStateSegmentWithIoPermissions<SIZE>>() as u32 - 1);
+        new_segment
+    }
+
+    /// The two descriptors of a long mode TSS: the lower one as for protected mode, and the upper
+    /// one holding the high half of the base
+    pub fn new_long_mode_tss(tss: &tss::LongModeTaskStateSegment) -> [Self; 2] {
+        let base = tss as *const _ as u64;
+        let mut lower = Self::blank();
+        lower.set_tss();
+        lower.set_base(base as u32);
+        lower.set_limit(size_of::<tss::LongModeTaskStateSegment>() as u32 - 1);
+        update_flags!(lower, |flags: &mut SegmentDescriptorFlags| {
+            flags.set_present();
+        });
+        let mut upper = Self::blank();
+        upper.segment_limit_lo = (base >> 32) as u16;
+        upper.base_low = (base >> 48) as u16;
+        [lower, upper]
+    }
+
     fn set_base(&mut self, base_addr: u32) {
         self.base_hi = (base_addr >> 24) as u8;
         self.base_mid = (base_addr >> 16) as u8;
@@ -385,19 +531,127 @@ impl SegmentDescriptor {
     pub fn has_4k_granularity(&self) -> bool {
         SegmentFlags::from(self.flags.0).has_4k_granularity()
     }
+
+    /// What the descriptor describes, e.g. one of a GDT read back with
+    /// `GDTDescriptor::read_current`. Only the lower half of long mode TSS descriptors decodes
+    /// to a TSS, the upper half holds the high bits of the base
+    pub fn decode(&self) -> DecodedSegmentDescriptor {
+        let flags = self.flags.0;
+        let kind = if u64::from(*self) == 0 {
+            DescriptorKind::Null
+        } else if flags & CODE_OR_DATA_SEGMENT_BIT == 0 {
+            match (flags & SYSTEM_TYPE_BITS) as u8 {
+                AVAILABLE_TSS => DescriptorKind::Tss { busy: false },
+                BUSY_TSS => DescriptorKind::Tss { busy: true },
+                system_type => DescriptorKind::System(system_type),
+            }
+        } else if flags & CODE_DATA_TYPE_BITS == CODE_DATA_TYPE_BITS {
+            DescriptorKind::Code
+        } else {
+            DescriptorKind::Data
+        };
+        let limit = if self.has_4k_granularity() {
+            (self.get_limit() << 12) | 0xfff
+        } else {
+            self.get_limit()
+        };
+        DecodedSegmentDescriptor {
+            kind,
+            base: self.get_base(),
+            limit,
+            privilege_level: PrivilegeLevel::from_bits((flags >> PRIVILEGE_LEVEL_SHIFT) as u8),
+            flags: SegmentFlags::from(flags),
+        }
+    }
+}
+
+impl From<SegmentDescriptor> for u64 {
+    fn from(value: SegmentDescriptor) -> Self {
+        // SAFETY: a SegmentDescriptor is 8 bytes, all of them plain integers
+        u64::from_le_bytes(unsafe { core::mem::transmute::<SegmentDescriptor, [u8; 8]>(value) })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorKind {
+    /// All zeroes, like the first entry of every GDT
+    Null,
+    Code,
+    Data,
+    /// A TSS, busy once loaded in the task register
+    Tss {
+        busy: bool,
+    },
+    /// Any other system descriptor, e.g. an LDT or a call gate, by its type
+    System(u8),
+}
+
+/// A descriptor, with its base and limit put back together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedSegmentDescriptor {
+    pub kind: DescriptorKind,
+    pub base: u32,
+    /// The last valid offset in the segment, in bytes whatever the granularity
+    pub limit: u32,
+    pub privilege_level: PrivilegeLevel,
+    pub flags: SegmentFlags,
+}
+
+impl Display for DecodedSegmentDescriptor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.kind {
+            DescriptorKind::Null => return write!(f, "null"),
+            DescriptorKind::Code => write!(f, "code")?,
+            DescriptorKind::Data => write!(f, "data")?,
+            DescriptorKind::Tss { busy: false } => write!(f, "TSS")?,
+            DescriptorKind::Tss { busy: true } => write!(f, "busy TSS")?,
+            DescriptorKind::System(system_type) => write!(f, "system type {system_type:#x}")?,
+        }
+        write!(
+            f,
+            " base={:#010x} limit={:#010x} DPL{} {}",
+            self.base, self.limit, self.privilege_level as u8, self.flags
+        )
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::gdt::{self, SegmentDescriptor};
+    use core::fmt::Write;
+
+    use crate::{
+        gdt::{
+            self, CodeSegmentDescriptorFlags, DescriptorKind, SegmentDescriptor,
+            TaskSegmentDescriptorFlags,
+        },
+        protection::PrivilegeLevel,
+        test_support::TestWriter,
+        tss,
+    };
+
+    #[test]
+    fn flags_with_the_residual_bits() {
+        let mut writer = TestWriter::<64>::new();
+        // A flat 64-bit code segment: type, DPL and limit bits aren't flags
+        write!(
+            writer,
+            "{} {}",
+            CodeSegmentDescriptorFlags::from(0xaf9a),
+            TaskSegmentDescriptorFlags::from(0x0089)
+        )
+        .unwrap();
+        assert_eq!(b"R|P|L|G|0xf18 P|0x9", writer.as_bytes());
+    }
 
     #[test]
     fn flat_32bit() {
-        let code_segment = SegmentDescriptor::new_flat(gdt::SegmentKind::Code, false);
+        let code_segment =
+            SegmentDescriptor::new_flat(gdt::SegmentKind::Code, false, PrivilegeLevel::Ring0);
         assert_eq!([0xff, 0xff, 0, 0, 0, 0x9a, 0xcf, 0], unsafe {
             core::mem::transmute::<SegmentDescriptor, [u8; 8]>(code_segment)
         });
-        let data_segment = SegmentDescriptor::new_flat(gdt::SegmentKind::Data, false);
+        let data_segment =
+            SegmentDescriptor::new_flat(gdt::SegmentKind::Data, false, PrivilegeLevel::Ring0);
         assert_eq!([0xff, 0xff, 0, 0, 0, 0x92, 0xcf, 0], unsafe {
             core::mem::transmute::<SegmentDescriptor, [u8; 8]>(data_segment)
         });
@@ -405,13 +659,89 @@ mod tests {
 
     #[test]
     fn flat_64bit() {
-        let code_segment = SegmentDescriptor::new_flat(gdt::SegmentKind::Code, true);
+        let code_segment =
+            SegmentDescriptor::new_flat(gdt::SegmentKind::Code, true, PrivilegeLevel::Ring0);
         assert_eq!([0xff, 0xff, 0, 0, 0, 0x9a, 0xaf, 0], unsafe {
             core::mem::transmute::<SegmentDescriptor, [u8; 8]>(code_segment)
         });
-        let data_segment = SegmentDescriptor::new_flat(gdt::SegmentKind::Data, true);
+        let data_segment =
+            SegmentDescriptor::new_flat(gdt::SegmentKind::Data, true, PrivilegeLevel::Ring0);
         assert_eq!([0xff, 0xff, 0, 0, 0, 0x92, 0xcf, 0], unsafe {
             core::mem::transmute::<SegmentDescriptor, [u8; 8]>(data_segment)
         });
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn flat_user_segments() {
+        let code_segment =
+            SegmentDescriptor::new_flat(gdt::SegmentKind::Code, true, PrivilegeLevel::Ring3);
+        assert_eq!([0xff, 0xff, 0, 0, 0, 0xfa, 0xaf, 0], unsafe {
+            core::mem::transmute::<SegmentDescriptor, [u8; 8]>(code_segment)
+        });
+        let data_segment =
+            SegmentDescriptor::new_flat(gdt::SegmentKind::Data, true, PrivilegeLevel::Ring3);
+        assert_eq!([0xff, 0xff, 0, 0, 0, 0xf2, 0xcf, 0], unsafe {
+            core::mem::transmute::<SegmentDescriptor, [u8; 8]>(data_segment)
+        });
+        assert_eq!(PrivilegeLevel::Ring3, data_segment.decode().privilege_level);
+    }
+
+    #[test]
+    fn long_mode_tss() {
+        let tss = tss::LongModeTaskStateSegment::blank();
+        let base = core::ptr::addr_of!(tss) as u64;
+        let [lower, upper] = SegmentDescriptor::new_long_mode_tss(&tss);
+        assert_eq!(
+            [
+                0x67,
+                0,
+                base as u8,
+                (base >> 8) as u8,
+                (base >> 16) as u8,
+                0x89,
+                0,
+                (base >> 24) as u8
+            ],
+            unsafe { core::mem::transmute::<SegmentDescriptor, [u8; 8]>(lower) }
+        );
+        assert_eq!(base >> 32, u64::from(upper));
+        assert_eq!(DescriptorKind::Tss { busy: false }, lower.decode().kind);
+        assert_eq!(base as u32, lower.decode().base);
+    }
+
+    #[test]
+    fn decoded_descriptors() {
+        let code_segment =
+            SegmentDescriptor::new_flat(gdt::SegmentKind::Code, true, PrivilegeLevel::Ring0)
+                .decode();
+        assert_eq!(DescriptorKind::Code, code_segment.kind);
+        assert_eq!(0, code_segment.base);
+        assert_eq!(u32::MAX, code_segment.limit);
+        assert_eq!(PrivilegeLevel::Ring0, code_segment.privilege_level);
+        let mut writer = TestWriter::<64>::new();
+        write!(writer, "{code_segment}").unwrap();
+        assert_eq!(
+            b"code base=0x00000000 limit=0xffffffff DPL0 R|P|L|G",
+            writer.as_bytes()
+        );
+
+        // SAFETY: a SegmentDescriptor is 8 bytes, all of them plain integers
+        let busy_tss = unsafe {
+            core::mem::transmute::<[u8; 8], SegmentDescriptor>([
+                0x67, 0, 0x00, 0x10, 0x20, 0x8b, 0x00, 0x30,
+            ])
+        }
+        .decode();
+        assert_eq!(DescriptorKind::Tss { busy: true }, busy_tss.kind);
+        assert_eq!(0x3020_1000, busy_tss.base);
+        let mut writer = TestWriter::<64>::new();
+        write!(writer, "{busy_tss}").unwrap();
+        assert_eq!(
+            b"busy TSS base=0x30201000 limit=0x00000067 DPL0 P",
+            writer.as_bytes()
+        );
+
+        let null = SegmentDescriptor::blank().decode();
+        assert_eq!(DescriptorKind::Null, null.kind);
+    }
+}
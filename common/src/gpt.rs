@@ -0,0 +1,534 @@
+// GUID partition tables. The header is at LBA 1 and points to an array of partition entries, and
+// both are covered by CRC32s. A copy of both is kept at the end of the disk, which is read instead
+// when the primary one is damaged
+// UEFI specification 2.10, section 5.3
+use core::fmt::Display;
+
+use zerocopy::TryFromBytes;
+
+use crate::{
+    block_device::BlockDevice,
+    error::{Error, Facility, Fault, try_read_error},
+    hash::Crc32,
+};
+
+/// Used partitions past this many aren't kept track of
+pub const MAX_PARTITIONS: usize = 16;
+const PRIMARY_HEADER_LBA: u64 = 1;
+const SIGNATURE: [u8; 8] = *b"EFI PART";
+const MIN_HEADER_SIZE: usize = size_of::<inner::Header>();
+const MIN_ENTRY_SIZE: usize = size_of::<inner::Entry>();
+// Sectors of up to this many bytes can be read
+const MAX_SECTOR_SIZE: usize = 4096;
+// Partition entry arrays bigger than this are refused rather than read sector by sector. The
+// specification asks for at least 16 KiB
+const MAX_ENTRY_ARRAY_SIZE: u64 = 1 << 20;
+// Where the CRC32 field is in the header, which is zeroed while computing it
+const HEADER_CRC32_OFFSET: usize = 16;
+
+mod inner {
+    use zerocopy::{FromBytes, Immutable, KnownLayout, LE, U16, U32, U64, Unaligned};
+
+    #[derive(FromBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Header {
+        pub(super) signature: [u8; 8],
+        pub(super) revision: U32<LE>,
+        pub(super) header_size: U32<LE>,
+        pub(super) header_crc32: U32<LE>,
+        pub(super) reserved: U32<LE>,
+        pub(super) my_lba: U64<LE>,
+        pub(super) alternate_lba: U64<LE>,
+        pub(super) first_usable_lba: U64<LE>,
+        pub(super) last_usable_lba: U64<LE>,
+        pub(super) disk_guid: [u8; 16],
+        pub(super) partition_entry_lba: U64<LE>,
+        pub(super) number_of_partition_entries: U32<LE>,
+        pub(super) size_of_partition_entry: U32<LE>,
+        pub(super) partition_entry_array_crc32: U32<LE>,
+    }
+
+    #[derive(FromBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Entry {
+        pub(super) partition_type_guid: [u8; 16],
+        pub(super) unique_partition_guid: [u8; 16],
+        pub(super) starting_lba: U64<LE>,
+        pub(super) ending_lba: U64<LE>,
+        pub(super) attributes: U64<LE>,
+        pub(super) partition_name: [U16<LE>; 36],
+    }
+}
+
+/// A GUID, as GPT stores it: the first three fields little endian, the rest as bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid([0; 16]);
+
+    /// The GUID written as `data1-data2-data3-data4[..2]-data4[2..]`
+    pub const fn from_fields(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let (data1, data2, data3) = (
+            data1.to_le_bytes(),
+            data2.to_le_bytes(),
+            data3.to_le_bytes(),
+        );
+        Guid([
+            data1[0], data1[1], data1[2], data1[3], data2[0], data2[1], data3[0], data3[1],
+            data4[0], data4[1], data4[2], data4[3], data4[4], data4[5], data4[6], data4[7],
+        ])
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let bytes = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            u16::from_le_bytes([bytes[4], bytes[5]]),
+            u16::from_le_bytes([bytes[6], bytes[7]])
+        )?;
+        for (index, byte) in bytes[8..].iter().enumerate() {
+            if index == 2 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+pub const EFI_SYSTEM_PARTITION: Guid = Guid::from_fields(
+    0xC12A_7328,
+    0xF81F,
+    0x11D2,
+    [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+);
+/// 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+pub const LINUX_FILESYSTEM: Guid = Guid::from_fields(
+    0x0FC6_3DAF,
+    0x8483,
+    0x4772,
+    [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+);
+/// 2BA4D7F1-9C3E-4A6B-8E51-B0C2D9F4E713, ours, for a partition holding the kernel
+pub const BLOG_OS_KERNEL: Guid = Guid::from_fields(
+    0x2BA4_D7F1,
+    0x9C3E,
+    0x4A6B,
+    [0x8E, 0x51, 0xB0, 0xC2, 0xD9, 0xF4, 0xE7, 0x13],
+);
+
+/// The name of the partition types there are constants for
+pub fn partition_type_name(partition_type: Guid) -> Option<&'static str> {
+    match partition_type {
+        EFI_SYSTEM_PARTITION => Some("EFI system partition"),
+        LINUX_FILESYSTEM => Some("Linux filesystem"),
+        BLOG_OS_KERNEL => Some("blog_os kernel"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Partition {
+    pub partition_type: Guid,
+    pub guid: Guid,
+    pub first_lba: u64,
+    /// Inclusive
+    pub last_lba: u64,
+    pub attributes: u64,
+    name: [u16; 36],
+}
+
+impl Partition {
+    pub fn sectors(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+
+    /// The name, UTF-16 as stored, with what doesn't decode replaced
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        let length = self
+            .name
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(self.name.len());
+        char::decode_utf16(self.name[..length].iter().copied())
+            .map(|character| character.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+impl Display for Partition {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:#x}-{:#x} ", self.first_lba, self.last_lba)?;
+        match partition_type_name(self.partition_type) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "{}", self.partition_type)?,
+        }
+        write!(f, " \"")?;
+        for character in self.name() {
+            write!(f, "{}", character)?;
+        }
+        write!(f, "\"")
+    }
+}
+
+/// A partition table that was read and checked
+#[derive(Debug, Clone)]
+pub struct Gpt {
+    pub disk_guid: Guid,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    /// Whether the primary table was damaged and this is the backup one
+    pub from_backup: bool,
+    partitions: [Partition; MAX_PARTITIONS],
+    count: usize,
+}
+
+fn parsing_error(fault: Fault) -> Error {
+    Error::parsing_error(fault, Facility::Gpt)
+}
+
+impl Gpt {
+    /// Reads the primary partition table of `device`, or the backup one at the end of the disk if
+    /// the primary one doesn't check out. The error is the primary one's if neither does
+    pub fn read(device: &mut impl BlockDevice) -> Result<Self, Error> {
+        let primary = Self::read_at(device, PRIMARY_HEADER_LBA);
+        let Err(err) = primary else {
+            return primary;
+        };
+        let backup_lba = device.sectors().saturating_sub(1);
+        let mut backup = Self::read_at(device, backup_lba).map_err(|_| err)?;
+        backup.from_backup = true;
+        Ok(backup)
+    }
+
+    /// Reads the header at `lba` and the entries it points to
+    fn read_at(device: &mut impl BlockDevice, lba: u64) -> Result<Self, Error> {
+        let sector_size = device.sector_size_bytes() as usize;
+        if !(MIN_ENTRY_SIZE..=MAX_SECTOR_SIZE).contains(&sector_size) {
+            return Err(parsing_error(Fault::InvalidValueForField("sector size")));
+        }
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        let sector = &mut sector[..sector_size];
+        device.read_sectors(lba, sector)?;
+
+        let (header, _) = inner::Header::try_read_from_prefix(sector)
+            .map_err(|err| try_read_error(Facility::Gpt, err))?;
+        if header.signature != SIGNATURE {
+            return Err(parsing_error(Fault::InvalidValueForField("signature")));
+        }
+        let header_size = header.header_size.get() as usize;
+        if !(MIN_HEADER_SIZE..=sector_size).contains(&header_size) {
+            return Err(parsing_error(Fault::InvalidValueForField("header_size")));
+        }
+        let mut crc = Crc32::new();
+        crc.update(&sector[..HEADER_CRC32_OFFSET]);
+        crc.update(&[0; size_of::<u32>()]);
+        crc.update(&sector[HEADER_CRC32_OFFSET + size_of::<u32>()..header_size]);
+        if crc.finish() != header.header_crc32.get() {
+            return Err(parsing_error(Fault::InvalidValueForField("header_crc32")));
+        }
+        if header.my_lba.get() != lba {
+            return Err(parsing_error(Fault::InvalidValueForField("my_lba")));
+        }
+        let first_usable_lba = header.first_usable_lba.get();
+        let last_usable_lba = header.last_usable_lba.get();
+        if first_usable_lba > last_usable_lba || last_usable_lba >= device.sectors() {
+            return Err(parsing_error(Fault::InvalidValueForField(
+                "last_usable_lba",
+            )));
+        }
+        // A multiple of 128 that's a power of two, so entries never straddle sectors
+        let entry_size = header.size_of_partition_entry.get() as usize;
+        if entry_size < MIN_ENTRY_SIZE
+            || !entry_size.is_power_of_two()
+            || !sector_size.is_multiple_of(entry_size)
+        {
+            return Err(parsing_error(Fault::InvalidValueForField(
+                "size_of_partition_entry",
+            )));
+        }
+
+        let mut gpt = Self {
+            disk_guid: Guid(header.disk_guid),
+            first_usable_lba,
+            last_usable_lba,
+            from_backup: false,
+            partitions: [Partition {
+                partition_type: Guid::UNUSED,
+                guid: Guid::UNUSED,
+                first_lba: 0,
+                last_lba: 0,
+                attributes: 0,
+                name: [0; 36],
+            }; MAX_PARTITIONS],
+            count: 0,
+        };
+        // The array sits between the header and the usable sectors, after the primary header and
+        // before the backup one
+        let entries = header.number_of_partition_entries.get() as usize;
+        let array_size = entries as u64 * entry_size as u64;
+        if array_size > MAX_ENTRY_ARRAY_SIZE {
+            return Err(parsing_error(Fault::InvalidValueForField(
+                "number_of_partition_entries",
+            )));
+        }
+        let array_sectors = array_size.div_ceil(sector_size as u64);
+        let (array_start, array_end) = if lba < first_usable_lba {
+            (lba + 1, first_usable_lba)
+        } else if lba > last_usable_lba {
+            (last_usable_lba + 1, lba)
+        } else {
+            return Err(parsing_error(Fault::InvalidValueForField("my_lba")));
+        };
+        let partition_entry_lba = header.partition_entry_lba.get();
+        if partition_entry_lba < array_start
+            || partition_entry_lba
+                .checked_add(array_sectors)
+                .is_none_or(|end| end > array_end)
+        {
+            return Err(parsing_error(Fault::InvalidValueForField(
+                "partition_entry_lba",
+            )));
+        }
+        let entries_crc32 = header.partition_entry_array_crc32.get();
+        let mut crc = Crc32::new();
+        let mut entry_lba = partition_entry_lba;
+        let mut remaining = entries;
+        while remaining > 0 {
+            device.read_sectors(entry_lba, sector)?;
+            let in_sector = remaining.min(sector_size / entry_size);
+            for entry in sector[..in_sector * entry_size].chunks_exact(entry_size) {
+                crc.update(entry);
+                gpt.add_entry(entry)?;
+            }
+            remaining -= in_sector;
+            entry_lba += 1;
+        }
+        if crc.finish() != entries_crc32 {
+            return Err(parsing_error(Fault::InvalidValueForField(
+                "partition_entry_array_crc32",
+            )));
+        }
+        Ok(gpt)
+    }
+
+    fn add_entry(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let (entry, _) = inner::Entry::try_read_from_prefix(bytes)
+            .map_err(|err| try_read_error(Facility::Gpt, err))?;
+        let partition_type = Guid(entry.partition_type_guid);
+        if partition_type == Guid::UNUSED {
+            return Ok(());
+        }
+        let (first_lba, last_lba) = (entry.starting_lba.get(), entry.ending_lba.get());
+        if first_lba > last_lba
+            || first_lba < self.first_usable_lba
+            || last_lba > self.last_usable_lba
+        {
+            return Err(parsing_error(Fault::InvalidValueForField("starting_lba")));
+        }
+        let Some(partition) = self.partitions.get_mut(self.count) else {
+            return Err(parsing_error(Fault::TooManyPartitions(self.count + 1)));
+        };
+        *partition = Partition {
+            partition_type,
+            guid: Guid(entry.unique_partition_guid),
+            first_lba,
+            last_lba,
+            attributes: entry.attributes.get(),
+            name: entry.partition_name.map(|unit| unit.get()),
+        };
+        self.count += 1;
+        Ok(())
+    }
+
+    /// The used partitions, in the order of their entries
+    pub fn partitions(&self) -> &[Partition] {
+        &self.partitions[..self.count]
+    }
+
+    /// The first partition of type `partition_type`
+    pub fn find(&self, partition_type: Guid) -> Option<&Partition> {
+        self.partitions()
+            .iter()
+            .find(|partition| partition.partition_type == partition_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        block_device::BlockDevice,
+        error::Fault,
+        gpt::{BLOG_OS_KERNEL, EFI_SYSTEM_PARTITION, Gpt, LINUX_FILESYSTEM},
+        hash::crc32,
+        ram_disk::RamDisk,
+        test_support::TestWriter,
+    };
+
+    fn displayed(value: impl core::fmt::Display) -> TestWriter<64> {
+        let mut writer = TestWriter::<64>::new();
+        write!(writer, "{}", value).unwrap();
+        writer
+    }
+
+    const SECTOR: usize = 512;
+    const SECTORS: usize = 64;
+    const ENTRIES: usize = 8;
+    const ENTRY_SIZE: usize = 128;
+
+    /// Writes a table with a kernel and a Linux partition, in the sectors after `header_lba` or,
+    /// for the backup one, before it
+    fn write_table(disk: &mut [u8], header_lba: u64, alternate_lba: u64) {
+        let entry_lba = if header_lba == 1 {
+            2
+        } else {
+            header_lba - (ENTRIES * ENTRY_SIZE / SECTOR) as u64
+        };
+        let entries = &mut disk[entry_lba as usize * SECTOR..][..ENTRIES * ENTRY_SIZE];
+        for (index, (partition_type, first, last, name)) in [
+            (BLOG_OS_KERNEL, 6u64, 20u64, "kernel"),
+            (LINUX_FILESYSTEM, 21, 40, "root"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let entry = &mut entries[index * ENTRY_SIZE..][..ENTRY_SIZE];
+            entry[..16].copy_from_slice(&partition_type.0);
+            entry[16] = index as u8 + 1;
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            for (unit, character) in entry[56..].chunks_exact_mut(2).zip(name.encode_utf16()) {
+                unit.copy_from_slice(&character.to_le_bytes());
+            }
+        }
+        let entries_crc32 = crc32(entries);
+
+        let header = &mut disk[header_lba as usize * SECTOR..][..SECTOR];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&header_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&6u64.to_le_bytes());
+        header[48..56].copy_from_slice(&(SECTORS as u64 - 4).to_le_bytes());
+        header[56..72].fill(0xAA);
+        header[72..80].copy_from_slice(&entry_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRIES as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc32.to_le_bytes());
+        let header_crc32 = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&header_crc32.to_le_bytes());
+    }
+
+    /// Changes the header at `header_lba` and computes its CRC32 again
+    fn patch_header(disk: &mut [u8], header_lba: u64, offset: usize, bytes: &[u8]) {
+        let header = &mut disk[header_lba as usize * SECTOR..][..SECTOR];
+        header[offset..][..bytes.len()].copy_from_slice(bytes);
+        header[16..20].fill(0);
+        let header_crc32 = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&header_crc32.to_le_bytes());
+    }
+
+    fn disk() -> [u8; SECTORS * SECTOR] {
+        let mut disk = [0u8; SECTORS * SECTOR];
+        let last = SECTORS as u64 - 1;
+        write_table(&mut disk, 1, last);
+        write_table(&mut disk, last, 1);
+        disk
+    }
+
+    #[test]
+    fn reads_partitions() {
+        let mut bytes = disk();
+        let gpt = Gpt::read(&mut RamDisk::new(&mut bytes)).unwrap();
+        assert!(!gpt.from_backup);
+        assert_eq!(2, gpt.partitions().len());
+        let kernel = gpt.find(BLOG_OS_KERNEL).unwrap();
+        assert_eq!(
+            (6, 20, 15),
+            (kernel.first_lba, kernel.last_lba, kernel.sectors())
+        );
+        assert!(gpt.find(EFI_SYSTEM_PARTITION).is_none());
+        let partition = displayed(gpt.partitions()[1]);
+        assert_eq!(b"0x15-0x28 Linux filesystem \"root\"", partition.as_bytes());
+        let guid = displayed(EFI_SYSTEM_PARTITION);
+        assert_eq!(b"C12A7328-F81F-11D2-BA4B-00A0C93EC93B", guid.as_bytes());
+    }
+
+    #[test]
+    fn falls_back_to_the_backup() {
+        let mut bytes = disk();
+        // A partition entry of the primary table changes, which its CRC32 catches
+        bytes[2 * SECTOR + 32] = 7;
+        let mut disk = RamDisk::new(&mut bytes);
+        let gpt = Gpt::read(&mut disk).unwrap();
+        assert!(gpt.from_backup);
+        assert_eq!(6, gpt.partitions()[0].first_lba);
+
+        // And the header of the backup one too
+        let mut sector = [0u8; SECTOR];
+        let last = disk.sectors() - 1;
+        disk.read_sectors(last, &mut sector).unwrap();
+        sector[20] ^= 1;
+        disk.write_sectors(last, &sector).unwrap();
+        let err = Gpt::read(&mut disk).unwrap_err();
+        assert!(matches!(
+            err.fault(),
+            Fault::InvalidValueForField("partition_entry_array_crc32")
+        ));
+    }
+
+    #[test]
+    fn entry_arrays_out_of_place() {
+        let last = SECTORS as u64 - 1;
+        let read_at = |bytes: &mut [u8], lba| {
+            let err = Gpt::read_at(&mut RamDisk::new(bytes), lba).unwrap_err();
+            err.fault()
+        };
+
+        // Way more entries than fit before the partitions, or than are worth reading
+        let mut bytes = disk();
+        patch_header(&mut bytes, 1, 80, &u32::MAX.to_le_bytes());
+        assert!(matches!(
+            read_at(&mut bytes, 1),
+            Fault::InvalidValueForField("number_of_partition_entries")
+        ));
+        assert!(
+            Gpt::read(&mut RamDisk::new(&mut bytes))
+                .unwrap()
+                .from_backup
+        );
+
+        let mut bytes = disk();
+        patch_header(&mut bytes, 1, 80, &64u32.to_le_bytes());
+        assert!(matches!(
+            read_at(&mut bytes, 1),
+            Fault::InvalidValueForField("partition_entry_lba")
+        ));
+
+        // Arrays reaching into the partitions, or past the backup header
+        let mut bytes = disk();
+        patch_header(&mut bytes, 1, 72, &5u64.to_le_bytes());
+        assert!(matches!(
+            read_at(&mut bytes, 1),
+            Fault::InvalidValueForField("partition_entry_lba")
+        ));
+        patch_header(&mut bytes, last, 72, &62u64.to_le_bytes());
+        assert!(matches!(
+            read_at(&mut bytes, last),
+            Fault::InvalidValueForField("partition_entry_lba")
+        ));
+        patch_header(&mut bytes, last, 72, &0u64.to_le_bytes());
+        assert!(matches!(
+            read_at(&mut bytes, last),
+            Fault::InvalidValueForField("partition_entry_lba")
+        ));
+    }
+}
@@ -0,0 +1,333 @@
+// Checksums and digests: CRC32, the one of Ethernet, zlib and GPT headers, for catching corruption,
+// and SHA-256, for checking that data is exactly what it's claimed to be. Both can be fed their
+// input a piece at a time, e.g. as it's read off a disk
+// https://www.rfc-editor.org/rfc/rfc1952#section-8
+// https://nvlpubs.nist.gov/nistpubs/FIPS/NIST.FIPS.180-4.pdf
+
+// The reflected form of the IEEE 802.3 polynomial
+const CRC32_POLYNOMIAL: u32 = 0xEDB8_8320;
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+pub const SHA256_DIGEST_SIZE: usize = 32;
+const SHA256_BLOCK_SIZE: usize = 64;
+// The first 32 bits of the fractional parts of the square roots of the first 8 primes
+const SHA256_INITIAL_STATE: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+// The first 32 bits of the fractional parts of the cube roots of the first 64 primes
+const SHA256_ROUND_CONSTANTS: [u32; 64] = [
+    0x428a_2f98,
+    0x7137_4491,
+    0xb5c0_fbcf,
+    0xe9b5_dba5,
+    0x3956_c25b,
+    0x59f1_11f1,
+    0x923f_82a4,
+    0xab1c_5ed5,
+    0xd807_aa98,
+    0x1283_5b01,
+    0x2431_85be,
+    0x550c_7dc3,
+    0x72be_5d74,
+    0x80de_b1fe,
+    0x9bdc_06a7,
+    0xc19b_f174,
+    0xe49b_69c1,
+    0xefbe_4786,
+    0x0fc1_9dc6,
+    0x240c_a1cc,
+    0x2de9_2c6f,
+    0x4a74_84aa,
+    0x5cb0_a9dc,
+    0x76f9_88da,
+    0x983e_5152,
+    0xa831_c66d,
+    0xb003_27c8,
+    0xbf59_7fc7,
+    0xc6e0_0bf3,
+    0xd5a7_9147,
+    0x06ca_6351,
+    0x1429_2967,
+    0x27b7_0a85,
+    0x2e1b_2138,
+    0x4d2c_6dfc,
+    0x5338_0d13,
+    0x650a_7354,
+    0x766a_0abb,
+    0x81c2_c92e,
+    0x9272_2c85,
+    0xa2bf_e8a1,
+    0xa81a_664b,
+    0xc24b_8b70,
+    0xc76c_51a3,
+    0xd192_e819,
+    0xd699_0624,
+    0xf40e_3585,
+    0x106a_a070,
+    0x19a4_c116,
+    0x1e37_6c08,
+    0x2748_774c,
+    0x34b0_bcb5,
+    0x391c_0cb3,
+    0x4ed8_aa4a,
+    0x5b9c_ca4f,
+    0x682e_6ff3,
+    0x748f_82ee,
+    0x78a5_636f,
+    0x84c8_7814,
+    0x8cc7_0208,
+    0x90be_fffa,
+    0xa450_6ceb,
+    0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+/// The CRC of each byte value, so that the CRC is updated a byte at a time rather than a bit
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut byte = 0;
+    while byte < table.len() {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// A CRC32 being computed
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self { crc: !0 }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.crc = CRC32_TABLE[((self.crc ^ byte as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+/// A SHA-256 digest being computed
+#[derive(Debug, Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    // What's left of the input that doesn't make a whole block yet
+    block: [u8; SHA256_BLOCK_SIZE],
+    block_length: usize,
+    length: u64,
+}
+
+impl Sha256 {
+    pub const fn new() -> Self {
+        Self {
+            state: SHA256_INITIAL_STATE,
+            block: [0; SHA256_BLOCK_SIZE],
+            block_length: 0,
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.length = self.length.wrapping_add(bytes.len() as u64);
+        while !bytes.is_empty() {
+            let taken = bytes.len().min(SHA256_BLOCK_SIZE - self.block_length);
+            self.block[self.block_length..self.block_length + taken]
+                .copy_from_slice(&bytes[..taken]);
+            self.block_length += taken;
+            bytes = &bytes[taken..];
+            if self.block_length == SHA256_BLOCK_SIZE {
+                let block = self.block;
+                self.compress(&block);
+                self.block_length = 0;
+            }
+        }
+    }
+
+    /// Pads the input, with a 1 bit, zeroes and its length in bits, and returns the digest
+    pub fn finish(mut self) -> [u8; SHA256_DIGEST_SIZE] {
+        let length_bits = self.length.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.block_length != SHA256_BLOCK_SIZE - size_of::<u64>() {
+            self.update(&[0]);
+        }
+        self.update(&length_bits.to_be_bytes());
+
+        let mut digest = [0u8; SHA256_DIGEST_SIZE];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; SHA256_BLOCK_SIZE]) {
+        let mut schedule = [0u32; 64];
+        for (word, bytes) in schedule.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..schedule.len() {
+            let s0 = schedule[i - 15].rotate_right(7)
+                ^ schedule[i - 15].rotate_right(18)
+                ^ (schedule[i - 15] >> 3);
+            let s1 = schedule[i - 2].rotate_right(17)
+                ^ schedule[i - 2].rotate_right(19)
+                ^ (schedule[i - 2] >> 10);
+            schedule[i] = schedule[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(schedule[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for (word, constant) in schedule.into_iter().zip(SHA256_ROUND_CONSTANTS) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(constant)
+                .wrapping_add(word);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(majority);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (state, word) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(word);
+        }
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn sha256(bytes: &[u8]) -> [u8; SHA256_DIGEST_SIZE] {
+    let mut sha256 = Sha256::new();
+    sha256.update(bytes);
+    sha256.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::{Crc32, SHA256_DIGEST_SIZE, Sha256, crc32, sha256};
+
+    fn digest(words: [u32; 8]) -> [u8; SHA256_DIGEST_SIZE] {
+        let mut digest = [0u8; SHA256_DIGEST_SIZE];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(words) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    #[test]
+    fn crc32_check_values() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+        assert_eq!(
+            0x414F_A339,
+            crc32(b"The quick brown fox jumps over the lazy dog")
+        );
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(0xCBF4_3926, crc.finish());
+    }
+
+    #[test]
+    fn sha256_test_vectors() {
+        // FIPS 180-2, appendix B
+        assert_eq!(
+            digest([
+                0xba78_16bf,
+                0x8f01_cfea,
+                0x4141_40de,
+                0x5dae_2223,
+                0xb003_61a3,
+                0x9617_7a9c,
+                0xb410_ff61,
+                0xf200_15ad
+            ]),
+            sha256(b"abc")
+        );
+        let two_blocks = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        let expected = digest([
+            0x248d_6a61,
+            0xd206_38b8,
+            0xe5c0_2693,
+            0x0c3e_6039,
+            0xa33c_e459,
+            0x64ff_2167,
+            0xf6ec_edd4,
+            0x19db_06c1,
+        ]);
+        assert_eq!(expected, sha256(two_blocks));
+        let mut sha256 = Sha256::new();
+        for piece in two_blocks.chunks(7) {
+            sha256.update(piece);
+        }
+        assert_eq!(expected, sha256.finish());
+    }
+
+    #[test]
+    fn sha256_of_nothing() {
+        assert_eq!(
+            digest([
+                0xe3b0_c442,
+                0x98fc_1c14,
+                0x9afb_f4c8,
+                0x996f_b924,
+                0x27ae_41e4,
+                0x649b_934c,
+                0xa495_991b,
+                0x7852_b855
+            ]),
+            sha256(b"")
+        );
+    }
+}
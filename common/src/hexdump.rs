@@ -0,0 +1,101 @@
+use core::fmt::{self, Write};
+
+pub const BYTES_PER_LINE: usize = 16;
+
+/// Writes `bytes` as lines of offset, hex bytes and printable ASCII, like `hexdump -C` does.
+/// Offsets start at `base_address`, and are printed with 8 digits when they fit in 32 bits, so
+/// that a line fits in the 80 columns of the VGA console. Lines are separated by newlines, with no
+/// newline after the last one
+pub fn hexdump(writer: &mut impl Write, base_address: u64, bytes: &[u8]) -> fmt::Result {
+    let end_address = base_address.saturating_add(bytes.len() as u64);
+    let address_digits = if end_address <= u32::MAX as u64 {
+        8
+    } else {
+        16
+    };
+
+    for (i, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        if i > 0 {
+            writeln!(writer)?;
+        }
+        write!(
+            writer,
+            "{:0address_digits$x} ",
+            base_address.wrapping_add((i * BYTES_PER_LINE) as u64)
+        )?;
+        for j in 0..BYTES_PER_LINE {
+            match line.get(j) {
+                Some(byte) => write!(writer, " {byte:02x}")?,
+                None => write!(writer, "   ")?,
+            }
+        }
+        write!(writer, "  |")?;
+        for byte in line {
+            writer.write_char(if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            })?;
+        }
+        write!(writer, "|")?;
+    }
+    Ok(())
+}
+
+/// Formats the wrapped bytes with `hexdump`, for use with the `writeln_no_sync` macros
+pub struct HexDump<'a> {
+    base_address: u64,
+    bytes: &'a [u8],
+}
+
+impl<'a> HexDump<'a> {
+    pub fn new(base_address: u64, bytes: &'a [u8]) -> Self {
+        Self {
+            base_address,
+            bytes,
+        }
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        hexdump(f, self.base_address, self.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        hexdump::{HexDump, hexdump},
+        test_support::TestWriter,
+    };
+
+    #[test]
+    fn partial_last_line() {
+        let mut writer = TestWriter::<512>::new();
+        hexdump(&mut writer, 0x7c00, b"Hello, world!\n\x00\xffstage2").unwrap();
+        assert_eq!(
+            "00007c00  48 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 0a 00 ff  |Hello, world!...|\n\
+             00007c10  73 74 61 67 65 32                                |stage2|",
+            writer.as_str()
+        );
+    }
+
+    #[test]
+    fn wide_addresses_and_display() {
+        let mut writer = TestWriter::<512>::new();
+        write!(
+            writer,
+            "{}",
+            HexDump::new(0xffff_8000_0000_0000, &[0xde, 0xad])
+        )
+        .unwrap();
+        assert!(writer.as_str().starts_with("ffff800000000000  de ad    "));
+
+        let mut writer = TestWriter::<512>::new();
+        hexdump(&mut writer, 0, &[]).unwrap();
+        assert_eq!("", writer.as_str());
+    }
+}
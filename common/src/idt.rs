@@ -1,6 +1,6 @@
 use core::mem::transmute;
 
-use crate::{make_bitmap, protection::PrivilegeLevel};
+use crate::{error::Fault, make_bitmap, protection::PrivilegeLevel, tss};
 
 #[repr(C, packed)]
 #[derive(Debug)]
@@ -65,6 +65,19 @@ pub enum Interrupt {
     UserDefinedLast = 255,
 }
 
+/// The mnemonic of the exception on `vector`, e.g. "#PF" for 14. None for the reserved vectors
+/// and the ones past the exceptions
+pub fn exception_mnemonic(vector: u8) -> Option<&'static str> {
+    const MNEMONICS: [&str; 22] = [
+        "#DE", "#DB", "NMI", "#BP", "#OF", "#BR", "#UD", "#NM", "#DF", "", "#TS", "#NP", "#SS",
+        "#GP", "#PF", "", "#MF", "#AC", "#MC", "#XM", "#VE", "#CP",
+    ];
+    MNEMONICS
+        .get(vector as usize)
+        .copied()
+        .filter(|mnemonic| !mnemonic.is_empty())
+}
+
 #[allow(unused)]
 #[repr(u16)]
 pub enum GateDescriptorBit {
@@ -77,7 +90,13 @@ make_bitmap!(new_type: GateDescriptorFlags, underlying_flag_type: GateDescriptor
 impl GateDescriptorFlags {
     pub fn set_privilege_level(&mut self, privilege_level: PrivilegeLevel) {
         self.bits &= !0x60_00;
-        self.bits |= (privilege_level as u16) << 12;
+        self.bits |= (privilege_level as u16) << 13;
+    }
+
+    /// The stack of the interrupt stack table the gate switches to, 0 for none. Long mode only
+    pub fn set_interrupt_stack(&mut self, index: u8) {
+        self.bits &= !0b111;
+        self.bits |= index as u16 & 0b111;
     }
 }
 
@@ -126,4 +145,211 @@ impl InterruptGateDescriptor {
             ..Default::default()
         }
     }
+
+    /// Lets `int n` reach the gate from `privilege_level` up, e.g. Ring3 for software interrupts
+    /// user programs raise. Exceptions and hardware interrupts ignore it
+    pub fn with_privilege_level(mut self, privilege_level: PrivilegeLevel) -> Self {
+        // Packed, no references to the field
+        let mut flags = self.flags;
+        flags.set_privilege_level(privilege_level);
+        self.flags = flags;
+        self
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct LongModeIDTDescriptor {
+    size: u16,
+    address: u64,
+}
+
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub struct LongModeGateDescriptor(u128);
+
+impl LongModeGateDescriptor {
+    pub const fn blank() -> Self {
+        Self(0)
+    }
+}
+
+pub type LongModeIDT<const N: usize> = [LongModeGateDescriptor; N];
+
+impl<const N: usize> From<&LongModeIDT<N>> for LongModeIDTDescriptor {
+    fn from(value: &LongModeIDT<N>) -> Self {
+        Self {
+            address: value.as_ptr() as u64,
+            size: size_of::<LongModeIDT<N>>() as u16 - 1,
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct LongModeInterruptGateDescriptor {
+    offset_low: u16,
+    segment_selector: u16,
+    flags: GateDescriptorFlags,
+    offset_mid: u16,
+    offset_hi: u32,
+    reserved: u32,
+}
+
+impl Default for LongModeInterruptGateDescriptor {
+    /// Present, Descriptor Privilege Level = 0, 64-bit interrupt gate, no IST
+    fn default() -> Self {
+        let InterruptGateDescriptor { flags, .. } = InterruptGateDescriptor::default();
+        Self {
+            reserved: Default::default(),
+            offset_hi: Default::default(),
+            offset_mid: Default::default(),
+            flags,
+            segment_selector: Default::default(),
+            offset_low: Default::default(),
+        }
+    }
+}
+
+impl From<LongModeInterruptGateDescriptor> for LongModeGateDescriptor {
+    fn from(value: LongModeInterruptGateDescriptor) -> Self {
+        LongModeGateDescriptor(
+            // SAFETY: `LongModeInterruptGateDescriptor` is a `#[repr(C, packed)]` struct with the
+            // same size as a `u128`, so this is safe.
+            unsafe { transmute::<LongModeInterruptGateDescriptor, u128>(value) },
+        )
+    }
+}
+
+impl LongModeInterruptGateDescriptor {
+    pub fn with_address_and_segment_selector(address: u64, segment_selector: u16) -> Self {
+        Self {
+            offset_hi: (address >> 32) as u32,
+            offset_mid: (address >> 16) as u16,
+            segment_selector,
+            offset_low: address as u16,
+            ..Default::default()
+        }
+    }
+
+    /// See `InterruptGateDescriptor::with_privilege_level`
+    pub fn with_privilege_level(mut self, privilege_level: PrivilegeLevel) -> Self {
+        // Packed, no references to the field
+        let mut flags = self.flags;
+        flags.set_privilege_level(privilege_level);
+        self.flags = flags;
+        self
+    }
+
+    /// Has the CPU switch to stack `index` of the TSS' interrupt stack table before pushing
+    /// anything, whatever the stack it was on, e.g. for double faults. See
+    /// `tss::LongModeTaskStateSegment::set_interrupt_stack`
+    pub fn with_interrupt_stack(mut self, index: u8) -> Result<Self, Fault> {
+        if !(1..=tss::INTERRUPT_STACKS).contains(&index) {
+            return Err(Fault::NoSuchInterruptStack(index));
+        }
+        let mut flags = self.flags;
+        flags.set_interrupt_stack(index);
+        self.flags = flags;
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Fault,
+        idt::{
+            GateDescriptor, Interrupt, InterruptGateDescriptor, LongModeGateDescriptor,
+            LongModeInterruptGateDescriptor, exception_mnemonic,
+        },
+        protection::PrivilegeLevel,
+    };
+
+    #[test]
+    fn exception_mnemonics() {
+        assert_eq!(Some("#PF"), exception_mnemonic(Interrupt::PageFault as u8));
+        assert_eq!(
+            Some("#MC"),
+            exception_mnemonic(Interrupt::MachineCheck as u8)
+        );
+        assert_eq!(
+            Some("#CP"),
+            exception_mnemonic(Interrupt::ControlProtectionException as u8)
+        );
+        assert_eq!(None, exception_mnemonic(Interrupt::IntelReserved as u8));
+        assert_eq!(None, exception_mnemonic(Interrupt::UserDefinedFirst as u8));
+    }
+
+    #[test]
+    fn protected_mode_interrupt_gate() {
+        let gate: GateDescriptor =
+            InterruptGateDescriptor::with_address_and_segment_selector(0x0012_3456, 0x08).into();
+        assert_eq!([0x56, 0x34, 0x08, 0, 0, 0x8e, 0x12, 0], unsafe {
+            core::mem::transmute::<GateDescriptor, [u8; 8]>(gate)
+        });
+    }
+
+    #[test]
+    fn long_mode_interrupt_gate() {
+        let gate: LongModeGateDescriptor =
+            LongModeInterruptGateDescriptor::with_address_and_segment_selector(
+                0xffff_8000_0012_3456,
+                0x18,
+            )
+            .into();
+        assert_eq!(
+            [
+                0x56, 0x34, 0x18, 0, 0, 0x8e, 0x12, 0, 0, 0x80, 0xff, 0xff, 0, 0, 0, 0
+            ],
+            unsafe { core::mem::transmute::<LongModeGateDescriptor, [u8; 16]>(gate) }
+        );
+    }
+
+    #[test]
+    fn gates_with_an_interrupt_stack() {
+        let gate: LongModeGateDescriptor =
+            LongModeInterruptGateDescriptor::with_address_and_segment_selector(
+                0xffff_8000_0012_3456,
+                0x08,
+            )
+            .with_interrupt_stack(1)
+            .unwrap()
+            .into();
+        assert_eq!(
+            [
+                0x56, 0x34, 0x08, 0, 1, 0x8e, 0x12, 0, 0, 0x80, 0xff, 0xff, 0, 0, 0, 0
+            ],
+            unsafe { core::mem::transmute::<LongModeGateDescriptor, [u8; 16]>(gate) }
+        );
+        assert!(matches!(
+            LongModeInterruptGateDescriptor::default().with_interrupt_stack(8),
+            Err(Fault::NoSuchInterruptStack(8))
+        ));
+    }
+
+    #[test]
+    fn user_gates() {
+        let gate: GateDescriptor =
+            InterruptGateDescriptor::with_address_and_segment_selector(0x0012_3456, 0x08)
+                .with_privilege_level(PrivilegeLevel::Ring3)
+                .into();
+        assert_eq!([0x56, 0x34, 0x08, 0, 0, 0xee, 0x12, 0], unsafe {
+            core::mem::transmute::<GateDescriptor, [u8; 8]>(gate)
+        });
+
+        let gate: LongModeGateDescriptor =
+            LongModeInterruptGateDescriptor::with_address_and_segment_selector(
+                0xffff_8000_0012_3456,
+                0x18,
+            )
+            .with_privilege_level(PrivilegeLevel::Ring3)
+            .into();
+        assert_eq!(
+            [
+                0x56, 0x34, 0x18, 0, 0, 0xee, 0x12, 0, 0, 0x80, 0xff, 0xff, 0, 0, 0, 0
+            ],
+            unsafe { core::mem::transmute::<LongModeGateDescriptor, [u8; 16]>(gate) }
+        );
+    }
 }
@@ -0,0 +1,50 @@
+use core::arch::asm;
+
+const INTERRUPT_ENABLE_FLAG: usize = 1 << 9;
+
+pub fn are_enabled() -> bool {
+    let flags: usize;
+    // SAFETY: Pushing and popping the flags register has no side effects
+    unsafe {
+        asm!("pushf", "pop {flags}", flags = out(reg) flags, options(preserves_flags));
+    }
+    flags & INTERRUPT_ENABLE_FLAG != 0
+}
+
+pub fn enable() {
+    // SAFETY: It is assumed that the IDT was set up before enabling interrupts
+    unsafe {
+        asm!("sti", options(nomem, nostack));
+    }
+}
+
+pub fn disable() {
+    // SAFETY: Masking interrupts can't break memory safety
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+    }
+}
+
+/// Enables interrupts and halts until the next one. sti only takes effect after the instruction
+/// that follows it, so an interrupt that's pending while they're still disabled ends the halt
+/// rather than coming before it
+pub fn enable_and_halt() {
+    // SAFETY: It is assumed that the IDT was set up before enabling interrupts, and halting has no
+    // side effects
+    unsafe {
+        asm!("sti", "hlt", options(nomem, nostack));
+    }
+}
+
+/// Runs `f` with interrupts disabled, restoring the previous state afterwards
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let were_enabled = are_enabled();
+    if were_enabled {
+        disable();
+    }
+    let result = f();
+    if were_enabled {
+        enable();
+    }
+    result
+}
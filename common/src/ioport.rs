@@ -51,6 +51,18 @@ impl Port {
         result
     }
 
+    pub fn readw(&self) -> u16 {
+        let result: u16;
+        // SAFETY: It is assumed that the user initialised this port with a valid port number
+        unsafe {
+            asm! {
+                "in ax, dx", in("dx") self.port_number, out("ax") result,
+                options(nomem, nostack, preserves_flags)
+            }
+        }
+        result
+    }
+
     pub fn readd(&self) -> u32 {
         let result: u32;
         // SAFETY: It is assumed that the user initialised this port with a valid port number
@@ -72,11 +84,276 @@ impl Port {
             asm!("rep insw",
                 in("dx") self.port_number,
                 in("edi") output_buffer.as_mut_ptr(),
-                // u16 is the size of word
-                in("cx") n_words,
+                // u16 is the size of word. Widened to the native register size since rep uses the
+                // whole of rcx in 64-bit mode
+                in("cx") n_words as usize,
                 options(nostack, preserves_flags)
             );
         }
         Ok(())
     }
 }
+
+/// I/O ports by number, for drivers to be generic over so that their command sequences can be
+/// tested on the host, with `mock::MockPorts` in place of `IoPorts`
+pub trait PortIo {
+    fn readb(&self, port_number: u16) -> u8;
+
+    fn readw(&self, port_number: u16) -> u16;
+
+    fn writeb(&self, port_number: u16, byte: u8);
+
+    fn writew(&self, port_number: u16, word: u16);
+
+    /// Reads `n_words` words into `output_buffer`, which must hold exactly that many, like
+    /// `Port::rep_insw`
+    fn rep_insw(
+        &self,
+        port_number: u16,
+        output_buffer: &mut [u8],
+        n_words: u16,
+    ) -> Result<(), u16> {
+        if output_buffer.len() / size_of::<u16>() != n_words as usize {
+            return Err(n_words);
+        }
+        for word in output_buffer.chunks_exact_mut(size_of::<u16>()) {
+            word.copy_from_slice(&self.readw(port_number).to_le_bytes());
+        }
+        Ok(())
+    }
+
+    /// The port numbered `port_number`, with the same methods as a `Port`
+    fn port(&self, port_number: u16) -> PortOf<'_, Self>
+    where
+        Self: Sized,
+    {
+        PortOf {
+            io: self,
+            port_number,
+        }
+    }
+}
+
+/// The actual I/O ports, through the in and out instructions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoPorts;
+
+impl PortIo for IoPorts {
+    fn readb(&self, port_number: u16) -> u8 {
+        Port::new(port_number).readb()
+    }
+
+    fn readw(&self, port_number: u16) -> u16 {
+        Port::new(port_number).readw()
+    }
+
+    fn writeb(&self, port_number: u16, byte: u8) {
+        Port::new(port_number).writeb(byte);
+    }
+
+    fn writew(&self, port_number: u16, word: u16) {
+        Port::new(port_number).writew(word);
+    }
+
+    fn rep_insw(
+        &self,
+        port_number: u16,
+        output_buffer: &mut [u8],
+        n_words: u16,
+    ) -> Result<(), u16> {
+        Port::new(port_number).rep_insw(output_buffer, n_words)
+    }
+}
+
+impl<P: PortIo> PortIo for &P {
+    fn readb(&self, port_number: u16) -> u8 {
+        (*self).readb(port_number)
+    }
+
+    fn readw(&self, port_number: u16) -> u16 {
+        (*self).readw(port_number)
+    }
+
+    fn writeb(&self, port_number: u16, byte: u8) {
+        (*self).writeb(port_number, byte);
+    }
+
+    fn writew(&self, port_number: u16, word: u16) {
+        (*self).writew(port_number, word);
+    }
+
+    fn rep_insw(
+        &self,
+        port_number: u16,
+        output_buffer: &mut [u8],
+        n_words: u16,
+    ) -> Result<(), u16> {
+        (*self).rep_insw(port_number, output_buffer, n_words)
+    }
+}
+
+/// A port of some `PortIo`, see `PortIo::port`
+pub struct PortOf<'a, P> {
+    io: &'a P,
+    port_number: u16,
+}
+
+impl<P: PortIo> PortOf<'_, P> {
+    pub fn writeb(&self, byte: u8) {
+        self.io.writeb(self.port_number, byte);
+    }
+
+    pub fn writew(&self, word: u16) {
+        self.io.writew(self.port_number, word);
+    }
+
+    pub fn readb(&self) -> u8 {
+        self.io.readb(self.port_number)
+    }
+
+    pub fn readw(&self) -> u16 {
+        self.io.readw(self.port_number)
+    }
+
+    pub fn rep_insw(&self, output_buffer: &mut [u8], n_words: u16) -> Result<(), u16> {
+        self.io.rep_insw(self.port_number, output_buffer, n_words)
+    }
+}
+
+/// Ports for host tests, which record what's written to them. Reads return the next bytes of the
+/// data a port was fed, else what it was stubbed with, else what was last written to it, so that
+/// e.g. a UART passes its loopback test, else 0
+#[cfg(test)]
+pub mod mock {
+    use core::cell::{Cell, Ref, RefCell};
+
+    use crate::ioport::PortIo;
+
+    const MAX_WRITES: usize = 128;
+    const MAX_PORTS: usize = 16;
+
+    #[derive(Clone, Copy)]
+    struct Register {
+        port_number: u16,
+        value: u16,
+        stubbed: bool,
+    }
+
+    struct Writes {
+        entries: [(u16, u16); MAX_WRITES],
+        length: usize,
+    }
+
+    pub struct MockPorts<'a> {
+        writes: RefCell<Writes>,
+        registers: RefCell<[Option<Register>; MAX_PORTS]>,
+        data: Cell<Option<(u16, &'a [u8])>>,
+    }
+
+    impl<'a> MockPorts<'a> {
+        pub fn new() -> Self {
+            Self {
+                writes: RefCell::new(Writes {
+                    entries: [(0, 0); MAX_WRITES],
+                    length: 0,
+                }),
+                registers: RefCell::new([None; MAX_PORTS]),
+                data: Cell::new(None),
+            }
+        }
+
+        /// Makes reads of `port_number` return `value`, whatever is written to it
+        pub fn stub(&self, port_number: u16, value: u16) {
+            self.set(port_number, value, true);
+        }
+
+        /// Makes word reads of `port_number` return `data`, two bytes at a time, until it runs out
+        pub fn feed(&self, port_number: u16, data: &'a [u8]) {
+            self.data.set(Some((port_number, data)));
+        }
+
+        /// Every write so far as the port number and the value, oldest first
+        pub fn writes(&self) -> Ref<'_, [(u16, u16)]> {
+            Ref::map(self.writes.borrow(), |writes| {
+                &writes.entries[..writes.length]
+            })
+        }
+
+        pub fn clear_writes(&self) {
+            self.writes.borrow_mut().length = 0;
+        }
+
+        fn set(&self, port_number: u16, value: u16, stubbed: bool) {
+            let mut registers = self.registers.borrow_mut();
+            if let Some(register) = registers
+                .iter_mut()
+                .flatten()
+                .find(|register| register.port_number == port_number)
+            {
+                if stubbed || !register.stubbed {
+                    register.value = value;
+                    register.stubbed = stubbed;
+                }
+                return;
+            }
+            let free = registers
+                .iter_mut()
+                .find(|register| register.is_none())
+                .expect("too many ports for the mock");
+            *free = Some(Register {
+                port_number,
+                value,
+                stubbed,
+            });
+        }
+
+        fn read(&self, port_number: u16, size: usize) -> u16 {
+            if let Some((data_port_number, data)) = self.data.get()
+                && data_port_number == port_number
+                && let Some((bytes, rest)) = data.split_at_checked(size)
+            {
+                self.data.set(Some((data_port_number, rest)));
+                return bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |word, byte| (word << 8) | u16::from(*byte));
+            }
+            self.registers
+                .borrow()
+                .iter()
+                .flatten()
+                .find(|register| register.port_number == port_number)
+                .map_or(0, |register| register.value)
+        }
+
+        fn write(&self, port_number: u16, value: u16) {
+            let mut writes = self.writes.borrow_mut();
+            let length = writes.length;
+            *writes
+                .entries
+                .get_mut(length)
+                .expect("too many writes for the mock") = (port_number, value);
+            writes.length += 1;
+            drop(writes);
+            self.set(port_number, value, false);
+        }
+    }
+
+    impl PortIo for MockPorts<'_> {
+        fn readb(&self, port_number: u16) -> u8 {
+            self.read(port_number, size_of::<u8>()) as u8
+        }
+
+        fn readw(&self, port_number: u16) -> u16 {
+            self.read(port_number, size_of::<u16>())
+        }
+
+        fn writeb(&self, port_number: u16, byte: u8) {
+            self.write(port_number, byte.into());
+        }
+
+        fn writew(&self, port_number: u16, word: u16) {
+            self.write(port_number, word);
+        }
+    }
+}
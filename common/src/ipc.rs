@@ -0,0 +1,415 @@
+// Ways for tasks to talk to each other: pipes, for a stream of bytes from a writer to a reader, and
+// message queues, for messages that keep their boundaries. Both are fixed-capacity, usable in
+// statics and without an allocator. Each side has a wait queue the other side wakes when it makes
+// room or brings something to read, which the `_blocking` functions wait on. Until there are
+// tasks, the other side can only be an interrupt handler, or whatever a timer callback runs. The
+// `_waiting` functions run a `wait` of the caller's instead, for callers that can make the other
+// side progress themselves, e.g. by polling a device
+use crate::{
+    error::Fault,
+    ring_buffer::RingBuffer,
+    wait_queue::{self, WaitQueue},
+};
+
+/// A byte stream with a capacity of `N` bytes. Writes fail once the reader is gone, and reads see
+/// the end of the stream once the writer is gone and everything it wrote was read
+#[derive(Debug, Clone)]
+pub struct Pipe<const N: usize> {
+    bytes: RingBuffer<u8, N>,
+    reader_closed: bool,
+    writer_closed: bool,
+    // Woken when there's something new to read, or the writer is gone
+    readable: WaitQueue,
+    // Woken when there's room to write again, or the reader is gone
+    writable: WaitQueue,
+}
+
+impl<const N: usize> Pipe<N> {
+    pub const fn new() -> Self {
+        Self {
+            bytes: RingBuffer::new(0),
+            reader_closed: false,
+            writer_closed: false,
+            readable: WaitQueue::new(),
+            writable: WaitQueue::new(),
+        }
+    }
+
+    /// Writes as much of `bytes` as there's room for, returning how much that was, 0 if the pipe
+    /// is full
+    pub fn write(&mut self, bytes: &[u8]) -> Result<usize, Fault> {
+        if self.reader_closed || self.writer_closed {
+            return Err(Fault::BrokenPipe);
+        }
+        let written = bytes.len().min(N - self.bytes.len());
+        for &byte in &bytes[..written] {
+            let _ = self.bytes.push(byte);
+        }
+        if written != 0 {
+            self.readable.wake();
+        }
+        Ok(written)
+    }
+
+    /// Writes all of `bytes`, running `wait` whenever the pipe is full
+    pub fn write_all_waiting(
+        &mut self,
+        mut bytes: &[u8],
+        mut wait: impl FnMut(&mut Self),
+    ) -> Result<(), Fault> {
+        while !bytes.is_empty() {
+            let written = self.write(bytes)?;
+            bytes = &bytes[written..];
+            if written == 0 {
+                wait(self);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads what's in the pipe into `buffer`, as much as fits, returning how much that was. 0
+    /// means there's nothing to read yet, or, if `is_at_end`, ever
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let mut read = 0;
+        for slot in buffer.iter_mut() {
+            let Some(byte) = self.bytes.pop() else {
+                break;
+            };
+            *slot = byte;
+            read += 1;
+        }
+        if read != 0 {
+            self.writable.wake();
+        }
+        read
+    }
+
+    /// Reads at least a byte into `buffer`, running `wait` while there's nothing to read. Returns
+    /// 0 only at the end of the stream, or for an empty `buffer`
+    pub fn read_waiting(&mut self, buffer: &mut [u8], mut wait: impl FnMut(&mut Self)) -> usize {
+        loop {
+            let read = self.read(buffer);
+            if read != 0 || buffer.is_empty() || self.is_at_end() {
+                return read;
+            }
+            wait(self);
+        }
+    }
+
+    /// Whether the writer is gone and everything it wrote was read
+    pub fn is_at_end(&self) -> bool {
+        self.writer_closed && self.bytes.is_empty()
+    }
+
+    /// How many bytes are waiting to be read
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Whether there was something new to read, or the writer left, since the last call, for
+    /// `wait_queue::wait_until` to wait for it with
+    pub fn take_readable_wakeup(&mut self) -> bool {
+        self.readable.take_wakeup()
+    }
+
+    /// Whether room was made to write, or the reader left, since the last call
+    pub fn take_writable_wakeup(&mut self) -> bool {
+        self.writable.take_wakeup()
+    }
+
+    /// The reader is gone: what's in the pipe is dropped, and writes fail from now on
+    pub fn close_reader(&mut self) {
+        self.reader_closed = true;
+        self.bytes.clear();
+        self.writable.wake();
+    }
+
+    /// The writer is gone: the reader gets what's left, and then the end of the stream
+    pub fn close_writer(&mut self) {
+        self.writer_closed = true;
+        self.readable.wake();
+    }
+
+    /// Reads at least a byte from `pipe` into `buffer`, halting until there's one. Returns 0 only
+    /// at the end of the stream, or for an empty `buffer`
+    ///
+    /// # Safety
+    /// `pipe` has to be valid until this returns, and whatever else uses it, e.g. an interrupt
+    /// handler, may only do so with interrupts disabled
+    pub unsafe fn read_blocking(pipe: *mut Self, buffer: &mut [u8]) -> usize {
+        wait_queue::wait_until(|| {
+            // SAFETY: the caller guarantees `pipe` is valid, and interrupts are disabled while
+            // this runs, so nothing else uses it
+            let pipe = unsafe { &mut *pipe };
+            pipe.readable.take_wakeup();
+            let read = pipe.read(buffer);
+            (read != 0 || buffer.is_empty() || pipe.is_at_end()).then_some(read)
+        })
+    }
+
+    /// Writes all of `bytes` to `pipe`, halting whenever it's full
+    ///
+    /// # Safety
+    /// See `read_blocking`
+    pub unsafe fn write_all_blocking(pipe: *mut Self, mut bytes: &[u8]) -> Result<(), Fault> {
+        wait_queue::wait_until(|| {
+            // SAFETY: see read_blocking
+            let pipe = unsafe { &mut *pipe };
+            pipe.writable.take_wakeup();
+            match pipe.write(bytes) {
+                Ok(written) => {
+                    bytes = &bytes[written..];
+                    bytes.is_empty().then_some(Ok(()))
+                }
+                Err(fault) => Some(Err(fault)),
+            }
+        })
+    }
+}
+
+impl<const N: usize> Default for Pipe<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A message of up to `LEN` bytes
+#[derive(Debug, Clone, Copy)]
+pub struct Message<const LEN: usize> {
+    bytes: [u8; LEN],
+    length: usize,
+}
+
+impl<const LEN: usize> Message<LEN> {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+}
+
+/// Up to `N` messages of up to `LEN` bytes each, received in the order they were sent
+#[derive(Debug, Clone)]
+pub struct MessageQueue<const LEN: usize, const N: usize> {
+    messages: RingBuffer<Message<LEN>, N>,
+    // Woken when a message is sent
+    readable: WaitQueue,
+    // Woken when a message is received, making room for another
+    writable: WaitQueue,
+}
+
+impl<const LEN: usize, const N: usize> MessageQueue<LEN, N> {
+    pub const fn new() -> Self {
+        Self {
+            messages: RingBuffer::new(Message {
+                bytes: [0; LEN],
+                length: 0,
+            }),
+            readable: WaitQueue::new(),
+            writable: WaitQueue::new(),
+        }
+    }
+
+    /// Queues a copy of `message`, failing if it's too long or the queue is full
+    pub fn send(&mut self, message: &[u8]) -> Result<(), Fault> {
+        if message.len() > LEN {
+            return Err(Fault::MessageTooLarge(message.len()));
+        }
+        let mut queued = Message {
+            bytes: [0; LEN],
+            length: message.len(),
+        };
+        queued.bytes[..message.len()].copy_from_slice(message);
+        self.messages.push(queued).map_err(|_| Fault::QueueFull)?;
+        self.readable.wake();
+        Ok(())
+    }
+
+    /// Queues a copy of `message`, running `wait` while the queue is full
+    pub fn send_waiting(
+        &mut self,
+        message: &[u8],
+        mut wait: impl FnMut(&mut Self),
+    ) -> Result<(), Fault> {
+        loop {
+            match self.send(message) {
+                Err(Fault::QueueFull) => wait(self),
+                result => return result,
+            }
+        }
+    }
+
+    /// The oldest message, if there's any
+    pub fn receive(&mut self) -> Option<Message<LEN>> {
+        let message = self.messages.pop()?;
+        self.writable.wake();
+        Some(message)
+    }
+
+    /// The oldest message, running `wait` until there's one
+    pub fn receive_waiting(&mut self, mut wait: impl FnMut(&mut Self)) -> Message<LEN> {
+        loop {
+            if let Some(message) = self.receive() {
+                return message;
+            }
+            wait(self);
+        }
+    }
+
+    /// Queues a copy of `message` in `queue`, halting while it's full
+    ///
+    /// # Safety
+    /// `queue` has to be valid until this returns, and whatever else uses it, e.g. an interrupt
+    /// handler, may only do so with interrupts disabled
+    pub unsafe fn send_blocking(queue: *mut Self, message: &[u8]) -> Result<(), Fault> {
+        wait_queue::wait_until(|| {
+            // SAFETY: the caller guarantees `queue` is valid, and interrupts are disabled while
+            // this runs, so nothing else uses it
+            let queue = unsafe { &mut *queue };
+            queue.writable.take_wakeup();
+            match queue.send(message) {
+                Err(Fault::QueueFull) => None,
+                result => Some(result),
+            }
+        })
+    }
+
+    /// The oldest message in `queue`, halting until there's one
+    ///
+    /// # Safety
+    /// See `send_blocking`
+    pub unsafe fn receive_blocking(queue: *mut Self) -> Message<LEN> {
+        wait_queue::wait_until(|| {
+            // SAFETY: see send_blocking
+            let queue = unsafe { &mut *queue };
+            queue.readable.take_wakeup();
+            queue.receive()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Whether a message was sent since the last call, for `wait_queue::wait_until` to wait for
+    /// one with
+    pub fn take_readable_wakeup(&mut self) -> bool {
+        self.readable.take_wakeup()
+    }
+
+    /// Whether a message was received since the last call, making room for another
+    pub fn take_writable_wakeup(&mut self) -> bool {
+        self.writable.take_wakeup()
+    }
+}
+
+impl<const LEN: usize, const N: usize> Default for MessageQueue<LEN, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Fault,
+        ipc::{MessageQueue, Pipe},
+    };
+
+    #[test]
+    fn pipe_stream() {
+        let mut pipe = Pipe::<4>::new();
+        let mut buffer = [0u8; 8];
+        assert_eq!(0, pipe.read(&mut buffer));
+        assert!(!pipe.is_at_end());
+        assert_eq!(Some(4), pipe.write(b"hello").ok());
+        assert_eq!(Some(0), pipe.write(b"o").ok());
+        assert_eq!(2, pipe.read(&mut buffer[..2]));
+        assert_eq!(b"he", &buffer[..2]);
+
+        // The reader drains the pipe whenever the writer has to wait
+        let mut drained = [0u8; 8];
+        let mut drained_length = 0;
+        let result = pipe.write_all_waiting(b"o, world", |pipe| {
+            drained_length += pipe.read(&mut drained[drained_length..]);
+        });
+        assert!(result.is_ok());
+        assert_eq!(b"llo, wor", &drained[..drained_length]);
+
+        pipe.close_writer();
+        assert!(matches!(pipe.write(b"!"), Err(Fault::BrokenPipe)));
+        assert_eq!(
+            2,
+            pipe.read_waiting(&mut buffer, |_| panic!("nothing to wait for"))
+        );
+        assert_eq!(b"ld", &buffer[..2]);
+        assert!(pipe.is_at_end());
+        assert_eq!(0, pipe.read_waiting(&mut buffer, |_| panic!("at the end")));
+    }
+
+    #[test]
+    fn wakeups() {
+        let mut pipe = Pipe::<4>::new();
+        assert!(!pipe.take_readable_wakeup());
+        assert_eq!(Some(4), pipe.write(b"hello").ok());
+        assert!(pipe.take_readable_wakeup());
+        // Nothing written, nothing to wake the reader for
+        assert_eq!(Some(0), pipe.write(b"o").ok());
+        assert!(!pipe.take_readable_wakeup());
+        assert!(!pipe.take_writable_wakeup());
+        assert_eq!(1, pipe.read(&mut [0u8; 1]));
+        assert!(pipe.take_writable_wakeup());
+        pipe.close_writer();
+        assert!(pipe.take_readable_wakeup());
+        pipe.close_reader();
+        assert!(pipe.take_writable_wakeup());
+
+        let mut queue = MessageQueue::<4, 1>::new();
+        assert!(queue.send(b"ping").is_ok());
+        assert!(queue.take_readable_wakeup());
+        assert!(queue.send(b"pong").is_err());
+        assert!(!queue.take_readable_wakeup());
+        assert!(queue.receive().is_some());
+        assert!(queue.take_writable_wakeup());
+        assert!(queue.receive().is_none());
+        assert!(!queue.take_writable_wakeup());
+    }
+
+    #[test]
+    fn pipe_without_reader() {
+        let mut pipe = Pipe::<4>::new();
+        assert_eq!(Some(2), pipe.write(b"hi").ok());
+        pipe.close_reader();
+        assert!(pipe.is_empty());
+        assert!(matches!(pipe.write(b"hi"), Err(Fault::BrokenPipe)));
+    }
+
+    #[test]
+    fn message_boundaries() {
+        let mut queue = MessageQueue::<4, 2>::new();
+        assert!(queue.send(b"ping").is_ok());
+        assert!(queue.send(b"").is_ok());
+        assert!(matches!(queue.send(b"x"), Err(Fault::QueueFull)));
+        assert!(matches!(
+            queue.send(b"hello"),
+            Err(Fault::MessageTooLarge(5))
+        ));
+
+        let mut waits = 0;
+        let sent = queue.send_waiting(b"pong", |queue| {
+            waits += 1;
+            let _ = queue.receive();
+        });
+        assert!(sent.is_ok());
+        assert_eq!(1, waits);
+        assert_eq!(b"", queue.receive_waiting(|_| panic!("queued")).bytes());
+        assert_eq!(b"pong", queue.receive().unwrap().bytes());
+        assert!(queue.receive().is_none());
+    }
+}
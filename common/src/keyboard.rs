@@ -0,0 +1,478 @@
+// PS/2 keyboard driver. The controller translates whatever the keyboard sends to scancode set 1,
+// which is turned into characters according to a keymap
+// https://wiki.osdev.org/PS/2_Keyboard
+use core::fmt::Display;
+
+use crate::{interrupts, make_bitmap, ps2, ring_buffer::RingBuffer, wait_queue::WaitQueue};
+
+const RECEIVE_BUFFER_SIZE: usize = 64;
+
+// Scancode set 1, the one translation produces
+const EXTENDED_PREFIX: u8 = 0xE0;
+const PAUSE_PREFIX: u8 = 0xE1;
+const RELEASED: u8 = 0x80;
+const ESCAPE: u8 = 0x01;
+const BACKSPACE: u8 = 0x0E;
+const TAB: u8 = 0x0F;
+const ENTER: u8 = 0x1C;
+const LEFT_SHIFT: u8 = 0x2A;
+const RIGHT_SHIFT: u8 = 0x36;
+const SPACE: u8 = 0x39;
+const CAPS_LOCK: u8 = 0x3A;
+// The key between left shift and Z, which only ISO keyboards have
+const ISO_EXTRA_KEY: u8 = 0x56;
+// Following EXTENDED_PREFIX
+const RIGHT_ALT: u8 = 0x38;
+const KEYPAD_ENTER: u8 = 0x1C;
+const KEYPAD_SLASH: u8 = 0x35;
+
+static mut KEYBOARD: Keyboard = Keyboard::new(Layout::Us);
+static mut KEYBOARD_RECEIVE_BUFFER: RingBuffer<char, RECEIVE_BUFFER_SIZE> = RingBuffer::new('\0');
+// Woken whenever a character is typed
+static mut KEYBOARD_WAIT_QUEUE: WaitQueue = WaitQueue::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    De,
+    It,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 3] = [Layout::Us, Layout::De, Layout::It];
+
+    /// The layout called `name`, like the ones Display prints
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|layout| layout.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::De => "de",
+            Layout::It => "it",
+        }
+    }
+
+    fn keymap(self) -> &'static Keymap {
+        match self {
+            Layout::Us => &US,
+            Layout::De => &DE,
+            Layout::It => &IT,
+        }
+    }
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The characters of a run of consecutive scancodes, one per key. Spaces stand for keys that
+/// produce nothing at that level, as the space bar isn't part of any row
+struct Row {
+    first_scancode: u8,
+    base: &'static str,
+    shift: &'static str,
+    altgr: &'static str,
+}
+
+/// What the printable keys produce in a layout. Dead keys aren't supported, accents produce
+/// themselves right away
+struct Keymap {
+    rows: [Row; 5],
+}
+
+impl Keymap {
+    fn lookup(&self, scancode: u8, modifiers: Modifiers) -> Option<char> {
+        let row = self.rows.iter().find(|row| {
+            (row.first_scancode..row.first_scancode + row.base.chars().count() as u8)
+                .contains(&scancode)
+        })?;
+        let index = (scancode - row.first_scancode) as usize;
+        let base = row.base.chars().nth(index)?;
+        let shifted = row.shift.chars().nth(index)?;
+
+        let character = if modifiers.is_set(Modifier::AltGr) {
+            row.altgr.chars().nth(index)?
+        } else {
+            let shift =
+                modifiers.is_set(Modifier::LeftShift) || modifiers.is_set(Modifier::RightShift);
+            // Caps lock only shifts letters that have an uppercase on the shift level
+            let caps_lock = modifiers.is_set(Modifier::CapsLock)
+                && base.is_alphabetic()
+                && shifted.is_uppercase();
+            if shift != caps_lock { shifted } else { base }
+        };
+        (character != ' ').then_some(character)
+    }
+}
+
+// https://kbdlayout.info/kbdus
+static US: Keymap = Keymap {
+    rows: [
+        Row {
+            first_scancode: 0x02,
+            base: "1234567890-=",
+            shift: "!@#$%^&*()_+",
+            altgr: "            ",
+        },
+        Row {
+            first_scancode: 0x10,
+            base: "qwertyuiop[]",
+            shift: "QWERTYUIOP{}",
+            altgr: "            ",
+        },
+        Row {
+            first_scancode: 0x1E,
+            base: "asdfghjkl;'`",
+            shift: "ASDFGHJKL:\"~",
+            altgr: "            ",
+        },
+        Row {
+            first_scancode: 0x2B,
+            base: "\\zxcvbnm,./",
+            shift: "|ZXCVBNM<>?",
+            altgr: "           ",
+        },
+        Row {
+            first_scancode: ISO_EXTRA_KEY,
+            base: "\\",
+            shift: "|",
+            altgr: " ",
+        },
+    ],
+};
+
+// https://kbdlayout.info/kbdgr
+static DE: Keymap = Keymap {
+    rows: [
+        Row {
+            first_scancode: 0x02,
+            base: "1234567890ß´",
+            shift: "!\"§$%&/()=?`",
+            altgr: " ²³   {[]}\\ ",
+        },
+        Row {
+            first_scancode: 0x10,
+            base: "qwertzuiopü+",
+            shift: "QWERTZUIOPÜ*",
+            altgr: "@ €        ~",
+        },
+        Row {
+            first_scancode: 0x1E,
+            base: "asdfghjklöä^",
+            shift: "ASDFGHJKLÖÄ°",
+            altgr: "            ",
+        },
+        Row {
+            first_scancode: 0x2B,
+            base: "#yxcvbnm,.-",
+            shift: "'YXCVBNM;:_",
+            altgr: "       µ   ",
+        },
+        Row {
+            first_scancode: ISO_EXTRA_KEY,
+            base: "<",
+            shift: ">",
+            altgr: "|",
+        },
+    ],
+};
+
+// https://kbdlayout.info/kbdit
+static IT: Keymap = Keymap {
+    rows: [
+        Row {
+            first_scancode: 0x02,
+            base: "1234567890'ì",
+            shift: "!\"£$%&/()=?^",
+            altgr: "            ",
+        },
+        Row {
+            first_scancode: 0x10,
+            base: "qwertyuiopè+",
+            shift: "QWERTYUIOPé*",
+            altgr: "  €       []",
+        },
+        Row {
+            first_scancode: 0x1E,
+            base: "asdfghjklòà\\",
+            shift: "ASDFGHJKLç°|",
+            altgr: "         @# ",
+        },
+        Row {
+            first_scancode: 0x2B,
+            base: "ùzxcvbnm,.-",
+            shift: "§ZXCVBNM;:_",
+            altgr: "           ",
+        },
+        Row {
+            first_scancode: ISO_EXTRA_KEY,
+            base: "<",
+            shift: ">",
+            altgr: " ",
+        },
+    ],
+};
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum Modifier {
+    LeftShift = 0x1,
+    RightShift = 0x2,
+    AltGr = 0x4,
+    CapsLock = 0x8,
+}
+
+make_bitmap!(new_type: Modifiers, underlying_flag_type: Modifier, repr: u8, nodisplay);
+
+/// Turns scancodes into characters, keeping track of the modifiers in between
+#[derive(Debug, Clone, Copy)]
+pub struct Keyboard {
+    layout: Layout,
+    modifiers: Modifiers,
+    extended: bool,
+}
+
+impl Keyboard {
+    pub const fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            modifiers: Modifiers::empty(),
+            extended: false,
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Feeds the next scancode byte from the keyboard. Returns the character typed, if the byte
+    /// completed a key press that produces one
+    pub fn process_scancode(&mut self, scancode: u8) -> Option<char> {
+        if scancode == EXTENDED_PREFIX {
+            self.extended = true;
+            return None;
+        }
+        // Pause is the only key sending this, along with codes that are ignored anyway
+        if scancode == PAUSE_PREFIX {
+            return None;
+        }
+        let extended = core::mem::take(&mut self.extended);
+        let released = scancode & RELEASED != 0;
+        let key = scancode & !RELEASED;
+
+        let modifier = match (extended, key) {
+            (false, LEFT_SHIFT) => Some(Modifier::LeftShift),
+            (false, RIGHT_SHIFT) => Some(Modifier::RightShift),
+            (true, RIGHT_ALT) => Some(Modifier::AltGr),
+            _ => None,
+        };
+        if let Some(modifier) = modifier {
+            if released {
+                self.modifiers.clear_flag(modifier);
+            } else {
+                self.modifiers.set_flag(modifier);
+            }
+            return None;
+        }
+        if released {
+            return None;
+        }
+
+        match (extended, key) {
+            (false, CAPS_LOCK) => {
+                if self.modifiers.is_set(Modifier::CapsLock) {
+                    self.modifiers.clear_flag(Modifier::CapsLock);
+                } else {
+                    self.modifiers.set_flag(Modifier::CapsLock);
+                }
+                None
+            }
+            (false, ESCAPE) => Some('\x1b'),
+            (false, BACKSPACE) => Some('\x08'),
+            (false, TAB) => Some('\t'),
+            (false, ENTER) | (true, KEYPAD_ENTER) => Some('\n'),
+            (false, SPACE) => Some(' '),
+            (true, KEYPAD_SLASH) => Some('/'),
+            (true, _) => None,
+            (false, _) => self.layout.keymap().lookup(key, self.modifiers),
+        }
+    }
+}
+
+/// Decodes the scancodes the controller received into the receive buffer. Characters typed while
+/// the buffer is full are dropped
+pub fn handle_interrupt_no_sync() {
+    let keyboard_ptr = &raw mut KEYBOARD;
+    // SAFETY: no threads, and the keyboard is only accessed with interrupts disabled outside of
+    // the interrupt handler
+    let keyboard = unsafe { &mut *keyboard_ptr };
+    let receive_buffer_ptr = &raw mut KEYBOARD_RECEIVE_BUFFER;
+    // SAFETY: same as above
+    let receive_buffer = unsafe { &mut *receive_buffer_ptr };
+    let wait_queue_ptr = &raw mut KEYBOARD_WAIT_QUEUE;
+    // SAFETY: same as above
+    let wait_queue = unsafe { &mut *wait_queue_ptr };
+    while ps2::has_keyboard_data() {
+        if let Some(character) = keyboard.process_scancode(ps2::read_data()) {
+            let _ = receive_buffer.push(character);
+            wait_queue.wake();
+        }
+    }
+}
+
+/// Returns the next character typed, if any, without blocking
+pub fn read_char() -> Option<char> {
+    interrupts::without_interrupts(|| {
+        let receive_buffer_ptr = &raw mut KEYBOARD_RECEIVE_BUFFER;
+        // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+        let receive_buffer = unsafe { &mut *receive_buffer_ptr };
+        receive_buffer.pop()
+    })
+}
+
+/// Whether a character was typed since the last call, for `wait_queue::wait_until` to wait for
+/// one with
+pub fn take_wakeup() -> bool {
+    interrupts::without_interrupts(|| {
+        let wait_queue_ptr = &raw mut KEYBOARD_WAIT_QUEUE;
+        // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+        unsafe { (*wait_queue_ptr).take_wakeup() }
+    })
+}
+
+pub fn layout() -> Layout {
+    interrupts::without_interrupts(|| {
+        let keyboard_ptr = &raw const KEYBOARD;
+        // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+        unsafe { (*keyboard_ptr).layout() }
+    })
+}
+
+pub fn set_layout(layout: Layout) {
+    interrupts::without_interrupts(|| {
+        let keyboard_ptr = &raw mut KEYBOARD;
+        // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+        unsafe { (*keyboard_ptr).set_layout(layout) };
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keyboard::{Keyboard, Layout, Modifier};
+
+    #[test]
+    fn keymap_rows_line_up() {
+        for layout in Layout::ALL {
+            for row in &layout.keymap().rows {
+                let keys = row.base.chars().count();
+                assert_eq!(
+                    keys,
+                    row.shift.chars().count(),
+                    "{layout} {:#x}",
+                    row.first_scancode
+                );
+                assert_eq!(
+                    keys,
+                    row.altgr.chars().count(),
+                    "{layout} {:#x}",
+                    row.first_scancode
+                );
+            }
+        }
+    }
+
+    fn type_scancodes(keyboard: &mut Keyboard, scancodes: &[u8]) -> [Option<char>; 8] {
+        let mut typed = [None; 8];
+        for (slot, &scancode) in typed.iter_mut().zip(scancodes) {
+            *slot = keyboard.process_scancode(scancode);
+        }
+        typed
+    }
+
+    #[test]
+    fn layouts_and_modifiers() {
+        assert_eq!(Some(Layout::De), Layout::from_name("DE"));
+        assert_eq!(None, Layout::from_name("fr"));
+
+        let mut keyboard = Keyboard::new(Layout::Us);
+        // y, shift+2, release shift
+        let typed = type_scancodes(&mut keyboard, &[0x15, 0x95, 0x2A, 0x03, 0x83, 0xAA, 0x03]);
+        assert_eq!(
+            [
+                Some('y'),
+                None,
+                None,
+                Some('@'),
+                None,
+                None,
+                Some('2'),
+                None
+            ],
+            typed
+        );
+
+        keyboard.set_layout(Layout::De);
+        // y is z on German keyboards, AltGr+q is @, AltGr+e is €
+        let typed = type_scancodes(&mut keyboard, &[0x15, 0xE0, 0x38, 0x10, 0x12, 0xE0, 0xB8]);
+        assert_eq!(
+            [
+                Some('z'),
+                None,
+                None,
+                Some('@'),
+                Some('€'),
+                None,
+                None,
+                None
+            ],
+            typed
+        );
+        assert!(!keyboard.modifiers().is_set(Modifier::AltGr));
+
+        // Caps lock shifts letters, but not ß, and shift undoes it
+        let typed = type_scancodes(&mut keyboard, &[0x3A, 0x27, 0x0C, 0x2A, 0x27, 0xAA]);
+        assert_eq!(
+            [
+                None,
+                Some('Ö'),
+                Some('ß'),
+                None,
+                Some('ö'),
+                None,
+                None,
+                None
+            ],
+            typed
+        );
+
+        keyboard.set_layout(Layout::It);
+        let typed = type_scancodes(&mut keyboard, &[0x27, 0x56, 0xE0, 0x1C, 0x39]);
+        assert_eq!(
+            [
+                Some('ò'),
+                Some('<'),
+                None,
+                Some('\n'),
+                Some(' '),
+                None,
+                None,
+                None
+            ],
+            typed
+        );
+    }
+}
@@ -0,0 +1,40 @@
+// The address space the bootloader sets up for itself and for the kernel. Everything below 1GB is
+// identity mapped, so these are both virtual and physical addresses. The linker scripts of the
+// bootloader and the kernel are generated from these by `xtasks linker-scripts`, so keep this file
+// free of anything but constants: xtasks includes it as it is
+use core::ops::Range;
+
+/// Where stage1 loads stage2, and where stage2 is linked to run from
+pub const STAGE2_BASE: u64 = 0x60000;
+/// stage2 and its statics must end before its stack
+pub const STAGE2_STACK: Range<u64> = 0x80000..0x90000;
+
+/// Where the kernel is linked, before any KASLR slide
+pub const KERNEL_BASE: u64 = 0x200000;
+/// The stack the bootloader hands over to the kernel, at the first multiple of this past the
+/// kernel's entrypoint
+pub const KERNEL_STACK_SIZE: u64 = 0x100000;
+
+/// Where the kernel heap goes, past the kernel, any KASLR slide and the payloads the bootloader
+/// loads after it
+pub const HEAP_START: u64 = 0x1000_0000;
+pub const HEAP_SIZE: u64 = 0x100_0000;
+
+/// The frames the kernel hands out one by one, reference counted, e.g. for copy-on-write copies
+pub const FRAME_POOL: Range<u64> = 0x1100_0000..0x1200_0000;
+
+/// Where PCI BARs get mapped: the 32-bit PCI hole, right under 4GB
+pub const MMIO_WINDOW: Range<u64> = 0xC000_0000..0x1_0000_0000;
+
+/// Where the physical memory under the MMIO window is mapped again, in the upper half, so that
+/// the kernel can reach page tables and any other frame by physical address once it owns CR3.
+/// The MMIO window is left out so that it's never mapped with two memory types
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+/// Where the kernel hands out virtual address ranges at runtime, see the vma module: the 512GB
+/// of a PML4 entry of its own, away from the identity mapped memory and the physical memory map
+pub const KERNEL_REGIONS: Range<u64> = 0xffff_c000_0000_0000..0xffff_c080_0000_0000;
+
+/// Where the memory of user tasks goes: the lower half, past the PML4 entry of the memory the
+/// bootloader identity maps
+pub const USER_REGIONS: Range<u64> = 0x80_0000_0000..0x8000_0000_0000;
@@ -3,19 +3,71 @@
 #![deny(clippy::missing_panics_doc)]
 #![deny(clippy::unwrap_used)]
 #![no_std]
+pub mod anonymous_memory;
+pub mod archive;
 pub mod ata;
+pub mod bios;
+pub mod block_device;
+pub mod boot_info;
+pub mod command_line;
 pub mod control_registers;
+pub mod devfs;
+pub mod dma;
+pub mod e1000;
+pub mod e820;
 pub mod elf;
 pub mod error;
+pub mod fat32;
+pub mod fpu;
+pub mod frame;
+pub mod gdb;
 pub mod gdt;
+pub mod gpt;
+pub mod hash;
+pub mod hexdump;
 pub mod idt;
+pub mod interrupts;
 pub mod ioport;
+pub mod ipc;
+pub mod keyboard;
+pub mod layout;
+pub mod local_apic;
+pub mod log;
 pub mod macros;
+pub mod metrics;
+pub mod module;
+pub mod mouse;
+pub mod msr;
+pub mod net;
 pub mod paging;
+pub mod panicking;
+pub mod payload;
 pub mod pci;
+pub mod pci_capability;
+pub mod pci_class;
+pub mod pci_function;
+pub mod pic;
 pub mod protection;
+pub mod ps2;
+pub mod ram_disk;
+pub mod random;
+pub mod reserved_regions;
+pub mod ring_buffer;
 pub mod serial;
+pub mod symbols;
+#[cfg(test)]
+mod test_support;
 pub mod timer;
+pub mod timer_wheel;
+pub mod tmpfs;
+pub mod trace;
 pub mod tss;
+pub mod tunables;
 pub mod usb;
+pub mod user_program;
+pub mod vectors;
 pub mod vga;
+pub mod virtio;
+pub mod virtio_net;
+pub mod vma;
+pub mod wait_queue;
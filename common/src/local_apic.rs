@@ -0,0 +1,60 @@
+// The local APIC of the CPU, through its xAPIC MMIO registers, as far as taking message signaled
+// interrupts goes: PCI functions write their messages to it, and it has to be software enabled
+// for them to be delivered. The PICs keep going through its LINT0 pin, which the firmware sets up
+// in virtual wire mode
+// https://wiki.osdev.org/APIC
+use crate::{
+    error::Fault,
+    msr::{ApicBase, MemoryType},
+    paging,
+};
+
+const ID: u64 = 0x20;
+const END_OF_INTERRUPT: u64 = 0xB0;
+const SPURIOUS_INTERRUPT_VECTOR: u64 = 0xF0;
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const ID_SHIFT: u32 = 24;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    base_address: u64,
+}
+
+impl LocalApic {
+    fn read_register(&self, register: u64) -> u32 {
+        let register_ptr = (self.base_address + register) as usize as *const u32;
+        // SAFETY: `base_address` is the local APIC's, identity mapped as uncacheable by `enable`,
+        // and the registers are within its page
+        unsafe { register_ptr.read_volatile() }
+    }
+
+    fn write_register(&self, register: u64, value: u32) {
+        let register_ptr = (self.base_address + register) as usize as *mut u32;
+        // SAFETY: see `read_register`
+        unsafe { register_ptr.write_volatile(value) }
+    }
+
+    /// Maps the local APIC's registers and software enables it, with spurious interrupts going
+    /// to `spurious_vector`, which needs a handler that doesn't signal the end of the interrupt
+    pub fn enable(spurious_vector: u8) -> Result<Self, Fault> {
+        let base_address = ApicBase::read().base_address();
+        paging::Mapper::active().identity_map_gigabyte(base_address, MemoryType::Uncacheable)?;
+        let local_apic = Self { base_address };
+        let spurious_interrupt_vector = local_apic.read_register(SPURIOUS_INTERRUPT_VECTOR);
+        local_apic.write_register(
+            SPURIOUS_INTERRUPT_VECTOR,
+            spurious_interrupt_vector & !0xFF | SOFTWARE_ENABLE | spurious_vector as u32,
+        );
+        Ok(local_apic)
+    }
+
+    /// What messages for this CPU are addressed to
+    pub fn id(&self) -> u8 {
+        (self.read_register(ID) >> ID_SHIFT) as u8
+    }
+
+    /// Must be sent at the end of the handlers of the interrupts the local APIC delivered
+    pub fn end_of_interrupt(&self) {
+        self.write_register(END_OF_INTERRUPT, 0);
+    }
+}
@@ -0,0 +1,370 @@
+// Leveled logging to both the screen and the log serial port. Each line is tagged with its level
+// and the module that logged it, so that the bootloader's and the kernel's lines can be told
+// apart once they're interleaved on the serial port:
+//   WARN  [blog_os::memory] The heap at 0x... isn't free RAM
+// The tag is colored with ANSI escapes on the serial port, unless they're turned off for
+// terminals that don't understand them, and with the VGA attribute of the level on the screen.
+// Every line is also kept, uncolored, in a fixed-size history, which is all there is until
+// `start_sinks_no_sync` says the screen and the serial port are set up: it replays the history to
+// them, and they get every line from then on
+use core::fmt::{Arguments, Display, Write};
+
+use crate::{ring_buffer::RingBuffer, serial, vga};
+
+/// How many bytes of log lines are kept, the oldest lines are dropped to make room
+pub const HISTORY_SIZE: usize = 16 * 1024;
+/// Longer lines are split when replayed
+const MAX_LINE_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// The level named `name`, case insensitive, e.g. from a `loglevel=` option
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ]
+        .into_iter()
+        .find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    /// The SGR sequence the tag starts with on the serial port
+    pub fn ansi_color(&self) -> &'static str {
+        match self {
+            Level::Error => "\x1b[1;31m",
+            Level::Warn => "\x1b[33m",
+            Level::Info => "\x1b[32m",
+            Level::Debug => "\x1b[36m",
+            Level::Trace => "\x1b[90m",
+        }
+    }
+
+    pub fn vga_color(&self) -> vga::Color {
+        match self {
+            Level::Error => vga::Color::LightRed,
+            Level::Warn => vga::Color::Yellow,
+            Level::Info => vga::Color::LightGreen,
+            Level::Debug => vga::Color::LightCyan,
+            Level::Trace => vga::Color::DarkGray,
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+const ANSI_RESET: &str = "\x1b[0m";
+
+struct Settings {
+    max_level: Level,
+    ansi_colors: bool,
+    sinks_started: bool,
+}
+
+static mut SETTINGS: Settings = Settings {
+    max_level: Level::Info,
+    ansi_colors: true,
+    sinks_started: false,
+};
+
+struct History {
+    bytes: RingBuffer<u8, HISTORY_SIZE>,
+    /// Whether the oldest bytes were dropped, so the first line is likely cut
+    overwritten: bool,
+}
+
+impl Write for History {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if self.bytes.push_overwriting(byte).is_some() {
+                self.overwritten = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+static mut HISTORY: History = History {
+    bytes: RingBuffer::new(0),
+    overwritten: false,
+};
+
+/// Drops the lines less severe than `max_level` from now on. Info by default
+pub fn set_max_level_no_sync(max_level: Level) {
+    let settings_ptr = &raw mut SETTINGS;
+    // SAFETY: no multitasking, no synchronization needed
+    unsafe { (*settings_ptr).max_level = max_level };
+}
+
+/// Whether the level tags on the serial port are colored. On by default
+pub fn set_ansi_colors_no_sync(ansi_colors: bool) {
+    let settings_ptr = &raw mut SETTINGS;
+    // SAFETY: no multitasking, no synchronization needed
+    unsafe { (*settings_ptr).ansi_colors = ansi_colors };
+}
+
+pub fn enabled_no_sync(level: Level) -> bool {
+    let settings_ptr = &raw const SETTINGS;
+    // SAFETY: no multitasking, no synchronization needed
+    level <= unsafe { (*settings_ptr).max_level }
+}
+
+/// A log line as it goes out on the serial port: the tag, optionally colored, then the message
+pub struct Line<'a> {
+    pub level: Level,
+    pub module_path: &'a str,
+    pub args: Arguments<'a>,
+    pub ansi_colors: bool,
+}
+
+impl Display for Line<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.ansi_colors {
+            write!(
+                f,
+                "{}{:<5}{} [{}] {}",
+                self.level.ansi_color(),
+                self.level,
+                ANSI_RESET,
+                self.module_path,
+                self.args
+            )
+        } else {
+            write!(f, "{:<5} [{}] {}", self.level, self.module_path, self.args)
+        }
+    }
+}
+
+/// Sends the lines logged so far to the screen and the log serial port, and every line from now on
+/// as it's logged. Until then, lines are only kept in the history
+pub fn start_sinks_no_sync() {
+    let settings_ptr = &raw mut SETTINGS;
+    // SAFETY: no multitasking, no synchronization needed
+    let settings = unsafe { &mut *settings_ptr };
+    if settings.sinks_started {
+        return;
+    }
+    settings.sinks_started = true;
+    for_each_line_no_sync(|line| {
+        let _ = vga::__writeln_no_sync(format_args!("{line}"));
+        let _ = serial::__writeln_no_sync(format_args!("{line}"));
+    });
+}
+
+/// Visits the lines in the history, oldest first, like dmesg prints them
+pub fn for_each_line_no_sync(mut f: impl FnMut(&str)) {
+    let history_ptr = &raw const HISTORY;
+    // SAFETY: no multitasking, no synchronization needed
+    let history = unsafe { &*history_ptr };
+    let (front, back) = history.bytes.as_slices();
+    let mut line = [0u8; MAX_LINE_LENGTH];
+    let mut length = 0;
+    // A line cut by the oldest bytes being dropped isn't worth showing
+    let mut skipping = history.overwritten;
+    for byte in front.iter().chain(back) {
+        if skipping {
+            skipping = *byte != b'\n';
+            continue;
+        }
+        if *byte != b'\n' {
+            line[length] = *byte;
+            length += 1;
+            if length < MAX_LINE_LENGTH {
+                continue;
+            }
+        }
+        // Lines are whole strs, but a split can cut a character in two
+        let bytes = &line[..length];
+        f(match core::str::from_utf8(bytes) {
+            Ok(line) => line,
+            Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or_default(),
+        });
+        length = 0;
+    }
+}
+
+pub fn __log_no_sync(level: Level, module_path: &str, args: Arguments) -> core::fmt::Result {
+    if !enabled_no_sync(level) {
+        return Ok(());
+    }
+    let history_ptr = &raw mut HISTORY;
+    // SAFETY: no multitasking, no synchronization needed
+    let history = unsafe { &mut *history_ptr };
+    writeln!(
+        history,
+        "{}",
+        Line {
+            level,
+            module_path,
+            args,
+            ansi_colors: false,
+        }
+    )?;
+    let settings_ptr = &raw const SETTINGS;
+    // SAFETY: no multitasking, no synchronization needed
+    if !unsafe { (*settings_ptr).sinks_started } {
+        return Ok(());
+    }
+    vga::with_default_writer_no_sync(|writer| {
+        writer.set_foreground(level.vga_color());
+        write!(writer, "{level:<5}")?;
+        writer.set_foreground(vga::Color::White);
+        writeln!(writer, " [{module_path}] {args}")
+    })?;
+    let settings_ptr = &raw const SETTINGS;
+    // SAFETY: no multitasking, no synchronization needed
+    let ansi_colors = unsafe { (*settings_ptr).ansi_colors };
+    serial::__writeln_no_sync(format_args!(
+        "{}",
+        Line {
+            level,
+            module_path,
+            args,
+            ansi_colors,
+        }
+    ))
+}
+
+#[macro_export]
+macro_rules! log_no_sync {
+    ($level:expr, $format_string:literal$(, $args:expr)*) => {
+        $crate::log::__log_no_sync(
+            $level,
+            ::core::module_path!(),
+            ::core::format_args!($format_string $(,$args)*,),
+        )
+        .expect("couldn't write the log line")
+    };
+}
+
+#[macro_export]
+macro_rules! log_error_no_sync {
+    ($format_string:literal$(, $args:expr)*) => {
+        $crate::log_no_sync!($crate::log::Level::Error, $format_string $(,$args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn_no_sync {
+    ($format_string:literal$(, $args:expr)*) => {
+        $crate::log_no_sync!($crate::log::Level::Warn, $format_string $(,$args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_info_no_sync {
+    ($format_string:literal$(, $args:expr)*) => {
+        $crate::log_no_sync!($crate::log::Level::Info, $format_string $(,$args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug_no_sync {
+    ($format_string:literal$(, $args:expr)*) => {
+        $crate::log_no_sync!($crate::log::Level::Debug, $format_string $(,$args)*)
+    };
+}
+
+#[macro_export]
+macro_rules! log_trace_no_sync {
+    ($format_string:literal$(, $args:expr)*) => {
+        $crate::log_no_sync!($crate::log::Level::Trace, $format_string $(,$args)*)
+    };
+}
+
+pub use log_debug_no_sync as debug_no_sync;
+pub use log_error_no_sync as error_no_sync;
+pub use log_info_no_sync as info_no_sync;
+pub use log_trace_no_sync as trace_no_sync;
+pub use log_warn_no_sync as warn_no_sync;
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        log::{Level, Line, enabled_no_sync, for_each_line_no_sync},
+        test_support::TestWriter,
+    };
+
+    #[test]
+    fn lines() {
+        let mut writer = TestWriter::<128>::new();
+        let frames = 3;
+        write!(
+            writer,
+            "{}",
+            Line {
+                level: Level::Warn,
+                module_path: "blog_os::memory",
+                args: format_args!("{frames} frames reserved"),
+                ansi_colors: true,
+            }
+        )
+        .unwrap();
+        assert_eq!(
+            "\x1b[33mWARN \x1b[0m [blog_os::memory] 3 frames reserved",
+            writer.as_str()
+        );
+
+        writer = TestWriter::new();
+        write!(
+            writer,
+            "{}",
+            Line {
+                level: Level::Error,
+                module_path: "bootloader",
+                args: format_args!("no kernel"),
+                ansi_colors: false,
+            }
+        )
+        .unwrap();
+        assert_eq!("ERROR [bootloader] no kernel", writer.as_str());
+    }
+
+    #[test]
+    fn history() {
+        crate::log::warn_no_sync!("kept {}", "before the sinks start");
+        crate::log::debug_no_sync!("not kept");
+        let mut kept = false;
+        for_each_line_no_sync(|line| {
+            kept |= line == "WARN  [common::log::tests] kept before the sinks start";
+            assert!(!line.contains("not kept"));
+        });
+        assert!(kept);
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(Some(Level::Debug), Level::from_name("debug"));
+        assert_eq!(Some(Level::Warn), Level::from_name("WARN"));
+        assert_eq!(None, Level::from_name("verbose"));
+        assert!(Level::Error < Level::Trace);
+        assert!(enabled_no_sync(Level::Warn));
+        assert!(!enabled_no_sync(Level::Debug));
+    }
+}
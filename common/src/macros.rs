@@ -24,6 +24,29 @@ macro_rules! make_bitmap {
             }
         }
     };
+    // Lists the set flags by the names given, then the bits none of them accounts for in hex, e.g.
+    // "P|G|0x4f" for the flags of a descriptor with its type, DPL and limit bits set, or "0x0"
+    (new_type: $flags_type:ident, underlying_flag_type: $flag_type:ty, repr: $flag_unsigned_type:ty, names: [$($flag:ident => $name:literal),+ $(,)?]) => {
+        make_bitmap!(new_type: $flags_type, underlying_flag_type: $flag_type, repr: $flag_unsigned_type, nodisplay);
+
+        impl ::core::fmt::Display for $flags_type {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                let mut residual = self.bits;
+                let mut separator = "";
+                $(
+                    if self.is_set(<$flag_type>::$flag) {
+                        write!(f, "{}{}", separator, $name)?;
+                        residual &= !(<$flag_type>::$flag as $flag_unsigned_type);
+                        separator = "|";
+                    }
+                )+
+                if residual != 0 || separator.is_empty() {
+                    write!(f, "{}{:#x}", separator, residual)?;
+                }
+                Ok(())
+            }
+        }
+    };
     (new_type: $flags_type:ident, underlying_flag_type: $flag_type:ty, repr: $flag_unsigned_type:ty, nodisplay) => {
         #[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
         pub struct $flags_type {
@@ -38,7 +61,7 @@ macro_rules! make_bitmap {
                 }
             }
 
-            fn is_set(&self, flag: $flag_type) -> bool {
+            pub fn is_set(&self, flag: $flag_type) -> bool {
                 self.bits & (flag as $flag_unsigned_type) != 0
             }
 
@@ -46,7 +69,7 @@ macro_rules! make_bitmap {
                 self.bits |= flag as $flag_unsigned_type;
             }
 
-            fn clear_flag(&mut self, flag: $flag_type) {
+            pub fn clear_flag(&mut self, flag: $flag_type) {
                 self.bits &= !(flag as $flag_unsigned_type);
             }
         }
@@ -0,0 +1,206 @@
+// Boot performance counters: how many timestamp counter cycles the bootloader spends in each of
+// the steps below, and how many times it went through them, for the kernel to print at entry.
+// Only measured with the `metrics` feature. Without it `measure` just runs the step and the
+// counters stay at 0, so the BootInfo they travel in is laid out the same either way
+use core::fmt::Display;
+
+#[cfg(feature = "metrics")]
+use crate::{interrupts, random};
+
+pub const COUNTERS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Counter {
+    DiskRead,
+    ElfParse,
+    SegmentCopy,
+    Relocation,
+    PageTableSetup,
+}
+
+impl Counter {
+    pub const ALL: [Counter; COUNTERS] = [
+        Counter::DiskRead,
+        Counter::ElfParse,
+        Counter::SegmentCopy,
+        Counter::Relocation,
+        Counter::PageTableSetup,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Counter::DiskRead => "disk reads",
+            Counter::ElfParse => "ELF parsing",
+            Counter::SegmentCopy => "segment copies",
+            Counter::Relocation => "relocation",
+            Counter::PageTableSetup => "page table setup",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Sample {
+    pub cycles: u64,
+    pub count: u64,
+}
+
+/// The samples of every counter. Laid out with no padding, so that it's the same for the 32-bit
+/// bootloader and the 64-bit kernel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct BootMetrics {
+    samples: [Sample; COUNTERS],
+}
+
+impl BootMetrics {
+    pub const fn new() -> Self {
+        Self {
+            samples: [Sample {
+                cycles: 0,
+                count: 0,
+            }; COUNTERS],
+        }
+    }
+
+    pub fn record(&mut self, counter: Counter, cycles: u64) {
+        let sample = &mut self.samples[counter as usize];
+        sample.cycles = sample.cycles.saturating_add(cycles);
+        sample.count += 1;
+    }
+
+    pub fn sample(&self, counter: Counter) -> Sample {
+        self.samples[counter as usize]
+    }
+
+    /// Whether anything was measured, i.e. the bootloader was built with the `metrics` feature
+    pub fn is_empty(&self) -> bool {
+        self.samples.iter().all(|sample| sample.count == 0)
+    }
+
+    /// The samples as times, given the frequency of the timestamp counter
+    pub fn breakdown(&self, timestamp_counter_hz: u64) -> Breakdown<'_> {
+        Breakdown {
+            metrics: self,
+            timestamp_counter_hz: timestamp_counter_hz.max(1),
+        }
+    }
+}
+
+/// One line per counter with the time spent in it, how many times, and its share of the total
+pub struct Breakdown<'a> {
+    metrics: &'a BootMetrics,
+    timestamp_counter_hz: u64,
+}
+
+impl Display for Breakdown<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let total_cycles: u64 = self
+            .metrics
+            .samples
+            .iter()
+            .map(|sample| sample.cycles)
+            .sum();
+        for (i, counter) in Counter::ALL.iter().enumerate() {
+            let sample = self.metrics.sample(*counter);
+            let microseconds = (u128::from(sample.cycles) * 1_000_000
+                / u128::from(self.timestamp_counter_hz)) as u64;
+            let percent = sample.cycles * 100 / total_cycles.max(1);
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{:<16} {:>6}.{:03} ms {:>3}% ({} times)",
+                counter.name(),
+                microseconds / 1_000,
+                microseconds % 1_000,
+                percent,
+                sample.count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "metrics")]
+static mut BOOT_METRICS: BootMetrics = BootMetrics::new();
+
+/// Runs `step`, adding the cycles it took to `counter`
+#[cfg(feature = "metrics")]
+pub fn measure<R>(counter: Counter, step: impl FnOnce() -> R) -> R {
+    let start = random::read_timestamp_counter();
+    let result = step();
+    let cycles = random::read_timestamp_counter().wrapping_sub(start);
+    interrupts::without_interrupts(|| {
+        let metrics_ptr = &raw mut BOOT_METRICS;
+        // SAFETY: no threads, and interrupts are disabled so no handler can measure concurrently
+        unsafe { (*metrics_ptr).record(counter, cycles) };
+    });
+    result
+}
+
+/// Runs `step`, adding the cycles it took to `counter`
+#[cfg(not(feature = "metrics"))]
+pub fn measure<R>(_counter: Counter, step: impl FnOnce() -> R) -> R {
+    step()
+}
+
+/// What was measured so far
+pub fn boot_metrics() -> BootMetrics {
+    #[cfg(feature = "metrics")]
+    {
+        interrupts::without_interrupts(|| {
+            let metrics_ptr = &raw const BOOT_METRICS;
+            // SAFETY: no threads, and interrupts are disabled so nothing measures concurrently
+            unsafe { *metrics_ptr }
+        })
+    }
+    #[cfg(not(feature = "metrics"))]
+    BootMetrics::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        metrics::{BootMetrics, COUNTERS, Counter, Sample},
+        test_support::TestWriter,
+    };
+
+    #[test]
+    fn breakdown() {
+        assert_eq!(16 * COUNTERS, size_of::<BootMetrics>());
+        let mut metrics = BootMetrics::new();
+        assert!(metrics.is_empty());
+        metrics.record(Counter::DiskRead, 2_500_000);
+        metrics.record(Counter::DiskRead, 500_000);
+        metrics.record(Counter::PageTableSetup, 1_000_000);
+        assert!(!metrics.is_empty());
+        assert_eq!(
+            Sample {
+                cycles: 3_000_000,
+                count: 2
+            },
+            metrics.sample(Counter::DiskRead)
+        );
+
+        let mut writer = TestWriter::<512>::new();
+        write!(writer, "{}", metrics.breakdown(1_000_000_000)).unwrap();
+        let mut lines = writer.as_str().lines();
+        assert_eq!(
+            Some("disk reads            3.000 ms  75% (2 times)"),
+            lines.next()
+        );
+        assert_eq!(
+            Some("segment copies        0.000 ms   0% (0 times)"),
+            lines.nth(1)
+        );
+        assert_eq!(
+            Some("page table setup      1.000 ms  25% (1 times)"),
+            lines.last()
+        );
+    }
+}
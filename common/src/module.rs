@@ -0,0 +1,335 @@
+// Loads relocatable ELF objects (.o files) into memory, like a tiny static linker: allocated
+// sections are laid out one after the other, and relocations are applied against them and
+// against the symbols the host exports
+// https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.reloc.html
+
+use crate::{
+    elf::{
+        self,
+        header::{Class, Machine, ObjectType},
+        relocation::{RelocationEntries, RelocationEntry, RelocationType},
+        section::{FlagType, SectionEntryType, StringTable},
+        symbol::{ABSOLUTE_SECTION_INDEX, SymbolTable, UNDEFINED_SECTION_INDEX},
+    },
+    error::{Context, Error, Facility, Fault, bounded_context},
+};
+
+/// Name of the function the loader of a module calls once it's in memory, as
+/// `extern "C" fn() -> i32`
+pub const INIT_SYMBOL: &str = "module_init";
+const MAX_SECTIONS: usize = 64;
+/// Modules are x86_64 objects, relocated as they're loaded
+const MODULE_TARGET: elf::Target = elf::Target {
+    machine: Machine::X86_64,
+    class: Class::Elf64,
+    object_types: &[ObjectType::Relocatable],
+};
+
+/// A module laid out in memory, ready to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Module {
+    /// How many bytes of the memory passed to `load` the module uses, from the start
+    pub size: usize,
+    /// Address of the init function
+    pub init: u64,
+}
+
+fn loading_error(fault: Fault, facility: Facility) -> Error {
+    Error::new(fault, Context::LoadingModule, facility)
+}
+
+/// Lays out the allocated sections of `object` at the start of `memory` and relocates them,
+/// looking up undefined symbols with `resolve`
+pub fn load(
+    object: &[u8],
+    memory: &mut [u8],
+    resolve: impl Fn(&str) -> Option<u64>,
+) -> Result<Module, Error> {
+    let file = elf::File::try_from(object)?;
+    file.check_target(&MODULE_TARGET)
+        .map_err(|err| loading_error(err.fault(), Facility::ElfHeader))?;
+    let section_count = file.header().section_header_entries() as usize;
+    if section_count > MAX_SECTIONS {
+        return Err(loading_error(
+            Fault::TooManySections(section_count),
+            Facility::ElfSectionHeader,
+        ));
+    }
+
+    let base = memory.as_ptr() as u64;
+    let mut section_addresses = [None; MAX_SECTIONS];
+    let mut size = 0;
+    let mut symbol_table_section = None;
+    for (index, section_entry_header) in file.sections().enumerate() {
+        let section_entry_header = section_entry_header?;
+        let facility = Facility::ElfSectionHeaderEntry(index as u16);
+        if matches!(section_entry_header.r#type(), SectionEntryType::Symtab) {
+            symbol_table_section = Some(section_entry_header);
+            continue;
+        }
+        if !section_entry_header.flags().is_set(FlagType::Allocated) {
+            continue;
+        }
+
+        let alignment = section_entry_header.address_alignment().max(1);
+        let start = ((base + size as u64).next_multiple_of(alignment) - base) as usize;
+        let end = start + section_entry_header.size() as usize;
+        let destination = memory
+            .get_mut(start..end)
+            .ok_or(loading_error(Fault::ModuleTooLarge(end), facility))?;
+        if matches!(section_entry_header.r#type(), SectionEntryType::NoBits) {
+            destination.fill(0);
+        } else {
+            destination.copy_from_slice(
+                file.section_bytes(&section_entry_header)
+                    .ok_or(loading_error(Fault::NotEnoughBytesFor("section"), facility))?,
+            );
+        }
+        section_addresses[index] = Some(base + start as u64);
+        size = end;
+    }
+
+    let symbol_table_section = symbol_table_section.ok_or(loading_error(
+        Fault::UndefinedSymbol(bounded_context(INIT_SYMBOL.as_bytes())),
+        Facility::ElfSymbolTable,
+    ))?;
+    let symbols = SymbolTable::new(file.section_bytes(&symbol_table_section).ok_or(
+        loading_error(
+            Fault::NotEnoughBytesFor("symbols"),
+            Facility::ElfSymbolTable,
+        ),
+    )?)?;
+    let names = file
+        .sections()
+        .nth(symbol_table_section.link() as usize)
+        .ok_or(loading_error(
+            Fault::InvalidValueForField("link"),
+            Facility::ElfSymbolTable,
+        ))??;
+    let names = StringTable::new(file.section_bytes(&names).ok_or(loading_error(
+        Fault::NotEnoughBytesFor("symbol names"),
+        Facility::ElfStringTable,
+    ))?);
+
+    let symbol_address = |index: usize| -> Result<u64, Error> {
+        let facility = Facility::ElfSymbol(index as u32);
+        let symbol = symbols.get(index).ok_or(loading_error(
+            Fault::InvalidValueForField("symbol index"),
+            facility,
+        ))??;
+        match symbol.section_index() {
+            UNDEFINED_SECTION_INDEX => {
+                let name = names.get_string(symbol.name_index() as usize)?;
+                resolve(name).ok_or(loading_error(
+                    Fault::UndefinedSymbol(bounded_context(name.as_bytes())),
+                    facility,
+                ))
+            }
+            ABSOLUTE_SECTION_INDEX => Ok(symbol.value()),
+            section_index => section_addresses
+                .get(section_index as usize)
+                .copied()
+                .flatten()
+                .map(|address| address + symbol.value())
+                .ok_or(loading_error(
+                    Fault::InvalidValueForField("symbol section"),
+                    facility,
+                )),
+        }
+    };
+
+    for (index, section_entry_header) in file.sections().enumerate() {
+        let section_entry_header = section_entry_header?;
+        if !matches!(section_entry_header.r#type(), SectionEntryType::Rela) {
+            continue;
+        }
+        // Relocations for sections that aren't loaded, like debug info, don't matter
+        let Some(Some(target_address)) = section_addresses
+            .get(section_entry_header.info() as usize)
+            .copied()
+        else {
+            continue;
+        };
+        let relocations = RelocationEntries::new(
+            file.section_bytes(&section_entry_header)
+                .ok_or(loading_error(
+                    Fault::NotEnoughBytesFor("relocations"),
+                    Facility::ElfSectionHeaderEntry(index as u16),
+                ))?,
+        )?;
+        for relocation in relocations {
+            let relocation = relocation?;
+            let symbol = match relocation.symbol_index() {
+                0 => 0,
+                symbol_index => symbol_address(symbol_index as usize)?,
+            };
+            apply_relocation(
+                &relocation,
+                symbol,
+                target_address + relocation.offset(),
+                base,
+                &mut memory[..size],
+            )?;
+        }
+    }
+
+    let init = (0..symbols.len())
+        .find_map(|index| {
+            let symbol = symbols.get(index)?.ok()?;
+            (symbol.section_index() != UNDEFINED_SECTION_INDEX
+                && names.get_string(symbol.name_index() as usize).ok()? == INIT_SYMBOL)
+                .then_some(index)
+        })
+        .ok_or(loading_error(
+            Fault::UndefinedSymbol(bounded_context(INIT_SYMBOL.as_bytes())),
+            Facility::ElfSymbolTable,
+        ))
+        .and_then(symbol_address)?;
+
+    Ok(Module { size, init })
+}
+
+/// Patches the location at address `place` of `memory`, which starts at address `base`
+fn apply_relocation(
+    relocation: &RelocationEntry,
+    symbol: u64,
+    place: u64,
+    base: u64,
+    memory: &mut [u8],
+) -> Result<(), Error> {
+    let relocation_error = |fault| loading_error(fault, Facility::ElfRelocationTable);
+    let value = symbol.wrapping_add(relocation.addend() as u64);
+    let overflow = || relocation_error(Fault::RelocationOverflow(value));
+    let mut bytes = [0u8; size_of::<u64>()];
+    let length = match relocation.r#type().map_err(relocation_error)? {
+        RelocationType::None => return Ok(()),
+        RelocationType::Absolute64 => {
+            bytes = value.to_le_bytes();
+            size_of::<u64>()
+        }
+        RelocationType::Pc32 | RelocationType::Plt32 => {
+            let value = i32::try_from(value.wrapping_sub(place) as i64).map_err(|_| overflow())?;
+            bytes[..4].copy_from_slice(&value.to_le_bytes());
+            size_of::<u32>()
+        }
+        RelocationType::Absolute32 => {
+            let value = u32::try_from(value).map_err(|_| overflow())?;
+            bytes[..4].copy_from_slice(&value.to_le_bytes());
+            size_of::<u32>()
+        }
+        RelocationType::Absolute32Signed => {
+            let value = i32::try_from(value as i64).map_err(|_| overflow())?;
+            bytes[..4].copy_from_slice(&value.to_le_bytes());
+            size_of::<u32>()
+        }
+        // Only found in shared objects and position independent executables
+        RelocationType::Relative => {
+            return Err(relocation_error(Fault::UnsupportedRelocation(
+                RelocationType::Relative as u32,
+            )));
+        }
+    };
+    let offset = place.wrapping_sub(base) as usize;
+    memory
+        .get_mut(offset..offset.saturating_add(length))
+        .ok_or(relocation_error(Fault::RelocationOutOfBounds(place)))?
+        .copy_from_slice(&bytes[..length]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::load;
+
+    const SECTION_HEADER_OFFSET: usize = 64;
+    const TEXT_OFFSET: usize = SECTION_HEADER_OFFSET + 6 * 64;
+    const RELOCATIONS_OFFSET: usize = TEXT_OFFSET + 16;
+    const SYMBOLS_OFFSET: usize = RELOCATIONS_OFFSET + 2 * 24;
+    const NAMES_OFFSET: usize = SYMBOLS_OFFSET + 3 * 24;
+    const NAMES: &[u8] = b"\0module_init\0kernel_print\0";
+    const OBJECT_SIZE: usize = NAMES_OFFSET + NAMES.len();
+
+    #[repr(C, align(4096))]
+    struct Memory([u8; 4096]);
+
+    /// An object with a .text calling kernel_print and holding its own address, and a .bss
+    fn object() -> [u8; OBJECT_SIZE] {
+        let mut bytes = [0u8; OBJECT_SIZE];
+        bytes[..8].copy_from_slice(b"\x7fELF\x02\x01\x01\x00");
+        bytes[16..18].copy_from_slice(&1u16.to_le_bytes());
+        bytes[18..20].copy_from_slice(&0x3eu16.to_le_bytes());
+        bytes[20..24].copy_from_slice(&1u32.to_le_bytes());
+        bytes[40..48].copy_from_slice(&(SECTION_HEADER_OFFSET as u64).to_le_bytes());
+        bytes[52..54].copy_from_slice(&64u16.to_le_bytes());
+        bytes[58..60].copy_from_slice(&64u16.to_le_bytes());
+        bytes[60..62].copy_from_slice(&6u16.to_le_bytes());
+
+        // Index, type, flags, offset, size, link, info, alignment
+        let sections: [(usize, u32, u64, usize, usize, u32, u32, u64); 5] = [
+            (1, 1, 0x6, TEXT_OFFSET, 16, 0, 0, 16),
+            (2, 4, 0x40, RELOCATIONS_OFFSET, 2 * 24, 3, 1, 8),
+            (3, 2, 0, SYMBOLS_OFFSET, 3 * 24, 4, 1, 8),
+            (4, 3, 0, NAMES_OFFSET, NAMES.len(), 0, 0, 1),
+            (5, 8, 0x3, OBJECT_SIZE, 8, 0, 0, 8),
+        ];
+        for (index, r#type, flags, offset, size, link, info, alignment) in sections {
+            let entry = &mut bytes[SECTION_HEADER_OFFSET + index * 64..][..64];
+            entry[4..8].copy_from_slice(&r#type.to_le_bytes());
+            entry[8..16].copy_from_slice(&flags.to_le_bytes());
+            entry[24..32].copy_from_slice(&(offset as u64).to_le_bytes());
+            entry[32..40].copy_from_slice(&(size as u64).to_le_bytes());
+            entry[40..44].copy_from_slice(&link.to_le_bytes());
+            entry[44..48].copy_from_slice(&info.to_le_bytes());
+            entry[48..56].copy_from_slice(&alignment.to_le_bytes());
+        }
+
+        // call kernel_print, with a PLT32 relocation for the displacement
+        bytes[TEXT_OFFSET..TEXT_OFFSET + 8].copy_from_slice(&[0x90, 0x90, 0x90, 0xe8, 0, 0, 0, 0]);
+        // Offset, symbol and type, addend
+        let relocations: [(u64, u64, i64); 2] = [(4, 2 << 32 | 4, -4), (8, 1 << 32 | 1, 0)];
+        for (index, (offset, info, addend)) in relocations.into_iter().enumerate() {
+            let entry = &mut bytes[RELOCATIONS_OFFSET + index * 24..][..24];
+            entry[..8].copy_from_slice(&offset.to_le_bytes());
+            entry[8..16].copy_from_slice(&info.to_le_bytes());
+            entry[16..].copy_from_slice(&addend.to_le_bytes());
+        }
+
+        // module_init, global function at the start of .text, and the undefined kernel_print
+        let symbols: [(u32, u8, u16); 2] = [(1, 0x12, 1), (13, 0x10, 0)];
+        for (index, (name_index, info, section_index)) in symbols.into_iter().enumerate() {
+            let entry = &mut bytes[SYMBOLS_OFFSET + (index + 1) * 24..][..24];
+            entry[..4].copy_from_slice(&name_index.to_le_bytes());
+            entry[4] = info;
+            entry[6..8].copy_from_slice(&section_index.to_le_bytes());
+        }
+        bytes[NAMES_OFFSET..].copy_from_slice(NAMES);
+        bytes
+    }
+
+    #[test]
+    fn loads_and_relocates() {
+        let object = object();
+        let mut memory = Memory([0xff; 4096]);
+        let base = memory.0.as_ptr() as u64;
+        let kernel_print = base + 0x800;
+        let module = load(&object, &mut memory.0, |name| {
+            (name == "kernel_print").then_some(kernel_print)
+        })
+        .unwrap();
+
+        assert_eq!(base, module.init);
+        // .text, then .bss zeroed at the next multiple of 8
+        assert_eq!(24, module.size);
+        assert_eq!([0; 8], memory.0[16..24]);
+        // Relative to the end of the call instruction
+        let displacement = i32::from_le_bytes(memory.0[4..8].try_into().unwrap());
+        assert_eq!(0x800 - 8, displacement);
+        assert_eq!(
+            base,
+            u64::from_le_bytes(memory.0[8..16].try_into().unwrap())
+        );
+
+        assert!(load(&object, &mut memory.0, |_| None).is_err());
+        assert!(load(&object, &mut memory.0[..20], |_| Some(kernel_print)).is_err());
+    }
+}
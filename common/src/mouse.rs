@@ -0,0 +1,246 @@
+// PS/2 mouse driver. The mouse sends a packet of 3 bytes per movement or button change, 4 with the
+// IntelliMouse extension, where the last one is the wheel
+// https://wiki.osdev.org/PS/2_Mouse
+use crate::{
+    error::{Context, Error, Facility, Fault},
+    interrupts, make_bitmap, ps2,
+    ring_buffer::RingBuffer,
+};
+
+const EVENT_BUFFER_SIZE: usize = 64;
+
+const ACKNOWLEDGE: u8 = 0xFA;
+// The device ID once the IntelliMouse knock sequence switched the wheel on
+const INTELLIMOUSE_ID: u8 = 3;
+// Setting these sample rates in a row is how a mouse is asked to report its wheel
+const INTELLIMOUSE_KNOCK: [SampleRate; 3] =
+    [SampleRate::Hz200, SampleRate::Hz100, SampleRate::Hz80];
+
+// First byte of a packet
+const ALWAYS_SET: u8 = 0x08;
+const X_SIGN: u8 = 0x10;
+const Y_SIGN: u8 = 0x20;
+const X_OVERFLOW: u8 = 0x40;
+const Y_OVERFLOW: u8 = 0x80;
+const BUTTONS: u8 = 0x07;
+
+static mut MOUSE: Mouse = Mouse::new(false);
+static mut MOUSE_EVENTS: RingBuffer<MouseEvent, EVENT_BUFFER_SIZE> = RingBuffer::new(MouseEvent {
+    dx: 0,
+    dy: 0,
+    wheel: 0,
+    buttons: Buttons::empty(),
+});
+
+#[allow(unused)]
+#[repr(u8)]
+enum DeviceCommand {
+    GetDeviceId = 0xF2,
+    SetSampleRate = 0xF3,
+    EnableDataReporting = 0xF4,
+    SetDefaults = 0xF6,
+}
+
+/// Packets per second the mouse sends at most, the ones it supports
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum SampleRate {
+    Hz10 = 10,
+    Hz20 = 20,
+    Hz40 = 40,
+    Hz60 = 60,
+    Hz80 = 80,
+    Hz100 = 100,
+    Hz200 = 200,
+}
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum Button {
+    Left = 0x1,
+    Right = 0x2,
+    Middle = 0x4,
+}
+
+make_bitmap!(new_type: Buttons, underlying_flag_type: Button, repr: u8, nodisplay);
+
+/// What a packet reported: movement since the previous one, with positive `dy` going up, and the
+/// buttons held down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    /// Positive when scrolling down, always 0 without the wheel extension
+    pub wheel: i8,
+    pub buttons: Buttons,
+}
+
+/// Puts packets back together from the bytes the mouse sends
+#[derive(Debug, Clone, Copy)]
+pub struct Mouse {
+    packet: [u8; 4],
+    received: usize,
+    has_wheel: bool,
+}
+
+impl Mouse {
+    pub const fn new(has_wheel: bool) -> Self {
+        Self {
+            packet: [0; 4],
+            received: 0,
+            has_wheel,
+        }
+    }
+
+    pub fn has_wheel(&self) -> bool {
+        self.has_wheel
+    }
+
+    fn packet_size(&self) -> usize {
+        if self.has_wheel { 4 } else { 3 }
+    }
+
+    /// Feeds the next byte from the mouse. Returns the event, if the byte completed a packet.
+    /// Packets whose movement overflowed are dropped
+    pub fn process_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // Bytes lost along the way would shift every packet after them. A first byte always has
+        // this bit set, which is enough to get back in sync sooner or later
+        if self.received == 0 && byte & ALWAYS_SET == 0 {
+            return None;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet_size() {
+            return None;
+        }
+        self.received = 0;
+
+        let [flags, dx, dy, wheel] = self.packet;
+        if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+            return None;
+        }
+        // The sign bits make dx and dy 9-bit two's complement numbers
+        Some(MouseEvent {
+            dx: i16::from(dx) - (i16::from(flags & X_SIGN) << 4),
+            dy: i16::from(dy) - (i16::from(flags & Y_SIGN) << 3),
+            wheel: if self.has_wheel { wheel as i8 } else { 0 },
+            buttons: Buttons::from(flags & BUTTONS),
+        })
+    }
+}
+
+fn mouse_error(fault: Fault) -> Error {
+    Error::new(fault, Context::Io, Facility::Ps2Mouse)
+}
+
+/// Sends a command or its argument, which the mouse acknowledges one byte at a time
+fn send_byte(byte: u8) -> Result<(), Error> {
+    match ps2::send_to_auxiliary_device(byte)? {
+        ACKNOWLEDGE => Ok(()),
+        response => Err(mouse_error(Fault::UnexpectedPs2Response(response))),
+    }
+}
+
+fn send(command: DeviceCommand) -> Result<(), Error> {
+    send_byte(command as u8)
+}
+
+fn set_sample_rate(sample_rate: SampleRate) -> Result<(), Error> {
+    send(DeviceCommand::SetSampleRate)?;
+    send_byte(sample_rate as u8)
+}
+
+/// Enables the mouse on the controller's auxiliary port and has it report at `sample_rate`,
+/// with the wheel if it has one. True if it does. The IRQ handler is expected to call
+/// `handle_interrupt_no_sync` afterwards, so this must run with the mouse IRQ masked
+pub fn init(sample_rate: SampleRate) -> Result<bool, Error> {
+    ps2::send_command(ps2::Command::EnableAuxiliaryDevice)?;
+    let mut configuration = ps2::read_configuration()?;
+    configuration.set_flag(ps2::ConfigurationFlag::AuxiliaryInterrupt);
+    configuration.clear_flag(ps2::ConfigurationFlag::AuxiliaryClockDisabled);
+    ps2::write_configuration(configuration)?;
+
+    send(DeviceCommand::SetDefaults)?;
+    for knock_rate in INTELLIMOUSE_KNOCK {
+        set_sample_rate(knock_rate)?;
+    }
+    send(DeviceCommand::GetDeviceId)?;
+    let has_wheel = ps2::receive()? == INTELLIMOUSE_ID;
+    set_sample_rate(sample_rate)?;
+    send(DeviceCommand::EnableDataReporting)?;
+
+    let mouse_ptr = &raw mut MOUSE;
+    interrupts::without_interrupts(|| {
+        // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+        unsafe { *mouse_ptr = Mouse::new(has_wheel) };
+    });
+    Ok(has_wheel)
+}
+
+/// Decodes the bytes the mouse sent into the event queue. Events coming while the queue is full
+/// are dropped
+pub fn handle_interrupt_no_sync() {
+    let mouse_ptr = &raw mut MOUSE;
+    // SAFETY: no threads, and the mouse is only accessed with interrupts disabled outside of the
+    // interrupt handler
+    let mouse = unsafe { &mut *mouse_ptr };
+    let events_ptr = &raw mut MOUSE_EVENTS;
+    // SAFETY: same as above
+    let events = unsafe { &mut *events_ptr };
+    while ps2::has_auxiliary_data() {
+        if let Some(event) = mouse.process_byte(ps2::read_data()) {
+            let _ = events.push(event);
+        }
+    }
+}
+
+/// Returns the oldest event not read yet, if any, without blocking
+pub fn read_event() -> Option<MouseEvent> {
+    interrupts::without_interrupts(|| {
+        let events_ptr = &raw mut MOUSE_EVENTS;
+        // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+        let events = unsafe { &mut *events_ptr };
+        events.pop()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mouse::{Button, Buttons, Mouse, MouseEvent};
+
+    #[test]
+    fn packets() {
+        let mut mouse = Mouse::new(false);
+        // A stray byte without the always set bit, then left button down, moving right and down
+        let events = [0x01, 0x29, 0x05, 0xFB].map(|byte| mouse.process_byte(byte));
+        assert_eq!(
+            [
+                None,
+                None,
+                None,
+                Some(MouseEvent {
+                    dx: 5,
+                    dy: -5,
+                    wheel: 0,
+                    buttons: Buttons::from(Button::Left),
+                })
+            ],
+            events
+        );
+        // Overflowed packets are dropped
+        let events = [0x48, 0xFF, 0x00].map(|byte| mouse.process_byte(byte));
+        assert_eq!([None; 3], events);
+
+        let mut mouse = Mouse::new(true);
+        let events = [0x1C, 0xFE, 0x00, 0xFF].map(|byte| mouse.process_byte(byte));
+        assert_eq!(
+            Some(MouseEvent {
+                dx: -2,
+                dy: 0,
+                wheel: -1,
+                buttons: Buttons::from(Button::Middle),
+            }),
+            events[3]
+        );
+    }
+}
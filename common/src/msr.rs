@@ -0,0 +1,562 @@
+#[cfg(target_arch = "x86")]
+use core::arch::x86::__cpuid;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+use core::{arch::asm, fmt::Display};
+
+use num_enum::TryFromPrimitive;
+
+// https://cdrdv2-public.intel.com/868137/325462-089-sdm-vol-1-2abcd-3abcd-4.pdf (vol. 4)
+use crate::{
+    control_registers::ExtendedFeatureEnableRegister,
+    fpu::{FeatureInformationEdx, FeatureInformationEdxBit},
+    make_bitmap,
+};
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
+pub const IA32_MTRRCAP: u32 = 0xFE;
+pub const IA32_MCG_CAP: u32 = 0x179;
+pub const IA32_MCG_STATUS: u32 = 0x17A;
+pub const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+pub const IA32_MTRR_PHYSMASK0: u32 = 0x201;
+pub const IA32_PAT: u32 = 0x277;
+pub const IA32_MTRR_DEF_TYPE: u32 = 0x2FF;
+/// The status register of the first machine check bank, each bank has 4 registers: CTL, STATUS,
+/// ADDR and MISC
+pub const IA32_MC0_STATUS: u32 = 0x401;
+pub const IA32_MC0_ADDR: u32 = 0x402;
+pub const IA32_EFER: u32 = 0xC000_0080;
+pub const IA32_FS_BASE: u32 = 0xC000_0100;
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+const PAGE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const MACHINE_CHECK_BANK_REGISTERS: u32 = 4;
+const FEATURE_INFORMATION: u32 = 0x1;
+
+pub enum Msr {
+    Efer(ExtendedFeatureEnableRegister),
+    ApicBase(ApicBase),
+    FsBase(u64),
+    GsBase(u64),
+    KernelGsBase(u64),
+    Pat(PageAttributeTable),
+    MtrrDefaultType(MtrrDefaultType),
+    /// Base of the variable range MTRR with the given index
+    MtrrPhysicalBase(u8, MtrrPhysicalBase),
+    /// Mask of the variable range MTRR with the given index
+    MtrrPhysicalMask(u8, MtrrPhysicalMask),
+}
+
+impl Msr {
+    fn index(&self) -> u32 {
+        match self {
+            Msr::Efer(_) => IA32_EFER,
+            Msr::ApicBase(_) => IA32_APIC_BASE,
+            Msr::FsBase(_) => IA32_FS_BASE,
+            Msr::GsBase(_) => IA32_GS_BASE,
+            Msr::KernelGsBase(_) => IA32_KERNEL_GS_BASE,
+            Msr::Pat(_) => IA32_PAT,
+            Msr::MtrrDefaultType(_) => IA32_MTRR_DEF_TYPE,
+            Msr::MtrrPhysicalBase(index, _) => IA32_MTRR_PHYSBASE0 + 2 * *index as u32,
+            Msr::MtrrPhysicalMask(index, _) => IA32_MTRR_PHYSMASK0 + 2 * *index as u32,
+        }
+    }
+
+    fn value(&self) -> u64 {
+        match self {
+            Msr::Efer(efer) => (*efer).into(),
+            Msr::ApicBase(apic_base) => (*apic_base).into(),
+            Msr::FsBase(base) | Msr::GsBase(base) | Msr::KernelGsBase(base) => *base,
+            Msr::Pat(pat) => pat.0,
+            Msr::MtrrDefaultType(default_type) => (*default_type).into(),
+            Msr::MtrrPhysicalBase(_, physical_base) => physical_base.0,
+            Msr::MtrrPhysicalMask(_, physical_mask) => (*physical_mask).into(),
+        }
+    }
+}
+
+/// Reads the MSR with the given index. Reading an MSR the CPU doesn't implement raises #GP, so
+/// outside of this module MSRs are only read through their typed wrappers
+pub(crate) fn rdmsr(register_index: u32) -> u64 {
+    let (low, high): (u32, u32);
+    // SAFETY: Reading an MSR has no side effects on memory
+    unsafe {
+        asm!(
+          "rdmsr",
+          out("eax") low,
+          out("edx") high,
+          in("ecx") register_index,
+          options(nomem, nostack, preserves_flags)
+        )
+    }
+    (high as u64) << 32 | low as u64
+}
+
+pub fn wrmsr(msr: &Msr) {
+    let bits = msr.value();
+    let (low, high) = (bits as u32, (bits >> 32) as u32);
+
+    // SAFETY: The validity of the value for the given MSR is guaranteed by the type signature
+    unsafe {
+        asm!(
+          "wrmsr",
+          in("eax") low,
+          in("edx") high,
+          in("ecx") msr.index(),
+        )
+    }
+}
+
+pub fn read_fs_base() -> u64 {
+    rdmsr(IA32_FS_BASE)
+}
+
+pub fn read_gs_base() -> u64 {
+    rdmsr(IA32_GS_BASE)
+}
+
+/// The GS base `swapgs` exchanges with the current one
+pub fn read_kernel_gs_base() -> u64 {
+    rdmsr(IA32_KERNEL_GS_BASE)
+}
+
+#[allow(unused)]
+#[repr(u64)]
+pub enum ApicBaseFlag {
+    BootstrapProcessor = 1 << 8,
+    X2ApicEnable = 1 << 10,
+    ApicGlobalEnable = 1 << 11,
+}
+
+make_bitmap!(new_type: ApicBase, underlying_flag_type: ApicBaseFlag, repr: u64, nodisplay);
+
+impl ApicBase {
+    pub fn read() -> Self {
+        Self::from(rdmsr(IA32_APIC_BASE))
+    }
+
+    /// Physical address of the local APIC registers
+    pub fn base_address(&self) -> u64 {
+        self.bits & PAGE_ADDRESS_MASK
+    }
+
+    /// Moves the local APIC registers, `base_address` must be page aligned
+    pub fn set_base_address(&mut self, base_address: u64) {
+        self.bits = (self.bits & !PAGE_ADDRESS_MASK) | (base_address & PAGE_ADDRESS_MASK);
+    }
+}
+
+/// Memory types for the PAT and the MTRRs
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    /// Only valid in the PAT: uncacheable, unless the MTRRs say write-combining
+    UncacheableMinus = 7,
+}
+
+impl Display for MemoryType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mnemonic = match self {
+            MemoryType::Uncacheable => "UC",
+            MemoryType::WriteCombining => "WC",
+            MemoryType::WriteThrough => "WT",
+            MemoryType::WriteProtected => "WP",
+            MemoryType::WriteBack => "WB",
+            MemoryType::UncacheableMinus => "UC-",
+        };
+        write!(f, "{mnemonic}")
+    }
+}
+
+pub const PAT_ENTRIES: usize = 8;
+
+/// The 8 memory types page table entries can select through their PAT, PCD and PWT bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageAttributeTable(u64);
+
+impl Default for PageAttributeTable {
+    /// The power-on value: WB, WT, UC-, UC, repeated twice
+    fn default() -> Self {
+        Self(0x0007_0406_0007_0406)
+    }
+}
+
+impl PageAttributeTable {
+    pub fn read() -> Self {
+        Self(rdmsr(IA32_PAT))
+    }
+
+    /// None if `index` is out of bounds or the entry holds a reserved encoding
+    pub fn entry(&self, index: usize) -> Option<MemoryType> {
+        if index >= PAT_ENTRIES {
+            return None;
+        }
+        MemoryType::try_from((self.0 >> (index * 8)) as u8).ok()
+    }
+
+    /// # Panics
+    /// Panics if `index` is not lower than `PAT_ENTRIES`
+    pub fn set_entry(&mut self, index: usize, memory_type: MemoryType) {
+        assert!(index < PAT_ENTRIES, "PAT index {index} out of bounds");
+        self.0 &= !(0xff << (index * 8));
+        self.0 |= (memory_type as u64) << (index * 8);
+    }
+}
+
+impl Display for PageAttributeTable {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for index in 0..PAT_ENTRIES {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+            match self.entry(index) {
+                Some(memory_type) => write!(f, "PA{index}={memory_type}")?,
+                None => write!(f, "PA{index}=?")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[allow(unused)]
+#[repr(u64)]
+pub enum MtrrCapabilitiesFlag {
+    FixedRangeSupported = 1 << 8,
+    WriteCombiningSupported = 1 << 10,
+    SystemManagementRangeSupported = 1 << 11,
+}
+
+make_bitmap!(new_type: MtrrCapabilities, underlying_flag_type: MtrrCapabilitiesFlag, repr: u64, nodisplay);
+
+impl MtrrCapabilities {
+    pub fn read() -> Self {
+        Self::from(rdmsr(IA32_MTRRCAP))
+    }
+
+    pub fn variable_range_count(&self) -> u8 {
+        self.bits as u8
+    }
+}
+
+#[allow(unused)]
+#[repr(u64)]
+pub enum MtrrDefaultTypeFlag {
+    FixedRangeEnable = 1 << 10,
+    Enable = 1 << 11,
+}
+
+make_bitmap!(new_type: MtrrDefaultType, underlying_flag_type: MtrrDefaultTypeFlag, repr: u64, nodisplay);
+
+impl MtrrDefaultType {
+    pub fn read() -> Self {
+        Self::from(rdmsr(IA32_MTRR_DEF_TYPE))
+    }
+
+    /// Memory type of the physical memory no MTRR covers
+    pub fn memory_type(&self) -> Option<MemoryType> {
+        MemoryType::try_from(self.bits as u8).ok()
+    }
+
+    pub fn set_memory_type(&mut self, memory_type: MemoryType) {
+        self.bits = (self.bits & !0xff) | memory_type as u64;
+    }
+}
+
+/// Start and memory type of a variable range MTRR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtrrPhysicalBase(u64);
+
+impl MtrrPhysicalBase {
+    pub fn new(base_address: u64, memory_type: MemoryType) -> Self {
+        Self((base_address & PAGE_ADDRESS_MASK) | memory_type as u64)
+    }
+
+    /// # Panics
+    /// Panics if `index` is not lower than the variable range count of `MtrrCapabilities`
+    pub fn read(index: u8) -> Self {
+        assert!(index < MtrrCapabilities::read().variable_range_count());
+        Self(rdmsr(IA32_MTRR_PHYSBASE0 + 2 * index as u32))
+    }
+
+    pub fn base_address(&self) -> u64 {
+        self.0 & PAGE_ADDRESS_MASK
+    }
+
+    pub fn memory_type(&self) -> Option<MemoryType> {
+        MemoryType::try_from(self.0 as u8).ok()
+    }
+}
+
+#[allow(unused)]
+#[repr(u64)]
+pub enum MtrrPhysicalMaskFlag {
+    Valid = 1 << 11,
+}
+
+make_bitmap!(new_type: MtrrPhysicalMask, underlying_flag_type: MtrrPhysicalMaskFlag, repr: u64, nodisplay);
+
+impl MtrrPhysicalMask {
+    /// # Panics
+    /// Panics if `index` is not lower than the variable range count of `MtrrCapabilities`
+    pub fn read(index: u8) -> Self {
+        assert!(index < MtrrCapabilities::read().variable_range_count());
+        Self::from(rdmsr(IA32_MTRR_PHYSMASK0 + 2 * index as u32))
+    }
+
+    /// An address is in the range when `address & mask == base & mask`
+    pub fn mask(&self) -> u64 {
+        self.bits & PAGE_ADDRESS_MASK
+    }
+
+    pub fn set_mask(&mut self, mask: u64) {
+        self.bits = (self.bits & !PAGE_ADDRESS_MASK) | (mask & PAGE_ADDRESS_MASK);
+    }
+}
+
+/// Whether the CPU raises #MC once CR4.MCE is set, rather than shutting down on machine checks
+pub fn supports_machine_check_exception() -> bool {
+    // SAFETY: The `__cpuid` instruction is safe to call with the given arguments.
+    let result = FeatureInformationEdx::from(unsafe { __cpuid(FEATURE_INFORMATION).edx });
+    result.is_set(FeatureInformationEdxBit::MachineCheckException)
+}
+
+/// Whether the CPU has the IA32_MCG_* registers and the machine check banks, which can only be
+/// read if so
+pub fn supports_machine_check_architecture() -> bool {
+    // SAFETY: The `__cpuid` instruction is safe to call with the given arguments.
+    let result = FeatureInformationEdx::from(unsafe { __cpuid(FEATURE_INFORMATION).edx });
+    result.is_set(FeatureInformationEdxBit::MachineCheckArchitecture)
+}
+
+#[allow(unused)]
+#[repr(u64)]
+pub enum MachineCheckCapabilitiesFlag {
+    ControlPresent = 1 << 8,
+    ExtendedStatePresent = 1 << 9,
+    CorrectedInterruptPresent = 1 << 10,
+}
+
+make_bitmap!(new_type: MachineCheckCapabilities, underlying_flag_type: MachineCheckCapabilitiesFlag, repr: u64, nodisplay);
+
+impl MachineCheckCapabilities {
+    /// Only if `supports_machine_check_architecture`
+    pub fn read() -> Self {
+        Self::from(rdmsr(IA32_MCG_CAP))
+    }
+
+    pub fn bank_count(&self) -> u8 {
+        self.bits as u8
+    }
+}
+
+#[allow(unused)]
+#[derive(TryFromPrimitive, Clone, Copy)]
+#[repr(u64)]
+pub enum MachineCheckGlobalStatusFlag {
+    /// Execution can restart at the instruction pointer pushed for the #MC
+    RestartIpValid = 1 << 0,
+    /// The instruction pointer pushed for the #MC is the one of the instruction at fault
+    ErrorIpValid = 1 << 1,
+    /// A machine check is being handled, another one shuts the processor down
+    InProgress = 1 << 2,
+}
+
+impl Display for MachineCheckGlobalStatusFlag {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mnemonic = match self {
+            MachineCheckGlobalStatusFlag::RestartIpValid => "RIPV",
+            MachineCheckGlobalStatusFlag::ErrorIpValid => "EIPV",
+            MachineCheckGlobalStatusFlag::InProgress => "MCIP",
+        };
+        write!(f, "{mnemonic}")
+    }
+}
+
+make_bitmap!(new_type: MachineCheckGlobalStatus, underlying_flag_type: MachineCheckGlobalStatusFlag, repr: u64, bit_skipper: |i| i > 2);
+
+impl MachineCheckGlobalStatus {
+    /// Only if `supports_machine_check_architecture`
+    pub fn read() -> Self {
+        Self::from(rdmsr(IA32_MCG_STATUS))
+    }
+}
+
+#[allow(unused)]
+#[derive(TryFromPrimitive, Clone, Copy)]
+#[repr(u64)]
+pub enum MachineCheckStatusFlag {
+    /// The processor context is corrupt, execution can't go on
+    ProcessorContextCorrupt = 1 << 57,
+    AddressValid = 1 << 58,
+    MiscValid = 1 << 59,
+    /// Reporting the error was enabled in the bank's IA32_MCi_CTL
+    Enabled = 1 << 60,
+    /// The error wasn't corrected
+    Uncorrected = 1 << 61,
+    /// There was another error before this one was cleared
+    Overflow = 1 << 62,
+    /// The bank holds an error
+    Valid = 1 << 63,
+}
+
+impl Display for MachineCheckStatusFlag {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        use MachineCheckStatusFlag::*;
+        let mnemonic = match self {
+            ProcessorContextCorrupt => "PCC",
+            AddressValid => "ADDRV",
+            MiscValid => "MISCV",
+            Enabled => "EN",
+            Uncorrected => "UC",
+            Overflow => "OVER",
+            Valid => "VAL",
+        };
+        write!(f, "{mnemonic}")
+    }
+}
+
+make_bitmap!(new_type: MachineCheckStatus, underlying_flag_type: MachineCheckStatusFlag, repr: u64, bit_skipper: |i| i < 57);
+
+impl MachineCheckStatus {
+    /// The architectural error code, e.g. 0x0150 for a memory read error
+    pub fn error_code(&self) -> u16 {
+        self.bits as u16
+    }
+
+    /// The error code only the processor's model gives a meaning to
+    pub fn model_specific_error_code(&self) -> u16 {
+        (self.bits >> 16) as u16
+    }
+}
+
+/// An error a machine check bank logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MachineCheckBank {
+    pub index: u8,
+    pub status: MachineCheckStatus,
+    /// The address of the error, if the status says there's one
+    pub address: Option<u64>,
+}
+
+impl MachineCheckBank {
+    /// The bank with the given index, None if it holds no error. Only if
+    /// `supports_machine_check_architecture`, for an index lower than the bank count
+    pub fn read(index: u8) -> Option<Self> {
+        let first_register = MACHINE_CHECK_BANK_REGISTERS * index as u32;
+        let status = MachineCheckStatus::from(rdmsr(IA32_MC0_STATUS + first_register));
+        if !status.is_set(MachineCheckStatusFlag::Valid) {
+            return None;
+        }
+        let address = status
+            .is_set(MachineCheckStatusFlag::AddressValid)
+            .then(|| rdmsr(IA32_MC0_ADDR + first_register));
+        Some(Self {
+            index,
+            status,
+            address,
+        })
+    }
+
+    /// The banks holding an error, none if the CPU has no machine check architecture
+    pub fn read_all() -> impl Iterator<Item = Self> {
+        let bank_count = if supports_machine_check_architecture() {
+            MachineCheckCapabilities::read().bank_count()
+        } else {
+            0
+        };
+        (0..bank_count).filter_map(Self::read)
+    }
+}
+
+impl Display for MachineCheckBank {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "MC{}_STATUS={:#018x} ({}) MCACOD={:#06x} MSCOD={:#06x}",
+            self.index,
+            u64::from(self.status),
+            self.status,
+            self.status.error_code(),
+            self.status.model_specific_error_code()
+        )?;
+        if let Some(address) = self.address {
+            write!(f, " ADDR={address:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        msr::{
+            ApicBase, ApicBaseFlag, MachineCheckBank, MachineCheckGlobalStatus, MachineCheckStatus,
+            MemoryType, Msr, MtrrPhysicalBase, PageAttributeTable,
+        },
+        test_support::TestWriter,
+    };
+
+    #[test]
+    fn page_attribute_table_entries() {
+        let mut pat = PageAttributeTable::default();
+        assert_eq!(Some(MemoryType::WriteBack), pat.entry(0));
+        assert_eq!(Some(MemoryType::UncacheableMinus), pat.entry(6));
+        assert_eq!(None, pat.entry(8));
+
+        pat.set_entry(4, MemoryType::WriteCombining);
+        assert_eq!(Some(MemoryType::WriteCombining), pat.entry(4));
+        assert_eq!(Some(MemoryType::WriteBack), pat.entry(0));
+        assert_eq!(0x0007_0401_0007_0406, Msr::Pat(pat).value());
+    }
+
+    #[test]
+    fn apic_base_address() {
+        let mut apic_base = ApicBase::from(0xfee0_0900);
+        assert_eq!(0xfee0_0000, apic_base.base_address());
+        assert!(apic_base.is_set(ApicBaseFlag::ApicGlobalEnable));
+
+        apic_base.set_base_address(0xfec0_0000);
+        assert_eq!(0xfec0_0900, u64::from(apic_base));
+
+        let physical_base = MtrrPhysicalBase::new(0x8000_0fff, MemoryType::WriteCombining);
+        assert_eq!(0x8000_0001, physical_base.0);
+        assert_eq!(0x206, Msr::MtrrPhysicalBase(3, physical_base).index());
+    }
+
+    #[test]
+    fn machine_check_banks() {
+        let status = MachineCheckStatus::from(0xbe00_0000_0001_0150);
+        assert_eq!(0x0150, status.error_code());
+        assert_eq!(1, status.model_specific_error_code());
+
+        let mut bank = MachineCheckBank {
+            index: 4,
+            status,
+            address: Some(0x1234_5000),
+        };
+        let mut writer = TestWriter::<128>::new();
+        write!(writer, "{bank}").unwrap();
+        assert_eq!(
+            "MC4_STATUS=0xbe00000000010150 (PCC|ADDRV|MISCV|EN|UC|VAL) MCACOD=0x0150 \
+             MSCOD=0x0001 ADDR=0x12345000",
+            writer.as_str()
+        );
+
+        bank.address = None;
+        let mut writer = TestWriter::<128>::new();
+        write!(writer, "{bank}").unwrap();
+        assert!(writer.as_str().ends_with("MSCOD=0x0001"));
+
+        let mut writer = TestWriter::<128>::new();
+        write!(writer, "{}", MachineCheckGlobalStatus::from(0b101)).unwrap();
+        assert_eq!("RIPV|MCIP", writer.as_str());
+    }
+}
@@ -0,0 +1,175 @@
+// https://datatracker.ietf.org/doc/html/rfc826
+// Only IPv4 over Ethernet is supported
+use num_enum::TryFromPrimitive;
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::{
+    error::{Error, Facility, Fault},
+    net::{Interface, Ipv4Address, MacAddress, ethernet::EtherType},
+};
+
+const HARDWARE_TYPE_ETHERNET: u16 = 1;
+const CACHE_ENTRIES: usize = 16;
+
+mod inner {
+    use zerocopy::{BE, FromBytes, Immutable, IntoBytes, KnownLayout, U16, Unaligned};
+
+    #[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Packet {
+        pub(super) hardware_type: U16<BE>,
+        pub(super) protocol_type: U16<BE>,
+        pub(super) hardware_address_length: u8,
+        pub(super) protocol_address_length: u8,
+        pub(super) operation: U16<BE>,
+        pub(super) sender_hardware_address: [u8; 6],
+        pub(super) sender_protocol_address: [u8; 4],
+        pub(super) target_hardware_address: [u8; 6],
+        pub(super) target_protocol_address: [u8; 4],
+    }
+}
+
+pub const PACKET_SIZE: usize = size_of::<inner::Packet>();
+
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Operation {
+    Request = 1,
+    Reply = 2,
+}
+
+pub struct Packet<'a>(&'a inner::Packet);
+
+impl<'a> Packet<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        let (packet, _padding) = inner::Packet::ref_from_prefix(bytes).map_err(|_| {
+            Error::parsing_error(Fault::NotEnoughBytesFor("ARP packet"), Facility::ArpPacket)
+        })?;
+        if packet.hardware_type.get() != HARDWARE_TYPE_ETHERNET
+            || packet.protocol_type.get() != EtherType::Ipv4 as u16
+            || packet.hardware_address_length != 6
+            || packet.protocol_address_length != 4
+        {
+            return Err(Error::parsing_error(
+                Fault::InvalidValueForField("address types"),
+                Facility::ArpPacket,
+            ));
+        }
+        Ok(Self(packet))
+    }
+
+    /// None for operations other than request and reply
+    pub fn operation(&self) -> Option<Operation> {
+        Operation::try_from(self.0.operation.get()).ok()
+    }
+
+    pub fn sender_mac_address(&self) -> MacAddress {
+        MacAddress(self.0.sender_hardware_address)
+    }
+
+    pub fn sender_ipv4_address(&self) -> Ipv4Address {
+        Ipv4Address(self.0.sender_protocol_address)
+    }
+
+    pub fn target_mac_address(&self) -> MacAddress {
+        MacAddress(self.0.target_hardware_address)
+    }
+
+    pub fn target_ipv4_address(&self) -> Ipv4Address {
+        Ipv4Address(self.0.target_protocol_address)
+    }
+}
+
+/// Writes an ARP packet at the start of `buffer`, returning its size
+pub fn write_packet(
+    buffer: &mut [u8],
+    operation: Operation,
+    sender: (MacAddress, Ipv4Address),
+    target: (MacAddress, Ipv4Address),
+) -> Result<usize, Error> {
+    inner::Packet {
+        hardware_type: HARDWARE_TYPE_ETHERNET.into(),
+        protocol_type: (EtherType::Ipv4 as u16).into(),
+        hardware_address_length: 6,
+        protocol_address_length: 4,
+        operation: (operation as u16).into(),
+        sender_hardware_address: sender.0.0,
+        sender_protocol_address: sender.1.0,
+        target_hardware_address: target.0.0,
+        target_protocol_address: target.1.0,
+    }
+    .write_to_prefix(buffer)
+    .map_err(|_| {
+        Error::parsing_error(Fault::NotEnoughBytesFor("ARP packet"), Facility::ArpPacket)
+    })?;
+    Ok(PACKET_SIZE)
+}
+
+/// Writes the reply to `packet` into `buffer` if it's a request for `interface`'s address,
+/// returning its size
+pub fn answer(
+    interface: &Interface,
+    packet: &Packet,
+    buffer: &mut [u8],
+) -> Result<Option<usize>, Error> {
+    if packet.operation() != Some(Operation::Request)
+        || packet.target_ipv4_address() != interface.ipv4_address
+    {
+        return Ok(None);
+    }
+    write_packet(
+        buffer,
+        Operation::Reply,
+        (interface.mac_address, interface.ipv4_address),
+        (packet.sender_mac_address(), packet.sender_ipv4_address()),
+    )
+    .map(Some)
+}
+
+/// The MAC addresses of the hosts seen on the network, oldest entries replaced first
+pub struct Cache {
+    entries: [Option<(Ipv4Address, MacAddress)>; CACHE_ENTRIES],
+    next_replaced: usize,
+}
+
+impl Cache {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; CACHE_ENTRIES],
+            next_replaced: 0,
+        }
+    }
+
+    pub fn get(&self, ipv4_address: Ipv4Address) -> Option<MacAddress> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|(address, _)| *address == ipv4_address)
+            .map(|(_, mac_address)| *mac_address)
+    }
+
+    /// Adds or updates the MAC address of `ipv4_address`. The unspecified address, which
+    /// hosts without an address yet send requests from, isn't cached
+    pub fn insert(&mut self, ipv4_address: Ipv4Address, mac_address: MacAddress) {
+        if ipv4_address == Ipv4Address::UNSPECIFIED {
+            return;
+        }
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|(address, _)| *address == ipv4_address)
+        {
+            entry.1 = mac_address;
+            return;
+        }
+        self.entries[self.next_replaced] = Some((ipv4_address, mac_address));
+        self.next_replaced = (self.next_replaced + 1) % CACHE_ENTRIES;
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
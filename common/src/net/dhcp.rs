@@ -0,0 +1,434 @@
+// https://datatracker.ietf.org/doc/html/rfc2131
+// https://datatracker.ietf.org/doc/html/rfc2132
+// Only the initial lease is acquired: renewing it is left to whoever runs the client, by
+// restarting it before the lease expires
+use core::fmt;
+
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::{
+    error::{Error, Facility, Fault},
+    net::{Ipv4Address, MacAddress},
+};
+
+pub const CLIENT_PORT: u16 = 68;
+pub const SERVER_PORT: u16 = 67;
+
+const BOOT_REQUEST: u8 = 1;
+const BOOT_REPLY: u8 = 2;
+const HARDWARE_TYPE_ETHERNET: u8 = 1;
+// Asks servers to broadcast their replies, as we can't receive unicast before having an address
+const FLAG_BROADCAST: u16 = 1 << 15;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DOMAIN_NAME_SERVER: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_IDENTIFIER: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_END: u8 = 255;
+
+const PARAMETER_REQUEST_LIST: [u8; 4] = [
+    OPTION_SUBNET_MASK,
+    OPTION_ROUTER,
+    OPTION_DOMAIN_NAME_SERVER,
+    OPTION_LEASE_TIME,
+];
+/// Big enough for any message the client sends
+pub const MAX_MESSAGE_SIZE: usize = 300;
+
+mod inner {
+    use zerocopy::{BE, FromBytes, Immutable, IntoBytes, KnownLayout, U16, U32, Unaligned};
+
+    #[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Header {
+        pub(super) operation: u8,
+        pub(super) hardware_type: u8,
+        pub(super) hardware_address_length: u8,
+        pub(super) hops: u8,
+        pub(super) transaction_id: U32<BE>,
+        pub(super) seconds: U16<BE>,
+        pub(super) flags: U16<BE>,
+        pub(super) client_address: [u8; 4],
+        pub(super) your_address: [u8; 4],
+        pub(super) server_address: [u8; 4],
+        pub(super) gateway_address: [u8; 4],
+        pub(super) client_hardware_address: [u8; 16],
+        pub(super) server_name: [u8; 64],
+        pub(super) file: [u8; 128],
+        pub(super) magic_cookie: [u8; 4],
+    }
+}
+
+const HEADER_SIZE: usize = size_of::<inner::Header>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Ack = 5,
+    Nak = 6,
+}
+
+fn parsing_error(fault: Fault) -> Error {
+    Error::parsing_error(fault, Facility::DhcpMessage)
+}
+
+/// What the server handed out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Ipv4Address,
+    pub subnet_mask: Ipv4Address,
+    pub router: Option<Ipv4Address>,
+    pub dns_server: Option<Ipv4Address>,
+    pub server: Ipv4Address,
+    pub lease_time_seconds: u32,
+}
+
+impl fmt::Display for Lease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} from {} for {}s",
+            self.address, self.subnet_mask, self.server, self.lease_time_seconds
+        )?;
+        if let Some(router) = self.router {
+            write!(f, ", router {router}")?;
+        }
+        if let Some(dns_server) = self.dns_server {
+            write!(f, ", DNS {dns_server}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The options of a message the client cares about
+#[derive(Default)]
+struct Options {
+    message_type: Option<u8>,
+    subnet_mask: Option<Ipv4Address>,
+    router: Option<Ipv4Address>,
+    dns_server: Option<Ipv4Address>,
+    lease_time_seconds: Option<u32>,
+    server_identifier: Option<Ipv4Address>,
+}
+
+fn first_address(value: &[u8]) -> Option<Ipv4Address> {
+    value
+        .first_chunk::<4>()
+        .map(|address| Ipv4Address(*address))
+}
+
+fn parse_options(mut bytes: &[u8]) -> Result<Options, Error> {
+    let mut options = Options::default();
+    loop {
+        match bytes {
+            [] | [OPTION_END, ..] => return Ok(options),
+            [OPTION_PAD, rest @ ..] => bytes = rest,
+            [code, length, rest @ ..] if rest.len() >= *length as usize => {
+                let (value, rest) = rest.split_at(*length as usize);
+                match *code {
+                    OPTION_MESSAGE_TYPE => options.message_type = value.first().copied(),
+                    OPTION_SUBNET_MASK => options.subnet_mask = first_address(value),
+                    OPTION_ROUTER => options.router = first_address(value),
+                    OPTION_DOMAIN_NAME_SERVER => options.dns_server = first_address(value),
+                    OPTION_SERVER_IDENTIFIER => options.server_identifier = first_address(value),
+                    OPTION_LEASE_TIME => {
+                        options.lease_time_seconds = value
+                            .first_chunk::<4>()
+                            .map(|time| u32::from_be_bytes(*time))
+                    }
+                    _ => {}
+                }
+                bytes = rest;
+            }
+            _ => return Err(parsing_error(Fault::NotEnoughBytesFor("DHCP option"))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Looking for servers
+    Selecting,
+    /// Asking the server that made an offer for it
+    Requesting {
+        offered: Ipv4Address,
+        server: Ipv4Address,
+    },
+    Bound(Lease),
+}
+
+/// The client side of the DHCP exchange: DISCOVER, OFFER, REQUEST, ACK. It doesn't keep time:
+/// whoever runs it sends `write_message`'s message again if no reply came after a while
+pub struct Client {
+    mac_address: MacAddress,
+    transaction_id: u32,
+    state: State,
+}
+
+impl Client {
+    /// `transaction_id` should be random, to tell apart the replies to different clients
+    pub fn new(mac_address: MacAddress, transaction_id: u32) -> Self {
+        Self {
+            mac_address,
+            transaction_id,
+            state: State::Selecting,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Starts over from looking for servers, e.g. after too many unanswered requests
+    pub fn restart(&mut self, transaction_id: u32) {
+        self.transaction_id = transaction_id;
+        self.state = State::Selecting;
+    }
+
+    /// Writes the message to send in the current state into `buffer`, to be sent from
+    /// CLIENT_PORT to SERVER_PORT of the broadcast address. Returns its size, or None once bound
+    pub fn write_message(&self, buffer: &mut [u8]) -> Result<Option<usize>, Error> {
+        let (message_type, requested) = match self.state {
+            State::Selecting => (MessageType::Discover, None),
+            State::Requesting { offered, server } => {
+                (MessageType::Request, Some((offered, server)))
+            }
+            State::Bound(_) => return Ok(None),
+        };
+
+        let mut client_hardware_address = [0; 16];
+        client_hardware_address[..6].copy_from_slice(&self.mac_address.0);
+        inner::Header {
+            operation: BOOT_REQUEST,
+            hardware_type: HARDWARE_TYPE_ETHERNET,
+            hardware_address_length: 6,
+            hops: 0,
+            transaction_id: self.transaction_id.into(),
+            seconds: 0.into(),
+            flags: FLAG_BROADCAST.into(),
+            client_address: [0; 4],
+            your_address: [0; 4],
+            server_address: [0; 4],
+            gateway_address: [0; 4],
+            client_hardware_address,
+            server_name: [0; 64],
+            file: [0; 128],
+            magic_cookie: MAGIC_COOKIE,
+        }
+        .write_to_prefix(buffer)
+        .map_err(|_| parsing_error(Fault::NotEnoughBytesFor("DHCP message")))?;
+
+        let mut length = HEADER_SIZE;
+        let mut push_option = |code: u8, value: &[u8]| -> Result<(), Error> {
+            let option = buffer
+                .get_mut(length..length + 2 + value.len())
+                .ok_or(parsing_error(Fault::NotEnoughBytesFor("DHCP option")))?;
+            option[0] = code;
+            option[1] = value.len() as u8;
+            option[2..].copy_from_slice(value);
+            length += option.len();
+            Ok(())
+        };
+        push_option(OPTION_MESSAGE_TYPE, &[message_type as u8])?;
+        if let Some((offered, server)) = requested {
+            push_option(OPTION_REQUESTED_ADDRESS, &offered.0)?;
+            push_option(OPTION_SERVER_IDENTIFIER, &server.0)?;
+        }
+        push_option(OPTION_PARAMETER_REQUEST_LIST, &PARAMETER_REQUEST_LIST)?;
+        *buffer
+            .get_mut(length)
+            .ok_or(parsing_error(Fault::NotEnoughBytesFor("DHCP option")))? = OPTION_END;
+        Ok(Some(length + 1))
+    }
+
+    /// Handles a message received on CLIENT_PORT. Returns the lease when the server
+    /// acknowledges it. Messages for other clients, and those that make no sense in the current
+    /// state, are ignored
+    pub fn handle_message(&mut self, bytes: &[u8]) -> Result<Option<Lease>, Error> {
+        let (header, options) = inner::Header::ref_from_prefix(bytes)
+            .map_err(|_| parsing_error(Fault::NotEnoughBytesFor("DHCP message")))?;
+        if header.operation != BOOT_REPLY
+            || header.transaction_id.get() != self.transaction_id
+            || header.client_hardware_address[..6] != self.mac_address.0
+        {
+            return Ok(None);
+        }
+        if header.magic_cookie != MAGIC_COOKIE {
+            return Err(parsing_error(Fault::InvalidValueForField("magic cookie")));
+        }
+        let options = parse_options(options)?;
+        let offered = Ipv4Address(header.your_address);
+
+        match (self.state, options.message_type) {
+            (State::Selecting, Some(message_type)) if message_type == MessageType::Offer as u8 => {
+                let server =
+                    options
+                        .server_identifier
+                        .ok_or(parsing_error(Fault::InvalidValueForField(
+                            "server identifier",
+                        )))?;
+                self.state = State::Requesting { offered, server };
+                Ok(None)
+            }
+            (State::Requesting { server, .. }, Some(message_type))
+                if message_type == MessageType::Ack as u8 =>
+            {
+                let lease = Lease {
+                    address: offered,
+                    subnet_mask: options
+                        .subnet_mask
+                        .unwrap_or(Ipv4Address([255, 255, 255, 0])),
+                    router: options.router,
+                    dns_server: options.dns_server,
+                    server,
+                    lease_time_seconds: options.lease_time_seconds.unwrap_or(u32::MAX),
+                };
+                self.state = State::Bound(lease);
+                Ok(Some(lease))
+            }
+            (State::Requesting { .. }, Some(message_type))
+                if message_type == MessageType::Nak as u8 =>
+            {
+                self.state = State::Selecting;
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::{
+        Ipv4Address, MacAddress,
+        dhcp::{Client, HEADER_SIZE, MAX_MESSAGE_SIZE, State},
+    };
+
+    const MAC_ADDRESS: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    const TRANSACTION_ID: u32 = 0x1234_5678;
+
+    /// A reply from QEMU's server at 10.0.2.2 with the given message type
+    fn reply(message_type: u8, buffer: &mut [u8; MAX_MESSAGE_SIZE]) -> &[u8] {
+        buffer.fill(0);
+        buffer[0] = 2;
+        buffer[1] = 1;
+        buffer[2] = 6;
+        buffer[4..8].copy_from_slice(&TRANSACTION_ID.to_be_bytes());
+        buffer[16..20].copy_from_slice(&[10, 0, 2, 15]);
+        buffer[28..34].copy_from_slice(&MAC_ADDRESS.0);
+        buffer[236..240].copy_from_slice(&[99, 130, 83, 99]);
+        let options = [
+            53,
+            1,
+            message_type,
+            54,
+            4,
+            10,
+            0,
+            2,
+            2,
+            1,
+            4,
+            255,
+            255,
+            255,
+            0,
+            3,
+            4,
+            10,
+            0,
+            2,
+            2,
+            6,
+            4,
+            10,
+            0,
+            2,
+            3,
+            51,
+            4,
+            0,
+            1,
+            0x51,
+            0x80,
+            255,
+        ];
+        buffer[240..240 + options.len()].copy_from_slice(&options);
+        &buffer[..240 + options.len()]
+    }
+
+    #[test]
+    fn acquires_a_lease() {
+        let mut client = Client::new(MAC_ADDRESS, TRANSACTION_ID);
+        let mut message = [0u8; MAX_MESSAGE_SIZE];
+        let mut server_message = [0u8; MAX_MESSAGE_SIZE];
+
+        let length = client.write_message(&mut message).unwrap().unwrap();
+        assert_eq!([1, 1, 6], message[..3]);
+        assert_eq!(MAC_ADDRESS.0, message[28..34]);
+        assert_eq!([53, 1, 1], message[HEADER_SIZE..HEADER_SIZE + 3]);
+        assert_eq!(255, message[length - 1]);
+
+        assert_eq!(
+            None,
+            client
+                .handle_message(reply(2, &mut server_message))
+                .unwrap()
+        );
+        assert_eq!(
+            State::Requesting {
+                offered: Ipv4Address([10, 0, 2, 15]),
+                server: Ipv4Address([10, 0, 2, 2])
+            },
+            client.state()
+        );
+        client.write_message(&mut message).unwrap().unwrap();
+        assert_eq!(
+            [53, 1, 3, 50, 4, 10, 0, 2, 15, 54, 4, 10, 0, 2, 2],
+            message[HEADER_SIZE..HEADER_SIZE + 15]
+        );
+
+        let lease = client
+            .handle_message(reply(5, &mut server_message))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Ipv4Address([10, 0, 2, 15]), lease.address);
+        assert_eq!(Ipv4Address([255, 255, 255, 0]), lease.subnet_mask);
+        assert_eq!(Some(Ipv4Address([10, 0, 2, 2])), lease.router);
+        assert_eq!(Some(Ipv4Address([10, 0, 2, 3])), lease.dns_server);
+        assert_eq!(86400, lease.lease_time_seconds);
+        assert_eq!(State::Bound(lease), client.state());
+        assert_eq!(None, client.write_message(&mut message).unwrap());
+    }
+
+    #[test]
+    fn ignores_other_transactions_and_restarts_on_nak() {
+        let mut client = Client::new(MAC_ADDRESS, TRANSACTION_ID + 1);
+        let mut server_message = [0u8; MAX_MESSAGE_SIZE];
+        assert_eq!(
+            None,
+            client
+                .handle_message(reply(2, &mut server_message))
+                .unwrap()
+        );
+        assert_eq!(State::Selecting, client.state());
+
+        client.restart(TRANSACTION_ID);
+        client
+            .handle_message(reply(2, &mut server_message))
+            .unwrap();
+        client
+            .handle_message(reply(6, &mut server_message))
+            .unwrap();
+        assert_eq!(State::Selecting, client.state());
+    }
+}
@@ -0,0 +1,91 @@
+// https://en.wikipedia.org/wiki/Ethernet_frame
+use num_enum::TryFromPrimitive;
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::{
+    error::{Error, Facility, Fault},
+    net::MacAddress,
+};
+
+mod inner {
+    use zerocopy::{BE, FromBytes, Immutable, IntoBytes, KnownLayout, U16, Unaligned};
+
+    #[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Header {
+        pub(super) destination: [u8; 6],
+        pub(super) source: [u8; 6],
+        pub(super) ether_type: U16<BE>,
+    }
+}
+
+pub const HEADER_SIZE: usize = size_of::<inner::Header>();
+
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum EtherType {
+    Ipv4 = 0x0800,
+    Arp = 0x0806,
+}
+
+/// An Ethernet II frame, without the CRC the card strips
+pub struct Frame<'a> {
+    header: &'a inner::Header,
+    payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        let (header, payload) = inner::Header::ref_from_prefix(bytes).map_err(|_| {
+            Error::parsing_error(
+                Fault::NotEnoughBytesFor("Ethernet header"),
+                Facility::EthernetFrame,
+            )
+        })?;
+        Ok(Self { header, payload })
+    }
+
+    pub fn destination(&self) -> MacAddress {
+        MacAddress(self.header.destination)
+    }
+
+    pub fn source(&self) -> MacAddress {
+        MacAddress(self.header.source)
+    }
+
+    pub fn ether_type_value(&self) -> u16 {
+        self.header.ether_type.get()
+    }
+
+    /// None for the protocols the stack doesn't know
+    pub fn ether_type(&self) -> Option<EtherType> {
+        EtherType::try_from(self.ether_type_value()).ok()
+    }
+
+    /// May include padding after the packet, up to the minimum frame size
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+}
+
+/// Writes an Ethernet header at the start of `buffer`, returning its size
+pub fn write_header(
+    buffer: &mut [u8],
+    destination: MacAddress,
+    source: MacAddress,
+    ether_type: u16,
+) -> Result<usize, Error> {
+    inner::Header {
+        destination: destination.0,
+        source: source.0,
+        ether_type: ether_type.into(),
+    }
+    .write_to_prefix(buffer)
+    .map_err(|_| {
+        Error::parsing_error(
+            Fault::NotEnoughBytesFor("Ethernet header"),
+            Facility::EthernetFrame,
+        )
+    })?;
+    Ok(HEADER_SIZE)
+}
@@ -0,0 +1,108 @@
+// https://datatracker.ietf.org/doc/html/rfc792
+// Only echo requests and replies are supported
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::{
+    error::{Error, Facility, Fault},
+    net::{Interface, checksum, ipv4},
+};
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+
+mod inner {
+    use zerocopy::{BE, FromBytes, Immutable, IntoBytes, KnownLayout, U16, Unaligned};
+
+    #[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct EchoHeader {
+        pub(super) message_type: u8,
+        pub(super) code: u8,
+        pub(super) checksum: U16<BE>,
+        pub(super) identifier: U16<BE>,
+        pub(super) sequence_number: U16<BE>,
+    }
+}
+
+pub const ECHO_HEADER_SIZE: usize = size_of::<inner::EchoHeader>();
+
+fn parsing_error(fault: Fault) -> Error {
+    Error::parsing_error(fault, Facility::IcmpMessage)
+}
+
+/// An ICMP message with a valid checksum, seen through the echo header layout
+pub struct Message<'a> {
+    header: &'a inner::EchoHeader,
+    data: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        let (header, data) = inner::EchoHeader::ref_from_prefix(bytes)
+            .map_err(|_| parsing_error(Fault::NotEnoughBytesFor("ICMP header")))?;
+        let message_checksum = checksum(bytes);
+        if message_checksum != 0 {
+            return Err(parsing_error(Fault::InvalidChecksum(message_checksum)));
+        }
+        Ok(Self { header, data })
+    }
+
+    pub fn is_echo_request(&self) -> bool {
+        self.header.message_type == ECHO_REQUEST && self.header.code == 0
+    }
+
+    pub fn is_echo_reply(&self) -> bool {
+        self.header.message_type == ECHO_REPLY && self.header.code == 0
+    }
+
+    pub fn identifier(&self) -> u16 {
+        self.header.identifier.get()
+    }
+
+    pub fn sequence_number(&self) -> u16 {
+        self.header.sequence_number.get()
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// Writes an IPv4 packet with the reply to `message` into `buffer` if it's an echo request,
+/// returning its size
+pub fn answer(
+    interface: &Interface,
+    packet: &ipv4::Packet,
+    message: &Message,
+    buffer: &mut [u8],
+) -> Result<Option<usize>, Error> {
+    if !message.is_echo_request() {
+        return Ok(None);
+    }
+    let message_length = ECHO_HEADER_SIZE + message.data().len();
+    let header_length = ipv4::write_header(
+        buffer,
+        interface.ipv4_address,
+        packet.source(),
+        ipv4::Protocol::Icmp,
+        packet.identification(),
+        message_length,
+    )?;
+    let reply = buffer
+        .get_mut(header_length..header_length + message_length)
+        .ok_or(parsing_error(Fault::NotEnoughBytesFor("ICMP echo reply")))?;
+
+    inner::EchoHeader {
+        message_type: ECHO_REPLY,
+        code: 0,
+        checksum: 0.into(),
+        identifier: message.identifier().into(),
+        sequence_number: message.sequence_number().into(),
+    }
+    .write_to_prefix(reply)
+    .map_err(|_| parsing_error(Fault::NotEnoughBytesFor("ICMP header")))?;
+    reply[ECHO_HEADER_SIZE..].copy_from_slice(message.data());
+    let reply_checksum = checksum(reply);
+    reply[2..4].copy_from_slice(&reply_checksum.to_be_bytes());
+    Ok(Some(header_length + message_length))
+}
@@ -0,0 +1,137 @@
+// https://datatracker.ietf.org/doc/html/rfc791
+use num_enum::TryFromPrimitive;
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::{
+    error::{Error, Facility, Fault},
+    net::{Ipv4Address, checksum},
+};
+
+const VERSION: u8 = 4;
+const HEADER_WORD_SIZE: usize = 4;
+const DEFAULT_TIME_TO_LIVE: u8 = 64;
+const DONT_FRAGMENT: u16 = 1 << 14;
+const MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+mod inner {
+    use zerocopy::{BE, FromBytes, Immutable, IntoBytes, KnownLayout, U16, Unaligned};
+
+    #[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Header {
+        pub(super) version_and_header_length: u8,
+        pub(super) type_of_service: u8,
+        pub(super) total_length: U16<BE>,
+        pub(super) identification: U16<BE>,
+        pub(super) flags_and_fragment_offset: U16<BE>,
+        pub(super) time_to_live: u8,
+        pub(super) protocol: u8,
+        pub(super) checksum: U16<BE>,
+        pub(super) source: [u8; 4],
+        pub(super) destination: [u8; 4],
+    }
+}
+
+/// Size of a header without options, the only kind the stack sends
+pub const HEADER_SIZE: usize = size_of::<inner::Header>();
+
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Protocol {
+    Icmp = 1,
+    Udp = 17,
+}
+
+fn parsing_error(fault: Fault) -> Error {
+    Error::parsing_error(fault, Facility::Ipv4Packet)
+}
+
+/// An unfragmented IPv4 packet with a valid header checksum
+pub struct Packet<'a> {
+    header: &'a inner::Header,
+    payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        let (header, _) = inner::Header::ref_from_prefix(bytes)
+            .map_err(|_| parsing_error(Fault::NotEnoughBytesFor("IPv4 header")))?;
+        if header.version_and_header_length >> 4 != VERSION {
+            return Err(parsing_error(Fault::InvalidValueForField("version")));
+        }
+        let header_length = (header.version_and_header_length & 0xf) as usize * HEADER_WORD_SIZE;
+        let total_length = header.total_length.get() as usize;
+        if header_length < HEADER_SIZE || total_length < header_length {
+            return Err(parsing_error(Fault::InvalidValueForField("length")));
+        }
+        // Frames may be padded after the packet
+        let packet = bytes
+            .get(..total_length)
+            .ok_or(parsing_error(Fault::NotEnoughBytesFor("IPv4 packet")))?;
+        let header_checksum = checksum(&packet[..header_length]);
+        if header_checksum != 0 {
+            return Err(parsing_error(Fault::InvalidChecksum(header_checksum)));
+        }
+        let fragment = header.flags_and_fragment_offset.get();
+        if fragment & MORE_FRAGMENTS != 0 || fragment & FRAGMENT_OFFSET_MASK != 0 {
+            return Err(parsing_error(Fault::InvalidValueForField("fragment")));
+        }
+        Ok(Self {
+            header,
+            payload: &packet[header_length..],
+        })
+    }
+
+    pub fn source(&self) -> Ipv4Address {
+        Ipv4Address(self.header.source)
+    }
+
+    pub fn destination(&self) -> Ipv4Address {
+        Ipv4Address(self.header.destination)
+    }
+
+    /// None for the protocols the stack doesn't know
+    pub fn protocol(&self) -> Option<Protocol> {
+        Protocol::try_from(self.header.protocol).ok()
+    }
+
+    pub fn identification(&self) -> u16 {
+        self.header.identification.get()
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+}
+
+/// Writes the header of an unfragmented packet carrying `payload_length` bytes at the start of
+/// `buffer`, returning its size
+pub fn write_header(
+    buffer: &mut [u8],
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: Protocol,
+    identification: u16,
+    payload_length: usize,
+) -> Result<usize, Error> {
+    let total_length = u16::try_from(HEADER_SIZE + payload_length)
+        .map_err(|_| parsing_error(Fault::InvalidValueForField("length")))?;
+    let mut header = inner::Header {
+        version_and_header_length: VERSION << 4 | (HEADER_SIZE / HEADER_WORD_SIZE) as u8,
+        type_of_service: 0,
+        total_length: total_length.into(),
+        identification: identification.into(),
+        flags_and_fragment_offset: DONT_FRAGMENT.into(),
+        time_to_live: DEFAULT_TIME_TO_LIVE,
+        protocol: protocol as u8,
+        checksum: 0.into(),
+        source: source.0,
+        destination: destination.0,
+    };
+    header.checksum = checksum(header.as_bytes()).into();
+    header
+        .write_to_prefix(buffer)
+        .map_err(|_| parsing_error(Fault::NotEnoughBytesFor("IPv4 header")))?;
+    Ok(HEADER_SIZE)
+}
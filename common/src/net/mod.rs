@@ -0,0 +1,355 @@
+// Just enough of a network stack to answer ARP requests and pings, and to get an address with
+// DHCP. Packets are parsed into typed views borrowing from the frame, and replies are built in
+// caller provided buffers
+
+pub mod arp;
+pub mod dhcp;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod udp;
+
+use core::fmt;
+
+use crate::error::{Error, Facility, Fault};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: Self = Self([0xff; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Self = Self([0; 4]);
+    pub const BROADCAST: Self = Self([0xff; 4]);
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+/// The Internet checksum (RFC 1071): the one's complement of the one's complement sum of the
+/// 16-bit big endian words in `bytes`. Checksumming data that includes its checksum gives 0
+pub fn checksum(bytes: &[u8]) -> u16 {
+    let mut chunks = bytes.chunks_exact(2);
+    let mut sum = chunks
+        .by_ref()
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The addresses of the network card the stack runs on
+#[derive(Debug, Clone, Copy)]
+pub struct Interface {
+    pub mac_address: MacAddress,
+    /// Unspecified until configured, e.g. by DHCP
+    pub ipv4_address: Ipv4Address,
+    pub subnet_mask: Ipv4Address,
+    /// Where packets for addresses outside of the subnet go
+    pub gateway: Option<Ipv4Address>,
+}
+
+impl Interface {
+    /// An interface without an IPv4 address yet
+    pub fn new(mac_address: MacAddress) -> Self {
+        Self {
+            mac_address,
+            ipv4_address: Ipv4Address::UNSPECIFIED,
+            subnet_mask: Ipv4Address::UNSPECIFIED,
+            gateway: None,
+        }
+    }
+
+    fn accepts(&self, destination: Ipv4Address) -> bool {
+        destination == self.ipv4_address
+            || destination == Ipv4Address::BROADCAST
+            || self.ipv4_address == Ipv4Address::UNSPECIFIED
+    }
+
+    /// The address to resolve to reach `destination`: itself if it's on the subnet, the gateway
+    /// otherwise
+    pub fn next_hop(&self, destination: Ipv4Address) -> Option<Ipv4Address> {
+        let on_subnet = |address: Ipv4Address| {
+            u32::from_be_bytes(address.0) & u32::from_be_bytes(self.subnet_mask.0)
+        };
+        if destination == Ipv4Address::BROADCAST
+            || on_subnet(destination) == on_subnet(self.ipv4_address)
+        {
+            Some(destination)
+        } else {
+            self.gateway
+        }
+    }
+}
+
+/// What `Stack::write_udp_frame` wrote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outgoing {
+    /// A frame with the datagram, of the given length
+    Datagram(usize),
+    /// The next hop's MAC address isn't known, so an ARP request for it was written instead. The
+    /// datagram can be sent once the reply comes in
+    ArpRequest(usize),
+}
+
+/// An interface with the state the protocols on top of it need
+pub struct Stack {
+    pub interface: Interface,
+    pub arp_cache: arp::Cache,
+    pub sockets: udp::Sockets,
+}
+
+impl Stack {
+    pub const fn new(interface: Interface) -> Self {
+        Self {
+            interface,
+            arp_cache: arp::Cache::new(),
+            sockets: udp::Sockets::new(),
+        }
+    }
+
+    /// Handles a received frame: ARP requests and pings for this interface's address are
+    /// answered, UDP datagrams are queued on the socket bound to their port, everything else is
+    /// ignored. Returns the length of the reply written into `reply`, which needs to be as big as
+    /// `frame`, if there is one
+    pub fn handle_frame(&mut self, frame: &[u8], reply: &mut [u8]) -> Result<Option<usize>, Error> {
+        let interface = self.interface;
+        let frame = ethernet::Frame::parse(frame)?;
+        if frame.destination() != interface.mac_address
+            && frame.destination() != MacAddress::BROADCAST
+        {
+            return Ok(None);
+        }
+        let payload_length = match frame.ether_type() {
+            Some(ethernet::EtherType::Arp) => {
+                let packet = arp::Packet::parse(frame.payload())?;
+                self.arp_cache
+                    .insert(packet.sender_ipv4_address(), packet.sender_mac_address());
+                arp::answer(&interface, &packet, &mut reply[ethernet::HEADER_SIZE..])?
+            }
+            Some(ethernet::EtherType::Ipv4) => {
+                let packet = ipv4::Packet::parse(frame.payload())?;
+                if !interface.accepts(packet.destination()) {
+                    return Ok(None);
+                }
+                match packet.protocol() {
+                    Some(ipv4::Protocol::Icmp)
+                        if packet.destination() == interface.ipv4_address =>
+                    {
+                        let message = icmp::Message::parse(packet.payload())?;
+                        icmp::answer(
+                            &interface,
+                            &packet,
+                            &message,
+                            &mut reply[ethernet::HEADER_SIZE..],
+                        )?
+                    }
+                    Some(ipv4::Protocol::Udp) => {
+                        let datagram = udp::Datagram::parse(&packet)?;
+                        self.sockets.deliver(&packet, &datagram);
+                        None
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let Some(payload_length) = payload_length else {
+            return Ok(None);
+        };
+        let header_length = ethernet::write_header(
+            reply,
+            frame.source(),
+            interface.mac_address,
+            frame.ether_type_value(),
+        )?;
+        Ok(Some(header_length + payload_length))
+    }
+
+    /// Writes a frame carrying a UDP datagram from this interface's address into `buffer`, or an
+    /// ARP request if the next hop's MAC address still has to be resolved
+    pub fn write_udp_frame(
+        &self,
+        buffer: &mut [u8],
+        source_port: u16,
+        destination: Ipv4Address,
+        destination_port: u16,
+        payload: &[u8],
+    ) -> Result<Outgoing, Error> {
+        let interface = &self.interface;
+        let next_hop = interface.next_hop(destination).ok_or(Error::parsing_error(
+            Fault::NoRouteTo(destination.0),
+            Facility::Ipv4Packet,
+        ))?;
+        let destination_mac_address = if next_hop == Ipv4Address::BROADCAST {
+            MacAddress::BROADCAST
+        } else if let Some(mac_address) = self.arp_cache.get(next_hop) {
+            mac_address
+        } else {
+            let header_length = ethernet::write_header(
+                buffer,
+                MacAddress::BROADCAST,
+                interface.mac_address,
+                ethernet::EtherType::Arp as u16,
+            )?;
+            let packet_length = arp::write_packet(
+                &mut buffer[header_length..],
+                arp::Operation::Request,
+                (interface.mac_address, interface.ipv4_address),
+                (MacAddress::default(), next_hop),
+            )?;
+            return Ok(Outgoing::ArpRequest(header_length + packet_length));
+        };
+
+        let header_length = ethernet::write_header(
+            buffer,
+            destination_mac_address,
+            interface.mac_address,
+            ethernet::EtherType::Ipv4 as u16,
+        )?;
+        let datagram_length = udp::write_packet(
+            &mut buffer[header_length..],
+            (interface.ipv4_address, source_port),
+            (destination, destination_port),
+            payload,
+        )?;
+        Ok(Outgoing::Datagram(header_length + datagram_length))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::{Interface, Ipv4Address, MacAddress, Outgoing, Stack, checksum};
+
+    // An ARP request from 10.0.2.2 (52:55:0a:00:02:02) for 10.0.2.15, as QEMU sends it
+    const ARP_REQUEST: [u8; 42] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x08, 0x06, 0x00,
+        0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x0a, 0x00,
+        0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x02, 0x0f,
+    ];
+
+    // A ping from 10.0.2.2 to 10.0.2.15, identifier 0x1234, sequence 1, payload "ping"
+    const ECHO_REQUEST: [u8; 46] = [
+        0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x08, 0x00, 0x45,
+        0x00, 0x00, 0x20, 0x00, 0x01, 0x00, 0x00, 0x40, 0x01, 0x62, 0xcc, 0x0a, 0x00, 0x02, 0x02,
+        0x0a, 0x00, 0x02, 0x0f, 0x08, 0x00, 0x06, 0xfa, 0x12, 0x34, 0x00, 0x01, 0x70, 0x69, 0x6e,
+        0x67,
+    ];
+
+    const INTERFACE: Interface = Interface {
+        mac_address: MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
+        ipv4_address: Ipv4Address([10, 0, 2, 15]),
+        subnet_mask: Ipv4Address([255, 255, 255, 0]),
+        gateway: Some(Ipv4Address([10, 0, 2, 2])),
+    };
+
+    #[test]
+    fn internet_checksum() {
+        assert_eq!(0, checksum(&ECHO_REQUEST[14..34]));
+        assert_eq!(0, checksum(&ECHO_REQUEST[34..]));
+        assert_eq!(!0x0102, checksum(&[0x01, 0x02]));
+        assert_eq!(!0x0100, checksum(&[0x01]));
+    }
+
+    #[test]
+    fn answers_arp_requests() {
+        let mut stack = Stack::new(INTERFACE);
+        let mut reply = [0u8; 64];
+        let length = stack
+            .handle_frame(&ARP_REQUEST, &mut reply)
+            .unwrap()
+            .unwrap();
+        assert_eq!(42, length);
+        // Back to the requester, from us
+        assert_eq!(ARP_REQUEST[6..12], reply[..6]);
+        assert_eq!(INTERFACE.mac_address.0, reply[6..12]);
+        assert_eq!([0x08, 0x06], reply[12..14]);
+        // A reply, with our addresses as the sender's and the requester's as the target's
+        assert_eq!([0x00, 0x02], reply[20..22]);
+        assert_eq!(INTERFACE.mac_address.0, reply[22..28]);
+        assert_eq!(INTERFACE.ipv4_address.0, reply[28..32]);
+        assert_eq!(ARP_REQUEST[22..32], reply[32..42]);
+
+        let mut other_request = ARP_REQUEST;
+        other_request[41] = 0x10;
+        assert_eq!(
+            None,
+            stack.handle_frame(&other_request, &mut reply).unwrap()
+        );
+    }
+
+    #[test]
+    fn answers_pings() {
+        let mut stack = Stack::new(INTERFACE);
+        let mut reply = [0u8; 64];
+        let length = stack
+            .handle_frame(&ECHO_REQUEST, &mut reply)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ECHO_REQUEST.len(), length);
+        assert_eq!(ECHO_REQUEST[6..12], reply[..6]);
+        // Addresses swapped, valid checksums
+        assert_eq!(ECHO_REQUEST[26..30], reply[30..34]);
+        assert_eq!(ECHO_REQUEST[30..34], reply[26..30]);
+        assert_eq!(0, checksum(&reply[14..34]));
+        assert_eq!(0, checksum(&reply[34..length]));
+        // An echo reply with the request's identifier, sequence and payload
+        assert_eq!(0, reply[34]);
+        assert_eq!(ECHO_REQUEST[38..], reply[38..length]);
+
+        let mut corrupted = ECHO_REQUEST;
+        corrupted[45] ^= 0xff;
+        assert!(stack.handle_frame(&corrupted, &mut reply).is_err());
+    }
+
+    #[test]
+    fn resolves_next_hop_before_sending() {
+        let mut stack = Stack::new(INTERFACE);
+        let mut buffer = [0u8; 128];
+        let outside = Ipv4Address([192, 168, 1, 1]);
+
+        // The gateway isn't known yet
+        assert_eq!(
+            Outgoing::ArpRequest(42),
+            stack
+                .write_udp_frame(&mut buffer, 1234, outside, 53, b"hi")
+                .unwrap()
+        );
+        assert_eq!([0x00, 0x01], buffer[20..22]);
+        assert_eq!([10, 0, 2, 2], buffer[38..42]);
+
+        // Learned from its ARP request
+        let mut reply = [0u8; 64];
+        stack.handle_frame(&ARP_REQUEST, &mut reply).unwrap();
+        assert_eq!(
+            Outgoing::Datagram(14 + 20 + 8 + 2),
+            stack
+                .write_udp_frame(&mut buffer, 1234, outside, 53, b"hi")
+                .unwrap()
+        );
+        assert_eq!(ARP_REQUEST[6..12], buffer[..6]);
+        assert_eq!([192, 168, 1, 1], buffer[30..34]);
+        assert_eq!(0, checksum(&buffer[14..34]));
+    }
+}
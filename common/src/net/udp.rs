@@ -0,0 +1,246 @@
+// https://datatracker.ietf.org/doc/html/rfc768
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::{
+    error::{Error, Facility, Fault},
+    net::{Ipv4Address, checksum, ipv4},
+    ring_buffer::RingBuffer,
+};
+
+const SOCKETS: usize = 4;
+const SOCKET_QUEUE_LENGTH: usize = 4;
+/// Largest payload that fits in an unfragmented packet on Ethernet
+pub const MAX_PAYLOAD_SIZE: usize = 1500 - ipv4::HEADER_SIZE - HEADER_SIZE;
+// A computed checksum of 0 is sent as all ones, as 0 means there's no checksum
+const NO_CHECKSUM: u16 = 0;
+
+mod inner {
+    use zerocopy::{BE, FromBytes, Immutable, IntoBytes, KnownLayout, U16, Unaligned};
+
+    #[derive(FromBytes, IntoBytes, KnownLayout, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct Header {
+        pub(super) source_port: U16<BE>,
+        pub(super) destination_port: U16<BE>,
+        pub(super) length: U16<BE>,
+        pub(super) checksum: U16<BE>,
+    }
+
+    #[derive(IntoBytes, Immutable, Unaligned)]
+    #[repr(C)]
+    pub(super) struct PseudoHeader {
+        pub(super) source: [u8; 4],
+        pub(super) destination: [u8; 4],
+        pub(super) zero: u8,
+        pub(super) protocol: u8,
+        pub(super) length: U16<BE>,
+    }
+}
+
+pub const HEADER_SIZE: usize = size_of::<inner::Header>();
+
+fn parsing_error(fault: Fault) -> Error {
+    Error::parsing_error(fault, Facility::UdpDatagram)
+}
+
+/// The checksum over the pseudo header and `datagram`, whose checksum field is included
+fn datagram_checksum(source: Ipv4Address, destination: Ipv4Address, datagram: &[u8]) -> u16 {
+    let pseudo_header = inner::PseudoHeader {
+        source: source.0,
+        destination: destination.0,
+        zero: 0,
+        protocol: ipv4::Protocol::Udp as u8,
+        length: (datagram.len() as u16).into(),
+    };
+    // The pseudo header is an even number of bytes, so checksumming it separately and adding
+    // the one's complement sums back together is the same as checksumming it all at once
+    let sum = (!checksum(pseudo_header.as_bytes()) as u32) + (!checksum(datagram) as u32);
+    !((sum & 0xffff) as u16 + (sum >> 16) as u16)
+}
+
+/// A UDP datagram with a valid checksum, or none
+pub struct Datagram<'a> {
+    header: &'a inner::Header,
+    payload: &'a [u8],
+}
+
+impl<'a> Datagram<'a> {
+    pub fn parse(packet: &ipv4::Packet<'a>) -> Result<Self, Error> {
+        let bytes = packet.payload();
+        let (header, _) = inner::Header::ref_from_prefix(bytes)
+            .map_err(|_| parsing_error(Fault::NotEnoughBytesFor("UDP header")))?;
+        let length = header.length.get() as usize;
+        let datagram = bytes
+            .get(..length)
+            .filter(|_| length >= HEADER_SIZE)
+            .ok_or(parsing_error(Fault::InvalidValueForField("length")))?;
+        if header.checksum.get() != NO_CHECKSUM {
+            let sum = datagram_checksum(packet.source(), packet.destination(), datagram);
+            if sum != 0 {
+                return Err(parsing_error(Fault::InvalidChecksum(sum)));
+            }
+        }
+        Ok(Self {
+            header,
+            payload: &datagram[HEADER_SIZE..],
+        })
+    }
+
+    pub fn source_port(&self) -> u16 {
+        self.header.source_port.get()
+    }
+
+    pub fn destination_port(&self) -> u16 {
+        self.header.destination_port.get()
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+}
+
+/// Writes an IPv4 packet carrying a datagram with `payload` into `buffer`, returning its size
+pub fn write_packet(
+    buffer: &mut [u8],
+    (source, source_port): (Ipv4Address, u16),
+    (destination, destination_port): (Ipv4Address, u16),
+    payload: &[u8],
+) -> Result<usize, Error> {
+    if payload.len() > MAX_PAYLOAD_SIZE {
+        return Err(parsing_error(Fault::FrameTooLarge(payload.len())));
+    }
+    let datagram_length = HEADER_SIZE + payload.len();
+    let header_length = ipv4::write_header(
+        buffer,
+        source,
+        destination,
+        ipv4::Protocol::Udp,
+        0,
+        datagram_length,
+    )?;
+    let datagram = buffer
+        .get_mut(header_length..header_length + datagram_length)
+        .ok_or(parsing_error(Fault::NotEnoughBytesFor("UDP datagram")))?;
+    inner::Header {
+        source_port: source_port.into(),
+        destination_port: destination_port.into(),
+        length: (datagram_length as u16).into(),
+        checksum: NO_CHECKSUM.into(),
+    }
+    .write_to_prefix(datagram)
+    .map_err(|_| parsing_error(Fault::NotEnoughBytesFor("UDP header")))?;
+    datagram[HEADER_SIZE..].copy_from_slice(payload);
+    let sum = match datagram_checksum(source, destination, datagram) {
+        NO_CHECKSUM => !NO_CHECKSUM,
+        sum => sum,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    Ok(header_length + datagram_length)
+}
+
+/// A received datagram waiting on a socket
+#[derive(Clone, Copy)]
+pub struct Received {
+    pub source: Ipv4Address,
+    pub source_port: u16,
+    length: usize,
+    payload: [u8; MAX_PAYLOAD_SIZE],
+}
+
+impl Received {
+    const fn blank() -> Self {
+        Self {
+            source: Ipv4Address::UNSPECIFIED,
+            source_port: 0,
+            length: 0,
+            payload: [0; MAX_PAYLOAD_SIZE],
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.length]
+    }
+}
+
+/// Identifies a bound socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketHandle(usize);
+
+struct Socket {
+    port: u16,
+    queue: RingBuffer<Received, SOCKET_QUEUE_LENGTH>,
+}
+
+/// UDP sockets, each receiving the datagrams sent to the port it's bound to. Datagrams that
+/// arrive while a socket's queue is full, or for ports no socket is bound to, are dropped
+pub struct Sockets {
+    sockets: [Option<Socket>; SOCKETS],
+}
+
+impl Sockets {
+    pub const fn new() -> Self {
+        Self {
+            sockets: [const { None }; SOCKETS],
+        }
+    }
+
+    pub fn bind(&mut self, port: u16) -> Result<SocketHandle, Fault> {
+        if self
+            .sockets
+            .iter()
+            .flatten()
+            .any(|socket| socket.port == port)
+        {
+            return Err(Fault::PortInUse(port));
+        }
+        let (index, slot) = self
+            .sockets
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.is_none())
+            .ok_or(Fault::NoFreeSocket)?;
+        *slot = Some(Socket {
+            port,
+            queue: RingBuffer::new(Received::blank()),
+        });
+        Ok(SocketHandle(index))
+    }
+
+    pub fn unbind(&mut self, handle: SocketHandle) {
+        if let Some(slot) = self.sockets.get_mut(handle.0) {
+            *slot = None;
+        }
+    }
+
+    /// The oldest datagram received on the socket
+    pub fn receive(&mut self, handle: SocketHandle) -> Option<Received> {
+        self.sockets.get_mut(handle.0)?.as_mut()?.queue.pop()
+    }
+
+    pub(super) fn deliver(&mut self, packet: &ipv4::Packet, datagram: &Datagram) {
+        let Some(socket) = self
+            .sockets
+            .iter_mut()
+            .flatten()
+            .find(|socket| socket.port == datagram.destination_port())
+        else {
+            return;
+        };
+        // Only jumbo frames carry more than MAX_PAYLOAD_SIZE, and the drivers don't enable them
+        let length = datagram.payload().len().min(MAX_PAYLOAD_SIZE);
+        let mut received = Received {
+            source: packet.source(),
+            source_port: datagram.source_port(),
+            length,
+            ..Received::blank()
+        };
+        received.payload[..length].copy_from_slice(&datagram.payload()[..length]);
+        let _ = socket.queue.push(received);
+    }
+}
+
+impl Default for Sockets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,15 +1,24 @@
 #[cfg(target_arch = "x86")]
-use core::arch::x86::__cpuid;
+use core::arch::x86::{__cpuid, __cpuid_count};
 #[cfg(target_arch = "x86_64")]
-use core::arch::x86_64::__cpuid;
-use core::cmp::min;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::{
+    cmp::min,
+    fmt::{Display, Write},
+    ops::Range,
+};
+
+use num_enum::TryFromPrimitive;
 
 use crate::{
+    control_registers::{ControlRegister4Bit, Cr3, Cr4},
     error::{Fault, Feature},
-    make_bitmap,
+    layout, make_bitmap,
+    msr::{self, MemoryType, Msr, PAT_ENTRIES, PageAttributeTable},
 };
 
 #[allow(unused)]
+#[derive(TryFromPrimitive, Clone, Copy)]
 #[repr(u64)]
 pub enum PageTableEntryFlag {
     Present = 1 << 0,
@@ -19,11 +28,121 @@ pub enum PageTableEntryFlag {
     PageLevelCacheDisable = 1 << 4,
     Accessed = 1 << 5,
     MapsPage = 1 << 7,
+    /// Ignored by the CPU: the page is shared read-only and gets copied on the first write, see
+    /// the frame module
+    CopyOnWrite = 1 << 9,
     HLATRestart = 1 << 11,
     ExecuteDisable = 1 << 63,
 }
 
-make_bitmap!(new_type: PageTableEntry, underlying_flag_type: PageTableEntryFlag, repr: u64, nodisplay);
+impl Display for PageTableEntryFlag {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PageTableEntryFlag::Present => write!(f, "PRESENT"),
+            PageTableEntryFlag::Write => write!(f, "WRITE"),
+            PageTableEntryFlag::AllowUserModeAccess => write!(f, "USER"),
+            PageTableEntryFlag::PageLevelWriteThrough => write!(f, "WRITE_THROUGH"),
+            PageTableEntryFlag::PageLevelCacheDisable => write!(f, "CACHE_DISABLE"),
+            PageTableEntryFlag::Accessed => write!(f, "ACCESSED"),
+            PageTableEntryFlag::MapsPage => write!(f, "MAPS_PAGE"),
+            PageTableEntryFlag::CopyOnWrite => write!(f, "COPY_ON_WRITE"),
+            PageTableEntryFlag::HLATRestart => write!(f, "HLAT_RESTART"),
+            PageTableEntryFlag::ExecuteDisable => write!(f, "EXECUTE_DISABLE"),
+        }
+    }
+}
+
+// Skips the address bits and the bits that are ignored or only meaningful for some levels, except
+// for the ignored one the kernel uses for copy-on-write
+make_bitmap!(new_type: PageTableEntry, underlying_flag_type: PageTableEntryFlag, repr: u64, bit_skipper: |i| !matches!(i, 0..=5 | 7 | 9 | 11 | 63));
+
+#[derive(TryFromPrimitive, Clone, Copy)]
+#[repr(u32)]
+pub enum PageFaultErrorCodeFlag {
+    // Set for protection violations, clear when the page wasn't present
+    Present = 1 << 0,
+    Write = 1 << 1,
+    User = 1 << 2,
+    ReservedBitViolation = 1 << 3,
+    InstructionFetch = 1 << 4,
+    ProtectionKey = 1 << 5,
+    ShadowStack = 1 << 6,
+    SoftwareGuardExtensions = 1 << 15,
+}
+
+impl Display for PageFaultErrorCodeFlag {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PageFaultErrorCodeFlag::Present => write!(f, "PRESENT"),
+            PageFaultErrorCodeFlag::Write => write!(f, "WRITE"),
+            PageFaultErrorCodeFlag::User => write!(f, "USER"),
+            PageFaultErrorCodeFlag::ReservedBitViolation => write!(f, "RESERVED_BIT"),
+            PageFaultErrorCodeFlag::InstructionFetch => write!(f, "INSTRUCTION_FETCH"),
+            PageFaultErrorCodeFlag::ProtectionKey => write!(f, "PROTECTION_KEY"),
+            PageFaultErrorCodeFlag::ShadowStack => write!(f, "SHADOW_STACK"),
+            PageFaultErrorCodeFlag::SoftwareGuardExtensions => write!(f, "SGX"),
+        }
+    }
+}
+
+// https://wiki.osdev.org/Exceptions#Page_Fault
+make_bitmap!(new_type: PageFaultErrorCode, underlying_flag_type: PageFaultErrorCodeFlag, repr: u32, bit_skipper: |i| i > 6 && i != 15);
+
+impl PageFaultErrorCode {
+    /// Whether the fault was caused by a protection violation rather than a non-present page
+    pub fn is_protection_violation(&self) -> bool {
+        self.is_set(PageFaultErrorCodeFlag::Present)
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.is_set(PageFaultErrorCodeFlag::Write)
+    }
+
+    pub fn is_user_mode(&self) -> bool {
+        self.is_set(PageFaultErrorCodeFlag::User)
+    }
+
+    pub fn is_reserved_bit_violation(&self) -> bool {
+        self.is_set(PageFaultErrorCodeFlag::ReservedBitViolation)
+    }
+
+    pub fn is_instruction_fetch(&self) -> bool {
+        self.is_set(PageFaultErrorCodeFlag::InstructionFetch)
+    }
+
+    /// A one line explanation of the fault, e.g. "supervisor write to a non-present page"
+    pub fn describe(&self) -> PageFaultDescription {
+        PageFaultDescription(*self)
+    }
+}
+
+pub struct PageFaultDescription(PageFaultErrorCode);
+
+impl Display for PageFaultDescription {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let error_code = self.0;
+        let mode = if error_code.is_user_mode() {
+            "user"
+        } else {
+            "supervisor"
+        };
+        let access = if error_code.is_instruction_fetch() {
+            "instruction fetch"
+        } else if error_code.is_write() {
+            "write"
+        } else {
+            "read"
+        };
+        let cause = if error_code.is_reserved_bit_violation() {
+            "a page with reserved bits set"
+        } else if error_code.is_protection_violation() {
+            "a protected page"
+        } else {
+            "a non-present page"
+        };
+        write!(f, "{mode} {access} to {cause}")
+    }
+}
 
 #[allow(unused)]
 #[repr(u64)]
@@ -37,7 +156,15 @@ pub enum PageMappingEntryFlag {
     ExecuteDisable = 1 << 63,
 }
 
-make_bitmap!(new_type: PageMappingEntry, underlying_flag_type: PageMappingEntryFlag, repr: u64, nodisplay);
+make_bitmap!(new_type: PageMappingEntry, underlying_flag_type: PageMappingEntryFlag, repr: u64, names: [
+    AllowUserModeAccess => "U",
+    PageLevelWriteThrough => "PWT",
+    PageLevelCacheDisable => "PCD",
+    Accessed => "A",
+    Dirty => "D",
+    Global => "G",
+    ExecuteDisable => "XD",
+]);
 
 #[allow(unused)]
 #[repr(u64)]
@@ -45,7 +172,9 @@ pub enum LargePageEntryFlag {
     PageAttributeTable = 1 << 12,
 }
 
-make_bitmap!(new_type: LargePageEntry, underlying_flag_type: LargePageEntryFlag, repr: u64, nodisplay);
+make_bitmap!(new_type: LargePageEntry, underlying_flag_type: LargePageEntryFlag, repr: u64, names: [
+    PageAttributeTable => "PAT",
+]);
 
 #[allow(unused)]
 #[repr(u32)]
@@ -55,6 +184,24 @@ pub enum ExtendedProcessorSignatureAndFeatureBit {
 
 make_bitmap!(new_type: ExtendedProcessorSignatureAndFeatures, underlying_flag_type: ExtendedProcessorSignatureAndFeatureBit, repr: u32, nodisplay);
 
+#[allow(unused)]
+#[repr(u32)]
+pub enum FeatureInformationBit {
+    PageAttributeTable = 1 << 16,
+}
+
+make_bitmap!(new_type: FeatureInformation, underlying_flag_type: FeatureInformationBit, repr: u32, nodisplay);
+
+#[allow(unused)]
+#[repr(u32)]
+pub enum StructuredExtendedFeatureBit {
+    Invpcid = 1 << 10,
+}
+
+make_bitmap!(new_type: StructuredExtendedFeatures, underlying_flag_type: StructuredExtendedFeatureBit, repr: u32, nodisplay);
+
+const FEATURE_INFORMATION: u32 = 0x1;
+const STRUCTURED_EXTENDED_FEATURE_FLAGS: u32 = 0x7;
 const LINEAR_PHYSICAL_ADDRESS_SIZE: u32 = 0x80000008;
 const EXTENDED_PROCESSOR_SIGNATURE_AND_FEATURE_BITS: u32 = 0x80000001;
 
@@ -71,6 +218,85 @@ fn supports_1gb_pages() -> bool {
         .is_set(ExtendedProcessorSignatureAndFeatureBit::_1GBPagesAvailable)
 }
 
+fn supports_invpcid() -> bool {
+    // SAFETY: The `__cpuid_count` instruction is safe to call with the given arguments.
+    let result = unsafe { __cpuid_count(STRUCTURED_EXTENDED_FEATURE_FLAGS, 0).ebx };
+
+    StructuredExtendedFeatures::from(result).is_set(StructuredExtendedFeatureBit::Invpcid)
+}
+
+fn supports_page_attribute_table() -> bool {
+    // SAFETY: The `__cpuid` instruction is safe to call with the given arguments.
+    let result = unsafe { __cpuid(FEATURE_INFORMATION).edx };
+
+    FeatureInformation::from(result).is_set(FeatureInformationBit::PageAttributeTable)
+}
+
+/// The memory type each PAT index selects once `setup_page_attribute_table` ran. Indices 0, 2 and
+/// 3 keep their power-on types, so entries without the PAT bit mean the same before and after;
+/// index 1 becomes write-combining and write-through moves to index 5
+const PAGE_ATTRIBUTE_TABLE_LAYOUT: [MemoryType; PAT_ENTRIES] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteCombining,
+    MemoryType::UncacheableMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncacheableMinus,
+    MemoryType::Uncacheable,
+];
+
+/// Programs the PAT with `PAGE_ATTRIBUTE_TABLE_LAYOUT`, so that `set_memory_type` can be used on
+/// page table entries. Meant to be called before paging is enabled: changing the PAT with paging
+/// on requires flushing the caches and the TLB
+pub fn setup_page_attribute_table() -> Result<(), Fault> {
+    if !supports_page_attribute_table() {
+        return Err(Fault::UnsupportedFeature(Feature::PageAttributeTable));
+    }
+    let mut page_attribute_table = PageAttributeTable::default();
+    for (index, memory_type) in PAGE_ATTRIBUTE_TABLE_LAYOUT.into_iter().enumerate() {
+        page_attribute_table.set_entry(index, memory_type);
+    }
+    msr::wrmsr(&Msr::Pat(page_attribute_table));
+    Ok(())
+}
+
+const PAGE_TABLE_ENTRY_PAT_BIT: u64 = 1 << 7;
+
+/// Sets the PWT, PCD and PAT bits of a page mapping entry so that it selects `memory_type`.
+/// `pat_bit` depends on the level: bit 7 for 4KB pages, bit 12 for 2MB and 1GB pages. Without a
+/// PAT, the PWT and PCD bits select the other types the same, but write-combining isn't there
+fn set_memory_type_bits(
+    entry: &mut PageTableEntry,
+    memory_type: MemoryType,
+    pat_bit: u64,
+) -> Result<(), Fault> {
+    if memory_type == MemoryType::WriteCombining && !supports_page_attribute_table() {
+        return Err(Fault::UnsupportedFeature(Feature::PageAttributeTable));
+    }
+    let Some(index) = PAGE_ATTRIBUTE_TABLE_LAYOUT
+        .iter()
+        .position(|&candidate| candidate == memory_type)
+    else {
+        return Err(Fault::UnsupportedMemoryType(memory_type));
+    };
+    let mut bits = entry.bits
+        & !(PageTableEntryFlag::PageLevelWriteThrough as u64
+            | PageTableEntryFlag::PageLevelCacheDisable as u64
+            | pat_bit);
+    if index & 0b001 != 0 {
+        bits |= PageTableEntryFlag::PageLevelWriteThrough as u64;
+    }
+    if index & 0b010 != 0 {
+        bits |= PageTableEntryFlag::PageLevelCacheDisable as u64;
+    }
+    if index & 0b100 != 0 {
+        bits |= pat_bit;
+    }
+    entry.bits = bits;
+    Ok(())
+}
+
 macro_rules! impl_deref_to_page_table_entry {
     ($type:ty) => {
         impl core::ops::Deref for $type {
@@ -167,6 +393,15 @@ impl PageDirectoryPointerTableEntry {
         self.0.bits |= addr;
     }
 
+    /// Selects the memory type of the 1GB page this entry maps, see `setup_page_attribute_table`
+    pub fn set_memory_type(&mut self, memory_type: MemoryType) -> Result<(), Fault> {
+        set_memory_type_bits(
+            &mut self.0,
+            memory_type,
+            LargePageEntryFlag::PageAttributeTable as u64,
+        )
+    }
+
     pub fn set_page_directory(&mut self, page_directory: &'static PageDirectoryTable) {
         self.0.set_flag(PageTableEntryFlag::Present);
         let max_physical_width = get_max_physical_address_width();
@@ -216,6 +451,15 @@ impl PageDirectoryEntry {
         self.0.bits |= addr;
     }
 
+    /// Selects the memory type of the 2MB page this entry maps, see `setup_page_attribute_table`
+    pub fn set_memory_type(&mut self, memory_type: MemoryType) -> Result<(), Fault> {
+        set_memory_type_bits(
+            &mut self.0,
+            memory_type,
+            LargePageEntryFlag::PageAttributeTable as u64,
+        )
+    }
+
     pub fn set_page_table(&mut self, page_table: &'static PageTable) {
         self.0.set_flag(PageTableEntryFlag::Present);
         let max_physical_width = min(get_max_physical_address_width(), 39);
@@ -242,14 +486,987 @@ impl PageTableEntry {
         self.bits &= (u64::MAX << max_physical_width).rotate_left(12);
         self.bits |= addr;
     }
+
+    /// Selects the memory type of the 4KB page this entry maps, see `setup_page_attribute_table`
+    pub fn set_memory_type(&mut self, memory_type: MemoryType) -> Result<(), Fault> {
+        set_memory_type_bits(self, memory_type, PAGE_TABLE_ENTRY_PAT_BIT)
+    }
 }
 
 #[repr(align(4096))]
 pub struct PageTable([PageTableEntry; 512]);
 
+const PAGING_LEVELS: usize = 4;
+const ENTRIES_PER_TABLE: u64 = 512;
+const ENTRY_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const LEVEL_NAMES: [&str; PAGING_LEVELS] = ["PML4", "PDPT", "PD", "PT"];
+
+fn table_index(virtual_address: u64, level: usize) -> usize {
+    ((virtual_address >> (12 + 9 * (PAGING_LEVELS - 1 - level))) % ENTRIES_PER_TABLE) as usize
+}
+
+fn page_size(level: usize) -> u64 {
+    1 << (12 + 9 * (PAGING_LEVELS - 1 - level))
+}
+
+fn read_entry(table_physical_address: u64, index: usize, physical_memory_offset: u64) -> u64 {
+    let entry_address = physical_memory_offset
+        .wrapping_add(table_physical_address)
+        .wrapping_add((index * size_of::<u64>()) as u64);
+    // SAFETY: The caller provides the address of a valid PML4, and entries only point to other
+    // valid tables or pages
+    unsafe { core::ptr::read_volatile(entry_address as usize as *const u64) }
+}
+
+fn write_entry(table_physical_address: u64, index: usize, physical_memory_offset: u64, entry: u64) {
+    let entry_address = physical_memory_offset
+        .wrapping_add(table_physical_address)
+        .wrapping_add((index * size_of::<u64>()) as u64);
+    // SAFETY: The caller provides the address of a valid table, and makes sure the entry points to
+    // a valid table or page
+    unsafe { core::ptr::write_volatile(entry_address as usize as *mut u64, entry) }
+}
+
+/// Whether a present entry maps a page rather than pointing to the next table
+fn maps_page(level: usize, entry: u64) -> bool {
+    // MapsPage is reserved at the PML4 level
+    level == PAGING_LEVELS - 1
+        || (level > 0 && PageTableEntry::from(entry).is_set(PageTableEntryFlag::MapsPage))
+}
+
+/// Goes through the paging hierarchy rooted at `pml4_physical_address` for `virtual_address`,
+/// calling `visit` with the level, index and raw entry of every table it reads. Returns the
+/// physical address and the size of the page mapping `virtual_address`, if any.
+/// Tables are read at their physical address plus `physical_memory_offset`, which is 0 as long as
+/// the page tables are identity mapped
+fn walk(
+    pml4_physical_address: u64,
+    virtual_address: u64,
+    physical_memory_offset: u64,
+    mut visit: impl FnMut(usize, usize, u64),
+) -> Option<(u64, u64)> {
+    let mut table_physical_address = pml4_physical_address & ENTRY_ADDRESS_MASK;
+    for level in 0..PAGING_LEVELS {
+        let index = table_index(virtual_address, level);
+        let entry = read_entry(table_physical_address, index, physical_memory_offset);
+        visit(level, index, entry);
+
+        if !PageTableEntry::from(entry).is_set(PageTableEntryFlag::Present) {
+            return None;
+        }
+        if maps_page(level, entry) {
+            let page_size = page_size(level);
+            let page_physical_address = entry & ENTRY_ADDRESS_MASK & !(page_size - 1);
+            return Some((
+                page_physical_address | (virtual_address & (page_size - 1)),
+                page_size,
+            ));
+        }
+        table_physical_address = entry & ENTRY_ADDRESS_MASK;
+    }
+    None
+}
+
+/// The physical address `virtual_address` is mapped to, see `walk` for the meaning of the
+/// parameters
+pub fn translate(
+    pml4_physical_address: u64,
+    virtual_address: u64,
+    physical_memory_offset: u64,
+) -> Option<u64> {
+    walk(
+        pml4_physical_address,
+        virtual_address,
+        physical_memory_offset,
+        |_, _, _| {},
+    )
+    .map(|(physical_address, _)| physical_address)
+}
+
+const GIGABYTE: u64 = 1 << 30;
+
+/// Identity maps the GB containing `physical_address` with a single 1GB page of the given memory
+/// type, unless `physical_address` is mapped already. Meant for MMIO regions outside of the memory
+/// the bootloader maps, so it only supports addresses whose PDPT exists. See `walk` for the
+/// meaning of the other parameters
+pub fn identity_map_gigabyte(
+    pml4_physical_address: u64,
+    physical_address: u64,
+    physical_memory_offset: u64,
+    memory_type: MemoryType,
+) -> Result<(), Fault> {
+    if translate(
+        pml4_physical_address,
+        physical_address,
+        physical_memory_offset,
+    )
+    .is_some()
+    {
+        return Ok(());
+    }
+    if !supports_1gb_pages() {
+        return Err(Fault::UnsupportedFeature(Feature::_1GBPages));
+    }
+
+    let pml4_entry = read_entry(
+        pml4_physical_address & ENTRY_ADDRESS_MASK,
+        table_index(physical_address, 0),
+        physical_memory_offset,
+    );
+    let pdpt_index = table_index(physical_address, 1);
+    let pdpt_physical_address = pml4_entry & ENTRY_ADDRESS_MASK;
+    // A present PDPT entry means part of the GB is mapped through smaller pages already
+    if !PageTableEntry::from(pml4_entry).is_set(PageTableEntryFlag::Present)
+        || PageTableEntry::from(read_entry(
+            pdpt_physical_address,
+            pdpt_index,
+            physical_memory_offset,
+        ))
+        .is_set(PageTableEntryFlag::Present)
+    {
+        return Err(Fault::NoPageTableFor(physical_address));
+    }
+
+    use PageTableEntryFlag::*;
+    let mut entry = PageTableEntry::from(
+        (physical_address & !(GIGABYTE - 1)) | Present as u64 | Write as u64 | MapsPage as u64,
+    );
+    set_memory_type_bits(
+        &mut entry,
+        memory_type,
+        LargePageEntryFlag::PageAttributeTable as u64,
+    )?;
+    // Non-present entries aren't cached, so there's no TLB entry to flush
+    write_entry(
+        pdpt_physical_address,
+        pdpt_index,
+        physical_memory_offset,
+        entry.into(),
+    );
+    Ok(())
+}
+
+const LARGE_PAGE_SIZE: u64 = 1 << 21;
+// The level of page directories, whose entries map 2MB pages
+const PAGE_DIRECTORY_LEVEL: usize = 2;
+
+// INVPCID types, see the INVPCID instruction reference
+const INVPCID_INDIVIDUAL_ADDRESS: usize = 0;
+const INVPCID_SINGLE_CONTEXT: usize = 1;
+const PCID_MASK: u16 = 0xfff;
+
+/// Drops the cached translations of the page containing `virtual_address`, global or not, in the
+/// current PCID. Needed after a present entry on the way to it changed or was cleared: the TLB
+/// doesn't follow the page tables
+#[cfg(not(test))]
+pub fn flush(virtual_address: u64) {
+    // SAFETY: invlpg only drops cached translations, the page tables are still valid
+    unsafe {
+        core::arch::asm!(
+            "invlpg [{}]",
+            in(reg) virtual_address as usize,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+// Page tables in tests are never loaded into CR3, and invlpg is privileged
+#[cfg(test)]
+pub fn flush(_virtual_address: u64) {}
+
+/// Drops every cached translation, global pages and other PCIDs' included, by toggling CR4.PGE.
+/// Reloading CR3 would keep global pages, and with PCIDs only flush the current one
+pub fn flush_all() {
+    let cr4 = Cr4::read();
+    let mut toggled = cr4;
+    if cr4.is_set(ControlRegister4Bit::GlobalPage) {
+        toggled.clear_flag(ControlRegister4Bit::GlobalPage);
+    } else {
+        toggled.set_flag(ControlRegister4Bit::GlobalPage);
+    }
+    toggled.write();
+    cr4.write();
+}
+
+#[repr(C, align(16))]
+struct InvpcidDescriptor {
+    pcid: u64,
+    linear_address: u64,
+}
+
+fn invpcid(kind: usize, pcid: u16, virtual_address: u64) {
+    let descriptor = InvpcidDescriptor {
+        pcid: u64::from(pcid & PCID_MASK),
+        linear_address: virtual_address,
+    };
+    // SAFETY: invpcid only drops cached translations, and the descriptor is valid for reads with
+    // reserved bits clear. Callers check INVPCID is supported
+    unsafe {
+        core::arch::asm!(
+            "invpcid {kind}, [{descriptor}]",
+            kind = in(reg) kind,
+            descriptor = in(reg) &raw const descriptor,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Drops the cached translations of the page containing `virtual_address` tagged with `pcid`,
+/// which needn't be the current one, except for global pages. Without INVPCID, flushes everything
+pub fn flush_pcid(pcid: u16, virtual_address: u64) {
+    if supports_invpcid() {
+        invpcid(INVPCID_INDIVIDUAL_ADDRESS, pcid, virtual_address);
+    } else {
+        flush_all();
+    }
+}
+
+/// Drops all the cached translations tagged with `pcid`, except for global pages, e.g. before
+/// reusing the PCID for another address space. Without INVPCID, flushes everything
+pub fn flush_pcid_all(pcid: u16) {
+    if supports_invpcid() {
+        invpcid(INVPCID_SINGLE_CONTEXT, pcid, 0);
+    } else {
+        flush_all();
+    }
+}
+
+/// The entries read on the way to `virtual_address`, level by level, and the level of the one
+/// mapping it, if it's mapped. Entries past the last one read are 0
+fn entries_for(
+    pml4_physical_address: u64,
+    virtual_address: u64,
+    physical_memory_offset: u64,
+) -> ([u64; PAGING_LEVELS], Option<usize>) {
+    let mut entries = [0; PAGING_LEVELS];
+    let mut last_level = 0;
+    let mapped = walk(
+        pml4_physical_address,
+        virtual_address,
+        physical_memory_offset,
+        |level, _, entry| {
+            entries[level] = entry;
+            last_level = level;
+        },
+    );
+    (entries, mapped.map(|_| last_level))
+}
+
+/// The physical address of the table at `level`, given the entries read on the way to it
+fn table_at(pml4_physical_address: u64, entries: &[u64; PAGING_LEVELS], level: usize) -> u64 {
+    let table_entry = match level {
+        0 => pml4_physical_address,
+        _ => entries[level - 1],
+    };
+    table_entry & ENTRY_ADDRESS_MASK
+}
+
+/// Maps the 2MB page at `virtual_address` to `physical_address`, both 2MB aligned, as a page of
+/// the given memory type with `flags` (e.g. Write, ExecuteDisable) besides Present. The page
+/// directory it goes in must exist already, and nothing in the 2MB may be mapped yet. See `walk`
+/// for the meaning of the other parameters
+pub fn map_2m(
+    pml4_physical_address: u64,
+    virtual_address: u64,
+    physical_address: u64,
+    flags: PageTableEntry,
+    memory_type: MemoryType,
+    physical_memory_offset: u64,
+) -> Result<(), Fault> {
+    for address in [virtual_address, physical_address] {
+        if !address.is_multiple_of(LARGE_PAGE_SIZE) {
+            return Err(Fault::MisalignedAddress(address));
+        }
+    }
+    let (entries, mapped_level) = entries_for(
+        pml4_physical_address,
+        virtual_address,
+        physical_memory_offset,
+    );
+    // A present page directory entry that doesn't map the page points to a page table, which
+    // may map some of the 2MB already
+    if mapped_level.is_some()
+        || PageTableEntry::from(entries[PAGE_DIRECTORY_LEVEL]).is_set(PageTableEntryFlag::Present)
+    {
+        return Err(Fault::AlreadyMapped(virtual_address));
+    }
+    if !entries[..PAGE_DIRECTORY_LEVEL]
+        .iter()
+        .all(|&entry| PageTableEntry::from(entry).is_set(PageTableEntryFlag::Present))
+    {
+        return Err(Fault::NoPageTableFor(virtual_address));
+    }
+
+    use PageTableEntryFlag::*;
+    let mut entry = PageTableEntry::from(
+        physical_address
+            | (u64::from(flags) & !ENTRY_ADDRESS_MASK)
+            | Present as u64
+            | MapsPage as u64,
+    );
+    set_memory_type_bits(
+        &mut entry,
+        memory_type,
+        LargePageEntryFlag::PageAttributeTable as u64,
+    )?;
+    // Non-present entries aren't cached, so there's no TLB entry to flush
+    write_entry(
+        entries[PAGE_DIRECTORY_LEVEL - 1] & ENTRY_ADDRESS_MASK,
+        table_index(virtual_address, PAGE_DIRECTORY_LEVEL),
+        physical_memory_offset,
+        entry.into(),
+    );
+    Ok(())
+}
+
+/// Replaces the large page mapped by entry `index` of the table at `table_physical_address`, at
+/// `level`, with the table at `new_table_physical_address`, filled with pages of the next level
+/// mapping the same memory with the same flags and memory type
+fn split_large_page(
+    table_physical_address: u64,
+    level: usize,
+    index: usize,
+    new_table_physical_address: u64,
+    physical_memory_offset: u64,
+) {
+    use PageTableEntryFlag::*;
+    let entry = read_entry(table_physical_address, index, physical_memory_offset);
+    let page_physical_address = entry & ENTRY_ADDRESS_MASK & !(page_size(level) - 1);
+    let large_page_pat = LargePageEntryFlag::PageAttributeTable as u64;
+    let mut flags = entry & !ENTRY_ADDRESS_MASK;
+    if level + 1 == PAGING_LEVELS - 1 {
+        // 4KB pages have no MapsPage bit, the PAT bit takes its place
+        flags &= !(MapsPage as u64);
+        if entry & large_page_pat != 0 {
+            flags |= PAGE_TABLE_ENTRY_PAT_BIT;
+        }
+    } else {
+        flags |= entry & large_page_pat;
+    }
+    for new_index in 0..ENTRIES_PER_TABLE {
+        write_entry(
+            new_table_physical_address,
+            new_index as usize,
+            physical_memory_offset,
+            (page_physical_address + new_index * page_size(level + 1)) | flags,
+        );
+    }
+    // The new pages restrict themselves, the entry pointing to them lets everything through
+    write_entry(
+        table_physical_address,
+        index,
+        physical_memory_offset,
+        new_table_physical_address | Present as u64 | Write as u64 | AllowUserModeAccess as u64,
+    );
+}
+
+/// Sets the flags in `set` and then clears the ones in `clear` on the pages mapping `range`, both
+/// ends 4KB aligned. Large pages only partly in `range` are split into pages of the next level
+/// first, as many times as needed, with page tables from `allocate_table`: the physical address
+/// of an unused, 4KB aligned frame, or None if there are none left. MapsPage can't be changed
+/// this way. See `walk` for the meaning of the other parameters
+pub fn set_page_flags(
+    pml4_physical_address: u64,
+    range: Range<u64>,
+    set: PageTableEntry,
+    clear: PageTableEntry,
+    physical_memory_offset: u64,
+    mut allocate_table: impl FnMut() -> Option<u64>,
+) -> Result<(), Fault> {
+    for address in [range.start, range.end] {
+        if !address.is_multiple_of(page_size(PAGING_LEVELS - 1)) {
+            return Err(Fault::MisalignedAddress(address));
+        }
+    }
+    let maps_page = PageTableEntryFlag::MapsPage as u64;
+    let (set, clear) = (u64::from(set) & !maps_page, u64::from(clear) & !maps_page);
+
+    let mut virtual_address = range.start;
+    while virtual_address < range.end {
+        let (entries, Some(level)) = entries_for(
+            pml4_physical_address,
+            virtual_address,
+            physical_memory_offset,
+        ) else {
+            return Err(Fault::NotMapped(virtual_address));
+        };
+        let table_physical_address = table_at(pml4_physical_address, &entries, level);
+        let index = table_index(virtual_address, level);
+        let page_size = page_size(level);
+        let page_start = virtual_address & !(page_size - 1);
+
+        if page_start < range.start || page_start + page_size > range.end {
+            let Some(new_table_physical_address) = allocate_table() else {
+                return Err(Fault::NoPageTableFor(virtual_address));
+            };
+            split_large_page(
+                table_physical_address,
+                level,
+                index,
+                new_table_physical_address,
+                physical_memory_offset,
+            );
+            flush(page_start);
+            // Look the address up again, in the smaller pages
+            continue;
+        }
+        write_entry(
+            table_physical_address,
+            index,
+            physical_memory_offset,
+            (entries[level] | set) & !clear,
+        );
+        flush(page_start);
+        virtual_address = page_start + page_size;
+    }
+    Ok(())
+}
+
+/// Unmaps the page mapping `virtual_address`, whatever its size, and flushes it from the TLB.
+/// Returns the physical address and the size of the page that was unmapped. The tables on the way
+/// are kept, even if they end up empty. See `walk` for the meaning of the parameters
+pub fn unmap(
+    pml4_physical_address: u64,
+    virtual_address: u64,
+    physical_memory_offset: u64,
+) -> Result<(u64, u64), Fault> {
+    let (entries, Some(level)) = entries_for(
+        pml4_physical_address,
+        virtual_address,
+        physical_memory_offset,
+    ) else {
+        return Err(Fault::NotMapped(virtual_address));
+    };
+    write_entry(
+        table_at(pml4_physical_address, &entries, level),
+        table_index(virtual_address, level),
+        physical_memory_offset,
+        0,
+    );
+    flush(virtual_address);
+    let page_size = page_size(level);
+    Ok((
+        entries[level] & ENTRY_ADDRESS_MASK & !(page_size - 1),
+        page_size,
+    ))
+}
+
+/// The entry of the page mapping `virtual_address`, whatever its size, and the size of the page.
+/// See `walk` for the meaning of the parameters
+pub fn page_entry(
+    pml4_physical_address: u64,
+    virtual_address: u64,
+    physical_memory_offset: u64,
+) -> Option<(PageTableEntry, u64)> {
+    let (entries, level) = entries_for(
+        pml4_physical_address,
+        virtual_address,
+        physical_memory_offset,
+    );
+    level.map(|level| (PageTableEntry::from(entries[level]), page_size(level)))
+}
+
+/// Points the page mapping `virtual_address` to `physical_address` instead, keeping its flags and
+/// memory type, and flushes it from the TLB. `physical_address` must be aligned to the size of
+/// the page. See `walk` for the meaning of the other parameters
+pub fn remap(
+    pml4_physical_address: u64,
+    virtual_address: u64,
+    physical_address: u64,
+    physical_memory_offset: u64,
+) -> Result<(), Fault> {
+    let (entries, Some(level)) = entries_for(
+        pml4_physical_address,
+        virtual_address,
+        physical_memory_offset,
+    ) else {
+        return Err(Fault::NotMapped(virtual_address));
+    };
+    let page_size = page_size(level);
+    if !physical_address.is_multiple_of(page_size) {
+        return Err(Fault::MisalignedAddress(physical_address));
+    }
+    // The PAT bit of large pages sits in the address bits
+    let address_mask = ENTRY_ADDRESS_MASK & !(page_size - 1);
+    write_entry(
+        table_at(pml4_physical_address, &entries, level),
+        table_index(virtual_address, level),
+        physical_memory_offset,
+        (entries[level] & !address_mask) | physical_address,
+    );
+    flush(virtual_address);
+    Ok(())
+}
+
+/// Maps the 4KB page at `virtual_address` to `physical_address`, both 4KB aligned, as a page of
+/// the given memory type with `flags` (e.g. Write, ExecuteDisable) besides Present. Missing tables
+/// on the way are created with frames from `allocate_table`, as in `set_page_flags`, and nothing
+/// may be mapped at `virtual_address` yet. See `walk` for the meaning of the other parameters
+pub fn map_4k(
+    pml4_physical_address: u64,
+    virtual_address: u64,
+    physical_address: u64,
+    flags: PageTableEntry,
+    memory_type: MemoryType,
+    physical_memory_offset: u64,
+    mut allocate_table: impl FnMut() -> Option<u64>,
+) -> Result<(), Fault> {
+    let page_size = page_size(PAGING_LEVELS - 1);
+    for address in [virtual_address, physical_address] {
+        if !address.is_multiple_of(page_size) {
+            return Err(Fault::MisalignedAddress(address));
+        }
+    }
+
+    use PageTableEntryFlag::*;
+    let mut table_physical_address = pml4_physical_address & ENTRY_ADDRESS_MASK;
+    for level in 0..PAGING_LEVELS - 1 {
+        let index = table_index(virtual_address, level);
+        let mut entry = read_entry(table_physical_address, index, physical_memory_offset);
+        if !PageTableEntry::from(entry).is_set(Present) {
+            let Some(new_table_physical_address) = allocate_table() else {
+                return Err(Fault::NoPageTableFor(virtual_address));
+            };
+            for new_index in 0..ENTRIES_PER_TABLE as usize {
+                write_entry(
+                    new_table_physical_address,
+                    new_index,
+                    physical_memory_offset,
+                    0,
+                );
+            }
+            // As when splitting large pages, the page restricts itself
+            entry = new_table_physical_address
+                | Present as u64
+                | Write as u64
+                | AllowUserModeAccess as u64;
+            write_entry(table_physical_address, index, physical_memory_offset, entry);
+        } else if maps_page(level, entry) {
+            return Err(Fault::AlreadyMapped(virtual_address));
+        }
+        table_physical_address = entry & ENTRY_ADDRESS_MASK;
+    }
+
+    let index = table_index(virtual_address, PAGING_LEVELS - 1);
+    if PageTableEntry::from(read_entry(
+        table_physical_address,
+        index,
+        physical_memory_offset,
+    ))
+    .is_set(Present)
+    {
+        return Err(Fault::AlreadyMapped(virtual_address));
+    }
+    // Bit 7 selects the PAT entry in 4KB pages, MapsPage doesn't apply
+    let mut entry = PageTableEntry::from(
+        physical_address
+            | (u64::from(flags) & !ENTRY_ADDRESS_MASK & !(MapsPage as u64))
+            | Present as u64,
+    );
+    set_memory_type_bits(&mut entry, memory_type, PAGE_TABLE_ENTRY_PAT_BIT)?;
+    // Non-present entries aren't cached, so there's no TLB entry to flush
+    write_entry(
+        table_physical_address,
+        index,
+        physical_memory_offset,
+        entry.into(),
+    );
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+struct Mapping {
+    virtual_address: u64,
+    physical_address: u64,
+    size: u64,
+    flags: PageTableEntry,
+}
+
+impl Mapping {
+    /// Whether `next` continues this mapping both virtually and physically with the same flags
+    fn is_continued_by(&self, next: &Mapping) -> bool {
+        self.virtual_address.wrapping_add(self.size) == next.virtual_address
+            && self.physical_address.wrapping_add(self.size) == next.physical_address
+            && self.flags == next.flags
+    }
+}
+
+impl Display for Mapping {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:016x}-{:016x} -> {:016x}-{:016x} ",
+            self.virtual_address,
+            self.virtual_address.wrapping_add(self.size - 1),
+            self.physical_address,
+            self.physical_address.wrapping_add(self.size - 1)
+        )?;
+        match self.size {
+            size if size.is_multiple_of(1 << 30) => write!(f, "{}GB", size >> 30)?,
+            size if size.is_multiple_of(1 << 20) => write!(f, "{}MB", size >> 20)?,
+            size => write!(f, "{}KB", size >> 10)?,
+        }
+        write!(f, " {}", self.flags)
+    }
+}
+
+/// The flags that apply to a page, given those of all the entries leading to it: it's writable
+/// or user accessible only if every level allows it, and not executable if any level forbids it
+fn effective_flags(inherited_flags: u64, entry: u64) -> u64 {
+    use PageTableEntryFlag::*;
+    let all_levels = Write as u64 | AllowUserModeAccess as u64;
+    (inherited_flags & entry & all_levels)
+        | ((inherited_flags | entry) & ExecuteDisable as u64)
+        | Present as u64
+}
+
+fn for_each_mapping(
+    table_physical_address: u64,
+    level: usize,
+    table_virtual_address: u64,
+    range: &Range<u64>,
+    physical_memory_offset: u64,
+    inherited_flags: u64,
+    visit: &mut impl FnMut(Mapping) -> core::fmt::Result,
+) -> core::fmt::Result {
+    let entry_size = page_size(level);
+    for index in 0..ENTRIES_PER_TABLE as usize {
+        let mut virtual_address = table_virtual_address + index as u64 * entry_size;
+        // Addresses must be canonical, so the upper half of the PML4 maps sign extended addresses
+        if level == 0 && index >= ENTRIES_PER_TABLE as usize / 2 {
+            virtual_address |= 0xffff_0000_0000_0000;
+        }
+        if virtual_address.saturating_add(entry_size) <= range.start || virtual_address >= range.end
+        {
+            continue;
+        }
+
+        let entry = read_entry(table_physical_address, index, physical_memory_offset);
+        if !PageTableEntry::from(entry).is_set(PageTableEntryFlag::Present) {
+            continue;
+        }
+        let flags = effective_flags(inherited_flags, entry);
+        if maps_page(level, entry) {
+            visit(Mapping {
+                virtual_address,
+                physical_address: entry & ENTRY_ADDRESS_MASK & !(entry_size - 1),
+                size: entry_size,
+                flags: flags.into(),
+            })?;
+        } else {
+            for_each_mapping(
+                entry & ENTRY_ADDRESS_MASK,
+                level + 1,
+                virtual_address,
+                range,
+                physical_memory_offset,
+                flags,
+                visit,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the pages mapped in `range` by the hierarchy rooted at `pml4_physical_address`, one line
+/// per run of pages that are contiguous both virtually and physically and share their effective
+/// flags. Lines are separated by newlines, with no newline after the last one. See `walk` for
+/// the meaning of `physical_memory_offset`
+pub fn dump_mappings(
+    pml4_physical_address: u64,
+    range: Range<u64>,
+    physical_memory_offset: u64,
+    writer: &mut impl Write,
+) -> core::fmt::Result {
+    let mut current_mapping: Option<Mapping> = None;
+    let mut printed_once = false;
+    let mut print = |mapping: Mapping| -> core::fmt::Result {
+        if printed_once {
+            writeln!(writer)?;
+        }
+        printed_once = true;
+        write!(writer, "{mapping}")
+    };
+
+    for_each_mapping(
+        pml4_physical_address & ENTRY_ADDRESS_MASK,
+        0,
+        0,
+        &range,
+        physical_memory_offset,
+        PageTableEntryFlag::Write as u64 | PageTableEntryFlag::AllowUserModeAccess as u64,
+        &mut |mapping| match current_mapping.as_mut() {
+            Some(current) if current.is_continued_by(&mapping) => {
+                current.size += mapping.size;
+                Ok(())
+            }
+            _ => match current_mapping.replace(mapping) {
+                Some(previous) => print(previous),
+                None => Ok(()),
+            },
+        },
+    )?;
+    match current_mapping {
+        Some(current) => print(current),
+        None => Ok(()),
+    }
+}
+
+/// Formats the mappings in a range with `dump_mappings`, for use with the `writeln_no_sync`
+/// macros
+pub struct Mappings {
+    pml4_physical_address: u64,
+    range: Range<u64>,
+    physical_memory_offset: u64,
+}
+
+impl Mappings {
+    pub fn new(pml4_physical_address: u64, range: Range<u64>, physical_memory_offset: u64) -> Self {
+        Self {
+            pml4_physical_address,
+            range,
+            physical_memory_offset,
+        }
+    }
+}
+
+impl Display for Mappings {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        dump_mappings(
+            self.pml4_physical_address,
+            self.range.clone(),
+            self.physical_memory_offset,
+            f,
+        )
+    }
+}
+
+/// Shows every paging structure entry involved in translating a virtual address, one per line,
+/// followed by the resulting physical address. Meant for fault handlers, see `walk` for the
+/// meaning of the parameters
+pub struct PageWalk {
+    pml4_physical_address: u64,
+    virtual_address: u64,
+    physical_memory_offset: u64,
+}
+
+impl PageWalk {
+    pub fn new(
+        pml4_physical_address: u64,
+        virtual_address: u64,
+        physical_memory_offset: u64,
+    ) -> Self {
+        Self {
+            pml4_physical_address,
+            virtual_address,
+            physical_memory_offset,
+        }
+    }
+}
+
+impl Display for PageWalk {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut result = Ok(());
+        let mapping = walk(
+            self.pml4_physical_address,
+            self.virtual_address,
+            self.physical_memory_offset,
+            |level, index, entry| {
+                if result.is_ok() {
+                    result = writeln!(
+                        f,
+                        "{}[{index}]={entry:016x} {}",
+                        LEVEL_NAMES[level],
+                        PageTableEntry::from(entry)
+                    );
+                }
+            },
+        );
+        result?;
+        match mapping {
+            Some((physical_address, page_size)) => write!(
+                f,
+                "{:#x} -> {physical_address:#x} ({}KB page)",
+                self.virtual_address,
+                page_size / 1024
+            ),
+            None => write!(f, "{:#x} is not mapped", self.virtual_address),
+        }
+    }
+}
+
+/// The page tables rooted at a PML4, reached through a mapping of physical memory at
+/// `physical_memory_offset`, to read and edit them without passing both around
+#[derive(Clone, Copy, Debug)]
+pub struct Mapper {
+    pml4_physical_address: u64,
+    physical_memory_offset: u64,
+}
+
+impl Mapper {
+    pub fn new(pml4_physical_address: u64, physical_memory_offset: u64) -> Self {
+        Self {
+            pml4_physical_address,
+            physical_memory_offset,
+        }
+    }
+
+    /// The page tables in CR3, through the mapping of physical memory the bootloader sets up at
+    /// `layout::PHYSICAL_MEMORY_OFFSET`. Only valid in long mode, once the bootloader is done
+    pub fn active() -> Self {
+        Self::new(
+            Cr3::read().pml4_physical_address(),
+            layout::PHYSICAL_MEMORY_OFFSET,
+        )
+    }
+
+    pub fn physical_memory_offset(&self) -> u64 {
+        self.physical_memory_offset
+    }
+
+    pub fn pml4_physical_address(&self) -> u64 {
+        self.pml4_physical_address
+    }
+
+    pub fn translate(&self, virtual_address: u64) -> Option<u64> {
+        translate(
+            self.pml4_physical_address,
+            virtual_address,
+            self.physical_memory_offset,
+        )
+    }
+
+    pub fn page_walk(&self, virtual_address: u64) -> PageWalk {
+        PageWalk::new(
+            self.pml4_physical_address,
+            virtual_address,
+            self.physical_memory_offset,
+        )
+    }
+
+    pub fn mappings(&self, range: Range<u64>) -> Mappings {
+        Mappings::new(
+            self.pml4_physical_address,
+            range,
+            self.physical_memory_offset,
+        )
+    }
+
+    /// See `identity_map_gigabyte`
+    pub fn identity_map_gigabyte(
+        &self,
+        physical_address: u64,
+        memory_type: MemoryType,
+    ) -> Result<(), Fault> {
+        identity_map_gigabyte(
+            self.pml4_physical_address,
+            physical_address,
+            self.physical_memory_offset,
+            memory_type,
+        )
+    }
+
+    /// See `map_4k`
+    pub fn map_4k(
+        &self,
+        virtual_address: u64,
+        physical_address: u64,
+        flags: PageTableEntry,
+        memory_type: MemoryType,
+        allocate_table: impl FnMut() -> Option<u64>,
+    ) -> Result<(), Fault> {
+        map_4k(
+            self.pml4_physical_address,
+            virtual_address,
+            physical_address,
+            flags,
+            memory_type,
+            self.physical_memory_offset,
+            allocate_table,
+        )
+    }
+
+    /// See `map_2m`
+    pub fn map_2m(
+        &self,
+        virtual_address: u64,
+        physical_address: u64,
+        flags: PageTableEntry,
+        memory_type: MemoryType,
+    ) -> Result<(), Fault> {
+        map_2m(
+            self.pml4_physical_address,
+            virtual_address,
+            physical_address,
+            flags,
+            memory_type,
+            self.physical_memory_offset,
+        )
+    }
+
+    /// See `unmap`
+    pub fn unmap(&self, virtual_address: u64) -> Result<(u64, u64), Fault> {
+        unmap(
+            self.pml4_physical_address,
+            virtual_address,
+            self.physical_memory_offset,
+        )
+    }
+
+    /// See `page_entry`
+    pub fn page_entry(&self, virtual_address: u64) -> Option<(PageTableEntry, u64)> {
+        page_entry(
+            self.pml4_physical_address,
+            virtual_address,
+            self.physical_memory_offset,
+        )
+    }
+
+    /// See `remap`
+    pub fn remap(&self, virtual_address: u64, physical_address: u64) -> Result<(), Fault> {
+        remap(
+            self.pml4_physical_address,
+            virtual_address,
+            physical_address,
+            self.physical_memory_offset,
+        )
+    }
+
+    /// See `set_page_flags`
+    pub fn set_page_flags(
+        &self,
+        range: Range<u64>,
+        set: PageTableEntry,
+        clear: PageTableEntry,
+        allocate_table: impl FnMut() -> Option<u64>,
+    ) -> Result<(), Fault> {
+        set_page_flags(
+            self.pml4_physical_address,
+            range,
+            set,
+            clear,
+            self.physical_memory_offset,
+            allocate_table,
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::paging::{self, PML4Entry};
+    use core::fmt::Write;
+
+    use crate::{
+        error::Fault,
+        msr::MemoryType,
+        paging::{
+            self, Mapper, Mappings, PML4Entry, PageDirectoryEntry, PageFaultErrorCode,
+            PageTableEntry, PageTableEntryFlag, PageWalk,
+        },
+        vga::{Buffer, Writer},
+    };
+
+    #[repr(align(4096))]
+    struct Table([u64; 512]);
 
     #[test]
     fn first_gb_identity_mapped() {
@@ -282,4 +1499,403 @@ mod tests {
             unsafe { core::mem::transmute::<_, [u8; 8]>(pml4_entry) }
         );
     }
+
+    #[test]
+    fn memory_type_bits() {
+        let mut page_directory_entry = PageDirectoryEntry(PageTableEntry::from(0x20_0083));
+        page_directory_entry
+            .set_memory_type(MemoryType::WriteCombining)
+            .unwrap();
+        assert_eq!(0x20_008b, page_directory_entry.bits);
+        page_directory_entry
+            .set_memory_type(MemoryType::WriteThrough)
+            .unwrap();
+        assert_eq!(0x20_108b, page_directory_entry.bits);
+
+        let mut page_table_entry = PageTableEntry::from(0x1000_0003);
+        page_table_entry
+            .set_memory_type(MemoryType::Uncacheable)
+            .unwrap();
+        assert_eq!(0x1000_001b, page_table_entry.bits);
+        page_table_entry
+            .set_memory_type(MemoryType::WriteBack)
+            .unwrap();
+        assert_eq!(0x1000_0003, page_table_entry.bits);
+        assert!(matches!(
+            page_table_entry.set_memory_type(MemoryType::WriteProtected),
+            Err(Fault::UnsupportedMemoryType(MemoryType::WriteProtected))
+        ));
+    }
+
+    #[test]
+    fn page_fault_error_code() {
+        let error_code = PageFaultErrorCode::from(0b10u32);
+        assert!(error_code.is_write());
+        assert!(!error_code.is_protection_violation());
+
+        let mut buffer = Buffer::blank();
+        let mut writer = Writer::with_buffer(&mut buffer);
+        writeln!(writer, "{} ({})", error_code.describe(), error_code).unwrap();
+        write!(
+            writer,
+            "{}",
+            PageFaultErrorCode::from(0b10101u32).describe()
+        )
+        .unwrap();
+        assert!(buffer.contains("supervisor write to a non-present page (WRITE)"));
+        assert!(buffer.contains("user instruction fetch to a protected page"));
+    }
+
+    #[test]
+    fn page_walk() {
+        let mut pml4 = Table([0; 512]);
+        let mut pdpt = Table([0; 512]);
+        let mut page_directory = Table([0; 512]);
+
+        // 0x4020_1234 is PML4[0] -> PDPT[1] -> PD[1], mapped by a 2MB page at 0x8000_0000
+        page_directory.0[1] = 0x8000_0000 | 0x83;
+        pdpt.0[1] = &raw const page_directory as u64 | 0x3;
+        pml4.0[0] = &raw const pdpt as u64 | 0x3;
+
+        let mut buffer = Buffer::blank();
+        let mut writer = Writer::with_buffer(&mut buffer);
+        writeln!(
+            writer,
+            "{}",
+            PageWalk::new(&raw const pml4 as u64, 0x4020_1234, 0)
+        )
+        .unwrap();
+        write!(
+            writer,
+            "{}",
+            PageWalk::new(&raw const pml4 as u64, 0x8000_0000, 0)
+        )
+        .unwrap();
+
+        assert!(buffer.contains("PD[1]=0000000080000083 PRESENT|WRITE|MAPS_PAGE"));
+        assert!(buffer.contains("0x40201234 -> 0x80001234 (2048KB page)"));
+        assert!(buffer.contains("PDPT[2]=0000000000000000"));
+        assert!(buffer.contains("0x80000000 is not mapped"));
+    }
+
+    #[test]
+    fn translate_and_dump_mappings() {
+        let mut pml4 = Table([0; 512]);
+        let mut pdpt = Table([0; 512]);
+        let mut page_directory = Table([0; 512]);
+
+        // The first GB is identity mapped, and its first 2MB continue at 0x4000_0000
+        pdpt.0[0] = 0x83;
+        pdpt.0[1] = &raw const page_directory as u64 | 0x3;
+        page_directory.0[0] = 0x4000_0000 | 0x83;
+        page_directory.0[1] = 0x9000_0000 | 0x83;
+        page_directory.0[2] = 0x9020_0000 | 0x83 | 1 << 63;
+        pml4.0[0] = &raw const pdpt as u64 | 0x3;
+        let pml4_address = &raw const pml4 as u64;
+
+        assert_eq!(Some(0x1234), paging::translate(pml4_address, 0x1234, 0));
+        assert_eq!(
+            Some(0x9000_0042),
+            paging::translate(pml4_address, 0x4020_0042, 0)
+        );
+        assert_eq!(None, paging::translate(pml4_address, 0x4060_0000, 0));
+
+        let mut lines = 0;
+        paging::dump_mappings(
+            pml4_address,
+            0..0x4060_0000,
+            0,
+            &mut LineCounter(&mut lines),
+        )
+        .unwrap();
+        assert_eq!(3, lines);
+
+        let mut last_mapping = None;
+        paging::for_each_mapping(
+            pml4_address,
+            0,
+            0,
+            &(0x4040_0000..0x4060_0000),
+            0,
+            0x6,
+            &mut |mapping| {
+                last_mapping = Some(mapping);
+                Ok(())
+            },
+        )
+        .unwrap();
+        let last_mapping = last_mapping.unwrap();
+        assert_eq!(0x9020_0000, last_mapping.physical_address);
+        assert!(
+            last_mapping
+                .flags
+                .is_set(paging::PageTableEntryFlag::ExecuteDisable)
+        );
+
+        let mut buffer = Buffer::blank();
+        let mut writer = Writer::with_buffer(&mut buffer);
+        writeln!(writer, "{}", Mappings::new(pml4_address, 0..0x4020_0000, 0)).unwrap();
+        assert!(buffer.contains(
+            "0000000000000000-00000000401fffff -> 0000000000000000-00000000401fffff 1026MB"
+        ));
+    }
+
+    #[test]
+    fn identity_map_gigabyte() {
+        if !paging::supports_1gb_pages() {
+            return;
+        }
+        let mut pml4 = Table([0; 512]);
+        let mut pdpt = Table([0; 512]);
+        let page_directory = Table([0; 512]);
+        pdpt.0[0] = 0x83;
+        pdpt.0[1] = &raw const page_directory as u64 | 0x3;
+        pml4.0[0] = &raw const pdpt as u64 | 0x3;
+        let pml4_address = &raw const pml4 as u64;
+
+        paging::identity_map_gigabyte(pml4_address, 0xfeb8_0000, 0, MemoryType::Uncacheable)
+            .unwrap();
+        assert_eq!(0xc000_009b, pdpt.0[3]);
+        assert_eq!(
+            Some(0xfeb8_0010),
+            paging::translate(pml4_address, 0xfeb8_0010, 0)
+        );
+
+        // Mapped already
+        paging::identity_map_gigabyte(pml4_address, 0x1000, 0, MemoryType::Uncacheable).unwrap();
+        assert_eq!(0x83, pdpt.0[0]);
+        assert!(matches!(
+            paging::identity_map_gigabyte(pml4_address, 0x4000_0000, 0, MemoryType::Uncacheable),
+            Err(Fault::NoPageTableFor(0x4000_0000))
+        ));
+    }
+
+    #[test]
+    fn map_2m() {
+        let mut pml4 = Table([0; 512]);
+        let mut pdpt = Table([0; 512]);
+        let mut page_directory = Table([0; 512]);
+        let page_table = Table([0; 512]);
+        pdpt.0[0] = 0x83;
+        pdpt.0[1] = &raw const page_directory as u64 | 0x3;
+        page_directory.0[3] = &raw const page_table as u64 | 0x3;
+        pml4.0[0] = &raw const pdpt as u64 | 0x3;
+        let pml4_address = &raw const pml4 as u64;
+        let write_protected = PageTableEntry::from(PageTableEntryFlag::ExecuteDisable);
+
+        paging::map_2m(
+            pml4_address,
+            0x4020_0000,
+            0x8000_0000,
+            write_protected,
+            MemoryType::WriteBack,
+            0,
+        )
+        .unwrap();
+        assert_eq!(0x8000_0000 | 0x81 | 1 << 63, page_directory.0[1]);
+        assert_eq!(
+            Some(0x8012_3456),
+            paging::translate(pml4_address, 0x4032_3456, 0)
+        );
+
+        paging::map_2m(
+            pml4_address,
+            0x4040_0000,
+            0xc000_0000,
+            PageTableEntry::from(PageTableEntryFlag::Write),
+            MemoryType::WriteCombining,
+            0,
+        )
+        .unwrap();
+        assert_eq!(0xc000_0000 | 0x8b, page_directory.0[2]);
+
+        let map = |virtual_address| {
+            paging::map_2m(
+                pml4_address,
+                virtual_address,
+                0,
+                write_protected,
+                MemoryType::WriteBack,
+                0,
+            )
+        };
+        assert!(matches!(
+            map(0x4030_0000),
+            Err(Fault::MisalignedAddress(0x4030_0000))
+        ));
+        assert!(matches!(
+            map(0x4020_0000),
+            Err(Fault::AlreadyMapped(0x4020_0000))
+        ));
+        assert!(matches!(
+            map(0x20_0000),
+            Err(Fault::AlreadyMapped(0x20_0000))
+        ));
+        // Through the page table in PD[3], even though none of its pages are mapped
+        assert!(matches!(
+            map(0x4060_0000),
+            Err(Fault::AlreadyMapped(0x4060_0000))
+        ));
+        assert!(matches!(
+            map(0x8000_0000),
+            Err(Fault::NoPageTableFor(0x8000_0000))
+        ));
+    }
+
+    #[test]
+    fn set_page_flags_splits_large_pages() {
+        let mut pml4 = Table([0; 512]);
+        let mut pdpt = Table([0; 512]);
+        let mut new_tables = [Table([0; 512]), Table([0; 512]), Table([0; 512])];
+        // The first GB is identity mapped with a write-through 1GB page
+        pdpt.0[0] = 0x83 | 0x8;
+        pml4.0[0] = &raw const pdpt as u64 | 0x3;
+        let pml4_address = &raw const pml4 as u64;
+        let mut free_tables = new_tables.iter_mut().map(|table| &raw mut *table as u64);
+
+        // Write protect 0x20_1000..0x20_3000, splitting the 1GB page and then a 2MB one
+        paging::set_page_flags(
+            pml4_address,
+            0x20_1000..0x20_3000,
+            PageTableEntry::empty(),
+            PageTableEntry::from(PageTableEntryFlag::Write),
+            0,
+            || free_tables.next(),
+        )
+        .unwrap();
+        let [page_directory, page_table, unused] = &new_tables;
+        assert_eq!(&raw const page_directory.0 as u64 | 0x7, pdpt.0[0]);
+        assert_eq!(0x8b, page_directory.0[0]);
+        assert_eq!(&raw const page_table.0 as u64 | 0x7, page_directory.0[1]);
+        assert_eq!(0x20_0000 | 0xb, page_table.0[0]);
+        assert_eq!(0x20_1000 | 0x9, page_table.0[1]);
+        assert_eq!(0x20_2000 | 0x9, page_table.0[2]);
+        assert_eq!(0x20_3000 | 0xb, page_table.0[3]);
+        assert!(unused.0.iter().all(|&entry| entry == 0));
+        for address in [0x1234, 0x20_1234, 0x3fff_ffff] {
+            assert_eq!(Some(address), paging::translate(pml4_address, address, 0));
+        }
+
+        // Whole pages are updated in place
+        paging::set_page_flags(
+            pml4_address,
+            0x40_0000..0x60_0000,
+            PageTableEntry::from(PageTableEntryFlag::ExecuteDisable),
+            PageTableEntry::empty(),
+            0,
+            || None,
+        )
+        .unwrap();
+        assert_eq!(0x40_0000 | 0x8b | 1 << 63, page_directory.0[2]);
+
+        assert!(matches!(
+            paging::set_page_flags(
+                pml4_address,
+                0x60_1000..0x60_2000,
+                PageTableEntry::empty(),
+                PageTableEntry::from(PageTableEntryFlag::Write),
+                0,
+                || None,
+            ),
+            Err(Fault::NoPageTableFor(0x60_1000))
+        ));
+        assert!(matches!(
+            paging::set_page_flags(
+                pml4_address,
+                0x4000_0000..0x4000_1000,
+                PageTableEntry::empty(),
+                PageTableEntry::empty(),
+                0,
+                || None,
+            ),
+            Err(Fault::NotMapped(0x4000_0000))
+        ));
+    }
+
+    #[test]
+    fn mapper_map_4k() {
+        // Pretend physical memory is mapped one page above where it is, so that every table
+        // access has to go through the offset
+        const OFFSET: u64 = 0x1000;
+        let mut pml4 = Table([0; 512]);
+        let mut pdpt = Table([0; 512]);
+        let mut new_tables = [Table([0xff; 512]), Table([0xff; 512]), Table([0xff; 512])];
+        pdpt.0[0] = 0x83;
+        pml4.0[0] = (&raw const pdpt as u64 - OFFSET) | 0x3;
+        let mapper = Mapper::new(&raw const pml4 as u64 - OFFSET, OFFSET);
+        let mut free_tables = new_tables
+            .iter_mut()
+            .map(|table| &raw mut *table as u64 - OFFSET);
+        let read_only = PageTableEntry::from(PageTableEntryFlag::ExecuteDisable);
+
+        // Needs a PDPT, a PD and a PT under PML4[1]
+        mapper
+            .map_4k(
+                0x80_0000_3000,
+                0x1234_5000,
+                read_only,
+                MemoryType::Uncacheable,
+                || free_tables.next(),
+            )
+            .unwrap();
+        mapper
+            .map_4k(
+                0x80_0000_4000,
+                0x1234_6000,
+                read_only,
+                MemoryType::WriteBack,
+                || None,
+            )
+            .unwrap();
+        assert_eq!(Some(0x1234_5678), mapper.translate(0x80_0000_3678));
+        assert_eq!(Some(0x1234_6000), mapper.translate(0x80_0000_4000));
+        assert_eq!(None, mapper.translate(0x80_0000_5000));
+        assert!(free_tables.next().is_none());
+        let [_, _, page_table] = &new_tables;
+        assert_eq!(0x1234_5000 | 0x19 | 1 << 63, page_table.0[3]);
+        assert_eq!(0, page_table.0[5]);
+
+        assert!(matches!(
+            mapper.map_4k(0x80_0000_3000, 0, read_only, MemoryType::WriteBack, || None),
+            Err(Fault::AlreadyMapped(0x80_0000_3000))
+        ));
+        assert!(matches!(
+            mapper.map_4k(0x5000, 0, read_only, MemoryType::WriteBack, || None),
+            Err(Fault::AlreadyMapped(0x5000))
+        ));
+        assert!(matches!(
+            mapper.map_4k(0x100_0000_0000, 0, read_only, MemoryType::WriteBack, || {
+                None
+            }),
+            Err(Fault::NoPageTableFor(0x100_0000_0000))
+        ));
+        assert!(matches!(
+            mapper.map_4k(0x1234, 0, read_only, MemoryType::WriteBack, || None),
+            Err(Fault::MisalignedAddress(0x1234))
+        ));
+
+        assert_eq!((0x1234_5000, 0x1000), mapper.unmap(0x80_0000_3abc).unwrap());
+        assert_eq!(None, mapper.translate(0x80_0000_3000));
+        assert_eq!(Some(0x1234_6000), mapper.translate(0x80_0000_4000));
+        assert!(matches!(
+            mapper.unmap(0x80_0000_3000),
+            Err(Fault::NotMapped(0x80_0000_3000))
+        ));
+        // A whole 1GB page
+        assert_eq!((0, 1 << 30), mapper.unmap(0x20_0000).unwrap());
+        assert_eq!(0, pdpt.0[0]);
+    }
+
+    /// Counts the lines written to it
+    struct LineCounter<'a>(&'a mut usize);
+
+    impl Write for LineCounter<'_> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            if *self.0 == 0 && !s.is_empty() {
+                *self.0 = 1;
+            }
+            *self.0 += s.matches('\n').count();
+            Ok(())
+        }
+    }
 }
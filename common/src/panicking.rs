@@ -0,0 +1,69 @@
+// What the panic handlers of the bootloader and the kernel have in common. A panic can happen
+// anywhere, in an interrupt handler or halfway through writing to the console, so they mask
+// interrupts first. A panic while handling a panic only gets a fixed message written straight to
+// COM1's registers, as whatever the first one was printing with may be what's broken. Once done,
+// they exit QEMU with a failure code if asked to, e.g. with the `panic=exit` kernel option, which
+// needs its isa-debug-exit device:
+//   qemu-system-x86_64 ... -device isa-debug-exit,iobase=0xf4,iosize=0x04
+use core::{arch::asm, fmt::Write};
+
+use crate::{
+    interrupts,
+    ioport::Port,
+    serial::{self, Com1, SerialPort},
+};
+
+pub const QEMU_EXIT_PORT: u16 = 0xF4;
+/// What QEMU exits with, shifted left by one and with the low bit set, i.e. 35
+pub const QEMU_EXIT_FAILURE: u32 = 0x11;
+
+static mut PANICS: usize = 0;
+static mut EXIT_QEMU: bool = false;
+
+/// Whether `halt_no_sync` exits QEMU instead of halting. Off by default, as without the exit
+/// device the write goes nowhere, or to whatever real hardware has at that port
+pub fn set_exit_qemu_no_sync(exit_qemu: bool) {
+    let exit_qemu_ptr = &raw mut EXIT_QEMU;
+    // SAFETY: no multitasking, no synchronization needed
+    unsafe { *exit_qemu_ptr = exit_qemu };
+}
+
+/// Starts handling a panic: masks interrupts, and tells whether it's the first one. If it isn't,
+/// it happened while handling another, and all that's safe is `write_raw` and `halt_no_sync`
+pub fn enter_no_sync() -> bool {
+    interrupts::disable();
+    let panics_ptr = &raw mut PANICS;
+    // SAFETY: no threads, and interrupts are masked so no handler can panic concurrently
+    let panics = unsafe { &mut *panics_ptr };
+    *panics += 1;
+    *panics == 1
+}
+
+/// Writes `message` to COM1 polling its registers, with none of the state the console keeps
+pub fn write_raw(message: &str) {
+    let _ = SerialPort::unchecked(serial::COM1).write_str(message);
+}
+
+/// Ends a panic: exits QEMU if asked to, halts for good otherwise
+pub fn halt_no_sync() -> ! {
+    let panics_ptr = &raw const PANICS;
+    // SAFETY: no threads, and interrupts are masked since `enter_no_sync`
+    let nested = unsafe { *panics_ptr } > 1;
+    let exit_qemu_ptr = &raw const EXIT_QEMU;
+    // SAFETY: no multitasking, no synchronization needed
+    if unsafe { *exit_qemu_ptr } {
+        // What's still queued for the UART would be lost with the machine, unless that's where
+        // the first panic happened
+        if !nested && Com1::initialized() {
+            Com1::get().flush();
+        }
+        Port::new(QEMU_EXIT_PORT).writed(QEMU_EXIT_FAILURE);
+    }
+    // Not running in QEMU, or without the exit device
+    loop {
+        // SAFETY: Halting with interrupts masked has no side effects
+        unsafe {
+            asm!("hlt", options(nomem, nostack, preserves_flags));
+        }
+    }
+}
@@ -0,0 +1,161 @@
+// The table of what the bootloader loads besides itself, which xtasks writes into the sector right
+// after stage2. Each entry says what a payload is and which sectors of the boot disk it takes, so
+// that shipping something new doesn't take a new define in stage1:
+//   0x00  magic "BLOGPAYL"
+//   0x08  u32 number of entries
+//   0x0C  u32 reserved, 0
+//   0x10  entries of { u32 kind, u32 sectors, u64 LBA }
+use core::fmt::Display;
+
+use num_enum::TryFromPrimitive;
+use zerocopy::{LE, TryFromBytes, U32, U64};
+
+use crate::error::{Error, Facility, Fault, try_read_error};
+
+pub const TABLE_MAGIC: [u8; 8] = *b"BLOGPAYL";
+/// As many entries as fit in the table's sector
+pub const MAX_PAYLOADS: usize = 31;
+
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PayloadKind {
+    Kernel = 1,
+    /// The kernel's symbols, for backtraces
+    Symbols = 2,
+    Initrd = 3,
+    /// Options for the kernel, see command_line.rs
+    CommandLine = 4,
+    /// stage2's symbols, for the backtraces of its exception handlers
+    Stage2Symbols = 5,
+}
+
+impl Display for PayloadKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            PayloadKind::Kernel => "kernel",
+            PayloadKind::Symbols => "symbols",
+            PayloadKind::Initrd => "initrd",
+            PayloadKind::CommandLine => "command line",
+            PayloadKind::Stage2Symbols => "stage2 symbols",
+        })
+    }
+}
+
+#[derive(TryFromBytes)]
+#[repr(C)]
+struct PayloadTableHeaderRaw {
+    magic: [u8; 8],
+    count: U32<LE>,
+    reserved: U32<LE>,
+}
+
+#[derive(TryFromBytes)]
+#[repr(C)]
+struct PayloadEntryRaw {
+    kind: U32<LE>,
+    sectors: U32<LE>,
+    lba: U64<LE>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadEntry {
+    pub kind: PayloadKind,
+    pub sectors: u32,
+    pub lba: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadTable {
+    entries: [Option<PayloadEntry>; MAX_PAYLOADS],
+    count: usize,
+}
+
+impl PayloadTable {
+    pub fn entries(&self) -> impl Iterator<Item = &PayloadEntry> {
+        self.entries[..self.count].iter().flatten()
+    }
+
+    /// The first payload of `kind`
+    pub fn find(&self, kind: PayloadKind) -> Option<&PayloadEntry> {
+        self.entries().find(|entry| entry.kind == kind)
+    }
+}
+
+fn parsing_error(fault: Fault) -> Error {
+    Error::parsing_error(fault, Facility::PayloadTable)
+}
+
+impl TryFrom<&[u8]> for PayloadTable {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let (header, mut rest) = PayloadTableHeaderRaw::try_read_from_prefix(bytes)
+            .map_err(|err| try_read_error(Facility::PayloadTable, err))?;
+        if header.magic != TABLE_MAGIC {
+            return Err(parsing_error(Fault::InvalidValueForField("magic")));
+        }
+        if header.reserved.get() != 0 {
+            return Err(parsing_error(Fault::InvalidValueForField("reserved")));
+        }
+        let count = header.count.get() as usize;
+        if count > MAX_PAYLOADS {
+            return Err(parsing_error(Fault::InvalidValueForField("count")));
+        }
+
+        let mut entries = [None; MAX_PAYLOADS];
+        for entry in &mut entries[..count] {
+            let (raw_entry, after) = PayloadEntryRaw::try_read_from_prefix(rest)
+                .map_err(|err| try_read_error(Facility::PayloadTable, err))?;
+            rest = after;
+            *entry = Some(PayloadEntry {
+                kind: PayloadKind::try_from(raw_entry.kind.get())
+                    .map_err(|_| parsing_error(Fault::InvalidValueForField("kind")))?,
+                sectors: raw_entry.sectors.get(),
+                lba: raw_entry.lba.get(),
+            });
+        }
+        Ok(Self { entries, count })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::payload::{PayloadEntry, PayloadKind, PayloadTable, TABLE_MAGIC};
+
+    fn table(entries: &[(u32, u32, u64)]) -> [u8; 512] {
+        let mut bytes = [0u8; 512];
+        bytes[..8].copy_from_slice(&TABLE_MAGIC);
+        bytes[8..12].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+        for (i, (kind, sectors, lba)) in entries.iter().enumerate() {
+            let entry = &mut bytes[16 + i * 16..32 + i * 16];
+            entry[..4].copy_from_slice(&kind.to_le_bytes());
+            entry[4..8].copy_from_slice(&sectors.to_le_bytes());
+            entry[8..].copy_from_slice(&lba.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn parse() {
+        let bytes = table(&[(1, 40, 7), (3, 8, 47)]);
+        let payloads = PayloadTable::try_from(&bytes[..]).unwrap();
+        assert_eq!(2, payloads.entries().count());
+        assert_eq!(
+            Some(&PayloadEntry {
+                kind: PayloadKind::Initrd,
+                sectors: 8,
+                lba: 47
+            }),
+            payloads.find(PayloadKind::Initrd)
+        );
+        assert_eq!(None, payloads.find(PayloadKind::Symbols));
+
+        assert!(PayloadTable::try_from(&table(&[(6, 1, 1)])[..]).is_err());
+        let mut bad_magic = bytes;
+        bad_magic[0] = b'X';
+        assert!(PayloadTable::try_from(&bad_magic[..]).is_err());
+        let mut too_many = bytes;
+        too_many[8] = 32;
+        assert!(PayloadTable::try_from(&too_many[..]).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+// Typed views of the capabilities of a PCI function that interrupts and power depend on: MSI and
+// MSI-X, which have the function signal interrupts by writing a message to the local APICs
+// instead of asserting a pin, and power management, which has D0 to D3hot. Each view is the
+// function along with the capability's offset in its configuration space, found by walking its
+// capabilities list
+// https://wiki.osdev.org/PCI#Message_Signaled_Interrupts
+// https://wiki.osdev.org/PCI#Enabling_MSI-X
+use crate::{
+    error::Fault,
+    msr::MemoryType,
+    paging,
+    pci_function::{BaseAddress, Function},
+};
+
+pub const POWER_MANAGEMENT_CAPABILITY: u8 = 0x01;
+pub const MSI_CAPABILITY: u8 = 0x05;
+pub const VENDOR_SPECIFIC_CAPABILITY: u8 = 0x09;
+pub const MSI_X_CAPABILITY: u8 = 0x11;
+
+// The message control register is the upper half of the first dword of both MSI and MSI-X
+const MSI_ENABLE: u32 = 1 << 16;
+const MSI_MULTIPLE_MESSAGE_CAPABLE_SHIFT: u32 = 17;
+const MSI_MULTIPLE_MESSAGE_ENABLE_SHIFT: u32 = 20;
+const MSI_MULTIPLE_MESSAGE_MASK: u32 = 0x7;
+const MSI_64_BIT: u32 = 1 << 23;
+const MSI_ADDRESS_OFFSET: u8 = 0x04;
+const MSI_X_TABLE_SIZE_MASK: u32 = 0x7FF << 16;
+const MSI_X_FUNCTION_MASK: u32 = 1 << 30;
+const MSI_X_ENABLE: u32 = 1 << 31;
+const MSI_X_TABLE_OFFSET: u8 = 0x04;
+const MSI_X_PENDING_BIT_ARRAY_OFFSET: u8 = 0x08;
+// The low 3 bits of the table and pending bit array offsets are the BAR they're in
+const MSI_X_BASE_ADDRESS_REGISTER_MASK: u32 = 0x7;
+const MSI_X_TABLE_ENTRY_SIZE: u64 = 16;
+const MSI_X_VECTOR_MASKED: u32 = 1 << 0;
+const POWER_MANAGEMENT_CONTROL_STATUS_OFFSET: u8 = 0x04;
+// In the capabilities register, the upper half of the first dword
+const POWER_MANAGEMENT_D1_SUPPORT: u32 = 1 << 25;
+const POWER_MANAGEMENT_D2_SUPPORT: u32 = 1 << 26;
+const POWER_STATE_MASK: u32 = 0x3;
+
+// Where the local APICs take messages, as a fixed interrupt with edge trigger
+const MESSAGE_ADDRESS_BASE: u64 = 0xFEE0_0000;
+const MESSAGE_DESTINATION_SHIFT: u32 = 12;
+
+/// What a function writes to signal an interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message {
+    pub address: u64,
+    pub data: u32,
+}
+
+impl Message {
+    /// A fixed, edge triggered interrupt `vector` for the local APIC with ID `destination`
+    pub fn fixed(destination: u8, vector: u8) -> Self {
+        Self {
+            address: MESSAGE_ADDRESS_BASE | (destination as u64) << MESSAGE_DESTINATION_SHIFT,
+            data: vector as u32,
+        }
+    }
+}
+
+impl Function {
+    fn capability_offset(&self, id: u8) -> Option<u8> {
+        self.capabilities()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+
+    pub fn msi(&self) -> Option<Msi<'_>> {
+        self.capability_offset(MSI_CAPABILITY).map(|offset| Msi {
+            function: self,
+            offset,
+        })
+    }
+
+    pub fn msi_x(&self) -> Option<MsiX<'_>> {
+        self.capability_offset(MSI_X_CAPABILITY).map(|offset| MsiX {
+            function: self,
+            offset,
+        })
+    }
+
+    pub fn power_management(&self) -> Option<PowerManagement<'_>> {
+        self.capability_offset(POWER_MANAGEMENT_CAPABILITY)
+            .map(|offset| PowerManagement {
+                function: self,
+                offset,
+            })
+    }
+}
+
+/// Where the message data register is, past a 32 or a 64-bit address
+fn msi_data_offset(control: u32) -> u8 {
+    if control & MSI_64_BIT != 0 {
+        0x0C
+    } else {
+        0x08
+    }
+}
+
+pub struct Msi<'a> {
+    function: &'a Function,
+    offset: u8,
+}
+
+impl Msi<'_> {
+    fn control(&self) -> u32 {
+        self.function.read_config(self.offset)
+    }
+
+    /// Whether the message address can be above 4GB
+    pub fn is_64_bit(&self) -> bool {
+        self.control() & MSI_64_BIT != 0
+    }
+
+    /// How many vectors the function can signal, which are consecutive
+    pub fn vectors(&self) -> u8 {
+        1 << (self.control() >> MSI_MULTIPLE_MESSAGE_CAPABLE_SHIFT & MSI_MULTIPLE_MESSAGE_MASK)
+    }
+
+    /// Has the function write `message` for its interrupt, and enables MSI with a single vector.
+    /// The function stops asserting its interrupt pin meanwhile
+    pub fn enable(&self, message: Message) -> Result<(), Fault> {
+        let control = self.control();
+        if control & MSI_64_BIT == 0 && message.address > u32::MAX as u64 {
+            return Err(Fault::InvalidValueForField("MSI message address"));
+        }
+        self.function
+            .write_config(self.offset + MSI_ADDRESS_OFFSET, message.address as u32);
+        if control & MSI_64_BIT != 0 {
+            self.function.write_config(
+                self.offset + MSI_ADDRESS_OFFSET + 4,
+                (message.address >> 32) as u32,
+            );
+        }
+        // The upper half of the data dword is reserved, or the extended message data
+        self.function.write_config(
+            self.offset + msi_data_offset(control),
+            message.data & 0xFFFF,
+        );
+        let single_vector = !(MSI_MULTIPLE_MESSAGE_MASK << MSI_MULTIPLE_MESSAGE_ENABLE_SHIFT);
+        self.function
+            .write_config(self.offset, control & single_vector | MSI_ENABLE);
+        Ok(())
+    }
+
+    pub fn disable(&self) {
+        self.function
+            .write_config(self.offset, self.control() & !MSI_ENABLE);
+    }
+}
+
+pub struct MsiX<'a> {
+    function: &'a Function,
+    offset: u8,
+}
+
+impl MsiX<'_> {
+    fn control(&self) -> u32 {
+        self.function.read_config(self.offset)
+    }
+
+    /// How many entries the table has
+    pub fn table_size(&self) -> u16 {
+        ((self.control() & MSI_X_TABLE_SIZE_MASK) >> 16) as u16 + 1
+    }
+
+    /// The physical address of the structure whose BAR and offset are at `offset`
+    fn structure_address(&self, offset: u8) -> Result<u64, Fault> {
+        let location = self.function.read_config(self.offset + offset);
+        let index = (location & MSI_X_BASE_ADDRESS_REGISTER_MASK) as u8;
+        match self.function.base_address(index) {
+            Some(BaseAddress::Memory(address)) => {
+                Ok(address + (location & !MSI_X_BASE_ADDRESS_REGISTER_MASK) as u64)
+            }
+            _ => Err(Fault::InvalidValueForField("MSI-X BIR")),
+        }
+    }
+
+    /// The table of the messages of each vector, identity mapped as uncacheable
+    pub fn table(&self) -> Result<MsiXTable, Fault> {
+        let address = self.structure_address(MSI_X_TABLE_OFFSET)?;
+        let size = self.table_size();
+        let mapper = paging::Mapper::active();
+        mapper.identity_map_gigabyte(address, MemoryType::Uncacheable)?;
+        mapper.identity_map_gigabyte(
+            address + size as u64 * MSI_X_TABLE_ENTRY_SIZE - 1,
+            MemoryType::Uncacheable,
+        )?;
+        Ok(MsiXTable { address, size })
+    }
+
+    /// The physical address of the bits of the vectors that are pending while masked
+    pub fn pending_bit_array_address(&self) -> Result<u64, Fault> {
+        self.structure_address(MSI_X_PENDING_BIT_ARRAY_OFFSET)
+    }
+
+    /// Enables MSI-X, with the vectors masked as a whole until `unmask_all`. The function stops
+    /// asserting its interrupt pin meanwhile
+    pub fn enable(&self) {
+        self.function.write_config(
+            self.offset,
+            self.control() | MSI_X_ENABLE | MSI_X_FUNCTION_MASK,
+        );
+    }
+
+    pub fn unmask_all(&self) {
+        self.function
+            .write_config(self.offset, self.control() & !MSI_X_FUNCTION_MASK);
+    }
+
+    pub fn disable(&self) {
+        self.function
+            .write_config(self.offset, self.control() & !MSI_X_ENABLE);
+    }
+}
+
+/// The MSI-X table of a function, an entry of a message and a mask bit per vector
+pub struct MsiXTable {
+    address: u64,
+    size: u16,
+}
+
+impl MsiXTable {
+    fn write(&self, vector: u16, offset: u64, value: u32) {
+        let register_ptr =
+            (self.address + vector as u64 * MSI_X_TABLE_ENTRY_SIZE + offset) as usize as *mut u32;
+        // SAFETY: The table was identity mapped as uncacheable by `MsiX::table`, and the callers
+        // check `vector` to be one of its entries
+        unsafe { register_ptr.write_volatile(value) }
+    }
+
+    /// Has the function write `message` for `vector`, which is left masked
+    pub fn program(&self, vector: u16, message: Message) -> Result<(), Fault> {
+        if vector >= self.size {
+            return Err(Fault::InvalidValueForField("MSI-X vector"));
+        }
+        // The entry is only to be changed while masked
+        self.write(vector, 0xC, MSI_X_VECTOR_MASKED);
+        self.write(vector, 0x0, message.address as u32);
+        self.write(vector, 0x4, (message.address >> 32) as u32);
+        self.write(vector, 0x8, message.data);
+        Ok(())
+    }
+
+    pub fn set_masked(&self, vector: u16, masked: bool) -> Result<(), Fault> {
+        if vector >= self.size {
+            return Err(Fault::InvalidValueForField("MSI-X vector"));
+        }
+        self.write(vector, 0xC, if masked { MSI_X_VECTOR_MASKED } else { 0 });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+pub struct PowerManagement<'a> {
+    function: &'a Function,
+    offset: u8,
+}
+
+impl PowerManagement<'_> {
+    pub fn supports(&self, state: PowerState) -> bool {
+        let capabilities = self.function.read_config(self.offset);
+        match state {
+            PowerState::D0 | PowerState::D3Hot => true,
+            PowerState::D1 => capabilities & POWER_MANAGEMENT_D1_SUPPORT != 0,
+            PowerState::D2 => capabilities & POWER_MANAGEMENT_D2_SUPPORT != 0,
+        }
+    }
+
+    pub fn power_state(&self) -> PowerState {
+        match self
+            .function
+            .read_config(self.offset + POWER_MANAGEMENT_CONTROL_STATUS_OFFSET)
+            & POWER_STATE_MASK
+        {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Moves the function to `state`. Going back to D0 from D3hot may reset it, and it takes the
+    /// function 10ms, which is up to the caller to wait for
+    pub fn set_power_state(&self, state: PowerState) -> Result<(), Fault> {
+        if !self.supports(state) {
+            return Err(Fault::InvalidValueForField("power state"));
+        }
+        let offset = self.offset + POWER_MANAGEMENT_CONTROL_STATUS_OFFSET;
+        // Only the control half is written back: the PME status bit is cleared by writing a 1
+        let control = self.function.read_config(offset) & 0xFFFF & !(1 << 15);
+        self.function
+            .write_config(offset, control & !POWER_STATE_MASK | state as u32);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pci_capability::{MSI_64_BIT, Message, msi_data_offset};
+
+    #[test]
+    fn fixed_messages() {
+        let message = Message::fixed(0, 0x30);
+        assert_eq!(0xFEE0_0000, message.address);
+        assert_eq!(0x30, message.data);
+        assert_eq!(0xFEE0_3000, Message::fixed(3, 0x41).address);
+    }
+
+    #[test]
+    fn msi_data_follows_the_address() {
+        assert_eq!(0x08, msi_data_offset(0));
+        assert_eq!(0x0C, msi_data_offset(MSI_64_BIT));
+    }
+}
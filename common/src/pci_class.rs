@@ -0,0 +1,394 @@
+// The class code of PCI functions: a class, a subclass and a programming interface, decoded into
+// the names lspci uses for them, from the PCI ID repository. Only the subclasses of the classes
+// there are drivers or listings for are broken down, the others keep their raw subclass
+// https://wiki.osdev.org/PCI#Class_Codes
+// https://pci-ids.ucw.cz/read/PD/
+use core::fmt::Display;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MassStorageSubclass {
+    Scsi,
+    Ide,
+    Floppy,
+    Ipi,
+    Raid,
+    Ata,
+    Sata,
+    Ahci,
+    SerialAttachedScsi,
+    NonVolatileMemory,
+    Nvme,
+    UniversalFlashStorage,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkSubclass {
+    Ethernet,
+    TokenRing,
+    Fddi,
+    Atm,
+    Isdn,
+    WorldFip,
+    Picmg,
+    InfiniBand,
+    Fabric,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplaySubclass {
+    Vga,
+    Xga,
+    ThreeD,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeSubclass {
+    Host,
+    Isa,
+    Eisa,
+    MicroChannel,
+    Pci,
+    Pcmcia,
+    NuBus,
+    CardBus,
+    RaceWay,
+    SemiTransparentPci,
+    InfiniBandToPci,
+    Other(u8),
+}
+
+/// The programming interface of a USB controller, i.e. the USB version it drives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbController {
+    Uhci,
+    Ohci,
+    Ehci,
+    Xhci,
+    Device,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialBusSubclass {
+    FireWire,
+    AccessBus,
+    Ssa,
+    Usb(UsbController),
+    FibreChannel,
+    SmBus,
+    InfiniBand,
+    Ipmi,
+    Sercos,
+    CanBus,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciClass {
+    Unclassified(u8),
+    MassStorage(MassStorageSubclass),
+    Network(NetworkSubclass),
+    Display(DisplaySubclass),
+    Multimedia(u8),
+    Memory(u8),
+    Bridge(BridgeSubclass),
+    Communication(u8),
+    SystemPeripheral(u8),
+    Input(u8),
+    DockingStation(u8),
+    Processor(u8),
+    SerialBus(SerialBusSubclass),
+    Wireless(u8),
+    Intelligent(u8),
+    SatelliteCommunication(u8),
+    Encryption(u8),
+    SignalProcessing(u8),
+    ProcessingAccelerator(u8),
+    NonEssentialInstrumentation(u8),
+    Coprocessor(u8),
+    Unassigned(u8),
+    Unknown { class: u8, subclass: u8 },
+}
+
+impl PciClass {
+    pub fn new(class: u8, subclass: u8, programming_interface: u8) -> Self {
+        match class {
+            0x00 => PciClass::Unclassified(subclass),
+            0x01 => PciClass::MassStorage(match (subclass, programming_interface) {
+                (0x00, _) => MassStorageSubclass::Scsi,
+                (0x01, _) => MassStorageSubclass::Ide,
+                (0x02, _) => MassStorageSubclass::Floppy,
+                (0x03, _) => MassStorageSubclass::Ipi,
+                (0x04, _) => MassStorageSubclass::Raid,
+                (0x05, _) => MassStorageSubclass::Ata,
+                (0x06, 0x01) => MassStorageSubclass::Ahci,
+                (0x06, _) => MassStorageSubclass::Sata,
+                (0x07, _) => MassStorageSubclass::SerialAttachedScsi,
+                (0x08, 0x02) => MassStorageSubclass::Nvme,
+                (0x08, _) => MassStorageSubclass::NonVolatileMemory,
+                (0x09, _) => MassStorageSubclass::UniversalFlashStorage,
+                (subclass, _) => MassStorageSubclass::Other(subclass),
+            }),
+            0x02 => PciClass::Network(match subclass {
+                0x00 => NetworkSubclass::Ethernet,
+                0x01 => NetworkSubclass::TokenRing,
+                0x02 => NetworkSubclass::Fddi,
+                0x03 => NetworkSubclass::Atm,
+                0x04 => NetworkSubclass::Isdn,
+                0x05 => NetworkSubclass::WorldFip,
+                0x06 => NetworkSubclass::Picmg,
+                0x07 => NetworkSubclass::InfiniBand,
+                0x08 => NetworkSubclass::Fabric,
+                subclass => NetworkSubclass::Other(subclass),
+            }),
+            0x03 => PciClass::Display(match subclass {
+                0x00 => DisplaySubclass::Vga,
+                0x01 => DisplaySubclass::Xga,
+                0x02 => DisplaySubclass::ThreeD,
+                subclass => DisplaySubclass::Other(subclass),
+            }),
+            0x04 => PciClass::Multimedia(subclass),
+            0x05 => PciClass::Memory(subclass),
+            0x06 => PciClass::Bridge(match subclass {
+                0x00 => BridgeSubclass::Host,
+                0x01 => BridgeSubclass::Isa,
+                0x02 => BridgeSubclass::Eisa,
+                0x03 => BridgeSubclass::MicroChannel,
+                0x04 => BridgeSubclass::Pci,
+                0x05 => BridgeSubclass::Pcmcia,
+                0x06 => BridgeSubclass::NuBus,
+                0x07 => BridgeSubclass::CardBus,
+                0x08 => BridgeSubclass::RaceWay,
+                0x09 => BridgeSubclass::SemiTransparentPci,
+                0x0A => BridgeSubclass::InfiniBandToPci,
+                subclass => BridgeSubclass::Other(subclass),
+            }),
+            0x07 => PciClass::Communication(subclass),
+            0x08 => PciClass::SystemPeripheral(subclass),
+            0x09 => PciClass::Input(subclass),
+            0x0A => PciClass::DockingStation(subclass),
+            0x0B => PciClass::Processor(subclass),
+            0x0C => PciClass::SerialBus(match subclass {
+                0x00 => SerialBusSubclass::FireWire,
+                0x01 => SerialBusSubclass::AccessBus,
+                0x02 => SerialBusSubclass::Ssa,
+                0x03 => SerialBusSubclass::Usb(match programming_interface {
+                    0x00 => UsbController::Uhci,
+                    0x10 => UsbController::Ohci,
+                    0x20 => UsbController::Ehci,
+                    0x30 => UsbController::Xhci,
+                    0xFE => UsbController::Device,
+                    programming_interface => UsbController::Other(programming_interface),
+                }),
+                0x04 => SerialBusSubclass::FibreChannel,
+                0x05 => SerialBusSubclass::SmBus,
+                0x06 => SerialBusSubclass::InfiniBand,
+                0x07 => SerialBusSubclass::Ipmi,
+                0x08 => SerialBusSubclass::Sercos,
+                0x09 => SerialBusSubclass::CanBus,
+                subclass => SerialBusSubclass::Other(subclass),
+            }),
+            0x0D => PciClass::Wireless(subclass),
+            0x0E => PciClass::Intelligent(subclass),
+            0x0F => PciClass::SatelliteCommunication(subclass),
+            0x10 => PciClass::Encryption(subclass),
+            0x11 => PciClass::SignalProcessing(subclass),
+            0x12 => PciClass::ProcessingAccelerator(subclass),
+            0x13 => PciClass::NonEssentialInstrumentation(subclass),
+            0x40 => PciClass::Coprocessor(subclass),
+            0xFF => PciClass::Unassigned(subclass),
+            class => PciClass::Unknown { class, subclass },
+        }
+    }
+
+    pub fn is_usb(&self) -> bool {
+        matches!(self, PciClass::SerialBus(SerialBusSubclass::Usb(_)))
+    }
+}
+
+impl Display for PciClass {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let name = match self {
+            PciClass::Unclassified(_) => "Unclassified device",
+            PciClass::MassStorage(subclass) => match subclass {
+                MassStorageSubclass::Scsi => "SCSI storage controller",
+                MassStorageSubclass::Ide => "IDE interface",
+                MassStorageSubclass::Floppy => "Floppy disk controller",
+                MassStorageSubclass::Ipi => "IPI bus controller",
+                MassStorageSubclass::Raid => "RAID bus controller",
+                MassStorageSubclass::Ata => "ATA controller",
+                MassStorageSubclass::Sata => "SATA controller",
+                MassStorageSubclass::Ahci => "SATA controller (AHCI)",
+                MassStorageSubclass::SerialAttachedScsi => "Serial Attached SCSI controller",
+                MassStorageSubclass::NonVolatileMemory => "Non-Volatile memory controller",
+                MassStorageSubclass::Nvme => "Non-Volatile memory controller (NVMe)",
+                MassStorageSubclass::UniversalFlashStorage => "Universal Flash Storage controller",
+                MassStorageSubclass::Other(_) => "Mass storage controller",
+            },
+            PciClass::Network(subclass) => match subclass {
+                NetworkSubclass::Ethernet => "Ethernet controller",
+                NetworkSubclass::TokenRing => "Token ring network controller",
+                NetworkSubclass::Fddi => "FDDI network controller",
+                NetworkSubclass::Atm => "ATM network controller",
+                NetworkSubclass::Isdn => "ISDN controller",
+                NetworkSubclass::WorldFip => "WorldFip controller",
+                NetworkSubclass::Picmg => "PICMG controller",
+                NetworkSubclass::InfiniBand => "Infiniband controller",
+                NetworkSubclass::Fabric => "Fabric controller",
+                NetworkSubclass::Other(_) => "Network controller",
+            },
+            PciClass::Display(subclass) => match subclass {
+                DisplaySubclass::Vga => "VGA compatible controller",
+                DisplaySubclass::Xga => "XGA compatible controller",
+                DisplaySubclass::ThreeD => "3D controller",
+                DisplaySubclass::Other(_) => "Display controller",
+            },
+            PciClass::Multimedia(_) => "Multimedia controller",
+            PciClass::Memory(_) => "Memory controller",
+            PciClass::Bridge(subclass) => match subclass {
+                BridgeSubclass::Host => "Host bridge",
+                BridgeSubclass::Isa => "ISA bridge",
+                BridgeSubclass::Eisa => "EISA bridge",
+                BridgeSubclass::MicroChannel => "MicroChannel bridge",
+                BridgeSubclass::Pci => "PCI bridge",
+                BridgeSubclass::Pcmcia => "PCMCIA bridge",
+                BridgeSubclass::NuBus => "NuBus bridge",
+                BridgeSubclass::CardBus => "CardBus bridge",
+                BridgeSubclass::RaceWay => "RACEway bridge",
+                BridgeSubclass::SemiTransparentPci => "Semi-transparent PCI-to-PCI bridge",
+                BridgeSubclass::InfiniBandToPci => "InfiniBand to PCI host bridge",
+                BridgeSubclass::Other(_) => "Bridge",
+            },
+            PciClass::Communication(_) => "Communication controller",
+            PciClass::SystemPeripheral(_) => "Generic system peripheral",
+            PciClass::Input(_) => "Input device controller",
+            PciClass::DockingStation(_) => "Docking station",
+            PciClass::Processor(_) => "Processor",
+            PciClass::SerialBus(subclass) => match subclass {
+                SerialBusSubclass::FireWire => "FireWire (IEEE 1394)",
+                SerialBusSubclass::AccessBus => "ACCESS Bus",
+                SerialBusSubclass::Ssa => "SSA",
+                SerialBusSubclass::Usb(controller) => match controller {
+                    UsbController::Uhci => "USB controller (UHCI)",
+                    UsbController::Ohci => "USB controller (OHCI)",
+                    UsbController::Ehci => "USB controller (EHCI)",
+                    UsbController::Xhci => "USB controller (XHCI)",
+                    UsbController::Device => "USB controller (USB Device)",
+                    UsbController::Other(_) => "USB controller",
+                },
+                SerialBusSubclass::FibreChannel => "Fibre Channel",
+                SerialBusSubclass::SmBus => "SMBus",
+                SerialBusSubclass::InfiniBand => "InfiniBand",
+                SerialBusSubclass::Ipmi => "IPMI Interface",
+                SerialBusSubclass::Sercos => "SERCOS interface",
+                SerialBusSubclass::CanBus => "CANBUS",
+                SerialBusSubclass::Other(_) => "Serial bus controller",
+            },
+            PciClass::Wireless(_) => "Wireless controller",
+            PciClass::Intelligent(_) => "Intelligent controller",
+            PciClass::SatelliteCommunication(_) => "Satellite communications controller",
+            PciClass::Encryption(_) => "Encryption controller",
+            PciClass::SignalProcessing(_) => "Signal processing controller",
+            PciClass::ProcessingAccelerator(_) => "Processing accelerators",
+            PciClass::NonEssentialInstrumentation(_) => "Non-Essential Instrumentation",
+            PciClass::Coprocessor(_) => "Coprocessor",
+            PciClass::Unassigned(_) => "Unassigned class",
+            // As lspci shows classes it has no name for
+            PciClass::Unknown { class, subclass } => {
+                return write!(f, "Class {:02x}{:02x}", class, subclass);
+            }
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        pci_class::{
+            BridgeSubclass, MassStorageSubclass, NetworkSubclass, PciClass, SerialBusSubclass,
+            UsbController,
+        },
+        test_support::TestWriter,
+    };
+
+    /// Displays `class` in a buffer as long as the longest name
+    fn name(class: PciClass) -> TestWriter<64> {
+        let mut writer = TestWriter::<64>::new();
+        write!(writer, "{}", class).unwrap();
+        writer
+    }
+
+    #[test]
+    fn decodes_qemu_devices() {
+        // The q35 machine's, with an e1000, an NVMe drive and an XHCI controller
+        assert_eq!(
+            PciClass::Bridge(BridgeSubclass::Host),
+            PciClass::new(0x06, 0x00, 0x00)
+        );
+        assert_eq!(
+            PciClass::MassStorage(MassStorageSubclass::Ahci),
+            PciClass::new(0x01, 0x06, 0x01)
+        );
+        assert_eq!(
+            PciClass::MassStorage(MassStorageSubclass::Nvme),
+            PciClass::new(0x01, 0x08, 0x02)
+        );
+        assert_eq!(
+            PciClass::Network(NetworkSubclass::Ethernet),
+            PciClass::new(0x02, 0x00, 0x00)
+        );
+        assert_eq!(
+            PciClass::SerialBus(SerialBusSubclass::Usb(UsbController::Xhci)),
+            PciClass::new(0x0C, 0x03, 0x30)
+        );
+        assert!(PciClass::new(0x0C, 0x03, 0x30).is_usb());
+        assert!(!PciClass::new(0x0C, 0x05, 0x00).is_usb());
+    }
+
+    #[test]
+    fn keeps_what_it_cant_name() {
+        assert_eq!(
+            PciClass::MassStorage(MassStorageSubclass::Other(0x80)),
+            PciClass::new(0x01, 0x80, 0x00)
+        );
+        assert_eq!(
+            PciClass::SerialBus(SerialBusSubclass::Usb(UsbController::Other(0x80))),
+            PciClass::new(0x0C, 0x03, 0x80)
+        );
+        assert_eq!(PciClass::Multimedia(0x03), PciClass::new(0x04, 0x03, 0x00));
+        assert_eq!(
+            PciClass::Unknown {
+                class: 0x20,
+                subclass: 0x01
+            },
+            PciClass::new(0x20, 0x01, 0x00)
+        );
+    }
+
+    #[test]
+    fn displays_like_lspci() {
+        assert_eq!(
+            "Host bridge",
+            name(PciClass::new(0x06, 0x00, 0x00)).as_str()
+        );
+        assert_eq!(
+            "VGA compatible controller",
+            name(PciClass::new(0x03, 0x00, 0x00)).as_str()
+        );
+        assert_eq!(
+            "USB controller (XHCI)",
+            name(PciClass::new(0x0C, 0x03, 0x30)).as_str()
+        );
+        assert_eq!(
+            "Mass storage controller",
+            name(PciClass::new(0x01, 0x80, 0x00)).as_str()
+        );
+        assert_eq!("Class 2001", name(PciClass::new(0x20, 0x01, 0x00)).as_str());
+    }
+}
@@ -0,0 +1,315 @@
+// https://wiki.osdev.org/PCI#Configuration_Space_Access_Mechanism_#1
+use core::fmt::Display;
+
+use crate::{ioport::Port, make_bitmap, pci, pci_class::PciClass};
+
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+const VENDOR_ID_OFFSET: u8 = 0x00;
+const COMMAND_OFFSET: u8 = 0x04;
+const CLASS_OFFSET: u8 = 0x08;
+const HEADER_TYPE_OFFSET: u8 = 0x0C;
+const BASE_ADDRESS_REGISTER_0_OFFSET: u8 = 0x10;
+const CAPABILITIES_POINTER_OFFSET: u8 = 0x34;
+const INTERRUPT_LINE_OFFSET: u8 = 0x3C;
+
+const NO_DEVICE_VENDOR_ID: u16 = 0xFFFF;
+const MULTI_FUNCTION_DEVICE: u8 = 0x80;
+const HEADER_TYPE_DEVICE: u8 = 0x0;
+const HEADER_TYPE_PCI_BRIDGE: u8 = 0x1;
+// In the status register, the upper half of the command dword
+const STATUS_CAPABILITIES_LIST: u32 = 1 << 20;
+// Bounds the walk of a malformed, looping capabilities list: they're at least 4 bytes each, in
+// the 192 bytes after the header
+const MAX_CAPABILITIES: usize = 48;
+pub const BASE_ADDRESS_REGISTERS: u8 = 6;
+
+#[allow(unused)]
+#[repr(u16)]
+pub enum CommandFlag {
+    IoSpace = 1 << 0,
+    MemorySpace = 1 << 1,
+    BusMaster = 1 << 2,
+    InterruptDisable = 1 << 10,
+}
+
+make_bitmap!(new_type: Command, underlying_flag_type: CommandFlag, repr: u16, nodisplay);
+
+/// What a base address register points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseAddress {
+    Memory(u64),
+    Io(u16),
+}
+
+/// An entry of a function's capabilities list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Configuration space offset of the capability, whose first two bytes are its ID and the
+    /// offset of the next one
+    pub offset: u8,
+}
+
+pub struct Capabilities<'a> {
+    function: &'a Function,
+    next_offset: u8,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities<'_> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The bottom two bits are reserved
+        let offset = self.next_offset & !0x3;
+        if offset == 0 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.function.read_config(offset);
+        self.next_offset = (header >> 8) as u8;
+        Some(Capability {
+            id: header as u8,
+            offset,
+        })
+    }
+}
+
+/// A function of a device on a PCI bus, with dword access to its whole configuration space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Function {
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl Function {
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    fn config_address(&self, offset: u8) -> u32 {
+        CONFIG_ADDRESS_ENABLE
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    /// Reads the dword containing `offset`
+    pub fn read_config(&self, offset: u8) -> u32 {
+        Port::new(CONFIG_ADDRESS_PORT).writed(self.config_address(offset));
+        Port::new(CONFIG_DATA_PORT).readd()
+    }
+
+    /// Writes the dword containing `offset`
+    pub fn write_config(&self, offset: u8, value: u32) {
+        Port::new(CONFIG_ADDRESS_PORT).writed(self.config_address(offset));
+        Port::new(CONFIG_DATA_PORT).writed(value);
+    }
+
+    /// Reads the byte at `offset`
+    pub fn read_config_byte(&self, offset: u8) -> u8 {
+        (self.read_config(offset) >> ((offset & 0x3) * 8)) as u8
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_config(VENDOR_ID_OFFSET) as u16
+    }
+
+    pub fn device_id(&self) -> u16 {
+        (self.read_config(VENDOR_ID_OFFSET) >> 16) as u16
+    }
+
+    /// Class code, subclass and programming interface
+    pub fn class(&self) -> (u8, u8, u8) {
+        let class = self.read_config(CLASS_OFFSET);
+        ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8)
+    }
+
+    pub fn pci_class(&self) -> PciClass {
+        let (class, subclass, programming_interface) = self.class();
+        PciClass::new(class, subclass, programming_interface)
+    }
+
+    pub fn exists(&self) -> bool {
+        self.vendor_id() != NO_DEVICE_VENDOR_ID
+    }
+
+    fn is_multi_function_device(&self) -> bool {
+        (self.read_config(HEADER_TYPE_OFFSET) >> 16) as u8 & MULTI_FUNCTION_DEVICE != 0
+    }
+
+    /// How many base address registers the function's header has: bridges have the rest of them
+    /// taken by bus numbers and windows, which mustn't be sized as BARs
+    pub fn base_address_registers(&self) -> u8 {
+        match (self.read_config(HEADER_TYPE_OFFSET) >> 16) as u8 & !MULTI_FUNCTION_DEVICE {
+            HEADER_TYPE_DEVICE => BASE_ADDRESS_REGISTERS,
+            HEADER_TYPE_PCI_BRIDGE => 2,
+            _ => 0,
+        }
+    }
+
+    pub fn command(&self) -> Command {
+        Command::from(self.read_config(COMMAND_OFFSET) as u16)
+    }
+
+    /// Writes the command register, leaving the status register untouched: its bits are cleared
+    /// by writing 1s, so only 0s are written there
+    pub fn set_command(&self, command: Command) {
+        self.write_config(COMMAND_OFFSET, u16::from(command) as u32);
+    }
+
+    /// Lets the function answer to memory accesses to its BARs and do DMA
+    pub fn enable_memory_space_and_bus_mastering(&self) {
+        let mut command = self.command();
+        command.set_flag(CommandFlag::MemorySpace);
+        command.set_flag(CommandFlag::BusMaster);
+        self.set_command(command);
+    }
+
+    /// The address base address register `index` points to, if it's implemented. 64-bit memory
+    /// BARs take up two registers, the second of which shouldn't be read on its own
+    pub fn base_address(&self, index: u8) -> Option<BaseAddress> {
+        if index >= BASE_ADDRESS_REGISTERS {
+            return None;
+        }
+        let offset = BASE_ADDRESS_REGISTER_0_OFFSET + index * 4;
+        let low = self.read_config(offset);
+        if low & 0x1 != 0 {
+            return Some(BaseAddress::Io((low & !0x3) as u16));
+        }
+        let address = match (low >> 1) & 0x3 {
+            0x0 => (low & !0xF) as u64,
+            0x2 if index + 1 < BASE_ADDRESS_REGISTERS => {
+                (self.read_config(offset + 4) as u64) << 32 | (low & !0xF) as u64
+            }
+            _ => return None,
+        };
+        (address != 0).then_some(BaseAddress::Memory(address))
+    }
+
+    /// Whether base address register `index` is the low half of a 64-bit memory BAR
+    pub fn is_64_bit_base_address(&self, index: u8) -> bool {
+        let low = self.read_config(BASE_ADDRESS_REGISTER_0_OFFSET + index * 4);
+        index + 1 < BASE_ADDRESS_REGISTERS && low & 0x1 == 0 && (low >> 1) & 0x3 == 0x2
+    }
+
+    /// Reads the register at `offset` back after writing all 1s to it, then restores it
+    fn size_mask(&self, offset: u8) -> u32 {
+        let value = self.read_config(offset);
+        self.write_config(offset, u32::MAX);
+        let mask = self.read_config(offset);
+        self.write_config(offset, value);
+        mask
+    }
+
+    /// What base address register `index` points to, as in `base_address`, and how many bytes
+    /// of it. The size is found by writing all 1s to the register, with decoding switched off
+    /// meanwhile so that the function doesn't answer at a bogus address
+    pub fn base_address_region(&self, index: u8) -> Option<(BaseAddress, u64)> {
+        let base_address = self.base_address(index)?;
+        let offset = BASE_ADDRESS_REGISTER_0_OFFSET + index * 4;
+        let command = self.command();
+        let mut decoding_off = command;
+        decoding_off.clear_flag(CommandFlag::IoSpace);
+        decoding_off.clear_flag(CommandFlag::MemorySpace);
+        self.set_command(decoding_off);
+        let low_mask = self.size_mask(offset) as u64;
+        let high_mask = if self.is_64_bit_base_address(index) {
+            self.size_mask(offset + 4) as u64
+        } else {
+            // Sizes are powers of 2 up to what the register can hold
+            0xFFFF_FFFF
+        };
+        self.set_command(command);
+
+        let mask = match base_address {
+            // Only the low 16 bits of I/O BARs may be implemented
+            BaseAddress::Io(_) => low_mask & !0x3 | 0xFFFF_FFFF_FFFF_0000,
+            BaseAddress::Memory(_) => high_mask << 32 | low_mask & !0xF,
+        };
+        Some((base_address, (!mask).wrapping_add(1)))
+    }
+
+    pub fn capabilities(&self) -> Capabilities<'_> {
+        let has_capabilities = self.read_config(COMMAND_OFFSET) & STATUS_CAPABILITIES_LIST != 0;
+        Capabilities {
+            function: self,
+            next_offset: if has_capabilities {
+                self.read_config_byte(CAPABILITIES_POINTER_OFFSET)
+            } else {
+                0
+            },
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// The legacy PIC line the function's interrupt pin is routed to, if any
+    pub fn interrupt_line(&self) -> Option<u8> {
+        let interrupt_line = self.read_config(INTERRUPT_LINE_OFFSET) as u8;
+        (interrupt_line < 16).then_some(interrupt_line)
+    }
+
+    /// Brute-force enumerates the buses for the first function `predicate` accepts
+    pub fn find(mut predicate: impl FnMut(&Function) -> bool) -> Option<Function> {
+        for bus in 0..=pci::MAX_BUS_NUMBER as u8 {
+            for device in 0..=pci::MAX_DEVICE_NUMBER {
+                let function = Function::new(bus, device, 0);
+                if !function.exists() {
+                    continue;
+                }
+                if predicate(&function) {
+                    return Some(function);
+                }
+                if !function.is_multi_function_device() {
+                    continue;
+                }
+                for function_number in 1..=pci::MAX_FUNCTION_NUMBER {
+                    let function = Function::new(bus, device, function_number);
+                    if function.exists() && predicate(&function) {
+                        return Some(function);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// A line of lspci -n, with the class named, e.g. `00:1f.2 SATA controller (AHCI): 8086:2922`
+impl Display for Function {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {}: {:04x}:{:04x}",
+            self.bus,
+            self.device,
+            self.function,
+            self.pci_class(),
+            self.vendor_id(),
+            self.device_id()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pci_function::Function;
+
+    #[test]
+    fn config_address() {
+        assert_eq!(0x8000_0810, Function::new(0, 1, 0).config_address(0x10));
+        assert_eq!(
+            0x8012_fb3c,
+            Function::new(0x12, 0x1f, 3).config_address(0x3f)
+        );
+    }
+}
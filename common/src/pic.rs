@@ -0,0 +1,148 @@
+// https://wiki.osdev.org/8259_PIC
+use num_enum::TryFromPrimitive;
+
+use crate::{ioport::Port, make_bitmap};
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_2_DATA: u16 = 0xA1;
+// Unused port, writing to it takes long enough for the PIC to catch up on old hardware
+const WAIT_PORT: u16 = 0x80;
+
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// First vector of the master PIC after remapping, right after the CPU exceptions
+pub const PIC_1_OFFSET: u8 = 32;
+/// First vector of the slave PIC after remapping
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+#[allow(unused)]
+#[derive(TryFromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Irq {
+    Timer = 0,
+    Keyboard = 1,
+    Cascade = 2,
+    Com2 = 3,
+    Com1 = 4,
+    Lpt2 = 5,
+    FloppyDisk = 6,
+    Lpt1 = 7,
+    RealTimeClock = 8,
+    Free1 = 9,
+    Free2 = 10,
+    Free3 = 11,
+    Mouse = 12,
+    Coprocessor = 13,
+    PrimaryAta = 14,
+    SecondaryAta = 15,
+}
+
+impl Irq {
+    /// The interrupt vector the IRQ is delivered on after `remap`
+    pub fn vector(self) -> u8 {
+        PIC_1_OFFSET + self as u8
+    }
+
+    fn is_on_slave(self) -> bool {
+        self as u8 >= 8
+    }
+}
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum InitializationCommandWord1Flag {
+    Icw4Needed = 0x1,
+    SingleMode = 0x2,
+    CallAddressInterval4 = 0x4,
+    LevelTriggeredMode = 0x8,
+    Initialization = 0x10,
+}
+
+make_bitmap!(new_type: InitializationCommandWord1, underlying_flag_type: InitializationCommandWord1Flag, repr: u8, nodisplay);
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum InitializationCommandWord4Flag {
+    Mode8086 = 0x1,
+    AutoEndOfInterrupt = 0x2,
+    BufferedModeMaster = 0x4,
+    BufferedMode = 0x8,
+    SpecialFullyNestedMode = 0x10,
+}
+
+make_bitmap!(new_type: InitializationCommandWord4, underlying_flag_type: InitializationCommandWord4Flag, repr: u8, nodisplay);
+
+fn io_wait() {
+    Port::new(WAIT_PORT).writeb(0);
+}
+
+/// Reinitializes both PICs so that IRQs are delivered starting at the given vectors instead of
+/// overlapping with the CPU exceptions. All IRQs are masked afterwards
+pub fn remap(master_offset: u8, slave_offset: u8) {
+    use InitializationCommandWord1Flag::*;
+
+    let pic_1_command = Port::new(PIC_1_COMMAND);
+    let pic_1_data = Port::new(PIC_1_DATA);
+    let pic_2_command = Port::new(PIC_2_COMMAND);
+    let pic_2_data = Port::new(PIC_2_DATA);
+
+    let icw1 = Initialization | Icw4Needed;
+    pic_1_command.writeb(icw1.into());
+    io_wait();
+    pic_2_command.writeb(icw1.into());
+    io_wait();
+    pic_1_data.writeb(master_offset);
+    io_wait();
+    pic_2_data.writeb(slave_offset);
+    io_wait();
+    // Tell the master there's a slave on IRQ2, and tell the slave its cascade identity
+    pic_1_data.writeb(1 << Irq::Cascade as u8);
+    io_wait();
+    pic_2_data.writeb(Irq::Cascade as u8);
+    io_wait();
+    let icw4 = InitializationCommandWord4::from(InitializationCommandWord4Flag::Mode8086);
+    pic_1_data.writeb(icw4.into());
+    io_wait();
+    pic_2_data.writeb(icw4.into());
+    io_wait();
+
+    pic_1_data.writeb(u8::MAX);
+    pic_2_data.writeb(u8::MAX);
+}
+
+fn data_port(irq: Irq) -> (Port, u8) {
+    if irq.is_on_slave() {
+        (Port::new(PIC_2_DATA), irq as u8 - 8)
+    } else {
+        (Port::new(PIC_1_DATA), irq as u8)
+    }
+}
+
+pub fn mask(irq: Irq) {
+    let (port, line) = data_port(irq);
+    port.writeb(port.readb() | (1 << line));
+}
+
+pub fn unmask(irq: Irq) {
+    let (port, line) = data_port(irq);
+    port.writeb(port.readb() & !(1 << line));
+    if irq.is_on_slave() {
+        unmask(Irq::Cascade);
+    }
+}
+
+/// Masks every IRQ on both PICs, e.g. before switching to the APIC
+pub fn disable() {
+    Port::new(PIC_1_DATA).writeb(u8::MAX);
+    Port::new(PIC_2_DATA).writeb(u8::MAX);
+}
+
+/// Must be sent at the end of every IRQ handler, or the PIC won't deliver that IRQ again
+pub fn end_of_interrupt(irq: Irq) {
+    if irq.is_on_slave() {
+        Port::new(PIC_2_COMMAND).writeb(END_OF_INTERRUPT);
+    }
+    Port::new(PIC_1_COMMAND).writeb(END_OF_INTERRUPT);
+}
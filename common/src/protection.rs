@@ -1,3 +1,4 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum PrivilegeLevel {
     Ring0,
@@ -5,3 +6,15 @@ pub enum PrivilegeLevel {
     Ring2,
     Ring3,
 }
+
+impl PrivilegeLevel {
+    /// The level the two low bits of `bits` encode, the rest is ignored
+    pub const fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => Self::Ring0,
+            1 => Self::Ring1,
+            2 => Self::Ring2,
+            _ => Self::Ring3,
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// The PS/2 controller (8042) the keyboard and the mouse sit behind. Bytes from both devices come
+// through the same data port, the status register tells which one sent them
+// https://wiki.osdev.org/I8042_PS/2_Controller
+use crate::{
+    error::{Context, Error, Facility, Fault},
+    ioport::Port,
+    make_bitmap, timer,
+};
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+// Devices answer within a few ms, except for resets which the drivers don't do
+const TIMEOUT_NS: u64 = 50_000_000;
+
+#[allow(unused)]
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum StatusRegisterFlag {
+    OutputBufferFull = 0x1,
+    InputBufferFull = 0x2,
+    SystemFlag = 0x4,
+    InputIsCommand = 0x8,
+    AuxiliaryOutputBufferFull = 0x20,
+    TimeoutError = 0x40,
+    ParityError = 0x80,
+}
+
+make_bitmap!(new_type: StatusRegisterFlags, underlying_flag_type: StatusRegisterFlag, repr: u8, nodisplay);
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum ConfigurationFlag {
+    KeyboardInterrupt = 0x1,
+    AuxiliaryInterrupt = 0x2,
+    SystemFlag = 0x4,
+    KeyboardClockDisabled = 0x10,
+    AuxiliaryClockDisabled = 0x20,
+    KeyboardTranslation = 0x40,
+}
+
+make_bitmap!(new_type: ConfigurationFlags, underlying_flag_type: ConfigurationFlag, repr: u8, nodisplay);
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum Command {
+    ReadConfiguration = 0x20,
+    WriteConfiguration = 0x60,
+    DisableAuxiliaryDevice = 0xA7,
+    EnableAuxiliaryDevice = 0xA8,
+    WriteToAuxiliaryDevice = 0xD4,
+}
+
+fn io_error(fault: Fault) -> Error {
+    Error::new(fault, Context::Io, Facility::Ps2Controller)
+}
+
+pub fn status() -> StatusRegisterFlags {
+    StatusRegisterFlags::from(Port::new(STATUS_PORT).readb())
+}
+
+/// Whether there's a byte from the keyboard waiting to be read
+pub fn has_keyboard_data() -> bool {
+    let status = status();
+    status.is_set(StatusRegisterFlag::OutputBufferFull)
+        && !status.is_set(StatusRegisterFlag::AuxiliaryOutputBufferFull)
+}
+
+/// Whether there's a byte from the mouse waiting to be read
+pub fn has_auxiliary_data() -> bool {
+    let status = status();
+    status.is_set(StatusRegisterFlag::OutputBufferFull)
+        && status.is_set(StatusRegisterFlag::AuxiliaryOutputBufferFull)
+}
+
+/// Reads the byte in the output buffer, without checking there is one
+pub fn read_data() -> u8 {
+    Port::new(DATA_PORT).readb()
+}
+
+/// Discards whatever the devices sent so far. Their IRQs are edge triggered, so a byte left in the
+/// output buffer would keep them from ever being raised again
+pub fn flush() {
+    while status().is_set(StatusRegisterFlag::OutputBufferFull) {
+        read_data();
+    }
+}
+
+fn wait_for_status(flag: StatusRegisterFlag, set: bool) -> Result<(), Error> {
+    let mut timeout_timer = timer::LowPrecisionTimer::new(TIMEOUT_NS);
+    while status().is_set(flag) != set {
+        timeout_timer.update();
+        if timeout_timer.timeout() {
+            return Err(io_error(Fault::Timeout(TIMEOUT_NS)));
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the next byte from either device. Only meant for before their IRQs are enabled, as
+/// the handlers would race for it otherwise
+pub fn receive() -> Result<u8, Error> {
+    wait_for_status(StatusRegisterFlag::OutputBufferFull, true)?;
+    Ok(read_data())
+}
+
+fn write(port: u16, byte: u8) -> Result<(), Error> {
+    wait_for_status(StatusRegisterFlag::InputBufferFull, false)?;
+    Port::new(port).writeb(byte);
+    Ok(())
+}
+
+pub fn send_command(command: Command) -> Result<(), Error> {
+    write(COMMAND_PORT, command as u8)
+}
+
+pub fn read_configuration() -> Result<ConfigurationFlags, Error> {
+    send_command(Command::ReadConfiguration)?;
+    receive().map(ConfigurationFlags::from)
+}
+
+pub fn write_configuration(configuration: ConfigurationFlags) -> Result<(), Error> {
+    send_command(Command::WriteConfiguration)?;
+    write(DATA_PORT, configuration.into())
+}
+
+/// Sends a byte to the mouse and returns its answer
+pub fn send_to_auxiliary_device(byte: u8) -> Result<u8, Error> {
+    send_command(Command::WriteToAuxiliaryDevice)?;
+    write(DATA_PORT, byte)?;
+    receive()
+}
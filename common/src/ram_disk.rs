@@ -0,0 +1,106 @@
+use crate::{
+    block_device::BlockDevice,
+    error::{Context, Error, Facility, Fault},
+};
+
+pub const SECTOR_SIZE_BYTES: u16 = 512;
+
+/// A block device backed by a buffer in memory, e.g. a static in the kernel or an array in a
+/// host test. Trailing bytes that don't make up a whole sector are left unused
+pub struct RamDisk<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl<'a> RamDisk<'a> {
+    pub fn new(bytes: &'a mut [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.bytes
+    }
+
+    /// The byte range of the disk the sectors `buffer_length` covers from `lba` map to
+    fn byte_range(&self, lba: u64, buffer_length: usize) -> Result<core::ops::Range<usize>, Error> {
+        let sector_size = SECTOR_SIZE_BYTES as usize;
+        if !buffer_length.is_multiple_of(sector_size) {
+            return Err(io_error(Fault::CantReadIntoBuffer(
+                buffer_length as u64,
+                buffer_length.next_multiple_of(sector_size) as u64,
+            )));
+        }
+        let sector_count = (buffer_length / sector_size) as u64;
+        if lba
+            .checked_add(sector_count)
+            .is_none_or(|end| end > self.sectors())
+        {
+            return Err(io_error(Fault::InvalidLBAAddress(lba, self.sectors())));
+        }
+        let start = lba as usize * sector_size;
+        Ok(start..start + buffer_length)
+    }
+}
+
+fn io_error(fault: Fault) -> Error {
+    Error::new(fault, Context::Io, Facility::RamDisk)
+}
+
+impl BlockDevice for RamDisk<'_> {
+    fn sector_size_bytes(&self) -> u16 {
+        SECTOR_SIZE_BYTES
+    }
+
+    fn sectors(&self) -> u64 {
+        (self.bytes.len() / SECTOR_SIZE_BYTES as usize) as u64
+    }
+
+    fn read_sectors(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let range = self.byte_range(lba, buffer.len())?;
+        buffer.copy_from_slice(&self.bytes[range]);
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buffer: &[u8]) -> Result<(), Error> {
+        let range = self.byte_range(lba, buffer.len())?;
+        self.bytes[range].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block_device::BlockDevice,
+        ram_disk::{RamDisk, SECTOR_SIZE_BYTES},
+    };
+
+    const SECTOR: usize = SECTOR_SIZE_BYTES as usize;
+
+    #[test]
+    fn reads_back_written_sectors() {
+        let mut bytes = [0u8; 4 * SECTOR + 100];
+        let mut disk = RamDisk::new(&mut bytes);
+        assert_eq!(4, disk.sectors());
+
+        let written = [0xab; 2 * SECTOR];
+        disk.write_sectors(2, &written).unwrap();
+        let mut read = [0u8; 2 * SECTOR];
+        disk.read_sectors(2, &mut read).unwrap();
+        assert_eq!(written, read);
+        disk.read_sectors(1, &mut read).unwrap();
+        assert_eq!([0; SECTOR], read[..SECTOR]);
+        assert_eq!([0xab; SECTOR], read[SECTOR..]);
+        assert!(disk.bytes()[4 * SECTOR..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_and_partial_sectors() {
+        let mut bytes = [0u8; 4 * SECTOR];
+        let mut disk = RamDisk::new(&mut bytes);
+        let mut buffer = [0u8; 2 * SECTOR];
+        assert!(disk.read_sectors(3, &mut buffer).is_err());
+        assert!(disk.write_sectors(u64::MAX, &buffer).is_err());
+        assert!(disk.read_sectors(0, &mut buffer[..100]).is_err());
+        disk.read_sectors(2, &mut buffer).unwrap();
+    }
+}
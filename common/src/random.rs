@@ -0,0 +1,359 @@
+// Random numbers, from the CPU's hardware generator where there's one: RDSEED, which hands out
+// conditioned entropy straight from the source, then RDRAND, a generator the CPU keeps reseeding
+// from it. CPUs with neither, or with a broken one, get the output of a ChaCha20 generator seeded
+// from the jitter of the timestamp counter instead, which is rekeyed after each use so that what
+// was handed out can't be worked out from its state. Deterministic boots, for QEMU's record and
+// replay, skip both the hardware and the jitter, and get the generator seeded with a fixed seed
+// https://www.intel.com/content/www/us/en/developer/articles/guide/intel-digital-random-number-generator-drng-software-implementation-guide.html
+use core::arch::asm;
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__cpuid, __cpuid_count};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+use crate::{interrupts, make_bitmap};
+
+const FEATURE_INFORMATION: u32 = 0x1;
+const STRUCTURED_EXTENDED_FEATURE_FLAGS: u32 = 0x7;
+// Intel recommends giving up after 10 consecutive failures, which mean the DRNG is broken
+const RDRAND_RETRIES: usize = 10;
+// RDSEED runs out when asked faster than the entropy source fills it, and failing isn't a sign of
+// it being broken, so it gets more tries, with a pause between them
+const RDSEED_RETRIES: usize = 100;
+// How many timestamp counter deltas the fallback generator's seed is folded from. Each one only
+// has a bit or so of entropy
+const JITTER_SAMPLES: usize = 2048;
+// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+const CHACHA_BLOCK_SIZE: usize = 64;
+
+#[allow(unused)]
+#[repr(u32)]
+pub enum RandomFeatureBit {
+    Rdrand = 1 << 30,
+}
+
+make_bitmap!(new_type: RandomFeatures, underlying_flag_type: RandomFeatureBit, repr: u32, nodisplay);
+
+#[allow(unused)]
+#[repr(u32)]
+pub enum StructuredExtendedRandomFeatureBit {
+    Rdseed = 1 << 18,
+}
+
+make_bitmap!(new_type: StructuredExtendedRandomFeatures, underlying_flag_type: StructuredExtendedRandomFeatureBit, repr: u32, nodisplay);
+
+// Only set up the first time the hardware generators can't be used
+static mut FALLBACK_GENERATOR: Option<ChaCha20> = None;
+// Whether everything comes from the fallback generator, with a fixed seed
+static mut DETERMINISTIC: bool = false;
+
+pub fn supports_rdrand() -> bool {
+    // SAFETY: The `__cpuid` instruction is safe to call with the given arguments.
+    let result = unsafe { __cpuid(FEATURE_INFORMATION).ecx };
+
+    RandomFeatures::from(result).is_set(RandomFeatureBit::Rdrand)
+}
+
+pub fn supports_rdseed() -> bool {
+    // SAFETY: The `__cpuid_count` instruction is safe to call with the given arguments.
+    let result = unsafe { __cpuid_count(STRUCTURED_EXTENDED_FEATURE_FLAGS, 0).ebx };
+
+    StructuredExtendedRandomFeatures::from(result)
+        .is_set(StructuredExtendedRandomFeatureBit::Rdseed)
+}
+
+fn rdrand32() -> Option<u32> {
+    for _ in 0..RDRAND_RETRIES {
+        let (value, success): (u32, u8);
+        // SAFETY: RDRAND only writes the destination register and the flags, and callers check
+        // that the CPU supports it
+        unsafe {
+            asm!(
+                "rdrand {value:e}",
+                "setc {success}",
+                value = out(reg) value,
+                success = out(reg_byte) success,
+                options(nomem, nostack),
+            );
+        }
+        if success != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// A random number from the CPU's hardware generator, if it has one that works
+pub fn rdrand() -> Option<u64> {
+    if !supports_rdrand() {
+        return None;
+    }
+    Some((rdrand32()? as u64) << 32 | rdrand32()? as u64)
+}
+
+fn rdseed32() -> Option<u32> {
+    for _ in 0..RDSEED_RETRIES {
+        let (value, success): (u32, u8);
+        // SAFETY: RDSEED only writes the destination register and the flags, and callers check
+        // that the CPU supports it
+        unsafe {
+            asm!(
+                "rdseed {value:e}",
+                "setc {success}",
+                value = out(reg) value,
+                success = out(reg_byte) success,
+                options(nomem, nostack),
+            );
+        }
+        if success != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// A random number from the CPU's entropy source, if it has one that works and isn't drained
+pub fn rdseed() -> Option<u64> {
+    if !supports_rdseed() {
+        return None;
+    }
+    Some((rdseed32()? as u64) << 32 | rdseed32()? as u64)
+}
+
+pub fn read_timestamp_counter() -> u64 {
+    let (low, high): (u32, u32);
+    // SAFETY: Reading the timestamp counter has no side effects
+    unsafe {
+        asm!(
+            "rdtsc",
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Mixes the bits of `value` (splitmix64's finalizer), so that inputs that differ in a few low
+/// bits, like timestamps, give unrelated outputs
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+/// A seed folded from how long the same bit of work takes each time, as measured by the timestamp
+/// counter, which varies with caches, the TLB, SMIs and what the other hardware is up to. How
+/// unpredictable it is comes down to the machine, it's only the last resort
+fn jitter_seed() -> [u32; 8] {
+    let mut lanes = [0u64; 4];
+    let mut scratch = [0u8; 256];
+    let mut previous = read_timestamp_counter();
+    for sample in 0..JITTER_SAMPLES {
+        for (index, byte) in scratch.iter_mut().enumerate().step_by(sample % 7 + 1) {
+            *byte = byte.wrapping_add(index as u8 ^ previous as u8);
+        }
+        core::hint::black_box(&mut scratch);
+        let now = read_timestamp_counter();
+        let lane = &mut lanes[sample % lanes.len()];
+        *lane = mix(lane.rotate_left(7) ^ now.wrapping_sub(previous) ^ now);
+        previous = now;
+    }
+    let mut seed = [0u32; 8];
+    for (words, lane) in seed.chunks_exact_mut(2).zip(lanes) {
+        words[0] = lane as u32;
+        words[1] = (lane >> 32) as u32;
+    }
+    seed
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block for `key`, `counter` and `nonce`, as RFC 8439 serializes it
+/// https://www.rfc-editor.org/rfc/rfc8439#section-2.3
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u8; CHACHA_BLOCK_SIZE] {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(nonce);
+
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut block = [0u8; CHACHA_BLOCK_SIZE];
+    for ((bytes, word), initial_word) in block.chunks_exact_mut(4).zip(state).zip(initial) {
+        bytes.copy_from_slice(&word.wrapping_add(initial_word).to_le_bytes());
+    }
+    block
+}
+
+/// A generator running ChaCha20 in counter mode, which takes the first block of what follows
+/// each output as its next key
+#[derive(Debug, Clone)]
+struct ChaCha20 {
+    key: [u32; 8],
+}
+
+impl ChaCha20 {
+    fn new(seed: [u32; 8]) -> Self {
+        Self { key: seed }
+    }
+
+    fn fill(&mut self, buffer: &mut [u8]) {
+        let nonce = [0; 3];
+        let mut counter = 0;
+        for chunk in buffer.chunks_mut(CHACHA_BLOCK_SIZE) {
+            counter += 1;
+            let block = chacha20_block(&self.key, counter, &nonce);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        let block = chacha20_block(&self.key, 0, &nonce);
+        for (word, bytes) in self.key.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+    }
+}
+
+/// Fills `buffer` from the fallback generator, setting it up first if it's not there yet
+fn fill_from_fallback(buffer: &mut [u8]) {
+    let generator_ptr = &raw mut FALLBACK_GENERATOR;
+    interrupts::without_interrupts(|| {
+        // SAFETY: no threads, and interrupts are disabled so nothing else can get to the generator
+        let generator = unsafe { &mut *generator_ptr };
+        generator
+            .get_or_insert_with(|| ChaCha20::new(jitter_seed()))
+            .fill(buffer);
+    });
+}
+
+/// The key of the fallback generator in deterministic boots: `seed` spread over the 256 bits
+fn fixed_seed(seed: u64) -> [u32; 8] {
+    let mut words = [0u32; 8];
+    let mut value = seed;
+    for pair in words.chunks_exact_mut(2) {
+        value = mix(value.wrapping_add(0x9e37_79b9_7f4a_7c15));
+        pair[0] = value as u32;
+        pair[1] = (value >> 32) as u32;
+    }
+    words
+}
+
+/// Makes every random number from now on come from the fallback generator seeded with `seed`,
+/// the same ones boot after boot, so that a run can be replayed. Not to be undone
+pub fn set_deterministic_no_sync(seed: u64) {
+    let generator_ptr = &raw mut FALLBACK_GENERATOR;
+    let deterministic_ptr = &raw mut DETERMINISTIC;
+    interrupts::without_interrupts(|| {
+        // SAFETY: no threads, and interrupts are disabled so nothing else can get to the generator
+        unsafe { *generator_ptr = Some(ChaCha20::new(fixed_seed(seed))) };
+        // SAFETY: same as above
+        unsafe { *deterministic_ptr = true };
+    });
+}
+
+pub fn is_deterministic() -> bool {
+    let deterministic_ptr = &raw const DETERMINISTIC;
+    // SAFETY: no multitasking, no synchronization needed
+    unsafe { *deterministic_ptr }
+}
+
+/// Fills `buffer` with random bytes, the way getrandom(2) does: from RDSEED or RDRAND, whichever
+/// works, and from the fallback generator once neither does, or in deterministic boots
+pub fn fill(buffer: &mut [u8]) {
+    let hardware = !is_deterministic() && (supports_rdseed() || supports_rdrand());
+    let mut chunks = buffer.chunks_mut(size_of::<u64>());
+    if hardware {
+        for chunk in chunks.by_ref() {
+            let Some(value) = rdseed().or_else(rdrand) else {
+                // Whatever's left of this chunk and the ones after it
+                fill_from_fallback(chunk);
+                break;
+            };
+            chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+        }
+    }
+    for chunk in chunks {
+        fill_from_fallback(chunk);
+    }
+}
+
+/// A random number, as `fill` gets them. The fallback is only as unpredictable as the timing of
+/// the machine, which is good enough to randomize layouts, canaries and protocol identifiers, not
+/// to make keys
+pub fn entropy() -> u64 {
+    let mut bytes = [0u8; size_of::<u64>()];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::random::{ChaCha20, chacha20_block, fixed_seed, mix};
+
+    #[test]
+    fn mix_spreads_close_inputs() {
+        let (a, b) = (mix(1000), mix(1001));
+        assert_ne!(a, b);
+        assert!((a ^ b).count_ones() > 16);
+        assert_eq!(0, mix(0));
+    }
+
+    #[test]
+    fn chacha20_test_vector() {
+        // RFC 8439, 2.3.2
+        let key = core::array::from_fn(|word| {
+            let byte = word as u32 * 4;
+            u32::from_le_bytes([byte as u8, byte as u8 + 1, byte as u8 + 2, byte as u8 + 3])
+        });
+        let block = chacha20_block(&key, 1, &[0x0900_0000, 0x4a00_0000, 0]);
+        assert_eq!([0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15], block[..8]);
+        assert_eq!(
+            [0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50, 0x3c, 0x4e],
+            block[56..]
+        );
+    }
+
+    #[test]
+    fn fallback_generator_rekeys() {
+        let mut generator = ChaCha20::new([7; 8]);
+        let (mut first, mut second) = ([0u8; 100], [0u8; 100]);
+        generator.fill(&mut first);
+        generator.fill(&mut second);
+        assert_ne!(first, second);
+        // Each block of an output is different
+        assert_ne!(first[..36], first[64..]);
+        // The same seed gives the same output
+        let mut again = [0u8; 100];
+        ChaCha20::new([7; 8]).fill(&mut again);
+        assert_eq!(first, again);
+    }
+
+    #[test]
+    fn fixed_seeds() {
+        assert_eq!(fixed_seed(1), fixed_seed(1));
+        assert_ne!(fixed_seed(1), fixed_seed(2));
+        // Even a zero seed gives a key with bits set all over
+        assert!(fixed_seed(0).iter().all(|word| *word != 0));
+    }
+}
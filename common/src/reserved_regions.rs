@@ -0,0 +1,226 @@
+// What the kernel must never hand out as free memory: what the firmware keeps for itself, the
+// legacy regions of the first MiB, device memory behind PCI BARs, and what the bootloader left
+// behind for the kernel (the kernel image, its payloads, stage2's statics). The frame allocator
+// and the heap set themselves up around it
+use core::{fmt::Display, ops::Range};
+
+use crate::{
+    boot_info::BootInfo,
+    e820,
+    error::Fault,
+    layout,
+    pci_function::{BaseAddress, Function},
+};
+
+/// Enough for a BIOS memory map, the legacy regions, the boot artifacts and a few PCI devices
+pub const MAX_RESERVED_REGIONS: usize = 128;
+
+/// The real mode IVT and BIOS data area, the extended BIOS data area, VGA memory and the ROMs.
+/// The EBDA is usually right under 0xA0000, but at most 128KB
+const LEGACY_REGIONS: [Range<u64>; 2] = [0x0..0x500, 0x8_0000..0x10_0000];
+// stage2's statics: the page tables, GDT and TSS the kernel keeps running on
+const STAGE2: Range<u64> = layout::STAGE2_BASE..layout::STAGE2_STACK.start;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Not usable RAM according to the BIOS memory map
+    Firmware,
+    Legacy,
+    Mmio,
+    Kernel,
+    /// A payload, or the statics of the bootloader
+    BootArtifact,
+}
+
+impl Display for RegionKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            RegionKind::Firmware => "firmware",
+            RegionKind::Legacy => "legacy",
+            RegionKind::Mmio => "MMIO",
+            RegionKind::Kernel => "kernel",
+            RegionKind::BootArtifact => "boot artifact",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservedRegion {
+    pub range: Range<u64>,
+    pub kind: RegionKind,
+}
+
+impl Display for ReservedRegion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:#014x}-{:#014x} {}",
+            self.range.start, self.range.end, self.kind
+        )
+    }
+}
+
+/// A fixed-capacity set of reserved physical address ranges, which may overlap
+pub struct ReservedRegions<const N: usize> {
+    regions: [ReservedRegion; N],
+    count: usize,
+}
+
+impl<const N: usize> ReservedRegions<N> {
+    pub const fn new() -> Self {
+        Self {
+            regions: [const {
+                ReservedRegion {
+                    range: 0..0,
+                    kind: RegionKind::Legacy,
+                }
+            }; N],
+            count: 0,
+        }
+    }
+
+    /// The regions the bootloader knows about: the unusable parts of the memory map, the legacy
+    /// regions, stage2's statics, the kernel image and the payloads
+    pub fn from_boot_info(boot_info: &BootInfo) -> Result<Self, Fault> {
+        let mut regions = Self::new();
+        for entry in boot_info.memory_map() {
+            if entry.is_valid() && !entry.is_usable() {
+                regions.reserve(entry.range(), RegionKind::Firmware)?;
+            }
+        }
+        for legacy_region in LEGACY_REGIONS {
+            regions.reserve(legacy_region, RegionKind::Legacy)?;
+        }
+        regions.reserve(STAGE2, RegionKind::BootArtifact)?;
+        regions.reserve(
+            boot_info.kernel_start..boot_info.kernel_end,
+            RegionKind::Kernel,
+        )?;
+        for payload in boot_info.payloads() {
+            regions.reserve(
+                payload.address..payload.address + payload.size,
+                RegionKind::BootArtifact,
+            )?;
+        }
+        Ok(regions)
+    }
+
+    /// Adds a region, unless it's empty
+    pub fn reserve(&mut self, range: Range<u64>, kind: RegionKind) -> Result<(), Fault> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        let Some(slot) = self.regions.get_mut(self.count) else {
+            return Err(Fault::TooManyReservedRegions);
+        };
+        *slot = ReservedRegion { range, kind };
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Reserves what the memory BARs of `function` point to
+    pub fn reserve_base_addresses(&mut self, function: &Function) -> Result<(), Fault> {
+        let registers = function.base_address_registers();
+        let mut index = 0;
+        while index < registers {
+            if let Some((BaseAddress::Memory(address), size)) = function.base_address_region(index)
+            {
+                self.reserve(address..address.saturating_add(size), RegionKind::Mmio)?;
+            }
+            // The high half of a 64-bit BAR isn't one of its own
+            index += if function.is_64_bit_base_address(index) {
+                2
+            } else {
+                1
+            };
+        }
+        Ok(())
+    }
+
+    pub fn regions(&self) -> &[ReservedRegion] {
+        &self.regions[..self.count]
+    }
+
+    /// The first region `range` overlaps, if any
+    pub fn overlapping(&self, range: &Range<u64>) -> Option<&ReservedRegion> {
+        self.regions().iter().find(|region| {
+            !range.is_empty() && region.range.start < range.end && range.start < region.range.end
+        })
+    }
+}
+
+impl<const N: usize> Default for ReservedRegions<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `range` is RAM that's free to hand out: not reserved, and usable according to the
+/// memory map, if the BIOS reported one
+pub fn is_available<const N: usize>(
+    regions: &ReservedRegions<N>,
+    memory_map: &[e820::Entry],
+    range: &Range<u64>,
+) -> bool {
+    regions.overlapping(range).is_none()
+        && (memory_map.is_empty() || e820::is_usable(memory_map, range))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        boot_info::{BootInfo, LoadedPayload},
+        e820::{self, MemoryKind},
+        error::Fault,
+        payload::PayloadKind,
+        reserved_regions::{RegionKind, ReservedRegions, is_available},
+    };
+
+    #[test]
+    fn from_boot_info() {
+        let mut boot_info = BootInfo::empty();
+        boot_info.kernel_start = 0x20_0000;
+        boot_info.kernel_end = 0x24_0000;
+        boot_info.add_memory_map_entry(e820::Entry::new(MemoryKind::Usable, 0x0..0x9_FC00));
+        boot_info.add_memory_map_entry(e820::Entry::new(MemoryKind::Reserved, 0xF_0000..0x10_0000));
+        boot_info.add_memory_map_entry(e820::Entry::new(MemoryKind::Usable, 0x10_0000..0x800_0000));
+        boot_info.add_payload(LoadedPayload::new(PayloadKind::Initrd, 0x30_0000, 0x1800));
+        let regions = ReservedRegions::<16>::from_boot_info(&boot_info).unwrap();
+        let memory_map = boot_info.memory_map();
+
+        let kind_at = |address: u64| {
+            regions
+                .overlapping(&(address..address + 1))
+                .map(|region| region.kind)
+        };
+        assert_eq!(Some(RegionKind::Firmware), kind_at(0xF_0000));
+        assert_eq!(Some(RegionKind::Legacy), kind_at(0xB_8000));
+        assert_eq!(Some(RegionKind::Legacy), kind_at(0x400));
+        assert_eq!(Some(RegionKind::BootArtifact), kind_at(0x6_0000));
+        assert_eq!(Some(RegionKind::Kernel), kind_at(0x23_FFFF));
+        assert_eq!(Some(RegionKind::BootArtifact), kind_at(0x30_1000));
+        assert_eq!(None, kind_at(0x30_2000));
+
+        assert!(is_available(&regions, memory_map, &(0x1000..0x5_0000)));
+        assert!(is_available(&regions, memory_map, &(0x24_0000..0x30_0000)));
+        assert!(!is_available(&regions, memory_map, &(0x23_F000..0x24_1000)));
+        // Not in the memory map
+        assert!(!is_available(
+            &regions,
+            memory_map,
+            &(0x800_0000..0x800_1000)
+        ));
+    }
+
+    #[test]
+    fn capacity() {
+        let mut regions = ReservedRegions::<1>::new();
+        regions.reserve(0x1000..0x1000, RegionKind::Mmio).unwrap();
+        regions.reserve(0x1000..0x2000, RegionKind::Mmio).unwrap();
+        assert!(matches!(
+            regions.reserve(0x3000..0x4000, RegionKind::Mmio),
+            Err(Fault::TooManyReservedRegions)
+        ));
+        assert_eq!(1, regions.regions().len());
+    }
+}
@@ -0,0 +1,125 @@
+/// Fixed-capacity FIFO queue, usable in statics and without an allocator
+#[derive(Debug, Clone)]
+pub struct RingBuffer<T: Copy, const N: usize> {
+    items: [T; N],
+    head: usize,
+    length: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    /// `filler` is only used to initialize the backing storage, it's never returned by `pop`
+    pub const fn new(filler: T) -> Self {
+        Self {
+            items: [filler; N],
+            head: 0,
+            length: 0,
+        }
+    }
+
+    /// Appends an item to the back of the queue, handing it back if the queue is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        self.items[(self.head + self.length) % N] = item;
+        self.length += 1;
+        Ok(())
+    }
+
+    /// Appends an item to the back of the queue, making room for it by dropping the item at the
+    /// front if the queue is full. Returns the dropped item
+    pub fn push_overwriting(&mut self, item: T) -> Option<T> {
+        let dropped = if self.is_full() { self.pop() } else { None };
+        let _ = self.push(item);
+        dropped
+    }
+
+    /// Removes the item at the front of the queue
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let item = self.items[self.head];
+        self.head = (self.head + 1) % N;
+        self.length -= 1;
+        Some(item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.length == N
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// The items from front to back, as the part up to the end of the storage and the part that
+    /// wrapped around to its start
+    pub fn as_slices(&self) -> (&[T], &[T]) {
+        let end = self.head + self.length;
+        if end <= N {
+            (&self.items[self.head..end], &[])
+        } else {
+            (&self.items[self.head..], &self.items[..end - N])
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.length = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ring_buffer::RingBuffer;
+
+    #[test]
+    fn fifo_order_and_wraparound() {
+        let mut ring_buffer = RingBuffer::<u8, 3>::new(0);
+        assert!(ring_buffer.is_empty());
+        assert_eq!(None, ring_buffer.pop());
+
+        ring_buffer.push(1).unwrap();
+        ring_buffer.push(2).unwrap();
+        ring_buffer.push(3).unwrap();
+        assert!(ring_buffer.is_full());
+        assert_eq!(Err(4), ring_buffer.push(4));
+
+        assert_eq!(Some(1), ring_buffer.pop());
+        ring_buffer.push(4).unwrap();
+        assert_eq!(3, ring_buffer.len());
+
+        assert_eq!(Some(2), ring_buffer.pop());
+        assert_eq!(Some(3), ring_buffer.pop());
+        assert_eq!(Some(4), ring_buffer.pop());
+        assert_eq!(None, ring_buffer.pop());
+
+        ring_buffer.push(5).unwrap();
+        ring_buffer.clear();
+        assert!(ring_buffer.is_empty());
+    }
+
+    #[test]
+    fn overwriting_and_slices() {
+        let mut ring_buffer = RingBuffer::<u8, 3>::new(0);
+        assert_eq!((&[][..], &[][..]), ring_buffer.as_slices());
+        for item in 1..=3 {
+            assert_eq!(None, ring_buffer.push_overwriting(item));
+        }
+        assert_eq!((&[1, 2, 3][..], &[][..]), ring_buffer.as_slices());
+        assert_eq!(Some(1), ring_buffer.push_overwriting(4));
+        assert_eq!(Some(2), ring_buffer.push_overwriting(5));
+        assert_eq!((&[3][..], &[4, 5][..]), ring_buffer.as_slices());
+        ring_buffer.clear();
+        assert!(ring_buffer.is_empty());
+    }
+}
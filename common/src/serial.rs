@@ -1,12 +1,52 @@
 use core::arch::asm;
 
-use crate::{ioport::Port, make_bitmap};
+use crate::{
+    interrupts,
+    ioport::{IoPorts, Port, PortIo, PortOf},
+    make_bitmap,
+    ring_buffer::RingBuffer,
+    wait_queue::WaitQueue,
+};
 
-const COM1: u16 = 0x3F8;
+/// The I/O port bases of the standard PC serial ports. COM1 and COM3 share IRQ4, COM2 and COM4
+/// share IRQ3
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+pub const COM3: u16 = 0x3E8;
+pub const COM4: u16 = 0x2E8;
+const PRESETS: [(&str, u16); 4] = [
+    ("com1", COM1),
+    ("com2", COM2),
+    ("com3", COM3),
+    ("com4", COM4),
+];
+/// What the divisor latch divides to get the baud rate
+const UART_CLOCK_HZ: u32 = 115_200;
+const RECEIVE_BUFFER_SIZE: usize = 256;
+const TRANSMIT_BUFFER_SIZE: usize = 4096;
+/// How many bytes the transmit FIFO of a 16550A holds
+const FIFO_SIZE: usize = 16;
+// Both set in the interrupt identification register when the FIFOs are enabled and work. The
+// 16550 without the A only sets the top one, its FIFOs are broken
+const FIFOS_ENABLED: u8 = 0b1100_0000;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
 
 pub struct Com1;
 
 static mut COM1_INITIALIZED: bool = false;
+static mut COM1_RECEIVE_BUFFER: RingBuffer<u8, RECEIVE_BUFFER_SIZE> = RingBuffer::new(0);
+// Woken whenever the IRQ4 handler receives a byte
+static mut COM1_WAIT_QUEUE: WaitQueue = WaitQueue::new();
+static mut COM1_TRANSMITTER: Transmitter = Transmitter {
+    buffer: RingBuffer::new(0),
+    batch: 1,
+    interrupt_driven: false,
+    draining: false,
+};
+// Where `writeln_no_sync` logs go, COM1 unless `set_log_port_no_sync` picked another port
+static mut LOG_PORT: Option<SerialPort> = None;
 
 #[allow(unused)]
 #[repr(u8)]
@@ -47,6 +87,8 @@ pub enum FifoControlRegisterFlag {
     InterruptTriggerLevel2 = 1 << 7,
 }
 
+make_bitmap!(new_type: FifoControlRegisterFlags, underlying_flag_type: FifoControlRegisterFlag, repr: u8, nodisplay);
+
 #[allow(unused)]
 #[derive(Clone, Copy)]
 #[repr(u8)]
@@ -76,6 +118,122 @@ pub enum LineStatusRegisterFlag {
 
 make_bitmap!(new_type: LineStatusRegisterFlags, underlying_flag_type: LineStatusRegisterFlag, repr: u8, nodisplay);
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Parity {
+    #[default]
+    None,
+    Odd,
+    Even,
+    /// The parity bit is always 1
+    Mark,
+    /// The parity bit is always 0
+    Space,
+}
+
+/// How a serial port frames what it sends. Always 8 data bits and one stop bit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineSettings {
+    pub baud_rate: u32,
+    pub parity: Parity,
+}
+
+impl Default for LineSettings {
+    /// 38400 8N1, what the ports were always set up with
+    fn default() -> Self {
+        Self {
+            baud_rate: 38_400,
+            parity: Parity::None,
+        }
+    }
+}
+
+impl LineSettings {
+    /// The divisor latch value for the baud rate. None if the UART can't do that rate exactly
+    pub fn divisor(&self) -> Option<u16> {
+        if self.baud_rate == 0 || !UART_CLOCK_HZ.is_multiple_of(self.baud_rate) {
+            return None;
+        }
+        u16::try_from(UART_CLOCK_HZ / self.baud_rate).ok()
+    }
+
+    fn line_control(&self) -> LineControlRegisterFlags {
+        use LineControlRegisterFlag::*;
+
+        let data_bits = DataBits1 | DataBits2;
+        match self.parity {
+            Parity::None => data_bits,
+            Parity::Odd => data_bits | ParityBits1,
+            Parity::Even => data_bits | ParityBits1 | ParityBits2,
+            Parity::Mark => data_bits | ParityBits1 | ParityBits3,
+            Parity::Space => data_bits | ParityBits1 | ParityBits2 | ParityBits3,
+        }
+    }
+}
+
+/// The base of the port a preset names, like "com2", or of a port given by its base in hex, like
+/// "0x2f8"
+pub fn port_base(name: &str) -> Option<u16> {
+    if let Some(hex) = name.strip_prefix("0x") {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+    PRESETS
+        .iter()
+        .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+        .map(|(_, base)| *base)
+}
+
+/// Parses a console spec in the style of Linux's `console=ttyS1,9600n8`: a port as `port_base`
+/// takes it, optionally followed by a comma, the baud rate, the parity as n, o, e, m or s, and the
+/// data bits, which can only be 8. E.g. "com2", "com3,115200" or "0x2e8,9600e8"
+pub fn parse_console(spec: &str) -> Option<(u16, LineSettings)> {
+    let (name, options) = spec.split_once(',').unwrap_or((spec, ""));
+    let base = port_base(name)?;
+    let mut settings = LineSettings::default();
+    if options.is_empty() {
+        return Some((base, settings));
+    }
+    let digits = options
+        .find(|character: char| !character.is_ascii_digit())
+        .unwrap_or(options.len());
+    let (baud_rate, framing) = options.split_at(digits);
+    settings.baud_rate = baud_rate.parse().ok()?;
+    let mut framing = framing.chars();
+    settings.parity = match framing.next() {
+        None | Some('n') => Parity::None,
+        Some('o') => Parity::Odd,
+        Some('e') => Parity::Even,
+        Some('m') => Parity::Mark,
+        Some('s') => Parity::Space,
+        Some(_) => return None,
+    };
+    match (framing.next(), framing.next()) {
+        (None | Some('8'), None) => {}
+        _ => return None,
+    }
+    settings.divisor()?;
+    Some((base, settings))
+}
+
+/// What COM1 is yet to send
+struct Transmitter {
+    buffer: RingBuffer<u8, TRANSMIT_BUFFER_SIZE>,
+    /// How many bytes the UART takes at once when its transmitter is empty: the FIFO size
+    batch: usize,
+    /// Whether the buffer is drained from the UART interrupt, see `enable_transmit_interrupts`
+    interrupt_driven: bool,
+    /// Whether the UART raises an interrupt when its transmitter is empty, to be refilled
+    draining: bool,
+}
+
+/// Runs `f` on COM1's transmitter, with interrupts disabled
+fn with_transmitter<R>(f: impl FnOnce(&mut Transmitter) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let transmitter_ptr = &raw mut COM1_TRANSMITTER;
+        // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+        f(unsafe { &mut *transmitter_ptr })
+    })
+}
+
 impl Com1 {
     /// # Panics
     /// Uses Self::initialize under the hood, which may panic under certain conditions
@@ -95,17 +253,6 @@ impl Com1 {
         Port::new(COM1 + 1)
     }
 
-    fn line_control_register() -> Port {
-        Port::new(COM1 + 3)
-    }
-
-    fn divisor_register_low() -> Port {
-        Port::new(COM1)
-    }
-    fn divisor_register_high() -> Port {
-        Port::new(COM1 + 1)
-    }
-
     fn modem_control_register() -> Port {
         Port::new(COM1 + 4)
     }
@@ -125,24 +272,11 @@ impl Com1 {
     /// Panics if COM1 doesn't exist or doesn't echo back its written char during loopback test
     /// TODO: Should we make it fallibe with Result instead?
     pub fn initialize() {
-        // https://wiki.osdev.org/Serial_Ports#Initialization
-
-        use LineControlRegisterFlag::*;
-        use ModemControlRegisterFlag::*;
-
-        Self::interrupt_enable_register().writeb(InterruptEnableFlags::empty().into());
-        Self::line_control_register().writeb(DivisorLatchAcccessBit as u8);
-        Self::divisor_register_low().writeb(3);
-        Self::divisor_register_high().writeb(0);
-        // 8 bits, one stop bit, no parity
-        Self::line_control_register().writeb((DataBits1 | DataBits2).into());
-        Self::modem_control_register().writeb((Loopback | Out1 | Out2 | RequestToSend).into());
-        let test_byte = 0xae;
-        Self::transmit_register().writeb(test_byte);
-        if Self::receive_register().readb() != test_byte {
+        let Some(port) = SerialPort::new(COM1) else {
             panic!("COM1 initialization");
-        }
-        Self::modem_control_register().writeb(ModemControlRegisterFlags::empty().into());
+        };
+        let batch = port.enable_fifos();
+        with_transmitter(|transmitter| transmitter.batch = batch);
 
         // SAFETY: no multitasking, no problem
         unsafe { COM1_INITIALIZED = true }
@@ -157,26 +291,373 @@ impl Com1 {
     }
 
     fn send_byte(byte: u8) {
-        loop {
-            if Self::is_transmit_empty() {
+        Self::send_bytes(&[byte]);
+    }
+
+    /// Queues `bytes` for the interrupt handler to send if transmit interrupts are on, sends them
+    /// right away otherwise
+    fn send_bytes(bytes: &[u8]) {
+        let interrupts_enabled = interrupts::are_enabled();
+        with_transmitter(|transmitter| {
+            if !(transmitter.interrupt_driven && interrupts_enabled) {
+                // Nothing would drain the buffer for now, e.g. in an exception handler
+                Self::flush_transmitter(transmitter);
+                for chunk in bytes.chunks(transmitter.batch) {
+                    Self::send_batch_polled(chunk);
+                }
+                return;
+            }
+            for byte in bytes {
+                if transmitter.buffer.is_full() {
+                    // No room until the handler runs, which it can't with interrupts disabled
+                    let batch = transmitter.batch;
+                    Self::send_batch_polled_from(transmitter, batch);
+                }
+                let _ = transmitter.buffer.push(*byte);
+            }
+            if !transmitter.draining {
+                transmitter.draining = true;
+                Self::set_transmitter_empty_interrupt(true);
+            }
+        });
+    }
+
+    /// Waits for the transmitter to be empty, then fills it with `batch`
+    fn send_batch_polled(batch: &[u8]) {
+        while !Self::is_transmit_empty() {
+            core::hint::spin_loop();
+        }
+        for byte in batch {
+            Self::transmit_register().writeb(*byte);
+        }
+    }
+
+    /// Like `send_batch_polled`, with up to `count` bytes from the transmitter's buffer
+    fn send_batch_polled_from(transmitter: &mut Transmitter, count: usize) {
+        while !Self::is_transmit_empty() {
+            core::hint::spin_loop();
+        }
+        for _ in 0..count {
+            let Some(byte) = transmitter.buffer.pop() else {
                 break;
+            };
+            Self::transmit_register().writeb(byte);
+        }
+    }
+
+    fn flush_transmitter(transmitter: &mut Transmitter) {
+        while !transmitter.buffer.is_empty() {
+            let batch = transmitter.batch;
+            Self::send_batch_polled_from(transmitter, batch);
+        }
+        if transmitter.draining {
+            transmitter.draining = false;
+            Self::set_transmitter_empty_interrupt(false);
+        }
+    }
+
+    fn set_transmitter_empty_interrupt(enabled: bool) {
+        let flag = InterruptEnableFlag::TransmitterHoldingRegisterEmpty as u8;
+        let interrupt_enable = Self::interrupt_enable_register().readb();
+        Self::interrupt_enable_register().writeb(if enabled {
+            interrupt_enable | flag
+        } else {
+            interrupt_enable & !flag
+        });
+    }
+
+    /// Sends what's still buffered, waiting for it to be out, e.g. before shutting down
+    pub fn flush(&mut self) {
+        with_transmitter(Self::flush_transmitter);
+    }
+
+    fn is_data_ready() -> bool {
+        use LineStatusRegisterFlag::*;
+        (LineStatusRegisterFlags {
+            bits: Self::line_status_register().readb(),
+        })
+        .is_set(DataReady)
+    }
+
+    /// Makes the UART raise IRQ4 whenever a byte is received. The IRQ handler is expected to call
+    /// `handle_interrupt_no_sync` to move the received bytes into the receive buffer
+    pub fn enable_receive_interrupts(&mut self) {
+        use ModemControlRegisterFlag::*;
+
+        let interrupt_enable = Self::interrupt_enable_register().readb();
+        Self::interrupt_enable_register()
+            .writeb(interrupt_enable | InterruptEnableFlag::ReceivedDataAvailable as u8);
+        // OUT2 gates the UART interrupt line on PC compatibles
+        Self::modem_control_register().writeb((DataTerminalReady | RequestToSend | Out2).into());
+    }
+
+    /// Makes writes only queue bytes, which the IRQ4 handler sends a FIFO's worth at a time as the
+    /// UART runs out of them, instead of spinning on the UART for every byte. Writes with
+    /// interrupts disabled are still sent right away, after what's queued. Needs the interrupt
+    /// line enabled by `enable_receive_interrupts`
+    pub fn enable_transmit_interrupts(&mut self) {
+        with_transmitter(|transmitter| transmitter.interrupt_driven = true);
+    }
+
+    /// Drains the UART into the receive buffer, and refills it from the transmit buffer if it's
+    /// done sending. Bytes received while the receive buffer is full are dropped
+    pub fn handle_interrupt_no_sync() {
+        let receive_buffer_ptr = &raw mut COM1_RECEIVE_BUFFER;
+        // SAFETY: no threads, and the buffer is only accessed with interrupts disabled outside of
+        // the interrupt handler
+        let receive_buffer = unsafe { &mut *receive_buffer_ptr };
+        let wait_queue_ptr = &raw mut COM1_WAIT_QUEUE;
+        // SAFETY: same as above
+        let wait_queue = unsafe { &mut *wait_queue_ptr };
+        while Self::is_data_ready() {
+            let _ = receive_buffer.push(Self::receive_register().readb());
+            wait_queue.wake();
+        }
+
+        with_transmitter(|transmitter| {
+            if !transmitter.draining || !Self::is_transmit_empty() {
+                return;
+            }
+            let batch = transmitter.batch;
+            Self::send_batch_polled_from(transmitter, batch);
+            if transmitter.buffer.is_empty() {
+                transmitter.draining = false;
+                Self::set_transmitter_empty_interrupt(false);
+            }
+        });
+    }
+
+    /// Returns the next received byte, if any, without blocking. Works both with and without
+    /// receive interrupts enabled
+    pub fn read_byte(&mut self) -> Option<u8> {
+        interrupts::without_interrupts(|| {
+            let receive_buffer_ptr = &raw mut COM1_RECEIVE_BUFFER;
+            // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+            let receive_buffer = unsafe { &mut *receive_buffer_ptr };
+            receive_buffer.pop()
+        })
+        .or_else(|| Self::is_data_ready().then(|| Self::receive_register().readb()))
+    }
+
+    /// Sends raw bytes, which unlike `write_str` needn't be UTF-8
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        Self::send_bytes(bytes);
+    }
+
+    /// Whether a byte was received since the last call, for `wait_queue::wait_until` to wait for
+    /// one with. Only bytes received with receive interrupts enabled count
+    pub fn take_wakeup() -> bool {
+        interrupts::without_interrupts(|| {
+            let wait_queue_ptr = &raw mut COM1_WAIT_QUEUE;
+            // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+            unsafe { (*wait_queue_ptr).take_wakeup() }
+        })
+    }
+
+    /// Blocks until a full line was received, echoing it back and handling backspace. Control
+    /// characters and non-ASCII bytes from the UART are dropped, and characters that don't fit in
+    /// `buffer` are discarded. `idle` runs whenever there's nothing to read, e.g. to poll other
+    /// devices or to sleep until there's more input, and can return characters typed on other
+    /// inputs, like the keyboard
+    pub fn read_line<'a>(
+        &mut self,
+        buffer: &'a mut [u8],
+        mut idle: impl FnMut() -> Option<char>,
+    ) -> &'a str {
+        let mut length = 0;
+        loop {
+            let received = self.read_byte().filter(u8::is_ascii).map(char::from);
+            let Some(character) = received.or_else(&mut idle) else {
+                core::hint::spin_loop();
+                continue;
+            };
+            match character {
+                '\r' | '\n' => {
+                    Self::send_byte(b'\r');
+                    Self::send_byte(b'\n');
+                    break;
+                }
+                character
+                    if (character == BACKSPACE.into() || character == DELETE.into())
+                        && length > 0 =>
+                {
+                    // The last character can take more than one byte
+                    length -= core::str::from_utf8(&buffer[..length])
+                        .ok()
+                        .and_then(|line| line.chars().next_back())
+                        .map_or(1, char::len_utf8);
+                    for byte in [BACKSPACE, b' ', BACKSPACE] {
+                        Self::send_byte(byte);
+                    }
+                }
+                character
+                    if !character.is_control() && length + character.len_utf8() <= buffer.len() =>
+                {
+                    let encoded = character.encode_utf8(&mut buffer[length..]);
+                    for byte in encoded.bytes() {
+                        Self::send_byte(byte);
+                    }
+                    length += encoded.len();
+                }
+                _ => {}
             }
         }
-        Self::transmit_register().writeb(byte);
+        // Only whole characters made it into the buffer, so it's valid UTF-8
+        core::str::from_utf8(&buffer[..length]).unwrap_or_default()
     }
 }
 
 impl core::fmt::Write for Com1 {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        Self::send_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// A serial port driven without interrupts nor buffering, for code that runs with interrupts
+/// disabled, like the GDB stub, and for logs. Driven through the ports of `P`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialPort<P: PortIo = IoPorts> {
+    ports: P,
+    base: u16,
+}
+
+impl SerialPort {
+    /// Sets the port at `base` up with the default line settings, without interrupts. None if it
+    /// doesn't pass the loopback test, e.g. because there's no port there
+    pub fn new(base: u16) -> Option<Self> {
+        Self::with_settings(base, LineSettings::default())
+    }
+
+    /// Like `new`, but framing with `settings`. None if the baud rate isn't one the UART can do
+    pub fn with_settings(base: u16, settings: LineSettings) -> Option<Self> {
+        Self::with_ports(IoPorts, base, settings)
+    }
+
+    /// The port at `base` as it is, without setting it up again, e.g. to write to it from where
+    /// the state of the other writers can't be trusted
+    pub fn unchecked(base: u16) -> Self {
+        Self {
+            ports: IoPorts,
+            base,
+        }
+    }
+}
+
+impl<P: PortIo> SerialPort<P> {
+    /// Like `with_settings`, through `ports`
+    pub fn with_ports(ports: P, base: u16, settings: LineSettings) -> Option<Self> {
+        // https://wiki.osdev.org/Serial_Ports#Initialization
+
+        use ModemControlRegisterFlag::*;
+
+        let divisor = settings.divisor()?;
+        let port = Self { ports, base };
+        port.register(1)
+            .writeb(InterruptEnableFlags::empty().into());
+        port.register(3)
+            .writeb(LineControlRegisterFlag::DivisorLatchAcccessBit as u8);
+        port.register(0).writeb(divisor as u8);
+        port.register(1).writeb((divisor >> 8) as u8);
+        port.register(3).writeb(settings.line_control().into());
+        port.register(4)
+            .writeb((Loopback | Out1 | Out2 | RequestToSend).into());
+        let test_byte = 0xae;
+        port.register(0).writeb(test_byte);
+        if port.register(0).readb() != test_byte {
+            return None;
+        }
+        port.register(4)
+            .writeb((DataTerminalReady | RequestToSend).into());
+        Some(port)
+    }
+
+    /// Enables and clears the FIFOs if the UART is a 16550A, and returns how many bytes its
+    /// transmitter takes at once: a FIFO's worth, or just one on older UARTs
+    pub fn enable_fifos(&self) -> usize {
+        use FifoControlRegisterFlag::*;
+
+        self.register(2).writeb(
+            (EnableFifo
+                | ClearReceiveFifo
+                | ClearTransmitFifo
+                | InterruptTriggerLevel1
+                | InterruptTriggerLevel2)
+                .into(),
+        );
+        if self.register(2).readb() & FIFOS_ENABLED == FIFOS_ENABLED {
+            FIFO_SIZE
+        } else {
+            self.register(2)
+                .writeb(FifoControlRegisterFlags::empty().into());
+            1
+        }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    fn register(&self, offset: u16) -> PortOf<'_, P> {
+        self.ports.port(self.base + offset)
+    }
+
+    fn line_status(&self) -> LineStatusRegisterFlags {
+        LineStatusRegisterFlags {
+            bits: self.register(5).readb(),
+        }
+    }
+
+    pub fn send_byte(&self, byte: u8) {
+        while !self
+            .line_status()
+            .is_set(LineStatusRegisterFlag::TransmitterHoldingRegisterEmpty)
+        {
+            core::hint::spin_loop();
+        }
+        self.register(0).writeb(byte);
+    }
+
+    pub fn read_byte(&self) -> Option<u8> {
+        self.line_status()
+            .is_set(LineStatusRegisterFlag::DataReady)
+            .then(|| self.register(0).readb())
+    }
+
+    pub fn read_byte_blocking(&self) -> u8 {
+        loop {
+            if let Some(byte) = self.read_byte() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<P: PortIo> core::fmt::Write for SerialPort<P> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         for byte in s.bytes() {
-            Self::send_byte(byte);
+            self.send_byte(byte);
         }
         Ok(())
     }
 }
 
+/// Sends what `writeln_no_sync` logs to `port` from now on, e.g. because COM1 isn't wired up
+pub fn set_log_port_no_sync(port: SerialPort) {
+    let log_port_ptr = &raw mut LOG_PORT;
+    // SAFETY: no threads, and logging doesn't happen from interrupt handlers
+    unsafe { *log_port_ptr = Some(port) };
+}
+
 pub fn __writeln_no_sync(args: core::fmt::Arguments) -> core::fmt::Result {
     use core::fmt::Write;
+    let log_port_ptr = &raw const LOG_PORT;
+    // SAFETY: no threads, and it's only written by `set_log_port_no_sync`
+    if let Some(mut log_port) = unsafe { *log_port_ptr } {
+        log_port.write_fmt(args)?;
+        return writeln!(log_port);
+    }
     let mut serial_writer = Com1::get();
     serial_writer.write_fmt(args)?;
     writeln!(serial_writer)
@@ -185,8 +666,110 @@ pub fn __writeln_no_sync(args: core::fmt::Arguments) -> core::fmt::Result {
 #[macro_export]
 macro_rules! serial_writeln_no_sync {
     ($format_string:literal$(, $args:expr)*) => {
-        $crate::serial::__writeln_no_sync(::core::format_args!($format_string $(,$args)*,)).expect("couldn't write to COM1")
+        $crate::serial::__writeln_no_sync(::core::format_args!($format_string $(,$args)*,)).expect("couldn't write to the log port")
     };
 }
 
 pub use serial_writeln_no_sync as writeln_no_sync;
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        ioport::mock::MockPorts,
+        serial::{COM2, COM3, COM4, LineSettings, Parity, SerialPort, parse_console, port_base},
+    };
+
+    #[test]
+    fn console_specs() {
+        assert_eq!(Some(COM4), port_base("COM4"));
+        assert_eq!(Some(0x2F8), port_base("0x2f8"));
+        assert_eq!(None, port_base("com5"));
+        assert_eq!(Some((COM2, LineSettings::default())), parse_console("com2"));
+        assert_eq!(
+            Some((
+                COM4,
+                LineSettings {
+                    baud_rate: 115_200,
+                    parity: Parity::None
+                }
+            )),
+            parse_console("com4,115200")
+        );
+        assert_eq!(
+            Some((
+                0x2F8,
+                LineSettings {
+                    baud_rate: 9_600,
+                    parity: Parity::Even
+                }
+            )),
+            parse_console("0x2f8,9600e8")
+        );
+        // 7 data bits, a rate the UART can't divide down to, and no rate at all
+        assert_eq!(None, parse_console("com2,9600n7"));
+        assert_eq!(None, parse_console("com2,10000"));
+        assert_eq!(None, parse_console("com2,n"));
+        assert_eq!(Some(3), LineSettings::default().divisor());
+        assert_eq!(
+            Some(384),
+            LineSettings {
+                baud_rate: 300,
+                parity: Parity::Odd
+            }
+            .divisor()
+        );
+    }
+
+    #[test]
+    fn port_setup() {
+        let ports = MockPorts::new();
+        let settings = LineSettings {
+            baud_rate: 115_200,
+            parity: Parity::Even,
+        };
+        let mut port = SerialPort::with_ports(&ports, COM2, settings).unwrap();
+        assert_eq!(
+            [
+                (0x2F9, 0),
+                // The divisor, then 8E1
+                (0x2FB, 0x80),
+                (0x2F8, 1),
+                (0x2F9, 0),
+                (0x2FB, 0x1B),
+                // The loopback test
+                (0x2FC, 0x1E),
+                (0x2F8, 0xAE),
+                (0x2FC, 0x03)
+            ],
+            *ports.writes()
+        );
+
+        ports.clear_writes();
+        assert_eq!(16, port.enable_fifos());
+        // Transmitter empty, nothing received
+        ports.stub(0x2FD, 0x20);
+        write!(port, "hi").unwrap();
+        assert_eq!(None, port.read_byte());
+        assert_eq!(
+            [(0x2FA, 0xC7), (0x2F8, b'h'.into()), (0x2F8, b'i'.into())],
+            *ports.writes()
+        );
+    }
+
+    #[test]
+    fn missing_and_old_ports() {
+        let ports = MockPorts::new();
+        // A floating bus fails the loopback test
+        ports.stub(COM3, 0xFF);
+        assert!(SerialPort::with_ports(&ports, COM3, LineSettings::default()).is_none());
+
+        // A 16450, without FIFOs, gets them disabled again
+        ports.stub(COM4 + 2, 0x01);
+        let port = SerialPort::with_ports(&ports, COM4, LineSettings::default()).unwrap();
+        ports.clear_writes();
+        assert_eq!(1, port.enable_fifos());
+        assert_eq!([(0x2EA, 0xC7), (0x2EA, 0)], *ports.writes());
+    }
+}
@@ -0,0 +1,198 @@
+// The function symbols of the kernel or of stage2, which xtasks extracts from their ELF with `nm`
+// and ships as the symbols payloads, for them to turn code addresses into names in backtraces:
+//   0x00  magic "BLOGSYMS"
+//   0x08  u32 number of symbols
+//   0x0C  u32 size of the names
+//   0x10  symbols of { u64 link-time address, u32 size, u32 offset of the name }, by address
+//   then the names, demangled, without their hash, and each NUL terminated
+use core::fmt::Display;
+
+use zerocopy::{LE, TryFromBytes, U32, U64};
+
+use crate::error::{Error, Facility, Fault, try_read_error};
+
+pub const TABLE_MAGIC: [u8; 8] = *b"BLOGSYMS";
+const SYMBOL_SIZE: usize = 16;
+
+#[derive(TryFromBytes)]
+#[repr(C)]
+struct SymbolTableHeaderRaw {
+    magic: [u8; 8],
+    count: U32<LE>,
+    names_size: U32<LE>,
+}
+
+#[derive(TryFromBytes)]
+#[repr(C)]
+struct SymbolRaw {
+    address: U64<LE>,
+    size: U32<LE>,
+    name_offset: U32<LE>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub address: u64,
+    /// 0 if `nm` didn't know it
+    pub size: u32,
+}
+
+/// Where an address is: in which symbol, and how far into it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location<'a> {
+    pub symbol: Symbol<'a>,
+    pub offset: u64,
+}
+
+impl Display for Location<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}+{:#x}", self.symbol.name, self.offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a> {
+    symbols: &'a [u8],
+    names: &'a [u8],
+}
+
+fn parsing_error(fault: Fault) -> Error {
+    Error::parsing_error(fault, Facility::SymbolTable)
+}
+
+impl<'a> TryFrom<&'a [u8]> for SymbolTable<'a> {
+    type Error = Error;
+
+    fn try_from(bytes: &'a [u8]) -> Result<Self, Self::Error> {
+        let (header, rest) = SymbolTableHeaderRaw::try_read_from_prefix(bytes)
+            .map_err(|err| try_read_error(Facility::SymbolTable, err))?;
+        if header.magic != TABLE_MAGIC {
+            return Err(parsing_error(Fault::InvalidValueForField("magic")));
+        }
+        let symbols_size = (header.count.get() as usize)
+            .checked_mul(SYMBOL_SIZE)
+            .ok_or(parsing_error(Fault::InvalidValueForField("count")))?;
+        let Some((symbols, rest)) = rest.split_at_checked(symbols_size) else {
+            return Err(parsing_error(Fault::InvalidValueForField("count")));
+        };
+        let Some(names) = rest.get(..header.names_size.get() as usize) else {
+            return Err(parsing_error(Fault::InvalidValueForField("names_size")));
+        };
+        Ok(Self { symbols, names })
+    }
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn len(&self) -> usize {
+        self.symbols.len() / SYMBOL_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// The `index`th symbol by address. Names that aren't NUL terminated UTF-8 within the names
+    /// come out as "?"
+    pub fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        let bytes = self.symbols.get(index * SYMBOL_SIZE..)?;
+        let (raw, _) = SymbolRaw::try_read_from_prefix(bytes).ok()?;
+        let name = self
+            .names
+            .get(raw.name_offset.get() as usize..)
+            .and_then(|name| name.split(|byte| *byte == 0).next())
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .unwrap_or("?");
+        Some(Symbol {
+            name,
+            address: raw.address.get(),
+            size: raw.size.get(),
+        })
+    }
+
+    /// The symbol `address` is in, i.e. the last one starting at or before it, unless `address` is
+    /// past its end
+    pub fn lookup(&self, address: u64) -> Option<Location<'a>> {
+        // The first symbol starting after the address
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.get(middle)?.address <= address {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        let symbol = self.get(low.checked_sub(1)?)?;
+        let offset = address - symbol.address;
+        if symbol.size != 0 && offset >= u64::from(symbol.size) {
+            return None;
+        }
+        Some(Location { symbol, offset })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        symbols::{Location, Symbol, SymbolTable, TABLE_MAGIC},
+        test_support::TestWriter,
+    };
+
+    fn table(symbols: &[(u64, u32, u32)], names: &[u8]) -> [u8; 256] {
+        let mut bytes = [0u8; 256];
+        bytes[..8].copy_from_slice(&TABLE_MAGIC);
+        bytes[8..12].copy_from_slice(&(symbols.len() as u32).to_le_bytes());
+        bytes[12..16].copy_from_slice(&(names.len() as u32).to_le_bytes());
+        for (i, (address, size, name_offset)) in symbols.iter().enumerate() {
+            let symbol = &mut bytes[16 + i * 16..32 + i * 16];
+            symbol[..8].copy_from_slice(&address.to_le_bytes());
+            symbol[8..12].copy_from_slice(&size.to_le_bytes());
+            symbol[12..].copy_from_slice(&name_offset.to_le_bytes());
+        }
+        let names_start = 16 + symbols.len() * 16;
+        bytes[names_start..names_start + names.len()].copy_from_slice(names);
+        bytes
+    }
+
+    #[test]
+    fn lookup() {
+        let bytes = table(
+            &[
+                (0x20_1000, 0x40, 0),
+                (0x20_1040, 0, 12),
+                (0x20_2000, 0x10, 26),
+            ],
+            b"_start\0\0\0\0\0\0blog_os::init\0core::panic\0",
+        );
+        let symbols = SymbolTable::try_from(&bytes[..]).unwrap();
+        assert_eq!(3, symbols.len());
+        assert_eq!(
+            Some(Location {
+                symbol: Symbol {
+                    name: "_start",
+                    address: 0x20_1000,
+                    size: 0x40
+                },
+                offset: 0x3f
+            }),
+            symbols.lookup(0x20_103f)
+        );
+        // Without a size, everything up to the next symbol is in it
+        let location = symbols.lookup(0x20_1140).unwrap();
+        let mut writer = TestWriter::<64>::new();
+        write!(writer, "{location}").unwrap();
+        assert_eq!(b"blog_os::init+0x100", writer.as_bytes());
+        assert_eq!(None, symbols.lookup(0x20_0fff));
+        assert_eq!(None, symbols.lookup(0x20_2010));
+        assert_eq!("core::panic", symbols.get(2).unwrap().name);
+        assert_eq!(None, symbols.get(3));
+
+        let mut bad_magic = bytes;
+        bad_magic[0] = b'X';
+        assert!(SymbolTable::try_from(&bad_magic[..]).is_err());
+        assert!(SymbolTable::try_from(&bytes[..64]).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+// Fixtures shared by the tests of several modules
+use core::fmt::Write;
+
+/// Collects what's formatted into it in a fixed buffer of `N` bytes, failing the write that
+/// doesn't fit
+pub struct TestWriter<const N: usize> {
+    bytes: [u8; N],
+    length: usize,
+}
+
+impl<const N: usize> TestWriter<N> {
+    pub fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            length: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+
+    /// # Panics
+    /// Never, only whole strs are written in
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).expect("only strs are written in")
+    }
+}
+
+impl<const N: usize> Write for TestWriter<N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.length + s.len();
+        self.bytes
+            .get_mut(self.length..end)
+            .ok_or(core::fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.length = end;
+        Ok(())
+    }
+}
@@ -1,12 +1,19 @@
 // https://www.alldatasheet.com/datasheet-pdf/download/66093/INTEL/PIIX3.html
 use core::arch::asm;
 
-use crate::{ioport::Port, make_bitmap};
+use crate::{ioport::Port, make_bitmap, random};
 
 const TIMER_0_FREQUENCY_HZ: u32 = 1_193_182;
 
 const TIMER_CONTROL_WORD: u8 = 0x43;
 const TIMER_0: u8 = 0x40;
+// A reload value of 0 counts down the whole 16 bits
+const TIMER_0_RELOAD_TICKS: u64 = u16::MAX as u64 + 1;
+// How long to count timestamp counter cycles for against timer zero
+const CALIBRATION_NS: u64 = 10_000_000;
+/// Time between two IRQ0s once `start_timer_0_rate_generator` ran, ~55ms
+pub const TIMER_0_PERIOD_NS: u64 =
+    TIMER_0_RELOAD_TICKS * 1_000_000_000 / TIMER_0_FREQUENCY_HZ as u64;
 
 enum Counter {
     _0,
@@ -56,6 +63,21 @@ impl TimerControlWordFlags {
         self
     }
 
+    fn low_then_high_byte(mut self) -> Self {
+        use TimerControlWordFlag::*;
+        self.set_flag(ReadWriteSelectBit1);
+        self.set_flag(ReadWriteSelectBit2);
+        self
+    }
+
+    fn rate_generator(mut self) -> Self {
+        use TimerControlWordFlag::*;
+        self.clear_flag(CounterModeBit1);
+        self.set_flag(CounterModeBit2);
+        self.clear_flag(CounterModeBit3);
+        self
+    }
+
     fn binary_countdown(mut self) -> Self {
         use TimerControlWordFlag::*;
         self.clear_flag(BinaryCodedDecimals);
@@ -71,8 +93,45 @@ fn read_timer_0_counter() -> u16 {
         .binary_countdown();
     let timer_control_word_port = Port::new(TIMER_CONTROL_WORD as u16);
 
+    let timer_0_port = Port::new(TIMER_0 as u16);
+
     timer_control_word_port.writeb(u8::from(timer_control_word));
-    (timer_control_word_port.readb() as u16) | ((timer_control_word_port.readb() as u16) << 8)
+    (timer_0_port.readb() as u16) | ((timer_0_port.readb() as u16) << 8)
+}
+
+/// Makes timer zero count down its whole range, one tick at a time, and raise IRQ0 every
+/// `TIMER_0_PERIOD_NS` as it wraps around. The counter keeps wrapping the way
+/// `LowPrecisionTimer` expects it to
+pub fn start_timer_0_rate_generator() {
+    let timer_control_word = TimerControlWordFlags::empty()
+        .select_counter(Counter::_0)
+        .low_then_high_byte()
+        .rate_generator()
+        .binary_countdown();
+    Port::new(TIMER_CONTROL_WORD as u16).writeb(u8::from(timer_control_word));
+    let timer_0_port = Port::new(TIMER_0 as u16);
+    timer_0_port.writeb(TIMER_0_RELOAD_TICKS as u8);
+    timer_0_port.writeb((TIMER_0_RELOAD_TICKS >> 8) as u8);
+}
+
+/// How far timer zero is into its current period, once `start_timer_0_rate_generator` ran
+pub fn timer_0_period_elapsed_ns() -> u64 {
+    // The counter counts down from the reload value, and reads 0 right as it wraps around
+    let elapsed_ticks =
+        (TIMER_0_RELOAD_TICKS - read_timer_0_counter() as u64) % TIMER_0_RELOAD_TICKS;
+    elapsed_ticks * 1_000_000_000 / TIMER_0_FREQUENCY_HZ as u64
+}
+
+/// The timestamp counter frequency, measured against timer zero, which must be running already
+pub fn calibrate_timestamp_counter() -> u64 {
+    let mut calibration_timer = LowPrecisionTimer::new(CALIBRATION_NS);
+    calibration_timer.update();
+    let start = random::read_timestamp_counter();
+    while !calibration_timer.timeout() {
+        calibration_timer.update();
+    }
+    let cycles = random::read_timestamp_counter() - start;
+    cycles * (1_000_000_000 / CALIBRATION_NS)
 }
 
 #[derive(Debug)]
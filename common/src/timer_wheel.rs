@@ -0,0 +1,304 @@
+// Software timers, multiplexed onto a hardware timer that ticks at a fixed rate: a hashed timer
+// wheel, with a slot for each tick of a revolution, where timers are linked into the slot their
+// deadline falls in. Advancing the wheel by a tick only looks at the timers of one slot, no matter
+// how many others there are, and timers further away than a revolution just stay in their slot
+// for another round. Deadlines and periods are in ticks, turning time into those is up to whoever
+// drives the wheel
+use crate::error::Fault;
+
+/// What happens when a timer expires
+#[derive(Debug, Clone, Copy)]
+pub enum TimerAction {
+    /// Whoever advances the wheel gets the callback to run
+    Callback(fn()),
+    /// The timer is marked as fired, for a waiter to take with `take_fired`, e.g. from
+    /// `wait_queue::wait_until`
+    Wake,
+}
+
+// Callbacks compare by address, which a derive would do too but warn about: the same function can
+// have different addresses in different codegen units
+impl PartialEq for TimerAction {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Callback(callback), Self::Callback(other)) => {
+                core::ptr::fn_addr_eq(*callback, *other)
+            }
+            (Self::Wake, Self::Wake) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for TimerAction {}
+
+/// A timer of a wheel. Ids of timers that are gone don't refer to the ones reusing their place
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Timer {
+    deadline: u64,
+    period: Option<u64>,
+    action: TimerAction,
+    armed: bool,
+    fired: bool,
+    in_use: bool,
+    generation: u32,
+    // The next timer of the same slot
+    next: Option<usize>,
+}
+
+/// Up to `N` timers, on a wheel of `SLOTS` ticks
+pub struct TimerWheel<const SLOTS: usize, const N: usize> {
+    // The tick whose timers expire next
+    current: u64,
+    slots: [Option<usize>; SLOTS],
+    timers: [Timer; N],
+}
+
+impl<const SLOTS: usize, const N: usize> TimerWheel<SLOTS, N> {
+    pub const fn new() -> Self {
+        Self {
+            current: 0,
+            slots: [None; SLOTS],
+            timers: [Timer {
+                deadline: 0,
+                period: None,
+                action: TimerAction::Wake,
+                armed: false,
+                fired: false,
+                in_use: false,
+                generation: 0,
+                next: None,
+            }; N],
+        }
+    }
+
+    /// A timer that expires once, `delay` ticks after the last one the wheel was advanced to, or
+    /// on the next one for a delay of 0
+    pub fn one_shot(&mut self, delay: u64, action: TimerAction) -> Result<TimerId, Fault> {
+        self.start(delay, None, action)
+    }
+
+    /// A timer that expires every `period` ticks, the first time `period` ticks from the last one
+    /// the wheel was advanced to. A period of 0 is invalid
+    pub fn periodic(&mut self, period: u64, action: TimerAction) -> Result<TimerId, Fault> {
+        if period == 0 {
+            return Err(Fault::InvalidValueForField("period"));
+        }
+        self.start(period, Some(period), action)
+    }
+
+    fn start(
+        &mut self,
+        delay: u64,
+        period: Option<u64>,
+        action: TimerAction,
+    ) -> Result<TimerId, Fault> {
+        let Some(index) = self.timers.iter().position(|timer| !timer.in_use) else {
+            return Err(Fault::TooManyTimers);
+        };
+        let timer = &mut self.timers[index];
+        *timer = Timer {
+            // `current` is a tick past the last one the wheel was advanced to
+            deadline: self.current.saturating_add(delay.saturating_sub(1)),
+            period,
+            action,
+            armed: true,
+            fired: false,
+            in_use: true,
+            generation: timer.generation.wrapping_add(1),
+            next: None,
+        };
+        let id = TimerId {
+            index,
+            generation: timer.generation,
+        };
+        self.link(index);
+        Ok(id)
+    }
+
+    fn slot(&self, deadline: u64) -> usize {
+        (deadline % SLOTS as u64) as usize
+    }
+
+    fn link(&mut self, index: usize) {
+        let slot = self.slot(self.timers[index].deadline);
+        self.timers[index].next = self.slots[slot];
+        self.slots[slot] = Some(index);
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let slot = self.slot(self.timers[index].deadline);
+        let next = self.timers[index].next;
+        if self.slots[slot] == Some(index) {
+            self.slots[slot] = next;
+            return;
+        }
+        let mut previous = self.slots[slot];
+        while let Some(other) = previous {
+            if self.timers[other].next == Some(index) {
+                self.timers[other].next = next;
+                return;
+            }
+            previous = self.timers[other].next;
+        }
+    }
+
+    fn get_mut(&mut self, id: TimerId) -> Option<&mut Timer> {
+        self.timers
+            .get_mut(id.index)
+            .filter(|timer| timer.in_use && timer.generation == id.generation)
+    }
+
+    /// Stops the timer and forgets it. False if it was gone already
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        let Some(timer) = self.get_mut(id) else {
+            return false;
+        };
+        let armed = timer.armed;
+        timer.in_use = false;
+        if armed {
+            self.unlink(id.index);
+        }
+        true
+    }
+
+    /// Whether a `TimerAction::Wake` timer fired since this was last called. A one-shot timer is
+    /// gone once this returned true for it
+    pub fn take_fired(&mut self, id: TimerId) -> bool {
+        let Some(timer) = self.get_mut(id) else {
+            return false;
+        };
+        let fired = core::mem::take(&mut timer.fired);
+        if fired && timer.period.is_none() {
+            timer.in_use = false;
+        }
+        fired
+    }
+
+    /// Advances the wheel up to tick `now`, returning the callback of the next timer that expired
+    /// on the way, if there's any. To be called until it returns None, so that callbacks run
+    /// without the wheel being borrowed and can start timers of their own
+    pub fn next_expired(&mut self, now: u64) -> Option<fn()> {
+        while self.current <= now {
+            let current = self.current;
+            let mut next = self.slots[self.slot(current)];
+            while let Some(index) = next {
+                next = self.timers[index].next;
+                if self.timers[index].deadline != current {
+                    // Not in this round
+                    continue;
+                }
+                self.unlink(index);
+                let timer = &mut self.timers[index];
+                match timer.period {
+                    Some(period) => {
+                        timer.deadline = current.saturating_add(period);
+                        self.link(index);
+                    }
+                    None => timer.armed = false,
+                }
+                let timer = &mut self.timers[index];
+                match timer.action {
+                    TimerAction::Callback(callback) => {
+                        if timer.period.is_none() {
+                            timer.in_use = false;
+                        }
+                        return Some(callback);
+                    }
+                    TimerAction::Wake => timer.fired = true,
+                }
+            }
+            self.current += 1;
+        }
+        None
+    }
+
+    /// How many timers there are, armed or fired and not taken yet
+    pub fn len(&self) -> usize {
+        self.timers.iter().filter(|timer| timer.in_use).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<const SLOTS: usize, const N: usize> Default for TimerWheel<SLOTS, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Fault,
+        timer_wheel::{TimerAction, TimerWheel},
+    };
+
+    // Different bodies, so that they can't be merged into one function
+    fn first() {
+        core::hint::black_box(1);
+    }
+    fn second() {
+        core::hint::black_box(2);
+    }
+
+    fn is(callback: Option<fn()>, expected: fn()) -> bool {
+        callback.is_some_and(|callback| core::ptr::fn_addr_eq(callback, expected))
+    }
+
+    #[test]
+    fn callbacks() {
+        let mut wheel = TimerWheel::<4, 3>::new();
+        // Past a revolution of the wheel, so it shares a slot with the one-shot one for a round
+        let periodic = wheel.periodic(5, TimerAction::Callback(second)).unwrap();
+        assert!(wheel.one_shot(1, TimerAction::Callback(first)).is_ok());
+        assert!(is(wheel.next_expired(0), first));
+        assert!(wheel.next_expired(0).is_none());
+        assert!(wheel.next_expired(3).is_none());
+        assert!(is(wheel.next_expired(4), second));
+        assert!(wheel.next_expired(4).is_none());
+        assert!(is(wheel.next_expired(14), second));
+        assert!(is(wheel.next_expired(14), second));
+        assert!(wheel.next_expired(14).is_none());
+        assert_eq!(1, wheel.len());
+        assert!(wheel.cancel(periodic));
+        assert!(!wheel.cancel(periodic));
+        assert!(wheel.next_expired(100).is_none());
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn wakeups() {
+        let mut wheel = TimerWheel::<8, 2>::new();
+        let timer = wheel.one_shot(3, TimerAction::Wake).unwrap();
+        let periodic = wheel.periodic(2, TimerAction::Wake).unwrap();
+        assert!(matches!(
+            wheel.one_shot(1, TimerAction::Wake),
+            Err(Fault::TooManyTimers)
+        ));
+        assert!(wheel.next_expired(1).is_none());
+        assert!(!wheel.take_fired(timer));
+        assert!(wheel.take_fired(periodic));
+        assert!(wheel.next_expired(2).is_none());
+        assert!(wheel.take_fired(timer));
+        // Gone once taken, and its place goes to the next timer
+        assert!(!wheel.take_fired(timer));
+        let other = wheel.one_shot(0, TimerAction::Wake).unwrap();
+        assert!(!wheel.cancel(timer));
+        assert!(wheel.next_expired(3).is_none());
+        assert!(wheel.take_fired(other));
+        assert!(wheel.take_fired(periodic));
+        assert!(wheel.next_expired(4).is_none());
+        assert!(!wheel.take_fired(periodic));
+        assert!(wheel.next_expired(5).is_none());
+        assert!(wheel.take_fired(periodic));
+    }
+}
@@ -0,0 +1,438 @@
+// An in-memory file system: the root file system until one on a disk is mounted, and where the
+// initrd gets unpacked to. Everything lives in fixed-size tables, usable in statics and without an
+// allocator: a node for each file or directory, and blocks of data that files chain together the
+// way a FAT does. Directories don't list their children, each node has its parent instead, so
+// looking a name up goes through all of the nodes. Fine for the handful of files this is for
+use crate::error::Fault;
+
+pub const BLOCK_SIZE: usize = 512;
+pub const MAX_NAME_LENGTH: usize = 64;
+const ROOT: usize = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+}
+
+/// A file or directory. Ids of nodes that were removed can end up referring to new ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeId(usize);
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    in_use: bool,
+    kind: NodeKind,
+    parent: usize,
+    name: [u8; MAX_NAME_LENGTH],
+    name_length: usize,
+    size: usize,
+    first_block: Option<usize>,
+}
+
+impl Node {
+    const UNUSED: Self = Self {
+        in_use: false,
+        kind: NodeKind::File,
+        parent: ROOT,
+        name: [0; MAX_NAME_LENGTH],
+        name_length: 0,
+        size: 0,
+        first_block: None,
+    };
+
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_length]).unwrap_or_default()
+    }
+}
+
+/// Up to `NODES` files and directories, the root included, holding up to `BLOCKS` blocks of data
+pub struct Tmpfs<const NODES: usize, const BLOCKS: usize> {
+    nodes: [Node; NODES],
+    // The block after each one in the chain of its file
+    next_block: [Option<usize>; BLOCKS],
+    block_in_use: [bool; BLOCKS],
+    blocks: [[u8; BLOCK_SIZE]; BLOCKS],
+}
+
+impl<const NODES: usize, const BLOCKS: usize> Tmpfs<NODES, BLOCKS> {
+    /// An empty file system, with nothing but the root directory
+    pub const fn new() -> Self {
+        let mut nodes = [Node::UNUSED; NODES];
+        nodes[ROOT] = Node {
+            in_use: true,
+            kind: NodeKind::Directory,
+            ..Node::UNUSED
+        };
+        Self {
+            nodes,
+            next_block: [None; BLOCKS],
+            block_in_use: [false; BLOCKS],
+            blocks: [[0; BLOCK_SIZE]; BLOCKS],
+        }
+    }
+
+    pub fn root(&self) -> NodeId {
+        NodeId(ROOT)
+    }
+
+    fn node(&self, id: NodeId) -> Result<&Node, Fault> {
+        self.nodes
+            .get(id.0)
+            .filter(|node| node.in_use)
+            .ok_or(Fault::FileNotFound)
+    }
+
+    pub fn kind(&self, id: NodeId) -> Result<NodeKind, Fault> {
+        Ok(self.node(id)?.kind)
+    }
+
+    pub fn size(&self, id: NodeId) -> Result<usize, Fault> {
+        Ok(self.node(id)?.size)
+    }
+
+    fn directory(&self, id: NodeId) -> Result<&Node, Fault> {
+        let node = self.node(id)?;
+        if node.kind != NodeKind::Directory {
+            return Err(Fault::NotADirectory);
+        }
+        Ok(node)
+    }
+
+    fn file(&self, id: NodeId) -> Result<&Node, Fault> {
+        let node = self.node(id)?;
+        if node.kind != NodeKind::File {
+            return Err(Fault::NotAFile);
+        }
+        Ok(node)
+    }
+
+    /// The name and id of everything in `directory`
+    pub fn children(
+        &self,
+        directory: NodeId,
+    ) -> Result<impl Iterator<Item = (&str, NodeId)> + '_, Fault> {
+        self.directory(directory)?;
+        Ok(self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(move |&(index, node)| {
+                node.in_use && index != ROOT && node.parent == directory.0
+            })
+            .map(|(index, node)| (node.name(), NodeId(index))))
+    }
+
+    fn find_in(&self, directory: usize, name: &str) -> Option<usize> {
+        match name {
+            "." => Some(directory),
+            ".." => Some(self.nodes[directory].parent),
+            _ => self.nodes.iter().enumerate().position(|(index, node)| {
+                node.in_use && index != ROOT && node.parent == directory && node.name() == name
+            }),
+        }
+    }
+
+    /// Looks up the file or directory at `path`, of names separated by slashes from the root
+    pub fn lookup(&self, path: &str) -> Result<NodeId, Fault> {
+        let mut current = ROOT;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            self.directory(NodeId(current))?;
+            current = self.find_in(current, name).ok_or(Fault::FileNotFound)?;
+        }
+        Ok(NodeId(current))
+    }
+
+    /// The directory `path` is in and the last name of it, which can be given to a new node
+    fn split_path<'a>(&self, path: &'a str) -> Result<(usize, &'a str), Fault> {
+        let path = path.trim_end_matches('/');
+        let (directory, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name == "." || name == ".." || name.len() > MAX_NAME_LENGTH {
+            return Err(Fault::InvalidFileName);
+        }
+        let directory = self.lookup(directory)?;
+        self.directory(directory)?;
+        Ok((directory.0, name))
+    }
+
+    /// Creates an empty file or directory at `path`, in a directory that exists already
+    pub fn create(&mut self, path: &str, kind: NodeKind) -> Result<NodeId, Fault> {
+        let (parent, name) = self.split_path(path)?;
+        if self.find_in(parent, name).is_some() {
+            return Err(Fault::FileExists);
+        }
+        let Some(index) = self.nodes.iter().position(|node| !node.in_use) else {
+            return Err(Fault::TooManyFiles);
+        };
+        let mut node = Node {
+            in_use: true,
+            kind,
+            parent,
+            name_length: name.len(),
+            ..Node::UNUSED
+        };
+        node.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.nodes[index] = node;
+        Ok(NodeId(index))
+    }
+
+    /// The `index`th block of the node at `node`
+    fn nth_block(&self, node: usize, index: usize) -> Option<usize> {
+        let mut block = self.nodes[node].first_block;
+        for _ in 0..index {
+            block = self.next_block[block?];
+        }
+        block
+    }
+
+    /// Gives the node at `node` as many blocks as `size` takes. Whatever is past the end of the
+    /// file reads as zeroes
+    fn resize(&mut self, node: usize, size: usize) -> Result<(), Fault> {
+        let blocks = self.nodes[node].size.div_ceil(BLOCK_SIZE);
+        let needed = size.div_ceil(BLOCK_SIZE);
+        if needed > blocks {
+            if needed - blocks > self.free_blocks() {
+                return Err(Fault::NoSpaceLeft);
+            }
+            let mut last = blocks
+                .checked_sub(1)
+                .and_then(|index| self.nth_block(node, index));
+            for _ in blocks..needed {
+                let Some(block) = self.block_in_use.iter().position(|in_use| !in_use) else {
+                    return Err(Fault::NoSpaceLeft);
+                };
+                self.block_in_use[block] = true;
+                self.blocks[block].fill(0);
+                self.next_block[block] = None;
+                match last {
+                    Some(last) => self.next_block[last] = Some(block),
+                    None => self.nodes[node].first_block = Some(block),
+                }
+                last = Some(block);
+            }
+        } else if needed < blocks {
+            let mut block = match needed.checked_sub(1) {
+                None => self.nodes[node].first_block.take(),
+                Some(index) => self
+                    .nth_block(node, index)
+                    .and_then(|last| self.next_block[last].take()),
+            };
+            while let Some(freed) = block {
+                block = self.next_block[freed].take();
+                self.block_in_use[freed] = false;
+            }
+        }
+        if size < self.nodes[node].size
+            && let Some(last) = needed
+                .checked_sub(1)
+                .and_then(|index| self.nth_block(node, index))
+        {
+            let end = size - (needed - 1) * BLOCK_SIZE;
+            self.blocks[last][end..].fill(0);
+        }
+        self.nodes[node].size = size;
+        Ok(())
+    }
+
+    /// Reads what's in `file` from `offset` into `buffer`, as much as fits, returning how much
+    /// that was
+    pub fn read(&self, file: NodeId, offset: usize, buffer: &mut [u8]) -> Result<usize, Fault> {
+        let size = self.file(file)?.size;
+        let length = buffer.len().min(size.saturating_sub(offset));
+        let mut block = self.nth_block(file.0, offset / BLOCK_SIZE);
+        let mut position = offset % BLOCK_SIZE;
+        let mut read = 0;
+        while read < length {
+            let Some(current) = block else {
+                break;
+            };
+            let copied = (BLOCK_SIZE - position).min(length - read);
+            buffer[read..read + copied]
+                .copy_from_slice(&self.blocks[current][position..position + copied]);
+            read += copied;
+            position = 0;
+            block = self.next_block[current];
+        }
+        Ok(read)
+    }
+
+    /// Writes `bytes` to `file` at `offset`, growing it if it ends up past its end. Nothing is
+    /// written if there are not enough free blocks for all of it
+    pub fn write(&mut self, file: NodeId, offset: usize, bytes: &[u8]) -> Result<(), Fault> {
+        let size = self.file(file)?.size;
+        let end = offset
+            .checked_add(bytes.len())
+            .ok_or(Fault::InvalidValueForField("offset"))?;
+        if end > size {
+            self.resize(file.0, end)?;
+        }
+        let mut block = self.nth_block(file.0, offset / BLOCK_SIZE);
+        let mut position = offset % BLOCK_SIZE;
+        let mut written = 0;
+        while written < bytes.len() {
+            let Some(current) = block else {
+                break;
+            };
+            let copied = (BLOCK_SIZE - position).min(bytes.len() - written);
+            self.blocks[current][position..position + copied]
+                .copy_from_slice(&bytes[written..written + copied]);
+            written += copied;
+            position = 0;
+            block = self.next_block[current];
+        }
+        Ok(())
+    }
+
+    /// Makes `file` `size` bytes long, with zeroes where it grows
+    pub fn truncate(&mut self, file: NodeId, size: usize) -> Result<(), Fault> {
+        self.file(file)?;
+        self.resize(file.0, size)
+    }
+
+    fn remove_node(&mut self, node: usize) -> Result<(), Fault> {
+        if self.nodes[node].kind == NodeKind::Directory
+            && self.children(NodeId(node))?.next().is_some()
+        {
+            return Err(Fault::DirectoryNotEmpty);
+        }
+        self.resize(node, 0)?;
+        self.nodes[node] = Node::UNUSED;
+        Ok(())
+    }
+
+    /// Removes the file or the empty directory at `path`
+    pub fn remove(&mut self, path: &str) -> Result<(), Fault> {
+        let node = self.lookup(path)?;
+        if node.0 == ROOT {
+            return Err(Fault::InvalidFileName);
+        }
+        self.remove_node(node.0)
+    }
+
+    /// Moves the file or directory at `from` to `to`, replacing what's there if it's of the same
+    /// kind, and empty if it's a directory
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), Fault> {
+        let node = self.lookup(from)?.0;
+        let (parent, name) = self.split_path(to)?;
+        if node == ROOT {
+            return Err(Fault::InvalidFileName);
+        }
+        // Into itself or one of its subdirectories, where it'd be cut off from the root
+        let mut ancestor = parent;
+        while ancestor != ROOT {
+            if ancestor == node {
+                return Err(Fault::MoveIntoItself);
+            }
+            ancestor = self.nodes[ancestor].parent;
+        }
+        if let Some(existing) = self.find_in(parent, name) {
+            if existing == node {
+                return Ok(());
+            }
+            match (self.nodes[node].kind, self.nodes[existing].kind) {
+                (NodeKind::File, NodeKind::Directory) => return Err(Fault::NotAFile),
+                (NodeKind::Directory, NodeKind::File) => return Err(Fault::NotADirectory),
+                _ => self.remove_node(existing)?,
+            }
+        }
+        let moved = &mut self.nodes[node];
+        moved.parent = parent;
+        moved.name = [0; MAX_NAME_LENGTH];
+        moved.name[..name.len()].copy_from_slice(name.as_bytes());
+        moved.name_length = name.len();
+        Ok(())
+    }
+
+    /// How many more blocks of data there is room for
+    pub fn free_blocks(&self) -> usize {
+        self.block_in_use.iter().filter(|in_use| !**in_use).count()
+    }
+}
+
+impl<const NODES: usize, const BLOCKS: usize> Default for Tmpfs<NODES, BLOCKS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Fault,
+        tmpfs::{BLOCK_SIZE, NodeKind, Tmpfs},
+    };
+
+    #[test]
+    fn files_and_directories() {
+        let mut fs = Tmpfs::<4, 4>::new();
+        fs.create("/etc", NodeKind::Directory).unwrap();
+        let file = fs.create("/etc/motd", NodeKind::File).unwrap();
+        assert!(matches!(
+            fs.create("etc/motd", NodeKind::File),
+            Err(Fault::FileExists)
+        ));
+        assert!(matches!(
+            fs.create("/etc/motd/x", NodeKind::File),
+            Err(Fault::NotADirectory)
+        ));
+
+        // A gap, which reads as zeroes, and a write across blocks
+        fs.write(file, BLOCK_SIZE + 500, b"hello, world").unwrap();
+        assert_eq!(BLOCK_SIZE + 512, fs.size(file).unwrap());
+        assert_eq!(2, fs.free_blocks());
+        let mut buffer = [0xFFu8; 16];
+        assert_eq!(Some(16), fs.read(file, BLOCK_SIZE + 496, &mut buffer).ok());
+        assert_eq!(b"\0\0\0\0hello, world", &buffer);
+        assert!(matches!(
+            fs.write(file, 4 * BLOCK_SIZE, b"!"),
+            Err(Fault::NoSpaceLeft)
+        ));
+        assert_eq!(BLOCK_SIZE + 512, fs.size(file).unwrap());
+
+        // What's cut off doesn't come back when the file grows again
+        fs.truncate(file, BLOCK_SIZE + 505).unwrap();
+        fs.truncate(file, BLOCK_SIZE + 512).unwrap();
+        assert_eq!(Some(16), fs.read(file, BLOCK_SIZE + 496, &mut buffer).ok());
+        assert_eq!(b"\0\0\0\0hello\0\0\0\0\0\0\0", &buffer);
+
+        assert_eq!(Some(file), fs.lookup("/etc/../etc/./motd").ok());
+        let etc = fs.lookup("/etc").unwrap();
+        let mut children = fs.children(etc).unwrap();
+        assert_eq!(Some(("motd", file)), children.next());
+        assert!(children.next().is_none());
+        drop(children);
+        assert!(matches!(fs.remove("/etc"), Err(Fault::DirectoryNotEmpty)));
+        fs.remove("/etc/motd").unwrap();
+        fs.remove("/etc").unwrap();
+        assert_eq!(4, fs.free_blocks());
+        assert!(matches!(fs.lookup("/etc"), Err(Fault::FileNotFound)));
+    }
+
+    #[test]
+    fn renames() {
+        let mut fs = Tmpfs::<8, 4>::new();
+        fs.create("/a", NodeKind::Directory).unwrap();
+        fs.create("/a/b", NodeKind::Directory).unwrap();
+        let old = fs.create("/old", NodeKind::File).unwrap();
+        let new = fs.create("/new", NodeKind::File).unwrap();
+        fs.write(new, 0, b"new").unwrap();
+
+        assert!(matches!(
+            fs.rename("/a", "/a/b/a"),
+            Err(Fault::MoveIntoItself)
+        ));
+        assert!(matches!(fs.rename("/new", "/a"), Err(Fault::NotAFile)));
+        // Replacing a file frees its blocks
+        fs.rename("/new", "/old").unwrap();
+        assert_eq!(Some(new), fs.lookup("/old").ok());
+        assert_ne!(old, new);
+        assert_eq!(3, fs.free_blocks());
+        fs.rename("/old", "/a/b/file").unwrap();
+        fs.rename("/a/b", "/b").unwrap();
+        let mut buffer = [0u8; 3];
+        let file = fs.lookup("/b/file").unwrap();
+        assert_eq!(Some(3), fs.read(file, 0, &mut buffer).ok());
+        assert_eq!(b"new", &buffer);
+        assert!(fs.lookup("/a/b").is_err());
+    }
+}
@@ -0,0 +1,219 @@
+// Event tracing, for debugging races between drivers, interrupt handlers and eventually the
+// scheduler, where logging takes too long and changes the timing too much. `trace_event!(Irq,
+// "...", args)` formats a short message into a fixed-size record stamped with the timestamp
+// counter, and pushes it into the ring buffer of the CPU it runs on, dropping the oldest record
+// once the buffer is full. Each category is a cargo feature of common, e.g. `trace-irq`: the calls
+// of the categories left out check a constant and compile to nothing. `dump` writes the records
+// out as JSON lines, oldest first, one CPU after the other:
+//   {"tsc":123456,"cpu":0,"category":"irq","message":"ATA channel 0"}
+#[cfg(target_arch = "x86")]
+use core::arch::x86::__cpuid;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+use core::fmt::{self, Arguments, Write};
+
+use crate::{interrupts, random, ring_buffer::RingBuffer};
+
+/// CPUs with a buffer of their own, the others share them by their APIC ID
+pub const MAX_CPUS: usize = 8;
+/// How many records each CPU keeps
+pub const EVENTS_PER_CPU: usize = 256;
+/// Longer messages are cut short
+pub const MESSAGE_SIZE: usize = 48;
+const FEATURE_INFORMATION: u32 = 1;
+const INITIAL_APIC_ID_SHIFT: u32 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Irq,
+    Timer,
+    Ata,
+    Net,
+    Sched,
+}
+
+impl Category {
+    /// Whether common was built with the category's feature. A constant, so that the events of
+    /// the categories left out compile to nothing
+    pub const fn enabled(self) -> bool {
+        match self {
+            Category::Irq => cfg!(feature = "trace-irq"),
+            Category::Timer => cfg!(feature = "trace-timer"),
+            Category::Ata => cfg!(feature = "trace-ata"),
+            Category::Net => cfg!(feature = "trace-net"),
+            Category::Sched => cfg!(feature = "trace-sched"),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Irq => "irq",
+            Category::Timer => "timer",
+            Category::Ata => "ata",
+            Category::Net => "net",
+            Category::Sched => "sched",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub timestamp: u64,
+    pub cpu: u8,
+    pub category: Category,
+    message: [u8; MESSAGE_SIZE],
+    length: u8,
+}
+
+impl Event {
+    const fn blank() -> Self {
+        Self {
+            timestamp: 0,
+            cpu: 0,
+            category: Category::Irq,
+            message: [0; MESSAGE_SIZE],
+            length: 0,
+        }
+    }
+
+    /// An event with `args` as its message, cut short to `MESSAGE_SIZE` bytes on a character
+    /// boundary
+    pub fn new(timestamp: u64, cpu: u8, category: Category, args: Arguments) -> Self {
+        let mut event = Self {
+            timestamp,
+            cpu,
+            category,
+            ..Self::blank()
+        };
+        let _ = event.write_fmt(args);
+        event
+    }
+
+    pub fn message(&self) -> &str {
+        // Only whole characters are written in
+        core::str::from_utf8(&self.message[..self.length as usize]).unwrap_or_default()
+    }
+}
+
+impl Write for Event {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for character in s.chars() {
+            let start = self.length as usize;
+            let Some(destination) = self.message.get_mut(start..start + character.len_utf8())
+            else {
+                // The rest is dropped, there's no telling the caller to stop formatting early
+                return Ok(());
+            };
+            character.encode_utf8(destination);
+            self.length += character.len_utf8() as u8;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Event {
+    /// As a JSON line, without the line break
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            r#"{{"tsc":{},"cpu":{},"category":"{}","message":""#,
+            self.timestamp,
+            self.cpu,
+            self.category.name()
+        )?;
+        for character in self.message().chars() {
+            match character {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                character if character.is_control() => write!(f, "\\u{:04x}", character as u32)?,
+                character => f.write_char(character)?,
+            }
+        }
+        f.write_str(r#""}"#)
+    }
+}
+
+static mut EVENTS: [RingBuffer<Event, EVENTS_PER_CPU>; MAX_CPUS] =
+    [const { RingBuffer::new(Event::blank()) }; MAX_CPUS];
+
+/// The initial APIC ID of the CPU this runs on. CPUID is slow under virtualization, but only the
+/// builds with a category enabled pay for it
+fn current_cpu() -> u8 {
+    // SAFETY: The `__cpuid` instruction is safe to call with the given arguments.
+    (unsafe { __cpuid(FEATURE_INFORMATION).ebx } >> INITIAL_APIC_ID_SHIFT) as u8
+}
+
+/// Records an event in the buffer of the current CPU, see `trace_event!`
+pub fn __record(category: Category, args: Arguments) {
+    let cpu = current_cpu();
+    let event = Event::new(random::read_timestamp_counter(), cpu, category, args);
+    let events_ptr = &raw mut EVENTS;
+    interrupts::without_interrupts(|| {
+        // SAFETY: each CPU only pushes to its own buffer, with interrupts disabled so that no
+        // handler pushes to it halfway through
+        unsafe { (*events_ptr)[cpu as usize % MAX_CPUS].push_overwriting(event) };
+    });
+}
+
+/// Writes the events recorded so far as JSON lines, the buffers' oldest first, and empties them.
+/// Events recorded meanwhile on other CPUs may be lost
+pub fn dump(writer: &mut impl Write) -> fmt::Result {
+    let events_ptr = &raw mut EVENTS;
+    for cpu in 0..MAX_CPUS {
+        interrupts::without_interrupts(|| {
+            // SAFETY: interrupts are disabled so no handler on this CPU pushes meanwhile, and
+            // nothing but dumps reads the buffers
+            let events = unsafe { &mut (*events_ptr)[cpu] };
+            let (front, back) = events.as_slices();
+            for event in front.iter().chain(back) {
+                writeln!(writer, "{event}")?;
+            }
+            events.clear();
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Records an event in the given category, e.g. `trace_event!(Ata, "channel {} IRQ", channel)`.
+/// Nothing is formatted, nor compiled in, unless common was built with the category's feature
+#[macro_export]
+macro_rules! trace_event {
+    ($category:ident, $format_string:literal$(, $args:expr)*) => {
+        if $crate::trace::Category::$category.enabled() {
+            $crate::trace::__record(
+                $crate::trace::Category::$category,
+                ::core::format_args!($format_string $(,$args)*),
+            );
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::{
+        test_support::TestWriter,
+        trace::{Category, Event, MESSAGE_SIZE},
+    };
+
+    #[test]
+    fn events_as_json_lines() {
+        let event = Event::new(42, 1, Category::Ata, format_args!("said \"{}\"\n", "hi"));
+        let mut writer = TestWriter::<128>::new();
+        write!(writer, "{event}").unwrap();
+        assert_eq!(
+            br#"{"tsc":42,"cpu":1,"category":"ata","message":"said \"hi\"\u000a"}"#,
+            writer.as_bytes()
+        );
+    }
+
+    #[test]
+    fn long_messages_are_cut_on_characters() {
+        // The é would take the last byte and one more
+        let event = Event::new(0, 0, Category::Irq, format_args!("{:x<47}é", ""));
+        assert_eq!(MESSAGE_SIZE - 1, event.message().len());
+        assert!(event.message().bytes().all(|byte| byte == b'x'));
+    }
+}
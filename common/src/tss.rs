@@ -1,4 +1,12 @@
-use crate::make_bitmap;
+use core::{
+    mem::offset_of,
+    ops::{Range, RangeInclusive},
+};
+
+use crate::{error::Fault, make_bitmap, protection::PrivilegeLevel};
+
+/// Bytes of an I/O permission bitmap covering every port
+pub const FULL_IO_PERMISSION_BITMAP_SIZE: usize = 0x10000 / 8;
 
 #[allow(unused)]
 #[repr(u8)]
@@ -62,6 +70,151 @@ pub struct TaskStateSegment {
     ssp: u32,
 }
 
+/// Which ports code running above IOPL can use, one bit per port, clear to allow it. It covers the
+/// first `8 * SIZE` ports, the ones past it are always denied. The CPU reads two bytes at a time,
+/// so the bitmap ends with a byte of ones for accesses at its last ports
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct IoPermissionBitmap<const SIZE: usize> {
+    bits: [u8; SIZE],
+    terminator: u8,
+}
+
+impl<const SIZE: usize> IoPermissionBitmap<SIZE> {
+    /// Every port denied
+    pub const fn new() -> Self {
+        Self {
+            bits: [0xff; SIZE],
+            terminator: 0xff,
+        }
+    }
+
+    /// Lets code above IOPL use `ports`, all of which must be covered by the bitmap
+    pub fn allow(&mut self, ports: RangeInclusive<u16>) -> Result<(), Fault> {
+        if let Some(port) = ports.clone().find(|&port| port as usize >= 8 * SIZE) {
+            return Err(Fault::PortPastIoPermissionBitmap(port));
+        }
+        for port in ports {
+            self.bits[port as usize / 8] &= !(1 << (port % 8));
+        }
+        Ok(())
+    }
+
+    /// Takes `ports` away from code above IOPL again. The ones past the bitmap are denied anyway
+    pub fn deny(&mut self, ports: RangeInclusive<u16>) {
+        for port in ports.filter(|&port| (port as usize) < 8 * SIZE) {
+            self.bits[port as usize / 8] |= 1 << (port % 8);
+        }
+    }
+
+    pub fn is_allowed(&self, port: u16) -> bool {
+        self.bits
+            .get(port as usize / 8)
+            .is_some_and(|byte| byte & (1 << (port % 8)) == 0)
+    }
+}
+
+impl<const SIZE: usize> Default for IoPermissionBitmap<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A TSS followed by an I/O permission bitmap, which the TSS descriptor's limit has to cover,
+/// see `gdt::SegmentDescriptor::new_tss_with_io_permissions`. Both are 1-byte aligned, so the
+/// bitmap comes right after the TSS
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct TaskStateSegmentWithIoPermissions<const SIZE: usize> {
+    tss: TaskStateSegment,
+    pub io_permissions: IoPermissionBitmap<SIZE>,
+}
+
+impl<const SIZE: usize> TaskStateSegmentWithIoPermissions<SIZE> {
+    /// `tss` with every port denied, until `io_permissions` allows some
+    pub fn new(mut tss: TaskStateSegment) -> Self {
+        tss.io_permission_map_base_address = offset_of!(Self, io_permissions) as u16;
+        Self {
+            tss,
+            io_permissions: IoPermissionBitmap::new(),
+        }
+    }
+
+    pub fn tss(&self) -> &TaskStateSegment {
+        &self.tss
+    }
+}
+
+/// How many stacks of the interrupt stack table there are, numbered from 1 as gates pick them
+pub const INTERRUPT_STACKS: u8 = 7;
+
+/// The TSS of long mode, which no longer holds a task's registers: only the stacks the CPU
+/// switches to when an interrupt raises the privilege level, and the interrupt stack table that
+/// gates can pick a stack from whatever the privilege level, e.g. for double faults that may come
+/// from a stack overflow. It takes two entries of the GDT, see
+/// `gdt::SegmentDescriptor::new_long_mode_tss`
+#[derive(Default, Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct LongModeTaskStateSegment {
+    reserved1: u32,
+    privilege_stacks: [u64; 3],
+    reserved2: u64,
+    interrupt_stacks: [u64; INTERRUPT_STACKS as usize],
+    reserved3: u64,
+    reserved4: u16,
+    io_permission_map_base_address: u16,
+}
+
+impl LongModeTaskStateSegment {
+    /// No stacks, and no I/O permission bitmap
+    pub const fn blank() -> Self {
+        Self {
+            reserved1: 0,
+            privilege_stacks: [0; 3],
+            reserved2: 0,
+            interrupt_stacks: [0; INTERRUPT_STACKS as usize],
+            reserved3: 0,
+            reserved4: 0,
+            io_permission_map_base_address: size_of::<LongModeTaskStateSegment>() as u16,
+        }
+    }
+
+    /// Where the stack pointer goes when an interrupt switches to `privilege_level`, e.g. RSP0
+    /// for the ones coming from ring 3. Ring 3 has none, nothing switches to it
+    pub fn set_privilege_stack(&mut self, privilege_level: PrivilegeLevel, stack_top: u64) {
+        // Packed, no references to the field
+        let mut privilege_stacks = self.privilege_stacks;
+        if let Some(stack) = privilege_stacks.get_mut(privilege_level as usize) {
+            *stack = stack_top;
+        }
+        self.privilege_stacks = privilege_stacks;
+    }
+
+    pub fn privilege_stack(&self, privilege_level: PrivilegeLevel) -> Option<u64> {
+        let privilege_stacks = self.privilege_stacks;
+        privilege_stacks.get(privilege_level as usize).copied()
+    }
+
+    /// Where the stack pointer goes for the gates using stack `index` of the interrupt stack
+    /// table, from 1 to `INTERRUPT_STACKS`
+    pub fn set_interrupt_stack(&mut self, index: u8, stack_top: u64) -> Result<(), Fault> {
+        if !(1..=INTERRUPT_STACKS).contains(&index) {
+            return Err(Fault::NoSuchInterruptStack(index));
+        }
+        let mut interrupt_stacks = self.interrupt_stacks;
+        interrupt_stacks[index as usize - 1] = stack_top;
+        self.interrupt_stacks = interrupt_stacks;
+        Ok(())
+    }
+
+    pub fn interrupt_stack(&self, index: u8) -> Option<u64> {
+        let interrupt_stacks = self.interrupt_stacks;
+        interrupt_stacks
+            .get((index as usize).checked_sub(1)?)
+            .copied()
+    }
+}
+
 #[repr(align(16))]
 pub struct Stack<const SIZE: usize>([u8; SIZE]);
 
@@ -69,6 +222,12 @@ impl<const SIZE: usize> Stack<SIZE> {
     pub const fn new(backing_buffer: [u8; SIZE]) -> Self {
         Self(backing_buffer)
     }
+
+    /// The addresses the stack spans, its top being the end
+    pub fn range(&self) -> Range<usize> {
+        let start = self.0.as_ptr() as usize;
+        start..start + SIZE
+    }
 }
 
 impl TaskStateSegment {
@@ -130,9 +289,13 @@ impl TaskStateSegment {
 
 #[cfg(test)]
 mod tests {
+    use crate::error::Fault;
     use crate::gdt;
+    use crate::protection::PrivilegeLevel;
     use crate::tss;
-    use crate::tss::Selector;
+    use crate::tss::{
+        FULL_IO_PERMISSION_BITMAP_SIZE, IoPermissionBitmap, LongModeTaskStateSegment, Selector,
+    };
 
     #[test]
     fn _32bit_tss() {
@@ -289,10 +452,85 @@ mod tests {
         );
     }
 
+    #[test]
+    fn long_mode_tss() {
+        let mut tss = LongModeTaskStateSegment::blank();
+        tss.set_privilege_stack(PrivilegeLevel::Ring0, 0xffff_8000_0001_0000);
+        tss.set_interrupt_stack(1, 0x20_0000).unwrap();
+        tss.set_interrupt_stack(7, 0x30_0000).unwrap();
+        assert!(matches!(
+            tss.set_interrupt_stack(0, 0x40_0000),
+            Err(Fault::NoSuchInterruptStack(0))
+        ));
+        assert!(matches!(
+            tss.set_interrupt_stack(8, 0x40_0000),
+            Err(Fault::NoSuchInterruptStack(8))
+        ));
+        assert_eq!(
+            Some(0xffff_8000_0001_0000),
+            tss.privilege_stack(PrivilegeLevel::Ring0)
+        );
+        assert_eq!(None, tss.privilege_stack(PrivilegeLevel::Ring3));
+        assert_eq!(Some(0x20_0000), tss.interrupt_stack(1));
+        assert_eq!(None, tss.interrupt_stack(0));
+
+        assert_eq!(104, size_of::<LongModeTaskStateSegment>());
+        // SAFETY: all fields are plain integers, packed
+        let bytes = unsafe { core::mem::transmute::<LongModeTaskStateSegment, [u8; 104]>(tss) };
+        assert_eq!([0, 0, 0x01, 0, 0x00, 0x80, 0xff, 0xff], bytes[4..12]);
+        assert_eq!([0, 0, 0x20, 0], bytes[0x24..0x28]);
+        assert_eq!([0, 0, 0x30, 0], bytes[0x54..0x58]);
+        assert_eq!([104, 0], bytes[102..104]);
+    }
+
     #[test]
     fn selector() {
         let selector = Selector::with_index(5);
         assert_eq!(5 << 3, u8::from(selector));
         assert!(!selector.is_set(tss::SelectorBit::UseLocalDescriptorTable))
     }
+
+    #[test]
+    fn io_permission_bitmap() {
+        let mut bitmap = IoPermissionBitmap::<FULL_IO_PERMISSION_BITMAP_SIZE>::new();
+        assert!(!bitmap.is_allowed(0x3d4));
+        bitmap.allow(0x3c0..=0x3df).unwrap();
+        assert!(bitmap.is_allowed(0x3c0));
+        assert!(bitmap.is_allowed(0x3d5));
+        assert!(!bitmap.is_allowed(0x3e0));
+        bitmap.deny(0x3d4..=0x3d5);
+        assert!(!bitmap.is_allowed(0x3d4));
+        assert!(bitmap.is_allowed(0x3d6));
+        bitmap.allow(0xfffe..=0xffff).unwrap();
+        assert!(bitmap.is_allowed(0xfffe));
+        assert!(bitmap.is_allowed(0xffff));
+
+        // Only the first 128 ports
+        let mut bitmap = IoPermissionBitmap::<16>::new();
+        assert!(matches!(
+            bitmap.allow(0x70..=0x8f),
+            Err(Fault::PortPastIoPermissionBitmap(0x80))
+        ));
+        assert!(!bitmap.is_allowed(0x70));
+        assert!(!bitmap.is_allowed(0x3d4));
+    }
+
+    #[test]
+    fn tss_with_io_permissions() {
+        let mut tss =
+            tss::TaskStateSegmentWithIoPermissions::<16>::new(tss::TaskStateSegment::blank());
+        tss.io_permissions.allow(0x60..=0x60).unwrap();
+        assert_eq!(
+            size_of::<tss::TaskStateSegment>(),
+            tss.tss().io_permission_map_base_address as usize
+        );
+        // SAFETY: all fields are plain integers, packed
+        let bytes = unsafe { core::mem::transmute::<_, [u8; 108 + 16 + 1]>(tss) };
+        assert_eq!([0xfe, 0xff], bytes[108 + 12..108 + 14]);
+        assert_eq!(0xff, bytes[108 + 16]);
+
+        let tss_descriptor = gdt::SegmentDescriptor::new_tss_with_io_permissions(&tss);
+        assert_eq!(108 + 16, tss_descriptor.get_limit());
+        assert_eq!(core::ptr::addr_of!(tss) as u32, tss_descriptor.get_base());
+    }
 }
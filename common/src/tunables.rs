@@ -0,0 +1,167 @@
+// Timing knobs that used to be hardcoded where they're used: how long the ATA driver waits for
+// drives, and how long stage2's watchdog gives each boot stage. The defaults suit QEMU and the
+// drives they were tried on. Slow real hardware may need longer ones, and CI runs can go for
+// shorter ones to fail fast, so both stage2 and the kernel take overrides from the command line,
+// each in the unit its name ends with, e.g. "ata_reset_timeout_ms=10000". stage2 only gets to read
+// the command line once it loaded it, so the kernel and the payloads are read with the defaults
+use crate::command_line::CommandLine;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tunables {
+    /// How long an ATA drive has to get ready for a command, or to answer it: 1ms
+    pub ata_command_timeout_ns: u64,
+    /// How long to wait after selecting a drive or sending it a command before its status register
+    /// can be trusted: 400ns
+    // https://wiki.osdev.org/ATA_PIO_Mode#400ns_delays
+    pub ata_courtesy_delay_ns: u64,
+    /// How long drives have to spin back up after a soft reset: 5s
+    pub ata_reset_timeout_ns: u64,
+    /// How long to wait before retrying a failed read, doubled after every attempt: 1ms
+    pub ata_retry_backoff_ns: u64,
+    /// How long each boot stage of stage2 can take before the watchdog gives up on booting: 1s
+    pub boot_stage_deadline_ns: u64,
+    /// The same for the stages reading the kernel and the payloads, by far the slowest ones with
+    /// PIO, especially on real hardware: 10s
+    pub payload_read_deadline_ns: u64,
+}
+
+/// A command line option setting a field, in units of `unit_ns`
+struct Knob {
+    name: &'static str,
+    unit_ns: u64,
+    field: fn(&mut Tunables) -> &mut u64,
+}
+
+const KNOBS: [Knob; 6] = [
+    Knob {
+        name: "ata_command_timeout_us",
+        unit_ns: 1_000,
+        field: |tunables| &mut tunables.ata_command_timeout_ns,
+    },
+    Knob {
+        name: "ata_courtesy_delay_ns",
+        unit_ns: 1,
+        field: |tunables| &mut tunables.ata_courtesy_delay_ns,
+    },
+    Knob {
+        name: "ata_reset_timeout_ms",
+        unit_ns: 1_000_000,
+        field: |tunables| &mut tunables.ata_reset_timeout_ns,
+    },
+    Knob {
+        name: "ata_retry_backoff_us",
+        unit_ns: 1_000,
+        field: |tunables| &mut tunables.ata_retry_backoff_ns,
+    },
+    Knob {
+        name: "boot_stage_deadline_ms",
+        unit_ns: 1_000_000,
+        field: |tunables| &mut tunables.boot_stage_deadline_ns,
+    },
+    Knob {
+        name: "payload_read_deadline_ms",
+        unit_ns: 1_000_000,
+        field: |tunables| &mut tunables.payload_read_deadline_ns,
+    },
+];
+
+impl Tunables {
+    pub const DEFAULT: Self = Self {
+        ata_command_timeout_ns: 1_000_000,
+        ata_courtesy_delay_ns: 400,
+        ata_reset_timeout_ns: 5_000_000_000,
+        ata_retry_backoff_ns: 1_000_000,
+        boot_stage_deadline_ns: 1_000_000_000,
+        payload_read_deadline_ns: 10_000_000_000,
+    };
+
+    /// Overrides the knobs `command_line` sets. Values that aren't decimal numbers, or that don't
+    /// fit in nanoseconds, are handed to `rejected` as the whole option and leave the knob alone
+    pub fn apply_command_line<'a>(
+        &mut self,
+        command_line: &CommandLine<'a>,
+        mut rejected: impl FnMut(&'a str, &'a str),
+    ) {
+        for knob in &KNOBS {
+            let Some(value) = command_line.get(knob.name) else {
+                continue;
+            };
+            match value
+                .parse::<u64>()
+                .ok()
+                .and_then(|value| value.checked_mul(knob.unit_ns))
+            {
+                Some(value_ns) => *(knob.field)(self) = value_ns,
+                None => rejected(knob.name, value),
+            }
+        }
+    }
+}
+
+impl Default for Tunables {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+static mut TUNABLES: Tunables = Tunables::DEFAULT;
+
+pub fn get() -> Tunables {
+    let tunables_ptr = &raw const TUNABLES;
+    // SAFETY: no multitasking, no synchronization needed
+    unsafe { *tunables_ptr }
+}
+
+pub fn set_no_sync(tunables: Tunables) {
+    let tunables_ptr = &raw mut TUNABLES;
+    // SAFETY: no multitasking, no synchronization needed
+    unsafe { *tunables_ptr = tunables };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{command_line::CommandLine, tunables::Tunables};
+
+    #[test]
+    fn command_line_overrides() {
+        let mut tunables = Tunables::DEFAULT;
+        let mut rejected = [None; 2];
+        let mut rejections = 0;
+        tunables.apply_command_line(
+            &CommandLine::new(
+                "ata_command_timeout_us=20 ata_reset_timeout_ms=fast quiet \
+                 payload_read_deadline_ms=60000 boot_stage_deadline_ms=99999999999999999",
+            ),
+            |name, value| {
+                rejected[rejections] = Some((name, value));
+                rejections += 1;
+            },
+        );
+        assert_eq!(20_000, tunables.ata_command_timeout_ns);
+        assert_eq!(60_000_000_000, tunables.payload_read_deadline_ns);
+        assert_eq!(
+            Tunables::DEFAULT.ata_reset_timeout_ns,
+            tunables.ata_reset_timeout_ns
+        );
+        assert_eq!(
+            Tunables::DEFAULT.boot_stage_deadline_ns,
+            tunables.boot_stage_deadline_ns
+        );
+        assert_eq!(
+            [
+                Some(("ata_reset_timeout_ms", "fast")),
+                Some(("boot_stage_deadline_ms", "99999999999999999"))
+            ],
+            rejected
+        );
+    }
+
+    #[test]
+    fn defaults_without_options() {
+        let mut tunables = Tunables::DEFAULT;
+        tunables.apply_command_line(&CommandLine::new("console=com2"), |_, _| {
+            panic!("nothing to reject")
+        });
+        assert_eq!(Tunables::DEFAULT, tunables);
+    }
+}
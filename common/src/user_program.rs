@@ -0,0 +1,434 @@
+// Loads statically linked x86_64 executables into an address space of their own, to run in ring 3.
+// The address space shares the kernel's half of the page tables, and the program's half is
+// `layout::USER_REGIONS`: the loadable segments go where they're linked in it, with the
+// permissions their program headers ask for, and the stack at its top starts with the arguments
+// and the environment, laid out the way the System V ABI has them for `_start`
+// https://gitlab.com/x86-psABIs/x86-64-ABI, "Initial Stack and Register State"
+
+use core::ops::Range;
+
+use crate::{
+    anonymous_memory::AnonymousMemory,
+    elf::{
+        self,
+        header::{Class, Machine, ObjectType},
+        program_header::{PermissionFlag, Permissions, ProgramHeaderEntryType},
+    },
+    error::{Context, Error, Facility, Fault},
+    frame::{FRAME_SIZE, FrameAllocator},
+    layout,
+    paging::Mapper,
+    vma::{RegionFlag, RegionFlags},
+};
+
+/// Enough for the segments, the stack and what the program maps on top
+pub const MAX_USER_REGIONS: usize = 32;
+/// How much stack user programs get unless the kernel asks for another size, mapped as it's used
+pub const DEFAULT_USER_STACK_SIZE: u64 = 0x10_0000;
+const USER_PROGRAM_TARGET: elf::Target = elf::Target {
+    machine: Machine::X86_64,
+    class: Class::Elf64,
+    object_types: &[ObjectType::Executable],
+};
+const STACK_ALIGNMENT: usize = 16;
+const PML4_ENTRIES: u64 = 512;
+// How much of the address space an entry of the PML4 maps
+const PML4_ENTRY_SPAN: u64 = 1 << 39;
+
+fn loading_error(fault: Fault, facility: Facility) -> Error {
+    Error::new(fault, Context::LoadingUserProgram, facility)
+}
+
+/// How a segment with `permissions` is mapped. Pages can't be write-only, so everything is
+/// readable
+fn segment_flags(permissions: Permissions) -> RegionFlags {
+    let mut flags = RegionFlags::from(RegionFlag::User);
+    if permissions.is_set(PermissionFlag::Writable) {
+        flags.set_flag(RegionFlag::Write);
+    }
+    if permissions.is_set(PermissionFlag::Executable) {
+        flags.set_flag(RegionFlag::Execute);
+    }
+    flags
+}
+
+/// The pages of the RELRO region of `size` bytes at `start` that are made read-only, the way the
+/// dynamic linker has it: from the page it starts in to the last page it covers whole, as the
+/// linker pads its end to a page boundary
+fn relro_pages(start: u64, size: u64) -> Range<u64> {
+    let start_page = start & !(FRAME_SIZE - 1);
+    let end_page = start.saturating_add(size) & !(FRAME_SIZE - 1);
+    start_page..end_page.max(start_page)
+}
+
+/// `pages` split in the part before `relro`, the part in it and the part after it, so that the
+/// RELRO part is a region of its own. The parts may be empty
+fn split_at_relro(pages: &Range<u64>, relro: &Range<u64>) -> [Range<u64>; 3] {
+    let relro_start = relro.start.clamp(pages.start, pages.end);
+    let relro_end = relro.end.clamp(relro_start, pages.end);
+    [
+        pages.start..relro_start,
+        relro_start..relro_end,
+        relro_end..pages.end,
+    ]
+}
+
+/// Lays out `arguments` and `environment` at the top of `page`, which is mapped at
+/// `page_address`, the way `_start` expects them: argc, argv, envp and an empty auxiliary vector
+/// from the stack pointer up, each vector ending in a null pointer, and the strings they point to
+/// above them. Returns the stack pointer
+fn write_arguments(
+    page: &mut [u8],
+    page_address: u64,
+    arguments: &[&str],
+    environment: &[&str],
+) -> Result<u64, Fault> {
+    let strings_size: usize = arguments
+        .iter()
+        .chain(environment)
+        .map(|string| string.len() + 1)
+        .sum();
+    // argc, the two vectors with their null pointers, and the AT_NULL entry of the auxiliary
+    // vector, which takes two
+    let pointers_size = size_of::<u64>() * (arguments.len() + environment.len() + 5);
+    let too_large = Fault::ArgumentsTooLarge(strings_size + pointers_size);
+    let strings_start = page.len().checked_sub(strings_size).ok_or(too_large)?;
+    let stack_offset =
+        strings_start.checked_sub(pointers_size).ok_or(too_large)? & !(STACK_ALIGNMENT - 1);
+
+    let (head, strings) = page.split_at_mut(strings_start);
+    let mut slots = head[stack_offset..].chunks_exact_mut(size_of::<u64>());
+    let mut push = |value: u64| {
+        if let Some(slot) = slots.next() {
+            slot.copy_from_slice(&value.to_le_bytes());
+        }
+    };
+    push(arguments.len() as u64);
+    let mut string_offset = 0;
+    for vector in [arguments, environment] {
+        for string in vector {
+            push(page_address + (strings_start + string_offset) as u64);
+            let string_end = string_offset + string.len();
+            strings[string_offset..string_end].copy_from_slice(string.as_bytes());
+            strings[string_end] = 0;
+            string_offset = string_end + 1;
+        }
+        push(0);
+    }
+    push(0);
+    push(0);
+    Ok(page_address + stack_offset as u64)
+}
+
+/// A program in an address space of its own, ready to start in ring 3
+pub struct UserProgram {
+    pub entrypoint: u64,
+    pub stack_pointer: u64,
+    pub memory: AnonymousMemory<MAX_USER_REGIONS>,
+    address_space: Mapper,
+}
+
+impl UserProgram {
+    /// The page tables of the program, for CR3 while it runs
+    pub fn pml4_physical_address(&self) -> u64 {
+        self.address_space.pml4_physical_address()
+    }
+
+    pub fn address_space(&self) -> &Mapper {
+        &self.address_space
+    }
+
+    /// Gives the frames of the program's memory and its PML4 back to `frames`. The tables in
+    /// between stay allocated, as `paging::unmap` keeps them
+    pub fn unload<const F: usize>(mut self, frames: &mut FrameAllocator<F>) -> Result<(), Fault> {
+        while let Some(start) = self
+            .memory
+            .regions()
+            .regions()
+            .first()
+            .map(|region| region.range.start)
+        {
+            self.memory.munmap(start, &self.address_space, frames)?;
+        }
+        frames.release(self.pml4_physical_address())?;
+        Ok(())
+    }
+
+    fn load_segments<const F: usize>(
+        &mut self,
+        file: &elf::File,
+        frames: &mut FrameAllocator<F>,
+    ) -> Result<(), Error> {
+        let physical_memory_offset = self.address_space.physical_memory_offset();
+        let mut relro = None;
+        for (index, program_header) in file.program_headers().enumerate() {
+            let facility = Facility::ElfProgramHeaderEntry(index as u16);
+            let program_header = program_header?;
+            match program_header.r#type() {
+                // The stack is never executable, programs that need it to be don't get to run
+                ProgramHeaderEntryType::GnuStack
+                    if program_header
+                        .permissions()
+                        .is_set(PermissionFlag::Executable) =>
+                {
+                    return Err(loading_error(Fault::ExecutableStack, facility));
+                }
+                ProgramHeaderEntryType::GnuRelro => {
+                    let pages = relro_pages(
+                        program_header.virtual_address(),
+                        program_header.segment_size_in_memory(),
+                    );
+                    relro = Some((pages, facility));
+                }
+                _ => {}
+            }
+        }
+        let relro_range = relro.as_ref().map_or(0..0, |(pages, _)| pages.clone());
+
+        for (index, program_header) in file.program_headers().enumerate() {
+            let facility = Facility::ElfProgramHeaderEntry(index as u16);
+            let program_header = program_header?;
+            if !matches!(program_header.r#type(), ProgramHeaderEntryType::Load) {
+                continue;
+            }
+            let start = program_header.virtual_address();
+            let size = program_header.segment_size_in_memory();
+            let invalid = loading_error(
+                Fault::InvalidSegmentParameters {
+                    virtual_address: start,
+                    size,
+                },
+                facility,
+            );
+            let end = start.checked_add(size).ok_or(invalid)?;
+            let bytes = file.get_segment(&program_header).ok_or(invalid)?;
+            if bytes.len() as u64 > size
+                || start < layout::USER_REGIONS.start
+                || end > layout::USER_REGIONS.end
+            {
+                return Err(invalid);
+            }
+
+            let pages = start & !(FRAME_SIZE - 1)..end.next_multiple_of(FRAME_SIZE);
+            for part in split_at_relro(&pages, &relro_range) {
+                self.memory
+                    .reserve(part, segment_flags(program_header.permissions()))
+                    .map_err(|fault| loading_error(fault, facility))?;
+            }
+            let file_bytes = start..start + bytes.len() as u64;
+            for page in pages.step_by(FRAME_SIZE as usize) {
+                let frame = self
+                    .memory
+                    .map_zeroed_page(page, &self.address_space, frames)
+                    .map_err(|fault| loading_error(fault, facility))?;
+                // What's past the bytes in the file stays zeroed, as the .bss
+                let copied = page.max(file_bytes.start)..(page + FRAME_SIZE).min(file_bytes.end);
+                if copied.is_empty() {
+                    continue;
+                }
+                let source = &bytes[(copied.start - start) as usize..(copied.end - start) as usize];
+                let destination =
+                    physical_memory_offset.wrapping_add(frame + (copied.start - page));
+                // SAFETY: the frame was just allocated for this page, the copy stays within it,
+                // and the frame pool is mapped at the physical memory offset
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        source.as_ptr(),
+                        destination as usize as *mut u8,
+                        source.len(),
+                    )
+                };
+            }
+        }
+
+        // Statically linked programs come with no relocations to apply, so the RELRO region is
+        // done being written to as soon as it's loaded. It has to be in a segment, which made a
+        // region of it above
+        if let Some((pages, facility)) = relro
+            && !pages.is_empty()
+        {
+            self.memory
+                .mprotect(pages.start, RegionFlag::User.into(), &self.address_space)
+                .map_err(|fault| loading_error(fault, facility))?;
+        }
+        Ok(())
+    }
+
+    fn set_up_stack<const F: usize>(
+        &mut self,
+        arguments: &[&str],
+        environment: &[&str],
+        stack_size: u64,
+        frames: &mut FrameAllocator<F>,
+    ) -> Result<(), Fault> {
+        let stack: Range<u64> = layout::USER_REGIONS.end - stack_size..layout::USER_REGIONS.end;
+        self.memory
+            .reserve(stack.clone(), RegionFlag::Write | RegionFlag::User)?;
+        // The rest of the stack is mapped as it grows into it
+        let top_page = stack.end - FRAME_SIZE;
+        let frame = self
+            .memory
+            .map_zeroed_page(top_page, &self.address_space, frames)?;
+        let page_address = self
+            .address_space
+            .physical_memory_offset()
+            .wrapping_add(frame);
+        // SAFETY: the frame was just allocated for the page, and the frame pool is mapped at the
+        // physical memory offset
+        let page = unsafe {
+            core::slice::from_raw_parts_mut(page_address as usize as *mut u8, FRAME_SIZE as usize)
+        };
+        self.stack_pointer = write_arguments(page, top_page, arguments, environment)?;
+        Ok(())
+    }
+}
+
+/// Loads the executable in `bytes` into a new address space, sharing the kernel's half with the
+/// page tables of `kernel`, with `arguments` and `environment` on its stack of `stack_size` bytes,
+/// a multiple of the page size. The address space and the program's memory come from `frames`
+pub fn load<const F: usize>(
+    bytes: &[u8],
+    arguments: &[&str],
+    environment: &[&str],
+    stack_size: u64,
+    kernel: &Mapper,
+    frames: &mut FrameAllocator<F>,
+) -> Result<UserProgram, Error> {
+    let file = elf::File::try_from(bytes)?;
+    file.check_target(&USER_PROGRAM_TARGET)
+        .map_err(|err| loading_error(err.fault(), Facility::ElfHeader))?;
+
+    let pml4 = frames
+        .allocate()
+        .ok_or(loading_error(Fault::OutOfFrames, Facility::UserProgram))?;
+    let physical_memory_offset = kernel.physical_memory_offset();
+    let pml4_ptr = physical_memory_offset.wrapping_add(pml4) as usize as *mut u64;
+    let kernel_pml4_ptr =
+        physical_memory_offset.wrapping_add(kernel.pml4_physical_address()) as usize as *const u64;
+    // SAFETY: the frame was just allocated, the kernel's PML4 is a whole frame too, and both are
+    // mapped at the physical memory offset
+    unsafe { core::ptr::copy_nonoverlapping(kernel_pml4_ptr, pml4_ptr, PML4_ENTRIES as usize) };
+    // Whatever the kernel may have in the program's half isn't the program's
+    for index in layout::USER_REGIONS.start / PML4_ENTRY_SPAN
+        ..layout::USER_REGIONS.end.div_ceil(PML4_ENTRY_SPAN)
+    {
+        // SAFETY: see above, and the index is within the PML4
+        unsafe { pml4_ptr.wrapping_add(index as usize).write(0) };
+    }
+
+    let mut program = UserProgram {
+        entrypoint: file.header().entrypoint(),
+        stack_pointer: 0,
+        memory: AnonymousMemory::new(layout::USER_REGIONS),
+        address_space: Mapper::new(pml4, physical_memory_offset),
+    };
+    let result = program.load_segments(&file, frames).and_then(|()| {
+        program
+            .set_up_stack(arguments, environment, stack_size, frames)
+            .map_err(|fault| loading_error(fault, Facility::UserProgram))
+    });
+    let entrypoint = program.entrypoint;
+    let executable = program
+        .memory
+        .regions()
+        .overlapping(&(entrypoint..entrypoint + 1))
+        .is_some_and(|region| region.flags.is_set(RegionFlag::Execute));
+    let result = result.and_then(|()| {
+        if executable {
+            Ok(())
+        } else {
+            Err(loading_error(
+                Fault::InvalidValueForField("entrypoint"),
+                Facility::ElfHeader,
+            ))
+        }
+    });
+    match result {
+        Ok(()) => Ok(program),
+        Err(error) => {
+            // The error that matters is the one that stopped the loading
+            let _ = program.unload(frames);
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        elf::program_header::{PermissionFlag, Permissions},
+        error::Fault,
+        user_program::{relro_pages, segment_flags, split_at_relro, write_arguments},
+        vma::{RegionFlag::*, RegionFlags},
+    };
+
+    fn read_u64(page: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(page[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn arguments_on_the_stack() {
+        let mut page = [0xAAu8; 256];
+        let stack_pointer =
+            write_arguments(&mut page, 0x1000, &["init", "-v"], &["TERM=vt100"]).unwrap();
+        // 19 bytes of strings from 237, under them 8 words, aligned down to 16
+        assert_eq!(0x1000 + 160, stack_pointer);
+        let words: [u64; 8] = core::array::from_fn(|index| read_u64(&page, 160 + index * 8));
+        assert_eq!(
+            [2, 0x1000 + 237, 0x1000 + 242, 0, 0x1000 + 245, 0, 0, 0],
+            words
+        );
+        assert_eq!(b"init\0-v\0TERM=vt100\0", &page[237..]);
+
+        let mut page = [0u8; 32];
+        assert!(matches!(
+            write_arguments(&mut page, 0x1000, &["init"], &[]),
+            Err(Fault::ArgumentsTooLarge(53))
+        ));
+    }
+
+    #[test]
+    fn segment_permissions() {
+        let text = Permissions::from(PermissionFlag::Readable | PermissionFlag::Executable);
+        assert_eq!(User | Execute, segment_flags(text));
+        let data = Permissions::from(PermissionFlag::Readable | PermissionFlag::Writable);
+        assert_eq!(User | Write, segment_flags(data));
+        assert_eq!(
+            RegionFlags::from(User),
+            segment_flags(PermissionFlag::Readable.into())
+        );
+    }
+
+    #[test]
+    fn relro_regions() {
+        // .data.rel.ro from the middle of a page, padded to the end of the next one
+        assert_eq!(0x40_1000..0x40_3000, relro_pages(0x40_1e40, 0x11c0));
+        // Less than a page, nothing covered whole
+        assert!(relro_pages(0x40_1e40, 0x100).is_empty());
+
+        let pages = 0x40_1000..0x40_6000;
+        assert_eq!(
+            [
+                0x40_1000..0x40_1000,
+                0x40_1000..0x40_3000,
+                0x40_3000..0x40_6000
+            ],
+            split_at_relro(&pages, &(0x40_1000..0x40_3000))
+        );
+        assert_eq!(
+            [
+                0x40_1000..0x40_2000,
+                0x40_2000..0x40_3000,
+                0x40_3000..0x40_6000
+            ],
+            split_at_relro(&pages, &(0x40_2000..0x40_3000))
+        );
+        // Segments without RELRO stay whole
+        let [before, relro, after] = split_at_relro(&pages, &(0..0));
+        assert!(before.is_empty() && relro.is_empty());
+        assert_eq!(pages, after);
+        let [before, relro, after] = split_at_relro(&(0x1000..0x2000), &pages);
+        assert_eq!(0x1000..0x2000, before);
+        assert!(relro.is_empty() && after.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+// Who has which of the 256 interrupt vectors, so that the PICs, the local APIC, the ATA channels
+// and the PCI functions with message signaled interrupts don't end up delivered on the same one.
+// Vectors 0-31 are the CPU's exceptions, which nobody allocates, the PICs' IRQs come right after
+// them once remapped, and the rest is handed out on demand, lowest first, but for the few fixed
+// vectors at the top, like the local APIC's spurious one
+use core::ops::Range;
+
+use crate::{
+    error::Fault,
+    idt::STANDARD_VECTOR_TABLE_SIZE,
+    pic::{self, Irq},
+};
+
+/// The CPU's exceptions, reserved from the start
+pub const EXCEPTION_VECTORS: Range<u8> = 0..pic::PIC_1_OFFSET;
+/// Where the PICs deliver their IRQs, one vector each
+pub const LEGACY_IRQ_VECTORS: Range<u8> = pic::PIC_1_OFFSET..pic::PIC_2_OFFSET + 8;
+/// What `allocate` hands out, e.g. for MSI and MSI-X
+pub const DYNAMIC_VECTORS: Range<u8> = LEGACY_IRQ_VECTORS.end..0xF0;
+
+/// The 256 vectors of the IDT and who each of the taken ones went to
+pub struct Vectors {
+    owners: [Option<&'static str>; STANDARD_VECTOR_TABLE_SIZE],
+}
+
+impl Vectors {
+    /// Every vector free, but the exceptions
+    pub const fn new() -> Self {
+        let mut owners = [None; STANDARD_VECTOR_TABLE_SIZE];
+        let mut vector = EXCEPTION_VECTORS.start;
+        while vector < EXCEPTION_VECTORS.end {
+            owners[vector as usize] = Some("exception");
+            vector += 1;
+        }
+        Self { owners }
+    }
+
+    /// Who has `vector`, None if it's free
+    pub fn owner(&self, vector: u8) -> Option<&'static str> {
+        self.owners[vector as usize]
+    }
+
+    pub fn is_free(&self, vector: u8) -> bool {
+        self.owner(vector).is_none()
+    }
+
+    /// Gives `owner` a vector whose number is fixed, outside of the exceptions
+    pub fn reserve(&mut self, vector: u8, owner: &'static str) -> Result<(), Fault> {
+        let slot = &mut self.owners[vector as usize];
+        if slot.is_some() {
+            return Err(Fault::VectorTaken(vector));
+        }
+        *slot = Some(owner);
+        Ok(())
+    }
+
+    /// Gives `owner` the vector the PICs deliver `irq` on, returning it
+    pub fn reserve_irq(&mut self, irq: Irq, owner: &'static str) -> Result<u8, Fault> {
+        let vector = irq.vector();
+        self.reserve(vector, owner)?;
+        Ok(vector)
+    }
+
+    /// Gives `owner` the lowest free vector of `DYNAMIC_VECTORS`
+    pub fn allocate(&mut self, owner: &'static str) -> Result<u8, Fault> {
+        let vector = DYNAMIC_VECTORS
+            .clone()
+            .find(|&vector| self.is_free(vector))
+            .ok_or(Fault::OutOfVectors)?;
+        self.owners[vector as usize] = Some(owner);
+        Ok(vector)
+    }
+
+    /// Makes `vector` free again, whoever had it. The exceptions stay reserved
+    pub fn release(&mut self, vector: u8) -> Result<(), Fault> {
+        if EXCEPTION_VECTORS.contains(&vector) || self.is_free(vector) {
+            return Err(Fault::VectorNotTaken(vector));
+        }
+        self.owners[vector as usize] = None;
+        Ok(())
+    }
+}
+
+impl Default for Vectors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Fault,
+        pic::Irq,
+        vectors::{DYNAMIC_VECTORS, EXCEPTION_VECTORS, LEGACY_IRQ_VECTORS, Vectors},
+    };
+
+    #[test]
+    fn ranges_dont_overlap() {
+        assert_eq!(EXCEPTION_VECTORS.end, LEGACY_IRQ_VECTORS.start);
+        assert_eq!(LEGACY_IRQ_VECTORS.end, DYNAMIC_VECTORS.start);
+        assert!(LEGACY_IRQ_VECTORS.contains(&Irq::SecondaryAta.vector()));
+    }
+
+    #[test]
+    fn reservations_and_allocations() {
+        let mut vectors = Vectors::new();
+        assert_eq!(Some("exception"), vectors.owner(14));
+        assert!(matches!(
+            vectors.reserve(14, "page faults"),
+            Err(Fault::VectorTaken(14))
+        ));
+        assert!(matches!(
+            vectors.release(14),
+            Err(Fault::VectorNotTaken(14))
+        ));
+
+        assert_eq!(46, vectors.reserve_irq(Irq::PrimaryAta, "ATA").unwrap());
+        assert!(matches!(
+            vectors.reserve_irq(Irq::PrimaryAta, "network card"),
+            Err(Fault::VectorTaken(46))
+        ));
+        assert_eq!(Some("ATA"), vectors.owner(46));
+        vectors.reserve(0xFF, "spurious").unwrap();
+
+        // Lowest first, and released ones are handed out again
+        assert_eq!(DYNAMIC_VECTORS.start, vectors.allocate("MSI-X").unwrap());
+        assert_eq!(DYNAMIC_VECTORS.start + 1, vectors.allocate("MSI").unwrap());
+        vectors.release(DYNAMIC_VECTORS.start).unwrap();
+        assert!(vectors.is_free(DYNAMIC_VECTORS.start));
+        assert_eq!(DYNAMIC_VECTORS.start, vectors.allocate("MSI-X").unwrap());
+        assert!(matches!(
+            vectors.release(DYNAMIC_VECTORS.start + 2),
+            Err(Fault::VectorNotTaken(_))
+        ));
+
+        while vectors.allocate("MSI").is_ok() {}
+        assert!(
+            DYNAMIC_VECTORS
+                .clone()
+                .all(|vector| !vectors.is_free(vector))
+        );
+        assert!(matches!(vectors.allocate("MSI"), Err(Fault::OutOfVectors)));
+        // Fixed vectors above the dynamic ones are left alone
+        assert!(vectors.is_free(DYNAMIC_VECTORS.end));
+    }
+}
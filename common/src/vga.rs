@@ -1,8 +1,17 @@
+// The VGA text console. The default writer doesn't write to the screen directly but to a shadow
+// buffer, which `flush_no_sync` copies to the screen as a whole, so that it never shows half of a
+// line or of a scroll. Lines are flushed as they're written, unless the console is batching, then
+// it's up to e.g. the timer interrupt to flush them. Fault handlers and the panic path stop the
+// batching, so that what they write is on the screen even if they never return
 use core::{
     fmt::Write,
+    marker::PhantomData,
     ptr::{addr_of, addr_of_mut},
 };
 
+#[cfg(not(test))]
+use crate::interrupts;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 #[allow(unused)]
@@ -47,29 +56,110 @@ const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
 #[repr(transparent)]
-struct Buffer {
+pub struct Buffer {
     chars: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+impl Buffer {
+    /// An in-memory buffer filled with blanks, suitable for capturing output
+    pub const fn blank() -> Self {
+        Self {
+            chars: [[ScreenChar {
+                ascii_character: b' ',
+                color_code: ColorCode::new(Color::White, Color::Black),
+            }; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        }
+    }
+
+    /// Returns the ASCII characters of the given row, or None if the row is out of bounds
+    pub fn row(&self, row: usize) -> Option<[u8; BUFFER_WIDTH]> {
+        let chars = self.chars.get(row)?;
+        let mut result = [0u8; BUFFER_WIDTH];
+        for (col, byte) in result.iter_mut().enumerate() {
+            // SAFETY: the pointer comes from a reference, so it's valid and aligned. Reading it
+            // volatile keeps the compiler from caching the contents of the memory mapped buffer
+            *byte = unsafe { core::ptr::read_volatile(&raw const chars[col]) }.ascii_character;
+        }
+        Some(result)
+    }
+
+    /// Whether any row of the buffer contains the given string
+    pub fn contains(&self, needle: &str) -> bool {
+        let needle = needle.as_bytes();
+        if needle.is_empty() {
+            return true;
+        }
+        (0..BUFFER_HEIGHT)
+            .filter_map(|row| self.row(row))
+            .any(|row| row.windows(needle.len()).any(|window| window == needle))
+    }
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Self::blank()
+    }
+}
+
 // Buffer has the same layout as Buffer.chars, and each element of Buffer.chars has the same layout
 // as u16
 const VGA_BUF: *mut Buffer = 0xb8000 as *mut Buffer;
 
-pub struct Writer {
+// Host-side unit tests can't touch the VGA memory, so the default writer prints into memory instead
+#[cfg(test)]
+static mut TEST_BUFFER: Buffer = Buffer::blank();
+
+/// Copies every character of `from` into `to`, both of which may be the VGA memory
+fn copy(from: *const Buffer, to: *mut Buffer) {
+    for row in 0..BUFFER_HEIGHT {
+        for col in 0..BUFFER_WIDTH {
+            // SAFETY: row and col are within bounds
+            let from_ptr = unsafe { addr_of!((*from).chars[row][col]) };
+            // SAFETY: row and col are within bounds
+            let to_ptr = unsafe { addr_of_mut!((*to).chars[row][col]) };
+            // SAFETY: both pointers are in bounds of a buffer, and reading and writing them
+            // volatile keeps the compiler from caching the contents of the memory mapped buffer
+            let screen_char = unsafe { core::ptr::read_volatile(from_ptr) };
+            // SAFETY: see above
+            unsafe { core::ptr::write_volatile(to_ptr, screen_char) };
+        }
+    }
+}
+
+pub struct Writer<'a> {
     column_position: usize,
     color_code: ColorCode,
     buffer: *mut Buffer,
+    _buffer: PhantomData<&'a mut Buffer>,
 }
 
-impl Writer {
+impl Writer<'static> {
     pub const fn new() -> Self {
         Self {
             column_position: 0,
             color_code: ColorCode::new(Color::White, Color::Black),
             buffer: VGA_BUF,
+            _buffer: PhantomData,
+        }
+    }
+}
+
+impl<'a> Writer<'a> {
+    /// A writer printing into the given buffer instead of the VGA memory, e.g. to capture output
+    pub fn with_buffer(buffer: &'a mut Buffer) -> Self {
+        Self {
+            column_position: 0,
+            color_code: ColorCode::new(Color::White, Color::Black),
+            buffer,
+            _buffer: PhantomData,
         }
     }
 
+    /// The color of what's written from now on
+    pub fn set_foreground(&mut self, foreground: Color) {
+        self.color_code = ColorCode::new(foreground, Color::Black);
+    }
+
     fn write_screen_char(&mut self, row: usize, col: usize, screen_char: ScreenChar) {
         if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
             return;
@@ -157,28 +247,157 @@ impl Writer {
     }
 }
 
-impl Default for Writer {
+impl Default for Writer<'static> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl core::fmt::Write for Writer {
+impl core::fmt::Write for Writer<'_> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         self.write_string(s);
         Ok(())
     }
 }
 
-static mut DEFAULT_SINGLE_TASK_WRITER: Writer = Writer::new();
+static mut SHADOW_BUFFER: Buffer = Buffer::blank();
 
-pub fn __writeln_no_sync(args: core::fmt::Arguments) -> core::fmt::Result {
-    // SAFETY: no multitasking, no synchronization needed
-    let writer_ptr = &raw mut DEFAULT_SINGLE_TASK_WRITER;
+static mut DEFAULT_SINGLE_TASK_WRITER: Writer<'static> = Writer {
+    column_position: 0,
+    color_code: ColorCode::new(Color::White, Color::Black),
+    buffer: &raw mut SHADOW_BUFFER,
+    _buffer: PhantomData,
+};
+
+struct Console {
+    /// Where the shadow buffer is flushed to: the VGA memory, or a buffer capturing the output
+    target: *mut Buffer,
+    /// Whether the shadow buffer was filled with what the target had, which happens on first use
+    /// so that what was on the screen before stays there
+    loaded: bool,
+    /// Whether the shadow buffer has changes the target doesn't
+    dirty: bool,
+    batching: bool,
+}
+
+static mut CONSOLE: Console = Console {
+    #[cfg(not(test))]
+    target: VGA_BUF,
+    #[cfg(test)]
+    target: &raw mut TEST_BUFFER,
+    loaded: false,
+    dirty: false,
+    batching: false,
+};
+
+/// Runs `f` with interrupts disabled, as their handlers write to the console too: their lines
+/// would land in the middle of the one being written, and their flushes would show half of it
+fn atomically<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(not(test))]
+    {
+        interrupts::without_interrupts(f)
+    }
+    // Host-side unit tests can't touch the interrupt flag
+    #[cfg(test)]
+    {
+        f()
+    }
+}
+
+fn console_no_sync() -> &'static mut Console {
+    let console_ptr = &raw mut CONSOLE;
     // SAFETY: no multitasking, no synchronization needed
-    let writer = unsafe { &mut *writer_ptr };
-    writer.write_fmt(args)?;
-    writeln!(writer)
+    let console = unsafe { &mut *console_ptr };
+    if !console.loaded {
+        copy(console.target, &raw mut SHADOW_BUFFER);
+        console.loaded = true;
+    }
+    console
+}
+
+fn flush(console: &mut Console) {
+    if console.dirty {
+        copy(&raw const SHADOW_BUFFER, console.target);
+        console.dirty = false;
+    }
+}
+
+/// Puts what was written since the last flush on the screen
+pub fn flush_no_sync() {
+    atomically(|| flush(console_no_sync()));
+}
+
+/// Whether lines are left in the shadow buffer until the next `flush_no_sync`, instead of being
+/// flushed as they're written. Turning it off flushes what was left
+pub fn set_batching_no_sync(batching: bool) {
+    atomically(|| {
+        let console = console_no_sync();
+        console.batching = batching;
+        flush(console);
+    });
+}
+
+/// Flushes what was written so far to `target`, and has the default writer start over on what
+/// `target` shows
+fn retarget_no_sync(target: *mut Buffer) {
+    atomically(|| {
+        let console = console_no_sync();
+        flush(console);
+        console.target = target;
+        console.loaded = false;
+        let writer_ptr = &raw mut DEFAULT_SINGLE_TASK_WRITER;
+        // SAFETY: no multitasking, no synchronization needed
+        unsafe { (*writer_ptr).column_position = 0 };
+    });
+}
+
+/// Redirects the output of the default writer into the given buffer, e.g. so that QEMU based tests
+/// can scrape the console output from a known location in memory
+pub fn capture_no_sync(buffer: &'static mut Buffer) {
+    retarget_no_sync(buffer);
+}
+
+/// Points the default writer back to the VGA memory
+pub fn release_capture_no_sync() {
+    retarget_no_sync(VGA_BUF);
+}
+
+/// Runs `f` on the buffer the default writer is currently printing into, flushed. Nothing is
+/// written to it until `f` returns
+pub fn with_default_buffer_no_sync<R>(f: impl FnOnce(&Buffer) -> R) -> R {
+    atomically(|| {
+        let console = console_no_sync();
+        flush(console);
+        // SAFETY: the target is either the VGA memory or a buffer that was handed over with a
+        // 'static lifetime. The default writer only writes to it atomically, so not before `f`
+        // returns, and Buffer reads its characters volatile, as the VGA memory is memory mapped
+        f(unsafe { &*console.target })
+    })
+}
+
+/// Runs `f` on the default writer, e.g. to write in colors. They're reset afterwards, and what
+/// `f` wrote is flushed unless the console is batching
+pub fn with_default_writer_no_sync<R>(f: impl FnOnce(&mut Writer<'static>) -> R) -> R {
+    atomically(|| {
+        let console = console_no_sync();
+        let writer_ptr = &raw mut DEFAULT_SINGLE_TASK_WRITER;
+        // SAFETY: no multitasking, no synchronization needed
+        let writer = unsafe { &mut *writer_ptr };
+        let result = f(writer);
+        writer.set_foreground(Color::White);
+        console.dirty = true;
+        if !console.batching {
+            flush(console);
+        }
+        result
+    })
+}
+
+pub fn __writeln_no_sync(args: core::fmt::Arguments) -> core::fmt::Result {
+    with_default_writer_no_sync(|writer| {
+        writer.write_fmt(args)?;
+        writeln!(writer)
+    })
 }
 
 #[macro_export]
@@ -189,3 +408,59 @@ macro_rules! vga_writeln_no_sync {
 }
 
 pub use vga_writeln_no_sync as writeln_no_sync;
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Write;
+
+    use crate::vga::{self, BUFFER_HEIGHT, Buffer, Writer};
+
+    #[test]
+    fn capture_into_buffer() {
+        let mut buffer = Buffer::blank();
+        let mut writer = Writer::with_buffer(&mut buffer);
+        writeln!(writer, "Hello from stage2!").unwrap();
+        write!(writer, "Read kernel from disk!").unwrap();
+
+        assert!(buffer.contains("Hello from stage2!"));
+        assert!(buffer.contains("Read kernel from disk!"));
+        assert!(!buffer.contains("Loaded kernel segments"));
+
+        let last_row = buffer.row(BUFFER_HEIGHT - 1).unwrap();
+        assert_eq!(b"Read kernel from disk! ", &last_row[..23]);
+        assert!(buffer.row(BUFFER_HEIGHT).is_none());
+    }
+
+    #[test]
+    fn non_printable_bytes_are_replaced() {
+        let mut buffer = Buffer::blank();
+        let mut writer = Writer::with_buffer(&mut buffer);
+        writer.write_string("a\tb");
+
+        assert_eq!(b"a\xfeb", &buffer.row(BUFFER_HEIGHT - 1).unwrap()[..3]);
+    }
+
+    // One test for everything going through the default writer, as they share it
+    #[test]
+    fn default_writer_prints_into_memory() {
+        vga::writeln_no_sync!("captured {}", 42);
+        assert!(vga::with_default_buffer_no_sync(
+            |buffer| buffer.contains("captured 42")
+        ));
+
+        let target_ptr = &raw const vga::TEST_BUFFER;
+        // SAFETY: nothing else writes to the test buffer while this test reads it
+        let target = || unsafe { &*target_ptr };
+        vga::set_batching_no_sync(true);
+        vga::writeln_no_sync!("batched line");
+        assert!(!target().contains("batched line"));
+        vga::flush_no_sync();
+        assert!(target().contains("batched line"));
+
+        vga::writeln_no_sync!("left over");
+        vga::set_batching_no_sync(false);
+        assert!(target().contains("left over"));
+        vga::writeln_no_sync!("immediate");
+        assert!(target().contains("immediate"));
+    }
+}
@@ -0,0 +1,478 @@
+// Virtio over PCI: the 1.x interface through vendor capabilities, with the legacy (0.9.5) I/O
+// port interface of transitional devices as a fallback
+// https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.html
+// https://ozlabs.org/~rusty/virtio-spec/virtio-0.9.5.pdf
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    dma,
+    error::Fault,
+    ioport::Port,
+    make_bitmap,
+    msr::MemoryType,
+    paging,
+    pci_capability::VENDOR_SPECIFIC_CAPABILITY,
+    pci_function::{BaseAddress, Function},
+};
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+/// The device only works the way the 1.x spec describes, which modern devices require
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// Legacy registers, relative to the I/O BAR0
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+// Where the device specific configuration starts while MSI-X is disabled
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+const LEGACY_QUEUE_ADDRESS_SHIFT: u32 = 12;
+// Legacy devices take the queue address as a 32-bit page number
+const LEGACY_QUEUE_LIMIT: u64 = 1 << (32 + LEGACY_QUEUE_ADDRESS_SHIFT);
+
+// Common configuration structure registers
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFFSET: u64 = 0x1E;
+const COMMON_QUEUE_DESCRIPTOR: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+// Offsets within a virtio PCI capability
+const CAPABILITY_CONFIGURATION_TYPE: u8 = 3;
+const CAPABILITY_BAR: u8 = 4;
+const CAPABILITY_OFFSET: u8 = 8;
+const CAPABILITY_NOTIFY_OFFSET_MULTIPLIER: u8 = 16;
+
+const RESET_POLLS: usize = 1_000_000;
+
+/// Largest queue `Virtqueue` supports, and the size of most legacy devices' queues
+pub const MAX_QUEUE_SIZE: u16 = 256;
+const PAGE_SIZE: usize = 4096;
+const DESCRIPTOR_SIZE: usize = 16;
+const DESCRIPTOR_WRITE: u16 = 2;
+// Flags and index before the rings
+const RING_HEADER_SIZE: usize = 4;
+const AVAILABLE_RING_ELEMENT_SIZE: usize = 2;
+const USED_RING_ELEMENT_SIZE: usize = 8;
+// The event index after each ring, unused without VIRTIO_F_EVENT_IDX
+const RING_FOOTER_SIZE: usize = 2;
+/// Memory needed by a queue of MAX_QUEUE_SIZE, in the legacy layout
+pub const QUEUE_MEMORY_SIZE: usize =
+    used_ring_offset(MAX_QUEUE_SIZE) + used_ring_size(MAX_QUEUE_SIZE).next_multiple_of(PAGE_SIZE);
+
+#[allow(unused)]
+#[repr(u8)]
+pub enum DeviceStatusFlag {
+    Acknowledge = 1 << 0,
+    Driver = 1 << 1,
+    DriverOk = 1 << 2,
+    FeaturesOk = 1 << 3,
+    DeviceNeedsReset = 1 << 6,
+    Failed = 1 << 7,
+}
+
+make_bitmap!(new_type: DeviceStatus, underlying_flag_type: DeviceStatusFlag, repr: u8, nodisplay);
+
+#[derive(TryFromPrimitive, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum ConfigurationType {
+    Common = 1,
+    Notify = 2,
+    Isr = 3,
+    Device = 4,
+}
+
+fn read_mmio<T: Copy>(address: u64) -> T {
+    // SAFETY: addresses come from the configuration structures found by `Transport::new`, which
+    // are in BARs it identity mapped as uncacheable
+    unsafe { (address as usize as *const T).read_volatile() }
+}
+
+fn write_mmio<T: Copy>(address: u64, value: T) {
+    // SAFETY: see `read_mmio`
+    unsafe { (address as usize as *mut T).write_volatile(value) }
+}
+
+/// How the driver talks to the device
+#[derive(Debug, Clone, Copy)]
+pub enum Transport {
+    Legacy {
+        io_base: u16,
+    },
+    Modern {
+        common: u64,
+        notify: u64,
+        notify_offset_multiplier: u32,
+        isr: u64,
+        device: u64,
+    },
+}
+
+impl Transport {
+    /// Picks the modern interface if the device has all its configuration structures in memory
+    /// BARs, which are identity mapped as uncacheable, and the legacy one otherwise
+    pub fn new(function: &Function) -> Result<Self, Fault> {
+        let mut addresses = [None; 4];
+        let mut notify_offset_multiplier = 0;
+        for capability in function
+            .capabilities()
+            .filter(|capability| capability.id == VENDOR_SPECIFIC_CAPABILITY)
+        {
+            let Ok(configuration_type) = ConfigurationType::try_from(
+                function.read_config_byte(capability.offset + CAPABILITY_CONFIGURATION_TYPE),
+            ) else {
+                continue;
+            };
+            let slot = &mut addresses[configuration_type as usize - 1];
+            // The first structure of a type is the preferred one
+            if slot.is_some() {
+                continue;
+            }
+            let Some(BaseAddress::Memory(base_address)) = function
+                .base_address(function.read_config_byte(capability.offset + CAPABILITY_BAR))
+            else {
+                continue;
+            };
+            *slot = Some(
+                base_address + function.read_config(capability.offset + CAPABILITY_OFFSET) as u64,
+            );
+            if configuration_type == ConfigurationType::Notify {
+                notify_offset_multiplier =
+                    function.read_config(capability.offset + CAPABILITY_NOTIFY_OFFSET_MULTIPLIER);
+            }
+        }
+
+        if let [Some(common), Some(notify), Some(isr), Some(device)] = addresses {
+            for address in [common, notify, isr, device] {
+                paging::Mapper::active().identity_map_gigabyte(address, MemoryType::Uncacheable)?;
+            }
+            return Ok(Self::Modern {
+                common,
+                notify,
+                notify_offset_multiplier,
+                isr,
+                device,
+            });
+        }
+
+        match function.base_address(0) {
+            Some(BaseAddress::Io(io_base)) => Ok(Self::Legacy { io_base }),
+            _ => Err(Fault::InvalidValueForField("BAR0")),
+        }
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Self::Modern { .. })
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        DeviceStatus::from(match *self {
+            Self::Legacy { io_base } => Port::new(io_base + LEGACY_DEVICE_STATUS).readb(),
+            Self::Modern { common, .. } => read_mmio(common + COMMON_DEVICE_STATUS),
+        })
+    }
+
+    pub fn set_status(&self, status: DeviceStatus) {
+        match *self {
+            Self::Legacy { io_base } => {
+                Port::new(io_base + LEGACY_DEVICE_STATUS).writeb(status.into())
+            }
+            Self::Modern { common, .. } => {
+                write_mmio::<u8>(common + COMMON_DEVICE_STATUS, status.into())
+            }
+        }
+    }
+
+    /// Sets `flag` on top of the current status
+    pub fn add_status(&self, flag: DeviceStatusFlag) {
+        let mut status = self.status();
+        status.set_flag(flag);
+        self.set_status(status);
+    }
+
+    /// Resets the device, which forgets the negotiated features and queues
+    pub fn reset(&self) -> Result<(), Fault> {
+        self.set_status(DeviceStatus::empty());
+        // Modern devices may take a while, and say so by not reading back 0 until they're done
+        if (0..RESET_POLLS).any(|_| u8::from(self.status()) == 0) {
+            Ok(())
+        } else {
+            Err(Fault::HangingNetworkCard)
+        }
+    }
+
+    /// Legacy devices only have 32 feature bits
+    pub fn device_features(&self) -> u64 {
+        match *self {
+            Self::Legacy { io_base } => Port::new(io_base + LEGACY_DEVICE_FEATURES).readd() as u64,
+            Self::Modern { common, .. } => {
+                write_mmio::<u32>(common + COMMON_DEVICE_FEATURE_SELECT, 0);
+                let low: u32 = read_mmio(common + COMMON_DEVICE_FEATURE);
+                write_mmio::<u32>(common + COMMON_DEVICE_FEATURE_SELECT, 1);
+                let high: u32 = read_mmio(common + COMMON_DEVICE_FEATURE);
+                (high as u64) << 32 | low as u64
+            }
+        }
+    }
+
+    pub fn set_driver_features(&self, features: u64) {
+        match *self {
+            Self::Legacy { io_base } => {
+                Port::new(io_base + LEGACY_DRIVER_FEATURES).writed(features as u32)
+            }
+            Self::Modern { common, .. } => {
+                write_mmio::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, 0);
+                write_mmio(common + COMMON_DRIVER_FEATURE, features as u32);
+                write_mmio::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, 1);
+                write_mmio(common + COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            }
+        }
+    }
+
+    /// The size of queue `index`, 0 if the device doesn't have it. Modern devices accept
+    /// smaller queues, legacy ones must be given a queue of exactly this size
+    pub fn queue_size(&self, index: u16) -> u16 {
+        match *self {
+            Self::Legacy { io_base } => {
+                Port::new(io_base + LEGACY_QUEUE_SELECT).writew(index);
+                Port::new(io_base + LEGACY_QUEUE_SIZE).readw()
+            }
+            Self::Modern { common, .. } => {
+                write_mmio(common + COMMON_QUEUE_SELECT, index);
+                read_mmio(common + COMMON_QUEUE_SIZE)
+            }
+        }
+    }
+
+    /// Hands `queue` over to the device, to be done before setting DRIVER_OK
+    pub fn setup_queue(&self, queue: &mut Virtqueue) -> Result<(), Fault> {
+        let device_size = self.queue_size(queue.index);
+        match *self {
+            Self::Legacy { io_base } => {
+                if device_size != queue.size {
+                    return Err(Fault::UnsupportedQueueSize(device_size));
+                }
+                Port::new(io_base + LEGACY_QUEUE_SELECT).writew(queue.index);
+                Port::new(io_base + LEGACY_QUEUE_ADDRESS)
+                    .writed((queue.physical_address >> LEGACY_QUEUE_ADDRESS_SHIFT) as u32);
+            }
+            Self::Modern { common, .. } => {
+                if device_size < queue.size {
+                    return Err(Fault::UnsupportedQueueSize(device_size));
+                }
+                write_mmio(common + COMMON_QUEUE_SELECT, queue.index);
+                write_mmio(common + COMMON_QUEUE_SIZE, queue.size);
+                write_mmio(common + COMMON_QUEUE_DESCRIPTOR, queue.physical_address);
+                write_mmio(
+                    common + COMMON_QUEUE_DRIVER,
+                    queue.physical_address + available_ring_offset(queue.size) as u64,
+                );
+                write_mmio(
+                    common + COMMON_QUEUE_DEVICE,
+                    queue.physical_address + used_ring_offset(queue.size) as u64,
+                );
+                queue.notify_offset = read_mmio(common + COMMON_QUEUE_NOTIFY_OFFSET);
+                write_mmio::<u16>(common + COMMON_QUEUE_ENABLE, 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the device there are new buffers in `queue`
+    pub fn notify(&self, queue: &Virtqueue) {
+        match *self {
+            Self::Legacy { io_base } => {
+                Port::new(io_base + LEGACY_QUEUE_NOTIFY).writew(queue.index)
+            }
+            Self::Modern {
+                notify,
+                notify_offset_multiplier,
+                ..
+            } => write_mmio(
+                notify + queue.notify_offset as u64 * notify_offset_multiplier as u64,
+                queue.index,
+            ),
+        }
+    }
+
+    /// Reads, and so acknowledges, the interrupt status: bit 0 for used buffers, bit 1 for a
+    /// configuration change
+    pub fn read_isr(&self) -> u8 {
+        match *self {
+            Self::Legacy { io_base } => Port::new(io_base + LEGACY_ISR_STATUS).readb(),
+            Self::Modern { isr, .. } => read_mmio(isr),
+        }
+    }
+
+    /// Reads the byte at `offset` in the device specific configuration
+    pub fn device_config_byte(&self, offset: u16) -> u8 {
+        match *self {
+            Self::Legacy { io_base } => Port::new(io_base + LEGACY_DEVICE_CONFIG + offset).readb(),
+            Self::Modern { device, .. } => read_mmio(device + offset as u64),
+        }
+    }
+}
+
+const fn available_ring_offset(size: u16) -> usize {
+    size as usize * DESCRIPTOR_SIZE
+}
+
+// Legacy devices derive the used ring address from the descriptor table's, which puts it on the
+// next page boundary after the available ring. Modern ones take it as is, but don't mind
+const fn used_ring_offset(size: u16) -> usize {
+    (available_ring_offset(size)
+        + RING_HEADER_SIZE
+        + size as usize * AVAILABLE_RING_ELEMENT_SIZE
+        + RING_FOOTER_SIZE)
+        .next_multiple_of(PAGE_SIZE)
+}
+
+const fn used_ring_size(size: u16) -> usize {
+    RING_HEADER_SIZE + size as usize * USED_RING_ELEMENT_SIZE + RING_FOOTER_SIZE
+}
+
+/// Backing memory for a `Virtqueue`, which the device accesses with DMA
+#[repr(C, align(4096))]
+pub struct QueueMemory([u8; QUEUE_MEMORY_SIZE]);
+
+impl QueueMemory {
+    pub const fn new() -> Self {
+        Self([0; QUEUE_MEMORY_SIZE])
+    }
+}
+
+impl Default for QueueMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A split virtqueue: a descriptor table, the available ring the driver puts descriptors to hand
+/// over in, and the used ring the device gives them back in. Only single descriptor buffers are
+/// supported, and the caller picks which descriptor each buffer goes in
+pub struct Virtqueue {
+    index: u16,
+    memory: *mut u8,
+    physical_address: u64,
+    size: u16,
+    next_available: u16,
+    last_used: u16,
+    notify_offset: u16,
+}
+
+impl Virtqueue {
+    /// Lays out queue `index` of `size` descriptors, a power of two up to MAX_QUEUE_SIZE, in
+    /// `memory`
+    pub fn new(index: u16, memory: &'static mut QueueMemory, size: u16) -> Result<Self, Fault> {
+        if !size.is_power_of_two() || size > MAX_QUEUE_SIZE {
+            return Err(Fault::UnsupportedQueueSize(size));
+        }
+        memory.0.fill(0);
+        // Within reach of legacy devices too, as the transport isn't known yet
+        let physical_address = dma::device_address(&memory.0, LEGACY_QUEUE_LIMIT)?;
+        Ok(Self {
+            index,
+            memory: memory.0.as_mut_ptr(),
+            physical_address,
+            size,
+            next_available: 0,
+            last_used: 0,
+            notify_offset: 0,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn field<T>(&self, offset: usize) -> *mut T {
+        // Offsets are computed from the layout functions, which stay within QUEUE_MEMORY_SIZE
+        self.memory.wrapping_add(offset).cast()
+    }
+
+    /// Makes the buffer at physical `address` available to the device in `descriptor`, which
+    /// must not be in use. Device writable buffers are for the device to fill in
+    pub fn push(&mut self, descriptor: u16, address: u64, length: u32, device_writable: bool) {
+        let descriptor = descriptor % self.size;
+        let descriptor_offset = descriptor as usize * DESCRIPTOR_SIZE;
+        let flags = if device_writable { DESCRIPTOR_WRITE } else { 0 };
+        let available_offset = available_ring_offset(self.size);
+        let ring_slot = (self.next_available % self.size) as usize;
+
+        // SAFETY: the descriptor is inside the table, and isn't in use by the device
+        unsafe {
+            self.field::<u64>(descriptor_offset).write_volatile(address);
+        }
+        // SAFETY: same as above
+        unsafe {
+            self.field::<u32>(descriptor_offset + 8)
+                .write_volatile(length);
+        }
+        // SAFETY: same as above
+        unsafe {
+            self.field::<u16>(descriptor_offset + 12)
+                .write_volatile(flags);
+        }
+        // SAFETY: the device only reads available ring slots up to the index
+        unsafe {
+            self.field::<u16>(
+                available_offset + RING_HEADER_SIZE + ring_slot * AVAILABLE_RING_ELEMENT_SIZE,
+            )
+            .write_volatile(descriptor);
+        }
+        // The device must see the descriptor before the index that makes it available
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+        self.next_available = self.next_available.wrapping_add(1);
+        // SAFETY: the available index is only written by the driver
+        unsafe {
+            self.field::<u16>(available_offset + 2)
+                .write_volatile(self.next_available);
+        }
+    }
+
+    /// The next descriptor the device is done with, with the number of bytes it wrote to it
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_offset = used_ring_offset(self.size);
+        // SAFETY: the used ring is inside the queue memory, and written by the device with DMA
+        let used_index = unsafe { self.field::<u16>(used_offset + 2).read_volatile() };
+        if used_index == self.last_used {
+            return None;
+        }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+        let element_offset = used_offset
+            + RING_HEADER_SIZE
+            + (self.last_used % self.size) as usize * USED_RING_ELEMENT_SIZE;
+        // SAFETY: the element is below the used index, so the device is done writing it
+        let id = unsafe { self.field::<u32>(element_offset).read_volatile() };
+        // SAFETY: same as above
+        let length = unsafe { self.field::<u32>(element_offset + 4).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((id as u16, length))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::virtio::{QUEUE_MEMORY_SIZE, available_ring_offset, used_ring_offset};
+
+    #[test]
+    fn legacy_queue_layout() {
+        assert_eq!(4096, available_ring_offset(256));
+        assert_eq!(8192, used_ring_offset(256));
+        assert_eq!(12288, QUEUE_MEMORY_SIZE);
+
+        assert_eq!(2048, available_ring_offset(128));
+        assert_eq!(4096, used_ring_offset(128));
+    }
+}
@@ -0,0 +1,324 @@
+// Virtio network device, QEMU's `virtio-net-pci`
+// https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-2170001
+use crate::{
+    dma::{self, DMA_64_BIT_LIMIT},
+    error::{Context, Error, Facility, Fault},
+    interrupts,
+    pci_function::Function,
+    random,
+    virtio::{self, DeviceStatusFlag, QueueMemory, Transport, Virtqueue},
+};
+
+const TRANSITIONAL_DEVICE_ID: u16 = 0x1000;
+const MODERN_DEVICE_ID: u16 = 0x1041;
+
+const FEATURE_MAC: u64 = 1 << 5;
+const FEATURE_STATUS: u64 = 1 << 16;
+// Device configuration fields
+const CONFIG_MAC: u16 = 0;
+const CONFIG_STATUS: u16 = 6;
+const STATUS_LINK_UP: u8 = 1 << 0;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+const RECEIVE_BUFFERS: usize = 32;
+const TRANSMIT_BUFFERS: usize = 8;
+const BUFFER_SIZE: usize = 2048;
+/// Largest frame `send` accepts, without the CRC
+pub const MAX_FRAME_SIZE: usize = 1514;
+const TRANSMIT_POLLS: usize = 1_000_000;
+
+// Every frame is preceded by a virtio_net_hdr, which is all zeros without offloads. Its
+// num_buffers field is only there with VIRTIO_F_VERSION_1 or mergeable receive buffers
+const LEGACY_HEADER_SIZE: usize = 10;
+const HEADER_SIZE: usize = 12;
+
+#[repr(C, align(16))]
+struct PacketBuffers<const N: usize>([[u8; BUFFER_SIZE]; N]);
+
+struct State {
+    transport: Transport,
+    receive_queue: Virtqueue,
+    transmit_queue: Virtqueue,
+    header_size: usize,
+    features: u64,
+    mac_address: [u8; 6],
+    // Bit i is set while transmit buffer i is queued
+    transmit_in_flight: u8,
+    received_frames: u64,
+    dropped_frames: u64,
+}
+
+static mut VIRTIO_NET_STATE: Option<State> = None;
+static mut VIRTIO_NET_RECEIVE_QUEUE_MEMORY: QueueMemory = QueueMemory::new();
+static mut VIRTIO_NET_TRANSMIT_QUEUE_MEMORY: QueueMemory = QueueMemory::new();
+static mut VIRTIO_NET_RECEIVE_BUFFERS: PacketBuffers<RECEIVE_BUFFERS> =
+    PacketBuffers([[0; BUFFER_SIZE]; _]);
+static mut VIRTIO_NET_TRANSMIT_BUFFERS: PacketBuffers<TRANSMIT_BUFFERS> =
+    PacketBuffers([[0; BUFFER_SIZE]; _]);
+
+pub struct VirtioNet;
+
+fn error(fault: Fault, context: Context) -> Error {
+    Error::new(fault, context, Facility::VirtioNet)
+}
+
+fn state_no_sync() -> Option<&'static mut State> {
+    let state_ptr = &raw mut VIRTIO_NET_STATE;
+    // SAFETY: no threads, and the state is only modified with interrupts disabled outside of
+    // `initialize`
+    unsafe { (*state_ptr).as_mut() }
+}
+
+/// A queue as big as the device allows, up to what the queue memory can hold
+fn queue_size(transport: &Transport, index: u16) -> u16 {
+    let device_size = transport.queue_size(index);
+    if transport.is_modern() {
+        device_size.min(virtio::MAX_QUEUE_SIZE)
+    } else {
+        device_size
+    }
+}
+
+impl VirtioNet {
+    /// Looks for a virtio network device on the PCI bus and brings it up with both queues, all
+    /// receive buffers posted. Returns the device's PCI function, whose interrupt handler is
+    /// expected to call `handle_interrupt_no_sync`. The device is left without MSI-X vectors, so
+    /// it only interrupts through its PIC line
+    pub fn initialize() -> Result<Function, Error> {
+        let Some(function) = Function::find(|function| {
+            function.vendor_id() == virtio::VENDOR_ID
+                && [TRANSITIONAL_DEVICE_ID, MODERN_DEVICE_ID].contains(&function.device_id())
+        }) else {
+            return Err(error(Fault::NoNetworkCard, Context::Io));
+        };
+        let setup_error = |fault| error(fault, Context::SettingUpNetworkCard);
+
+        let transport = Transport::new(&function).map_err(setup_error)?;
+        function.enable_memory_space_and_bus_mastering();
+
+        transport.reset().map_err(setup_error)?;
+        transport.add_status(DeviceStatusFlag::Acknowledge);
+        transport.add_status(DeviceStatusFlag::Driver);
+
+        let device_features = transport.device_features();
+        let mut wanted_features = FEATURE_MAC | FEATURE_STATUS;
+        if transport.is_modern() {
+            if device_features & virtio::FEATURE_VERSION_1 == 0 {
+                transport.add_status(DeviceStatusFlag::Failed);
+                return Err(setup_error(Fault::VirtioFeaturesRejected));
+            }
+            wanted_features |= virtio::FEATURE_VERSION_1;
+        }
+        let features = device_features & wanted_features;
+        transport.set_driver_features(features);
+        // Legacy devices have no way to refuse features
+        if transport.is_modern() {
+            transport.add_status(DeviceStatusFlag::FeaturesOk);
+            if !transport.status().is_set(DeviceStatusFlag::FeaturesOk) {
+                transport.add_status(DeviceStatusFlag::Failed);
+                return Err(setup_error(Fault::VirtioFeaturesRejected));
+            }
+        }
+
+        let receive_memory_ptr = &raw mut VIRTIO_NET_RECEIVE_QUEUE_MEMORY;
+        let transmit_memory_ptr = &raw mut VIRTIO_NET_TRANSMIT_QUEUE_MEMORY;
+        // SAFETY: no threads, and the device was reset so it isn't using the queue memory
+        let receive_memory = unsafe { &mut *receive_memory_ptr };
+        // SAFETY: same as above
+        let transmit_memory = unsafe { &mut *transmit_memory_ptr };
+        let mut receive_queue = Virtqueue::new(
+            RECEIVE_QUEUE,
+            receive_memory,
+            queue_size(&transport, RECEIVE_QUEUE),
+        )
+        .map_err(setup_error)?;
+        let mut transmit_queue = Virtqueue::new(
+            TRANSMIT_QUEUE,
+            transmit_memory,
+            queue_size(&transport, TRANSMIT_QUEUE),
+        )
+        .map_err(setup_error)?;
+        transport
+            .setup_queue(&mut receive_queue)
+            .and_then(|_| transport.setup_queue(&mut transmit_queue))
+            .map_err(setup_error)?;
+
+        let buffers_ptr = &raw const VIRTIO_NET_RECEIVE_BUFFERS;
+        // SAFETY: no threads, and the buffers aren't handed to the device yet
+        let buffers = unsafe { &(*buffers_ptr).0 };
+        for (descriptor, buffer) in buffers
+            .iter()
+            .take(receive_queue.size() as usize)
+            .enumerate()
+        {
+            receive_queue.push(
+                descriptor as u16,
+                dma::device_address(buffer, DMA_64_BIT_LIMIT).map_err(setup_error)?,
+                BUFFER_SIZE as u32,
+                true,
+            );
+        }
+
+        let mut mac_address = [0; 6];
+        if features & FEATURE_MAC != 0 {
+            for (offset, byte) in mac_address.iter_mut().enumerate() {
+                *byte = transport.device_config_byte(CONFIG_MAC + offset as u16);
+            }
+        } else {
+            // The device leaves picking an address to the driver: a random, locally
+            // administered, unicast one
+            mac_address.copy_from_slice(&random::entropy().to_le_bytes()[..6]);
+            mac_address[0] = (mac_address[0] | 0x02) & !0x01;
+        }
+
+        let state_ptr = &raw mut VIRTIO_NET_STATE;
+        // SAFETY: no threads, and the device can't interrupt before DRIVER_OK
+        unsafe {
+            *state_ptr = Some(State {
+                transport,
+                receive_queue,
+                transmit_queue,
+                header_size: if transport.is_modern() {
+                    HEADER_SIZE
+                } else {
+                    LEGACY_HEADER_SIZE
+                },
+                features,
+                mac_address,
+                transmit_in_flight: 0,
+                received_frames: 0,
+                dropped_frames: 0,
+            })
+        };
+
+        transport.add_status(DeviceStatusFlag::DriverOk);
+        if let Some(state) = state_no_sync() {
+            transport.notify(&state.receive_queue);
+        }
+        Ok(function)
+    }
+
+    pub fn initialized() -> bool {
+        interrupts::without_interrupts(|| state_no_sync().is_some())
+    }
+
+    pub fn mac_address() -> Option<[u8; 6]> {
+        interrupts::without_interrupts(|| state_no_sync().map(|state| state.mac_address))
+    }
+
+    /// Devices that can't report the link status are always up
+    pub fn link_up() -> bool {
+        interrupts::without_interrupts(|| {
+            state_no_sync().is_some_and(|state| {
+                state.features & FEATURE_STATUS == 0
+                    || state.transport.device_config_byte(CONFIG_STATUS) & STATUS_LINK_UP != 0
+            })
+        })
+    }
+
+    /// Frames received and frames dropped because the device returned them incomplete
+    pub fn statistics() -> (u64, u64) {
+        interrupts::without_interrupts(|| {
+            state_no_sync().map_or((0, 0), |state| {
+                (state.received_frames, state.dropped_frames)
+            })
+        })
+    }
+
+    /// Acknowledges the device's interrupt. Received frames stay in the receive queue until
+    /// `poll_recv` copies them out, the interrupt only wakes up whoever is waiting for them
+    pub fn handle_interrupt_no_sync() {
+        if let Some(state) = state_no_sync() {
+            state.transport.read_isr();
+        }
+    }
+
+    fn reclaim_transmit_buffers(state: &mut State) {
+        while let Some((descriptor, _)) = state.transmit_queue.pop_used() {
+            state.transmit_in_flight &= !(1 << descriptor);
+        }
+    }
+
+    /// Queues `frame`, a full Ethernet frame without CRC, for transmission. Waits for a queued
+    /// frame to be sent if all transmit buffers are in use
+    ///
+    /// Not reentrant: it must not be called from interrupt handlers
+    pub fn send(frame: &[u8]) -> Result<(), Fault> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(Fault::FrameTooLarge(frame.len()));
+        }
+        let buffers_ptr = &raw mut VIRTIO_NET_TRANSMIT_BUFFERS;
+        let mut polls = 0;
+        let descriptor = loop {
+            let free = interrupts::without_interrupts(|| {
+                let state = state_no_sync()?;
+                Self::reclaim_transmit_buffers(state);
+                Some(
+                    (0..TRANSMIT_BUFFERS.min(state.transmit_queue.size() as usize))
+                        .find(|&descriptor| state.transmit_in_flight & (1 << descriptor) == 0),
+                )
+            })
+            .ok_or(Fault::NoNetworkCard)?;
+            if let Some(descriptor) = free {
+                break descriptor;
+            }
+            polls += 1;
+            if polls == TRANSMIT_POLLS {
+                return Err(Fault::HangingNetworkCard);
+            }
+            core::hint::spin_loop();
+        };
+
+        // SAFETY: no threads, and the buffer isn't queued, so the device isn't reading it
+        let buffer = unsafe { &mut (*buffers_ptr).0[descriptor] };
+        let address = dma::device_address(buffer, DMA_64_BIT_LIMIT)?;
+        interrupts::without_interrupts(|| {
+            let state = state_no_sync().ok_or(Fault::NoNetworkCard)?;
+            let length = state.header_size + frame.len();
+            buffer[..state.header_size].fill(0);
+            buffer[state.header_size..length].copy_from_slice(frame);
+            state.transmit_in_flight |= 1 << descriptor;
+            state
+                .transmit_queue
+                .push(descriptor as u16, address, length as u32, false);
+            state.transport.notify(&state.transmit_queue);
+            Ok(())
+        })
+    }
+
+    /// Copies the next received frame into `buffer` and returns its length, or None if no frame
+    /// was received. Frames longer than `buffer` are truncated
+    pub fn poll_recv(buffer: &mut [u8]) -> Option<usize> {
+        interrupts::without_interrupts(|| {
+            let state = state_no_sync()?;
+            let buffers_ptr = &raw const VIRTIO_NET_RECEIVE_BUFFERS;
+            loop {
+                let (descriptor, written) = state.receive_queue.pop_used()?;
+                let index = descriptor as usize % RECEIVE_BUFFERS;
+                // SAFETY: no threads, and the device gave the buffer back
+                let received = unsafe { &(*buffers_ptr).0[index] };
+                let frame = received
+                    .get(state.header_size..(written as usize).min(BUFFER_SIZE))
+                    .unwrap_or_default();
+                let length = frame.len().min(buffer.len());
+                buffer[..length].copy_from_slice(&frame[..length]);
+
+                // Giving the buffer back to the device
+                if let Ok(address) = dma::device_address(received, DMA_64_BIT_LIMIT) {
+                    state
+                        .receive_queue
+                        .push(descriptor, address, BUFFER_SIZE as u32, true);
+                    state.transport.notify(&state.receive_queue);
+                }
+
+                if frame.is_empty() {
+                    state.dropped_frames += 1;
+                    continue;
+                }
+                state.received_frames += 1;
+                return Some(length);
+            }
+        })
+    }
+}
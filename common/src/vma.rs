@@ -0,0 +1,283 @@
+// The kernel's virtual address space, as far as who uses which part of it goes: the areas the
+// layout module fixes, and the ranges subsystems ask for at runtime, e.g. to map MMIO regions or
+// per-CPU areas at, rather than picking addresses of their own. Only the ranges are kept track of
+// here, mapping them is up to whoever asked for them. What `alloc_region` hands out is kept a page
+// apart from any other region, so that running off the end of one faults rather than silently
+// landing in the next
+use core::{fmt::Display, ops::Range};
+
+use num_enum::TryFromPrimitive;
+
+use crate::{error::Fault, frame::FRAME_SIZE, make_bitmap};
+
+/// Enough for the fixed areas and a few regions for each device and module
+pub const MAX_VIRTUAL_REGIONS: usize = 64;
+
+// Regions are handed out in whole pages, with one of these between them
+const PAGE_SIZE: u64 = FRAME_SIZE;
+
+/// What the memory of a region is for, and so how it's to be mapped
+#[allow(unused)]
+#[derive(TryFromPrimitive, Clone, Copy)]
+#[repr(u8)]
+pub enum RegionFlag {
+    Write = 1 << 0,
+    Execute = 1 << 1,
+    Uncacheable = 1 << 2,
+    /// Reachable from user mode
+    User = 1 << 3,
+}
+
+impl Display for RegionFlag {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RegionFlag::Write => write!(f, "WRITE"),
+            RegionFlag::Execute => write!(f, "EXECUTE"),
+            RegionFlag::Uncacheable => write!(f, "UNCACHEABLE"),
+            RegionFlag::User => write!(f, "USER"),
+        }
+    }
+}
+
+make_bitmap!(new_type: RegionFlags, underlying_flag_type: RegionFlag, repr: u8, bit_skipper: |i| i > 3);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualRegion {
+    pub range: Range<u64>,
+    pub flags: RegionFlags,
+}
+
+impl Display for VirtualRegion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} {}",
+            self.range.start, self.range.end, self.flags
+        )
+    }
+}
+
+/// A fixed-capacity set of virtual address ranges that don't overlap, handing out more of them
+/// from a window
+pub struct AddressSpace<const N: usize> {
+    window: Range<u64>,
+    // Sorted by where they start
+    regions: [VirtualRegion; N],
+    count: usize,
+}
+
+impl<const N: usize> AddressSpace<N> {
+    /// An address space handing out regions of `window`, with none in use yet
+    pub const fn new(window: Range<u64>) -> Self {
+        Self {
+            window,
+            regions: [const {
+                VirtualRegion {
+                    range: 0..0,
+                    flags: RegionFlags::empty(),
+                }
+            }; N],
+            count: 0,
+        }
+    }
+
+    /// Records a range whose place is fixed, e.g. the heap's, which doesn't have to be in the
+    /// window. Nothing is recorded for an empty range
+    pub fn reserve(&mut self, range: Range<u64>, flags: RegionFlags) -> Result<(), Fault> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        if let Some(region) = self.overlapping(&range) {
+            return Err(Fault::RegionTaken(region.range.start.max(range.start)));
+        }
+        self.insert(VirtualRegion { range, flags })
+    }
+
+    /// Hands out `size` bytes of the window, rounded up to whole pages, starting at a multiple of
+    /// `align`, which must be a power of two. They go at the lowest address that fits
+    pub fn alloc_region(
+        &mut self,
+        size: u64,
+        align: u64,
+        flags: RegionFlags,
+    ) -> Result<Range<u64>, Fault> {
+        if !align.is_power_of_two() {
+            return Err(Fault::InvalidValueForField("align"));
+        }
+        if size == 0 {
+            return Err(Fault::InvalidValueForField("size"));
+        }
+        let range = size
+            .checked_next_multiple_of(PAGE_SIZE)
+            .and_then(|size| self.find_room(size, align.max(PAGE_SIZE)))
+            .ok_or(Fault::OutOfAddressSpace(size))?;
+        self.insert(VirtualRegion {
+            range: range.clone(),
+            flags,
+        })?;
+        Ok(range)
+    }
+
+    /// The lowest range of the window of `size` bytes starting at a multiple of `align` that
+    /// leaves a page to the regions around it
+    fn find_room(&self, size: u64, align: u64) -> Option<Range<u64>> {
+        let mut candidate = self.window.start;
+        for region in self.regions() {
+            let start = candidate.checked_next_multiple_of(align)?;
+            if start.checked_add(size)?.saturating_add(PAGE_SIZE) <= region.range.start {
+                break;
+            }
+            candidate = candidate.max(region.range.end.saturating_add(PAGE_SIZE));
+        }
+        let start = candidate.checked_next_multiple_of(align)?;
+        let end = start.checked_add(size)?;
+        (end <= self.window.end).then_some(start..end)
+    }
+
+    fn insert(&mut self, region: VirtualRegion) -> Result<(), Fault> {
+        let index = self
+            .regions()
+            .partition_point(|other| other.range.start < region.range.start);
+        let Some(moved) = self.regions.get_mut(index..=self.count) else {
+            return Err(Fault::TooManyVirtualRegions);
+        };
+        moved.rotate_right(1);
+        moved[0] = region;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Changes the flags of the region starting at `start`, returning its range
+    pub fn set_flags(&mut self, start: u64, flags: RegionFlags) -> Result<Range<u64>, Fault> {
+        let Some(region) = self.regions.get_mut(..self.count).and_then(|regions| {
+            regions
+                .iter_mut()
+                .find(|region| region.range.start == start)
+        }) else {
+            return Err(Fault::NoRegionAt(start));
+        };
+        region.flags = flags;
+        Ok(region.range.clone())
+    }
+
+    /// Forgets the region starting at `start`, returning it
+    pub fn free_region(&mut self, start: u64) -> Result<VirtualRegion, Fault> {
+        let Some(index) = self
+            .regions()
+            .iter()
+            .position(|region| region.range.start == start)
+        else {
+            return Err(Fault::NoRegionAt(start));
+        };
+        let moved = &mut self.regions[index..self.count];
+        moved.rotate_left(1);
+        self.count -= 1;
+        Ok(self.regions[self.count].clone())
+    }
+
+    pub fn window(&self) -> &Range<u64> {
+        &self.window
+    }
+
+    pub fn regions(&self) -> &[VirtualRegion] {
+        &self.regions[..self.count]
+    }
+
+    /// The first region `range` overlaps, if any
+    pub fn overlapping(&self, range: &Range<u64>) -> Option<&VirtualRegion> {
+        self.regions().iter().find(|region| {
+            !range.is_empty() && region.range.start < range.end && range.start < region.range.end
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Fault,
+        vma::{AddressSpace, RegionFlag::*, RegionFlags},
+    };
+
+    const WINDOW: core::ops::Range<u64> = 0x10000..0x20000;
+
+    #[test]
+    fn allocates_with_guard_pages() {
+        let mut space = AddressSpace::<8>::new(WINDOW);
+        let flags = Write.into();
+        assert_eq!(
+            Some(0x10000..0x11000),
+            space.alloc_region(0x1000, 1, flags).ok()
+        );
+        assert_eq!(
+            Some(0x12000..0x14000),
+            space.alloc_region(0x1800, 1, flags).ok()
+        );
+        assert_eq!(
+            Some(0x18000..0x19000),
+            space.alloc_region(1, 0x4000, flags).ok()
+        );
+        // Fits between the last two, a page away from both
+        assert_eq!(
+            Some(0x15000..0x17000),
+            space.alloc_region(0x2000, 1, flags).ok()
+        );
+        assert!(matches!(
+            space.alloc_region(0x8000, 1, flags),
+            Err(Fault::OutOfAddressSpace(0x8000))
+        ));
+        assert_eq!(
+            Some(0x1a000..0x20000),
+            space.alloc_region(0x6000, 1, flags).ok()
+        );
+        assert!(matches!(
+            space.alloc_region(0x1000, 0x3000, flags),
+            Err(Fault::InvalidValueForField("align"))
+        ));
+        assert!(matches!(
+            space.alloc_region(0, 1, flags),
+            Err(Fault::InvalidValueForField("size"))
+        ));
+    }
+
+    #[test]
+    fn frees_and_reuses() {
+        let mut space = AddressSpace::<8>::new(WINDOW);
+        let flags = Write | Uncacheable;
+        let first = space.alloc_region(0x1000, 1, flags).unwrap();
+        let second = space.alloc_region(0x1000, 1, flags).unwrap();
+        let freed = space.free_region(first.start).unwrap();
+        assert_eq!(first, freed.range);
+        assert_eq!(flags, freed.flags);
+        assert!(matches!(
+            space.free_region(0x10000),
+            Err(Fault::NoRegionAt(0x10000))
+        ));
+        assert_eq!(1, space.regions().len());
+        assert_eq!(Some(first), space.alloc_region(0x1000, 1, flags).ok());
+        assert_eq!(second.start, space.regions()[1].range.start);
+    }
+
+    #[test]
+    fn fixed_regions() {
+        let mut space = AddressSpace::<3>::new(WINDOW);
+        let flags = RegionFlags::from(Write);
+        // Outside the window, so it doesn't get in the way of what's handed out
+        assert!(space.reserve(0x1000..0x8000, flags).is_ok());
+        assert!(space.reserve(0x11000..0x12000, Write | Execute).is_ok());
+        assert!(matches!(
+            space.reserve(0x11800..0x13000, flags),
+            Err(Fault::RegionTaken(0x11800))
+        ));
+        assert!(space.reserve(0x5000..0x5000, flags).is_ok());
+        assert_eq!(
+            Some(0x13000..0x14000),
+            space.alloc_region(0x1000, 1, flags).ok()
+        );
+        assert!(matches!(
+            space.alloc_region(0x1000, 1, flags),
+            Err(Fault::TooManyVirtualRegions)
+        ));
+        let starts = space.regions().iter().map(|region| region.range.start);
+        assert!(starts.eq([0x1000, 0x11000, 0x13000]));
+    }
+}
@@ -0,0 +1,61 @@
+// What code waits on for an interrupt handler to tell it that something happened, e.g. that a key
+// was pressed or that a disk finished a transfer, instead of polling for it in a loop. There's no
+// scheduler yet, so there's only ever the one flow of control waiting: rather than being put on
+// the queue and switched away from, it halts the CPU until the next interrupt and checks again.
+// Once there are tasks, `wait_until` is where the current one goes to sleep, and `wake` is where
+// the ones on the queue become runnable again
+use crate::interrupts;
+
+/// Something interrupt handlers `wake` and waiters take the wakeup of. Wakeups that come while
+/// nobody is waiting aren't lost, and the ones that come before the previous one was taken are
+/// merged into it
+#[derive(Debug, Clone, Default)]
+pub struct WaitQueue {
+    woken: bool,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { woken: false }
+    }
+
+    pub fn wake(&mut self) {
+        self.woken = true;
+    }
+
+    /// Whether the queue was woken since the wakeup was last taken
+    pub fn take_wakeup(&mut self) -> bool {
+        core::mem::take(&mut self.woken)
+    }
+}
+
+/// Halts until `ready` returns something, which is then returned, with interrupts enabled. `ready`
+/// runs with interrupts disabled, so that it can look at what interrupt handlers update, e.g. take
+/// the wakeup of a queue, without racing with them: an interrupt that comes right after it
+/// returned None still ends the halt that follows
+pub fn wait_until<T>(mut ready: impl FnMut() -> Option<T>) -> T {
+    loop {
+        interrupts::disable();
+        if let Some(value) = ready() {
+            interrupts::enable();
+            return value;
+        }
+        interrupts::enable_and_halt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wait_queue::WaitQueue;
+
+    #[test]
+    fn wakeups() {
+        let mut queue = WaitQueue::new();
+        assert!(!queue.take_wakeup());
+        // Before anyone waits, and twice before it's taken
+        queue.wake();
+        queue.wake();
+        assert!(queue.take_wakeup());
+        assert!(!queue.take_wakeup());
+    }
+}
@@ -0,0 +1,21 @@
+// Parses anything as the drive parameters the BIOS returns for int 13h AH=48h, and as a BIOS
+// drive table stage1 left behind. Parsing doesn't follow the fixed disk parameter table pointer,
+// see fixed_disk_parameter_table.rs for the table itself
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../bootloader/src/edd.rs"]
+mod edd;
+
+fuzz_target!(|bytes: &[u8]| {
+    if let Ok(drive_parameters) = edd::DriveParameters::try_from(bytes) {
+        let _ = drive_parameters.bios_drive(edd::FIRST_BIOS_DRIVE);
+        let _ = common::ata::Device::try_from(drive_parameters);
+    }
+    let _ = edd::boot_drive_number(bytes);
+    for (_number, drive_parameters) in edd::bios_drives(bytes) {
+        let _ = drive_parameters;
+    }
+});
@@ -0,0 +1,29 @@
+// Parses anything as an ELF file, then walks everything the bootloader and the kernel look at in
+// one: the section and program headers, the sections by name and index, the segments, the
+// relocations and the dynamic entries
+#![no_main]
+
+use common::elf::File;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: &[u8]| {
+    let Ok(file) = File::try_from(bytes) else {
+        return;
+    };
+    for (index, section) in file.sections().enumerate() {
+        if let Ok(section) = section {
+            let _ = file.section_bytes(&section);
+            let _ = file.get_section_by_index(index);
+        }
+    }
+    for program_header in file.program_headers().flatten() {
+        let _ = file.get_segment(&program_header);
+    }
+    let _ = file.get_section_by_name(".text");
+    for relocations in file.relocation_tables().flatten() {
+        relocations.for_each(drop);
+    }
+    if let Some(Ok(dynamic_entries)) = file.dynamic_entries() {
+        dynamic_entries.for_each(drop);
+    }
+});
@@ -0,0 +1,13 @@
+// Parses anything as the fixed disk parameter table the drive parameters of EDD 2.0 and later
+// point to
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../bootloader/src/edd.rs"]
+mod edd;
+
+fuzz_target!(|bytes: &[u8]| {
+    let _ = edd::FixedDiskParameterTable::try_from(bytes);
+});
@@ -0,0 +1,14 @@
+/* Generated by `xtasks linker-scripts` from common/src/layout.rs, edit that instead */
+ENTRY(_start)
+SECTIONS {
+  . = 0x200000;
+
+  .text   : ALIGN(4K) { *(.text .text.*) }
+  .rodata : ALIGN(4K) { *(.rodata .rodata.*) }
+  .data   : ALIGN(4K) { *(.data .data.*) }
+  .bss    : ALIGN(4K) { *(.bss .bss.*) *(COMMON) }
+
+  ASSERT(ALIGN(0x100000) + 0x100000 <= 0x10000000, "the kernel and its stack run into the heap")
+  ASSERT(0x11000000 <= 0x11000000, "the heap runs into the frame pool")
+  ASSERT(0x12000000 <= 0xc0000000, "the frame pool runs into the MMIO window")
+}
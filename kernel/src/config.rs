@@ -0,0 +1,223 @@
+// The kernel's configuration: which subsystems it was built with, through the cargo features of
+// kernel/Cargo.toml, and the knobs the command line turns at boot, read once by `init` before the
+// log sinks start. Whatever isn't on the command line keeps the default below, and options with
+// values that don't parse are logged and ignored rather than stopping the boot:
+//   loglevel=<level>          the least severe level logged
+//   console=<port>[,<baud>]   the serial port to log to, see serial::parse_console
+//   nocolor                   no ANSI colors on the serial port
+//   test, panic=exit          test mode: exit QEMU on panics, and leave the network card alone
+//   nonet                     don't bring up the network card, if the kernel was built with one
+//   dhcp_timeout_ms=<ms>      how long to wait for the DHCP server before asking again
+//   user_stack=<bytes>        how much stack user programs get, a multiple of the page size
+//   deterministic[ seed=<n>]  random numbers from a generator with a fixed seed, for replays
+// plus the timing knobs of common::tunables, e.g. ata_command_timeout_us=<us>
+use common::{
+    command_line::CommandLine,
+    frame::FRAME_SIZE,
+    log::{self, Level},
+    random,
+    serial::{self, LineSettings},
+    tunables, user_program,
+};
+
+const DEFAULT_DHCP_RETRANSMIT_TIMEOUT_NS: u64 = 4_000_000_000;
+const DEFAULT_DETERMINISTIC_SEED: u64 = 0x626c_6f67_5f6f_7321;
+// No stack past the user regions' PML4 entry
+const MAX_USER_STACK_SIZE: u64 = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// None to keep the log's default
+    pub log_level: Option<Level>,
+    /// The base of the serial port to log to and how to set it up, None for COM1
+    pub console: Option<(u16, LineSettings)>,
+    pub ansi_colors: bool,
+    pub test_mode: bool,
+    /// Whether to bring up a network card, only ever true with the `net` feature
+    pub net: bool,
+    /// Whether to start the other processors, only ever true with the `smp` feature
+    pub smp: bool,
+    pub dhcp_retransmit_timeout_ns: u64,
+    pub user_stack_size: u64,
+    /// The seed of every random number for deterministic boots, None for the real thing
+    pub deterministic_seed: Option<u64>,
+}
+
+impl Config {
+    pub const fn default() -> Self {
+        Self {
+            log_level: None,
+            console: None,
+            ansi_colors: true,
+            test_mode: false,
+            net: cfg!(feature = "net"),
+            smp: cfg!(feature = "smp"),
+            dhcp_retransmit_timeout_ns: DEFAULT_DHCP_RETRANSMIT_TIMEOUT_NS,
+            user_stack_size: user_program::DEFAULT_USER_STACK_SIZE,
+            deterministic_seed: None,
+        }
+    }
+
+    /// The configuration `command_line` asks for. Options that don't parse are logged, and leave
+    /// the default in place
+    pub fn from_command_line(command_line: &CommandLine) -> Self {
+        let mut config = Self::default();
+        if let Some(level) = command_line.get("loglevel") {
+            match Level::from_name(level) {
+                Some(level) => config.log_level = Some(level),
+                None => log::warn_no_sync!("Unknown log level {}", level),
+            }
+        }
+        if let Some(console) = command_line.get("console") {
+            match serial::parse_console(console) {
+                Some(console) => config.console = Some(console),
+                None => log::warn_no_sync!("Bad console={}, logging to COM1", console),
+            }
+        }
+        config.ansi_colors = !command_line.has_flag("nocolor");
+        config.test_mode =
+            command_line.has_flag("test") || command_line.get("panic") == Some("exit");
+        config.net &= !command_line.has_flag("nonet") && !config.test_mode;
+        config.smp &= !command_line.has_flag("nosmp");
+        if let Some(timeout_ms) = number(command_line, "dhcp_timeout_ms")
+            .filter(|timeout_ms| (1..=u64::MAX / 1_000_000).contains(timeout_ms))
+        {
+            config.dhcp_retransmit_timeout_ns = timeout_ms * 1_000_000;
+        }
+        if let Some(user_stack_size) = number(command_line, "user_stack") {
+            if user_stack_size.is_multiple_of(FRAME_SIZE)
+                && (FRAME_SIZE..=MAX_USER_STACK_SIZE).contains(&user_stack_size)
+            {
+                config.user_stack_size = user_stack_size;
+            } else {
+                log::warn_no_sync!(
+                    "user_stack={:#x} isn't a multiple of a page up to {:#x}, keeping {:#x}",
+                    user_stack_size,
+                    MAX_USER_STACK_SIZE,
+                    config.user_stack_size
+                );
+            }
+        }
+        if command_line.has_flag("deterministic") {
+            config.deterministic_seed =
+                Some(number(command_line, "seed").unwrap_or(DEFAULT_DETERMINISTIC_SEED));
+        }
+        config
+    }
+}
+
+/// The value of `key`, in hex with a 0x prefix or in decimal
+fn number(command_line: &CommandLine, key: &str) -> Option<u64> {
+    let value = command_line.get(key)?;
+    let number = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    };
+    if number.is_none() {
+        log::warn_no_sync!("{}={} isn't a number, ignoring it", key, value);
+    }
+    number
+}
+
+static mut CONFIG: Config = Config::default();
+
+/// Reads the configuration from the command line and sets logging up the way it asks. Only to be
+/// called once, at boot, before anything asks for the configuration
+pub fn init() {
+    let command_line = crate::command_line();
+    let config = Config::from_command_line(&command_line);
+    let config_ptr = &raw mut CONFIG;
+    // SAFETY: no threads, and nothing reads CONFIG before this
+    unsafe { *config_ptr = config };
+
+    if let Some(seed) = config.deterministic_seed {
+        random::set_deterministic_no_sync(seed);
+        log::info_no_sync!("Deterministic boot, random numbers seeded with {:#x}", seed);
+    }
+    log::set_ansi_colors_no_sync(config.ansi_colors);
+    if config.test_mode {
+        common::panicking::set_exit_qemu_no_sync(true);
+    }
+    if let Some(level) = config.log_level {
+        log::set_max_level_no_sync(level);
+    }
+    if let Some((base, settings)) = config.console {
+        match serial::SerialPort::with_settings(base, settings) {
+            Some(port) => serial::set_log_port_no_sync(port),
+            None => log::warn_no_sync!("No serial port at {:#x}, logging to COM1", base),
+        }
+    }
+    let mut tunables = tunables::get();
+    tunables.apply_command_line(&command_line, |name, value| {
+        log::warn_no_sync!(
+            "{}={} isn't a valid value, keeping the default",
+            name,
+            value
+        )
+    });
+    tunables::set_no_sync(tunables);
+    if config.smp {
+        log::warn_no_sync!("Built with smp, but only the bootstrap processor is brought up yet");
+    }
+}
+
+pub fn get() -> &'static Config {
+    let config_ptr = &raw const CONFIG;
+    // SAFETY: CONFIG is only written by init, at boot, before anything reads it
+    unsafe { &*config_ptr }
+}
+
+#[cfg(test)]
+// Failing is what test cases panic for
+#[allow(clippy::missing_panics_doc)]
+mod tests {
+    use common::{command_line::CommandLine, log::Level, serial::COM2, user_program};
+
+    use crate::config::Config;
+
+    #[test_case]
+    fn defaults_without_options() {
+        let config = Config::from_command_line(&CommandLine::new(""));
+        assert_eq!(Config::default(), config);
+        assert_eq!(
+            user_program::DEFAULT_USER_STACK_SIZE,
+            config.user_stack_size
+        );
+        assert!(!config.test_mode);
+    }
+
+    #[test_case]
+    fn options_override_the_defaults() {
+        let config = Config::from_command_line(&CommandLine::new(
+            "loglevel=debug console=com2 nocolor panic=exit dhcp_timeout_ms=500 user_stack=0x4000",
+        ));
+        assert_eq!(Some(Level::Debug), config.log_level);
+        assert_eq!(Some(COM2), config.console.map(|(base, _)| base));
+        assert!(!config.ansi_colors);
+        assert!(config.test_mode);
+        assert!(!config.net);
+        assert_eq!(500_000_000, config.dhcp_retransmit_timeout_ns);
+        assert_eq!(0x4000, config.user_stack_size);
+        assert_eq!(None, config.deterministic_seed);
+
+        let config = Config::from_command_line(&CommandLine::new("deterministic seed=0x2a"));
+        assert_eq!(Some(42), config.deterministic_seed);
+        let config = Config::from_command_line(&CommandLine::new("deterministic seed=x"));
+        assert!(config.deterministic_seed.is_some());
+    }
+
+    #[test_case]
+    fn bad_values_keep_the_defaults() {
+        let config = Config::from_command_line(&CommandLine::new(
+            "loglevel=loud dhcp_timeout_ms=0 user_stack=4097 console=com9",
+        ));
+        let default = Config::default();
+        assert_eq!(None, config.log_level);
+        assert_eq!(None, config.console);
+        assert_eq!(
+            default.dhcp_retransmit_timeout_ns,
+            config.dhcp_retransmit_timeout_ns
+        );
+        assert_eq!(default.user_stack_size, config.user_stack_size);
+    }
+}
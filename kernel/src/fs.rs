@@ -0,0 +1,249 @@
+// The root file system: a tmpfs, until there's a file system on a disk to mount instead. It's
+// where the initrd gets unpacked to, if it's a tar or cpio archive, and what the shell's `fs`
+// command works on. The devices are in a devfs mounted at /dev
+use common::{
+    archive, ata,
+    devfs::{self, DevFs, DeviceKind, ReadFn, WriteFn},
+    error::{Context, Error, Facility, Fault},
+    keyboard, log,
+    payload::PayloadKind,
+    ram_disk::RamDisk,
+    serial::Com1,
+    tmpfs::{NodeKind, Tmpfs},
+    vga,
+};
+
+use crate::interrupts;
+
+// 128KB of data, in the kernel's .bss
+const MAX_FILES: usize = 64;
+const MAX_BLOCKS: usize = 256;
+const MAX_DEVICES: usize = 8;
+const RAM_DISK_SIZE: usize = 64 * 1024;
+
+static mut ROOT: Tmpfs<MAX_FILES, MAX_BLOCKS> = Tmpfs::new();
+static mut DEVICES: DevFs<MAX_DEVICES> = DevFs::new();
+static mut RAM_DISK: [u8; RAM_DISK_SIZE] = [0; RAM_DISK_SIZE];
+// The first drive the bootloader found, if there's any
+static mut ATA0: Option<ata::Device> = None;
+
+/// The root file system
+pub fn root_no_sync() -> &'static mut Tmpfs<MAX_FILES, MAX_BLOCKS> {
+    let root_ptr = &raw mut ROOT;
+    // SAFETY: no threads, and callers don't hold on to it across calls that may use it too
+    unsafe { &mut *root_ptr }
+}
+
+/// The devices under /dev
+pub fn devices_no_sync() -> &'static DevFs<MAX_DEVICES> {
+    let devices_ptr = &raw const DEVICES;
+    // SAFETY: no threads, and devices are only registered by `init`
+    unsafe { &*devices_ptr }
+}
+
+/// Creates the directories everything else expects to be there, unpacks the initrd and registers
+/// the devices
+pub fn init() {
+    let root = root_no_sync();
+    for directory in ["/tmp", devfs::MOUNT_POINT] {
+        if let Err(fault) = root.create(directory, NodeKind::Directory) {
+            log::error_no_sync!("Creating {} failed: {}", directory, fault);
+        }
+    }
+    unpack_initrd();
+
+    let ata0 = crate::boot_info().drives().first().map(|drive| {
+        let new = if drive.is_atapi {
+            ata::Device::new_atapi
+        } else {
+            ata::Device::new
+        };
+        let mut device = new(
+            drive.io_port_base_address,
+            drive.control_port_base_address,
+            drive.is_slave,
+            drive.sectors,
+            drive.sector_size_bytes,
+        );
+        interrupts::use_ata_interrupts(&mut device);
+        device
+    });
+    let ata0_ptr = &raw mut ATA0;
+    // SAFETY: no threads, and nothing reads ATA0 before it's registered below
+    unsafe { *ata0_ptr = ata0 };
+
+    let devices_ptr = &raw mut DEVICES;
+    // SAFETY: no threads, and nothing looks devices up before `init`
+    let devices = unsafe { &mut *devices_ptr };
+    let character = DeviceKind::Character;
+    register(
+        devices,
+        "null",
+        character,
+        devfs::null_read,
+        devfs::null_write,
+    );
+    register(
+        devices,
+        "zero",
+        character,
+        devfs::zero_read,
+        devfs::null_write,
+    );
+    register(devices, "console", character, console_read, console_write);
+    register(devices, "com1", character, com1_read, com1_write);
+    let size = RAM_DISK_SIZE as u64;
+    register(
+        devices,
+        "ram0",
+        DeviceKind::Block { size },
+        ram0_read,
+        ram0_write,
+    );
+    if let Some(device) = ata0 {
+        let size = device.sectors() * device.sector_size_bytes() as u64;
+        register(
+            devices,
+            "ata0",
+            DeviceKind::Block { size },
+            ata0_read,
+            ata0_write,
+        );
+    }
+}
+
+/// Unpacks the initrd into the root, if it's an archive rather than e.g. a program to run
+fn unpack_initrd() {
+    let Some(payload) = crate::boot_info().payload(PayloadKind::Initrd) else {
+        return;
+    };
+    // SAFETY: The bootloader loaded the payload below 4GB, in the identity mapped first GB, and
+    // nothing writes over it
+    let bytes = unsafe {
+        core::slice::from_raw_parts(payload.address as usize as *const u8, payload.size as usize)
+    };
+    if archive::Format::detect(bytes).is_none() {
+        return;
+    }
+    match archive::unpack(bytes, root_no_sync(), "/") {
+        Ok(entries) => log::info_no_sync!("Unpacked {} entries of the initrd", entries),
+        Err(error) => log::error_no_sync!("Unpacking the initrd failed: {}", error),
+    }
+}
+
+fn register(
+    devices: &mut DevFs<MAX_DEVICES>,
+    name: &str,
+    kind: DeviceKind,
+    read: ReadFn,
+    write: WriteFn,
+) {
+    if let Err(fault) = devices.register(name, kind, read, write) {
+        log::error_no_sync!("Registering /dev/{} failed: {}", name, fault);
+    }
+}
+
+/// What was typed on the keyboard, as UTF-8
+fn console_read(_offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+    let mut length = 0;
+    while buffer.len() - length >= char::MAX_LEN_UTF8 {
+        let Some(character) = keyboard::read_char() else {
+            break;
+        };
+        length += character.encode_utf8(&mut buffer[length..]).len();
+    }
+    Ok(length)
+}
+
+fn console_write(_offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+    vga::with_default_writer_no_sync(|writer| {
+        for &byte in bytes {
+            writer.write_byte(byte);
+        }
+    });
+    Ok(bytes.len())
+}
+
+fn com1_read(_offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+    let mut com1 = Com1::get();
+    let mut length = 0;
+    while let Some(slot) = buffer.get_mut(length) {
+        let Some(byte) = com1.read_byte() else {
+            break;
+        };
+        *slot = byte;
+        length += 1;
+    }
+    Ok(length)
+}
+
+fn com1_write(_offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+    Com1::get().write_bytes(bytes);
+    Ok(bytes.len())
+}
+
+fn with_ram_disk<R>(f: impl FnOnce(&mut RamDisk) -> R) -> R {
+    let ram_disk_ptr = &raw mut RAM_DISK;
+    // SAFETY: no threads, and only the ram0 device uses it
+    f(&mut RamDisk::new(unsafe { &mut *ram_disk_ptr }))
+}
+
+fn ram0_read(offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+    with_ram_disk(|disk| devfs::read_blocks(disk, offset, buffer))
+}
+
+fn ram0_write(offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+    with_ram_disk(|disk| devfs::write_blocks(disk, offset, bytes))
+}
+
+fn ata0() -> Result<ata::Device, Error> {
+    let ata0_ptr = &raw const ATA0;
+    // SAFETY: no threads, and it's only written by `init`, before ata0 is registered
+    let device = unsafe { *ata0_ptr };
+    device.ok_or(Error::new(
+        Fault::NoAtaDevice,
+        Context::Io,
+        Facility::AtaDevice(ata::PRIMARY_BUS_IO_PORT_BASE_ADDRESS),
+    ))
+}
+
+fn ata0_read(offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+    devfs::read_blocks(&mut ata0()?, offset, buffer)
+}
+
+fn ata0_write(offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+    devfs::write_blocks(&mut ata0()?, offset, bytes)
+}
+
+#[cfg(test)]
+// Failing is what test cases panic for
+#[allow(clippy::missing_panics_doc)]
+mod tests {
+    use common::tmpfs::NodeKind;
+
+    use crate::fs;
+
+    #[test_case]
+    fn root_has_tmp() {
+        let root = fs::root_no_sync();
+        let Ok(file) = root.create("/tmp/test", NodeKind::File) else {
+            panic!("can't create /tmp/test");
+        };
+        assert!(root.write(file, 0, b"test").is_ok());
+        assert_eq!(Some(4), root.size(file).ok());
+        assert!(root.remove("/tmp/test").is_ok());
+    }
+
+    #[test_case]
+    fn devices() {
+        let devices = fs::devices_no_sync();
+        let Ok(ram0) = devices.lookup("/dev/ram0") else {
+            panic!("no /dev/ram0");
+        };
+        assert_eq!(Some(5), ram0.write(1000, b"hello").ok());
+        let mut buffer = [0u8; 5];
+        assert_eq!(Some(5), ram0.read(1000, &mut buffer).ok());
+        assert_eq!(b"hello", &buffer);
+        assert!(devices.lookup("/dev/null").is_ok());
+    }
+}
@@ -0,0 +1,194 @@
+// Debugging the kernel with GDB over COM2, for machines without QEMU's gdbstub. Breakpoint and
+// debug exceptions stop the kernel and hand it over to the stub, e.g.:
+//   qemu-system-x86_64 ... -serial stdio -serial tcp::1234,server,nowait
+//   gdb -ex 'target remote :1234' target/x86_64-blog_os/release/blog_os
+// then `gdb` in the shell breaks into the debugger
+
+use core::arch::{asm, naked_asm};
+
+use common::{
+    gdb::{Registers, Stub},
+    log, paging,
+    serial::{COM2, SerialPort},
+};
+
+static mut STUB: Option<(SerialPort, Stub)> = None;
+
+/// The registers saved by `trap_stub`, followed by what the CPU pushes when delivering an
+/// exception without an error code
+#[repr(C)]
+struct TrapFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    instruction_pointer: u64,
+    code_segment: u64,
+    cpu_flags: u64,
+    stack_pointer: u64,
+    stack_segment: u64,
+}
+
+fn data_segments() -> [u32; 4] {
+    let (ds, es, fs, gs): (u16, u16, u16, u16);
+    // SAFETY: Reading segment registers has no side effects
+    unsafe {
+        asm!(
+            "mov {ds:x}, ds",
+            "mov {es:x}, es",
+            "mov {fs:x}, fs",
+            "mov {gs:x}, gs",
+            ds = out(reg) ds,
+            es = out(reg) es,
+            fs = out(reg) fs,
+            gs = out(reg) gs,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    [ds.into(), es.into(), fs.into(), gs.into()]
+}
+
+impl TrapFrame {
+    fn registers(&self) -> Registers {
+        let [ds, es, fs, gs] = data_segments();
+        Registers {
+            general_purpose: [
+                self.rax,
+                self.rbx,
+                self.rcx,
+                self.rdx,
+                self.rsi,
+                self.rdi,
+                self.rbp,
+                self.stack_pointer,
+                self.r8,
+                self.r9,
+                self.r10,
+                self.r11,
+                self.r12,
+                self.r13,
+                self.r14,
+                self.r15,
+            ],
+            rip: self.instruction_pointer,
+            rflags: self.cpu_flags,
+            segments: [
+                self.code_segment as u32,
+                self.stack_segment as u32,
+                ds,
+                es,
+                fs,
+                gs,
+            ],
+        }
+    }
+
+    /// Segment registers are left alone, as changing them would crash the stopped code
+    fn set_registers(&mut self, registers: &Registers) {
+        [
+            self.rax,
+            self.rbx,
+            self.rcx,
+            self.rdx,
+            self.rsi,
+            self.rdi,
+            self.rbp,
+            self.stack_pointer,
+            self.r8,
+            self.r9,
+            self.r10,
+            self.r11,
+            self.r12,
+            self.r13,
+            self.r14,
+            self.r15,
+        ] = registers.general_purpose;
+        self.instruction_pointer = registers.rip;
+        self.cpu_flags = registers.rflags;
+    }
+}
+
+fn is_mapped(address: u64) -> bool {
+    paging::Mapper::active().translate(address).is_some()
+}
+
+fn read_memory(address: u64) -> Option<u8> {
+    // SAFETY: the address was just checked to be mapped, and GDB reading memory the kernel could
+    // be writing concurrently is fine, as the kernel is stopped
+    is_mapped(address).then(|| unsafe { (address as *const u8).read_volatile() })
+}
+
+fn write_memory(address: u64, byte: u8) -> bool {
+    if !is_mapped(address) {
+        return false;
+    }
+    // SAFETY: the address was just checked to be mapped, and it's on whoever debugs the kernel to
+    // only write where it makes sense. The kernel's memory is writable, code included
+    unsafe { (address as *mut u8).write_volatile(byte) };
+    true
+}
+
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    let stub_ptr = &raw mut STUB;
+    // SAFETY: no threads, and this runs from an interrupt gate, with interrupts disabled, so it
+    // can't be reentered. Traps while the stub runs would be bugs in the stub
+    let Some((port, stub)) = (unsafe { &mut *stub_ptr }).as_mut() else {
+        // Nobody to hand the kernel over to, carry on after the int3
+        return;
+    };
+    let mut registers = frame.registers();
+    if let Some(location) = crate::symbols::lookup(registers.rip) {
+        log::info_no_sync!("Stopped at {}", location);
+    }
+    stub.run(port, &mut registers, read_memory, write_memory);
+    frame.set_registers(&registers);
+}
+
+/// Entry point for the debug and breakpoint exceptions, saving every general purpose register
+/// for GDB to see and change
+#[unsafe(naked)]
+pub extern "C" fn trap_stub() {
+    naked_asm!(
+        "push rax", "push rbx", "push rcx", "push rdx", "push rsi", "push rdi", "push rbp",
+        "push r8", "push r9", "push r10", "push r11", "push r12", "push r13", "push r14", "push r15",
+        // The CPU aligned the stack before pushing its 5 quadwords, with the 15 above that's 16
+        // byte aligned again
+        "mov rdi, rsp",
+        "cld",
+        "call {handler}",
+        "pop r15", "pop r14", "pop r13", "pop r12", "pop r11", "pop r10", "pop r9", "pop r8",
+        "pop rbp", "pop rdi", "pop rsi", "pop rdx", "pop rcx", "pop rbx", "pop rax",
+        "iretq",
+        handler = sym trap_handler,
+    );
+}
+
+/// Sets COM2 up for the stub. False if there's no COM2, in which case breakpoints are ignored
+pub fn init() -> bool {
+    let Some(port) = SerialPort::new(COM2) else {
+        return false;
+    };
+    let stub_ptr = &raw mut STUB;
+    // SAFETY: no threads, and the trap handlers aren't installed yet
+    unsafe { *stub_ptr = Some((port, Stub::new())) };
+    true
+}
+
+/// Stops the kernel here and waits for GDB
+pub fn break_into_debugger() {
+    // SAFETY: int3 traps into `trap_stub`, which returns right after it
+    unsafe {
+        asm!("int3");
+    }
+}
@@ -0,0 +1,149 @@
+// The kernel's own GDT, replacing the one stage2 hands over, which has neither segments for ring 3
+// nor a 64-bit TSS. The TSS gives interrupts coming from ring 3 a stack of their own in the
+// kernel, and double faults one they get whatever the stack they came from, as they may come from
+// overflowing it. The user data segment comes right before the user code one, the way SYSRET
+// expects them
+use core::{arch::asm, ops::Range};
+
+use common::{
+    gdt::{GDTDescriptor, SegmentDescriptor, SegmentKind},
+    protection::PrivilegeLevel,
+    tss::{LongModeTaskStateSegment, Stack},
+};
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+/// With RPL 3, as loaded in ring 3
+pub const USER_DATA_SELECTOR: u16 = 0x18 | PrivilegeLevel::Ring3 as u16;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | PrivilegeLevel::Ring3 as u16;
+const TSS_SELECTOR: u16 = 0x28;
+/// The stack of the interrupt stack table double faults run on
+pub const DOUBLE_FAULT_STACK_INDEX: u8 = 1;
+const PRIVILEGE_STACK_SIZE: usize = 0x8000;
+const DOUBLE_FAULT_STACK_SIZE: usize = 0x4000;
+// The null descriptor, the four segments and the two halves of the TSS descriptor
+const GDT_ENTRIES: usize = 7;
+
+static mut PRIVILEGE_STACK: Stack<PRIVILEGE_STACK_SIZE> = Stack::new([0; _]);
+static mut DOUBLE_FAULT_STACK: Stack<DOUBLE_FAULT_STACK_SIZE> = Stack::new([0; _]);
+static mut TASK_STATE_SEGMENT: LongModeTaskStateSegment = LongModeTaskStateSegment::blank();
+static mut GLOBAL_DESCRIPTOR_TABLE: [SegmentDescriptor; GDT_ENTRIES] =
+    [SegmentDescriptor::blank(); _];
+
+/// The stack interrupts coming from ring 3 run on
+pub fn privilege_stack() -> Range<usize> {
+    let stack_ptr = &raw const PRIVILEGE_STACK;
+    // SAFETY: only the CPU writes the stack, and the range is all that's read of it
+    unsafe { (*stack_ptr).range() }
+}
+
+pub fn double_fault_stack() -> Range<usize> {
+    let stack_ptr = &raw const DOUBLE_FAULT_STACK;
+    // SAFETY: see privilege_stack
+    unsafe { (*stack_ptr).range() }
+}
+
+/// Loads the kernel's GDT and TSS, reloading the segment registers with its selectors. Only to be
+/// called once, at boot, before the IDT is set up with the code selector in CS
+pub fn init() {
+    let tss_ptr = &raw mut TASK_STATE_SEGMENT;
+    // SAFETY: no threads, and the TSS isn't loaded yet
+    let tss = unsafe { &mut *tss_ptr };
+    tss.set_privilege_stack(PrivilegeLevel::Ring0, privilege_stack().end as u64);
+    // The index is in range
+    let _ = tss.set_interrupt_stack(DOUBLE_FAULT_STACK_INDEX, double_fault_stack().end as u64);
+
+    let [tss_lower, tss_upper] = SegmentDescriptor::new_long_mode_tss(tss);
+    let gdt_ptr = &raw mut GLOBAL_DESCRIPTOR_TABLE;
+    // SAFETY: no threads, and the GDT isn't loaded yet
+    unsafe {
+        *gdt_ptr = [
+            SegmentDescriptor::blank(),
+            SegmentDescriptor::new_flat(SegmentKind::Code, true, PrivilegeLevel::Ring0),
+            SegmentDescriptor::new_flat(SegmentKind::Data, true, PrivilegeLevel::Ring0),
+            SegmentDescriptor::new_flat(SegmentKind::Data, true, PrivilegeLevel::Ring3),
+            SegmentDescriptor::new_flat(SegmentKind::Code, true, PrivilegeLevel::Ring3),
+            tss_lower,
+            tss_upper,
+        ]
+    };
+    // SAFETY: the GDT is a static, and isn't written again once loaded
+    let gdt_descriptor = GDTDescriptor::from(unsafe { &*gdt_ptr });
+
+    // SAFETY: The GDT has the kernel's code and data segments at the selectors loaded below, with
+    // the same flat base and limit as stage2's, so the code and data keep their addresses. CS
+    // can only be reloaded by a far jump, call or return, hence the far return to the next
+    // instruction. The TSS descriptor is available, as it was never loaded
+    unsafe {
+        asm!(
+            "lgdt [{gdt_descriptor}]",
+            "push {code_selector}",
+            "lea {scratch}, [rip + 2f]",
+            "push {scratch}",
+            "retfq",
+            "2:",
+            "mov {scratch:e}, {data_selector}",
+            "mov ds, {scratch:x}",
+            "mov es, {scratch:x}",
+            "mov ss, {scratch:x}",
+            "mov fs, {scratch:x}",
+            "mov gs, {scratch:x}",
+            "mov {scratch:e}, {tss_selector}",
+            "ltr {scratch:x}",
+            gdt_descriptor = in(reg) &gdt_descriptor,
+            code_selector = const KERNEL_CODE_SELECTOR,
+            data_selector = const KERNEL_DATA_SELECTOR,
+            tss_selector = const TSS_SELECTOR,
+            scratch = out(reg) _,
+        );
+    }
+}
+
+#[cfg(test)]
+// Failing is what test cases panic for
+#[allow(clippy::missing_panics_doc)]
+mod tests {
+    use core::arch::asm;
+
+    use common::{
+        gdt::{DescriptorKind, GDTDescriptor},
+        protection::PrivilegeLevel,
+    };
+
+    use crate::gdt::{
+        GDT_ENTRIES, KERNEL_CODE_SELECTOR, TSS_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR,
+    };
+
+    #[test_case]
+    fn kernel_tables_are_loaded() {
+        let (code_selector, task_register): (u16, u16);
+        // SAFETY: Reading CS and the task register has no side effects
+        unsafe {
+            asm!("mov {code:x}, cs", "str {task:x}", code = out(reg) code_selector,
+                 task = out(reg) task_register, options(nomem, nostack, preserves_flags));
+        }
+        assert_eq!(KERNEL_CODE_SELECTOR, code_selector);
+        assert_eq!(TSS_SELECTOR, task_register);
+
+        let gdt = GDTDescriptor::read_current();
+        assert_eq!(GDT_ENTRIES, gdt.entries());
+        // SAFETY: the kernel's GDT is a static, loaded for good
+        let descriptors = unsafe { gdt.descriptors() };
+        let decoded = |selector: u16| descriptors[selector as usize >> 3].decode();
+        assert_eq!(DescriptorKind::Code, decoded(USER_CODE_SELECTOR).kind);
+        assert_eq!(
+            PrivilegeLevel::Ring3,
+            decoded(USER_CODE_SELECTOR).privilege_level
+        );
+        assert_eq!(DescriptorKind::Data, decoded(USER_DATA_SELECTOR).kind);
+        assert_eq!(
+            PrivilegeLevel::Ring3,
+            decoded(USER_DATA_SELECTOR).privilege_level
+        );
+        // Busy once loaded in the task register
+        assert_eq!(
+            DescriptorKind::Tss { busy: true },
+            decoded(TSS_SELECTOR).kind
+        );
+    }
+}
@@ -0,0 +1,679 @@
+use core::arch::{asm, naked_asm};
+
+use common::{
+    ata,
+    control_registers::{ControlRegister4Bit, Cr2, Cr3, Cr4},
+    error, frame, idt, interrupts, keyboard,
+    local_apic::LocalApic,
+    log,
+    msr::{self, MachineCheckBank, MachineCheckGlobalStatus},
+    paging, panicking,
+    pci_capability::Message,
+    pci_function::Function,
+    pic::{self, Irq},
+    ps2,
+    serial::{self, Com1},
+    timer, trace,
+    vectors::Vectors,
+    vga,
+    wait_queue::{self, WaitQueue},
+};
+
+#[cfg(feature = "graphics")]
+use common::mouse::{self, SampleRate};
+
+use crate::{gdb, gdt, time, user_mode};
+
+const PCI_INTERRUPT_SLOTS: usize = 4;
+const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// How the interrupts of a PCI function reach its handler, on the vector they're delivered on
+/// for message signaled ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciInterrupt {
+    MsiX(u8),
+    Msi(u8),
+    Legacy(Irq),
+}
+
+impl PciInterrupt {
+    fn vector(self) -> u8 {
+        match self {
+            PciInterrupt::MsiX(vector) | PciInterrupt::Msi(vector) => vector,
+            PciInterrupt::Legacy(irq) => irq.vector(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PciInterruptRoute {
+    interrupt: PciInterrupt,
+    handler: fn(),
+}
+
+static mut PCI_INTERRUPT_ROUTES: [Option<PciInterruptRoute>; PCI_INTERRUPT_SLOTS] = [None; _];
+
+// Who each vector went to, handlers are only installed on the ones taken here
+static mut VECTORS: Vectors = Vectors::new();
+
+// Only enabled once a PCI function uses message signaled interrupts
+static mut LOCAL_APIC: Option<LocalApic> = None;
+
+// Woken whenever each legacy ATA channel raises its IRQ
+static mut ATA_WAIT_QUEUES: [WaitQueue; ata::CHANNELS.len()] = [const { WaitQueue::new() }; _];
+
+static mut INTERRUPT_DESCRIPTOR_TABLE: idt::LongModeIDT<{ idt::STANDARD_VECTOR_TABLE_SIZE }> =
+    [idt::LongModeGateDescriptor::blank(); _];
+
+/// Generates a naked entry point saving the caller-saved registers around a call to an
+/// `extern "C"` handler, for vectors where the CPU doesn't push an error code
+macro_rules! interrupt_stub {
+    ($stub:ident => $handler:path) => {
+        #[unsafe(naked)]
+        extern "C" fn $stub() {
+            naked_asm!(
+                "push rax", "push rcx", "push rdx", "push rsi", "push rdi",
+                "push r8", "push r9", "push r10", "push r11",
+                // The SysV ABI expects the direction flag to be clear on function entry
+                "cld",
+                "call {handler}",
+                "pop r11", "pop r10", "pop r9", "pop r8",
+                "pop rdi", "pop rsi", "pop rdx", "pop rcx", "pop rax",
+                "iretq",
+                handler = sym $handler,
+            );
+        }
+    };
+    // The CPU pushes an error code for these vectors, which is handed to the handler along with
+    // the rest of the exception stack frame
+    ($stub:ident => $handler:path, error_code) => {
+        #[unsafe(naked)]
+        extern "C" fn $stub() {
+            naked_asm!(
+                "push rax", "push rcx", "push rdx", "push rsi", "push rdi",
+                "push r8", "push r9", "push r10", "push r11",
+                // Keeps the stack 16-byte aligned for the call, as the error code unbalanced it
+                "sub rsp, 8",
+                "lea rdi, [rsp + 10 * 8]",
+                "cld",
+                "call {handler}",
+                "add rsp, 8",
+                "pop r11", "pop r10", "pop r9", "pop r8",
+                "pop rdi", "pop rsi", "pop rdx", "pop rcx", "pop rax",
+                // Discard the error code
+                "add rsp, 8",
+                "iretq",
+                handler = sym $handler,
+            );
+        }
+    };
+}
+
+/// What the CPU pushes on the stack when delivering an exception with an error code. Handlers may
+/// change it to return somewhere else
+#[repr(C)]
+pub struct ExceptionStackFrame {
+    pub error_code: u64,
+    pub instruction_pointer: u64,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: u64,
+    pub stack_segment: u64,
+}
+
+extern "C" fn com1_handler() {
+    Com1::handle_interrupt_no_sync();
+    pic::end_of_interrupt(Irq::Com1);
+}
+
+interrupt_stub!(com1_stub => com1_handler);
+
+extern "C" fn keyboard_handler() {
+    keyboard::handle_interrupt_no_sync();
+    pic::end_of_interrupt(Irq::Keyboard);
+}
+
+interrupt_stub!(keyboard_stub => keyboard_handler);
+
+#[cfg(feature = "graphics")]
+extern "C" fn mouse_handler() {
+    mouse::handle_interrupt_no_sync();
+    pic::end_of_interrupt(Irq::Mouse);
+}
+
+#[cfg(feature = "graphics")]
+interrupt_stub!(mouse_stub => mouse_handler);
+
+extern "C" fn timer_handler() {
+    time::tick_no_sync();
+    // What was logged since the last tick goes on the screen in one go
+    vga::flush_no_sync();
+    pic::end_of_interrupt(Irq::Timer);
+}
+
+interrupt_stub!(timer_stub => timer_handler);
+
+fn ata_handler(channel: usize, irq: Irq) {
+    common::trace_event!(Irq, "ATA channel {}", channel);
+    let (io_port_base_address, _) = ata::CHANNELS[channel];
+    ata::acknowledge_interrupt(io_port_base_address);
+    let wait_queues_ptr = &raw mut ATA_WAIT_QUEUES;
+    // SAFETY: no threads, and the handler isn't reentrant, as it runs with interrupts disabled
+    unsafe { (*wait_queues_ptr)[channel].wake() };
+    pic::end_of_interrupt(irq);
+}
+
+extern "C" fn primary_ata_handler() {
+    ata_handler(0, Irq::PrimaryAta);
+}
+
+interrupt_stub!(primary_ata_stub => primary_ata_handler);
+
+extern "C" fn secondary_ata_handler() {
+    ata_handler(1, Irq::SecondaryAta);
+}
+
+interrupt_stub!(secondary_ata_stub => secondary_ata_handler);
+
+fn pci_interrupt_handler(slot: usize) {
+    let routes_ptr = &raw const PCI_INTERRUPT_ROUTES;
+    // SAFETY: no threads, and routes are only added with interrupts disabled
+    let Some(route) = (unsafe { (*routes_ptr)[slot] }) else {
+        return;
+    };
+    common::trace_event!(Irq, "PCI slot {}: {:?}", slot, route.interrupt);
+    (route.handler)();
+    match route.interrupt {
+        PciInterrupt::Legacy(irq) => pic::end_of_interrupt(irq),
+        PciInterrupt::MsiX(_) | PciInterrupt::Msi(_) => {
+            let local_apic_ptr = &raw const LOCAL_APIC;
+            // SAFETY: no threads, and the local APIC is only set with interrupts disabled
+            if let Some(local_apic) = unsafe { *local_apic_ptr } {
+                local_apic.end_of_interrupt();
+            }
+        }
+    }
+}
+
+/// Generates the entry points of the slots of PCI interrupt routes, which don't know their
+/// vector otherwise
+macro_rules! pci_interrupt_stubs {
+    ($($slot:literal => $stub:ident, $handler:ident;)*) => {
+        $(
+            extern "C" fn $handler() {
+                pci_interrupt_handler($slot);
+            }
+
+            interrupt_stub!($stub => $handler);
+        )*
+
+        const PCI_INTERRUPT_STUBS: [extern "C" fn(); PCI_INTERRUPT_SLOTS] = [$($stub),*];
+    };
+}
+
+pci_interrupt_stubs! {
+    0 => pci_interrupt_0_stub, pci_interrupt_0_handler;
+    1 => pci_interrupt_1_stub, pci_interrupt_1_handler;
+    2 => pci_interrupt_2_stub, pci_interrupt_2_handler;
+    3 => pci_interrupt_3_stub, pci_interrupt_3_handler;
+}
+
+// Spurious interrupts of the local APIC aren't to be acknowledged
+extern "C" fn spurious_handler() {}
+
+interrupt_stub!(spurious_stub => spurious_handler);
+
+extern "C" fn page_fault_handler(stack_frame: &mut ExceptionStackFrame) {
+    let cr2 = Cr2::read().page_fault_linear_address();
+    let error_code = paging::PageFaultErrorCode::from(stack_frame.error_code as u32);
+    match frame::handle_page_fault_no_sync(cr2, error_code) {
+        // The write is retried on the page's own copy
+        Ok(true) => return,
+        Ok(false) => {}
+        Err(fault) => log::error_no_sync!("Copy-on-write at {:#x} failed: {}", cr2, fault),
+    }
+    if user_mode::handle_fault_no_sync(idt::Interrupt::PageFault as u8, stack_frame) {
+        return;
+    }
+    let cr3 = Cr3::read();
+    let page_walk = paging::Mapper::active().page_walk(cr2);
+
+    // This never returns, so the timer won't flush anymore
+    vga::set_batching_no_sync(false);
+    vga::writeln_no_sync!("Page Fault!");
+    vga::writeln_no_sync!("{} at {:#x}", error_code.describe(), cr2);
+    vga::writeln_no_sync!(
+        "RIP={:016X} CS={:04X} RFLAGS={:016X}",
+        stack_frame.instruction_pointer,
+        stack_frame.code_segment,
+        stack_frame.cpu_flags
+    );
+    vga::writeln_no_sync!(
+        "RSP={:016X} SS={:04X}",
+        stack_frame.stack_pointer,
+        stack_frame.stack_segment
+    );
+    vga::writeln_no_sync!("ERROR_CODE={:08X} ({})", u32::from(error_code), error_code);
+    vga::writeln_no_sync!("CR2={:016X} CR3={:016X}", cr2, u64::from(cr3));
+    vga::writeln_no_sync!("{}", page_walk);
+
+    serial::writeln_no_sync!(
+        "Page Fault: {} at {:#x}, RIP={:#x}, error code {:#x} ({})",
+        error_code.describe(),
+        cr2,
+        stack_frame.instruction_pointer,
+        u32::from(error_code),
+        error_code
+    );
+    serial::writeln_no_sync!("{}", page_walk);
+
+    // Only copy-on-write faults can be resolved, returning would just fault again
+    loop {
+        // SAFETY: Halting has no side effects
+        unsafe {
+            asm!("hlt", options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+interrupt_stub!(page_fault_stub => page_fault_handler, error_code);
+
+extern "C" fn general_protection_handler(stack_frame: &mut ExceptionStackFrame) {
+    if user_mode::handle_fault_no_sync(idt::Interrupt::GeneralProtectionFault as u8, stack_frame) {
+        return;
+    }
+    // The error code is the selector at fault, if any
+    panic!(
+        "General protection fault at {:#x}, CS={:#x}, error code {:#x}",
+        stack_frame.instruction_pointer, stack_frame.code_segment, stack_frame.error_code
+    );
+}
+
+interrupt_stub!(general_protection_stub => general_protection_handler, error_code);
+
+/// Runs on a stack of its own, as the one it came from may be what overflowed. Its error code is
+/// always 0
+extern "C" fn double_fault_handler(stack_frame: &ExceptionStackFrame) {
+    panic!(
+        "Double fault at {:#x}, RSP={:#x}",
+        stack_frame.instruction_pointer, stack_frame.stack_pointer
+    );
+}
+
+interrupt_stub!(double_fault_stub => double_fault_handler, error_code);
+
+/// Reports what's known about a hardware error before halting for good, the way the panic handler
+/// does: the machine check banks holding an error, the global error chain, and the trace events
+/// recorded so far, which may tell what the drivers were up to
+fn report_hardware_error_no_sync(what: &str) -> ! {
+    if !panicking::enter_no_sync() {
+        panicking::write_raw("NMI or machine check while handling a panic\n");
+        panicking::halt_no_sync();
+    }
+    vga::set_batching_no_sync(false);
+    vga::writeln_no_sync!("{}!", what);
+    serial::writeln_no_sync!("{}", what);
+    if msr::supports_machine_check_architecture() {
+        let global_status = MachineCheckGlobalStatus::read();
+        vga::writeln_no_sync!(
+            "IA32_MCG_STATUS={:#x} ({})",
+            u64::from(global_status),
+            global_status
+        );
+        serial::writeln_no_sync!(
+            "IA32_MCG_STATUS={:#x} ({})",
+            u64::from(global_status),
+            global_status
+        );
+        for bank in MachineCheckBank::read_all() {
+            vga::writeln_no_sync!("{}", bank);
+            serial::writeln_no_sync!("{}", bank);
+        }
+    }
+    let error_chain = error::get_global_error_chain_no_sync();
+    if !error_chain.is_empty() {
+        vga::writeln_no_sync!("{:#}", error_chain);
+        serial::writeln_no_sync!("{:#}", error_chain);
+    }
+    if Com1::initialized() {
+        let _ = trace::dump(&mut Com1::get());
+    }
+    panicking::halt_no_sync()
+}
+
+/// Raised by the chipset for memory and bus errors, by watchdogs, or by another processor to stop
+/// this one. None of those leave anything to go back to
+extern "C" fn non_maskable_interrupt_handler() {
+    report_hardware_error_no_sync("Non-maskable interrupt");
+}
+
+interrupt_stub!(non_maskable_interrupt_stub => non_maskable_interrupt_handler);
+
+/// Only raised with CR4.MCE set, otherwise machine checks shut the processor down
+extern "C" fn machine_check_handler() {
+    report_hardware_error_no_sync("Machine check");
+}
+
+interrupt_stub!(machine_check_stub => machine_check_handler);
+
+fn code_selector() -> u16 {
+    let code_selector: u16;
+    // SAFETY: Reading the code segment register has no side effects
+    unsafe {
+        asm!("mov {code_selector:x}, cs", code_selector = out(reg) code_selector,
+             options(nomem, nostack, preserves_flags));
+    }
+    code_selector
+}
+
+fn handler_gate(stub: extern "C" fn()) -> idt::LongModeInterruptGateDescriptor {
+    idt::LongModeInterruptGateDescriptor::with_address_and_segment_selector(
+        stub as usize as u64,
+        code_selector(),
+    )
+}
+
+fn set_gate(vector: u8, gate: idt::LongModeInterruptGateDescriptor) {
+    let idt_ptr = &raw mut INTERRUPT_DESCRIPTOR_TABLE;
+    // SAFETY: no threads, and handlers are only installed with interrupts disabled
+    let descriptor = unsafe { &mut (*idt_ptr)[vector as usize] };
+    *descriptor = gate.into();
+}
+
+fn set_handler(vector: u8, stub: extern "C" fn()) {
+    set_gate(vector, handler_gate(stub));
+}
+
+/// Takes the vector of `irq` for `owner` and installs `stub` on it. The handlers are installed at
+/// boot, before anything else takes vectors, so a taken one is a bug to report rather than to stop
+/// for
+fn set_irq_handler(irq: Irq, owner: &'static str, stub: extern "C" fn()) {
+    let vectors_ptr = &raw mut VECTORS;
+    // SAFETY: no threads, and vectors are only taken with interrupts disabled
+    match unsafe { (*vectors_ptr).reserve_irq(irq, owner) } {
+        Ok(vector) => set_handler(vector, stub),
+        Err(fault) => log::error_no_sync!("No handler for {:?}: {}", irq, fault),
+    }
+}
+
+/// Sets up the IDT and the PICs, then enables interrupts with the timer, the keyboard, COM1
+/// reception, the mouse if there's one and the kernel was built with graphics, and the ATA
+/// channels as IRQ sources. Page faults are reported with a dump of the faulting address'
+/// mappings, general protection and double faults panic, on a stack of their own for the latter,
+/// and those raised in ring 3 go back to the kernel through `user_mode`. Breakpoints and single
+/// steps go to the GDB stub if there's a COM2 for it, and NMIs and machine checks halt after
+/// dumping the machine check banks. Needs the kernel's GDT, see `gdt::init`
+pub fn init() {
+    interrupts::disable();
+
+    pic::remap(pic::PIC_1_OFFSET, pic::PIC_2_OFFSET);
+    set_handler(idt::Interrupt::PageFault as u8, page_fault_stub);
+    set_handler(
+        idt::Interrupt::GeneralProtectionFault as u8,
+        general_protection_stub,
+    );
+    match handler_gate(double_fault_stub).with_interrupt_stack(gdt::DOUBLE_FAULT_STACK_INDEX) {
+        Ok(gate) => set_gate(idt::Interrupt::DoubleFault as u8, gate),
+        Err(fault) => log::error_no_sync!("No double fault handler: {}", fault),
+    }
+    set_handler(
+        idt::Interrupt::NonMaskableInterrupt as u8,
+        non_maskable_interrupt_stub,
+    );
+    set_handler(idt::Interrupt::MachineCheck as u8, machine_check_stub);
+    if msr::supports_machine_check_exception() {
+        let mut cr4 = Cr4::read();
+        cr4.set_flag(ControlRegister4Bit::MachineCheckExceptions);
+        cr4.write();
+    }
+    if gdb::init() {
+        set_handler(idt::Interrupt::DebugException as u8, gdb::trap_stub);
+        set_handler(idt::Interrupt::Breakpoint as u8, gdb::trap_stub);
+    }
+    set_irq_handler(Irq::Timer, "timer", timer_stub);
+    set_irq_handler(Irq::Keyboard, "keyboard", keyboard_stub);
+    set_irq_handler(Irq::Com1, "COM1", com1_stub);
+    #[cfg(feature = "graphics")]
+    set_irq_handler(Irq::Mouse, "mouse", mouse_stub);
+    set_irq_handler(Irq::PrimaryAta, "primary ATA channel", primary_ata_stub);
+    set_irq_handler(
+        Irq::SecondaryAta,
+        "secondary ATA channel",
+        secondary_ata_stub,
+    );
+    let vectors_ptr = &raw mut VECTORS;
+    // SAFETY: see set_irq_handler
+    let vectors = unsafe { &mut *vectors_ptr };
+    // The slave PIC never raises the cascade IRQ, which no PCI function is to get, and spurious
+    // interrupts only come once the local APIC is enabled
+    let reserved = vectors
+        .reserve_irq(Irq::Cascade, "PIC cascade")
+        .and_then(|_| vectors.reserve(SPURIOUS_VECTOR, "spurious"));
+    match reserved {
+        Ok(()) => set_handler(SPURIOUS_VECTOR, spurious_stub),
+        Err(fault) => log::error_no_sync!("Fixed vectors not reserved: {}", fault),
+    }
+
+    let idt_ptr = &raw const INTERRUPT_DESCRIPTOR_TABLE;
+    // SAFETY: no threads, and the IDT is not modified while building its descriptor
+    let idt_descriptor = idt::LongModeIDTDescriptor::from(unsafe { &*idt_ptr });
+
+    // SAFETY: The IDT is a static, so it outlives the descriptor being loaded, and every vector
+    // that can fire after `sti` below has a handler installed above
+    unsafe {
+        asm!("lidt [{idt_descriptor}]", idt_descriptor = in(reg) &idt_descriptor);
+    }
+
+    timer::start_timer_0_rate_generator();
+    pic::unmask(Irq::Timer);
+    let mut com1 = Com1::get();
+    com1.enable_receive_interrupts();
+    com1.enable_transmit_interrupts();
+    pic::unmask(Irq::Com1);
+    // The mouse answers its setup commands through the controller's output buffer, which the
+    // keyboard handler would take them from
+    #[cfg(feature = "graphics")]
+    let mouse = mouse::init(SampleRate::Hz100);
+    #[cfg(feature = "graphics")]
+    if let Err(err) = mouse {
+        log::warn_no_sync!("PS/2 mouse not enabled: {}", err);
+    }
+    ps2::flush();
+    pic::unmask(Irq::Keyboard);
+    #[cfg(feature = "graphics")]
+    if mouse.is_ok() {
+        pic::unmask(Irq::Mouse);
+    }
+    // Devices only raise these once switched to interrupts, see `use_ata_interrupts`
+    pic::unmask(Irq::PrimaryAta);
+    pic::unmask(Irq::SecondaryAta);
+
+    interrupts::enable();
+    // The timer flushes the console from now on
+    vga::set_batching_no_sync(true);
+}
+
+/// The local APIC, software enabled the first time it's needed
+fn local_apic() -> Option<LocalApic> {
+    let local_apic_ptr = &raw mut LOCAL_APIC;
+    // SAFETY: no threads, and the local APIC is only set with interrupts disabled
+    if let Some(local_apic) = unsafe { *local_apic_ptr } {
+        return Some(local_apic);
+    }
+    match LocalApic::enable(SPURIOUS_VECTOR) {
+        Ok(local_apic) => {
+            // SAFETY: see above
+            unsafe { *local_apic_ptr = Some(local_apic) };
+            Some(local_apic)
+        }
+        Err(fault) => {
+            log::warn_no_sync!("Local APIC not enabled: {}", fault);
+            None
+        }
+    }
+}
+
+/// Has `function` send its interrupts on `vector` as messages, with MSI-X if `msi_x` and it has
+/// it, or MSI
+fn enable_message_signaled_interrupts(
+    function: &Function,
+    vector: u8,
+    msi_x: bool,
+) -> Option<PciInterrupt> {
+    let msi_x = function.msi_x().filter(|_| msi_x);
+    let msi = function.msi();
+    if msi_x.is_none() && msi.is_none() {
+        return None;
+    }
+    let message = Message::fixed(local_apic()?.id(), vector);
+    if let Some(msi_x) = msi_x {
+        let table = msi_x.table().and_then(|table| {
+            table.program(0, message)?;
+            table.set_masked(0, false)
+        });
+        match table {
+            Ok(()) => {
+                msi_x.enable();
+                msi_x.unmask_all();
+                return Some(PciInterrupt::MsiX(vector));
+            }
+            Err(fault) => log::warn_no_sync!("{}: MSI-X not enabled: {}", function, fault),
+        }
+    }
+    match msi?.enable(message) {
+        Ok(()) => Some(PciInterrupt::Msi(vector)),
+        Err(fault) => {
+            log::warn_no_sync!("{}: MSI not enabled: {}", function, fault);
+            None
+        }
+    }
+}
+
+/// The PIC line of `function`, unless its vector already went to another handler, as handlers
+/// don't chain
+fn legacy_interrupt(function: &Function, vectors: &mut Vectors) -> Option<PciInterrupt> {
+    let irq = Irq::try_from(function.interrupt_line()?).ok()?;
+    vectors.reserve_irq(irq, "PCI function").ok()?;
+    Some(PciInterrupt::Legacy(irq))
+}
+
+/// Routes the interrupts of `function` to `handler`, which runs with interrupts disabled and has
+/// to acknowledge them with the function. They're message signaled if the function can, with
+/// MSI-X only if `msi_x` says its driver expects it, and come on the function's PIC line
+/// otherwise. None if there's no way for them to come
+// Only network cards route their interrupts so far
+#[cfg_attr(not(feature = "net"), allow(dead_code))]
+pub fn route_pci_interrupts(
+    function: &Function,
+    handler: fn(),
+    msi_x: bool,
+) -> Option<PciInterrupt> {
+    interrupts::without_interrupts(|| {
+        let routes_ptr = &raw mut PCI_INTERRUPT_ROUTES;
+        // SAFETY: no threads, and interrupts are disabled so the handlers can't run concurrently
+        let routes = unsafe { &mut *routes_ptr };
+        let vectors_ptr = &raw mut VECTORS;
+        // SAFETY: same as above
+        let vectors = unsafe { &mut *vectors_ptr };
+        let slot = routes.iter().position(Option::is_none)?;
+        let interrupt = match vectors.allocate("PCI function") {
+            Ok(vector) => {
+                let interrupt = enable_message_signaled_interrupts(function, vector, msi_x);
+                if interrupt.is_none() {
+                    // Can't fail, it was just allocated
+                    let _ = vectors.release(vector);
+                }
+                interrupt
+            }
+            Err(fault) => {
+                log::warn_no_sync!("{}: no vector for messages: {}", function, fault);
+                None
+            }
+        }
+        .or_else(|| legacy_interrupt(function, vectors))?;
+        // Messages the function already sent stay pending until interrupts are enabled again,
+        // by which time the route is in place
+        routes[slot] = Some(PciInterruptRoute { interrupt, handler });
+        set_handler(interrupt.vector(), PCI_INTERRUPT_STUBS[slot]);
+        if let PciInterrupt::Legacy(irq) = interrupt {
+            pic::unmask(irq);
+        }
+        Some(interrupt)
+    })
+}
+
+/// Waits on the queue of the ATA channel at `io_port_base_address` until its IRQ comes, or until
+/// `timeout_ns` pass. False on timeouts
+fn wait_for_ata_interrupt(io_port_base_address: u16, timeout_ns: u64) -> bool {
+    let Some(channel) = ata::CHANNELS
+        .iter()
+        .position(|&(channel_io_port_base_address, _)| {
+            channel_io_port_base_address == io_port_base_address
+        })
+    else {
+        return false;
+    };
+    let deadline = time::Deadline::after(timeout_ns);
+    let wait_queues_ptr = &raw mut ATA_WAIT_QUEUES;
+    wait_queue::wait_until(|| {
+        // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+        let woken = unsafe { (*wait_queues_ptr)[channel].take_wakeup() };
+        if woken {
+            Some(true)
+        } else {
+            deadline.has_passed().then_some(false)
+        }
+    })
+}
+
+/// Makes `device` sleep until its channel's IRQ while it reads, instead of spinning
+pub fn use_ata_interrupts(device: &mut ata::Device) {
+    if device.irq().is_some() {
+        device.set_wait_mode(ata::WaitMode::Interrupts(wait_for_ata_interrupt));
+    }
+}
+
+#[cfg(test)]
+// Failing is what test cases panic for
+#[allow(clippy::missing_panics_doc)]
+mod tests {
+    use core::arch::asm;
+
+    use common::pic::Irq;
+
+    use crate::{
+        interrupts::{SPURIOUS_VECTOR, VECTORS, wait_for_ata_interrupt},
+        time::ticks,
+    };
+
+    #[test_case]
+    fn timer_ticks() {
+        let start = ticks();
+        while ticks() == start {
+            // SAFETY: Halting has no side effects, and interrupts are enabled so the next timer
+            // IRQ wakes it up
+            unsafe {
+                asm!("hlt", options(nomem, nostack, preserves_flags));
+            }
+        }
+    }
+
+    #[test_case]
+    fn init_takes_the_fixed_vectors() {
+        let vectors_ptr = &raw const VECTORS;
+        // SAFETY: no threads, and nothing takes vectors while the tests run
+        let vectors = unsafe { &*vectors_ptr };
+        assert_eq!(Some("timer"), vectors.owner(Irq::Timer.vector()));
+        assert_eq!(
+            Some("secondary ATA channel"),
+            vectors.owner(Irq::SecondaryAta.vector())
+        );
+        assert_eq!(Some("spurious"), vectors.owner(SPURIOUS_VECTOR));
+        assert!(vectors.is_free(Irq::Free1.vector()));
+    }
+
+    #[test_case]
+    fn ata_interrupt_wait_on_unknown_channel() {
+        assert!(!wait_for_ata_interrupt(0x1F8, 1_000_000));
+    }
+}
@@ -2,20 +2,142 @@
 #![no_main]
 #![deny(clippy::missing_panics_doc)]
 #![deny(clippy::unwrap_used)]
+#![cfg_attr(test, feature(custom_test_frameworks))]
+#![cfg_attr(test, test_runner(crate::testing::run))]
+#![cfg_attr(test, reexport_test_harness_main = "test_main")]
+
+mod config;
+mod fs;
+mod gdb;
+mod gdt;
+mod interrupts;
+mod memory;
+mod modules;
+#[cfg(feature = "net")]
+mod nic;
+mod shell;
+mod stack_protector;
+mod symbols;
+#[cfg(test)]
+mod testing;
+mod time;
+mod user_mode;
 
 use core::panic::PanicInfo;
 
-use common::vga;
+use common::{
+    boot_info::BootInfo, command_line::CommandLine, fpu, log, panicking, payload::PayloadKind,
+    serial, vga,
+};
+
+static mut BOOT_INFO: BootInfo = BootInfo::empty();
+
+pub fn boot_info() -> &'static BootInfo {
+    let boot_info_ptr = &raw const BOOT_INFO;
+    // SAFETY: BOOT_INFO is only written at the start of _start, before anything reads it
+    unsafe { &*boot_info_ptr }
+}
+
+/// The options the image was built with, empty if it ships no command line
+pub fn command_line() -> CommandLine<'static> {
+    let Some(payload) = boot_info().payload(PayloadKind::CommandLine) else {
+        return CommandLine::default();
+    };
+    // SAFETY: The bootloader loaded the payload below 4GB, in the identity mapped first GB, and
+    // nothing writes over it
+    let bytes = unsafe {
+        core::slice::from_raw_parts(payload.address as usize as *const u8, payload.size as usize)
+    };
+    CommandLine::from_payload(bytes)
+}
 
 /// This function is called on panic.
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    if !panicking::enter_no_sync() {
+        panicking::write_raw("Kernel panicked while handling a panic\n");
+        panicking::halt_no_sync();
+    }
+    // Interrupts won't come again to flush the console
+    vga::set_batching_no_sync(false);
     vga::writeln_no_sync!("{info:#?}");
-    loop {}
+    serial::writeln_no_sync!("Kernel {info}");
+    let kernel_slide = boot_info().kernel_slide;
+    if kernel_slide != 0 {
+        vga::writeln_no_sync!(
+            "Kernel loaded {:#x} bytes above its link address, subtract that from code addresses",
+            kernel_slide
+        );
+    }
+    symbols::print_backtrace_no_sync();
+    panicking::halt_no_sync()
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    testing::panicked(info)
+}
+
+/// What the bootloader ran into on its way here without giving up on booting, which its own log
+/// only showed before the kernel's console was up
+fn log_boot_errors() {
+    let boot_info = boot_info();
+    let Some(errors) = boot_info.boot_errors() else {
+        return;
+    };
+    log::warn_no_sync!("The bootloader booted past errors:\n{}", errors);
+    if boot_info.boot_errors_truncated() {
+        log::warn_no_sync!("The bootloader's errors were cut short");
+    }
+}
+
+/// What the bootloader measured on its way here, needs timer zero running for the timestamp
+/// counter frequency
+#[cfg(feature = "metrics")]
+fn print_boot_metrics() {
+    let metrics = boot_info().metrics;
+    if metrics.is_empty() {
+        vga::writeln_no_sync!("No boot metrics, the bootloader was built without them");
+        return;
+    }
+    let breakdown = metrics.breakdown(common::timer::calibrate_timestamp_counter());
+    vga::writeln_no_sync!("Boot time breakdown:\n{}", breakdown);
+    serial::writeln_no_sync!("Boot time breakdown:\n{}", breakdown);
 }
 
+/// The bootloader passes the address of its BootInfo, which is below 4GB. Only EDI is reliable
+/// after the switch to long mode, hence the u32
 #[unsafe(no_mangle)]
-pub extern "C" fn _start() -> ! {
+pub extern "C" fn _start(boot_info_address: u32) -> ! {
+    // SAFETY: The bootloader's BootInfo is in the identity mapped first GB
+    let boot_info = unsafe { *(boot_info_address as usize as *const BootInfo) };
+    let boot_info_ptr = &raw mut BOOT_INFO;
+    // SAFETY: no threads, and nothing reads BOOT_INFO before this
+    unsafe { *boot_info_ptr = boot_info };
+    config::init();
+    // After the configuration, which may ask for deterministic random numbers. What ran so far
+    // returned with the default guard, and this function never returns
+    stack_protector::init();
+    // The log port is the one the command line asked for, if any, from here on
+    log::start_sinks_no_sync();
     vga::writeln_no_sync!("Hello from the kernel!");
-    loop {}
+    log_boot_errors();
+    if let Err(err) = fpu::init() {
+        log::warn_no_sync!("FPU/SSE not enabled: {}", err);
+    }
+    gdt::init();
+    interrupts::init();
+    #[cfg(feature = "metrics")]
+    print_boot_metrics();
+    memory::init();
+    fs::init();
+    #[cfg(test)]
+    test_main();
+    #[cfg(feature = "net")]
+    if config::get().net {
+        nic::init();
+    }
+    shell::run()
 }
@@ -0,0 +1,85 @@
+// The physical memory the kernel may hand out. What's reserved is gathered once at boot, from what
+// the bootloader passed on and from the PCI BARs, and the frame pool and the heap are set up around
+// it. The kernel's virtual address space starts out with the areas the layout fixes, and hands
+// out the rest of it from `layout::KERNEL_REGIONS`
+use common::{
+    frame, layout, log,
+    pci_function::Function,
+    reserved_regions::{self, MAX_RESERVED_REGIONS, RegionKind, ReservedRegions},
+    vma::{AddressSpace, MAX_VIRTUAL_REGIONS, RegionFlag::*, RegionFlags},
+};
+
+static mut RESERVED_REGIONS: ReservedRegions<MAX_RESERVED_REGIONS> = ReservedRegions::new();
+static mut ADDRESS_SPACE: AddressSpace<MAX_VIRTUAL_REGIONS> =
+    AddressSpace::new(layout::KERNEL_REGIONS);
+
+/// Gathers the reserved regions and takes them out of the frame pool. If they can't all be
+/// recorded, nothing is handed out at all rather than something that may be in use
+pub fn init() {
+    let boot_info = crate::boot_info();
+    let mut complete = true;
+    let mut regions = ReservedRegions::from_boot_info(boot_info).unwrap_or_else(|fault| {
+        log::error_no_sync!("Reserving the boot regions failed: {}", fault);
+        complete = false;
+        ReservedRegions::new()
+    });
+    Function::find(|function| {
+        if let Err(fault) = regions.reserve_base_addresses(function) {
+            log::error_no_sync!("Reserving the BARs of {:?} failed: {}", function, fault);
+            complete = false;
+        }
+        false
+    });
+    if !complete {
+        log::warn_no_sync!("Not all reserved regions are known, not handing out any memory");
+        regions = ReservedRegions::new();
+        let _ = regions.reserve(0..u64::MAX, RegionKind::Firmware);
+    }
+
+    let reserved_frames =
+        frame::with_frames(|frames| frames.reserve(&regions, boot_info.memory_map()));
+    if reserved_frames != 0 {
+        log::info_no_sync!("{} frames of the frame pool are reserved", reserved_frames);
+    }
+    let heap = layout::HEAP_START..layout::HEAP_START + layout::HEAP_SIZE;
+    if !reserved_regions::is_available(&regions, boot_info.memory_map(), &heap) {
+        log::warn_no_sync!("The heap at {:#x?} isn't free RAM", heap);
+    }
+
+    let regions_ptr = &raw mut RESERVED_REGIONS;
+    // SAFETY: no threads, and nothing reads RESERVED_REGIONS before `init`
+    unsafe { *regions_ptr = regions };
+
+    let fixed_areas: [(_, RegionFlags); 4] = [
+        (
+            boot_info.kernel_start..boot_info.kernel_end,
+            Write | Execute,
+        ),
+        (heap, Write.into()),
+        (layout::MMIO_WINDOW, Write | Uncacheable),
+        (
+            layout::PHYSICAL_MEMORY_OFFSET
+                ..layout::PHYSICAL_MEMORY_OFFSET + layout::MMIO_WINDOW.start,
+            Write.into(),
+        ),
+    ];
+    let address_space = address_space_no_sync();
+    for (range, flags) in fixed_areas {
+        if let Err(fault) = address_space.reserve(range.clone(), flags) {
+            log::error_no_sync!("Recording {:#x?} as in use failed: {}", range, fault);
+        }
+    }
+}
+
+pub fn reserved_regions() -> &'static ReservedRegions<MAX_RESERVED_REGIONS> {
+    let regions_ptr = &raw const RESERVED_REGIONS;
+    // SAFETY: RESERVED_REGIONS is only written by `init`, before anything reads it
+    unsafe { &*regions_ptr }
+}
+
+/// The kernel's virtual address space, to ask for a range of instead of picking an address
+pub fn address_space_no_sync() -> &'static mut AddressSpace<MAX_VIRTUAL_REGIONS> {
+    let address_space_ptr = &raw mut ADDRESS_SPACE;
+    // SAFETY: no threads, and callers don't hold on to it across calls that may use it too
+    unsafe { &mut *address_space_ptr }
+}
@@ -0,0 +1,83 @@
+// Kernel modules: relocatable objects read from the boot disk, linked against the functions in
+// `exported_symbol` and run. There's no unloading, their memory is handed out from an arena once
+// and for all
+
+use common::{
+    ata,
+    error::{Context, Error, Facility, Fault},
+    module, serial, vga,
+};
+
+use crate::interrupts;
+
+const ARENA_SIZE: usize = 256 * 1024;
+pub const MAX_MODULE_SECTORS: u8 = 128;
+const SECTOR_SIZE: usize = 512;
+
+// Page aligned, so that sections keep any alignment they ask for. The kernel maps its memory
+// executable, so code can run from here
+#[repr(C, align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+static mut ARENA_USED: usize = 0;
+static mut OBJECT_BUFFER: [u8; MAX_MODULE_SECTORS as usize * SECTOR_SIZE] =
+    [0; MAX_MODULE_SECTORS as usize * SECTOR_SIZE];
+
+/// Prints a line to the console and COM1
+extern "C" fn kernel_print(bytes: *const u8, length: usize) {
+    // SAFETY: modules are trusted to pass a valid buffer, like any other kernel code
+    let bytes = unsafe { core::slice::from_raw_parts(bytes, length) };
+    let string = core::str::from_utf8(bytes).unwrap_or("<invalid UTF-8>");
+    vga::writeln_no_sync!("{}", string);
+    serial::writeln_no_sync!("{}", string);
+}
+
+/// What modules can link against
+fn exported_symbol(name: &str) -> Option<u64> {
+    match name {
+        "kernel_print" => Some(kernel_print as extern "C" fn(*const u8, usize) as usize as u64),
+        _ => None,
+    }
+}
+
+/// Reads a module of `sectors` sectors from `lba` on the primary ATA master, loads it and runs
+/// its init function, returning what it returned
+pub fn load_from_disk(lba: u32, sectors: u8) -> Result<i32, Error> {
+    if sectors == 0 || sectors > MAX_MODULE_SECTORS {
+        return Err(Error::new(
+            Fault::TooManySectors(sectors.into()),
+            Context::LoadingModule,
+            Facility::KernelModule,
+        ));
+    }
+    let mut device = ata::Device::identify(
+        ata::PRIMARY_BUS_IO_PORT_BASE_ADDRESS,
+        ata::PRIMARY_BUS_CONTROL_PORT_BASE_ADDRESS,
+        false,
+    )?;
+    interrupts::use_ata_interrupts(&mut device);
+    let object_buffer_ptr = &raw mut OBJECT_BUFFER;
+    // SAFETY: no threads, and the buffer is only used here, which isn't reentrant
+    let object_buffer = unsafe { &mut *object_buffer_ptr };
+    let object = &mut object_buffer[..sectors as usize * SECTOR_SIZE];
+    device.read_sectors_lba28_pio(sectors, lba, object)?;
+
+    let arena_used_ptr = &raw mut ARENA_USED;
+    // SAFETY: same as above
+    let arena_used = unsafe { &mut *arena_used_ptr };
+    let arena_ptr = &raw mut ARENA;
+    // SAFETY: same as above
+    let arena = unsafe { &mut *arena_ptr };
+    // Modules only get the part of the arena after the used one
+    let memory = &mut arena.0[*arena_used..];
+    let module = module::load(object, memory, exported_symbol)?;
+    // Keep the next module page aligned too
+    *arena_used = (*arena_used + module.size).next_multiple_of(4096);
+
+    // SAFETY: `load` checked the init symbol is defined in the module, which is now relocated in
+    // executable memory, and modules are built to export it with this signature
+    let init =
+        unsafe { core::mem::transmute::<usize, extern "C" fn() -> i32>(module.init as usize) };
+    Ok(init())
+}
@@ -0,0 +1,246 @@
+use common::{
+    e1000::E1000,
+    error::Fault,
+    log,
+    net::{
+        Interface, Ipv4Address, MacAddress, Outgoing, Stack,
+        dhcp::{self, Lease},
+        udp::SocketHandle,
+    },
+    random,
+    virtio_net::VirtioNet,
+};
+
+use crate::{config, interrupts, time::Deadline};
+
+/// Largest frame any of the drivers accepts, without the CRC
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+/// The network card the kernel drives, behind the API the drivers share
+#[derive(Clone, Copy)]
+pub enum Nic {
+    E1000,
+    VirtioNet,
+}
+
+struct Dhcp {
+    client: dhcp::Client,
+    socket: SocketHandle,
+    retransmit_deadline: Deadline,
+}
+
+static mut NIC: Option<Nic> = None;
+static mut STACK: Option<Stack> = None;
+static mut DHCP: Option<Dhcp> = None;
+
+/// Brings up the first network card found, preferring virtio-net, which is much cheaper for QEMU
+/// to emulate. Its interrupt is used if it can be routed, receiving works by polling either way.
+/// The card gets its address with DHCP, from `poll`
+pub fn init() {
+    let (nic, function) = match VirtioNet::initialize() {
+        Ok(function) => (Nic::VirtioNet, function),
+        Err(virtio_err) => match E1000::initialize() {
+            Ok(function) => (Nic::E1000, function),
+            Err(e1000_err) => {
+                log::warn_no_sync!("No network card:\n{}\n{}", virtio_err, e1000_err);
+                return;
+            }
+        },
+    };
+    let nic_ptr = &raw mut NIC;
+    // SAFETY: no threads, and the card's interrupt handler isn't installed yet
+    unsafe { *nic_ptr = Some(nic) };
+    if let Some(mac_address) = nic.mac_address() {
+        let mut stack = Stack::new(Interface::new(MacAddress(mac_address)));
+        match stack.sockets.bind(dhcp::CLIENT_PORT) {
+            Ok(socket) => {
+                let dhcp_ptr = &raw mut DHCP;
+                // SAFETY: no threads, and only `poll` uses the DHCP client, which isn't running
+                // yet
+                unsafe {
+                    *dhcp_ptr = Some(Dhcp {
+                        client: dhcp::Client::new(
+                            MacAddress(mac_address),
+                            random::entropy() as u32,
+                        ),
+                        socket,
+                        retransmit_deadline: Deadline::after(
+                            config::get().dhcp_retransmit_timeout_ns,
+                        ),
+                    })
+                };
+            }
+            Err(err) => log::warn_no_sync!("{}: no DHCP: {}", nic.name(), err),
+        }
+        let stack_ptr = &raw mut STACK;
+        // SAFETY: no threads, and only `poll` uses the stack, which isn't running yet
+        unsafe { *stack_ptr = Some(stack) };
+    }
+
+    // Neither driver sets up MSI-X vectors, without which virtio-net doesn't interrupt at all
+    match interrupts::route_pci_interrupts(&function, handle_interrupt, false) {
+        Some(interrupt) => log::info_no_sync!("{}: interrupts through {:?}", nic.name(), interrupt),
+        None => log::warn_no_sync!(
+            "{}: no usable interrupt, receive by polling only",
+            nic.name()
+        ),
+    }
+}
+
+fn handle_interrupt() {
+    if let Some(nic) = get() {
+        nic.handle_interrupt_no_sync();
+    }
+}
+
+pub fn get() -> Option<Nic> {
+    let nic_ptr = &raw const NIC;
+    // SAFETY: no threads, and NIC is only written by `init`
+    unsafe { *nic_ptr }
+}
+
+/// Unspecified until DHCP hands out an address
+pub fn ipv4_address() -> Ipv4Address {
+    let stack_ptr = &raw const STACK;
+    // SAFETY: no threads, and the stack is only modified by `init` and `poll`, which don't call
+    // this
+    let stack = unsafe { &*stack_ptr };
+    stack.as_ref().map_or(Ipv4Address::UNSPECIFIED, |stack| {
+        stack.interface.ipv4_address
+    })
+}
+
+/// The lease DHCP got, if it's done
+pub fn dhcp_lease() -> Option<Lease> {
+    let dhcp_ptr = &raw const DHCP;
+    // SAFETY: no threads, and the DHCP client is only modified by `init` and `poll`, which don't
+    // call this
+    let dhcp = unsafe { &*dhcp_ptr };
+    match dhcp.as_ref()?.client.state() {
+        dhcp::State::Bound(lease) => Some(lease),
+        _ => None,
+    }
+}
+
+/// Runs the network stack over the frames received since the last call, sending the replies,
+/// and moves DHCP along. Malformed frames are dropped
+pub fn poll() {
+    let Some(nic) = get() else {
+        return;
+    };
+    let stack_ptr = &raw mut STACK;
+    // SAFETY: no threads, and `poll` is only called from the shell's idle loop, not from
+    // interrupt handlers, so this is the only reference to the stack
+    let Some(stack) = (unsafe { &mut *stack_ptr }).as_mut() else {
+        return;
+    };
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    let mut reply = [0u8; MAX_FRAME_SIZE];
+    while let Some(length) = nic.poll_recv(&mut frame) {
+        common::trace_event!(Net, "{}: received {} bytes", nic.name(), length);
+        if let Ok(Some(reply_length)) = stack.handle_frame(&frame[..length], &mut reply) {
+            // Nothing to do about a reply that couldn't be sent, the peer will retry
+            let _ = nic.send(&reply[..reply_length]);
+        }
+    }
+
+    let dhcp_ptr = &raw mut DHCP;
+    // SAFETY: same as above
+    if let Some(dhcp) = (unsafe { &mut *dhcp_ptr }).as_mut() {
+        poll_dhcp(nic, stack, dhcp);
+    }
+}
+
+fn poll_dhcp(nic: Nic, stack: &mut Stack, dhcp: &mut Dhcp) {
+    let mut send_now = false;
+    while let Some(received) = stack.sockets.receive(dhcp.socket) {
+        let state = dhcp.client.state();
+        // Malformed messages are dropped, like any other
+        if let Ok(Some(lease)) = dhcp.client.handle_message(received.payload()) {
+            configure(nic, &mut stack.interface, &lease);
+        }
+        // Answer an offer right away rather than at the next retransmission
+        send_now |= dhcp.client.state() != state;
+    }
+
+    if !send_now && !dhcp.retransmit_deadline.has_passed() {
+        return;
+    }
+    dhcp.retransmit_deadline = Deadline::after(config::get().dhcp_retransmit_timeout_ns);
+    let mut message = [0u8; dhcp::MAX_MESSAGE_SIZE];
+    let Ok(Some(message_length)) = dhcp.client.write_message(&mut message) else {
+        return;
+    };
+    // Until bound, the interface has no address and the datagram goes out from 0.0.0.0. It's
+    // broadcast, so there's never an ARP request to send first
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    if let Ok(Outgoing::Datagram(length) | Outgoing::ArpRequest(length)) = stack.write_udp_frame(
+        &mut frame,
+        dhcp::CLIENT_PORT,
+        Ipv4Address::BROADCAST,
+        dhcp::SERVER_PORT,
+        &message[..message_length],
+    ) {
+        // Lost messages are sent again when the timer runs out
+        let _ = nic.send(&frame[..length]);
+    }
+}
+
+fn configure(nic: Nic, interface: &mut Interface, lease: &Lease) {
+    interface.ipv4_address = lease.address;
+    interface.subnet_mask = lease.subnet_mask;
+    interface.gateway = lease.router;
+    log::info_no_sync!("{}: DHCP lease {}", nic.name(), lease);
+}
+
+impl Nic {
+    pub fn name(self) -> &'static str {
+        match self {
+            Nic::E1000 => "e1000",
+            Nic::VirtioNet => "virtio-net",
+        }
+    }
+
+    pub fn mac_address(self) -> Option<[u8; 6]> {
+        match self {
+            Nic::E1000 => E1000::mac_address(),
+            Nic::VirtioNet => VirtioNet::mac_address(),
+        }
+    }
+
+    pub fn link_up(self) -> bool {
+        match self {
+            Nic::E1000 => E1000::link_up(),
+            Nic::VirtioNet => VirtioNet::link_up(),
+        }
+    }
+
+    /// Frames received and frames dropped
+    pub fn statistics(self) -> (u64, u64) {
+        match self {
+            Nic::E1000 => E1000::statistics(),
+            Nic::VirtioNet => VirtioNet::statistics(),
+        }
+    }
+
+    pub fn send(self, frame: &[u8]) -> Result<(), Fault> {
+        match self {
+            Nic::E1000 => E1000::send(frame),
+            Nic::VirtioNet => VirtioNet::send(frame),
+        }
+    }
+
+    pub fn poll_recv(self, buffer: &mut [u8]) -> Option<usize> {
+        match self {
+            Nic::E1000 => E1000::poll_recv(buffer),
+            Nic::VirtioNet => VirtioNet::poll_recv(buffer),
+        }
+    }
+
+    pub fn handle_interrupt_no_sync(self) {
+        match self {
+            Nic::E1000 => E1000::handle_interrupt_no_sync(),
+            Nic::VirtioNet => VirtioNet::handle_interrupt_no_sync(),
+        }
+    }
+}
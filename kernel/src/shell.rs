@@ -0,0 +1,654 @@
+use core::{arch::asm, fmt::Write, str::SplitAsciiWhitespace};
+
+#[cfg(feature = "net")]
+use common::net::MacAddress;
+use common::{
+    ata,
+    control_registers::{Cr0, Cr2, Cr3, Cr4, Efer},
+    devfs::{self, DeviceKind},
+    frame,
+    gdt::{GDTDescriptor, SegmentDescriptor},
+    hexdump::HexDump,
+    ioport::Port,
+    keyboard::{self, Layout},
+    log,
+    msr::PageAttributeTable,
+    paging,
+    payload::PayloadKind,
+    pci_function::Function,
+    serial,
+    serial::Com1,
+    tmpfs::NodeKind,
+    user_program, wait_queue,
+};
+#[cfg(feature = "graphics")]
+use common::{mouse, vga};
+
+#[cfg(feature = "net")]
+use crate::nic;
+use crate::{config, fs, gdb, interrupts, memory, modules, time};
+
+const LINE_BUFFER_SIZE: usize = 128;
+const DEFAULT_MEM_DUMP_SIZE: usize = 64;
+const MAX_MEM_DUMP_SIZE: usize = 512;
+// The first LBA a PIO read with a 28-bit address can't reach
+const LBA28_LIMIT: u64 = 1 << 28;
+
+// https://wiki.osdev.org/Reboot#Keyboard_controller
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+const KEYBOARD_CONTROLLER_INPUT_BUFFER_FULL: u8 = 0x2;
+const PULSE_RESET_LINE: u8 = 0xFE;
+
+const MAX_EXEC_ARGUMENTS: usize = 16;
+
+#[cfg(feature = "net")]
+const ETHERTYPE_LOCAL_EXPERIMENTAL: u16 = 0x88B5;
+#[cfg(feature = "net")]
+const TEST_FRAME_PAYLOAD: &[u8] = b"hello from blog_os";
+
+/// Writes a line both to the VGA console, if the kernel was built with one, and to COM1
+macro_rules! shell_writeln {
+    ($($args:tt)*) => {{
+        #[cfg(feature = "graphics")]
+        vga::writeln_no_sync!($($args)*);
+        serial::writeln_no_sync!($($args)*);
+    }};
+}
+
+/// Runs the debug monitor forever, reading commands from COM1 and the keyboard and printing their
+/// output to both the console and COM1
+pub fn run() -> ! {
+    let mut serial = Com1::get();
+    let mut line_buffer = [0u8; LINE_BUFFER_SIZE];
+
+    shell_writeln!("Debug shell ready, type 'help' for the list of commands");
+    loop {
+        let _ = write!(serial, "> ");
+        // Network packets are handled while waiting for commands
+        let line = serial.read_line(&mut line_buffer, || {
+            #[cfg(feature = "net")]
+            nic::poll();
+            keyboard::read_char().or_else(|| {
+                wait_for_input();
+                None
+            })
+        });
+        // COM1 already echoed the line back while it was being typed
+        #[cfg(feature = "graphics")]
+        vga::writeln_no_sync!("> {}", line);
+
+        let mut arguments = line.split_ascii_whitespace();
+        match arguments.next() {
+            None => {}
+            Some("help") => help(),
+            Some("mem") => mem(&mut arguments),
+            Some("regs") => regs(),
+            Some("gdt") => gdt(),
+            Some("translate") => translate(&mut arguments),
+            Some("mappings") => mappings(&mut arguments),
+            Some("memmap") => memmap(),
+            Some("vmas") => vmas(),
+            Some("dmesg") => log::for_each_line_no_sync(|line| shell_writeln!("{}", line)),
+            Some("trace") => trace(),
+            Some("pci") => pci(),
+            Some("ata") => ata(&mut arguments),
+            #[cfg(feature = "net")]
+            Some("net") => net(&mut arguments),
+            Some("insmod") => insmod(&mut arguments),
+            Some("exec") => exec(&mut arguments),
+            Some("fs") => fs(&mut arguments),
+            Some("gdb") => {
+                shell_writeln!("waiting for GDB on COM2");
+                gdb::break_into_debugger();
+            }
+            Some("keymap") => keymap(&mut arguments),
+            #[cfg(feature = "graphics")]
+            Some("mouse") => mouse(),
+            Some("reboot") => reboot(),
+            Some(command) => shell_writeln!("unknown command '{}', try 'help'", command),
+        }
+    }
+}
+
+fn help() {
+    shell_writeln!("mem <address> [length]  dump memory (hex, or decimal without 0x)");
+    shell_writeln!("regs                    print control and general purpose registers");
+    shell_writeln!("gdt                     decode the descriptors of the loaded GDT");
+    shell_writeln!("translate <address>     show how an address is mapped by the page tables");
+    shell_writeln!("mappings [start] [end]  list the mapped virtual address ranges");
+    shell_writeln!("memmap                  print the BIOS memory map and the reserved regions");
+    shell_writeln!("vmas                    list the kernel's virtual memory regions");
+    shell_writeln!("dmesg                   print the log lines kept since boot");
+    shell_writeln!("trace                   dump the trace events to COM1 as JSON lines");
+    shell_writeln!("pci                     list the devices on the PCI buses");
+    shell_writeln!("ata list                list the ATA and BIOS drives the bootloader found");
+    shell_writeln!("ata smart               SMART health of the primary ATA master");
+    shell_writeln!("ata read <lba>          read a sector from the primary ATA master");
+    #[cfg(feature = "net")]
+    shell_writeln!("net [send]              network status, or broadcast a test frame");
+    shell_writeln!("insmod <lba> <sectors>  load and run a kernel module from the ATA master");
+    shell_writeln!("exec [args]             load the initrd as a user program, without running it");
+    shell_writeln!("fs ls [path]            list a directory of the root tmpfs, or /dev");
+    shell_writeln!("fs cat <path>           print a file, or what a device has to read");
+    shell_writeln!("fs mkdir|rm <path>      make a directory, or remove a file or an empty one");
+    shell_writeln!("fs write <path> <text>  append a line to a file, creating it if needed");
+    shell_writeln!("fs mv <from> <to>       move or rename a file or directory");
+    shell_writeln!("gdb                     stop and wait for GDB on COM2");
+    shell_writeln!("keymap [us|de|it]       show or change the keyboard layout");
+    #[cfg(feature = "graphics")]
+    shell_writeln!("mouse                   print the mouse events since the last time");
+    shell_writeln!("reboot                  reset the machine through the keyboard controller");
+}
+
+fn parse_number(string: &str) -> Option<u64> {
+    match string
+        .strip_prefix("0x")
+        .or_else(|| string.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => string.parse().ok(),
+    }
+}
+
+fn dump_bytes(base_address: u64, bytes: &[u8]) {
+    shell_writeln!("{}", HexDump::new(base_address, bytes));
+}
+
+fn mem(arguments: &mut SplitAsciiWhitespace) {
+    let Some(address) = arguments.next().and_then(parse_number) else {
+        shell_writeln!("usage: mem <address> [length]");
+        return;
+    };
+    let length = match arguments.next().map(parse_number) {
+        None => DEFAULT_MEM_DUMP_SIZE,
+        Some(Some(length)) => (length as usize).min(MAX_MEM_DUMP_SIZE),
+        Some(None) => {
+            shell_writeln!("usage: mem <address> [length]");
+            return;
+        }
+    };
+
+    let mut bytes = [0u8; MAX_MEM_DUMP_SIZE];
+    for (i, byte) in bytes[..length].iter_mut().enumerate() {
+        // SAFETY: this is a debugging tool, the user is trusted to only ask for mapped addresses.
+        // Reading volatile so that MMIO regions can be inspected too
+        *byte = unsafe { core::ptr::read_volatile((address as usize + i) as *const u8) };
+    }
+    dump_bytes(address, &bytes[..length]);
+}
+
+fn regs() {
+    let (rsp, rbp, rflags): (u64, u64, u64);
+    let (cs, ss): (u16, u16);
+    // SAFETY: Reading these registers has no side effects
+    unsafe {
+        asm!(
+            "mov {rsp}, rsp",
+            "mov {rbp}, rbp",
+            "pushf",
+            "pop {rflags}",
+            "mov {cs:x}, cs",
+            "mov {ss:x}, ss",
+            rsp = out(reg) rsp,
+            rbp = out(reg) rbp,
+            rflags = out(reg) rflags,
+            cs = out(reg) cs,
+            ss = out(reg) ss,
+        );
+    }
+
+    shell_writeln!("RSP={:016X} RBP={:016X} RFLAGS={:016X}", rsp, rbp, rflags);
+    shell_writeln!("CS={:04X} SS={:04X}", cs, ss);
+
+    let cr0 = Cr0::read();
+    let cr3 = Cr3::read();
+    let cr4 = Cr4::read();
+    let efer = Efer::read();
+    shell_writeln!("CR0={:016X} ({})", u32::from(cr0), cr0);
+    shell_writeln!("CR2={:016X}", Cr2::read().page_fault_linear_address());
+    shell_writeln!("CR3={:016X}", u64::from(cr3));
+    shell_writeln!("CR4={:016X} ({})", u32::from(cr4), cr4);
+    shell_writeln!("EFER={:016X} ({})", u64::from(efer), efer);
+    shell_writeln!("PAT: {}", PageAttributeTable::read());
+}
+
+fn gdt() {
+    let gdt = GDTDescriptor::read_current();
+    shell_writeln!("GDT at {:#x}, {} entries", gdt.address(), gdt.entries());
+    // SAFETY: The GDT is the bootloader's, in the identity mapped first GB, and stays loaded
+    let descriptors = unsafe { gdt.descriptors() };
+    for (index, descriptor) in descriptors.iter().enumerate() {
+        shell_writeln!(
+            "{:#06x} {}",
+            index * size_of::<SegmentDescriptor>(),
+            descriptor.decode()
+        );
+    }
+}
+
+fn translate(arguments: &mut SplitAsciiWhitespace) {
+    let Some(address) = arguments.next().and_then(parse_number) else {
+        shell_writeln!("usage: translate <address>");
+        return;
+    };
+    shell_writeln!("{}", paging::Mapper::active().page_walk(address));
+}
+
+fn mappings(arguments: &mut SplitAsciiWhitespace) {
+    let mut bound = |default| match arguments.next() {
+        None => Some(default),
+        Some(argument) => parse_number(argument),
+    };
+    let (Some(start), Some(end)) = (bound(0), bound(u64::MAX)) else {
+        shell_writeln!("usage: mappings [start] [end]");
+        return;
+    };
+    shell_writeln!("{}", paging::Mapper::active().mappings(start..end));
+}
+
+fn pci() {
+    Function::find(|function| {
+        shell_writeln!("{}", function);
+        false
+    });
+}
+
+fn ata(arguments: &mut SplitAsciiWhitespace) {
+    let command = arguments.next();
+    if command == Some("list") {
+        let boot_info = crate::boot_info();
+        for drive in boot_info.drives() {
+            let is_boot_drive = boot_info.boot_drive() == Some(drive);
+            shell_writeln!("{}{}", drive, if is_boot_drive { " (boot)" } else { "" });
+        }
+        for drive in boot_info.bios_drives() {
+            let is_boot_drive = boot_info.boot_bios_drive() == Some(drive);
+            shell_writeln!("{}{}", drive, if is_boot_drive { " (boot)" } else { "" });
+        }
+        return;
+    }
+    if command == Some("smart") {
+        ata_smart();
+        return;
+    }
+    let (Some("read"), Some(lba)) = (command, arguments.next().and_then(parse_number)) else {
+        shell_writeln!("usage: ata list | ata smart | ata read <lba>");
+        return;
+    };
+    if lba >= LBA28_LIMIT {
+        shell_writeln!("LBA {:#x} doesn't fit in 28 bits", lba);
+        return;
+    }
+    let lba = lba as u32;
+
+    let mut device = match ata::Device::identify(
+        ata::PRIMARY_BUS_IO_PORT_BASE_ADDRESS,
+        ata::PRIMARY_BUS_CONTROL_PORT_BASE_ADDRESS,
+        false,
+    ) {
+        Ok(device) => device,
+        Err(err) => {
+            shell_writeln!("{}", err);
+            return;
+        }
+    };
+    interrupts::use_ata_interrupts(&mut device);
+
+    let mut sector = [0u8; 512];
+    match device.read_sectors_lba28_pio(1, lba, &mut sector) {
+        Ok(()) => dump_bytes(0, &sector),
+        Err(err) => shell_writeln!("{}", err),
+    }
+}
+
+fn ata_smart() {
+    let mut device = match ata::Device::identify(
+        ata::PRIMARY_BUS_IO_PORT_BASE_ADDRESS,
+        ata::PRIMARY_BUS_CONTROL_PORT_BASE_ADDRESS,
+        false,
+    ) {
+        Ok(device) => device,
+        Err(err) => {
+            shell_writeln!("{}", err);
+            return;
+        }
+    };
+    interrupts::use_ata_interrupts(&mut device);
+    let report = device.smart_enable().and_then(|()| {
+        let status = device.smart_status()?;
+        let attributes = device.smart_attributes()?;
+        Ok((status, attributes))
+    });
+    match report {
+        Ok((status, attributes)) => {
+            shell_writeln!("status: {:?}", status);
+            for attribute in attributes.iter() {
+                shell_writeln!("{}", attribute);
+            }
+        }
+        Err(err) => shell_writeln!("{}", err),
+    }
+}
+
+#[cfg(feature = "net")]
+fn net(arguments: &mut SplitAsciiWhitespace) {
+    let Some((nic, Some(mac_address))) = nic::get().map(|nic| (nic, nic.mac_address())) else {
+        shell_writeln!("no network card");
+        return;
+    };
+    match arguments.next() {
+        None => {
+            let (received, dropped) = nic.statistics();
+            shell_writeln!(
+                "{}: MAC {}, IPv4 {}, link {}, {} frames received, {} dropped",
+                nic.name(),
+                MacAddress(mac_address),
+                nic::ipv4_address(),
+                if nic.link_up() { "up" } else { "down" },
+                received,
+                dropped
+            );
+            match nic::dhcp_lease() {
+                Some(lease) => shell_writeln!("DHCP lease {}", lease),
+                None => shell_writeln!("waiting for a DHCP lease"),
+            }
+        }
+        Some("send") => {
+            // Broadcast with the local experimental EtherType, padded to the minimum frame size
+            let mut frame = [0u8; 60];
+            frame[..6].fill(0xff);
+            frame[6..12].copy_from_slice(&mac_address);
+            frame[12..14].copy_from_slice(&ETHERTYPE_LOCAL_EXPERIMENTAL.to_be_bytes());
+            frame[14..14 + TEST_FRAME_PAYLOAD.len()].copy_from_slice(TEST_FRAME_PAYLOAD);
+            if let Err(err) = nic.send(&frame) {
+                shell_writeln!("{}", err);
+            }
+        }
+        Some(_) => shell_writeln!("usage: net [send]"),
+    }
+}
+
+fn insmod(arguments: &mut SplitAsciiWhitespace) {
+    let (Some(lba), Some(sectors)) = (
+        arguments.next().and_then(parse_number),
+        arguments.next().and_then(parse_number),
+    ) else {
+        shell_writeln!("usage: insmod <lba> <sectors>");
+        return;
+    };
+    let lba = u32::try_from(lba)
+        .ok()
+        .filter(|&lba| u64::from(lba) < LBA28_LIMIT);
+    let (Some(lba), Ok(sectors)) = (lba, u8::try_from(sectors)) else {
+        shell_writeln!(
+            "LBA must fit in 28 bits, and at most {} sectors",
+            modules::MAX_MODULE_SECTORS
+        );
+        return;
+    };
+    match modules::load_from_disk(lba, sectors) {
+        Ok(result) => shell_writeln!("module_init returned {}", result),
+        Err(err) => shell_writeln!("{}", err),
+    }
+}
+
+fn exec(arguments: &mut SplitAsciiWhitespace) {
+    let Some(payload) = crate::boot_info().payload(PayloadKind::Initrd) else {
+        shell_writeln!("no initrd to load");
+        return;
+    };
+    // SAFETY: The bootloader loaded the payload below 4GB, in the identity mapped first GB, and
+    // nothing writes over it
+    let bytes = unsafe {
+        core::slice::from_raw_parts(payload.address as usize as *const u8, payload.size as usize)
+    };
+    let mut argv = ["initrd"; MAX_EXEC_ARGUMENTS];
+    let mut argc = 1;
+    for (slot, argument) in argv[1..].iter_mut().zip(arguments) {
+        *slot = argument;
+        argc += 1;
+    }
+
+    let result = frame::with_frames(|frames| {
+        user_program::load(
+            bytes,
+            &argv[..argc],
+            &[],
+            config::get().user_stack_size,
+            &paging::Mapper::active(),
+            frames,
+        )
+    });
+    let program = match result {
+        Ok(program) => program,
+        Err(error) => {
+            shell_writeln!("loading the initrd failed: {}", error);
+            return;
+        }
+    };
+    shell_writeln!(
+        "entrypoint {:#x}, stack pointer {:#x}, PML4 at {:#x}",
+        program.entrypoint,
+        program.stack_pointer,
+        program.pml4_physical_address()
+    );
+    for region in program.memory.regions().regions() {
+        shell_writeln!("{}", region);
+    }
+    // user_mode can run code in ring 3, but programs would only come back by faulting, without
+    // system calls to exit through
+    shell_writeln!("not running it: there are no system calls to exit through yet");
+    if let Err(fault) = frame::with_frames(|frames| program.unload(frames)) {
+        shell_writeln!("unloading it failed: {}", fault);
+    }
+}
+
+fn fs(arguments: &mut SplitAsciiWhitespace) {
+    let command = arguments.next();
+    let path = arguments.next();
+    let is_device = |path: &&str| {
+        path.strip_prefix(devfs::MOUNT_POINT)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    if let Some(path) = path.filter(is_device) {
+        device(command, path, arguments);
+        return;
+    }
+    let root = fs::root_no_sync();
+    let result = match (command, path) {
+        (Some("ls"), path) => root.lookup(path.unwrap_or("/")).and_then(|node| {
+            for (name, child) in root.children(node)? {
+                match root.kind(child)? {
+                    NodeKind::Directory => shell_writeln!("{}/", name),
+                    NodeKind::File => shell_writeln!("{:<24}{}", name, root.size(child)?),
+                }
+            }
+            Ok(())
+        }),
+        (Some("cat"), Some(path)) => root.lookup(path).and_then(|file| {
+            let mut buffer = [0u8; MAX_MEM_DUMP_SIZE];
+            let mut offset = 0;
+            loop {
+                let read = root.read(file, offset, &mut buffer)?;
+                if read == 0 {
+                    return Ok(());
+                }
+                match core::str::from_utf8(&buffer[..read]) {
+                    Ok(text) => shell_writeln!("{}", text.trim_end_matches('\n')),
+                    Err(_) => dump_bytes(offset as u64, &buffer[..read]),
+                }
+                offset += read;
+            }
+        }),
+        (Some("mkdir"), Some(path)) => root.create(path, NodeKind::Directory).map(|_| ()),
+        (Some("rm"), Some(path)) => root.remove(path),
+        (Some("write"), Some(path)) => {
+            let file = match root.lookup(path) {
+                Ok(file) => Ok(file),
+                Err(_) => root.create(path, NodeKind::File),
+            };
+            file.and_then(|file| {
+                let mut separator = "";
+                for word in arguments.by_ref() {
+                    root.write(file, root.size(file)?, separator.as_bytes())?;
+                    root.write(file, root.size(file)?, word.as_bytes())?;
+                    separator = " ";
+                }
+                root.write(file, root.size(file)?, b"\n")
+            })
+        }
+        (Some("mv"), Some(from)) => match arguments.next() {
+            Some(to) => root.rename(from, to),
+            None => {
+                shell_writeln!("usage: fs mv <from> <to>");
+                Ok(())
+            }
+        },
+        _ => {
+            shell_writeln!(
+                "usage: fs ls [path] | cat|mkdir|rm <path> | write <path> <text> | mv <from> <to>"
+            );
+            Ok(())
+        }
+    };
+    if let Err(fault) = result {
+        shell_writeln!("{}", fault);
+    }
+}
+
+/// `fs` on a path under /dev, where the devices are
+fn device(command: Option<&str>, path: &str, arguments: &mut SplitAsciiWhitespace) {
+    let devices = fs::devices_no_sync();
+    if command == Some("ls") && path.trim_end_matches('/') == devfs::MOUNT_POINT {
+        for device in devices.devices() {
+            match device.kind {
+                DeviceKind::Character => shell_writeln!("{}", device.name()),
+                DeviceKind::Block { size } => shell_writeln!("{:<24}{}", device.name(), size),
+            }
+        }
+        return;
+    }
+    let device = match devices.lookup(path) {
+        Ok(device) => device,
+        Err(fault) => {
+            shell_writeln!("{}", fault);
+            return;
+        }
+    };
+    let result = match command {
+        // Whatever character devices have for now, the start of block devices
+        Some("cat") => {
+            let mut buffer = [0u8; MAX_MEM_DUMP_SIZE];
+            device
+                .read(0, &mut buffer)
+                .map(|read| dump_bytes(0, &buffer[..read]))
+        }
+        Some("write") => {
+            let mut offset = 0;
+            let mut separator = "";
+            arguments
+                .try_for_each(|word| {
+                    for bytes in [separator.as_bytes(), word.as_bytes()] {
+                        offset += device.write(offset, bytes)? as u64;
+                    }
+                    separator = " ";
+                    Ok(())
+                })
+                .and_then(|()| device.write(offset, b"\n").map(|_| ()))
+        }
+        _ => {
+            shell_writeln!("usage: fs ls /dev | fs cat <device> | fs write <device> <text>");
+            Ok(())
+        }
+    };
+    if let Err(error) = result {
+        shell_writeln!("{}", error);
+    }
+}
+
+fn keymap(arguments: &mut SplitAsciiWhitespace) {
+    let Some(name) = arguments.next() else {
+        shell_writeln!("keyboard layout: {}", keyboard::layout());
+        return;
+    };
+    match Layout::from_name(name) {
+        Some(layout) => keyboard::set_layout(layout),
+        None => shell_writeln!("usage: keymap [us|de|it]"),
+    }
+}
+
+fn memmap() {
+    let memory_map = crate::boot_info().memory_map();
+    if memory_map.is_empty() {
+        shell_writeln!("no BIOS memory map");
+    }
+    for entry in memory_map {
+        shell_writeln!("{}", entry);
+    }
+    shell_writeln!("reserved:");
+    for region in memory::reserved_regions().regions() {
+        shell_writeln!("{}", region);
+    }
+}
+
+fn vmas() {
+    let address_space = memory::address_space_no_sync();
+    let window = address_space.window();
+    shell_writeln!("handing out {:#018x}-{:#018x}", window.start, window.end);
+    for region in address_space.regions() {
+        shell_writeln!("{}", region);
+    }
+}
+
+/// Only to COM1, as the lines are for tools to read
+fn trace() {
+    let mut serial = Com1::get();
+    if let Err(err) = common::trace::dump(&mut serial) {
+        shell_writeln!("dumping the trace events failed: {}", err);
+    }
+}
+
+#[cfg(feature = "graphics")]
+fn mouse() {
+    let mut events = 0;
+    while let Some(event) = mouse::read_event() {
+        shell_writeln!(
+            "dx={:<4} dy={:<4} wheel={:<3} buttons={:#05b}",
+            event.dx,
+            event.dy,
+            event.wheel,
+            u8::from(event.buttons)
+        );
+        events += 1;
+    }
+    if events == 0 {
+        shell_writeln!("no mouse events");
+    }
+}
+
+/// Sleeps until a key is typed or a byte comes in on COM1, or until the next timer tick, so that
+/// the NIC is still polled every now and then
+fn wait_for_input() {
+    let start = time::ticks();
+    wait_queue::wait_until(|| {
+        let keyboard_woken = keyboard::take_wakeup();
+        let serial_woken = Com1::take_wakeup();
+        (keyboard_woken || serial_woken || time::ticks() != start).then_some(())
+    });
+}
+
+fn reboot() -> ! {
+    shell_writeln!("Rebooting...");
+    Com1::get().flush();
+    let keyboard_controller = Port::new(KEYBOARD_CONTROLLER_COMMAND);
+    while keyboard_controller.readb() & KEYBOARD_CONTROLLER_INPUT_BUFFER_FULL != 0 {
+        core::hint::spin_loop();
+    }
+    keyboard_controller.writeb(PULSE_RESET_LINE);
+    loop {
+        // SAFETY: Halting until the reset kicks in has no side effects
+        unsafe {
+            asm!("hlt", options(nomem, nostack, preserves_flags));
+        }
+    }
+}
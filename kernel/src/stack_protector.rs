@@ -0,0 +1,31 @@
+// Support for `-Z stack-protector`: the compiler stores `__stack_chk_guard` below the return
+// address of protected functions, and calls `__stack_chk_fail` if it changed by the time they
+// return
+
+use common::random;
+
+// Used until `init` runs. The zero low byte stops overflows through C-style strings from
+// reproducing the canary
+const DEFAULT_GUARD: usize = 0x595e_9fbd_94fd_a700;
+
+#[allow(non_upper_case_globals)]
+#[unsafe(no_mangle)]
+static mut __stack_chk_guard: usize = DEFAULT_GUARD;
+
+/// # Panics
+/// Always panics, as returning would resume with a corrupted stack
+#[unsafe(no_mangle)]
+extern "C" fn __stack_chk_fail() -> ! {
+    panic!("stack smashing detected");
+}
+
+/// Replaces the default stack guard with a random one. Inlined because a protected function
+/// calling it would find its canary changed when returning: the caller must never return either,
+/// and must call it before enabling interrupts
+#[inline(always)]
+pub fn init() {
+    let guard = random::entropy() as usize & !0xff;
+    let guard_ptr = &raw mut __stack_chk_guard;
+    // SAFETY: no threads, and this runs before interrupts are enabled
+    unsafe { guard_ptr.write_volatile(guard) };
+}
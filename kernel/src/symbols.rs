@@ -0,0 +1,73 @@
+// Code addresses to symbol names, with the symbol table xtasks ships as the symbols payload when
+// the image is built with `--symbols`. The kernel is built with frame pointers, so a backtrace is
+// the chain of saved RBPs, each followed by the return address of its frame
+
+use core::arch::asm;
+
+use common::{
+    payload::PayloadKind,
+    serial,
+    symbols::{Location, SymbolTable},
+    vga,
+};
+
+use crate::boot_info;
+
+/// Stops at the first frame pointer out of it, which is where the stacks are
+const IDENTITY_MAPPED_END: u64 = 1 << 30;
+const MAX_FRAMES: usize = 32;
+
+/// The table the image was built with, if any
+pub fn table() -> Option<SymbolTable<'static>> {
+    let payload = boot_info().payload(PayloadKind::Symbols)?;
+    // SAFETY: The bootloader loaded the payload below 4GB, in the identity mapped first GB, and
+    // nothing writes over it
+    let bytes = unsafe {
+        core::slice::from_raw_parts(payload.address as usize as *const u8, payload.size as usize)
+    };
+    SymbolTable::try_from(bytes).ok()
+}
+
+/// The symbol the run-time `address` is in, once the KASLR slide is taken off
+pub fn lookup(address: u64) -> Option<Location<'static>> {
+    table()?.lookup(boot_info().deslide(address))
+}
+
+/// Prints the return addresses up the stack of the caller, symbolized if the image ships symbols
+pub fn print_backtrace_no_sync() {
+    let mut frame_pointer: u64;
+    // SAFETY: only reads RBP
+    unsafe { asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack)) };
+    vga::writeln_no_sync!("Backtrace:");
+    serial::writeln_no_sync!("Backtrace:");
+    for _ in 0..MAX_FRAMES {
+        if frame_pointer == 0
+            || !frame_pointer.is_multiple_of(8)
+            || frame_pointer + 16 > IDENTITY_MAPPED_END
+        {
+            break;
+        }
+        let frame = frame_pointer as usize as *const u64;
+        // SAFETY: the frame pointer is aligned and in the identity mapped first GB, and frames
+        // start with the caller's frame pointer followed by the return address
+        let (caller_frame_pointer, return_address) = unsafe { (*frame, *frame.add(1)) };
+        if return_address == 0 {
+            break;
+        }
+        match lookup(return_address) {
+            Some(location) => {
+                vga::writeln_no_sync!("  {return_address:#x} {location}");
+                serial::writeln_no_sync!("  {return_address:#x} {location}");
+            }
+            None => {
+                vga::writeln_no_sync!("  {return_address:#x}");
+                serial::writeln_no_sync!("  {return_address:#x}");
+            }
+        }
+        // Stacks grow down, so callers' frames are above, anything else is a broken chain
+        if caller_frame_pointer <= frame_pointer {
+            break;
+        }
+        frame_pointer = caller_frame_pointer;
+    }
+}
@@ -0,0 +1,145 @@
+// Runner for the kernel's `#[test_case]`s, which `cargo xtasks test` boots in QEMU. Results go
+// out on COM1 as JSON lines, one per test, and QEMU is shut down through its isa-debug-exit device
+// with the outcome once they're done or one panics:
+//   qemu-system-x86_64 ... -serial stdio -device isa-debug-exit,iobase=0xf4,iosize=0x04
+// The runner announces itself with {"event":"ready","tests":N}, then waits for a line on COM1
+// with the filter: only the tests whose name contains it run, all of them if it's empty
+
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    panic::PanicInfo,
+};
+
+use common::{ioport::Port, panicking::QEMU_EXIT_PORT, random, serial::Com1, timer};
+
+const FILTER_BUFFER_SIZE: usize = 128;
+
+static mut CURRENT_TEST: Option<(&'static str, u64)> = None;
+static mut TIMESTAMP_COUNTER_HZ: u64 = 0;
+
+/// What QEMU exits with, shifted left by one and with the low bit set, i.e. 33 and 35
+#[repr(u32)]
+enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+pub trait Testable {
+    fn run(&self);
+    fn name(&self) -> &'static str;
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        self()
+    }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+/// Writes what's written to it into the inner writer as the contents of a JSON string
+struct JsonEscaped<W: Write>(W);
+
+impl<W: Write> Write for JsonEscaped<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for character in s.chars() {
+            match character {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                character if character.is_control() => {
+                    write!(self.0, "\\u{:04x}", character as u32)?
+                }
+                character => self.0.write_char(character)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn elapsed_ns(start: u64) -> u64 {
+    let timestamp_counter_hz_ptr = &raw const TIMESTAMP_COUNTER_HZ;
+    // SAFETY: no threads, and it's only written before the first test starts
+    let timestamp_counter_hz = unsafe { *timestamp_counter_hz_ptr }.max(1);
+    let cycles = random::read_timestamp_counter() - start;
+    (u128::from(cycles) * 1_000_000_000 / u128::from(timestamp_counter_hz)) as u64
+}
+
+fn exit_qemu(exit_code: ExitCode) -> ! {
+    // What's still queued for the UART would be lost with the machine
+    Com1::get().flush();
+    Port::new(QEMU_EXIT_PORT).writed(exit_code as u32);
+    // Not running in QEMU, or without the exit device
+    loop {
+        // SAFETY: Halting has no side effects
+        unsafe {
+            asm!("hlt", options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+pub fn run(tests: &[&dyn Testable]) {
+    let mut serial = Com1::get();
+    let timestamp_counter_hz = timer::calibrate_timestamp_counter();
+    let timestamp_counter_hz_ptr = &raw mut TIMESTAMP_COUNTER_HZ;
+    // SAFETY: no threads, and no test started yet
+    unsafe { *timestamp_counter_hz_ptr = timestamp_counter_hz };
+
+    let _ = writeln!(serial, r#"{{"event":"ready","tests":{}}}"#, tests.len());
+    let mut filter_buffer = [0u8; FILTER_BUFFER_SIZE];
+    let filter = serial.read_line(&mut filter_buffer, || None);
+
+    let (mut passed, mut filtered_out) = (0, 0);
+    let current_test_ptr = &raw mut CURRENT_TEST;
+    for test in tests {
+        if !test.name().contains(filter) {
+            filtered_out += 1;
+            continue;
+        }
+        let start = random::read_timestamp_counter();
+        // SAFETY: no threads, and the panic handler only reads it
+        unsafe { *current_test_ptr = Some((test.name(), start)) };
+        test.run();
+        let duration_ns = elapsed_ns(start);
+        // SAFETY: same as above
+        unsafe { *current_test_ptr = None };
+
+        let _ = write!(serial, r#"{{"event":"test","name":""#);
+        let _ = write!(JsonEscaped(&mut serial), "{}", test.name());
+        let _ = writeln!(serial, r#"","result":"ok","duration_ns":{duration_ns}}}"#);
+        passed += 1;
+    }
+
+    let _ = writeln!(
+        serial,
+        r#"{{"event":"done","passed":{passed},"failed":0,"filtered_out":{filtered_out}}}"#
+    );
+    exit_qemu(ExitCode::Success);
+}
+
+/// Reports the test that was running as failed, with the panic message, and shuts QEMU down as
+/// the kernel can't carry on with the next one
+pub fn panicked(info: &PanicInfo) -> ! {
+    let mut serial = Com1::get();
+    let current_test_ptr = &raw const CURRENT_TEST;
+    // SAFETY: no threads, and the runner isn't going to write it anymore
+    match unsafe { *current_test_ptr } {
+        Some((name, start)) => {
+            let duration_ns = elapsed_ns(start);
+            let _ = write!(serial, r#"{{"event":"test","name":""#);
+            let _ = write!(JsonEscaped(&mut serial), "{}", name);
+            let _ = write!(
+                serial,
+                r#"","result":"failed","duration_ns":{duration_ns},"message":""#
+            );
+        }
+        None => {
+            let _ = write!(serial, r#"{{"event":"panic","message":""#);
+        }
+    }
+    let _ = write!(JsonEscaped(&mut serial), "{}", info);
+    let _ = writeln!(serial, r#""}}"#);
+    exit_qemu(ExitCode::Failure);
+}
@@ -0,0 +1,175 @@
+// The kernel's timekeeping. The timer IRQ comes every TIMER_0_PERIOD_NS, which counts the uptime
+// and drives a timer wheel, so that anything that needs to wait for a while or do something every
+// now and then asks for a software timer here instead of counting on a timer of its own. Timers
+// only have the resolution of a tick, ~55ms: finer delays still need spinning on the PIT or the
+// timestamp counter
+use common::{
+    error::Fault,
+    interrupts,
+    timer::{self, TIMER_0_PERIOD_NS},
+    timer_wheel::{TimerAction, TimerId, TimerWheel},
+    wait_queue,
+};
+
+// A revolution takes ~3.5s, longer timers go around more than once
+const WHEEL_SLOTS: usize = 64;
+const MAX_TIMERS: usize = 32;
+
+// Timer IRQs since `interrupts::init`
+static mut TICKS: u64 = 0;
+static mut TIMERS: TimerWheel<WHEEL_SLOTS, MAX_TIMERS> = TimerWheel::new();
+
+/// Counts a tick and runs the callbacks of the timers that expired with it. Only to be called by
+/// the timer IRQ handler
+pub fn tick_no_sync() {
+    let ticks_ptr = &raw mut TICKS;
+    // SAFETY: no threads, and the handler isn't reentrant, as it runs with interrupts disabled
+    let ticks = unsafe { &mut *ticks_ptr };
+    *ticks += 1;
+    let ticks = *ticks;
+    let timers_ptr = &raw mut TIMERS;
+    // SAFETY: no threads, and the timers are only accessed with interrupts disabled outside of the
+    // interrupt handler. The reference is gone by the time the callback runs
+    while let Some(callback) = unsafe { (*timers_ptr).next_expired(ticks) } {
+        common::trace_event!(Timer, "expired at tick {}", ticks);
+        callback();
+    }
+}
+
+/// How many timer IRQs came since `interrupts::init`
+pub fn ticks() -> u64 {
+    let ticks_ptr = &raw const TICKS;
+    interrupts::without_interrupts(|| {
+        // SAFETY: no threads, and interrupts are disabled so the handler can't update it halfway
+        unsafe { *ticks_ptr }
+    })
+}
+
+/// Time since `interrupts::init`, good to the PIT's resolution
+pub fn uptime_ns() -> u64 {
+    let ticks_ptr = &raw const TICKS;
+    interrupts::without_interrupts(|| {
+        // SAFETY: no threads, and interrupts are disabled so the handler can't update it halfway.
+        // A wrap around whose IRQ is still pending is only counted once it's handled, so this can
+        // be a period behind
+        let ticks = unsafe { *ticks_ptr };
+        ticks * TIMER_0_PERIOD_NS + timer::timer_0_period_elapsed_ns()
+    })
+}
+
+/// How many ticks are at least `ns` long
+fn ticks_for(ns: u64) -> u64 {
+    ns.div_ceil(TIMER_0_PERIOD_NS)
+}
+
+fn with_timers<R>(f: impl FnOnce(&mut TimerWheel<WHEEL_SLOTS, MAX_TIMERS>) -> R) -> R {
+    let timers_ptr = &raw mut TIMERS;
+    interrupts::without_interrupts(|| {
+        // SAFETY: no threads, and interrupts are disabled so the handler can't run concurrently
+        f(unsafe { &mut *timers_ptr })
+    })
+}
+
+/// A timer that expires once, at least `timeout_ns` from now. Callbacks run in the timer IRQ
+/// handler, with interrupts disabled
+pub fn one_shot(timeout_ns: u64, action: TimerAction) -> Result<TimerId, Fault> {
+    with_timers(|timers| timers.one_shot(ticks_for(timeout_ns), action))
+}
+
+/// A timer that expires every `period_ns`, rounded up to whole ticks
+pub fn periodic(period_ns: u64, action: TimerAction) -> Result<TimerId, Fault> {
+    with_timers(|timers| timers.periodic(ticks_for(period_ns), action))
+}
+
+/// Stops a timer. False if it was gone already
+pub fn cancel(timer: TimerId) -> bool {
+    with_timers(|timers| timers.cancel(timer))
+}
+
+/// Whether a `TimerAction::Wake` timer fired since this was last called
+pub fn take_fired(timer: TimerId) -> bool {
+    with_timers(|timers| timers.take_fired(timer))
+}
+
+/// Halts for at least `duration_ns`, with interrupts enabled
+pub fn sleep_ns(duration_ns: u64) -> Result<(), Fault> {
+    let timer = one_shot(duration_ns, TimerAction::Wake)?;
+    wait_queue::wait_until(|| take_fired(timer).then_some(()));
+    Ok(())
+}
+
+/// A point in time to give up waiting at, for timeouts that don't need a timer to wake anyone
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    uptime_ns: u64,
+}
+
+impl Deadline {
+    /// `timeout_ns` from now
+    pub fn after(timeout_ns: u64) -> Self {
+        Self {
+            uptime_ns: uptime_ns().saturating_add(timeout_ns),
+        }
+    }
+
+    pub fn has_passed(&self) -> bool {
+        uptime_ns() >= self.uptime_ns
+    }
+}
+
+#[cfg(test)]
+// Failing is what test cases panic for
+#[allow(clippy::missing_panics_doc)]
+mod tests {
+    use common::{ipc::Pipe, timer::TIMER_0_PERIOD_NS, timer_wheel::TimerAction};
+
+    use crate::time::{self, Deadline};
+
+    static mut CALLBACKS: u32 = 0;
+    static mut PIPE: Pipe<8> = Pipe::new();
+
+    fn count_callback() {
+        let callbacks_ptr = &raw mut CALLBACKS;
+        // SAFETY: no threads, and only the timer IRQ handler runs this
+        unsafe { *callbacks_ptr += 1 };
+    }
+
+    fn write_to_pipe() {
+        let pipe_ptr = &raw mut PIPE;
+        // SAFETY: no threads, only the timer IRQ handler runs this, and the reader only uses the
+        // pipe with interrupts disabled
+        let _ = unsafe { (*pipe_ptr).write(b"tick") };
+    }
+
+    #[test_case]
+    fn sleeps_and_deadlines() {
+        let deadline = Deadline::after(1_000_000);
+        let start = time::uptime_ns();
+        assert!(time::sleep_ns(1_000_000).is_ok());
+        assert!(time::uptime_ns() > start);
+        assert!(deadline.has_passed());
+        assert!(!Deadline::after(1_000_000_000).has_passed());
+    }
+
+    #[test_case]
+    fn periodic_callbacks() {
+        let Ok(timer) = time::periodic(1, TimerAction::Callback(count_callback)) else {
+            panic!("no timer");
+        };
+        assert!(time::sleep_ns(3 * TIMER_0_PERIOD_NS).is_ok());
+        assert!(time::cancel(timer));
+        let callbacks_ptr = &raw const CALLBACKS;
+        // SAFETY: no threads, and the timer that updates it is gone
+        assert!(unsafe { *callbacks_ptr } >= 2);
+    }
+
+    #[test_case]
+    fn callbacks_wake_blocking_readers() {
+        assert!(time::one_shot(TIMER_0_PERIOD_NS, TimerAction::Callback(write_to_pipe)).is_ok());
+        let mut buffer = [0u8; 8];
+        // SAFETY: the pipe is a static, and the callback writing it runs in the timer IRQ handler,
+        // with interrupts disabled
+        let read = unsafe { Pipe::read_blocking(&raw mut PIPE, &mut buffer) };
+        assert_eq!(b"tick", &buffer[..read]);
+    }
+}
@@ -0,0 +1,205 @@
+// Runs code in ring 3 until it raises an exception, the only way back to the kernel until there
+// are system calls. Entering saves the kernel's callee-saved registers and the stack pointer they
+// leave, then returns to the code with IRETQ and the user selectors of the kernel's GDT. The #GP
+// and #PF handlers hand the faults coming from ring 3 to `handle_fault_no_sync`, which records
+// them and rewrites the exception stack frame to return to the saved stack, in ring 0, instead of
+// to the faulting instruction
+use core::arch::naked_asm;
+
+use crate::{gdt, interrupts::ExceptionStackFrame};
+
+// The interrupt flag, plus the bit that's always set
+const USER_CPU_FLAGS: u64 = 0x202;
+// No interrupts until the kernel's own flags are back
+const RESUME_CPU_FLAGS: u64 = 0x2;
+
+// Where the kernel's stack was when it entered ring 3, 0 unless code is running there
+static mut RESUME_STACK_POINTER: u64 = 0;
+static mut LAST_FAULT: Option<UserFault> = None;
+
+/// An exception raised in ring 3, as its handler saw it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserFault {
+    pub vector: u8,
+    pub error_code: u64,
+    pub instruction_pointer: u64,
+    pub code_segment: u16,
+    pub stack_pointer: u64,
+    pub stack_segment: u16,
+    /// Where the CPU pushed the exception stack frame, on the stack the TSS has for ring 0
+    pub handler_stack_pointer: u64,
+}
+
+/// Saves the callee-saved registers and the flags on the kernel's stack, leaving the stack pointer
+/// in `RESUME_STACK_POINTER`, then returns to `entrypoint` in ring 3 with `stack_pointer`,
+/// interrupts enabled and the other registers cleared. Returns through `resume_from_user_mode`
+#[unsafe(naked)]
+extern "C" fn enter_user_mode(entrypoint: u64, stack_pointer: u64) {
+    naked_asm!(
+        "push rbx", "push rbp", "push r12", "push r13", "push r14", "push r15",
+        "pushfq",
+        "mov [rip + {resume_stack_pointer}], rsp",
+        // What IRETQ pops: RIP, CS, RFLAGS, RSP and SS
+        "push {user_data_selector}",
+        "push rsi",
+        "push {user_cpu_flags}",
+        "push {user_code_selector}",
+        "push rdi",
+        // Nothing of the kernel's is left for the code to see
+        "xor eax, eax", "xor ebx, ebx", "xor ecx, ecx", "xor edx, edx",
+        "xor esi, esi", "xor edi, edi", "xor ebp, ebp",
+        "xor r8d, r8d", "xor r9d, r9d", "xor r10d, r10d", "xor r11d, r11d",
+        "xor r12d, r12d", "xor r13d, r13d", "xor r14d, r14d", "xor r15d, r15d",
+        "iretq",
+        resume_stack_pointer = sym RESUME_STACK_POINTER,
+        user_data_selector = const gdt::USER_DATA_SELECTOR,
+        user_code_selector = const gdt::USER_CODE_SELECTOR,
+        user_cpu_flags = const USER_CPU_FLAGS,
+    );
+}
+
+/// Where faults in ring 3 return to, on the stack `enter_user_mode` left: restores what it saved
+/// and returns to its caller
+#[unsafe(naked)]
+extern "C" fn resume_from_user_mode() {
+    naked_asm!(
+        "popfq", "pop r15", "pop r14", "pop r13", "pop r12", "pop rbp", "pop rbx", "ret",
+    );
+}
+
+/// Records the exception `vector` raised in ring 3, if code entered there by `run_until_fault`
+/// is running, and has the handler return to the kernel instead of to the code. False, and the
+/// frame left alone, for exceptions raised anywhere else
+pub fn handle_fault_no_sync(vector: u8, stack_frame: &mut ExceptionStackFrame) -> bool {
+    let resume_stack_pointer_ptr = &raw mut RESUME_STACK_POINTER;
+    // SAFETY: no threads, and the exception handlers run with interrupts disabled
+    let resume_stack_pointer = unsafe { *resume_stack_pointer_ptr };
+    if resume_stack_pointer == 0 || stack_frame.code_segment != gdt::USER_CODE_SELECTOR as u64 {
+        return false;
+    }
+    let fault = UserFault {
+        vector,
+        error_code: stack_frame.error_code,
+        instruction_pointer: stack_frame.instruction_pointer,
+        code_segment: stack_frame.code_segment as u16,
+        stack_pointer: stack_frame.stack_pointer,
+        stack_segment: stack_frame.stack_segment as u16,
+        handler_stack_pointer: stack_frame as *const _ as u64,
+    };
+    let last_fault_ptr = &raw mut LAST_FAULT;
+    // SAFETY: see above
+    unsafe {
+        *last_fault_ptr = Some(fault);
+        *resume_stack_pointer_ptr = 0;
+    }
+    stack_frame.instruction_pointer = resume_from_user_mode as usize as u64;
+    stack_frame.code_segment = gdt::KERNEL_CODE_SELECTOR as u64;
+    stack_frame.cpu_flags = RESUME_CPU_FLAGS;
+    stack_frame.stack_pointer = resume_stack_pointer;
+    stack_frame.stack_segment = gdt::KERNEL_DATA_SELECTOR as u64;
+    true
+}
+
+/// Runs the code at `entrypoint` in ring 3 with `stack_pointer`, until it raises a #GP or a page
+/// fault the kernel doesn't resolve, which it returns. None if it came back some other way
+///
+/// # Safety
+/// The code and its stack have to be mapped for ring 3, and nothing of the kernel's may be
+/// writable from there
+pub unsafe fn run_until_fault(entrypoint: u64, stack_pointer: u64) -> Option<UserFault> {
+    let last_fault_ptr = &raw mut LAST_FAULT;
+    // SAFETY: no threads, and nothing runs in ring 3 yet to fault
+    unsafe { *last_fault_ptr = None };
+    enter_user_mode(entrypoint, stack_pointer);
+    // SAFETY: the handler that wrote it returned already
+    unsafe { (*last_fault_ptr).take() }
+}
+
+#[cfg(test)]
+// Failing is what test cases panic for
+#[allow(clippy::missing_panics_doc)]
+mod tests {
+    use common::{
+        anonymous_memory::AnonymousMemory,
+        frame::{self, FRAME_SIZE},
+        idt::Interrupt,
+        layout,
+        paging::{Mapper, PageFaultErrorCode},
+        vma::RegionFlag::*,
+    };
+
+    use crate::{
+        gdt,
+        user_mode::{UserFault, run_until_fault},
+    };
+
+    // cli, which takes IOPL 3
+    const PRIVILEGED_INSTRUCTION: [u8; 1] = [0xfa];
+
+    /// Maps `code` and a page of stack for ring 3, runs it until it faults, and unmaps both again
+    fn run_in_ring_3(code: &[u8]) -> UserFault {
+        let mapper = Mapper::active();
+        let mut memory = AnonymousMemory::<2>::new(layout::USER_REGIONS);
+        let (code_page, stack) = frame::with_frames(|frames| {
+            let code_page = memory.mmap(FRAME_SIZE, User | Execute)?;
+            let frame = memory.map_zeroed_page(code_page.start, &mapper, frames)?;
+            let stack = memory.mmap(FRAME_SIZE, User | Write)?;
+            memory.map_zeroed_page(stack.start, &mapper, frames)?;
+            let frame_ptr = (mapper.physical_memory_offset() + frame) as usize as *mut u8;
+            // SAFETY: the frame was just allocated, and the pool is mapped at the physical memory
+            // offset
+            unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), frame_ptr, code.len()) };
+            Ok::<_, common::error::Fault>((code_page, stack))
+        })
+        .unwrap_or_else(|fault| panic!("mapping the code failed: {}", fault));
+
+        // SAFETY: both are mapped for ring 3 above, and the code only faults
+        let fault = unsafe { run_until_fault(code_page.start, stack.end) };
+
+        frame::with_frames(|frames| {
+            memory.munmap(code_page.start, &mapper, frames)?;
+            memory.munmap(stack.start, &mapper, frames)
+        })
+        .unwrap_or_else(|fault| panic!("unmapping the code failed: {}", fault));
+        let Some(fault) = fault else {
+            panic!("ring 3 returned without a fault");
+        };
+        assert_eq!(code_page.start, fault.instruction_pointer);
+        assert_eq!(stack.end, fault.stack_pointer);
+        fault
+    }
+
+    /// Whether the handler ran on the TSS' stack for ring 0, with the user selectors in the frame
+    fn assert_handled_from_ring_3(fault: &UserFault) {
+        assert_eq!(gdt::USER_CODE_SELECTOR, fault.code_segment);
+        assert_eq!(gdt::USER_DATA_SELECTOR, fault.stack_segment);
+        assert!(
+            gdt::privilege_stack().contains(&(fault.handler_stack_pointer as usize)),
+            "handler stack {:#x} isn't RSP0's {:#x?}",
+            fault.handler_stack_pointer,
+            gdt::privilege_stack()
+        );
+    }
+
+    #[test_case]
+    fn general_protection_fault_in_ring_3() {
+        let fault = run_in_ring_3(&PRIVILEGED_INSTRUCTION);
+        assert_eq!(Interrupt::GeneralProtectionFault as u8, fault.vector);
+        assert_eq!(0, fault.error_code);
+        assert_handled_from_ring_3(&fault);
+    }
+
+    #[test_case]
+    fn page_fault_in_ring_3() {
+        // mov rax, [layout::KERNEL_BASE], mapped for ring 0 only
+        let mut code = [0x48, 0xa1, 0, 0, 0, 0, 0, 0, 0, 0];
+        code[2..].copy_from_slice(&layout::KERNEL_BASE.to_le_bytes());
+        let fault = run_in_ring_3(&code);
+        assert_eq!(Interrupt::PageFault as u8, fault.vector);
+        let error_code = PageFaultErrorCode::from(fault.error_code as u32);
+        assert!(error_code.is_user_mode());
+        assert!(error_code.is_protection_violation());
+        assert!(!error_code.is_write());
+        assert_handled_from_ring_3(&fault);
+    }
+}
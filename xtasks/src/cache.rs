@@ -0,0 +1,129 @@
+//! Remembering what the build steps that cargo doesn't track were last run on, so that they can be
+//! skipped when their inputs didn't change. Inputs are fingerprinted by content, not by
+//! modification time, as cargo touches the ELF files it links even when they come out the same
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+/// Where the fingerprints are kept, relative to the root directory
+const CACHE_PATH: &str = "target/xtasks-cache";
+
+/// A hash of everything a build step's output depends on. DefaultHasher's output may change
+/// between Rust releases, which only means rebuilding once after an upgrade
+pub(crate) struct Fingerprint(DefaultHasher);
+
+impl Fingerprint {
+    pub(crate) fn new() -> Self {
+        Self(DefaultHasher::new())
+    }
+
+    pub(crate) fn file(mut self, path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read(path)
+            .with_context(|| format!("reading {} to fingerprint it", path.to_string_lossy()))?;
+        contents.hash(&mut self.0);
+        Ok(self)
+    }
+
+    pub(crate) fn value(mut self, value: impl Hash) -> Self {
+        value.hash(&mut self.0);
+        self
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+pub(crate) struct Cache {
+    path: PathBuf,
+    fingerprints: HashMap<String, u64>,
+    force: bool,
+}
+
+impl Cache {
+    /// Reads the fingerprints of the last build. With `force`, every step is considered stale
+    pub(crate) fn load(root_dir: &Path, force: bool) -> Self {
+        let path = root_dir.join(CACHE_PATH);
+        // A missing or mangled cache only costs a rebuild
+        let fingerprints = std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let (step, fingerprint) = line.rsplit_once(' ')?;
+                Some((step.to_string(), u64::from_str_radix(fingerprint, 16).ok()?))
+            })
+            .collect();
+        Self {
+            path,
+            fingerprints,
+            force,
+        }
+    }
+
+    /// Whether `step` last ran on the same inputs and its outputs are still around
+    pub(crate) fn is_fresh(
+        &self,
+        step: &str,
+        fingerprint: &Fingerprint,
+        outputs: &[&Path],
+    ) -> bool {
+        !self.force
+            && self.fingerprints.get(step) == Some(&fingerprint.finish())
+            && outputs.iter().all(|output| output.exists())
+    }
+
+    /// Remembers that `step` ran on the inputs in `fingerprint`
+    pub(crate) fn record(&mut self, step: &str, fingerprint: &Fingerprint) -> anyhow::Result<()> {
+        self.fingerprints
+            .insert(step.to_string(), fingerprint.finish());
+        let mut steps: Vec<_> = self.fingerprints.iter().collect();
+        steps.sort();
+        let contents: String = steps
+            .into_iter()
+            .map(|(step, fingerprint)| format!("{step} {fingerprint:016x}\n"))
+            .collect();
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).context("creating the cache directory")?;
+        }
+        std::fs::write(&self.path, contents).context("writing the build cache")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{Cache, Fingerprint};
+
+    #[test]
+    fn steps_are_skipped_until_inputs_change() {
+        let root_dir =
+            std::env::temp_dir().join(format!("xtasks-cache-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root_dir).unwrap();
+        let input = root_dir.join("boot.asm");
+        let output = root_dir.join("stage1.bin");
+        std::fs::write(&input, "org 0x7C00").unwrap();
+        std::fs::write(&output, [0x55, 0xAA]).unwrap();
+        let fingerprint = || Fingerprint::new().file(&input).unwrap().value(3u64);
+
+        let mut cache = Cache::load(&root_dir, false);
+        assert!(!cache.is_fresh("stage1", &fingerprint(), &[&output]));
+        cache.record("stage1", &fingerprint()).unwrap();
+
+        let cache = Cache::load(&root_dir, false);
+        assert!(cache.is_fresh("stage1", &fingerprint(), &[&output]));
+        assert!(!cache.is_fresh("stage1", &fingerprint().value(4u64), &[&output]));
+        assert!(!Cache::load(&root_dir, true).is_fresh("stage1", &fingerprint(), &[&output]));
+
+        std::fs::write(&input, "org 0x7E00").unwrap();
+        assert!(!cache.is_fresh("stage1", &fingerprint(), &[&output]));
+        std::fs::write(&input, "org 0x7C00").unwrap();
+        std::fs::remove_file(&output).unwrap();
+        assert!(!cache.is_fresh("stage1", &fingerprint(), &[&output]));
+
+        std::fs::remove_dir_all(&root_dir).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+//! Packing a directory into a "new" ASCII cpio archive, the format Linux takes its initramfs in,
+//! for the kernel to unpack its initrd from (see common/src/archive.rs). Only directories and
+//! regular files go in, with their permissions but no owners or times, so that the same directory
+//! always makes the same archive
+
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+const MAGIC: &str = "070701";
+const TRAILER: &str = "TRAILER!!!";
+const ALIGNMENT: usize = 4;
+const REGULAR_FILE: u32 = 0o100000;
+const DIRECTORY: u32 = 0o040000;
+const PERMISSIONS_MASK: u32 = 0o7777;
+
+/// Appends an entry, whose header fields are all 8 hex digits, with the name and the data each
+/// padded to 4 bytes
+fn push_entry(archive: &mut Vec<u8>, inode: u32, name: &str, mode: u32, data: &[u8]) {
+    let links = if mode & DIRECTORY != 0 { 2 } else { 1 };
+    let fields = [
+        inode,
+        mode,
+        0, // uid
+        0, // gid
+        links,
+        0, // mtime
+        data.len() as u32,
+        0, // device major
+        0, // device minor
+        0, // rdevice major
+        0, // rdevice minor
+        name.len() as u32 + 1,
+        0, // check
+    ];
+    archive.extend_from_slice(MAGIC.as_bytes());
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08X}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(ALIGNMENT), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(ALIGNMENT), 0);
+}
+
+/// Everything under `directory`, parents before their children and in name order
+fn walk(directory: &Path, paths: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(directory)
+        .with_context(|| format!("reading {}", directory.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("reading {}", directory.display()))?;
+    entries.sort();
+    for path in entries {
+        let is_directory = path.is_dir();
+        paths.push(path.clone());
+        if is_directory {
+            walk(&path, paths)?;
+        }
+    }
+    Ok(())
+}
+
+/// The archive of everything under `directory`, with paths relative to it
+pub fn archive(directory: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut paths = Vec::new();
+    walk(directory, &mut paths)?;
+    let mut archive = Vec::new();
+    let mut inode = 1;
+    for path in paths {
+        let metadata = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("reading the metadata of {}", path.display()))?;
+        let name = path
+            .strip_prefix(directory)
+            .context("walking the initrd directory")?
+            .to_str()
+            .with_context(|| format!("{} isn't valid UTF-8", path.display()))?;
+        let permissions = metadata.permissions().mode() & PERMISSIONS_MASK;
+        if metadata.is_dir() {
+            push_entry(&mut archive, inode, name, DIRECTORY | permissions, &[]);
+        } else if metadata.is_file() {
+            let data =
+                std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+            push_entry(&mut archive, inode, name, REGULAR_FILE | permissions, &data);
+        } else {
+            println!(
+                "Skipping {}, which isn't a file nor a directory",
+                path.display()
+            );
+            continue;
+        }
+        inode += 1;
+    }
+    push_entry(&mut archive, 0, TRAILER, 0, &[]);
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpio::push_entry;
+
+    #[test]
+    fn entry_layout() {
+        let mut archive = Vec::new();
+        push_entry(&mut archive, 1, "init", 0o100755, b"\x7fELF!");
+        let header = std::str::from_utf8(&archive[..110]).unwrap();
+        let fields = [
+            "00000001", "000081ED", "00000000", "00000000", "00000001", "00000000", "00000005",
+            "00000000", "00000000", "00000000", "00000000", "00000005", "00000000",
+        ];
+        assert_eq!(format!("070701{}", fields.concat()), header);
+        // The name and its NUL end at 115, padded to 116
+        assert_eq!(b"init\0\0", &archive[110..116]);
+        assert_eq!(b"\x7fELF!\0\0\0", &archive[116..]);
+    }
+}
@@ -0,0 +1,404 @@
+//! What `common::elf` makes of an ELF file, like a small readelf: the header, then the sections,
+//! the segments, the symbols and the notes as tables. It's the parser the bootloader and the kernel
+//! load with, so a file they reject fails here the same way
+
+use std::fmt::{Display, Write as _};
+
+use anyhow::Context as _;
+use common::{
+    elf::{
+        self,
+        header::Class,
+        program_header::{PermissionFlag, Permissions, ProgramHeaderEntryType},
+        section::{SectionEntryType, StringTable},
+        symbol::{ABSOLUTE_SECTION_INDEX, Binding, SymbolTable, UNDEFINED_SECTION_INDEX},
+    },
+    error::Error,
+};
+
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+// The name and the description of a note are each padded to this
+const NOTE_ALIGNMENT: usize = 4;
+const NOTE_HEADER_SIZE: usize = 12;
+const GNU_NOTE_TYPES: [(u32, &str); 4] = [
+    (1, "NT_GNU_ABI_TAG"),
+    (3, "NT_GNU_BUILD_ID"),
+    (4, "NT_GNU_GOLD_VERSION"),
+    (5, "NT_GNU_PROPERTY_TYPE_0"),
+];
+
+/// Which tables to print after the header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Tables {
+    pub(crate) sections: bool,
+    pub(crate) segments: bool,
+    pub(crate) symbols: bool,
+    pub(crate) notes: bool,
+}
+
+impl Tables {
+    pub(crate) const ALL: Self = Self {
+        sections: true,
+        segments: true,
+        symbols: true,
+        notes: true,
+    };
+}
+
+struct Output {
+    text: String,
+    colors: bool,
+}
+
+impl Output {
+    fn paint(&self, color: &str, text: impl Display) -> String {
+        if self.colors {
+            format!("{color}{text}{RESET}")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn heading(&mut self, title: &str) {
+        let title = self.paint(BOLD, title);
+        let _ = writeln!(self.text, "\n{title}");
+    }
+
+    fn line(&mut self, line: impl Display) {
+        let _ = writeln!(self.text, "  {line}");
+    }
+}
+
+/// common's errors may hold a raw pointer, so they aren't Send and don't go through `?` into anyhow
+fn elf_error(err: Error) -> anyhow::Error {
+    anyhow::anyhow!("{err}")
+}
+
+/// A note of a NOTE section: who defines its type, the type and what it says
+#[derive(Debug, PartialEq, Eq)]
+struct Note<'a> {
+    owner: &'a str,
+    r#type: u32,
+    description: &'a [u8],
+}
+
+fn read_word(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// The notes of a NOTE section, one after the other: the sizes of the name and of the description,
+/// the type, then the NUL terminated name and the description, each padded to 4 bytes
+fn parse_notes(mut bytes: &[u8]) -> anyhow::Result<Vec<Note<'_>>> {
+    let mut notes = Vec::new();
+    while !bytes.is_empty() {
+        let (Some(name_size), Some(description_size), Some(r#type)) = (
+            read_word(bytes, 0),
+            read_word(bytes, 4),
+            read_word(bytes, 8),
+        ) else {
+            anyhow::bail!("truncated note header");
+        };
+        let name_end = NOTE_HEADER_SIZE + name_size as usize;
+        let description_start = name_end.next_multiple_of(NOTE_ALIGNMENT);
+        let description_end = description_start + description_size as usize;
+        let (Some(name), Some(description)) = (
+            bytes.get(NOTE_HEADER_SIZE..name_end),
+            bytes.get(description_start..description_end),
+        ) else {
+            anyhow::bail!("note of type {type:#x} runs past the end of its section");
+        };
+        let owner = std::str::from_utf8(name.strip_suffix(b"\0").unwrap_or(name))
+            .context("note owner isn't UTF-8")?;
+        notes.push(Note {
+            owner,
+            r#type,
+            description,
+        });
+        bytes = bytes
+            .get(description_end.next_multiple_of(NOTE_ALIGNMENT)..)
+            .unwrap_or_default();
+    }
+    Ok(notes)
+}
+
+/// "RWE" for what a segment allows, with dashes for what it doesn't
+fn permission_letters(permissions: Permissions) -> String {
+    [
+        (PermissionFlag::Readable, 'R'),
+        (PermissionFlag::Writable, 'W'),
+        (PermissionFlag::Executable, 'E'),
+    ]
+    .into_iter()
+    .map(|(flag, letter)| {
+        if permissions.is_set(flag) {
+            letter
+        } else {
+            '-'
+        }
+    })
+    .collect()
+}
+
+fn write_sections(output: &mut Output, file: &elf::File) -> anyhow::Result<()> {
+    output.heading("Sections");
+    output.line(format_args!(
+        "[Nr] {:<24} {:<16} {:<18} {:<10} {:<10} Flags",
+        "Name", "Type", "Address", "Offset", "Size"
+    ));
+    for (section, name) in file.sections().zip(file.section_names()) {
+        let section = section.map_err(elf_error)?;
+        let (index, name) = name.map_err(elf_error)?;
+        let name = output.paint(GREEN, format_args!("{name:<24}"));
+        let r#type = output.paint(CYAN, format_args!("{:<16}", section.r#type().to_string()));
+        output.line(format_args!(
+            "[{index:>2}] {name} {type} {:#018x} {:#010x} {:#010x} {}",
+            section.address(),
+            section.offset(),
+            section.size(),
+            section.flags()
+        ));
+    }
+    Ok(())
+}
+
+fn write_segments(output: &mut Output, file: &elf::File) -> anyhow::Result<()> {
+    output.heading("Segments");
+    output.line(format_args!(
+        "{:<14} {:<10} {:<18} {:<18} {:<10} {:<10} Flg Align",
+        "Type", "Offset", "VirtAddr", "PhysAddr", "FileSiz", "MemSiz"
+    ));
+    for program_header in file.program_headers() {
+        let program_header = program_header.map_err(elf_error)?;
+        let r#type = output.paint(
+            CYAN,
+            format_args!("{:<14}", program_header.r#type().to_string()),
+        );
+        output.line(format_args!(
+            "{type} {:#010x} {:#018x} {:#018x} {:#010x} {:#010x} {} {:#x}",
+            program_header.offset(),
+            program_header.virtual_address(),
+            program_header.physical_address(),
+            program_header.segment_size_on_file(),
+            program_header.segment_size_in_memory(),
+            permission_letters(program_header.permissions()),
+            program_header.address_alignment()
+        ));
+        if matches!(program_header.r#type(), ProgramHeaderEntryType::Interpreter)
+            && let Some(interpreter) = file.get_segment(&program_header)
+        {
+            let interpreter = String::from_utf8_lossy(interpreter);
+            output.line(format_args!(
+                "    [Requesting program interpreter: {}]",
+                interpreter.trim_end_matches('\0')
+            ));
+        }
+    }
+
+    if let Some(dynamic_entries) = file.dynamic_entries() {
+        output.heading("Dynamic");
+        for entry in dynamic_entries.map_err(elf_error)? {
+            output.line(format_args!("{:x?}", entry.map_err(elf_error)?));
+        }
+    }
+    Ok(())
+}
+
+fn write_symbols(output: &mut Output, file: &elf::File) -> anyhow::Result<()> {
+    if !matches!(file.header().class(), Class::Elf64) {
+        output.heading("Symbols");
+        output.line("Only the symbol tables of 64-bit files are read");
+        return Ok(());
+    }
+    for (section, name) in file.sections().zip(file.section_names()) {
+        let section = section.map_err(elf_error)?;
+        if !matches!(
+            section.r#type(),
+            SectionEntryType::Symtab | SectionEntryType::DynSym
+        ) {
+            continue;
+        }
+        let (_, table_name) = name.map_err(elf_error)?;
+        let symbols = SymbolTable::new(
+            file.section_bytes(&section)
+                .with_context(|| format!("{table_name} runs past the end of the file"))?,
+        )
+        .map_err(elf_error)?;
+        let names = file
+            .sections()
+            .nth(section.link() as usize)
+            .with_context(|| format!("{table_name} links to no string table"))?
+            .map_err(elf_error)?;
+        let names =
+            StringTable::new(file.section_bytes(&names).with_context(|| {
+                format!("the names of {table_name} run past the end of the file")
+            })?);
+
+        output.heading(&format!("Symbols of {table_name}"));
+        output.line(format_args!(
+            "{:>5} {:<18} {:>8} {:<6} {:>5} Name",
+            "Num", "Value", "Size", "Bind", "Ndx"
+        ));
+        for index in 0..symbols.len() {
+            let Some(symbol) = symbols.get(index) else {
+                break;
+            };
+            let symbol = symbol.map_err(elf_error)?;
+            let binding = match symbol.binding() {
+                Some(Binding::Local) => "LOCAL",
+                Some(Binding::Global) => "GLOBAL",
+                Some(Binding::Weak) => "WEAK",
+                None => "OTHER",
+            };
+            let section_index = match symbol.section_index() {
+                UNDEFINED_SECTION_INDEX => "UND".to_string(),
+                ABSOLUTE_SECTION_INDEX => "ABS".to_string(),
+                section_index => section_index.to_string(),
+            };
+            let name = output.paint(
+                YELLOW,
+                names
+                    .get_string(symbol.name_index() as usize)
+                    .map_err(elf_error)?,
+            );
+            output.line(format_args!(
+                "{index:>5} {:#018x} {:>8} {binding:<6} {section_index:>5} {name}",
+                symbol.value(),
+                symbol.size()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn write_notes(output: &mut Output, file: &elf::File) -> anyhow::Result<()> {
+    for (section, name) in file.sections().zip(file.section_names()) {
+        let section = section.map_err(elf_error)?;
+        if !matches!(section.r#type(), SectionEntryType::Note) {
+            continue;
+        }
+        let (_, section_name) = name.map_err(elf_error)?;
+        let notes = file
+            .section_bytes(&section)
+            .with_context(|| format!("{section_name} runs past the end of the file"))
+            .and_then(parse_notes)
+            .with_context(|| format!("reading the notes of {section_name}"))?;
+
+        output.heading(&format!("Notes of {section_name}"));
+        output.line(format_args!("{:<8} {:<24} Description", "Owner", "Type"));
+        for note in notes {
+            let r#type = GNU_NOTE_TYPES
+                .iter()
+                .find(|(r#type, _)| note.owner == "GNU" && *r#type == note.r#type)
+                .map_or_else(
+                    || format!("{:#x}", note.r#type),
+                    |(_, name)| name.to_string(),
+                );
+            let owner = output.paint(GREEN, format_args!("{:<8}", note.owner));
+            let r#type = output.paint(CYAN, format_args!("{type:<24}"));
+            let description: String = note
+                .description
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            output.line(format_args!("{owner} {type} {description}"));
+        }
+    }
+    Ok(())
+}
+
+/// The header of the ELF file in `bytes`, then the `tables` asked for. With `colors`, names and
+/// types stand out through ANSI escape codes
+pub(crate) fn describe(bytes: &[u8], tables: Tables, colors: bool) -> anyhow::Result<String> {
+    let file = elf::File::try_from(bytes).map_err(elf_error)?;
+    let mut output = Output {
+        text: String::new(),
+        colors,
+    };
+    output.heading("ELF header");
+    for line in file.header().to_string().lines() {
+        output.line(line);
+    }
+    if tables.sections {
+        write_sections(&mut output, &file)?;
+    }
+    if tables.segments {
+        write_segments(&mut output, &file)?;
+    }
+    if tables.symbols {
+        write_symbols(&mut output, &file)?;
+    }
+    if tables.notes {
+        write_notes(&mut output, &file)?;
+    }
+    Ok(output.text)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elf_info::{Note, Tables, describe, parse_notes};
+
+    #[test]
+    fn notes() {
+        // A GNU build ID, then a note without a name whose description needs no padding
+        let mut bytes = Vec::new();
+        for word in [4u32, 5, 3] {
+            bytes.extend(word.to_le_bytes());
+        }
+        bytes.extend(b"GNU\0\x01\x02\x03\x04\x05\0\0\0");
+        for word in [0u32, 4, 0x2a] {
+            bytes.extend(word.to_le_bytes());
+        }
+        bytes.extend(b"abcd");
+        assert_eq!(
+            vec![
+                Note {
+                    owner: "GNU",
+                    r#type: 3,
+                    description: &[1, 2, 3, 4, 5],
+                },
+                Note {
+                    owner: "",
+                    r#type: 0x2a,
+                    description: b"abcd",
+                },
+            ],
+            parse_notes(&bytes).unwrap()
+        );
+
+        assert!(parse_notes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(parse_notes(&bytes[..8]).is_err());
+    }
+
+    #[test]
+    fn describes_its_own_executable() {
+        let bytes = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let text = describe(&bytes, Tables::ALL, false).unwrap();
+        for expected in [
+            "ELF header",
+            ".text",
+            "PROGBITS",
+            "LOAD",
+            "R-E",
+            "Symbols of .symtab",
+        ] {
+            assert!(text.contains(expected), "no {expected:?} in\n{text}");
+        }
+        assert!(!text.contains('\x1b'));
+
+        let tables = Tables {
+            sections: false,
+            segments: true,
+            symbols: false,
+            notes: false,
+        };
+        let text = describe(&bytes, tables, true).unwrap();
+        assert!(text.contains("\x1b[36mLOAD"));
+        assert!(!text.contains(".text"));
+    }
+}
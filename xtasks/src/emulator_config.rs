@@ -0,0 +1,119 @@
+//! Configuration for emulators other than QEMU, to check the bootloader against other BIOSes: a
+//! bochsrc booting an image, and a VMDK descriptor wrapping one so that VirtualBox can attach it
+
+use std::path::Path;
+
+use crate::SECTOR_SIZE;
+
+const HEADS: u64 = 16;
+const SECTORS_PER_TRACK: u64 = 63;
+
+/// The CHS geometry both emulators want for a flat disk, the one BIOSes translate LBA to for
+/// disks this small
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Geometry {
+    pub(crate) cylinders: u64,
+    pub(crate) heads: u64,
+    pub(crate) sectors_per_track: u64,
+}
+
+impl Geometry {
+    /// The smallest geometry holding `sectors`
+    pub(crate) fn for_sectors(sectors: u64) -> Self {
+        Self {
+            cylinders: sectors.div_ceil(HEADS * SECTORS_PER_TRACK).max(1),
+            heads: HEADS,
+            sectors_per_track: SECTORS_PER_TRACK,
+        }
+    }
+
+    pub(crate) fn sectors(&self) -> u64 {
+        self.cylinders * self.heads * self.sectors_per_track
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.sectors() * SECTOR_SIZE
+    }
+}
+
+/// A bochsrc booting the image at `image_path`, which Bochs wants exactly `geometry` big, with
+/// COM1 going to `serial_path`
+pub(crate) fn bochsrc(image_path: &Path, geometry: Geometry, serial_path: &Path) -> String {
+    format!(
+        r#"megs: 128
+cpu: count=1, reset_on_triple_fault=0
+romimage: file=$BXSHARE/BIOS-bochs-latest
+vgaromimage: file=$BXSHARE/VGABIOS-lgpl-latest
+ata0: enabled=1, ioaddr1=0x1f0, ioaddr2=0x3f0, irq=14
+ata0-master: type=disk, path="{}", mode=flat, cylinders={}, heads={}, spt={}
+boot: disk
+com1: enabled=1, mode=file, dev="{}"
+log: -
+"#,
+        image_path.to_string_lossy(),
+        geometry.cylinders,
+        geometry.heads,
+        geometry.sectors_per_track,
+        serial_path.to_string_lossy()
+    )
+}
+
+/// A monolithic flat VMDK descriptor for the image at `image_path`, `sectors` long. The image
+/// itself stays as it is, so a rebuilt one is picked up without regenerating the descriptor as
+/// long as its size doesn't change
+pub(crate) fn vmdk_descriptor(image_path: &Path, sectors: u64) -> String {
+    let geometry = Geometry::for_sectors(sectors);
+    format!(
+        r#"# Disk DescriptorFile
+version=1
+CID=fffffffe
+parentCID=ffffffff
+createType="monolithicFlat"
+
+# Extent description
+RW {sectors} FLAT "{}" 0
+
+# The Disk Data Base
+#DDB
+ddb.virtualHWVersion = "4"
+ddb.adapterType = "ide"
+ddb.geometry.cylinders = "{}"
+ddb.geometry.heads = "{}"
+ddb.geometry.sectors = "{}"
+"#,
+        image_path.to_string_lossy(),
+        // The extent may end in a partial cylinder, which the geometry leaves out unless it's the
+        // only one
+        (sectors / (geometry.heads * geometry.sectors_per_track)).max(1),
+        geometry.heads,
+        geometry.sectors_per_track
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::emulator_config::{Geometry, bochsrc, vmdk_descriptor};
+
+    #[test]
+    fn geometry_and_configs() {
+        let geometry = Geometry::for_sectors(300);
+        assert_eq!(1, geometry.cylinders);
+        assert_eq!(1008, geometry.sectors());
+        assert_eq!(2, Geometry::for_sectors(1009).cylinders);
+
+        let config = bochsrc(
+            Path::new("/blog_os/disk.img"),
+            geometry,
+            Path::new("com1.txt"),
+        );
+        assert!(config.contains(
+            r#"ata0-master: type=disk, path="/blog_os/disk.img", mode=flat, cylinders=1, heads=16, spt=63"#
+        ));
+
+        let descriptor = vmdk_descriptor(Path::new("/blog_os/disk.img"), 2100);
+        assert!(descriptor.contains(r#"RW 2100 FLAT "/blog_os/disk.img" 0"#));
+        assert!(descriptor.contains(r#"ddb.geometry.cylinders = "2""#));
+    }
+}
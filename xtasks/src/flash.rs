@@ -0,0 +1,192 @@
+//! Writing an image to a USB stick, after making sure the device is one, and reporting what about
+//! it could trip the bootloader up on real hardware, where the BIOS decides how the stick shows up
+//! in EDD
+
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail, ensure};
+
+use crate::{SECTOR_SIZE, cache::Fingerprint, image};
+
+// Not in std, from asm-generic/fcntl.h. Reading back through the page cache would only check
+// that the cache holds what was written
+const O_DIRECT: i32 = 0o40000;
+pub(crate) const DIRECT_IO_ALIGNMENT: usize = 4096;
+// The most sectors CHS addressing reaches, 1024 cylinders of 255 heads of 63 sectors
+const CHS_MAX_SECTORS: u64 = 1024 * 255 * 63;
+
+/// A whole disk, as sysfs describes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BlockDevice {
+    pub(crate) path: PathBuf,
+    pub(crate) name: String,
+    pub(crate) model: String,
+    pub(crate) removable: bool,
+    /// In 512 bytes sectors, whatever the device's block size
+    pub(crate) sectors: u64,
+    pub(crate) logical_block_size: u64,
+}
+
+fn read_sysfs(path: &Path) -> anyhow::Result<String> {
+    std::fs::read_to_string(path)
+        .map(|contents| contents.trim().to_string())
+        .with_context(|| format!("reading {}", path.to_string_lossy()))
+}
+
+impl BlockDevice {
+    /// Looks `path` up in sysfs, refusing partitions: the image brings its own boot sector
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("resolving {}", path.to_string_lossy()))?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .context("no device name")?;
+        let class_path = Path::new("/sys/class/block").join(&name);
+        ensure!(class_path.exists(), "{name} isn't a block device");
+        ensure!(
+            !class_path.join("partition").exists(),
+            "{name} is a partition, pass the whole device"
+        );
+        let block_path = Path::new("/sys/block").join(&name);
+        let vendor = read_sysfs(&block_path.join("device/vendor")).unwrap_or_default();
+        let model = read_sysfs(&block_path.join("device/model")).unwrap_or_default();
+        Ok(Self {
+            model: format!("{vendor} {model}").trim().to_string(),
+            removable: read_sysfs(&block_path.join("removable"))? == "1",
+            sectors: read_sysfs(&block_path.join("size"))?
+                .parse()
+                .context("parsing the device size")?,
+            logical_block_size: read_sysfs(&block_path.join("queue/logical_block_size"))?
+                .parse()
+                .context("parsing the logical block size")?,
+            path,
+            name,
+        })
+    }
+
+    /// The mount points of the device's partitions, or of the device itself
+    pub(crate) fn mount_points(&self) -> anyhow::Result<Vec<String>> {
+        let mounts = std::fs::read_to_string("/proc/mounts").context("reading /proc/mounts")?;
+        let device = self.path.to_string_lossy();
+        Ok(mounts
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let source = fields.next()?;
+                let mount_point = fields.next()?;
+                source
+                    .starts_with(device.as_ref())
+                    .then(|| mount_point.to_string())
+            })
+            .collect())
+    }
+}
+
+/// What about `device` and `image` makes BIOSes differ in how they boot the stick, and so in what
+/// stage1 and stage2 get from int 13h
+pub(crate) fn edd_quirks(image: &[u8], device: &BlockDevice) -> Vec<String> {
+    let mut quirks = Vec::new();
+    if device.logical_block_size != SECTOR_SIZE {
+        quirks.push(format!(
+            "the device has {} bytes logical blocks: EDD will report them as bytes per sector, \
+             while the bootloader reads 512 bytes sectors",
+            device.logical_block_size
+        ));
+    }
+    if device.sectors > CHS_MAX_SECTORS {
+        quirks.push(format!(
+            "the device is {} MiB, more than CHS reaches: BIOSes without EDD support for USB may \
+             emulate it as a floppy, or not boot it at all",
+            device.sectors * SECTOR_SIZE / (1024 * 1024)
+        ));
+    }
+    if !image::has_active_partition(image) {
+        quirks.push(
+            "the image has no active partition: some BIOSes then boot the stick as a floppy \
+             (drive 0x00), and EDD extensions aren't available for floppies"
+                .into(),
+        );
+    }
+    quirks
+}
+
+/// A buffer for O_DIRECT reads, which need it aligned
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct DirectIoBlock([u8; DIRECT_IO_ALIGNMENT]);
+
+/// Writes `image` to the start of `device` and reads it back from the device itself, bypassing
+/// the page cache, to compare it
+pub(crate) fn write_and_verify(image: &[u8], device: &BlockDevice) -> anyhow::Result<()> {
+    let mut output = OpenOptions::new()
+        .write(true)
+        .open(&device.path)
+        .with_context(|| format!("opening {} for writing", device.path.to_string_lossy()))?;
+    output.write_all(image).context("writing the image")?;
+    output
+        .sync_all()
+        .context("flushing the image to the device")?;
+    drop(output);
+
+    let mut input = OpenOptions::new()
+        .read(true)
+        .custom_flags(O_DIRECT)
+        .open(&device.path)
+        .with_context(|| format!("opening {} for reading", device.path.to_string_lossy()))?;
+    let blocks = image.len().div_ceil(DIRECT_IO_ALIGNMENT);
+    let mut read_back = vec![DirectIoBlock([0; DIRECT_IO_ALIGNMENT]); blocks];
+    for block in &mut read_back {
+        // The device is at least as big as the image, so the last block is still on it
+        input
+            .read_exact(&mut block.0)
+            .context("reading the image back")?;
+    }
+    let read_back: Vec<u8> = read_back
+        .iter()
+        .flat_map(|block| block.0)
+        .take(image.len())
+        .collect();
+
+    let written = Fingerprint::new().value(image).finish();
+    let read = Fingerprint::new().value(read_back.as_slice()).finish();
+    if written != read {
+        bail!("the device reads back {read:016x}, the image hashes to {written:016x}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::flash::{BlockDevice, edd_quirks};
+
+    #[test]
+    fn quirks() {
+        let mut image = vec![0u8; 1024];
+        // An active partition after the bootloader
+        image[0x1BE] = 0x80;
+        image[0x1BE + 8] = 2;
+        let mut device = BlockDevice {
+            path: PathBuf::from("/dev/sdb"),
+            name: "sdb".into(),
+            model: "Stick".into(),
+            removable: true,
+            sectors: 4 * 1024 * 1024,
+            logical_block_size: 512,
+        };
+        assert!(edd_quirks(&image, &device).is_empty());
+
+        device.logical_block_size = 4096;
+        device.sectors = 64 * 1024 * 1024;
+        image[0x1BE] = 0;
+        assert_eq!(3, edd_quirks(&image, &device).len());
+    }
+}
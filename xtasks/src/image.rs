@@ -0,0 +1,529 @@
+//! Checking that a disk image is laid out the way the bootloader expects it: stage1 in the boot
+//! sector, stage2 right after it, then the payload table (see common/src/payload.rs) and the
+//! payloads it lists, the kernel ELF among them, with the stage2 size nasm or cargo baked into
+//! stage1 matching what's actually there
+
+use std::fmt::Display;
+
+use anyhow::{Context, bail, ensure};
+use common::{gpt::Gpt, ram_disk::RamDisk};
+
+use crate::{
+    SECTOR_SIZE,
+    layout::{STAGE2_BASE, STAGE2_STACK},
+};
+
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const STAGE1_LOAD_ADDRESS: usize = 0x7C00;
+// stage1 hands over to stage2 with
+//   push dword STAGE2_STACK_START
+//   push dword STAGE2_SECTORS
+//   push dword DriveParameters
+//   call STAGE2_ENTRYPOINT
+const PUSH_IMMEDIATE_32: u8 = 0x68;
+const CALL_RELATIVE: u8 = 0xE8;
+const HANDOVER_PUSHES: usize = 3;
+const HANDOVER_LENGTH: usize = HANDOVER_PUSHES * 5 + 1;
+// What the first word of DriveParameters is initialized to, the size of the EDD buffer
+const DRIVE_PARAMETERS_SIZE: u16 = 66;
+// stage2 reads the kernel file into 0x10000..0x60000, see bootloader/src/memory_map.rs
+const MAX_KERNEL_SECTORS: u64 = 0x50000 / SECTOR_SIZE;
+// stage2 has to end before its stack. Its statics come on top of that, which only the linker checks
+const MAX_STAGE2_SECTORS: u64 = (STAGE2_STACK.start - STAGE2_BASE) / SECTOR_SIZE;
+
+const PAYLOAD_TABLE_MAGIC: [u8; 8] = *b"BLOGPAYL";
+const PAYLOAD_TABLE_HEADER_SIZE: usize = 16;
+const PAYLOAD_ENTRY_SIZE: usize = 16;
+pub(crate) const MAX_PAYLOADS: usize =
+    (SECTOR_SIZE as usize - PAYLOAD_TABLE_HEADER_SIZE) / PAYLOAD_ENTRY_SIZE;
+
+const PARTITION_TABLE_OFFSET: usize = 0x1BE;
+const PARTITION_ENTRY_SIZE: usize = 16;
+const PARTITION_ENTRIES: usize = 4;
+// The type of the one MBR partition covering a GPT disk from sector 1
+const PROTECTIVE_PARTITION_TYPE: u8 = 0xEE;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_MACHINE_X86_64: u16 = 0x3E;
+
+/// The payload kinds of common/src/payload.rs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PayloadKind {
+    Kernel = 1,
+    Symbols = 2,
+    Initrd = 3,
+    CommandLine = 4,
+    Stage2Symbols = 5,
+}
+
+impl PayloadKind {
+    fn from_u32(kind: u32) -> Option<Self> {
+        match kind {
+            1 => Some(PayloadKind::Kernel),
+            2 => Some(PayloadKind::Symbols),
+            3 => Some(PayloadKind::Initrd),
+            4 => Some(PayloadKind::CommandLine),
+            5 => Some(PayloadKind::Stage2Symbols),
+            _ => None,
+        }
+    }
+}
+
+impl Display for PayloadKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PayloadKind::Kernel => "kernel",
+            PayloadKind::Symbols => "symbols",
+            PayloadKind::Initrd => "initrd",
+            PayloadKind::CommandLine => "command line",
+            PayloadKind::Stage2Symbols => "stage2 symbols",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Payload {
+    pub(crate) kind: PayloadKind,
+    pub(crate) sectors: u64,
+    pub(crate) lba: u64,
+}
+
+impl Payload {
+    fn end(&self) -> u64 {
+        self.lba + self.sectors
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Layout {
+    pub(crate) stage2_sectors: u64,
+    pub(crate) payloads: Vec<Payload>,
+}
+
+impl Layout {
+    pub(crate) fn payload_table_sector(&self) -> u64 {
+        1 + self.stage2_sectors
+    }
+
+    fn total_sectors(&self) -> u64 {
+        self.payloads
+            .iter()
+            .map(Payload::end)
+            .max()
+            .unwrap_or_default()
+            .max(self.payload_table_sector() + 1)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// The payload table sector for payloads of the given kinds and sizes in bytes, which follow it on
+/// disk in that order, each from a sector of its own and the first one from `first_lba`
+pub(crate) fn payload_table(kinds_and_sizes: &[(PayloadKind, u64)], first_lba: u64) -> Vec<u8> {
+    let mut table = vec![0u8; SECTOR_SIZE as usize];
+    table[..8].copy_from_slice(&PAYLOAD_TABLE_MAGIC);
+    table[8..12].copy_from_slice(&(kinds_and_sizes.len() as u32).to_le_bytes());
+    let mut lba = first_lba;
+    for (index, (kind, size)) in kinds_and_sizes.iter().enumerate() {
+        let sectors = size.div_ceil(SECTOR_SIZE);
+        let offset = PAYLOAD_TABLE_HEADER_SIZE + index * PAYLOAD_ENTRY_SIZE;
+        let entry = &mut table[offset..offset + PAYLOAD_ENTRY_SIZE];
+        entry[..4].copy_from_slice(&(*kind as u32).to_le_bytes());
+        entry[4..8].copy_from_slice(&(sectors as u32).to_le_bytes());
+        entry[8..].copy_from_slice(&lba.to_le_bytes());
+        lba += sectors;
+    }
+    table
+}
+
+fn read_payload_table(sector: &[u8]) -> anyhow::Result<Vec<Payload>> {
+    ensure!(
+        sector.get(..8) == Some(&PAYLOAD_TABLE_MAGIC[..]),
+        "no payload table after stage2"
+    );
+    let count = read_u32(sector, 8).unwrap_or_default() as usize;
+    ensure!(
+        count <= MAX_PAYLOADS,
+        "the payload table lists {count} payloads, it can hold {MAX_PAYLOADS}"
+    );
+    (0..count)
+        .map(|index| {
+            let offset = PAYLOAD_TABLE_HEADER_SIZE + index * PAYLOAD_ENTRY_SIZE;
+            let (Some(kind), Some(sectors), Some(lba)) = (
+                read_u32(sector, offset),
+                read_u32(sector, offset + 4),
+                read_u64(sector, offset + 8),
+            ) else {
+                bail!("truncated payload table");
+            };
+            Ok(Payload {
+                kind: PayloadKind::from_u32(kind)
+                    .with_context(|| format!("payload {index} has an unknown kind {kind}"))?,
+                sectors: sectors.into(),
+                lba,
+            })
+        })
+        .collect()
+}
+
+/// The STAGE2_SECTORS stage1 was assembled with, from the arguments it pushes for stage2, which
+/// also point at DriveParameters, checked to be in stage1
+fn read_stage2_sectors(stage1: &[u8]) -> anyhow::Result<u64> {
+    let mut handovers = stage1.windows(HANDOVER_LENGTH).filter(|window| {
+        (0..HANDOVER_PUSHES).all(|push| window[push * 5] == PUSH_IMMEDIATE_32)
+            && window[HANDOVER_LENGTH - 1] == CALL_RELATIVE
+    });
+    let Some(handover) = handovers.next() else {
+        bail!("stage1 doesn't hand over to stage2 the way boot.asm does");
+    };
+    ensure!(
+        handovers.next().is_none(),
+        "more than one candidate handover to stage2 in stage1"
+    );
+    let read_argument = |push: usize| read_u32(handover, push * 5 + 1).map(u64::from);
+    let (Some(stage2_sectors), Some(drive_parameters)) = (read_argument(1), read_argument(2))
+    else {
+        bail!("truncated handover to stage2");
+    };
+
+    let drive_parameters = (drive_parameters as usize)
+        .checked_sub(STAGE1_LOAD_ADDRESS)
+        .context("DriveParameters outside of stage1")?;
+    ensure!(
+        read_u16(stage1, drive_parameters) == Some(DRIVE_PARAMETERS_SIZE),
+        "DriveParameters at {drive_parameters:#x} isn't where stage2 is told it is"
+    );
+    Ok(stage2_sectors)
+}
+
+/// An MBR partition table isn't needed to boot, and stage1 doesn't have one, but any partition
+/// there is must not overlap the bootloader or the payloads, nor go past the end of the image
+fn check_partition_table(image: &[u8], layout: &Layout, image_sectors: u64) -> anyhow::Result<()> {
+    for index in 0..PARTITION_ENTRIES {
+        let offset = PARTITION_TABLE_OFFSET + index * PARTITION_ENTRY_SIZE;
+        let entry = &image[offset..offset + PARTITION_ENTRY_SIZE];
+        if entry.iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let status = entry[0];
+        ensure!(
+            status == 0x00 || status == 0x80,
+            "partition {index} has an invalid status byte {status:#x}"
+        );
+        let start = read_u32(entry, 8).map(u64::from).unwrap_or_default();
+        let sectors = read_u32(entry, 12).map(u64::from).unwrap_or_default();
+        if entry[4] == PROTECTIVE_PARTITION_TYPE {
+            check_gpt(image, layout)?;
+        } else {
+            ensure!(
+                start >= layout.total_sectors(),
+                "partition {index} starts at sector {start}, over the bootloader or the payloads"
+            );
+        }
+        ensure!(
+            start + sectors <= image_sectors,
+            "partition {index} ends at sector {}, past the end of the image",
+            start + sectors
+        );
+    }
+    Ok(())
+}
+
+/// Reads the GPT a protective MBR partition stands for with `common::gpt`, the primary table or
+/// the backup one at the end of the image, and checks its partitions stay clear of the bootloader
+/// and the payloads
+fn check_gpt(image: &[u8], layout: &Layout) -> anyhow::Result<()> {
+    let mut disk = image.to_vec();
+    let gpt = Gpt::read(&mut RamDisk::new(&mut disk))
+        .map_err(|err| anyhow::anyhow!("{err}"))
+        .context("the MBR has a protective partition, but no GPT checks out")?;
+    for partition in gpt.partitions() {
+        ensure!(
+            partition.first_lba >= layout.total_sectors(),
+            "GPT partition {partition} starts over the bootloader or the payloads"
+        );
+    }
+    Ok(())
+}
+
+/// Whether the image's partition table marks any partition bootable
+pub(crate) fn has_active_partition(image: &[u8]) -> bool {
+    (0..PARTITION_ENTRIES).any(|index| {
+        image.get(PARTITION_TABLE_OFFSET + index * PARTITION_ENTRY_SIZE) == Some(&0x80)
+    })
+}
+
+/// Checks the kernel is a 64-bit x86 ELF taking exactly the sectors its payload entry says. The
+/// section headers are the last thing in the kernel file, so they tell its size
+fn check_kernel(kernel: &[u8], payload: &Payload) -> anyhow::Result<()> {
+    ensure!(
+        kernel.get(..4) == Some(&ELF_MAGIC[..]),
+        "no ELF magic at sector {}, where the payload table puts the kernel",
+        payload.lba
+    );
+    ensure!(
+        kernel.get(4) == Some(&ELF_CLASS_64),
+        "the kernel isn't a 64-bit ELF"
+    );
+    ensure!(
+        read_u16(kernel, 0x12) == Some(ELF_MACHINE_X86_64),
+        "the kernel isn't an x86_64 ELF"
+    );
+    let (Some(section_headers_offset), Some(section_header_size), Some(section_headers)) = (
+        read_u64(kernel, 0x28),
+        read_u16(kernel, 0x3A),
+        read_u16(kernel, 0x3C),
+    ) else {
+        bail!("truncated kernel ELF header");
+    };
+    let kernel_size =
+        section_headers_offset + u64::from(section_header_size) * u64::from(section_headers);
+    ensure!(
+        kernel_size.div_ceil(SECTOR_SIZE) == payload.sectors,
+        "the kernel takes {} sectors, but the payload table says {}",
+        kernel_size.div_ceil(SECTOR_SIZE),
+        payload.sectors
+    );
+    Ok(())
+}
+
+/// Checks `image` is a bootable disk image. With `stage2`, the one that was built, it's also
+/// checked to be what's in the image
+pub(crate) fn verify(image: &[u8], stage2: Option<&[u8]>) -> anyhow::Result<Layout> {
+    let sector_size = SECTOR_SIZE as usize;
+    ensure!(
+        image.len() >= sector_size && image.len().is_multiple_of(sector_size),
+        "the image is {} bytes, not a whole number of sectors",
+        image.len()
+    );
+    let stage1 = &image[..sector_size];
+    ensure!(
+        stage1[BOOT_SIGNATURE_OFFSET..] == BOOT_SIGNATURE,
+        "no boot signature at the end of the first sector, the BIOS won't boot it"
+    );
+
+    let stage2_sectors = read_stage2_sectors(stage1)?;
+    ensure!(
+        (1..=MAX_STAGE2_SECTORS).contains(&stage2_sectors),
+        "stage1 reads {stage2_sectors} stage2 sectors, it can take between 1 and \
+         {MAX_STAGE2_SECTORS}"
+    );
+    let image_sectors = (image.len() / sector_size) as u64;
+    let table_sector = 1 + stage2_sectors;
+    ensure!(
+        table_sector < image_sectors,
+        "the image ends before the payload table, at sector {table_sector}"
+    );
+    let table_start = table_sector as usize * sector_size;
+    let layout = Layout {
+        stage2_sectors,
+        payloads: read_payload_table(&image[table_start..table_start + sector_size])?,
+    };
+
+    let mut payloads = layout.payloads.clone();
+    payloads.sort_by_key(|payload| payload.lba);
+    let mut previous_end = table_sector + 1;
+    for payload in &payloads {
+        ensure!(
+            payload.lba >= previous_end,
+            "the {} payload at sector {} overlaps what comes before it",
+            payload.kind,
+            payload.lba
+        );
+        previous_end = payload.end();
+    }
+    ensure!(
+        image_sectors == layout.total_sectors(),
+        "the image has {image_sectors} sectors, but its payloads end at sector {}",
+        layout.total_sectors()
+    );
+
+    let mut kernels = layout
+        .payloads
+        .iter()
+        .filter(|payload| payload.kind == PayloadKind::Kernel);
+    let (Some(kernel), None) = (kernels.next(), kernels.next()) else {
+        bail!("the payload table has to list exactly one kernel");
+    };
+    ensure!(
+        (1..=MAX_KERNEL_SECTORS).contains(&kernel.sectors),
+        "the kernel takes {} sectors, the bootloader can read between 1 and {}",
+        kernel.sectors,
+        MAX_KERNEL_SECTORS
+    );
+
+    if let Some(stage2) = stage2 {
+        ensure!(
+            stage2.len().div_ceil(sector_size) as u64 == layout.stage2_sectors,
+            "stage2 takes {} sectors, but stage1 reads {}",
+            stage2.len().div_ceil(sector_size),
+            layout.stage2_sectors
+        );
+        let (image_stage2, padding) = image[sector_size..table_start].split_at(stage2.len());
+        ensure!(
+            image_stage2 == stage2 && padding.iter().all(|&byte| byte == 0),
+            "the stage2 in the image isn't the one that was built"
+        );
+    }
+
+    check_kernel(
+        &image[kernel.lba as usize * sector_size..kernel.end() as usize * sector_size],
+        kernel,
+    )?;
+    check_partition_table(image, &layout, image_sectors)?;
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use common::hash::crc32;
+
+    use crate::{
+        SECTOR_SIZE,
+        image::{Layout, Payload, PayloadKind, payload_table, verify},
+    };
+
+    const SECTOR: usize = SECTOR_SIZE as usize;
+
+    type Corruption = (&'static str, fn(&mut Vec<u8>));
+
+    /// What xtasks builds: stage1 with the handover boot.asm assembles to, stage2, the payload
+    /// table, a kernel ELF whose section headers end in its last sector, and an initrd
+    fn golden_image() -> (Vec<u8>, Vec<u8>) {
+        let mut stage1 = vec![0u8; SECTOR];
+        let handover = [
+            [0x68, 0x00, 0x00, 0x09, 0x00],
+            [0x68, 0x02, 0x00, 0x00, 0x00],
+            [0x68, 0x40, 0x7D, 0x00, 0x00],
+        ];
+        stage1[0x60..0x6F].copy_from_slice(handover.as_flattened());
+        stage1[0x6F] = 0xE8;
+        stage1[0x140..0x142].copy_from_slice(&66u16.to_le_bytes());
+        stage1[510..].copy_from_slice(&[0x55, 0xAA]);
+
+        let stage2 = vec![0x90; SECTOR + 100];
+
+        let mut kernel = vec![0u8; 3 * SECTOR];
+        kernel[..5].copy_from_slice(b"\x7fELF\x02");
+        kernel[0x12..0x14].copy_from_slice(&0x3Eu16.to_le_bytes());
+        kernel[0x28..0x30].copy_from_slice(&0x400u64.to_le_bytes());
+        kernel[0x3A..0x3C].copy_from_slice(&64u16.to_le_bytes());
+        kernel[0x3C..0x3E].copy_from_slice(&5u16.to_le_bytes());
+
+        let mut image = stage1;
+        image.extend_from_slice(&stage2);
+        image.resize(3 * SECTOR, 0);
+        image.extend_from_slice(&payload_table(
+            &[
+                (PayloadKind::Kernel, kernel.len() as u64),
+                (PayloadKind::Initrd, 10),
+            ],
+            4,
+        ));
+        image.extend_from_slice(&kernel);
+        image.resize(8 * SECTOR, 0x42);
+        (image, stage2)
+    }
+
+    #[test]
+    fn golden_layout() {
+        let (image, stage2) = golden_image();
+        assert_eq!(
+            Layout {
+                stage2_sectors: 2,
+                payloads: vec![
+                    Payload {
+                        kind: PayloadKind::Kernel,
+                        sectors: 3,
+                        lba: 4
+                    },
+                    Payload {
+                        kind: PayloadKind::Initrd,
+                        sectors: 1,
+                        lba: 7
+                    }
+                ]
+            },
+            verify(&image, Some(&stage2)).unwrap()
+        );
+    }
+
+    #[test]
+    fn malformed_images() {
+        let (image, stage2) = golden_image();
+        let corruptions: [Corruption; 9] = [
+            ("boot signature", |image| image[511] = 0),
+            ("truncated", |image| image.truncate(image.len() - SECTOR)),
+            ("kernel size mismatch", |image| image[3 * SECTOR + 0x14] = 4),
+            ("payload table magic", |image| image[3 * SECTOR] = 0),
+            ("overlapping payloads", |image| image[3 * SECTOR + 0x28] = 6),
+            ("kernel ELF magic", |image| image[4 * SECTOR] = 0),
+            ("kernel architecture", |image| {
+                image[4 * SECTOR + 0x12] = 0x28
+            }),
+            ("kernel longer than read", |image| {
+                image[4 * SECTOR + 0x3C] = 9
+            }),
+            ("partition over the payloads", |image| {
+                image[0x1BE + 8] = 7;
+                image[0x1BE + 12] = 1;
+            }),
+        ];
+        for (corruption, corrupt) in corruptions {
+            let mut image = image.clone();
+            corrupt(&mut image);
+            assert!(verify(&image, Some(&stage2)).is_err(), "{corruption}");
+        }
+
+        let mut other_stage2 = stage2.clone();
+        other_stage2[0] = 0xCC;
+        assert!(verify(&image, Some(&other_stage2)).is_err());
+        assert!(verify(&image, None).is_ok());
+    }
+
+    #[test]
+    fn protective_mbr() {
+        let (mut image, stage2) = golden_image();
+        image[0x1BE + 4] = 0xEE;
+        image[0x1BE + 8] = 1;
+        image[0x1BE + 12] = 7;
+        let err = verify(&image, Some(&stage2)).unwrap_err();
+        assert!(format!("{err:#}").contains("no GPT checks out"), "{err:#}");
+
+        // stage2 is where the primary GPT goes, but a backup one without partitions in the last
+        // sector checks out
+        let header = &mut image[7 * SECTOR..][..SECTOR];
+        header.fill(0);
+        header[..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&7u64.to_le_bytes());
+        header[32..40].copy_from_slice(&1u64.to_le_bytes());
+        header[40..48].copy_from_slice(&6u64.to_le_bytes());
+        header[48..56].copy_from_slice(&6u64.to_le_bytes());
+        header[72..80].copy_from_slice(&7u64.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&[]).to_le_bytes());
+        let header_crc32 = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&header_crc32.to_le_bytes());
+        assert!(verify(&image, Some(&stage2)).is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+//! Generating the linker scripts of stage2 and the kernel from common/src/layout.rs, so that the
+//! addresses the Rust code assumes and the ones the linker puts things at can't drift apart. The
+//! scripts are checked in, the linker reads them from there
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::layout::{
+    FRAME_POOL, HEAP_SIZE, HEAP_START, KERNEL_BASE, KERNEL_STACK_SIZE, MMIO_WINDOW, STAGE2_BASE,
+    STAGE2_STACK,
+};
+
+const HEADER: &str = "/* Generated by `xtasks linker-scripts` from common/src/layout.rs, edit that \
+                      instead */\n";
+
+pub(crate) fn bootloader() -> String {
+    format!(
+        "{HEADER}ENTRY(start)
+SECTIONS {{
+  . = {STAGE2_BASE:#x};                /* where stage1 copies it, see memory_map.rs */
+
+  /DISCARD/ : {{
+    *(.eh_frame*) *(.gcc_except_table*)
+    *(.note*) *(.comment)
+    *(.interp) *(.dynamic) *(.dynsym) *(.dynstr)
+    *(.got.plt) *(.got) *(.plt) *(.rel*) *(.rela*)
+  }}
+
+  .text : ALIGN(16) {{
+    KEEP(*(.text.start))      /* start is literally first bytes */
+    *(.text .text.*)
+  }}
+
+  .rodata : ALIGN(16) {{ *(.rodata .rodata.*) }}  /* include constants! */
+  .data   : ALIGN(16) {{ *(.data .data.*) }}
+  .bss (NOLOAD) : ALIGN(16) {{ *(.bss .bss.*) *(COMMON) }}
+
+  ASSERT(. <= {:#x}, \"stage2 doesn't fit under its stack\")
+}}
+",
+        STAGE2_STACK.start
+    )
+}
+
+/// The kernel keeps its dynamic sections, the bootloader applies its relocations when it's built
+/// position independent
+pub(crate) fn kernel() -> String {
+    format!(
+        "{HEADER}ENTRY(_start)
+SECTIONS {{
+  . = {KERNEL_BASE:#x};
+
+  .text   : ALIGN(4K) {{ *(.text .text.*) }}
+  .rodata : ALIGN(4K) {{ *(.rodata .rodata.*) }}
+  .data   : ALIGN(4K) {{ *(.data .data.*) }}
+  .bss    : ALIGN(4K) {{ *(.bss .bss.*) *(COMMON) }}
+
+  ASSERT(ALIGN({KERNEL_STACK_SIZE:#x}) + {KERNEL_STACK_SIZE:#x} <= {HEAP_START:#x}, \"the kernel and its stack run into the heap\")
+  ASSERT({:#x} <= {:#x}, \"the heap runs into the frame pool\")
+  ASSERT({:#x} <= {:#x}, \"the frame pool runs into the MMIO window\")
+}}
+",
+        HEAP_START + HEAP_SIZE,
+        FRAME_POOL.start,
+        FRAME_POOL.end,
+        MMIO_WINDOW.start
+    )
+}
+
+/// The scripts, with where they go under the root directory
+pub(crate) fn scripts() -> [(&'static str, String); 2] {
+    [
+        ("bootloader/link.x", bootloader()),
+        ("kernel/link.x", kernel()),
+    ]
+}
+
+/// Writes the scripts that aren't up to date, returning which ones were
+pub(crate) fn generate(root_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut written = Vec::new();
+    for (path, script) in scripts() {
+        let path = root_dir.join(path);
+        if std::fs::read_to_string(&path).ok().as_deref() == Some(script.as_str()) {
+            continue;
+        }
+        std::fs::write(&path, script)
+            .with_context(|| format!("writing {}", path.to_string_lossy()))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::linker_scripts::{bootloader, kernel};
+
+    #[test]
+    fn checked_in_scripts_are_up_to_date() {
+        assert_eq!(include_str!("../../bootloader/link.x"), bootloader());
+        assert_eq!(include_str!("../../kernel/link.x"), kernel());
+    }
+}
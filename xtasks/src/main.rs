@@ -1,16 +1,41 @@
 use std::{
+    io::{BufRead, BufReader, IsTerminal, Write},
     os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use clap::Parser as _;
 
+use crate::{
+    cache::{Cache, Fingerprint},
+    test_report::{Event, Report},
+};
+
+mod cache;
+mod cpio;
+mod elf_info;
+mod emulator_config;
+mod flash;
+mod image;
+// Only what the linker scripts and the image checks need of it is used here
+#[allow(dead_code)]
+#[path = "../../common/src/layout.rs"]
+mod layout;
+mod linker_scripts;
+mod qemu;
+mod symbols;
+mod test_report;
+
 const SECTOR_SIZE: u64 = 512;
+// What the kernel's test runner has QEMU exit with when every test passed
+const QEMU_TESTS_PASSED_EXIT_CODE: i32 = 33;
 
 mod xtasks {
-    use clap::{Parser, Subcommand};
+    use clap::{Parser, Subcommand, ValueEnum};
     #[derive(Parser, Debug)]
     #[command(author, version, about, long_about = None)]
     pub(crate) struct Cli {
@@ -38,16 +63,204 @@ mod xtasks {
             #[arg(short, long, default_value_t = false)]
             /// Collect and print extra info during the build process
             verbose: bool,
+            #[arg(long, value_enum, default_value_t = StackProtector::None)]
+            /// Which kernel functions get a stack canary checked before returning
+            stack_protector: StackProtector,
+            #[arg(long, default_value_t = false)]
+            /// Build the kernel position independent, so the bootloader loads it at a random offset
+            kaslr: bool,
+            #[arg(long, default_value_t = false)]
+            /// Redo every step, even the ones whose inputs didn't change since the last build
+            force: bool,
+            #[arg(long, default_value_t = false)]
+            /// Ship the code symbols of the kernel and of stage2, to symbolize their backtraces
+            symbols: bool,
+            #[arg(long)]
+            /// A file for the bootloader to load as the initrd, relative to the root directory
+            initrd: Option<String>,
+            #[arg(long, conflicts_with = "initrd")]
+            /// A directory to pack into a cpio archive for the initrd, for the kernel to unpack
+            /// into its root file system. Relative to the root directory
+            initrd_dir: Option<String>,
+            #[arg(long)]
+            /// Options for the kernel, e.g. "console=com2,115200" to log to COM2 at 115200 baud
+            cmdline: Option<String>,
+            #[arg(long, default_value_t = false, conflicts_with = "kaslr")]
+            /// Boot the same way every time, for `run --record` and `--replay`: adds
+            /// `deterministic` to the kernel command line, for random numbers with a fixed seed
+            deterministic: bool,
+            #[arg(long, default_value_t = false)]
+            /// Time the boot steps of the bootloader, for the kernel to print a breakdown at entry
+            metrics: bool,
+            #[arg(long, default_value_t = false)]
+            /// Build stage2 without the USB probe, the PCI report and the progress lines, so that
+            /// it takes fewer sectors to load
+            minimal: bool,
+            #[arg(long, value_enum, default_value_t = Stage1::Nasm)]
+            /// How to build the boot sector
+            stage1: Stage1,
+        },
+        /// Boot an image in qemu, attached through the given drive interface, or in Bochs
+        Run {
+            #[arg(default_value_t = String::from("disk.img"))]
+            /// The image to boot, relative to the root directory
+            image: String,
+            #[arg(long, value_enum, default_value_t = Emulator::Qemu)]
+            /// Which emulator, and so which BIOS, to boot the image with
+            emulator: Emulator,
+            #[arg(long, value_enum, default_value_t = DriveInterface::Ide)]
+            /// The controller the image and the data disks sit behind
+            interface: DriveInterface,
+            #[arg(long, default_value_t = 0)]
+            /// How many blank data disks to attach after the image
+            data_disks: usize,
+            #[arg(long, default_value_t = 64)]
+            /// The size of each data disk in MiB. Existing ones are reused as they are
+            data_disk_size: u64,
+            #[arg(long, conflicts_with = "replay")]
+            /// Record the run to this file, relative to the root directory, for `--replay` to
+            /// go through it again. Only with IDE, and best with a `--deterministic` image
+            record: Option<String>,
+            #[arg(long)]
+            /// Replay a run recorded with `--record`, e.g. to debug it with GDB
+            replay: Option<String>,
+        },
+        /// Write an image to a USB stick, and read it back to check it was written right
+        Flash {
+            #[arg(short, long)]
+            /// The whole device to write to, e.g. /dev/sdb. It must be removable and unmounted
+            device: String,
+            #[arg(default_value_t = String::from("disk.img"))]
+            /// The image to write, relative to the root directory
+            image: String,
+            #[arg(short, long, default_value_t = false)]
+            /// Don't ask for confirmation before writing
+            yes: bool,
+        },
+        /// Write a VMDK descriptor wrapping an image, for attaching it to a VirtualBox VM
+        Vmdk {
+            #[arg(default_value_t = String::from("disk.img"))]
+            /// The image to wrap, relative to the root directory
+            image: String,
+            #[arg(short, long, default_value_t = String::from("target/disk.vmdk"))]
+            /// Where to write the descriptor, relative to the root directory
+            output: String,
+        },
+        /// Check that an image is laid out the way the bootloader expects it
+        VerifyImage {
+            #[arg(default_value_t = String::from("disk.img"))]
+            /// The image to check, relative to the root directory
+            image: String,
+        },
+        /// Print the header of an ELF file and its tables, the way the bootloader and the kernel
+        /// parse them. Every table unless some are picked
+        ElfInfo {
+            /// The ELF file, relative to the root directory
+            path: String,
+            #[arg(long, default_value_t = false)]
+            /// The section headers
+            sections: bool,
+            #[arg(long, default_value_t = false)]
+            /// The program headers, and the dynamic entries if there are any
+            segments: bool,
+            #[arg(long, default_value_t = false)]
+            /// The entries of the symbol tables
+            symbols: bool,
+            #[arg(long, default_value_t = false)]
+            /// The notes of the NOTE sections, e.g. the build ID
+            notes: bool,
+            #[arg(long, default_value_t = false)]
+            /// No colors, which are left out anyway when the output isn't a terminal
+            no_color: bool,
+        },
+        /// Regenerate the linker scripts of stage2 and the kernel from common/src/layout.rs
+        LinkerScripts {
+            #[arg(long, default_value_t = false)]
+            /// Don't write anything, fail if a script isn't up to date instead
+            check: bool,
+        },
+        /// Run clippy on every crate, for every target it's built for, and report which ones failed
+        Check {
+            #[arg(long, default_value_t = false)]
+            /// Run cargo check instead of clippy
+            no_clippy: bool,
+        },
+        /// Build the kernel's tests into an image and run them in qemu
+        Test {
+            #[arg(short, long)]
+            /// Only run the tests whose name contains this
+            filter: Option<String>,
+            #[arg(long, default_value_t = 300)]
+            /// Seconds to give the whole run before giving up on it
+            timeout: u64,
+            #[arg(short, long, default_value_t = false)]
+            /// Also print what the kernel writes to the serial port besides test results
+            verbose: bool,
+            #[arg(long, default_value_t = false)]
+            /// Redo every step, even the ones whose inputs didn't change since the last build
+            force: bool,
+            #[arg(long, value_enum, default_value_t = Stage1::Nasm)]
+            /// How to build the boot sector
+            stage1: Stage1,
         },
     }
+
+    #[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Emulator {
+        Qemu,
+        /// Only with IDE and without data disks
+        Bochs,
+    }
+
+    /// How qemu attaches the drives
+    #[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum DriveInterface {
+        Ide,
+        Ahci,
+        Nvme,
+        VirtioBlk,
+        Usb,
+    }
+
+    /// The two builds of the same boot sector
+    #[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Stage1 {
+        /// bootloader/stage1/boot.asm, with nasm
+        Nasm,
+        /// The bootloader/stage1 crate, with cargo and objcopy
+        Cargo,
+    }
+
+    /// The levels of rustc's `-Z stack-protector`
+    #[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum StackProtector {
+        None,
+        Basic,
+        Strong,
+        All,
+    }
+
+    impl StackProtector {
+        pub(crate) fn rustc_value(&self) -> &'static str {
+            match self {
+                StackProtector::None => "none",
+                StackProtector::Basic => "basic",
+                StackProtector::Strong => "strong",
+                StackProtector::All => "all",
+            }
+        }
+    }
 }
 
 fn build_bootloader(
     root_dir: &Path,
-    kernel_sectors: u64,
+    cache: &mut Cache,
+    stage1: xtasks::Stage1,
+    metrics: bool,
+    minimal: bool,
     verbose: bool,
 ) -> anyhow::Result<PathBuf> {
-    let stage2_path = build_stage2(root_dir, verbose)?;
+    let stage2_path = build_stage2(root_dir, cache, metrics, minimal, verbose)?;
 
     let metadata = std::fs::metadata(&stage2_path)
         .context("collecting info about the generated stage2 file")?;
@@ -55,29 +268,65 @@ fn build_bootloader(
     // Build stage1 to read enough sectors to load stage2
     let stage2_sectors = metadata.size().div_ceil(SECTOR_SIZE);
 
-    let stage1_path = build_stage1(root_dir, stage2_sectors, kernel_sectors)?;
+    let stage1_path = build_stage1(root_dir, stage1, stage2_sectors, cache, verbose)?;
+
+    let bootloader_path = root_dir.join("bootloader.bin");
+    let fingerprint = Fingerprint::new().file(&stage1_path)?.file(&stage2_path)?;
+    if cache.is_fresh("bootloader", &fingerprint, &[&bootloader_path]) {
+        skipped("bootloader.bin", verbose);
+        return Ok(bootloader_path);
+    }
 
     let mut bootloader = std::fs::read(&stage1_path).context("reading stage1 bytes")?;
     let mut stage2 = std::fs::read(&stage2_path).context("reading stage2 bytes")?;
     stage2.resize((stage2_sectors * SECTOR_SIZE) as usize, 0);
 
     bootloader.append(&mut stage2);
-    let bootloader_path = root_dir.join("bootloader.bin");
 
     std::fs::write(&bootloader_path, bootloader).context("writing bootloader file")?;
+    cache.record("bootloader", &fingerprint)?;
     Ok(bootloader_path)
 }
 
 fn build_stage1(
     root_dir: &Path,
+    stage1: xtasks::Stage1,
     stage2_sectors: u64,
-    kernel_sectors: u64,
+    cache: &mut Cache,
+    verbose: bool,
 ) -> Result<PathBuf, anyhow::Error> {
     let stage1_path = root_dir.join("stage1.bin");
+    let fingerprint = match stage1 {
+        xtasks::Stage1::Nasm => {
+            Fingerprint::new().file(&root_dir.join("bootloader/stage1/boot.asm"))?
+        }
+        xtasks::Stage1::Cargo => Fingerprint::new()
+            .file(&root_dir.join("bootloader/stage1/src/main.rs"))?
+            .file(&root_dir.join("bootloader/stage1/link.x"))?
+            .file(&root_dir.join("common/src/layout.rs"))?,
+    }
+    .value(format!("{stage1:?}"))
+    .value(stage2_sectors);
+    if cache.is_fresh("stage1", &fingerprint, &[&stage1_path]) {
+        skipped("stage1", verbose);
+        return Ok(stage1_path);
+    }
+    match stage1 {
+        xtasks::Stage1::Nasm => build_stage1_nasm(root_dir, stage2_sectors, &stage1_path)?,
+        xtasks::Stage1::Cargo => build_stage1_cargo(root_dir, stage2_sectors, &stage1_path)?,
+    }
+    cache.record("stage1", &fingerprint)?;
+    Ok(stage1_path)
+}
+
+fn build_stage1_nasm(
+    root_dir: &Path,
+    stage2_sectors: u64,
+    stage1_path: &Path,
+) -> anyhow::Result<()> {
     let status = Command::new("nasm")
         .args([
             &format!("-DSTAGE2_SECTORS={stage2_sectors}"),
-            &format!("-DKERNEL_SECTORS={kernel_sectors}"),
             "-fbin",
             "-o",
             &stage1_path.to_string_lossy(),
@@ -90,19 +339,97 @@ fn build_stage1(
     if !status.success() {
         anyhow::bail!("building stage1 failed");
     }
-    Ok(stage1_path)
+    Ok(())
 }
 
-fn build_stage2(root_dir: &Path, verbose: bool) -> Result<PathBuf, anyhow::Error> {
+/// Builds the stage1 crate as an ELF placed at 0x7C00 by its linker script, then copies its boot
+/// sector out of it
+fn build_stage1_cargo(
+    root_dir: &Path,
+    stage2_sectors: u64,
+    stage1_path: &Path,
+) -> anyhow::Result<()> {
+    let crate_dir = root_dir.join("bootloader/stage1");
+    // These take over from the rustflags of bootloader/.cargo/config.toml, which link stage2. The
+    // encoded form, separated by 0x1F, keeps spaces in the path to the linker script in one flag
+    let rustflags = [
+        "-C".to_string(),
+        format!("link-arg=-T{}", crate_dir.join("link.x").to_string_lossy()),
+        "-C".to_string(),
+        "link-arg=-no-pie".to_string(),
+        "-C".to_string(),
+        "relocation-model=static".to_string(),
+        "-C".to_string(),
+        "force-unwind-tables=no".to_string(),
+    ]
+    .join("\x1f");
     let status = Command::new("cargo")
-        .args(["+nightly", "bios", "--release"])
-        .current_dir(root_dir.join("bootloader"))
+        .args([
+            "+nightly",
+            "build",
+            "--release",
+            "-Zbuild-std=core,compiler_builtins",
+            "-Zbuild-std-features=mem",
+            "--target",
+            &root_dir
+                .join("bootloader/i686-bootloader.json")
+                .to_string_lossy(),
+        ])
+        .env("STAGE2_SECTORS", stage2_sectors.to_string())
+        .env("CARGO_ENCODED_RUSTFLAGS", rustflags)
+        .current_dir(&crate_dir)
         .status()
-        .context("building stage2")?;
+        .context("building stage1")?;
+    if !status.success() {
+        anyhow::bail!("building stage1 failed");
+    }
+    let status = Command::new("objcopy")
+        .args([
+            "-O",
+            "binary",
+            "-j",
+            ".boot",
+            &root_dir
+                .join("target/i686-bootloader/release/stage1")
+                .to_string_lossy(),
+            &stage1_path.to_string_lossy(),
+        ])
+        .status()
+        .context("extracting the boot sector of stage1")?;
+    if !status.success() {
+        anyhow::bail!("extracting the boot sector of stage1 failed");
+    }
+    Ok(())
+}
+
+fn stage2_elf_path(root_dir: &Path) -> PathBuf {
+    root_dir.join("target/i686-bootloader/release/bootloader")
+}
+
+fn build_stage2(
+    root_dir: &Path,
+    cache: &mut Cache,
+    metrics: bool,
+    minimal: bool,
+    verbose: bool,
+) -> Result<PathBuf, anyhow::Error> {
+    linker_scripts::generate(root_dir)?;
+    // cargo already skips the build itself when nothing changed
+    let mut command = Command::new("cargo");
+    command
+        .args(["+nightly", "bios", "--release"])
+        .current_dir(root_dir.join("bootloader"));
+    if metrics {
+        command.args(["--features", "metrics"]);
+    }
+    if minimal {
+        command.arg("--no-default-features");
+    }
+    let status = command.status().context("building stage2")?;
     if !status.success() {
         anyhow::bail!("build stage2 failed");
     }
-    let stage2_elf_path = root_dir.join("target/i686-bootloader/release/bootloader");
+    let stage2_elf_path = stage2_elf_path(root_dir);
     if verbose {
         let status = Command::new("sh")
             .args([
@@ -144,6 +471,11 @@ fn build_stage2(root_dir: &Path, verbose: bool) -> Result<PathBuf, anyhow::Error
         .parent()
         .ok_or(anyhow::anyhow!("No parent for stage2 ELF?"))?
         .join("stage2.bin");
+    let fingerprint = Fingerprint::new().file(&stage2_elf_path)?;
+    if cache.is_fresh("stage2", &fingerprint, &[&stage2_path]) {
+        skipped("stage2", verbose);
+        return Ok(stage2_path);
+    }
     let status = Command::new("objcopy")
         .args([
             "-O",
@@ -162,15 +494,48 @@ fn build_stage2(root_dir: &Path, verbose: bool) -> Result<PathBuf, anyhow::Error
     if !status.success() {
         anyhow::bail!("extracting sections from ELF file to generate stage2 failed");
     }
+    cache.record("stage2", &fingerprint)?;
     Ok(stage2_path)
 }
 
-fn build_kernel(root_dir: &Path) -> anyhow::Result<PathBuf> {
-    let status = Command::new("cargo")
+fn build_kernel(
+    root_dir: &Path,
+    stack_protector: xtasks::StackProtector,
+    kaslr: bool,
+    metrics: bool,
+) -> anyhow::Result<PathBuf> {
+    let mut rustflags = Vec::new();
+    if stack_protector != xtasks::StackProtector::None {
+        // The kernel defines __stack_chk_guard and __stack_chk_fail itself
+        rustflags.push(format!(
+            "-Zstack-protector={}",
+            stack_protector.rustc_value()
+        ));
+    }
+    if kaslr {
+        // The bootloader applies the resulting R_X86_64_RELATIVE relocations
+        rustflags.extend(
+            [
+                "-Crelocation-model=pie",
+                "-Ccode-model=small",
+                "-Clink-arg=-pie",
+            ]
+            .map(String::from),
+        );
+    }
+
+    linker_scripts::generate(root_dir)?;
+    let mut command = Command::new("cargo");
+    command
         .args(["+nightly", "kernel", "--release"])
-        .current_dir(root_dir.join("kernel"))
-        .status()
-        .context("building the kernel")?;
+        .current_dir(root_dir.join("kernel"));
+    if metrics {
+        command.args(["--features", "metrics"]);
+    }
+    if !rustflags.is_empty() {
+        command.env("RUSTFLAGS", rustflags.join(" "));
+    }
+    let status = command.status().context("building the kernel")?;
     if !status.success() {
         anyhow::bail!("building the kernel failed");
     }
@@ -178,6 +543,519 @@ fn build_kernel(root_dir: &Path) -> anyhow::Result<PathBuf> {
     Ok(kernel_elf_path)
 }
 
+/// Checks the image at `image_path`, against the last stage2 built if there's one
+fn verify_image(root_dir: &Path, image_path: &Path) -> anyhow::Result<image::Layout> {
+    let image = std::fs::read(image_path).context("reading image bytes")?;
+    let stage2_path = root_dir.join("target/i686-bootloader/release/stage2.bin");
+    let stage2 = if stage2_path.exists() {
+        Some(std::fs::read(&stage2_path).context("reading stage2 bytes")?)
+    } else {
+        None
+    };
+    image::verify(&image, stage2.as_deref())
+        .with_context(|| format!("{} is malformed", image_path.to_string_lossy()))
+}
+
+/// A crate built for one target, the way `check` runs cargo on it
+struct CheckTarget {
+    name: &'static str,
+    /// The crate's directory, relative to the root directory
+    dir: &'static str,
+    /// Targets other than the host need a nightly toolchain to build core for them
+    target: Option<&'static str>,
+    all_targets: bool,
+    /// Set for cargo, for crates that read it at build time
+    env: &'static [(&'static str, &'static str)],
+}
+
+const CHECK_TARGETS: [CheckTarget; 7] = [
+    CheckTarget {
+        name: "bootloader (i686)",
+        dir: "bootloader",
+        target: Some("bootloader/i686-bootloader.json"),
+        all_targets: false,
+        env: &[],
+    },
+    CheckTarget {
+        name: "stage1 (i686)",
+        dir: "bootloader/stage1",
+        target: Some("bootloader/i686-bootloader.json"),
+        all_targets: false,
+        // Any sector count, it's only baked into the boot sector
+        env: &[("STAGE2_SECTORS", "1")],
+    },
+    CheckTarget {
+        name: "kernel (x86_64)",
+        dir: "kernel",
+        target: Some("kernel/x86_64-blog_os.json"),
+        all_targets: false,
+        env: &[],
+    },
+    CheckTarget {
+        name: "common (host)",
+        dir: "common",
+        target: None,
+        all_targets: true,
+        env: &[],
+    },
+    CheckTarget {
+        name: "common (i686)",
+        dir: "common",
+        target: Some("bootloader/i686-bootloader.json"),
+        all_targets: false,
+        env: &[],
+    },
+    CheckTarget {
+        name: "common (x86_64)",
+        dir: "common",
+        target: Some("kernel/x86_64-blog_os.json"),
+        all_targets: false,
+        env: &[],
+    },
+    CheckTarget {
+        name: "xtasks (host)",
+        dir: "xtasks",
+        target: None,
+        all_targets: true,
+        env: &[],
+    },
+];
+
+/// Runs clippy, or just cargo check, on every entry of `CHECK_TARGETS`, carrying on past failures.
+/// Returns the names of the ones that failed
+fn check(root_dir: &Path, clippy: bool) -> anyhow::Result<Vec<&'static str>> {
+    let mut failed = Vec::new();
+    for check_target in &CHECK_TARGETS {
+        println!("Checking {}", check_target.name);
+        let mut command = Command::new("cargo");
+        command
+            .current_dir(root_dir.join(check_target.dir))
+            .envs(check_target.env.iter().copied());
+        let target = check_target.target.map(|target| root_dir.join(target));
+        if let Some(target) = &target {
+            command.args([
+                "+nightly",
+                if clippy { "clippy" } else { "check" },
+                "-Zbuild-std=core,compiler_builtins",
+                "-Zbuild-std-features=mem",
+                "--target",
+                &target.to_string_lossy(),
+            ]);
+        } else {
+            command.args([
+                if clippy { "clippy" } else { "check" },
+                "--target",
+                "x86_64-unknown-linux-gnu",
+            ]);
+        }
+        if check_target.all_targets {
+            command.arg("--all-targets");
+        }
+        if clippy {
+            command.args(["--", "-D", "warnings"]);
+        }
+        let status = command
+            .status()
+            .with_context(|| format!("checking {}", check_target.name))?;
+        if !status.success() {
+            failed.push(check_target.name);
+        }
+    }
+    Ok(failed)
+}
+
+/// Builds the kernel's test harness, returning the path of the executable
+fn build_kernel_tests(root_dir: &Path) -> anyhow::Result<PathBuf> {
+    linker_scripts::generate(root_dir)?;
+    let output = Command::new("cargo")
+        .args([
+            "+nightly",
+            "kernel_test",
+            "--release",
+            "--message-format=json",
+        ])
+        .current_dir(root_dir.join("kernel"))
+        .stderr(Stdio::inherit())
+        .output()
+        .context("building the kernel tests")?;
+    if !output.status.success() {
+        anyhow::bail!("building the kernel tests failed");
+    }
+    let executable = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| test_report::string_field(line, "executable"))
+        .next_back()
+        .ok_or(anyhow::anyhow!(
+            "No test executable among cargo's artifacts?"
+        ))?;
+    Ok(PathBuf::from(executable))
+}
+
+fn skipped(step: &str, verbose: bool) {
+    if verbose {
+        println!("{step} is up to date, skipping it");
+    }
+}
+
+/// Puts the bootloader at `bootloader_path`, the payload table, the kernel at `kernel_path` and
+/// `extra_payloads` together into a disk image, each payload starting on a sector of its own
+fn build_image(
+    root_dir: &Path,
+    bootloader_path: &Path,
+    kernel_path: &Path,
+    extra_payloads: &[(image::PayloadKind, PathBuf)],
+    image_name: &str,
+    cache: &mut Cache,
+    verbose: bool,
+) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(
+        extra_payloads.len() < image::MAX_PAYLOADS,
+        "the payload table holds at most {} payloads",
+        image::MAX_PAYLOADS
+    );
+    let image_path = root_dir.join(image_name);
+    let mut fingerprint = Fingerprint::new()
+        .file(bootloader_path)?
+        .file(kernel_path)?;
+    for (kind, path) in extra_payloads {
+        fingerprint = fingerprint.value(kind.to_string()).file(path)?;
+    }
+    if cache.is_fresh(image_name, &fingerprint, &[&image_path]) {
+        skipped(image_name, verbose);
+        verify_image(root_dir, &image_path)?;
+        return Ok(image_path);
+    }
+
+    let mut image = std::fs::read(bootloader_path).context("reading bootloader bytes")?;
+    let mut payloads = vec![(
+        image::PayloadKind::Kernel,
+        std::fs::read(kernel_path).context("reading kernel bytes")?,
+    )];
+    for (kind, path) in extra_payloads {
+        let payload = std::fs::read(path)
+            .with_context(|| format!("reading the {kind} from {}", path.to_string_lossy()))?;
+        payloads.push((*kind, payload));
+    }
+
+    let kinds_and_sizes: Vec<_> = payloads
+        .iter()
+        .map(|(kind, payload)| (*kind, payload.len() as u64))
+        .collect();
+    // The table takes the sector right after the bootloader, the payloads follow it
+    let first_payload_sector = (image.len() as u64).div_ceil(SECTOR_SIZE) + 1;
+    image.append(&mut image::payload_table(
+        &kinds_and_sizes,
+        first_payload_sector,
+    ));
+    for (_, mut payload) in payloads {
+        let sectors = (payload.len() as u64).div_ceil(SECTOR_SIZE);
+        payload.resize((sectors * SECTOR_SIZE) as usize, 0);
+        image.append(&mut payload);
+    }
+
+    std::fs::write(&image_path, image).context("writing image file")?;
+    verify_image(root_dir, &image_path)?;
+    cache.record(image_name, &fingerprint)?;
+    Ok(image_path)
+}
+
+/// Encodes the code symbols the ELF at `elf_path` defines into target/`file_name`, for the kernel
+/// or stage2 to symbolize backtraces with
+fn build_symbols(root_dir: &Path, elf_path: &Path, file_name: &str) -> anyhow::Result<PathBuf> {
+    let output = Command::new("nm")
+        .args([
+            "--defined-only",
+            "-n",
+            "-S",
+            "-C",
+            &elf_path.to_string_lossy(),
+        ])
+        .output()
+        .with_context(|| format!("running nm on {}", elf_path.to_string_lossy()))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "listing the symbols of {} failed: {}",
+            elf_path.to_string_lossy(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let target_dir = root_dir.join("target");
+    std::fs::create_dir_all(&target_dir).context("creating the target directory")?;
+    let symbols_path = target_dir.join(file_name);
+    let table = symbols::encode(&String::from_utf8_lossy(&output.stdout));
+    std::fs::write(&symbols_path, table).context("writing the symbol table")?;
+    Ok(symbols_path)
+}
+
+/// Writes the kernel command line to a file of its own, for `build_image` to ship like the
+/// other payloads
+fn write_command_line(root_dir: &Path, cmdline: &str) -> anyhow::Result<PathBuf> {
+    let target_dir = root_dir.join("target");
+    std::fs::create_dir_all(&target_dir).context("creating the target directory")?;
+    let command_line_path = target_dir.join("cmdline");
+    std::fs::write(&command_line_path, cmdline).context("writing the kernel command line")?;
+    Ok(command_line_path)
+}
+
+/// Packs `directory` into target/initrd.cpio
+fn write_initrd_archive(root_dir: &Path, directory: &Path) -> anyhow::Result<PathBuf> {
+    let archive = cpio::archive(directory).context("packing the initrd directory")?;
+    let target_dir = root_dir.join("target");
+    std::fs::create_dir_all(&target_dir).context("creating the target directory")?;
+    let archive_path = target_dir.join("initrd.cpio");
+    std::fs::write(&archive_path, archive).context("writing the initrd archive")?;
+    Ok(archive_path)
+}
+
+/// Boots `image_path` in Bochs, from a copy padded to a whole number of cylinders as Bochs wants
+/// flat images to be. What the kernel writes to COM1 ends up in target/bochs-com1.txt
+fn run_bochs(root_dir: &Path, image_path: &Path) -> anyhow::Result<()> {
+    let mut image = std::fs::read(image_path).context("reading image bytes")?;
+    let geometry =
+        emulator_config::Geometry::for_sectors(image.len().div_ceil(SECTOR_SIZE as usize) as u64);
+    image.resize(geometry.bytes() as usize, 0);
+
+    let target_dir = root_dir.join("target");
+    std::fs::create_dir_all(&target_dir).context("creating the target directory")?;
+    let bochs_image_path = target_dir.join("bochs-disk.img");
+    std::fs::write(&bochs_image_path, image).context("writing the padded image")?;
+    let bochsrc_path = target_dir.join("bochsrc");
+    let bochsrc = emulator_config::bochsrc(
+        &bochs_image_path,
+        geometry,
+        &target_dir.join("bochs-com1.txt"),
+    );
+    std::fs::write(&bochsrc_path, bochsrc).context("writing bochsrc")?;
+
+    let status = Command::new("bochs")
+        .args(["-q", "-f", &bochsrc_path.to_string_lossy()])
+        .status()
+        .context("running bochs")?;
+    if !status.success() {
+        anyhow::bail!("bochs exited with {status}");
+    }
+    Ok(())
+}
+
+/// Writes `image_path` to `device_path` once the image checks out and the device is a removable
+/// one nothing is mounted from, asking first unless `yes`
+fn flash_image(
+    root_dir: &Path,
+    image_path: &Path,
+    device_path: &Path,
+    yes: bool,
+) -> anyhow::Result<()> {
+    verify_image(root_dir, image_path)?;
+    let image = std::fs::read(image_path).context("reading image bytes")?;
+    let device = flash::BlockDevice::open(device_path)?;
+    if !device.removable {
+        anyhow::bail!(
+            "{} ({}) isn't removable, refusing to write to it",
+            device.name,
+            device.model
+        );
+    }
+    let mount_points = device.mount_points()?;
+    if !mount_points.is_empty() {
+        anyhow::bail!(
+            "{} is mounted on {}, unmount it first",
+            device.name,
+            mount_points.join(", ")
+        );
+    }
+    if device.sectors * SECTOR_SIZE
+        < image.len().next_multiple_of(flash::DIRECT_IO_ALIGNMENT) as u64
+    {
+        anyhow::bail!("{} is too small for the image", device.name);
+    }
+
+    for quirk in flash::edd_quirks(&image, &device) {
+        println!("warning: {quirk}");
+    }
+    if !yes {
+        print!(
+            "Write {} to {} ({}, {} MiB)? Everything on it will be lost [y/N] ",
+            image_path.to_string_lossy(),
+            device.path.to_string_lossy(),
+            device.model,
+            device.sectors * SECTOR_SIZE / (1024 * 1024)
+        );
+        std::io::stdout().flush().context("flushing stdout")?;
+        let mut answer = String::new();
+        std::io::stdin()
+            .read_line(&mut answer)
+            .context("reading the answer")?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            anyhow::bail!("not flashing");
+        }
+    }
+    flash::write_and_verify(&image, &device)
+}
+
+/// Writes a VMDK descriptor for `image_path` to `output_path`
+fn write_vmdk(image_path: &Path, output_path: &Path) -> anyhow::Result<()> {
+    let size = std::fs::metadata(image_path)
+        .context("collecting info about the image")?
+        .size();
+    if size % SECTOR_SIZE != 0 {
+        anyhow::bail!("the image isn't a whole number of sectors long");
+    }
+    if let Some(parent) = output_path.parent() {
+        std::fs::create_dir_all(parent).context("creating the output directory")?;
+    }
+    let descriptor = emulator_config::vmdk_descriptor(image_path, size / SECTOR_SIZE);
+    std::fs::write(output_path, descriptor).context("writing the VMDK descriptor")
+}
+
+/// Boots `image_path` behind `interface`, with `data_disks` blank disks of `data_disk_size` MiB
+/// after it, kept in target/ so that what's written to them survives between runs. With
+/// `record_replay`, the run is recorded to or replayed from its log, and nothing is written to the
+/// disks
+fn run(
+    root_dir: &Path,
+    image_path: &Path,
+    interface: xtasks::DriveInterface,
+    data_disks: usize,
+    data_disk_size: u64,
+    record_replay: Option<(qemu::RecordReplay, PathBuf)>,
+) -> anyhow::Result<()> {
+    if !image_path.exists() {
+        anyhow::bail!(
+            "{} doesn't exist, build it with `build-image` first",
+            image_path.to_string_lossy()
+        );
+    }
+    if data_disks + 1 > qemu::max_drives(interface) {
+        anyhow::bail!(
+            "{interface:?} takes at most {} drives, the image included",
+            qemu::max_drives(interface)
+        );
+    }
+    std::fs::create_dir_all(root_dir.join("target")).context("creating the target directory")?;
+    let mut drives = vec![image_path.to_path_buf()];
+    for index in 1..=data_disks {
+        let data_disk_path = root_dir.join(format!("target/data-{index}.img"));
+        if !data_disk_path.exists() {
+            std::fs::File::create(&data_disk_path)
+                .and_then(|file| file.set_len(data_disk_size * 1024 * 1024))
+                .with_context(|| {
+                    format!("creating data disk {}", data_disk_path.to_string_lossy())
+                })?;
+        }
+        drives.push(data_disk_path);
+    }
+    let drives: Vec<_> = drives.iter().map(PathBuf::as_path).collect();
+    let drive_args = match &record_replay {
+        Some(_) if interface != xtasks::DriveInterface::Ide => {
+            anyhow::bail!("Runs are only recorded and replayed with IDE drives")
+        }
+        Some((mode, log)) => qemu::record_replay_args(*mode, log, &drives),
+        None => qemu::drive_args(interface, &drives),
+    };
+
+    let status = Command::new("qemu-system-x86_64")
+        .args(qemu::MEMORY_ARGS)
+        .args(drive_args)
+        .args(["-serial", "stdio"])
+        .status()
+        .context("running qemu")?;
+    if !status.success() {
+        anyhow::bail!("qemu exited with {status}");
+    }
+    Ok(())
+}
+
+/// Boots the test image, hands the filter over to the kernel's test runner and prints the results
+/// as they come. True if every test that was to run passed
+fn run_tests(
+    image_path: &Path,
+    filter: &str,
+    timeout: Duration,
+    verbose: bool,
+) -> anyhow::Result<bool> {
+    let mut qemu = Command::new("qemu-system-x86_64")
+        .args(qemu::MEMORY_ARGS)
+        .args([
+            "-drive",
+            &format!("format=raw,file={}", image_path.to_string_lossy()),
+            "-serial",
+            "stdio",
+            "-display",
+            "none",
+            "-device",
+            "isa-debug-exit,iobase=0xf4,iosize=0x04",
+            "-no-reboot",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("starting qemu")?;
+    let mut serial_input = qemu.stdin.take().context("getting qemu's stdin")?;
+    let serial_output = qemu.stdout.take().context("getting qemu's stdout")?;
+
+    // Lines are read on their own thread, so that waiting for them can time out
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(serial_output).lines() {
+            let Ok(line) = line else { break };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    let mut report = Report::default();
+    loop {
+        let line = match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => {
+                println!("timed out after {}s", timeout.as_secs());
+                qemu.kill().context("killing qemu")?;
+                break;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let Some(event) = Event::parse(&line) else {
+            if verbose {
+                println!("{}", line.trim_end());
+            }
+            continue;
+        };
+        report.record(&event);
+        match event {
+            Event::Ready { .. } => writeln!(serial_input, "{filter}")
+                .context("sending the filter to the test runner")?,
+            Event::Test {
+                name,
+                passed,
+                duration,
+                message,
+            } => {
+                let result = if passed { "ok" } else { "FAILED" };
+                println!("test {name} ... {result} ({duration:?})");
+                if let Some(message) = message {
+                    println!("{message}");
+                }
+            }
+            Event::Panic { message } => println!("kernel panicked outside of any test: {message}"),
+            Event::Done { .. } => {}
+        }
+    }
+    let status = qemu.wait().context("waiting for qemu")?;
+
+    let success = report.success() && status.code() == Some(QEMU_TESTS_PASSED_EXIT_CODE);
+    println!(
+        "test result: {}. {} passed; {} failed; {} not run; {} filtered out",
+        if success { "ok" } else { "FAILED" },
+        report.passed,
+        report.failed,
+        report.not_run(),
+        report.filtered_out
+    );
+    Ok(success)
+}
+
 fn main() -> anyhow::Result<()> {
     let cli = xtasks::Cli::parse();
     let root_dir = PathBuf::from(cli.root_dir())
@@ -185,26 +1063,208 @@ fn main() -> anyhow::Result<()> {
         .context("canonicalising root dir")?;
 
     match cli.command() {
-        &xtasks::Command::BuildImage { verbose } => {
-            let kernel_path = build_kernel(&root_dir)?;
-
-            let metadata = std::fs::metadata(&kernel_path)
-                .context("collecting info about the generated kernel file")?;
-
-            // Build stage1 to read enough sectors to load stage2
-            let kernel_sectors = metadata.size().div_ceil(SECTOR_SIZE);
-            let bootloader_path = build_bootloader(&root_dir, kernel_sectors, verbose)?;
-
-            let mut image = std::fs::read(&bootloader_path).context("reading bootloader bytes")?;
-            let mut kernel = std::fs::read(&kernel_path).context("reading kernel bytes")?;
-            kernel.resize((kernel_sectors * SECTOR_SIZE) as usize, 0);
-
-            image.append(&mut kernel);
-            let image_path = root_dir.join("disk.img");
-
-            std::fs::write(&image_path, image).context("writing image file")?;
+        xtasks::Command::BuildImage {
+            verbose,
+            stack_protector,
+            kaslr,
+            force,
+            symbols,
+            initrd,
+            initrd_dir,
+            cmdline,
+            deterministic,
+            metrics,
+            minimal,
+            stage1,
+        } => {
+            let mut cache = Cache::load(&root_dir, *force);
+            let kernel_path = build_kernel(&root_dir, *stack_protector, *kaslr, *metrics)?;
+            let bootloader_path =
+                build_bootloader(&root_dir, &mut cache, *stage1, *metrics, *minimal, *verbose)?;
+            let mut extra_payloads = Vec::new();
+            if *symbols {
+                extra_payloads.push((
+                    image::PayloadKind::Symbols,
+                    build_symbols(&root_dir, &kernel_path, "kernel.sym")?,
+                ));
+                extra_payloads.push((
+                    image::PayloadKind::Stage2Symbols,
+                    build_symbols(&root_dir, &stage2_elf_path(&root_dir), "stage2.sym")?,
+                ));
+            }
+            if let Some(initrd) = initrd {
+                extra_payloads.push((image::PayloadKind::Initrd, root_dir.join(initrd)));
+            }
+            if let Some(initrd_dir) = initrd_dir {
+                extra_payloads.push((
+                    image::PayloadKind::Initrd,
+                    write_initrd_archive(&root_dir, &root_dir.join(initrd_dir))?,
+                ));
+            }
+            let cmdline = match (cmdline, *deterministic) {
+                (Some(cmdline), true) => Some(format!("{cmdline} deterministic")),
+                (None, true) => Some("deterministic".into()),
+                (cmdline, false) => cmdline.clone(),
+            };
+            if let Some(cmdline) = cmdline {
+                extra_payloads.push((
+                    image::PayloadKind::CommandLine,
+                    write_command_line(&root_dir, &cmdline)?,
+                ));
+            }
+            let image_path = build_image(
+                &root_dir,
+                &bootloader_path,
+                &kernel_path,
+                &extra_payloads,
+                "disk.img",
+                &mut cache,
+                *verbose,
+            )?;
             println!("Disk image built: {}", image_path.to_string_lossy());
         }
+        xtasks::Command::Run {
+            image,
+            emulator: xtasks::Emulator::Bochs,
+            interface,
+            data_disks,
+            record,
+            replay,
+            ..
+        } => {
+            if *interface != xtasks::DriveInterface::Ide || *data_disks != 0 {
+                anyhow::bail!("Bochs only boots from IDE, without data disks");
+            }
+            if record.is_some() || replay.is_some() {
+                anyhow::bail!("Only qemu records and replays runs");
+            }
+            run_bochs(&root_dir, &root_dir.join(image))?;
+        }
+        xtasks::Command::Run {
+            image,
+            emulator: xtasks::Emulator::Qemu,
+            interface,
+            data_disks,
+            data_disk_size,
+            record,
+            replay,
+        } => {
+            let record_replay = match (record, replay) {
+                (Some(log), _) => Some((qemu::RecordReplay::Record, root_dir.join(log))),
+                (None, Some(log)) => Some((qemu::RecordReplay::Replay, root_dir.join(log))),
+                (None, None) => None,
+            };
+            run(
+                &root_dir,
+                &root_dir.join(image),
+                *interface,
+                *data_disks,
+                *data_disk_size,
+                record_replay,
+            )?
+        }
+        xtasks::Command::Flash { device, image, yes } => {
+            flash_image(&root_dir, &root_dir.join(image), Path::new(device), *yes)?;
+            println!("{image} written to {device} and read back fine");
+        }
+        xtasks::Command::Vmdk { image, output } => {
+            let output_path = root_dir.join(output);
+            write_vmdk(&root_dir.join(image), &output_path)?;
+            println!("VMDK descriptor written: {}", output_path.to_string_lossy());
+        }
+        xtasks::Command::VerifyImage { image } => {
+            let image_path = root_dir.join(image);
+            let layout = verify_image(&root_dir, &image_path)?;
+            println!(
+                "{}: stage1, {} sectors of stage2, the payload table at sector {}",
+                image_path.to_string_lossy(),
+                layout.stage2_sectors,
+                layout.payload_table_sector()
+            );
+            for payload in &layout.payloads {
+                println!(
+                    "  {}: {} sectors from sector {}",
+                    payload.kind, payload.sectors, payload.lba
+                );
+            }
+        }
+        xtasks::Command::ElfInfo {
+            path,
+            sections,
+            segments,
+            symbols,
+            notes,
+            no_color,
+        } => {
+            let path = root_dir.join(path);
+            let bytes = std::fs::read(&path)
+                .with_context(|| format!("reading {}", path.to_string_lossy()))?;
+            let mut tables = elf_info::Tables {
+                sections: *sections,
+                segments: *segments,
+                symbols: *symbols,
+                notes: *notes,
+            };
+            if !(tables.sections || tables.segments || tables.symbols || tables.notes) {
+                tables = elf_info::Tables::ALL;
+            }
+            let colors = !no_color && std::io::stdout().is_terminal();
+            let description = elf_info::describe(&bytes, tables, colors)
+                .with_context(|| format!("reading {} as ELF", path.to_string_lossy()))?;
+            print!("{description}");
+        }
+        &xtasks::Command::LinkerScripts { check } => {
+            if check {
+                let stale: Vec<_> = linker_scripts::scripts()
+                    .into_iter()
+                    .filter(|(path, script)| {
+                        std::fs::read_to_string(root_dir.join(path)).ok().as_deref()
+                            != Some(script.as_str())
+                    })
+                    .map(|(path, _)| path)
+                    .collect();
+                if !stale.is_empty() {
+                    anyhow::bail!("out of date: {}", stale.join(", "));
+                }
+                println!("The linker scripts are up to date");
+            } else {
+                for path in linker_scripts::generate(&root_dir)? {
+                    println!("Regenerated {}", path.to_string_lossy());
+                }
+            }
+        }
+        &xtasks::Command::Check { no_clippy } => {
+            let failed = check(&root_dir, !no_clippy)?;
+            if !failed.is_empty() {
+                anyhow::bail!("checking failed for: {}", failed.join(", "));
+            }
+            println!("All {} checks passed", CHECK_TARGETS.len());
+        }
+        xtasks::Command::Test {
+            filter,
+            timeout,
+            verbose,
+            force,
+            stage1,
+        } => {
+            let mut cache = Cache::load(&root_dir, *force);
+            let kernel_path = build_kernel_tests(&root_dir)?;
+            let bootloader_path =
+                build_bootloader(&root_dir, &mut cache, *stage1, false, false, *verbose)?;
+            let image_path = build_image(
+                &root_dir,
+                &bootloader_path,
+                &kernel_path,
+                &[],
+                "test-disk.img",
+                &mut cache,
+                *verbose,
+            )?;
+            let filter = filter.as_deref().unwrap_or_default();
+            if !run_tests(&image_path, filter, Duration::from_secs(*timeout), *verbose)? {
+                anyhow::bail!("kernel tests failed");
+            }
+        }
     }
 
     Ok(())
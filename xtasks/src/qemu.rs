@@ -0,0 +1,171 @@
+//! Turning a drive topology into QEMU arguments: which controller the disks sit behind, the boot
+//! image being the first of them
+
+use std::path::Path;
+
+use crate::xtasks::DriveInterface;
+
+/// Enough RAM for the kernel heap and the frame pool, which sit past QEMU's default 128MB, see
+/// common/src/layout.rs
+pub(crate) const MEMORY_ARGS: [&str; 2] = ["-m", "512M"];
+
+/// The arguments attaching `drives` to QEMU through `interface`, booting from the first one
+pub(crate) fn drive_args(interface: DriveInterface, drives: &[&Path]) -> Vec<String> {
+    let mut args = Vec::new();
+    // Controllers that can hold every drive are added once, up front
+    match interface {
+        DriveInterface::Ahci => args.extend(["-device".into(), "ahci,id=ahci".into()]),
+        DriveInterface::Usb => args.extend(["-device".into(), "qemu-xhci,id=xhci".into()]),
+        DriveInterface::Ide | DriveInterface::Nvme | DriveInterface::VirtioBlk => {}
+    }
+    for (index, drive) in drives.iter().enumerate() {
+        let file = drive.to_string_lossy();
+        // SeaBIOS only boots from drives other than IDE ones when told to
+        let boot = if index == 0 { ",bootindex=0" } else { "" };
+        let device = match interface {
+            DriveInterface::Ide => {
+                args.extend([
+                    "-drive".into(),
+                    format!("format=raw,file={file},if=ide,index={index}"),
+                ]);
+                continue;
+            }
+            DriveInterface::Ahci => format!("ide-hd,drive=disk{index},bus=ahci.{index}{boot}"),
+            DriveInterface::Nvme => format!("nvme,serial=blogos{index},drive=disk{index}{boot}"),
+            DriveInterface::VirtioBlk => format!("virtio-blk-pci,drive=disk{index}{boot}"),
+            DriveInterface::Usb => format!("usb-storage,bus=xhci.0,drive=disk{index}{boot}"),
+        };
+        args.extend([
+            "-drive".into(),
+            format!("id=disk{index},format=raw,file={file},if=none"),
+        ]);
+        args.extend(["-device".into(), device]);
+    }
+    args
+}
+
+/// Whether QEMU records a run's nondeterministic inputs to a log, or replays them from one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecordReplay {
+    Record,
+    Replay,
+}
+
+/// The arguments recording or replaying a run with `log`, on IDE `drives` booting from the first
+/// one. The instruction counter stands in for the clock, and the drives go through blkreplay so
+/// that their reads are in the log too. They're opened as snapshots: a replay needs them as they
+/// were when the run was recorded
+// https://www.qemu.org/docs/master/devel/replay.html
+pub(crate) fn record_replay_args(mode: RecordReplay, log: &Path, drives: &[&Path]) -> Vec<String> {
+    let mode = match mode {
+        RecordReplay::Record => "record",
+        RecordReplay::Replay => "replay",
+    };
+    let mut args = vec![
+        "-icount".into(),
+        format!("shift=auto,rr={mode},rrfile={}", log.to_string_lossy()),
+    ];
+    for (index, drive) in drives.iter().enumerate() {
+        let file = drive.to_string_lossy();
+        let boot = if index == 0 { ",bootindex=0" } else { "" };
+        args.extend([
+            "-drive".into(),
+            format!("id=direct{index},format=raw,file={file},if=none,snapshot=on"),
+            "-drive".into(),
+            format!("id=disk{index},driver=blkreplay,image=direct{index},if=none"),
+            "-device".into(),
+            format!(
+                "ide-hd,drive=disk{index},bus=ide.{},unit={}{boot}",
+                index / 2,
+                index % 2
+            ),
+        ]);
+    }
+    args
+}
+
+/// How many drives `interface` can take, as QEMU sets its controllers up
+pub(crate) fn max_drives(interface: DriveInterface) -> usize {
+    match interface {
+        DriveInterface::Ide => 4,
+        DriveInterface::Ahci => 6,
+        // A PCI device per drive, bounded by the free slots
+        DriveInterface::Nvme | DriveInterface::VirtioBlk => 16,
+        // The xHCI controller's root ports
+        DriveInterface::Usb => 8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        qemu::{RecordReplay, drive_args, record_replay_args},
+        xtasks::DriveInterface,
+    };
+
+    #[test]
+    fn topologies() {
+        let drives = [Path::new("disk.img"), Path::new("data-1.img")];
+        assert_eq!(
+            vec![
+                "-drive",
+                "format=raw,file=disk.img,if=ide,index=0",
+                "-drive",
+                "format=raw,file=data-1.img,if=ide,index=1",
+            ],
+            drive_args(DriveInterface::Ide, &drives)
+        );
+        assert_eq!(
+            vec![
+                "-device",
+                "ahci,id=ahci",
+                "-drive",
+                "id=disk0,format=raw,file=disk.img,if=none",
+                "-device",
+                "ide-hd,drive=disk0,bus=ahci.0,bootindex=0",
+                "-drive",
+                "id=disk1,format=raw,file=data-1.img,if=none",
+                "-device",
+                "ide-hd,drive=disk1,bus=ahci.1",
+            ],
+            drive_args(DriveInterface::Ahci, &drives)
+        );
+        assert_eq!(
+            vec![
+                "-device",
+                "qemu-xhci,id=xhci",
+                "-drive",
+                "id=disk0,format=raw,file=disk.img,if=none",
+                "-device",
+                "usb-storage,bus=xhci.0,drive=disk0,bootindex=0",
+            ],
+            drive_args(DriveInterface::Usb, &drives[..1])
+        );
+    }
+
+    #[test]
+    fn replays() {
+        let drives = [Path::new("disk.img"), Path::new("data-1.img")];
+        assert_eq!(
+            vec![
+                "-icount",
+                "shift=auto,rr=replay,rrfile=boot.rr",
+                "-drive",
+                "id=direct0,format=raw,file=disk.img,if=none,snapshot=on",
+                "-drive",
+                "id=disk0,driver=blkreplay,image=direct0,if=none",
+                "-device",
+                "ide-hd,drive=disk0,bus=ide.0,unit=0,bootindex=0",
+                "-drive",
+                "id=direct1,format=raw,file=data-1.img,if=none,snapshot=on",
+                "-drive",
+                "id=disk1,driver=blkreplay,image=direct1,if=none",
+                "-device",
+                "ide-hd,drive=disk1,bus=ide.0,unit=1",
+            ],
+            record_replay_args(RecordReplay::Replay, Path::new("boot.rr"), &drives)
+        );
+    }
+}
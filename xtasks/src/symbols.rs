@@ -0,0 +1,97 @@
+//! Turning what `nm` lists of the kernel or of stage2 into the compact symbol table they read from
+//! their symbols payload (see common/src/symbols.rs): only the code symbols, by address, with
+//! their demangled names stripped of the hash rustc appends
+
+const TABLE_MAGIC: &[u8; 8] = b"BLOGSYMS";
+
+struct Symbol<'a> {
+    address: u64,
+    size: u32,
+    name: &'a str,
+}
+
+/// Parses a line of `nm --defined-only -n -S -C`: the address, the size unless nm doesn't know
+/// it, the type and the name, which may have spaces of its own
+fn parse_line(line: &str) -> Option<Symbol<'_>> {
+    let (address, rest) = line.split_once(' ')?;
+    let address = u64::from_str_radix(address, 16).ok()?;
+    let (field, rest) = rest.split_once(' ')?;
+    let (size, (kind, name)) = if field.len() == 1 {
+        (0, (field, rest))
+    } else {
+        (u32::from_str_radix(field, 16).ok()?, rest.split_once(' ')?)
+    };
+    if !matches!(kind, "t" | "T" | "W" | "w") {
+        return None;
+    }
+    Some(Symbol {
+        address,
+        size,
+        name: strip_hash(name),
+    })
+}
+
+/// `blog_os::init::h0123456789abcdef` to `blog_os::init`
+fn strip_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            path
+        }
+        _ => name,
+    }
+}
+
+/// The symbol table of the code symbols in `nm_output`, which must be sorted by address
+pub fn encode(nm_output: &str) -> Vec<u8> {
+    let symbols: Vec<_> = nm_output.lines().filter_map(parse_line).collect();
+    let mut entries = Vec::with_capacity(symbols.len() * 16);
+    let mut names = Vec::new();
+    for symbol in &symbols {
+        entries.extend_from_slice(&symbol.address.to_le_bytes());
+        entries.extend_from_slice(&symbol.size.to_le_bytes());
+        entries.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        names.extend_from_slice(symbol.name.as_bytes());
+        names.push(0);
+    }
+    let mut table = Vec::with_capacity(16 + entries.len() + names.len());
+    table.extend_from_slice(TABLE_MAGIC);
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+    table.append(&mut entries);
+    table.append(&mut names);
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::encode;
+
+    #[test]
+    fn encode_nm_output() {
+        let nm_output = "\
+0000000000200000 0000000000000040 T _start
+0000000000200040 t blog_os::init::h0123456789abcdef
+0000000000201000 0000000000000010 T <blog_os::Foo as core::fmt::Display>::fmt::hfedcba9876543210
+0000000000300000 0000000000000008 D BOOT_INFO
+0000000000300008 B STUB
+";
+        let table = encode(nm_output);
+        assert_eq!(b"BLOGSYMS", &table[..8]);
+        assert_eq!(3, u32::from_le_bytes(table[8..12].try_into().unwrap()));
+        let names = b"_start\0blog_os::init\0<blog_os::Foo as core::fmt::Display>::fmt\0";
+        assert_eq!(
+            names.len() as u32,
+            u32::from_le_bytes(table[12..16].try_into().unwrap())
+        );
+        let second = &table[32..48];
+        assert_eq!(
+            0x200040,
+            u64::from_le_bytes(second[..8].try_into().unwrap())
+        );
+        assert_eq!(0, u32::from_le_bytes(second[8..12].try_into().unwrap()));
+        assert_eq!(7, u32::from_le_bytes(second[12..].try_into().unwrap()));
+        let third = &table[48..64];
+        assert_eq!(0x10, u32::from_le_bytes(third[8..12].try_into().unwrap()));
+        assert_eq!(names, &table[64..]);
+    }
+}
@@ -0,0 +1,212 @@
+//! Parsing what the kernel's test runner reports over serial, one flat JSON object per line (see
+//! kernel/src/testing.rs), and tallying it up
+
+use std::{collections::HashMap, time::Duration};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    String(String),
+    Number(u64),
+}
+
+/// Parses a JSON string starting at the opening quote, returning it and what follows it
+fn parse_string(input: &str) -> Option<(String, &str)> {
+    let mut chars = input.strip_prefix('"')?.char_indices();
+    let mut string = String::new();
+    while let Some((i, character)) = chars.next() {
+        match character {
+            '"' => return Some((string, &input[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => string.push('\n'),
+                'r' => string.push('\r'),
+                't' => string.push('\t'),
+                'u' => {
+                    let hex: String = (0..4)
+                        .filter_map(|_| chars.next())
+                        .map(|(_, c)| c)
+                        .collect();
+                    string.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                escaped => string.push(escaped),
+            },
+            character => string.push(character),
+        }
+    }
+    None
+}
+
+/// Parses an object of string and unsigned integer fields, which is all the runner sends
+pub(crate) fn parse_object(line: &str) -> Option<HashMap<String, Value>> {
+    let mut rest = line.trim().strip_prefix('{')?.trim_start();
+    let mut object = HashMap::new();
+    if let Some(after) = rest.strip_prefix('}') {
+        return after.trim().is_empty().then_some(object);
+    }
+    loop {
+        let (key, after_key) = parse_string(rest)?;
+        rest = after_key.trim_start().strip_prefix(':')?.trim_start();
+        let value = if rest.starts_with('"') {
+            let (value, after_value) = parse_string(rest)?;
+            rest = after_value;
+            Value::String(value)
+        } else {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let value = rest[..digits].parse().ok()?;
+            rest = &rest[digits..];
+            Value::Number(value)
+        };
+        object.insert(key, value);
+
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after.trim_start();
+        } else {
+            rest = rest.strip_prefix('}')?;
+            return rest.trim().is_empty().then_some(object);
+        }
+    }
+}
+
+/// The string field `field` of a JSON line, wherever it is in it. Good enough for picking the
+/// executable out of cargo's messages without a full JSON parser
+pub(crate) fn string_field(line: &str, field: &str) -> Option<String> {
+    let key = format!("\"{field}\":");
+    let start = line.find(&key)? + key.len();
+    parse_string(line[start..].trim_start()).map(|(value, _)| value)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Event {
+    Ready {
+        tests: u64,
+    },
+    Test {
+        name: String,
+        passed: bool,
+        duration: Duration,
+        message: Option<String>,
+    },
+    Done {
+        filtered_out: u64,
+    },
+    /// A panic outside of any test
+    Panic {
+        message: String,
+    },
+}
+
+impl Event {
+    pub(crate) fn parse(line: &str) -> Option<Self> {
+        let object = parse_object(line)?;
+        let string = |field: &str| match object.get(field) {
+            Some(Value::String(value)) => Some(value.clone()),
+            _ => None,
+        };
+        let number = |field: &str| match object.get(field) {
+            Some(Value::Number(value)) => Some(*value),
+            _ => None,
+        };
+        match string("event")?.as_str() {
+            "ready" => Some(Event::Ready {
+                tests: number("tests")?,
+            }),
+            "test" => Some(Event::Test {
+                name: string("name")?,
+                passed: string("result")? == "ok",
+                duration: Duration::from_nanos(number("duration_ns")?),
+                message: string("message"),
+            }),
+            "done" => Some(Event::Done {
+                filtered_out: number("filtered_out")?,
+            }),
+            "panic" => Some(Event::Panic {
+                message: string("message")?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Report {
+    pub(crate) tests: u64,
+    pub(crate) passed: u64,
+    pub(crate) failed: u64,
+    pub(crate) filtered_out: u64,
+    pub(crate) finished: bool,
+}
+
+impl Report {
+    pub(crate) fn record(&mut self, event: &Event) {
+        match event {
+            Event::Ready { tests } => self.tests = *tests,
+            Event::Test { passed: true, .. } => self.passed += 1,
+            Event::Test { passed: false, .. } => self.failed += 1,
+            Event::Done { filtered_out } => {
+                self.filtered_out = *filtered_out;
+                self.finished = true;
+            }
+            Event::Panic { .. } => {}
+        }
+    }
+
+    /// Tests that neither ran nor were filtered out, because the kernel stopped before them
+    pub(crate) fn not_run(&self) -> u64 {
+        if self.finished {
+            return 0;
+        }
+        self.tests.saturating_sub(self.passed + self.failed)
+    }
+
+    pub(crate) fn success(&self) -> bool {
+        self.finished && self.failed == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::test_report::{Event, Report, string_field};
+
+    #[test]
+    fn parse_and_tally() {
+        let lines = [
+            r#"{"event":"ready","tests":3}"#,
+            "some other output on the serial port",
+            r#"{"event":"test","name":"blog_os::a","result":"ok","duration_ns":1500}"#,
+            r#"{"event":"test","name":"blog_os::b","result":"failed","duration_ns":7,"message":"panicked at \"x\"\n\u0009"}"#,
+        ];
+        let events: Vec<_> = lines.iter().filter_map(|line| Event::parse(line)).collect();
+        assert_eq!(3, events.len());
+        assert_eq!(
+            Event::Test {
+                name: "blog_os::b".into(),
+                passed: false,
+                duration: Duration::from_nanos(7),
+                message: Some("panicked at \"x\"\n\t".into()),
+            },
+            events[2]
+        );
+
+        let mut report = Report::default();
+        events.iter().for_each(|event| report.record(event));
+        assert_eq!((1, 1, 1), (report.passed, report.failed, report.not_run()));
+        assert!(!report.success());
+
+        report.record(
+            &Event::parse(r#"{"event":"done","passed":1,"failed":0,"filtered_out":1}"#).unwrap(),
+        );
+        assert_eq!(0, report.not_run());
+
+        assert_eq!(
+            Some("/target/deps/blog_os-0123".into()),
+            string_field(
+                r#"{"reason":"compiler-artifact","filenames":[],"executable":"/target/deps/blog_os-0123","fresh":true}"#,
+                "executable"
+            )
+        );
+    }
+}